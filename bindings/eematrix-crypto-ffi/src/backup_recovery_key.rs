@@ -134,7 +134,7 @@ impl BackupRecoveryKey {
 
     /// Convert the recovery key to a base 58 encoded string.
     pub fn to_base58(&self) -> String {
-        self.inner.to_base58()
+        self.inner.to_base58().expose_secret().to_owned()
     }
 
     /// Convert the recovery key to a base 64 encoded string.
@@ -30,6 +30,12 @@ pub enum DehydrationError {
     PickleKeyLength(usize),
     #[error(transparent)]
     Rand(#[from] rand::Error),
+    #[error(transparent)]
+    Base64(#[from] matrix_sdk_crypto::vodozemac::Base64DecodeError),
+    #[error("The MAC check for the pickle key stored in the secret storage failed")]
+    SecretStorageMac,
+    #[error("Dehydrated devices are disabled")]
+    Disabled,
 }
 
 impl From<matrix_sdk_crypto::dehydrated_devices::DehydrationError> for DehydrationError {
@@ -47,6 +53,12 @@ impl From<matrix_sdk_crypto::dehydrated_devices::DehydrationError> for Dehydrati
             matrix_sdk_crypto::dehydrated_devices::DehydrationError::PickleKeyLength(l) => {
                 Self::PickleKeyLength(l)
             }
+            matrix_sdk_crypto::dehydrated_devices::DehydrationError::Rand(e) => Self::Rand(e),
+            matrix_sdk_crypto::dehydrated_devices::DehydrationError::Base64(e) => Self::Base64(e),
+            matrix_sdk_crypto::dehydrated_devices::DehydrationError::SecretStorageMac(_) => {
+                Self::SecretStorageMac
+            }
+            matrix_sdk_crypto::dehydrated_devices::DehydrationError::Disabled => Self::Disabled,
         }
     }
 }
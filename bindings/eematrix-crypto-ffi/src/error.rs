@@ -79,7 +79,7 @@ pub enum DecryptionError {
 impl From<MegolmError> for DecryptionError {
     fn from(value: MegolmError) -> Self {
         match &value {
-            MegolmError::MissingRoomKey(withheld_code) => Self::MissingRoomKey {
+            MegolmError::MissingRoomKey(withheld_code, _) => Self::MissingRoomKey {
                 error: value.to_string(),
                 withheld_code: withheld_code.as_ref().map(|w| w.as_str().to_owned()),
             },
@@ -117,7 +117,7 @@ mod tests {
     fn test_withheld_error_mapping() {
         use matrix_sdk_common::deserialized_responses::WithheldCode;
 
-        let inner_error = MegolmError::MissingRoomKey(Some(WithheldCode::Unverified));
+        let inner_error = MegolmError::MissingRoomKey(Some(WithheldCode::Unverified), None);
 
         let binding_error: DecryptionError = inner_error.into();
 
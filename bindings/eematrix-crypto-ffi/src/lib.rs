@@ -260,8 +260,12 @@ async fn migrate_data(
         dehydrated: false, // dehydrated devices are never involved in migration
         shared: data.account.shared,
         uploaded_signed_key_count: data.account.uploaded_signed_key_count as u64,
+        published_one_time_key_count: 0,
         creation_local_time: MilliSecondsSinceUnixEpoch::now(),
         fallback_key_creation_timestamp: Some(MilliSecondsSinceUnixEpoch::now()),
+        fallback_key_rotation_policy: Default::default(),
+        fallback_key: None,
+        fallback_key_use_count: 0,
     };
     let account = matrix_sdk_crypto::olm::Account::from_pickle(pickled_account)?;
 
@@ -512,6 +516,12 @@ fn collect_sessions(
             history_visibility: None,
             shared_history: false,
             algorithm: RustEventEncryptionAlgorithm::MegolmV1AesSha2,
+            received_at: None,
+            forwarding_chain: session
+                .forwarding_chains
+                .iter()
+                .filter_map(|key| Curve25519PublicKey::from_base64(key).ok())
+                .collect(),
         };
 
         let session = matrix_sdk_crypto::olm::InboundGroupSession::from_pickle(pickle)?;
@@ -698,6 +708,8 @@ impl From<EncryptionSettings> for RustEncryptionSettings {
             rotation_period_msgs: v.rotation_period_msgs,
             history_visibility: v.history_visibility.into(),
             sharing_strategy,
+            max_content_size: None,
+            strict_relation_validation: false,
         }
     }
 }
@@ -878,9 +890,9 @@ impl From<matrix_sdk_crypto::store::types::RoomKeyCounts> for RoomKeyCounts {
 impl From<matrix_sdk_crypto::CrossSigningKeyExport> for CrossSigningKeyExport {
     fn from(e: matrix_sdk_crypto::CrossSigningKeyExport) -> Self {
         Self {
-            master_key: e.master_key.clone(),
-            self_signing_key: e.self_signing_key.clone(),
-            user_signing_key: e.user_signing_key.clone(),
+            master_key: e.master_key.as_ref().map(|k| k.expose_secret().to_owned()),
+            self_signing_key: e.self_signing_key.as_ref().map(|k| k.expose_secret().to_owned()),
+            user_signing_key: e.user_signing_key.as_ref().map(|k| k.expose_secret().to_owned()),
         }
     }
 }
@@ -888,9 +900,9 @@ impl From<matrix_sdk_crypto::CrossSigningKeyExport> for CrossSigningKeyExport {
 impl From<CrossSigningKeyExport> for matrix_sdk_crypto::CrossSigningKeyExport {
     fn from(e: CrossSigningKeyExport) -> Self {
         matrix_sdk_crypto::CrossSigningKeyExport {
-            master_key: e.master_key,
-            self_signing_key: e.self_signing_key,
-            user_signing_key: e.user_signing_key,
+            master_key: e.master_key.map(Into::into),
+            self_signing_key: e.self_signing_key.map(Into::into),
+            user_signing_key: e.user_signing_key.map(Into::into),
         }
     }
 }
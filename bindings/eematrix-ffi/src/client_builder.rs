@@ -170,6 +170,7 @@ impl ClientBuilder {
                 backup_download_strategy:
                     matrix_sdk::encryption::BackupDownloadStrategy::AfterDecryptionFailure,
                 auto_enable_backups: false,
+                automatic_room_key_requests: false,
             },
             room_key_recipient_strategy: Default::default(),
             decryption_settings: DecryptionSettings {
@@ -352,6 +353,14 @@ impl ClientBuilder {
         Arc::new(builder)
     }
 
+    /// Automatically request the room keys of events we failed to decrypt
+    /// from our other devices.
+    pub fn automatic_room_key_requests(self: Arc<Self>, enabled: bool) -> Arc<Self> {
+        let mut builder = unwrap_or_clone_arc(self);
+        builder.encryption_settings.automatic_room_key_requests = enabled;
+        Arc::new(builder)
+    }
+
     /// Set the strategy to be used for picking recipient devices when sending
     /// an encrypted message.
     pub fn room_key_recipient_strategy(self: Arc<Self>, strategy: CollectStrategy) -> Arc<Self> {
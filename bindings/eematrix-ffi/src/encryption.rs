@@ -193,7 +193,7 @@ impl From<recovery::EnableProgress> for EnableRecoveryProgress {
             },
             recovery::EnableProgress::RoomKeyUploadError => Self::RoomKeyUploadError,
             recovery::EnableProgress::Done { recovery_key } => {
-                Self::Done { recovery_key: recovery_key.to_owned() }
+                Self::Done { recovery_key: recovery_key.expose_secret().to_owned() }
             }
         }
     }
@@ -350,7 +350,7 @@ impl Encryption {
         task.abort();
         passphrase.zeroize();
 
-        Ok(ret)
+        Ok(ret.expose_secret().to_owned())
     }
 
     pub async fn disable_recovery(&self) -> Result<()> {
@@ -358,7 +358,7 @@ impl Encryption {
     }
 
     pub async fn reset_recovery_key(&self) -> Result<String> {
-        Ok(self.inner.recovery().reset_key().await?)
+        Ok(self.inner.recovery().reset_key().await?.expose_secret().to_owned())
     }
 
     pub async fn recover_and_reset(&self, mut old_recovery_key: String) -> Result<String> {
@@ -366,7 +366,7 @@ impl Encryption {
 
         old_recovery_key.zeroize();
 
-        Ok(result?)
+        Ok(result?.expose_secret().to_owned())
     }
 
     /// Completely reset the current user's crypto identity: reset the cross
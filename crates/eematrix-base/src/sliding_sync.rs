@@ -2536,7 +2536,10 @@ mod tests {
             .unwrap(),
             UnableToDecryptInfo {
                 session_id: Some("".to_owned()),
-                reason: UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+                reason: UnableToDecryptReason::MissingMegolmSession {
+                    withheld_code: None,
+                    withheld_by: None,
+                },
             },
         )
     }
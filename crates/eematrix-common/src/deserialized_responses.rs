@@ -937,7 +937,10 @@ where
     // Backwards compatibility: `MissingMegolmSession` used to be stored without the
     // withheld code.
     if v.as_str().is_some_and(|s| s == "MissingMegolmSession") {
-        return Ok(UnableToDecryptReason::MissingMegolmSession { withheld_code: None });
+        return Ok(UnableToDecryptReason::MissingMegolmSession {
+            withheld_code: None,
+            withheld_by: None,
+        });
     }
     // Otherwise, use the derived deserialize impl to turn the JSON into a
     // UnableToDecryptReason
@@ -963,6 +966,10 @@ pub enum UnableToDecryptReason {
         /// If the key was withheld on purpose, the associated code. `None`
         /// means no withheld code was received.
         withheld_code: Option<WithheldCode>,
+
+        /// If the key was withheld on purpose, who told us about it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        withheld_by: Option<WithheldSender>,
     },
 
     /// Decryption failed because, while we have the megolm session that was
@@ -989,6 +996,10 @@ pub enum UnableToDecryptReason {
     /// cross-signing identity did not satisfy the requested
     /// `TrustRequirement`.
     SenderIdentityNotTrusted(VerificationLevel),
+
+    /// The message index of the megolm session was already used to encrypt
+    /// a different event, which means that the event was replayed.
+    ReplayedMegolmMessage,
 }
 
 impl UnableToDecryptReason {
@@ -999,11 +1010,25 @@ impl UnableToDecryptReason {
         // given that this API is used to decide if waiting a bit will help.
         matches!(
             self,
-            Self::MissingMegolmSession { withheld_code: None } | Self::UnknownMegolmMessageIndex
+            Self::MissingMegolmSession { withheld_code: None, .. }
+                | Self::UnknownMegolmMessageIndex
         )
     }
 }
 
+/// The sender of a `m.room_key.withheld` message, telling us that a room key
+/// was withheld from us.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WithheldSender {
+    /// The user who sent the `m.room_key.withheld` message.
+    pub user_id: OwnedUserId,
+
+    /// The device which sent the `m.room_key.withheld` message, if it told us
+    /// about it (MSC3735).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<OwnedDeviceId>,
+}
+
 /// A machine-readable code for why a Megolm key was not sent.
 ///
 /// Normally sent as the payload of an [`m.room_key.withheld`](https://spec.matrix.org/v1.12/client-server-api/#mroom_keywithheld) to-device message.
@@ -1630,7 +1655,7 @@ mod tests {
             TimelineEventKind::UnableToDecrypt { utd_info, .. }=> {
                 assert_matches!(
                     utd_info.reason,
-                    UnableToDecryptReason::MissingMegolmSession { withheld_code: None }
+                    UnableToDecryptReason::MissingMegolmSession { withheld_code: None, .. }
                 );
             }
         )
@@ -1649,7 +1674,7 @@ mod tests {
         assert_eq!(deserialized.session_id, session_id);
         assert_eq!(
             deserialized.reason,
-            UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+            UnableToDecryptReason::MissingMegolmSession { withheld_code: None, withheld_by: None },
         );
 
         let new_format = json!({
@@ -1665,18 +1690,20 @@ mod tests {
 
         assert_eq!(
             deserialized.reason,
-            UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+            UnableToDecryptReason::MissingMegolmSession { withheld_code: None, withheld_by: None },
         );
         assert_eq!(deserialized.session_id, session_id);
     }
 
     #[test]
     fn unable_to_decrypt_reason_is_missing_room_key() {
-        let reason = UnableToDecryptReason::MissingMegolmSession { withheld_code: None };
+        let reason =
+            UnableToDecryptReason::MissingMegolmSession { withheld_code: None, withheld_by: None };
         assert!(reason.is_missing_room_key());
 
         let reason = UnableToDecryptReason::MissingMegolmSession {
             withheld_code: Some(WithheldCode::Blacklisted),
+            withheld_by: None,
         };
         assert!(!reason.is_missing_room_key());
 
@@ -1834,6 +1861,7 @@ mod tests {
                         session_id: Some("xyz".to_owned()),
                         reason: UnableToDecryptReason::MissingMegolmSession {
                             withheld_code: Some(WithheldCode::Unverified),
+                            withheld_by: None,
                         },
                     }),
                 )])),
@@ -50,12 +50,17 @@ use std::{
 use tokio::sync::Mutex;
 use tracing::{debug, error, instrument, trace};
 
+#[cfg(all(target_family = "wasm", feature = "js"))]
+pub use self::web_locks::{WebLockError, WebLocksBackingStore};
 use crate::{
     executor::{spawn, JoinHandle},
     sleep::sleep,
     SendOutsideWasm,
 };
 
+#[cfg(all(target_family = "wasm", feature = "js"))]
+mod web_locks;
+
 /// Backing store for a cross-process lock.
 pub trait BackingStore {
     #[cfg(not(target_family = "wasm"))]
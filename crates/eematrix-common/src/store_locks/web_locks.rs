@@ -0,0 +1,279 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A [`BackingStore`] using the [Web Locks API], so multiple tabs or workers
+//! of a web client can coordinate their access to a store, like multiple
+//! processes of a native client do.
+//!
+//! A Web Lock is held until the promise returned by its callback resolves, and
+//! it's released by the browser if the tab holding it goes away. The leases
+//! handed out by [`CrossProcessStoreLock`] are mapped onto this: the Web Lock
+//! is taken when the lease is first acquired, and released once the lease
+//! expires without being extended, or right away when the lease is cancelled
+//! with a 0ms lease.
+//!
+//! [Web Locks API]: https://developer.mozilla.org/en-US/docs/Web/API/Web_Locks_API
+//! [`CrossProcessStoreLock`]: super::CrossProcessStoreLock
+
+use std::{cell::RefCell, collections::HashMap, pin::pin, rc::Rc, time::Duration};
+
+use futures_util::{
+    channel::{mpsc, oneshot},
+    future::select,
+    StreamExt,
+};
+use js_sys::{Date, Function, Object, Promise, Reflect};
+use thiserror::Error;
+use tracing::{error, trace};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::{future_to_promise, spawn_local, JsFuture};
+
+use super::BackingStore;
+use crate::sleep::sleep;
+
+/// An error from the Web Locks API.
+#[derive(Debug, Error)]
+pub enum WebLockError {
+    /// The Web Locks API isn't available, e.g. because the page isn't served
+    /// over a secure context.
+    #[error("the Web Locks API isn't available")]
+    Unsupported,
+
+    /// A call to the Web Locks API failed.
+    #[error("the Web Locks API failed: {0}")]
+    Js(String),
+}
+
+impl From<JsValue> for WebLockError {
+    fn from(value: JsValue) -> Self {
+        Self::Js(format!("{value:?}"))
+    }
+}
+
+/// A lease on a Web Lock held by this tab.
+#[derive(Debug)]
+struct Lease {
+    /// The holder of the lease.
+    holder: String,
+
+    /// When the lease expires, in milliseconds since the Unix epoch, unless
+    /// it's extended.
+    expires_at: f64,
+
+    /// Wakes up the task holding the Web Lock, when the lease is shortened.
+    wake: mpsc::UnboundedSender<()>,
+}
+
+type Leases = Rc<RefCell<HashMap<String, Lease>>>;
+
+/// A [`BackingStore`] using the [Web Locks API].
+///
+/// Web Locks are shared by all the tabs and workers of an origin, so the
+/// names of the locks are prefixed with the given prefix, which should
+/// identify the store that is being locked.
+///
+/// [Web Locks API]: https://developer.mozilla.org/en-US/docs/Web/API/Web_Locks_API
+#[derive(Clone, Debug)]
+pub struct WebLocksBackingStore {
+    prefix: String,
+    leases: Leases,
+}
+
+impl WebLocksBackingStore {
+    /// Create a new [`WebLocksBackingStore`], prefixing the names of the Web
+    /// Locks with the given prefix.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self { prefix: prefix.into(), leases: Default::default() }
+    }
+
+    /// Is the Web Locks API available in the current context?
+    pub fn is_supported() -> bool {
+        lock_manager().is_ok()
+    }
+
+    fn lock_name(&self, key: &str) -> String {
+        format!("{}:{key}", self.prefix)
+    }
+}
+
+impl BackingStore for WebLocksBackingStore {
+    type LockError = WebLockError;
+
+    async fn try_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool, Self::LockError> {
+        let name = self.lock_name(key);
+        let expires_at = Date::now() + f64::from(lease_duration_ms);
+
+        if let Some(lease) = self.leases.borrow_mut().get_mut(&name) {
+            // This tab holds the Web Lock already, only the holder of the lease may extend
+            // it.
+            if lease.holder != holder {
+                return Ok(false);
+            }
+
+            if expires_at < lease.expires_at {
+                // The task holding the Web Lock is waiting for the previous expiry, e.g.
+                // because the lease is being cancelled.
+                let _ = lease.wake.unbounded_send(());
+            }
+
+            lease.expires_at = expires_at;
+            return Ok(true);
+        }
+
+        let locks = lock_manager()?;
+        let options = Object::new();
+        Reflect::set(&options, &"ifAvailable".into(), &JsValue::TRUE)?;
+
+        let (sender, receiver) = oneshot::channel();
+        let leases = self.leases.clone();
+        let (wake, wakes) = mpsc::unbounded();
+        let lease = Lease { holder: holder.to_owned(), expires_at, wake };
+        let lock_name = name.clone();
+
+        let callback = Closure::once_into_js(move |lock: JsValue| -> JsValue {
+            // With `ifAvailable`, the callback is called with `null` if another tab or
+            // worker holds the lock.
+            if lock.is_null() {
+                let _ = sender.send(false);
+                return JsValue::UNDEFINED;
+            }
+
+            leases.borrow_mut().insert(lock_name.clone(), lease);
+            let _ = sender.send(true);
+
+            // The lock is held until the returned promise resolves.
+            future_to_promise(async move {
+                hold_until_expired(&leases, &lock_name, wakes).await;
+                Ok(JsValue::UNDEFINED)
+            })
+            .into()
+        });
+
+        let request: Function = Reflect::get(&locks, &"request".into())?.dyn_into()?;
+        let promise: Promise =
+            request.call3(&locks, &name.clone().into(), &options, &callback)?.dyn_into()?;
+
+        // The promise resolves once the lock is released, and is rejected if the lock
+        // couldn't be requested, in which case the callback is never called.
+        spawn_local(async move {
+            if let Err(error) = JsFuture::from(promise).await {
+                error!(%name, ?error, "Couldn't request a Web Lock");
+            }
+        });
+
+        receiver.await.map_err(|_| WebLockError::Js("the Web Lock request failed".to_owned()))
+    }
+}
+
+/// Hold on to the Web Lock with the given name until its lease expires.
+///
+/// `wakes` receives a message when the lease is shortened, so the expiry is
+/// checked again without waiting for the previous one.
+async fn hold_until_expired(leases: &Leases, name: &str, mut wakes: mpsc::UnboundedReceiver<()>) {
+    loop {
+        let Some(expires_at) = leases.borrow().get(name).map(|lease| lease.expires_at) else {
+            return;
+        };
+
+        let remaining = expires_at - Date::now();
+        if remaining <= 0.0 {
+            break;
+        }
+
+        let sleep = pin!(sleep(Duration::from_millis(remaining.ceil() as u64)));
+        select(sleep, wakes.next()).await;
+    }
+
+    leases.borrow_mut().remove(name);
+    trace!(%name, "The lease expired, releasing the Web Lock");
+}
+
+/// Get the `LockManager` of the current context.
+fn lock_manager() -> Result<JsValue, WebLockError> {
+    let navigator = Reflect::get(&js_sys::global(), &"navigator".into())?;
+    if navigator.is_undefined() {
+        return Err(WebLockError::Unsupported);
+    }
+
+    let locks = Reflect::get(&navigator, &"locks".into())?;
+    if locks.is_undefined() || locks.is_null() {
+        return Err(WebLockError::Unsupported);
+    }
+
+    Ok(locks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use matrix_sdk_test_macros::async_test;
+
+    use super::{BackingStore, WebLocksBackingStore};
+    use crate::sleep::sleep;
+
+    wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
+    #[async_test]
+    async fn test_lease_is_exclusive() {
+        let first = WebLocksBackingStore::new("test_lease_is_exclusive");
+        let second = WebLocksBackingStore::new("test_lease_is_exclusive");
+
+        assert!(first.try_lock(10_000, "key", "first").await.unwrap());
+
+        // Only the holder of the lease can extend it.
+        assert!(first.try_lock(10_000, "key", "first").await.unwrap());
+        assert!(!first.try_lock(10_000, "key", "second").await.unwrap());
+
+        // The Web Lock is held, for other stores using the same prefix too.
+        assert!(!second.try_lock(10_000, "key", "second").await.unwrap());
+
+        // Other keys, or other prefixes, use other Web Locks.
+        assert!(second.try_lock(10_000, "other_key", "second").await.unwrap());
+        let other = WebLocksBackingStore::new("test_lease_is_exclusive_other");
+        assert!(other.try_lock(10_000, "key", "second").await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_lease_expires() {
+        let first = WebLocksBackingStore::new("test_lease_expires");
+        let second = WebLocksBackingStore::new("test_lease_expires");
+
+        assert!(first.try_lock(50, "key", "first").await.unwrap());
+        assert!(!second.try_lock(50, "key", "second").await.unwrap());
+
+        // Once the lease expires without being extended, the Web Lock is released.
+        sleep(Duration::from_millis(200)).await;
+        assert!(second.try_lock(50, "key", "second").await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_cancelled_lease_releases_the_lock() {
+        let first = WebLocksBackingStore::new("test_cancelled_lease");
+        let second = WebLocksBackingStore::new("test_cancelled_lease");
+
+        assert!(first.try_lock(10_000, "key", "first").await.unwrap());
+
+        // Cancelling the lease with a 0ms lease releases the Web Lock right away, not
+        // when the previous lease would have expired.
+        assert!(first.try_lock(0, "key", "first").await.unwrap());
+        sleep(Duration::from_millis(50)).await;
+        assert!(second.try_lock(10_000, "key", "second").await.unwrap());
+    }
+}
@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use matrix_sdk_crypto::fuzzing::FuzzTarget;
+
+fuzz_target!(|data: &[u8]| FuzzTarget::PickledInboundGroupSession.run(data));
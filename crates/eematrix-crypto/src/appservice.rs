@@ -0,0 +1,449 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Submodule for appservice support.
+//!
+//! Appservices, for example bridges, act on behalf of many users at once: the
+//! users in their namespace are masqueraded by the appservice and don't log in
+//! by themselves. A bridge can easily have thousands of such users, most of
+//! which are never involved in an encrypted room.
+//!
+//! Instead of creating an [`OlmMachine`] for each of those users upfront, an
+//! [`AppserviceOlmMachine`] creates them lazily, the first time one of them is
+//! needed. The account of a user, and with it their device keys, is only
+//! created when the store doesn't contain one for the user yet.
+//!
+//! The crypto state of all the users is kept in a single
+//! [`AppserviceCryptoStore`], which hands out a view for every user.
+
+use std::{collections::HashMap, fmt, num::NonZeroUsize, sync::Arc};
+
+use matrix_sdk_common::{locks::Mutex as StdMutex, SendOutsideWasm, SyncOutsideWasm};
+use ruma::{DeviceId, OwnedDeviceId, OwnedServerName, OwnedUserId, ServerName, UserId};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::{debug, instrument};
+
+use crate::{
+    store::{DynCryptoStore, IntoCryptoStore, MemoryStore},
+    CryptoStoreError, OlmMachine,
+};
+
+/// The number of machines an [`AppserviceOlmMachine`] keeps loaded by
+/// default, see [`AppserviceOlmMachine::set_max_loaded_users()`].
+const DEFAULT_MAX_LOADED_USERS: usize = 1000;
+
+/// Error type for the [`AppserviceOlmMachine`].
+#[derive(Debug, Error)]
+pub enum AppserviceError {
+    /// The user isn't part of the namespace of the appservice.
+    #[error("The user {0} isn't part of the namespace of the appservice")]
+    UserNotInNamespace(OwnedUserId),
+
+    /// The store ran into an error.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// A crypto store holding the crypto state of all the users masqueraded by an
+/// appservice.
+///
+/// Every user gets their own view on the store, which implements
+/// [`CryptoStore`] and only ever contains the data of that user. The views
+/// share the underlying database, so the appservice only has to open a single
+/// store.
+///
+/// [`CryptoStore`]: crate::store::CryptoStore
+pub trait AppserviceCryptoStore: fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// Get the view on the store holding the crypto state of the given user.
+    fn store_for_user(&self, user_id: &UserId) -> Result<Arc<DynCryptoStore>, CryptoStoreError>;
+}
+
+/// An [`AppserviceCryptoStore`] keeping the crypto state of every user in a
+/// [`MemoryStore`].
+///
+/// Nothing is persisted, so this is mostly useful for tests.
+#[derive(Debug, Default)]
+pub struct MemoryAppserviceStore {
+    stores: StdMutex<HashMap<OwnedUserId, Arc<DynCryptoStore>>>,
+}
+
+impl AppserviceCryptoStore for MemoryAppserviceStore {
+    fn store_for_user(&self, user_id: &UserId) -> Result<Arc<DynCryptoStore>, CryptoStoreError> {
+        Ok(self
+            .stores
+            .lock()
+            .entry(user_id.to_owned())
+            .or_insert_with(|| MemoryStore::new().into_crypto_store())
+            .clone())
+    }
+}
+
+impl OlmMachine {
+    /// Create an [`AppserviceOlmMachine`] which manages the machines of the
+    /// users an appservice masquerades as.
+    ///
+    /// The machine of a user is only created once it's first requested, see
+    /// [`AppserviceOlmMachine::machine_for_user()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `server_name` - The server name of the users of the appservice.
+    ///
+    /// * `user_localpart_pattern` - The pattern the localparts of the users of
+    ///   the appservice match, where `*` matches any sequence of characters,
+    ///   for example `_telegram_*`.
+    ///
+    /// * `device_id` - The device ID all the users of the appservice use.
+    ///
+    /// * `store` - The store holding the crypto state of all the users.
+    pub fn for_appservice(
+        server_name: &ServerName,
+        user_localpart_pattern: impl Into<String>,
+        device_id: &DeviceId,
+        store: Arc<dyn AppserviceCryptoStore>,
+    ) -> AppserviceOlmMachine {
+        AppserviceOlmMachine {
+            inner: Arc::new(AppserviceOlmMachineInner {
+                server_name: server_name.to_owned(),
+                user_localpart_pattern: user_localpart_pattern.into(),
+                device_id: device_id.to_owned(),
+                store,
+                machines: StdMutex::new(LoadedMachines {
+                    max_loaded_users: DEFAULT_MAX_LOADED_USERS,
+                    tick: 0,
+                    machines: HashMap::new(),
+                }),
+            }),
+        }
+    }
+}
+
+/// Manages the [`OlmMachine`]s of the users masqueraded by an appservice.
+///
+/// Create one with [`OlmMachine::for_appservice()`].
+#[derive(Clone, Debug)]
+pub struct AppserviceOlmMachine {
+    inner: Arc<AppserviceOlmMachineInner>,
+}
+
+#[derive(Debug)]
+struct AppserviceOlmMachineInner {
+    server_name: OwnedServerName,
+    user_localpart_pattern: String,
+    device_id: OwnedDeviceId,
+    store: Arc<dyn AppserviceCryptoStore>,
+    machines: StdMutex<LoadedMachines>,
+}
+
+/// The machines of the users which are currently loaded.
+#[derive(Debug)]
+struct LoadedMachines {
+    max_loaded_users: usize,
+    /// Incremented on every access, the machine with the lowest tick is the
+    /// least recently used one.
+    tick: u64,
+    machines: HashMap<OwnedUserId, LoadedMachine>,
+}
+
+#[derive(Debug)]
+struct LoadedMachine {
+    last_used: u64,
+    /// Initialized once the machine is loaded, concurrent callers wait for
+    /// the first one to load it.
+    machine: Arc<OnceCell<OlmMachine>>,
+}
+
+impl LoadedMachines {
+    /// Get the cell holding the machine of the given user, adding an empty one
+    /// if the machine isn't loaded yet.
+    fn cell(&mut self, user_id: &UserId) -> Arc<OnceCell<OlmMachine>> {
+        self.tick += 1;
+
+        let loaded = self
+            .machines
+            .entry(user_id.to_owned())
+            .or_insert_with(|| LoadedMachine { last_used: 0, machine: Default::default() });
+        loaded.last_used = self.tick;
+
+        loaded.machine.clone()
+    }
+
+    /// Unload the least recently used machines until no more than the maximum
+    /// are loaded.
+    ///
+    /// Machines which are still being loaded are kept, so a user never ends
+    /// up with two machines being loaded at the same time.
+    fn evict(&mut self) {
+        while self.machines.len() > self.max_loaded_users {
+            let Some(user_id) = self
+                .machines
+                .iter()
+                .filter(|(_, loaded)| loaded.machine.initialized())
+                .min_by_key(|(_, loaded)| loaded.last_used)
+                .map(|(user_id, _)| user_id.clone())
+            else {
+                break;
+            };
+
+            debug!(?user_id, "Unloading the least recently used machine of an appservice user");
+            self.machines.remove(&user_id);
+        }
+    }
+}
+
+impl AppserviceOlmMachine {
+    /// The server name of the users of this appservice.
+    pub fn server_name(&self) -> &ServerName {
+        &self.inner.server_name
+    }
+
+    /// The pattern the localparts of the users of this appservice match.
+    pub fn user_localpart_pattern(&self) -> &str {
+        &self.inner.user_localpart_pattern
+    }
+
+    /// The device ID the users of this appservice use.
+    pub fn device_id(&self) -> &DeviceId {
+        &self.inner.device_id
+    }
+
+    /// Set the maximum number of machines which are kept loaded, 1000 by
+    /// default.
+    ///
+    /// Once more machines are loaded, the least recently used ones are
+    /// unloaded. Their data stays in the store, they are loaded again by the
+    /// next call to [`AppserviceOlmMachine::machine_for_user()`].
+    pub fn set_max_loaded_users(&self, max_loaded_users: NonZeroUsize) {
+        let mut machines = self.inner.machines.lock();
+        machines.max_loaded_users = max_loaded_users.get();
+        machines.evict();
+    }
+
+    /// Is the given user part of the namespace of this appservice?
+    pub fn is_user_in_namespace(&self, user_id: &UserId) -> bool {
+        user_id.server_name() == self.inner.server_name
+            && localpart_matches(&self.inner.user_localpart_pattern, user_id.localpart())
+    }
+
+    /// Get the [`OlmMachine`] of the given user.
+    ///
+    /// The machine is loaded the first time it's requested, creating a new
+    /// account for the user if the store doesn't contain one yet. Later calls
+    /// return the same machine, until it's unloaded.
+    ///
+    /// The machines of different users are loaded concurrently. The returned
+    /// machine shouldn't be kept around, but requested again when it's
+    /// needed, so an unloaded machine isn't used next to its replacement.
+    #[instrument(skip(self))]
+    pub async fn machine_for_user(&self, user_id: &UserId) -> Result<OlmMachine, AppserviceError> {
+        if !self.is_user_in_namespace(user_id) {
+            return Err(AppserviceError::UserNotInNamespace(user_id.to_owned()));
+        }
+
+        let cell = self.inner.machines.lock().cell(user_id);
+
+        let result = cell
+            .get_or_try_init(|| async {
+                debug!("Loading the machine of an appservice user");
+
+                let store = self.inner.store.store_for_user(user_id)?;
+                OlmMachine::with_store(user_id, &self.inner.device_id, store, None).await
+            })
+            .await
+            .cloned();
+
+        let mut machines = self.inner.machines.lock();
+
+        match result {
+            Ok(machine) => {
+                machines.evict();
+                Ok(machine)
+            }
+            Err(error) => {
+                // Don't keep the empty cell around, the next call tries again.
+                if machines.machines.get(user_id).is_some_and(|loaded| {
+                    Arc::ptr_eq(&loaded.machine, &cell) && !cell.initialized()
+                }) {
+                    machines.machines.remove(user_id);
+                }
+
+                Err(error.into())
+            }
+        }
+    }
+
+    /// Get the users whose [`OlmMachine`] is currently loaded.
+    pub fn loaded_users(&self) -> Vec<OwnedUserId> {
+        self.inner
+            .machines
+            .lock()
+            .machines
+            .iter()
+            .filter(|(_, loaded)| loaded.machine.initialized())
+            .map(|(user_id, _)| user_id.clone())
+            .collect()
+    }
+
+    /// Unload the [`OlmMachine`] of the given user, for example if the user
+    /// hasn't been active in a while.
+    ///
+    /// The data of the user stays in the store, the machine is loaded again
+    /// by the next call to [`AppserviceOlmMachine::machine_for_user()`].
+    pub fn unload_user(&self, user_id: &UserId) -> Option<OlmMachine> {
+        let loaded = self.inner.machines.lock().machines.remove(user_id)?;
+        loaded.machine.get().cloned()
+    }
+}
+
+/// Check if the given localpart matches the pattern, where `*` in the pattern
+/// matches any, possibly empty, sequence of characters.
+fn localpart_matches(pattern: &str, localpart: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // `split()` always returns at least one part.
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = localpart.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+
+    let Some(last) = parts.pop() else {
+        // There was no `*` in the pattern, so it has to match exactly.
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, sync::Arc};
+
+    use assert_matches2::assert_matches;
+    use futures_util::future::join_all;
+    use matrix_sdk_test::async_test;
+    use ruma::{device_id, server_name, user_id};
+
+    use super::{localpart_matches, AppserviceError, AppserviceOlmMachine, MemoryAppserviceStore};
+    use crate::OlmMachine;
+
+    fn appservice() -> AppserviceOlmMachine {
+        OlmMachine::for_appservice(
+            server_name!("example.org"),
+            "_bridge_*",
+            device_id!("BRIDGE"),
+            Arc::new(MemoryAppserviceStore::default()),
+        )
+    }
+
+    #[test]
+    fn test_localpart_matches() {
+        assert!(localpart_matches("bot", "bot"));
+        assert!(!localpart_matches("bot", "bots"));
+        assert!(localpart_matches("_telegram_*", "_telegram_1234"));
+        assert!(localpart_matches("_telegram_*", "_telegram_"));
+        assert!(!localpart_matches("_telegram_*", "telegram_1234"));
+        assert!(localpart_matches("*_bridge", "irc_bridge"));
+        assert!(localpart_matches("_irc_*_*", "_irc_libera_alice"));
+        assert!(!localpart_matches("_irc_*_*", "_irc_libera"));
+        assert!(!localpart_matches("a*a", "a"));
+    }
+
+    #[async_test]
+    async fn test_appservice_machines_are_created_lazily() {
+        let appservice = appservice();
+
+        assert!(appservice.loaded_users().is_empty());
+
+        let alice = user_id!("@_bridge_alice:example.org");
+        let machine = appservice.machine_for_user(alice).await.unwrap();
+        assert_eq!(machine.user_id(), alice);
+        assert_eq!(machine.device_id(), device_id!("BRIDGE"));
+
+        // The same machine, with the same keys, is returned for later calls.
+        let again = appservice.machine_for_user(alice).await.unwrap();
+        assert_eq!(machine.identity_keys().curve25519, again.identity_keys().curve25519);
+        assert_eq!(appservice.loaded_users(), vec![alice.to_owned()]);
+
+        let bob = appservice.machine_for_user(user_id!("@_bridge_bob:example.org")).await.unwrap();
+        assert_ne!(machine.identity_keys().curve25519, bob.identity_keys().curve25519);
+
+        assert!(appservice.unload_user(alice).is_some());
+        assert_eq!(appservice.loaded_users().len(), 1);
+
+        // Once it's loaded again, the machine uses the account from the store.
+        let reloaded = appservice.machine_for_user(alice).await.unwrap();
+        assert_eq!(machine.identity_keys().curve25519, reloaded.identity_keys().curve25519);
+    }
+
+    #[async_test]
+    async fn test_appservice_concurrent_loads_share_the_machine() {
+        let appservice = appservice();
+        let alice = user_id!("@_bridge_alice:example.org");
+
+        let machines = join_all((0..5).map(|_| appservice.machine_for_user(alice))).await;
+        let keys: Vec<_> = machines
+            .into_iter()
+            .map(|machine| machine.unwrap().identity_keys().curve25519)
+            .collect();
+
+        assert!(keys.iter().all(|key| *key == keys[0]));
+        assert_eq!(appservice.loaded_users().len(), 1);
+    }
+
+    #[async_test]
+    async fn test_appservice_unloads_least_recently_used_machines() {
+        let appservice = appservice();
+        appservice.set_max_loaded_users(NonZeroUsize::new(2).unwrap());
+
+        let alice = user_id!("@_bridge_alice:example.org");
+        let bob = user_id!("@_bridge_bob:example.org");
+        let carol = user_id!("@_bridge_carol:example.org");
+
+        appservice.machine_for_user(alice).await.unwrap();
+        appservice.machine_for_user(bob).await.unwrap();
+        // Alice was used more recently than Bob.
+        appservice.machine_for_user(alice).await.unwrap();
+        appservice.machine_for_user(carol).await.unwrap();
+
+        let mut loaded = appservice.loaded_users();
+        loaded.sort();
+        assert_eq!(loaded, [alice.to_owned(), carol.to_owned()]);
+
+        appservice.set_max_loaded_users(NonZeroUsize::MIN);
+        assert_eq!(appservice.loaded_users(), [carol.to_owned()]);
+    }
+
+    #[async_test]
+    async fn test_appservice_rejects_users_outside_namespace() {
+        let appservice = appservice();
+
+        for user_id in [user_id!("@alice:example.org"), user_id!("@_bridge_alice:other.org")] {
+            assert!(!appservice.is_user_in_namespace(user_id));
+            assert_matches!(
+                appservice.machine_for_user(user_id).await,
+                Err(AppserviceError::UserNotInNamespace(_))
+            );
+        }
+    }
+}
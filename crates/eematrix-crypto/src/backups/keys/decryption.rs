@@ -18,6 +18,8 @@ use std::{
 };
 
 use ruma::api::client::backup::EncryptedSessionData;
+#[cfg(feature = "experimental-symmetric-backup")]
+use ruma::serde::Base64;
 use thiserror::Error;
 use vodozemac::{
     pk_encryption::{Message, PkDecryption},
@@ -26,10 +28,15 @@ use vodozemac::{
 use zeroize::{Zeroize, Zeroizing};
 
 use super::MegolmV1BackupKey;
+#[cfg(feature = "experimental-symmetric-backup")]
+use crate::{
+    ciphers::{AesHmacSha2Key, HmacSha256Mac, IV_SIZE},
+    types::{MegolmV2AuthData, MegolmV2EncryptedSessionData},
+};
 use crate::{
     olm::BackedUpRoomKey,
     store::types::BackupDecryptionKey,
-    types::{MegolmV1AuthData, RoomKeyBackupInfo},
+    types::{MegolmV1AuthData, RoomKeyBackupInfo, SecretString},
 };
 
 /// Error type for the decoding of a [`BackupDecryptionKey`].
@@ -71,6 +78,15 @@ pub enum DecryptionError {
     /// plaintext isn't valid JSON.
     #[error("The decrypted message isn't valid JSON: {0}")]
     Json(#[from] serde_json::error::Error),
+    /// The MAC of a symmetrically encrypted message didn't pass validation.
+    #[cfg(feature = "experimental-symmetric-backup")]
+    #[error("The MAC of the ciphertext didn't pass validation")]
+    Mac(#[from] hmac::digest::MacError),
+    /// The initialization vector or the MAC of a symmetrically encrypted
+    /// message has an invalid length.
+    #[cfg(feature = "experimental-symmetric-backup")]
+    #[error("The initialization vector or the MAC has an invalid length")]
+    InvalidLength,
 }
 
 impl TryFrom<String> for BackupDecryptionKey {
@@ -83,10 +99,11 @@ impl TryFrom<String> for BackupDecryptionKey {
 
 impl std::fmt::Display for BackupDecryptionKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let string = Zeroizing::new(self.to_base58());
+        let string = self.to_base58();
 
         let string = Zeroizing::new(
             string
+                .expose_secret()
                 .chars()
                 .collect::<Vec<char>>()
                 .chunks(Self::DISPLAY_CHUNK_SIZE)
@@ -173,8 +190,28 @@ impl BackupDecryptionKey {
         }
     }
 
+    /// Try to create a [`BackupDecryptionKey`] from a recovery phrase created
+    /// by [`BackupDecryptionKey::to_mnemonic()`].
+    ///
+    /// See the [`recovery`](crate::recovery) module for details about the
+    /// format.
+    #[cfg(feature = "mnemonic")]
+    pub fn from_mnemonic(phrase: &str) -> Result<Self, crate::recovery::MnemonicError> {
+        let key = crate::recovery::decode_mnemonic(phrase)?;
+        Ok(Self::from_boxed_bytes(key))
+    }
+
+    /// Export the [`BackupDecryptionKey`] as a recovery phrase of 24 words.
+    ///
+    /// See the [`recovery`](crate::recovery) module for details about the
+    /// format.
+    #[cfg(feature = "mnemonic")]
+    pub fn to_mnemonic(&self) -> SecretString {
+        crate::recovery::encode_mnemonic(&self.inner)
+    }
+
     /// Export the `[`BackupDecryptionKey`] as a base58 encoded string.
-    pub fn to_base58(&self) -> String {
+    pub fn to_base58(&self) -> SecretString {
         let bytes = Zeroizing::new(
             [
                 Self::PREFIX.as_ref(),
@@ -184,7 +221,7 @@ impl BackupDecryptionKey {
             .concat(),
         );
 
-        bs58::encode(bytes.as_slice()).with_alphabet(bs58::Alphabet::BITCOIN).into_string()
+        bs58::encode(bytes.as_slice()).with_alphabet(bs58::Alphabet::BITCOIN).into_string().into()
     }
 
     fn get_pk_decryption(&self) -> PkDecryption {
@@ -263,11 +300,116 @@ impl BackupDecryptionKey {
 
                 info.public_key == public_key
             }
+            #[cfg(feature = "experimental-symmetric-backup")]
+            RoomKeyBackupInfo::MegolmBackupV2AesHmacSha2(info) => {
+                self.check_key_v2(&info.iv, &info.mac).is_ok()
+            }
             RoomKeyBackupInfo::Other { .. } => false,
         }
     }
 }
 
+#[cfg(feature = "experimental-symmetric-backup")]
+impl BackupDecryptionKey {
+    const ZERO_MESSAGE: &'static [u8; 32] = &[0u8; 32];
+
+    /// Get the [`RoomKeyBackupInfo`] for a
+    /// `org.matrix.msc3270.v1.aes-hmac-sha2` backup, using this
+    /// [`BackupDecryptionKey`] as the symmetric backup key.
+    ///
+    /// The [`RoomKeyBackupInfo`] can be uploaded to the homeserver to activate
+    /// a new backup version.
+    pub fn to_backup_info_v2(&self) -> RoomKeyBackupInfo {
+        let key = AesHmacSha2Key::from_secret_storage_key(&self.inner, "");
+
+        let (ciphertext, iv) = key.encrypt(Self::ZERO_MESSAGE.to_vec());
+        let mac = key.create_mac_tag(&ciphertext);
+
+        let auth_data = MegolmV2AuthData::new(
+            Base64::new(iv.to_vec()),
+            Base64::new(mac.as_bytes().to_vec()),
+            Default::default(),
+        );
+
+        RoomKeyBackupInfo::MegolmBackupV2AesHmacSha2(auth_data)
+    }
+
+    /// Encrypt the given room key, so it can be uploaded to a
+    /// `org.matrix.msc3270.v1.aes-hmac-sha2` backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The ID of the session the room key belongs to. A
+    ///   separate key is derived for every session.
+    ///
+    /// * `room_key` - The room key that should be encrypted.
+    pub fn encrypt_session_data_v2(
+        &self,
+        session_id: &str,
+        room_key: &BackedUpRoomKey,
+    ) -> MegolmV2EncryptedSessionData {
+        let key = AesHmacSha2Key::from_secret_storage_key(&self.inner, session_id);
+
+        // The plaintext is encrypted in place, so it doesn't need to be zeroized.
+        let plaintext = serde_json::to_vec(room_key).expect("Can't serialize a room key");
+
+        let (ciphertext, iv) = key.encrypt(plaintext);
+        let mac = key.create_mac_tag(&ciphertext);
+
+        MegolmV2EncryptedSessionData {
+            iv: Base64::new(iv.to_vec()),
+            ciphertext: Base64::new(ciphertext),
+            mac: Base64::new(mac.as_bytes().to_vec()),
+        }
+    }
+
+    /// Try to decrypt a room key from a `org.matrix.msc3270.v1.aes-hmac-sha2`
+    /// backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The ID of the session the room key belongs to.
+    ///
+    /// * `session_data` - The encrypted room key, as it was found in the
+    ///   backup.
+    pub fn decrypt_session_data_v2(
+        &self,
+        session_id: &str,
+        session_data: &MegolmV2EncryptedSessionData,
+    ) -> Result<BackedUpRoomKey, DecryptionError> {
+        let key = AesHmacSha2Key::from_secret_storage_key(&self.inner, session_id);
+
+        let iv = Self::iv_from_base64(&session_data.iv)?;
+        let mac = HmacSha256Mac::from_slice(session_data.mac.as_bytes())
+            .ok_or(DecryptionError::InvalidLength)?;
+
+        let ciphertext = session_data.ciphertext.as_bytes().to_vec();
+        key.verify_mac(&ciphertext, mac.as_bytes())?;
+
+        let decrypted = Zeroizing::new(key.decrypt(ciphertext, &iv));
+
+        Ok(serde_json::from_slice(&decrypted)?)
+    }
+
+    /// Check that this [`BackupDecryptionKey`] is the key of the backup the
+    /// given key check belongs to.
+    fn check_key_v2(&self, iv: &Base64, mac: &Base64) -> Result<(), DecryptionError> {
+        let key = AesHmacSha2Key::from_secret_storage_key(&self.inner, "");
+
+        let iv = Self::iv_from_base64(iv)?;
+        let mac =
+            HmacSha256Mac::from_slice(mac.as_bytes()).ok_or(DecryptionError::InvalidLength)?;
+
+        let ciphertext = key.apply_keystream(Self::ZERO_MESSAGE.to_vec(), &iv);
+
+        Ok(key.verify_mac(&ciphertext, mac.as_bytes())?)
+    }
+
+    fn iv_from_base64(iv: &Base64) -> Result<[u8; IV_SIZE], DecryptionError> {
+        iv.as_bytes().try_into().map_err(|_| DecryptionError::InvalidLength)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use matrix_sdk_test::async_test;
@@ -318,8 +460,8 @@ mod tests {
     fn base58_decoding() -> Result<(), DecodeError> {
         let key = BackupDecryptionKey::new().expect("Can't create a new recovery key");
 
-        let base64 = key.to_base58();
-        let decoded_key = BackupDecryptionKey::from_base58(&base64)?;
+        let base58 = key.to_base58();
+        let decoded_key = BackupDecryptionKey::from_base58(base58.expose_secret())?;
         assert_eq!(key.inner, decoded_key.inner, "The decode key doesn't match the original");
 
         let test_key =
@@ -415,4 +557,47 @@ mod tests {
             "The backup info should match the decryption key"
         );
     }
+
+    #[cfg(feature = "experimental-symmetric-backup")]
+    #[async_test]
+    async fn test_symmetric_encryption_cycle() {
+        let session = InboundGroupSession::from_export(&room_key()).unwrap();
+        let session_id = session.session_id().to_owned();
+        let room_key = session.to_backup().await;
+
+        let decryption_key = BackupDecryptionKey::new().unwrap();
+        let encrypted = decryption_key.encrypt_session_data_v2(&session_id, &room_key);
+
+        let decrypted = decryption_key
+            .decrypt_session_data_v2(&session_id, &encrypted)
+            .expect("We should be able to decrypt a just encrypted room key");
+        assert_eq!(decrypted.sender_key, room_key.sender_key);
+
+        decryption_key
+            .decrypt_session_data_v2("another session", &encrypted)
+            .expect_err("The room key is bound to its session ID");
+        BackupDecryptionKey::new()
+            .unwrap()
+            .decrypt_session_data_v2(&session_id, &encrypted)
+            .expect_err("A different backup key can't decrypt the room key");
+    }
+
+    #[cfg(feature = "experimental-symmetric-backup")]
+    #[test]
+    fn symmetric_key_matches() {
+        let decryption_key = BackupDecryptionKey::new().unwrap();
+
+        let key_info = decryption_key.to_backup_info_v2();
+        let key_info: crate::types::RoomKeyBackupInfo =
+            serde_json::from_value(serde_json::to_value(key_info).unwrap()).unwrap();
+
+        assert!(
+            decryption_key.backup_key_matches(&key_info),
+            "The backup info should match the decryption key"
+        );
+        assert!(
+            !BackupDecryptionKey::new().unwrap().backup_key_matches(&key_info),
+            "The backup info shouldn't match another decryption key"
+        );
+    }
 }
@@ -25,15 +25,24 @@
 
 use std::{
     collections::{BTreeMap, BTreeSet},
+    pin::pin,
     sync::Arc,
+    time::Duration,
 };
 
+use eyeball::SharedObservable;
+use futures_core::Stream;
+use futures_util::TryStreamExt;
 use ruma::{
-    api::client::backup::RoomKeyBackup, serde::Raw, DeviceId, DeviceKeyAlgorithm, OwnedDeviceId,
-    OwnedRoomId, OwnedTransactionId, RoomId, TransactionId,
+    api::client::backup::{KeyBackupData, RoomKeyBackup},
+    serde::Raw,
+    DeviceId, DeviceKeyAlgorithm, OwnedDeviceId, OwnedRoomId, OwnedTransactionId, RoomId,
+    TransactionId,
 };
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, RwLock};
 use tracing::{debug, info, instrument, trace, warn};
+use vodozemac::megolm::SessionOrdering;
 
 use crate::{
     olm::{BackedUpRoomKey, ExportedRoomKey, InboundGroupSession, SignedJsonObject},
@@ -60,6 +69,31 @@ pub struct BackupMachine {
     store: Store,
     backup_key: Arc<RwLock<Option<MegolmV1BackupKey>>>,
     pending_backup: Arc<RwLock<Option<PendingBackup>>>,
+    /// The scheduling state of [`BackupMachine::backup_batches()`], loaded
+    /// from the store the first time it's needed.
+    backup_schedule: Arc<Mutex<Option<BackupSchedule>>>,
+    state: SharedObservable<BackupState>,
+    /// Whether backups were turned off when the [`OlmMachine`] was built, see
+    /// [`OlmMachineFeatures::backups`].
+    ///
+    /// [`OlmMachine`]: crate::OlmMachine
+    /// [`OlmMachineFeatures::backups`]: crate::OlmMachineFeatures::backups
+    disabled: bool,
+}
+
+/// The state of the server-side key backup, as seen by the [`BackupMachine`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum BackupState {
+    /// No backup has been enabled or disabled yet.
+    #[default]
+    Unknown,
+    /// Room keys are being backed up to the backup with the given version.
+    Enabled(String),
+    /// The backup has been disabled, room keys aren't backed up.
+    Disabled,
+    /// The backup we were backing up to isn't the current backup on the
+    /// server anymore, see [`BackupMachine::mark_backup_version_outdated()`].
+    VersionOutdated,
 }
 
 type SenderKey = String;
@@ -72,6 +106,60 @@ struct PendingBackup {
     sessions: BTreeMap<OwnedRoomId, BTreeMap<SenderKey, BTreeSet<SessionId>>>,
 }
 
+/// The key under which the [`BackupSchedule`] is persisted in the store.
+const BACKUP_SCHEDULE_KEY: &str = "backup_schedule";
+
+/// The scheduling state of [`BackupMachine::backup_batches()`].
+///
+/// This is persisted in the store, so the rate limit and the backoff of room
+/// keys survive restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BackupSchedule {
+    /// When the last backup request was created, in milliseconds since the
+    /// unix epoch.
+    last_request_ts: Option<u64>,
+    /// The room keys which failed to be uploaded.
+    backoff: BTreeMap<OwnedRoomId, BTreeMap<SessionId, SessionBackoff>>,
+}
+
+impl BackupSchedule {
+    /// The room keys which shouldn't be retried yet.
+    fn backed_off_sessions(&self, now: u64) -> BTreeSet<(&RoomId, &str)> {
+        self.backoff
+            .iter()
+            .flat_map(|(room_id, sessions)| std::iter::repeat(room_id).zip(sessions))
+            .filter(|(_, (_, backoff))| backoff.retry_after_ts > now)
+            .map(|(room_id, (session_id, _))| (room_id.as_ref(), session_id.as_str()))
+            .collect()
+    }
+}
+
+/// The backoff state of a single room key that failed to be uploaded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SessionBackoff {
+    /// How many times in a row the upload failed.
+    failures: u32,
+    /// When we may try to upload the room key again, in milliseconds since the
+    /// unix epoch.
+    retry_after_ts: u64,
+}
+
+impl SessionBackoff {
+    const INITIAL_DELAY: Duration = Duration::from_secs(10);
+    const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+    /// Record another failed upload attempt which happened at `now`.
+    fn fail(&mut self, now: u64) {
+        self.failures = self.failures.saturating_add(1);
+
+        let delay = Self::INITIAL_DELAY
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(Self::MAX_DELAY);
+
+        self.retry_after_ts = now.saturating_add(delay.as_millis() as u64);
+    }
+}
+
 /// The result of a signature verification of a signed JSON object.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct SignatureVerification {
@@ -119,6 +207,36 @@ pub enum SignatureState {
     ValidAndTrusted,
 }
 
+/// The result of a [`BackupMachine::audit()`] of the server-side key backup.
+///
+/// Each room key is identified by the ID of its room and its session ID.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupAuditReport {
+    /// The number of room keys in our store that were checked.
+    pub checked: usize,
+    /// Room keys we have in our store which are missing from the backup.
+    pub missing: Vec<(OwnedRoomId, String)>,
+    /// Room keys for which the backup holds a worse copy than we do, i.e. one
+    /// which can't decrypt as many messages as ours.
+    pub stale: Vec<(OwnedRoomId, String)>,
+    /// Room keys in the backup which can't be used, either because they are
+    /// malformed, can't be decrypted, or don't match the key we have.
+    pub corrupted: Vec<(OwnedRoomId, String)>,
+}
+
+impl BackupAuditReport {
+    /// Does the backup contain a usable copy of every room key we have?
+    pub fn is_healthy(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// The problem found with a single room key in the backup.
+enum AuditIssue {
+    Stale,
+    Corrupted,
+}
+
 impl SignatureState {
     /// Is the state considered to be trusted?
     pub fn trusted(self) -> bool {
@@ -133,15 +251,47 @@ impl SignatureState {
 
 impl BackupMachine {
     const BACKUP_BATCH_SIZE: usize = 100;
+    const AUDIT_BATCH_SIZE: usize = 1000;
 
     pub(crate) fn new(store: Store, backup_key: Option<MegolmV1BackupKey>) -> Self {
+        let state = backup_key
+            .as_ref()
+            .and_then(|k| k.backup_version())
+            .map(BackupState::Enabled)
+            .unwrap_or_default();
+
         Self {
             store,
             backup_key: RwLock::new(backup_key).into(),
             pending_backup: RwLock::new(None).into(),
+            backup_schedule: Mutex::new(None).into(),
+            state: SharedObservable::new(state),
+            disabled: false,
         }
     }
 
+    /// Create a [`BackupMachine`] which never activates a backup.
+    pub(crate) fn new_disabled(store: Store) -> Self {
+        Self { disabled: true, ..Self::new(store, None) }
+    }
+
+    /// Get the current state of the backup.
+    ///
+    /// To listen to changes to the [`BackupState`] use the
+    /// [`BackupMachine::state_stream()`] method.
+    pub fn state(&self) -> BackupState {
+        self.state.get()
+    }
+
+    /// Listen for changes to the state of the backup.
+    ///
+    /// A new [`BackupState`] is emitted whenever a backup is enabled or
+    /// disabled, or the current backup is reported as outdated. The current
+    /// state isn't emitted, use [`BackupMachine::state()`] to get it.
+    pub fn state_stream(&self) -> impl Stream<Item = BackupState> {
+        self.state.subscribe()
+    }
+
     /// Are we able to back up room keys to the server?
     pub async fn enabled(&self) -> bool {
         self.backup_key.read().await.as_ref().is_some_and(|b| b.backup_version().is_some())
@@ -385,12 +535,23 @@ impl BackupMachine {
     /// This will use the [`m.megolm_backup.v1.curve25519-aes-sha2`] algorithm
     /// to encrypt the room keys.
     ///
+    /// Does nothing if backups are disabled, see
+    /// [`OlmMachineFeatures::backups`].
+    ///
+    /// [`OlmMachineFeatures::backups`]: crate::OlmMachineFeatures::backups
+    ///
     /// [`m.megolm_backup.v1.curve25519-aes-sha2`]:
     /// https://spec.matrix.org/unstable/client-server-api/#backup-algorithm-mmegolm_backupv1curve25519-aes-sha2
     pub async fn enable_backup_v1(&self, key: MegolmV1BackupKey) -> Result<(), CryptoStoreError> {
-        if key.backup_version().is_some() {
+        if self.disabled {
+            warn!(backup_key = ?key, "Tried to activate a backup, but backups are disabled");
+        } else if key.backup_version().is_some() {
             *self.backup_key.write().await = Some(key.clone());
             info!(backup_key = ?key, "Activated a backup");
+
+            if let Some(version) = key.backup_version() {
+                self.state.set_if_not_eq(BackupState::Enabled(version));
+            }
         } else {
             warn!(backup_key = ?key, "Tried to activate a backup without having the backup key uploaded");
         }
@@ -417,11 +578,33 @@ impl BackupMachine {
 
         self.store.reset_backup_state().await?;
 
+        // An outdated backup is usually disabled because it's outdated, keep
+        // reporting why.
+        if self.state.get() != BackupState::VersionOutdated {
+            self.state.set_if_not_eq(BackupState::Disabled);
+        }
+
         debug!("Done disabling backup");
 
         Ok(())
     }
 
+    /// Report that the backup we are backing up to isn't the current backup on
+    /// the server anymore.
+    ///
+    /// This should be called when the server rejects a backup request because
+    /// of a wrong backup version, or when another client created a new backup
+    /// version. The [`BackupState`] changes to
+    /// [`BackupState::VersionOutdated`] until a new backup is enabled using
+    /// [`BackupMachine::enable_backup_v1()`], disabling the outdated backup
+    /// doesn't change it.
+    pub fn mark_backup_version_outdated(&self) {
+        if matches!(self.state.get(), BackupState::Enabled(_)) {
+            info!("The backup version we're backing up to is outdated");
+            self.state.set(BackupState::VersionOutdated);
+        }
+    }
+
     /// Provide the `backup_version` of the current `backup_key`, or None if
     /// there is no current key, or the key is not used with any backup
     /// version.
@@ -448,6 +631,153 @@ impl BackupMachine {
         self.store.load_backup_keys().await
     }
 
+    /// Check the contents of the server-side key backup against the room keys
+    /// we have in our store.
+    ///
+    /// Every room key we know about is looked up in the given backup
+    /// contents, and reported as missing if the backup doesn't contain it,
+    /// or as stale if the backed up copy can't decrypt as many messages as
+    /// ours. Entries in the backup which can't be deserialized are reported
+    /// as corrupted.
+    ///
+    /// If the backup decryption key is given, every entry in the backup is
+    /// decrypted as well, and entries that fail to decrypt or don't match
+    /// our copy of the room key are reported as corrupted too.
+    ///
+    /// # Arguments
+    ///
+    /// * `backup` - The room keys the server holds for the current backup
+    ///   version, as returned by the [`GET /room_keys/keys`] endpoint.
+    ///
+    /// * `decryption_key` - The decryption key of the backup, if it's
+    ///   available.
+    ///
+    /// [`GET /room_keys/keys`]: https://spec.matrix.org/unstable/client-server-api/#get_matrixclientv3room_keyskeys
+    pub async fn audit(
+        &self,
+        backup: &BTreeMap<OwnedRoomId, RoomKeyBackup>,
+        decryption_key: Option<&BackupDecryptionKey>,
+    ) -> Result<BackupAuditReport, CryptoStoreError> {
+        let mut report = BackupAuditReport::default();
+        let mut seen: BTreeSet<(&RoomId, &str)> = BTreeSet::new();
+
+        let mut sessions =
+            pin!(self.store.get_inbound_group_sessions_stream(Self::AUDIT_BATCH_SIZE));
+
+        while let Some(session) = sessions.try_next().await? {
+            report.checked += 1;
+
+            let room_id = session.room_id();
+            let session_id = session.session_id();
+
+            let Some((room_id_key, room_backup)) = backup.get_key_value(room_id) else {
+                report.missing.push((room_id.to_owned(), session_id.to_owned()));
+                continue;
+            };
+
+            let Some((session_id_key, backed_up)) = room_backup.sessions.get_key_value(session_id)
+            else {
+                report.missing.push((room_id.to_owned(), session_id.to_owned()));
+                continue;
+            };
+
+            seen.insert((room_id_key, session_id_key));
+
+            match Self::audit_backed_up_key(backed_up, decryption_key, Some(&session)).await {
+                Some(AuditIssue::Stale) => {
+                    report.stale.push((room_id.to_owned(), session_id.to_owned()))
+                }
+                Some(AuditIssue::Corrupted) => {
+                    report.corrupted.push((room_id.to_owned(), session_id.to_owned()))
+                }
+                None => {}
+            }
+        }
+
+        // Room keys we don't know about can't be stale, but they can still be
+        // corrupted.
+        for (room_id, room_backup) in backup {
+            for (session_id, backed_up) in &room_backup.sessions {
+                if seen.contains(&(&**room_id, session_id.as_str())) {
+                    continue;
+                }
+
+                if let Some(AuditIssue::Corrupted) =
+                    Self::audit_backed_up_key(backed_up, decryption_key, None).await
+                {
+                    report.corrupted.push((room_id.to_owned(), session_id.to_owned()));
+                }
+            }
+        }
+
+        info!(
+            checked = report.checked,
+            missing = report.missing.len(),
+            stale = report.stale.len(),
+            corrupted = report.corrupted.len(),
+            "Finished auditing the key backup"
+        );
+
+        Ok(report)
+    }
+
+    /// Check a single entry of the backup, optionally against our own copy of
+    /// the room key.
+    async fn audit_backed_up_key(
+        backed_up: &Raw<KeyBackupData>,
+        decryption_key: Option<&BackupDecryptionKey>,
+        session: Option<&InboundGroupSession>,
+    ) -> Option<AuditIssue> {
+        let backed_up = match backed_up.deserialize() {
+            Ok(backed_up) => backed_up,
+            Err(e) => {
+                warn!("Found a malformed room key in the backup: {e:?}");
+                return Some(AuditIssue::Corrupted);
+            }
+        };
+
+        if let Some(decryption_key) = decryption_key {
+            match decryption_key.decrypt_session_data(backed_up.session_data) {
+                Ok(room_key) => {
+                    if let Some(session) = session {
+                        if !Self::is_same_room_key(session, room_key).await {
+                            warn!("Found a room key in the backup which doesn't match ours");
+                            return Some(AuditIssue::Corrupted);
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Found a room key in the backup which can't be decrypted: {e:?}");
+                    return Some(AuditIssue::Corrupted);
+                }
+            }
+        }
+
+        let first_message_index = u64::from(backed_up.first_message_index);
+
+        session
+            .is_some_and(|s| first_message_index > s.first_known_index().into())
+            .then_some(AuditIssue::Stale)
+    }
+
+    /// Check if the decrypted room key from the backup is a copy of the given
+    /// room key.
+    ///
+    /// Besides the sender key, the signing keys, the algorithm and the Megolm
+    /// ratchet itself need to match, see [`InboundGroupSession::compare()`].
+    async fn is_same_room_key(session: &InboundGroupSession, room_key: BackedUpRoomKey) -> bool {
+        let room_key = ExportedRoomKey::from_backed_up_room_key(
+            session.room_id().to_owned(),
+            session.session_id().to_owned(),
+            room_key,
+        );
+
+        match InboundGroupSession::from_export(&room_key) {
+            Ok(backed_up) => session.compare(&backed_up).await != SessionOrdering::Unconnected,
+            Err(_) => false,
+        }
+    }
+
     /// Encrypt a batch of room keys and return a request that needs to be sent
     /// out to backup the room keys.
     pub async fn backup(
@@ -462,13 +792,73 @@ impl BackupMachine {
         } else {
             trace!("Backing up, creating a new request");
 
-            let new_request = self.backup_helper().await?;
+            let new_request = self.backup_helper(Self::BACKUP_BATCH_SIZE, &BTreeSet::new()).await?;
             *request = new_request.clone();
 
             Ok(new_request.map(|r| (r.request_id, r.request)))
         }
     }
 
+    /// Encrypt a batch of room keys and return a request that needs to be sent
+    /// out to backup the room keys, while limiting how often such requests
+    /// are created.
+    ///
+    /// This works like [`BackupMachine::backup()`], but is meant for clients
+    /// which have a large number of room keys to back up. No new request is
+    /// created if the previous one was created less than `min_interval` ago,
+    /// and room keys which recently failed to be uploaded, as reported by
+    /// [`BackupMachine::backup_request_failed()`], are skipped until their
+    /// backoff period has passed.
+    ///
+    /// The scheduling state is persisted in the store, so it survives
+    /// restarts.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch_size` - The maximum number of room keys a single request
+    ///   should contain.
+    ///
+    /// * `min_interval` - The minimum amount of time between the creation of
+    ///   two requests.
+    pub async fn backup_batches(
+        &self,
+        batch_size: usize,
+        min_interval: Duration,
+    ) -> Result<Option<(OwnedTransactionId, KeysBackupRequest)>, CryptoStoreError> {
+        let mut request = self.pending_backup.write().await;
+
+        if let Some(request) = &*request {
+            trace!("Backing up in batches, returning an existing request");
+            return Ok(Some((request.request_id.clone(), request.request.clone())));
+        }
+
+        let mut schedule = self.backup_schedule().await?;
+        let now: u64 = self.store.clock().now().get().into();
+
+        if let Some(last_request_ts) = schedule.last_request_ts {
+            let elapsed = Duration::from_millis(now.saturating_sub(last_request_ts));
+
+            if elapsed < min_interval {
+                trace!(?elapsed, ?min_interval, "Backing up in batches, rate limited");
+                return Ok(None);
+            }
+        }
+
+        trace!("Backing up in batches, creating a new request");
+
+        let new_request =
+            self.backup_helper(batch_size, &schedule.backed_off_sessions(now)).await?;
+
+        if new_request.is_some() {
+            schedule.last_request_ts = Some(now);
+            self.store.set_value(BACKUP_SCHEDULE_KEY, &*schedule).await?;
+        }
+
+        *request = new_request.clone();
+
+        Ok(new_request.map(|r| (r.request_id, r.request)))
+    }
+
     pub(crate) async fn mark_request_as_sent(
         &self,
         request_id: &TransactionId,
@@ -500,6 +890,13 @@ impl BackupMachine {
                     "Marked room keys as backed up"
                 );
 
+                let backed_up_count = room_and_session_ids.len() as u64;
+                self.store
+                    .record_statistics(|statistics| statistics.keys_backed_up += backed_up_count);
+                self.store.flush_statistics().await;
+
+                self.reset_backoff(&r.sessions).await?;
+
                 *request = None;
             } else {
                 warn!(
@@ -518,7 +915,45 @@ impl BackupMachine {
         Ok(())
     }
 
-    async fn backup_helper(&self) -> Result<Option<PendingBackup>, CryptoStoreError> {
+    /// Report that the upload of a backup request failed.
+    ///
+    /// The pending request is discarded, and the room keys it contained are
+    /// backed off exponentially by [`BackupMachine::backup_batches()`].
+    pub async fn backup_request_failed(
+        &self,
+        request_id: &TransactionId,
+    ) -> Result<(), CryptoStoreError> {
+        let mut request = self.pending_backup.write().await;
+
+        let Some(r) = request.as_ref().filter(|r| r.request_id == request_id) else {
+            warn!(?request_id, "Tried to mark a backup as failed but it isn't the pending one");
+            return Ok(());
+        };
+
+        let mut schedule = self.backup_schedule().await?;
+        let now: u64 = self.store.clock().now().get().into();
+
+        for (room_id, sender_key_to_session_ids) in &r.sessions {
+            let room_backoff = schedule.backoff.entry(room_id.to_owned()).or_default();
+
+            for session_id in sender_key_to_session_ids.values().flatten() {
+                room_backoff.entry(session_id.to_owned()).or_default().fail(now);
+            }
+        }
+
+        debug!(?request_id, keys = ?r.sessions, "Backing off room keys which failed to upload");
+
+        self.store.set_value(BACKUP_SCHEDULE_KEY, &*schedule).await?;
+        *request = None;
+
+        Ok(())
+    }
+
+    async fn backup_helper(
+        &self,
+        batch_size: usize,
+        skipped: &BTreeSet<(&RoomId, &str)>,
+    ) -> Result<Option<PendingBackup>, CryptoStoreError> {
         let Some(backup_key) = &*self.backup_key.read().await else {
             warn!("Trying to backup room keys but no backup key was found");
             return Ok(None);
@@ -529,8 +964,15 @@ impl BackupMachine {
             return Ok(None);
         };
 
-        let sessions =
-            self.store.inbound_group_sessions_for_backup(&version, Self::BACKUP_BATCH_SIZE).await?;
+        // Fetch enough room keys to fill up the batch even if all the skipped ones
+        // are included.
+        let mut sessions = self
+            .store
+            .inbound_group_sessions_for_backup(&version, batch_size + skipped.len())
+            .await?;
+
+        sessions.retain(|s| !skipped.contains(&(s.room_id(), s.session_id())));
+        sessions.truncate(batch_size);
 
         if sessions.is_empty() {
             trace!(?backup_key, "No room keys need to be backed up");
@@ -593,6 +1035,46 @@ impl BackupMachine {
         (backup, session_record)
     }
 
+    /// Get the scheduling state of [`BackupMachine::backup_batches()`],
+    /// loading it from the store if this is the first time it's needed.
+    async fn backup_schedule(
+        &self,
+    ) -> Result<MappedMutexGuard<'_, BackupSchedule>, CryptoStoreError> {
+        let mut schedule = self.backup_schedule.lock().await;
+
+        if schedule.is_none() {
+            *schedule = Some(self.store.get_value(BACKUP_SCHEDULE_KEY).await?.unwrap_or_default());
+        }
+
+        Ok(MutexGuard::map(schedule, |s| s.get_or_insert_with(Default::default)))
+    }
+
+    /// Forget the backoff of the given room keys, now that they are backed up.
+    async fn reset_backoff(
+        &self,
+        sessions: &BTreeMap<OwnedRoomId, BTreeMap<SenderKey, BTreeSet<SessionId>>>,
+    ) -> Result<(), CryptoStoreError> {
+        let mut schedule = self.backup_schedule().await?;
+
+        if schedule.backoff.is_empty() {
+            return Ok(());
+        }
+
+        for (room_id, sender_key_to_session_ids) in sessions {
+            if let Some(room_backoff) = schedule.backoff.get_mut(room_id) {
+                for session_id in sender_key_to_session_ids.values().flatten() {
+                    room_backoff.remove(session_id);
+                }
+
+                if room_backoff.is_empty() {
+                    schedule.backoff.remove(room_id);
+                }
+            }
+        }
+
+        self.store.set_value(BACKUP_SCHEDULE_KEY, &*schedule).await
+    }
+
     /// Import the given room keys into our store.
     ///
     /// # Arguments
@@ -637,14 +1119,17 @@ impl BackupMachine {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::BTreeMap;
+    use std::{collections::BTreeMap, time::Duration};
 
     use assert_matches2::assert_let;
+    use futures_util::{FutureExt, StreamExt};
     use matrix_sdk_test::async_test;
-    use ruma::{device_id, room_id, user_id, CanonicalJsonValue, DeviceId, RoomId, UserId};
-    use serde_json::json;
+    use ruma::{
+        device_id, room_id, serde::Raw, user_id, CanonicalJsonValue, DeviceId, RoomId, UserId,
+    };
+    use serde_json::{json, value::to_raw_value};
 
-    use super::BackupMachine;
+    use super::{BackupMachine, BackupSchedule, BackupState, BACKUP_SCHEDULE_KEY};
     use crate::{
         olm::BackedUpRoomKey,
         store::{
@@ -904,6 +1389,61 @@ mod tests {
         assert!(result.trusted());
     }
 
+    #[async_test]
+    async fn test_backup_state_stream() {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+        let backup_machine = machine.backup_machine();
+        let mut stream = Box::pin(backup_machine.state_stream());
+
+        assert_eq!(backup_machine.state(), BackupState::Unknown);
+
+        // Marking the backup as outdated does nothing if no backup is enabled.
+        backup_machine.mark_backup_version_outdated();
+        assert_eq!(backup_machine.state(), BackupState::Unknown);
+        assert!(stream.next().now_or_never().is_none());
+
+        let decryption_key = BackupDecryptionKey::new().unwrap();
+        let backup_key = decryption_key.megolm_v1_public_key();
+        backup_key.set_version("1".to_owned());
+        backup_machine.enable_backup_v1(backup_key).await.unwrap();
+
+        assert_eq!(stream.next().await, Some(BackupState::Enabled("1".to_owned())));
+        assert_eq!(backup_machine.state(), BackupState::Enabled("1".to_owned()));
+
+        backup_machine.mark_backup_version_outdated();
+        assert_eq!(stream.next().await, Some(BackupState::VersionOutdated));
+
+        // Disabling the outdated backup keeps reporting that it's outdated.
+        backup_machine.disable_backup().await.unwrap();
+        assert_eq!(backup_machine.state(), BackupState::VersionOutdated);
+        assert!(stream.next().now_or_never().is_none());
+
+        let backup_key = decryption_key.megolm_v1_public_key();
+        backup_key.set_version("2".to_owned());
+        backup_machine.enable_backup_v1(backup_key).await.unwrap();
+        assert_eq!(stream.next().await, Some(BackupState::Enabled("2".to_owned())));
+
+        backup_machine.disable_backup().await.unwrap();
+        assert_eq!(stream.next().await, Some(BackupState::Disabled));
+        assert_eq!(backup_machine.state(), BackupState::Disabled);
+
+        // A machine created with an enabled backup starts out as enabled.
+        let store = MemoryStore::new();
+        let decryption_key = BackupDecryptionKey::new().unwrap();
+        store
+            .save_changes(Changes {
+                backup_decryption_key: Some(decryption_key),
+                backup_version: Some("2".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let machine =
+            OlmMachine::with_store(alice_id(), alice_device_id(), store, None).await.unwrap();
+
+        assert_eq!(machine.backup_machine().state(), BackupState::Enabled("2".to_owned()));
+    }
+
     #[async_test]
     async fn test_fix_backup_key_mismatch() {
         let store = MemoryStore::new();
@@ -933,4 +1473,112 @@ mod tests {
             "The OlmMachine loaded the wrong backup key."
         );
     }
+
+    #[async_test]
+    async fn test_backup_audit() {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+        let backup_machine = machine.backup_machine();
+
+        machine.create_outbound_group_session_with_defaults_test_helper(room_id()).await.unwrap();
+        machine.create_outbound_group_session_with_defaults_test_helper(room_id2()).await.unwrap();
+
+        let decryption_key = BackupDecryptionKey::new().unwrap();
+        let backup_key = decryption_key.megolm_v1_public_key();
+        backup_key.set_version("1".to_owned());
+        backup_machine.enable_backup_v1(backup_key).await.unwrap();
+
+        let (_, request) = backup_machine.backup().await.unwrap().unwrap();
+        let mut backup = request.rooms;
+
+        let report = backup_machine.audit(&backup, Some(&decryption_key)).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert!(report.is_healthy(), "A freshly uploaded backup should be healthy");
+
+        // Room keys that can't be decrypted with the backup key are corrupted.
+        let other_key = BackupDecryptionKey::new().unwrap();
+        let report = backup_machine.audit(&backup, Some(&other_key)).await.unwrap();
+        assert_eq!(report.corrupted.len(), 2);
+
+        // A different room key of the same sender, stored under the ID of one of ours, is
+        // corrupted as well.
+        let mut swapped = backup.clone();
+        let other_room_key = swapped[room_id2()].sessions.values().next().unwrap().clone();
+        let (session_id, backed_up) =
+            swapped.get_mut(room_id()).unwrap().sessions.iter_mut().next().unwrap();
+        let session_id = session_id.clone();
+        *backed_up = other_room_key;
+
+        let report = backup_machine.audit(&swapped, Some(&decryption_key)).await.unwrap();
+        assert_eq!(report.corrupted, vec![(room_id().to_owned(), session_id)]);
+
+        // Lose one of the room keys, and make the other one worse than ours.
+        backup.remove(room_id2());
+
+        let room_backup = backup.get_mut(room_id()).unwrap();
+        let (session_id, backed_up) = room_backup.sessions.iter_mut().next().unwrap();
+        let session_id = session_id.clone();
+
+        let mut json: serde_json::Value = backed_up.deserialize_as().unwrap();
+        json["first_message_index"] = 10.into();
+        *backed_up = Raw::from_json(to_raw_value(&json).unwrap());
+
+        // Garbage in the backup is reported as well, even if we don't know the room key.
+        room_backup.sessions.insert(
+            "garbage".to_owned(),
+            Raw::from_json(to_raw_value(&json!({ "foo": "bar" })).unwrap()),
+        );
+
+        let report = backup_machine.audit(&backup, None).await.unwrap();
+        assert!(!report.is_healthy());
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].0, room_id2());
+        assert_eq!(report.stale, vec![(room_id().to_owned(), session_id)]);
+        assert_eq!(report.corrupted, vec![(room_id().to_owned(), "garbage".to_owned())]);
+    }
+
+    #[async_test]
+    async fn test_backup_batches_rate_limit_and_backoff() {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+        let backup_machine = machine.backup_machine();
+
+        machine.create_outbound_group_session_with_defaults_test_helper(room_id()).await.unwrap();
+        machine.create_outbound_group_session_with_defaults_test_helper(room_id2()).await.unwrap();
+
+        let decryption_key = BackupDecryptionKey::new().unwrap();
+        let backup_key = decryption_key.megolm_v1_public_key();
+        backup_key.set_version("1".to_owned());
+        backup_machine.enable_backup_v1(backup_key).await.unwrap();
+
+        let (failed_id, failed) =
+            backup_machine.backup_batches(1, Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(failed.rooms.len(), 1, "The request should respect the batch size");
+        let failed_room = failed.rooms.keys().next().unwrap().to_owned();
+
+        backup_machine.backup_request_failed(&failed_id).await.unwrap();
+
+        assert!(
+            backup_machine.backup_batches(1, Duration::from_secs(3600)).await.unwrap().is_none(),
+            "A new request shouldn't be created before the minimum interval passed"
+        );
+
+        let (request_id, request) =
+            backup_machine.backup_batches(1, Duration::ZERO).await.unwrap().unwrap();
+        assert!(
+            !request.rooms.contains_key(&failed_room),
+            "The room key which failed to upload should be backed off"
+        );
+        backup_machine.mark_request_as_sent(&request_id).await.unwrap();
+
+        assert!(
+            backup_machine.backup_batches(1, Duration::ZERO).await.unwrap().is_none(),
+            "The only room key left to back up is still backed off"
+        );
+
+        // The backoff is persisted in the store.
+        let schedule: BackupSchedule =
+            backup_machine.store.get_value(BACKUP_SCHEDULE_KEY).await.unwrap().unwrap();
+        assert_eq!(schedule.backoff[&failed_room].len(), 1);
+        assert_eq!(schedule.backoff[&failed_room].values().next().unwrap().failures, 1);
+    }
 }
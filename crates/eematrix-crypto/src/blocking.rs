@@ -0,0 +1,148 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Synchronous wrappers for the most common [`OlmMachine`] store operations.
+//!
+//! The [`BlockingOlmMachine`] owns a small runtime which drives the async
+//! methods of the [`OlmMachine`] to completion, which allows the crypto store
+//! to be used from codebases which don't use async Rust, for example GUI
+//! toolkits with their own main loop or game engines.
+//!
+//! The methods of the [`BlockingOlmMachine`] block the current thread, they
+//! must not be called from within an async context. Doing so will panic.
+
+use std::{fmt, future::Future, io, sync::Arc, time::Duration};
+
+use ruma::{DeviceId, UserId};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{
+    olm::{ExportedRoomKey, InboundGroupSession},
+    store::Result as StoreResult,
+    Device, OlmMachine, RoomKeyImportResult,
+};
+
+/// A wrapper around an [`OlmMachine`] exposing some of its store operations
+/// as blocking methods.
+///
+/// Cloning a `BlockingOlmMachine` is cheap, clones share the same
+/// [`OlmMachine`] and runtime.
+#[derive(Clone)]
+pub struct BlockingOlmMachine {
+    machine: OlmMachine,
+    runtime: Arc<Runtime>,
+}
+
+impl fmt::Debug for BlockingOlmMachine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingOlmMachine").field("machine", &self.machine).finish_non_exhaustive()
+    }
+}
+
+impl BlockingOlmMachine {
+    /// Wrap the given [`OlmMachine`], creating the runtime which will be used
+    /// to drive its async methods.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the runtime couldn't be created.
+    pub fn new(machine: OlmMachine) -> io::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        Ok(Self { machine, runtime: Arc::new(runtime) })
+    }
+
+    /// Get the wrapped [`OlmMachine`].
+    pub fn inner(&self) -> &OlmMachine {
+        &self.machine
+    }
+
+    /// Run the given future to completion on the runtime of this
+    /// `BlockingOlmMachine`, blocking the current thread.
+    ///
+    /// This can be used for any operation of the [`OlmMachine`] which doesn't
+    /// have a blocking counterpart here.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Blocking version of [`OlmMachine::get_device()`].
+    pub fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        timeout: Option<Duration>,
+    ) -> StoreResult<Option<Device>> {
+        self.block_on(self.machine.get_device(user_id, device_id, timeout))
+    }
+
+    /// Blocking version of [`Store::export_room_keys()`].
+    ///
+    /// [`Store::export_room_keys()`]: crate::store::Store::export_room_keys
+    pub fn export_room_keys(
+        &self,
+        predicate: impl FnMut(&InboundGroupSession) -> bool,
+    ) -> StoreResult<Vec<ExportedRoomKey>> {
+        self.block_on(self.machine.store().export_room_keys(predicate))
+    }
+
+    /// Blocking version of [`Store::import_room_keys()`].
+    ///
+    /// [`Store::import_room_keys()`]: crate::store::Store::import_room_keys
+    pub fn import_room_keys(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        from_backup_version: Option<&str>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> StoreResult<RoomKeyImportResult> {
+        self.block_on(self.machine.store().import_room_keys(
+            exported_keys,
+            from_backup_version,
+            progress_listener,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_executor::block_on;
+    use ruma::{room_id, user_id};
+
+    use super::BlockingOlmMachine;
+    use crate::machine::test_helpers::get_machine_pair;
+
+    #[test]
+    fn test_blocking_room_key_export_and_import() {
+        let (alice, bob, _) =
+            block_on(get_machine_pair(user_id!("@a:s.co"), user_id!("@b:s.co"), false));
+
+        let room_id = room_id!("!room:localhost");
+        block_on(alice.create_outbound_group_session_with_defaults_test_helper(room_id)).unwrap();
+
+        let alice = BlockingOlmMachine::new(alice).unwrap();
+        let bob = BlockingOlmMachine::new(bob).unwrap();
+
+        let exported_keys = alice.export_room_keys(|_| true).unwrap();
+        assert_eq!(exported_keys.len(), 1);
+
+        let result = bob.import_room_keys(exported_keys, None, |_, _| {}).unwrap();
+        assert_eq!(result.imported_count, 1);
+        assert_eq!(result.total_count, 1);
+
+        let device = alice
+            .get_device(bob.inner().user_id(), bob.inner().device_id(), None)
+            .unwrap()
+            .expect("Alice should know about Bob's device");
+        assert_eq!(device.device_id(), bob.inner().device_id());
+    }
+}
@@ -119,6 +119,25 @@ impl AesHmacSha2Key {
         Self { aes_key, mac_key }
     }
 
+    /// Create an [`AesHmacSha2Key`] from the shared secret of a Curve25519 key
+    /// agreement.
+    ///
+    /// The shared secret is expanded using HKDF-SHA-256 with the given salt
+    /// and info.
+    pub(crate) fn from_shared_secret(shared_secret: &[u8; 32], salt: &[u8], info: &[u8]) -> Self {
+        let mut expanded_keys = [0u8; KEY_SIZE * 2];
+        let hkdf: Hkdf<Sha256> = Hkdf::new(Some(salt), shared_secret);
+
+        hkdf.expand(info, &mut expanded_keys)
+            .expect("We should be able to expand 64 bytes of output key material.");
+
+        let (aes_key, mac_key) = Self::split_keys(&expanded_keys);
+
+        expanded_keys.zeroize();
+
+        Self { aes_key, mac_key }
+    }
+
     pub(crate) fn from_passphrase(
         passphrase: &str,
         pbkdf_rounds: u32,
@@ -0,0 +1,153 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The source of the current time used by the crypto machine.
+//!
+//! By default, the [`OlmMachine`] uses the [`SystemClock`]. A different
+//! [`Clock`] can be installed with [`OlmMachine::set_clock()`], for example to
+//! control time deterministically in tests and simulations, or to read the
+//! time from a platform specific source. The clock is handed down to the
+//! [`MemoryStore`] as well, for its leased locks.
+//!
+//! [`OlmMachine`]: crate::OlmMachine
+//! [`OlmMachine::set_clock()`]: crate::OlmMachine::set_clock
+//! [`MemoryStore`]: crate::store::MemoryStore
+
+use std::fmt::Debug;
+#[cfg(any(test, feature = "testing"))]
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[cfg(any(test, feature = "testing"))]
+use ruma::UInt;
+use ruma::{time::Instant, MilliSecondsSinceUnixEpoch, SecondsSinceUnixEpoch};
+
+/// A source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time, in milliseconds since the unix epoch.
+    fn now(&self) -> MilliSecondsSinceUnixEpoch;
+
+    /// The current time, in seconds since the unix epoch.
+    fn now_secs(&self) -> SecondsSinceUnixEpoch {
+        SecondsSinceUnixEpoch(self.now().as_secs())
+    }
+
+    /// The current instant, used to measure timeouts, for example the ones of
+    /// verifications.
+    ///
+    /// Defaults to the monotonic clock of the system.
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The [`Clock`] which reads the time from the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch::now()
+    }
+}
+
+/// A [`Clock`] which only moves when it's told to.
+///
+/// Clones of a `MockClock` share their time, so a clone can be handed to the
+/// [`OlmMachine`](crate::OlmMachine) while the test keeps advancing the
+/// original.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now_ms: Arc<AtomicU64>,
+    /// The instant returned for the time the clock was created at, the
+    /// instants at other times are derived from it.
+    origin: (Instant, u64),
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockClock {
+    /// Create a new `MockClock` which is stopped at the given time.
+    pub fn new(now: MilliSecondsSinceUnixEpoch) -> Self {
+        let now_ms = now.get().into();
+        Self { now_ms: Arc::new(AtomicU64::new(now_ms)), origin: (Instant::now(), now_ms) }
+    }
+
+    /// Set the time of this clock.
+    pub fn set(&self, now: MilliSecondsSinceUnixEpoch) {
+        self.now_ms.store(now.get().into(), Ordering::SeqCst);
+    }
+
+    /// Move the time of this clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        self.now_ms.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for MockClock {
+    fn now(&self) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(UInt::new_saturating(self.now_ms.load(Ordering::SeqCst)))
+    }
+
+    fn instant(&self) -> Instant {
+        let (origin, origin_ms) = self.origin;
+        let now_ms = self.now_ms.load(Ordering::SeqCst);
+
+        if now_ms >= origin_ms {
+            origin + Duration::from_millis(now_ms - origin_ms)
+        } else {
+            origin.checked_sub(Duration::from_millis(origin_ms - now_ms)).unwrap_or(origin)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ruma::{uint, MilliSecondsSinceUnixEpoch, SecondsSinceUnixEpoch};
+
+    use super::{Clock, MockClock};
+
+    #[test]
+    fn test_mock_clock_only_moves_when_told() {
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch(uint!(1_000_500)));
+        let clone = clock.clone();
+
+        assert_eq!(clock.now(), MilliSecondsSinceUnixEpoch(uint!(1_000_500)));
+        assert_eq!(clock.now_secs(), SecondsSinceUnixEpoch(uint!(1000)));
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clone.now(), MilliSecondsSinceUnixEpoch(uint!(1_060_500)));
+
+        clone.set(MilliSecondsSinceUnixEpoch(uint!(42)));
+        assert_eq!(clock.now(), MilliSecondsSinceUnixEpoch(uint!(42)));
+    }
+
+    #[test]
+    fn test_mock_clock_instant_follows_time() {
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch(uint!(1_000_000)));
+        let start = clock.instant();
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.instant().duration_since(start), Duration::from_secs(60));
+    }
+}
@@ -46,15 +46,19 @@ use std::sync::Arc;
 use ruma::{
     api::client::dehydrated_device::{put_dehydrated_device, DehydratedDeviceData},
     assign,
-    events::AnyToDeviceEvent,
+    events::{
+        secret::request::SecretName, secret_storage::secret::SecretEventContent, AnyToDeviceEvent,
+    },
     serde::Raw,
     DeviceId,
 };
 use thiserror::Error;
 use tracing::{instrument, trace};
-use vodozemac::{DehydratedDeviceError, LibolmPickleError};
+use vodozemac::{Base64DecodeError, DehydratedDeviceError, LibolmPickleError};
+use zeroize::Zeroizing;
 
 use crate::{
+    secret_storage::{MacError, SecretStorageKey},
     store::{
         types::{Changes, DehydratedDeviceKey, RoomKeyInfo},
         CryptoStoreWrapper, MemoryStore, Store,
@@ -90,8 +94,35 @@ pub enum DehydrationError {
     /// The store ran into an error.
     #[error(transparent)]
     Store(#[from] CryptoStoreError),
+
+    /// A new pickle key could not be generated.
+    #[error(transparent)]
+    Rand(#[from] rand::Error),
+
+    /// The pickle key isn't valid base64.
+    #[error(transparent)]
+    Base64(#[from] Base64DecodeError),
+
+    /// The pickle key could not be decrypted using the given secret storage
+    /// key, the MAC check failed.
+    #[error("The MAC check for the pickle key stored in the secret storage failed")]
+    SecretStorageMac(#[from] MacError),
+
+    /// Dehydrated devices were turned off when the [`OlmMachine`] was built,
+    /// see [`OlmMachineFeatures::dehydrated_devices`].
+    ///
+    /// [`OlmMachineFeatures::dehydrated_devices`]: crate::OlmMachineFeatures::dehydrated_devices
+    #[error("Dehydrated devices are disabled")]
+    Disabled,
 }
 
+/// The name of the secret, and of the global account data event, the
+/// dehydrated device pickle key is stored under in the Secrets Storage, as
+/// defined in [MSC3814].
+///
+/// [MSC3814]: https://github.com/matrix-org/matrix-spec-proposals/pull/3814
+pub const DEHYDRATED_DEVICE_SECRET_NAME: &str = "org.matrix.msc3814";
+
 /// Struct collecting methods to create and rehydrate dehydrated devices.
 #[derive(Debug)]
 pub struct DehydratedDevices {
@@ -99,8 +130,18 @@ pub struct DehydratedDevices {
 }
 
 impl DehydratedDevices {
+    fn ensure_enabled(&self) -> Result<(), DehydrationError> {
+        if self.inner.features().dehydrated_devices {
+            Ok(())
+        } else {
+            Err(DehydrationError::Disabled)
+        }
+    }
+
     /// Create a new [`DehydratedDevice`] which can be uploaded to the server.
     pub async fn create(&self) -> Result<DehydratedDevice, DehydrationError> {
+        self.ensure_enabled()?;
+
         let user_id = self.inner.user_id();
         let user_identity = self.inner.store().private_identity();
 
@@ -147,6 +188,8 @@ impl DehydratedDevices {
         device_id: &DeviceId,
         device_data: Raw<DehydratedDeviceData>,
     ) -> Result<RehydratedDevice, DehydrationError> {
+        self.ensure_enabled()?;
+
         let rehydrated =
             self.inner.rehydrate(pickle_key.inner.as_ref(), device_id, device_data).await?;
 
@@ -186,6 +229,84 @@ impl DehydratedDevices {
     pub async fn delete_dehydrated_device_pickle_key(&self) -> Result<(), DehydrationError> {
         Ok(self.inner.store().delete_dehydrated_device_pickle_key().await?)
     }
+
+    /// Get the stored dehydrated device pickle key, or create a new random one
+    /// and store it if none was stored yet.
+    pub async fn get_or_create_dehydrated_device_pickle_key(
+        &self,
+    ) -> Result<DehydratedDeviceKey, DehydrationError> {
+        if let Some(pickle_key) = self.get_dehydrated_device_pickle_key().await? {
+            Ok(pickle_key)
+        } else {
+            let pickle_key = DehydratedDeviceKey::new()?;
+            self.save_dehydrated_device_pickle_key(&pickle_key).await?;
+
+            Ok(pickle_key)
+        }
+    }
+
+    /// Encrypt the dehydrated device pickle key so it can be stored in the
+    /// Secrets Storage, creating a new pickle key if none was stored yet.
+    ///
+    /// The returned content should be uploaded as the
+    /// [`DEHYDRATED_DEVICE_SECRET_NAME`] global account data event. Secrets in
+    /// an existing event which were encrypted using other secret storage keys
+    /// should be merged into the content before uploading it.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_storage_key` - The secret storage key which should be used to
+    ///   encrypt the pickle key.
+    pub async fn export_pickle_key_to_secret_storage(
+        &self,
+        secret_storage_key: &SecretStorageKey,
+    ) -> Result<SecretEventContent, DehydrationError> {
+        let pickle_key = self.get_or_create_dehydrated_device_pickle_key().await?;
+        let secret_name = SecretName::from(DEHYDRATED_DEVICE_SECRET_NAME);
+
+        let encrypted =
+            secret_storage_key.encrypt(pickle_key.to_base64().into_bytes(), &secret_name);
+
+        Ok(SecretEventContent::new(
+            [(secret_storage_key.key_id().to_owned(), encrypted.into())].into(),
+        ))
+    }
+
+    /// Decrypt the dehydrated device pickle key from the Secrets Storage and
+    /// store it in the crypto store.
+    ///
+    /// Returns `None` if the content doesn't contain a pickle key encrypted
+    /// using the given secret storage key.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_storage_key` - The secret storage key the pickle key was
+    ///   encrypted with.
+    ///
+    /// * `content` - The content of the [`DEHYDRATED_DEVICE_SECRET_NAME`]
+    ///   global account data event.
+    pub async fn import_pickle_key_from_secret_storage(
+        &self,
+        secret_storage_key: &SecretStorageKey,
+        content: &SecretEventContent,
+    ) -> Result<Option<DehydratedDeviceKey>, DehydrationError> {
+        let Some(encrypted) = content.encrypted.get(secret_storage_key.key_id()) else {
+            return Ok(None);
+        };
+
+        let secret_name = SecretName::from(DEHYDRATED_DEVICE_SECRET_NAME);
+        let decrypted = Zeroizing::new(
+            secret_storage_key.decrypt(&encrypted.to_owned().try_into()?, &secret_name)?,
+        );
+        // Invalid UTF-8 can't be valid base64 either, so decoding the key will fail
+        // for it below.
+        let decrypted = Zeroizing::new(String::from_utf8_lossy(&decrypted).into_owned());
+
+        let pickle_key = DehydratedDeviceKey::from_base64(&decrypted)?;
+        self.save_dehydrated_device_pickle_key(&pickle_key).await?;
+
+        Ok(Some(pickle_key))
+    }
 }
 
 /// A rehydraded device.
@@ -420,6 +541,7 @@ mod tests {
             tests::to_device_requests_to_content,
         },
         olm::OutboundGroupSession,
+        secret_storage::SecretStorageKey,
         store::types::DehydratedDeviceKey,
         types::{events::ToDeviceEvent, DeviceKeys as DeviceKeysType},
         utilities::json_convert,
@@ -631,6 +753,45 @@ mod tests {
         assert!(stored_key.is_none());
     }
 
+    #[async_test]
+    async fn test_dehydrated_device_pickle_key_secret_storage() {
+        let alice = get_olm_machine().await;
+        let dehydrated_manager = alice.dehydrated_devices();
+        let secret_storage_key = SecretStorageKey::new();
+
+        // Exporting creates a new pickle key if none is stored.
+        let content = dehydrated_manager
+            .export_pickle_key_to_secret_storage(&secret_storage_key)
+            .await
+            .unwrap();
+        let pickle_key =
+            dehydrated_manager.get_dehydrated_device_pickle_key().await.unwrap().unwrap();
+        assert!(content.encrypted.contains_key(secret_storage_key.key_id()));
+
+        // Another device can import the pickle key from the secret storage.
+        let bob = get_olm_machine().await;
+        let imported = bob
+            .dehydrated_devices()
+            .import_pickle_key_from_secret_storage(&secret_storage_key, &content)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(imported.to_base64(), pickle_key.to_base64());
+
+        let stored_key =
+            bob.dehydrated_devices().get_dehydrated_device_pickle_key().await.unwrap().unwrap();
+        assert_eq!(stored_key.to_base64(), pickle_key.to_base64());
+
+        // A different secret storage key can't be used to import the pickle key.
+        let other_key = SecretStorageKey::new();
+        let imported = bob
+            .dehydrated_devices()
+            .import_pickle_key_from_secret_storage(&other_key, &content)
+            .await
+            .unwrap();
+        assert!(imported.is_none());
+    }
+
     /// Test that we can rehydrate an older version of dehydrated device
     #[async_test]
     async fn test_legacy_dehydrated_device_rehydration() {
@@ -14,8 +14,10 @@
 
 use std::collections::BTreeMap;
 
-use matrix_sdk_common::deserialized_responses::{VerificationLevel, WithheldCode};
-use ruma::{CanonicalJsonError, IdParseError, OwnedDeviceId, OwnedRoomId, OwnedUserId};
+use matrix_sdk_common::deserialized_responses::{VerificationLevel, WithheldCode, WithheldSender};
+use ruma::{
+    CanonicalJsonError, IdParseError, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId,
+};
 use serde::{ser::SerializeMap, Serializer};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
@@ -65,6 +67,15 @@ pub enum OlmError {
     #[error("decryption failed because an Olm message from {0} with sender key {1} was replayed")]
     ReplayedMessage(OwnedUserId, Curve25519PublicKey),
 
+    /// A pre-key message was rejected because the device already created too
+    /// many new Olm sessions with us recently, see
+    /// [`SessionCreationLimit::reject`](crate::store::SessionCreationLimit::reject).
+    #[error(
+        "rejected a new Olm session from {0} with sender key {1} because the device created too \
+            many sessions recently"
+    )]
+    SessionCreationThrottled(OwnedUserId, Curve25519PublicKey),
+
     /// Encryption failed because the device does not have a valid Olm session
     /// with us.
     #[error(
@@ -91,8 +102,11 @@ pub enum MegolmError {
 
     /// Decryption failed because we're missing the room key that was used to
     /// encrypt the event.
+    ///
+    /// If we were told that the room key was withheld on purpose, this
+    /// contains the withheld code and who told us about it.
     #[error("Can't find the room key to decrypt the event, withheld code: {0:?}")]
-    MissingRoomKey(Option<WithheldCode>),
+    MissingRoomKey(Option<WithheldCode>, Option<WithheldSender>),
 
     /// Decryption failed because of a mismatch between the identity keys of the
     /// device we received the room key from and the identity keys recorded in
@@ -121,6 +135,45 @@ pub enum MegolmError {
     /// The nested value is the sender's current verification level.
     #[error("decryption failed because trust requirement not satisfied: {0}")]
     SenderIdentityNotTrusted(VerificationLevel),
+
+    /// The message index of the Megolm session was already used to encrypt
+    /// another event, the event is most likely a replay of it.
+    ///
+    /// The nested value is the ID of the event that first used the message
+    /// index.
+    #[error("decryption failed because the message index was already used by the event {0}")]
+    ReplayedMessage(OwnedEventId),
+
+    /// The content that should have been encrypted was rejected by the
+    /// validation configured in the [`crate::olm::EncryptionSettings`] of
+    /// the room.
+    #[error(transparent)]
+    InvalidPayload(#[from] PayloadValidationError),
+
+    /// A targeted room key request was created without any devices, or with
+    /// devices of more than one user.
+    #[error("a room key request must be sent to the devices of a single user")]
+    InvalidKeyRequestRecipients,
+}
+
+/// Error representing content that was rejected before being encrypted, see
+/// [`crate::olm::EncryptionSettings::max_content_size`] and
+/// [`crate::olm::EncryptionSettings::strict_relation_validation`].
+#[derive(Error, Debug)]
+pub enum PayloadValidationError {
+    /// The serialized content is larger than the configured maximum.
+    #[error("the content is {size} bytes long, but at most {max_size} bytes are allowed")]
+    ContentTooLarge {
+        /// The size of the serialized content, in bytes.
+        size: usize,
+        /// The configured maximum size, in bytes.
+        max_size: usize,
+    },
+
+    /// The `m.relates_to` field of the content doesn't follow the schema of
+    /// its relation type.
+    #[error("the content contains a malformed `m.relates_to` field: {0}")]
+    MalformedRelation(String),
 }
 
 /// Decryption failed because of a mismatch between the identity keys of the
@@ -281,6 +334,13 @@ pub enum SignatureError {
     #[error(transparent)]
     JsonError(#[from] CanonicalJsonError),
 
+    /// The type of a payload signed using [`OlmMachine::sign_json()`] isn't
+    /// namespaced, or isn't the expected one.
+    ///
+    /// [`OlmMachine::sign_json()`]: crate::OlmMachine::sign_json
+    #[error("the signed payload type `{0}` isn't namespaced or isn't the expected one")]
+    InvalidPayloadType(String),
+
     /// The store ran into an error.
     #[error(transparent)]
     StoreError(#[from] CryptoStoreError),
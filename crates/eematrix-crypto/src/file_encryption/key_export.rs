@@ -18,7 +18,7 @@ use byteorder::{BigEndian, ReadBytesExt};
 use rand::{thread_rng, RngCore};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
-use vodozemac::{base64_decode, base64_encode};
+use vodozemac::{base64_decode, base64_encode, Curve25519PublicKey, Curve25519SecretKey};
 use zeroize::Zeroize;
 
 use crate::{
@@ -27,6 +27,15 @@ use crate::{
 };
 
 const VERSION: u8 = 1;
+/// The version of exports which also carry the sender data of the room keys.
+const VERSION_WITH_SENDER_DATA: u8 = 2;
+/// The version of exports which are encrypted for a Curve25519 public key
+/// instead of using a passphrase.
+const VERSION_FOR_KEY: u8 = 3;
+
+/// The HKDF info used to derive the keys of exports encrypted for a public
+/// key.
+const KEY_EXPORT_HKDF_INFO: &[u8] = b"MATRIX_ROOM_KEY_EXPORT_CURVE25519_AES_HMAC_SHA2";
 
 const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
 const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
@@ -59,6 +68,10 @@ pub enum KeyExportError {
 
 /// Try to decrypt a reader into a list of exported room keys.
 ///
+/// Both version 1 and version 2 exports are supported. The
+/// [`ExportedRoomKey::sender_data`] is only kept for version 2 exports, see
+/// [`encrypt_room_key_export_v2()`].
+///
 /// # Arguments
 ///
 /// * `passphrase` - The passphrase that was used to encrypt the exported keys.
@@ -78,31 +91,48 @@ pub enum KeyExportError {
 /// # };
 /// ```
 pub fn decrypt_room_key_export(
-    mut input: impl Read,
+    input: impl Read,
     passphrase: &str,
 ) -> Result<Vec<ExportedRoomKey>, KeyExportError> {
-    let mut x: String = String::new();
-
-    input.read_to_string(&mut x)?;
+    decrypt_room_key_export_with_max_rounds(input, passphrase, u32::MAX)
+}
 
-    if !(x.trim_start().starts_with(HEADER) && x.trim_end().ends_with(FOOTER)) {
-        return Err(KeyExportError::InvalidHeaders);
-    }
+/// Decrypt a reader into a list of exported room keys, like
+/// [`decrypt_room_key_export()`] does, but derive the key using at most
+/// `max_rounds` PBKDF2 rounds, however many the export claims to use.
+pub(crate) fn decrypt_room_key_export_with_max_rounds(
+    mut input: impl Read,
+    passphrase: &str,
+    max_rounds: u32,
+) -> Result<Vec<ExportedRoomKey>, KeyExportError> {
+    let payload = read_payload(&mut input)?;
+    let (version, mut decrypted) = decrypt_helper(&payload, passphrase, max_rounds)?;
 
-    let payload: String =
-        x.lines().filter(|l| !(l.starts_with(HEADER) || l.starts_with(FOOTER))).collect();
+    let ret: Result<Vec<ExportedRoomKey>, _> = serde_json::from_str(&decrypted);
 
-    let mut decrypted = decrypt_helper(&payload, passphrase)?;
+    decrypted.zeroize();
 
-    let ret = serde_json::from_str(&decrypted);
+    let mut keys = ret?;
 
-    decrypted.zeroize();
+    // Only version 2 exports are created with the intent of carrying over the
+    // sender data, don't trust whatever a version 1 export might contain.
+    if version != VERSION_WITH_SENDER_DATA {
+        for key in &mut keys {
+            key.sender_data = None;
+        }
+    }
 
-    Ok(ret?)
+    Ok(keys)
 }
 
 /// Encrypt the list of exported room keys using the given passphrase.
 ///
+/// This creates a version 1 export, as defined in the [spec]. The sender
+/// data of the keys is ignored when such an export is imported again, use
+/// [`encrypt_room_key_export_v2()`] to preserve it.
+///
+/// [spec]: https://spec.matrix.org/v1.13/client-server-api/#key-export-format
+///
 /// # Arguments
 ///
 /// * `keys` - A list of sessions that should be encrypted.
@@ -138,16 +168,207 @@ pub fn encrypt_room_key_export(
     keys: &[ExportedRoomKey],
     passphrase: &str,
     rounds: u32,
+) -> Result<String, SerdeError> {
+    encrypt_room_key_export_helper(keys, passphrase, rounds, VERSION)
+}
+
+/// Encrypt the list of exported room keys using the given passphrase, keeping
+/// the sender data of the keys.
+///
+/// This works like [`encrypt_room_key_export()`], but creates a version 2
+/// export, which keeps the [`ExportedRoomKey::sender_data`] the sessions had
+/// when they were exported, e.g. if the sender was verified.
+///
+/// The sender data is informational only. Anyone who knows the passphrase can
+/// modify it, so sessions imported from the export still start out with
+/// unknown sender data, which is recalculated once they decrypt an event.
+///
+/// Version 2 exports can't be imported by clients which only support the
+/// version 1 format of the spec.
+///
+/// # Arguments
+///
+/// * `keys` - A list of sessions that should be encrypted.
+///
+/// * `passphrase` - The passphrase that will be used to encrypt the exported
+///   room keys.
+///
+/// * `rounds` - The number of rounds that should be used for the key
+///   derivation, see [`encrypt_room_key_export()`].
+///
+/// # Panics
+///
+/// This method will panic if it can't get enough randomness from the OS to
+/// encrypt the exported keys securely.
+pub fn encrypt_room_key_export_v2(
+    keys: &[ExportedRoomKey],
+    passphrase: &str,
+    rounds: u32,
+) -> Result<String, SerdeError> {
+    encrypt_room_key_export_helper(keys, passphrase, rounds, VERSION_WITH_SENDER_DATA)
+}
+
+/// Encrypt the list of exported room keys for the given Curve25519 public key.
+///
+/// Instead of deriving the encryption key from a passphrase, a new ephemeral
+/// key pair is created for every export and combined with the given public
+/// key, so only the holder of the matching private key can decrypt the export
+/// using [`decrypt_room_key_export_with_key()`]. This allows automated backup
+/// tooling to create exports for a key which is kept offline, e.g. in a
+/// hardware token, without handling any secret itself.
+///
+/// Such exports are a non-standard extension of the [spec] format and can't
+/// be imported by other clients. The sender data of the keys isn't preserved,
+/// since anyone who knows the public key can create an export for it.
+///
+/// [spec]: https://spec.matrix.org/v1.13/client-server-api/#key-export-format
+///
+/// # Arguments
+///
+/// * `keys` - A list of sessions that should be encrypted.
+///
+/// * `recipient_key` - The public key of the recipient of the export.
+///
+/// # Panics
+///
+/// This method will panic if it can't get enough randomness from the OS to
+/// encrypt the exported keys securely.
+pub fn encrypt_room_key_export_for_key(
+    keys: &[ExportedRoomKey],
+    recipient_key: Curve25519PublicKey,
+) -> Result<String, SerdeError> {
+    let mut plaintext = serde_json::to_string(keys)?.into_bytes();
+
+    let ephemeral_key = Curve25519SecretKey::new();
+    let ephemeral_public_key = Curve25519PublicKey::from(&ephemeral_key);
+    let shared_secret = ephemeral_key.diffie_hellman(&recipient_key);
+
+    let key = AesHmacSha2Key::from_shared_secret(
+        shared_secret.as_bytes(),
+        &[ephemeral_public_key.as_bytes().as_slice(), recipient_key.as_bytes()].concat(),
+        KEY_EXPORT_HKDF_INFO,
+    );
+    let (ciphertext, initialization_vector) = key.encrypt(plaintext.clone());
+
+    plaintext.zeroize();
+
+    let mut payload = [
+        VERSION_FOR_KEY.to_be_bytes().as_slice(),
+        ephemeral_public_key.as_bytes(),
+        &initialization_vector,
+        &ciphertext,
+    ]
+    .concat();
+
+    let mac = key.create_mac_tag(&payload);
+    payload.extend(mac.as_bytes());
+
+    Ok([HEADER.to_owned(), base64_encode(payload), FOOTER.to_owned()].join("\n"))
+}
+
+/// Try to decrypt a reader into a list of exported room keys, using the
+/// private key the export was encrypted for.
+///
+/// This is the counterpart of [`encrypt_room_key_export_for_key()`],
+/// passphrase-based exports have to be decrypted using
+/// [`decrypt_room_key_export()`].
+///
+/// # Arguments
+///
+/// * `secret_key` - The private key matching the public key the export was
+///   encrypted for.
+pub fn decrypt_room_key_export_with_key(
+    mut input: impl Read,
+    secret_key: &Curve25519SecretKey,
+) -> Result<Vec<ExportedRoomKey>, KeyExportError> {
+    let payload = read_payload(&mut input)?;
+    let decoded = base64_decode(payload)?;
+
+    let mut decoded = Cursor::new(decoded);
+
+    let mut ephemeral_public_key = [0u8; 32];
+    let mut iv = [0u8; IV_SIZE];
+    let mut mac = [0u8; MAC_SIZE];
+
+    let version = decoded.read_u8()?;
+
+    if version != VERSION_FOR_KEY {
+        return Err(KeyExportError::UnsupportedVersion);
+    }
+
+    decoded.read_exact(&mut ephemeral_public_key)?;
+    decoded.read_exact(&mut iv)?;
+    let ciphertext_start = decoded.position() as usize;
+
+    decoded.seek(SeekFrom::End(-(MAC_SIZE as i64)))?;
+    let ciphertext_end = decoded.position() as usize;
+
+    decoded.read_exact(&mut mac)?;
+
+    if ciphertext_end < ciphertext_start {
+        return Err(KeyExportError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    let decoded = decoded.into_inner();
+
+    let ephemeral_public_key = Curve25519PublicKey::from_bytes(ephemeral_public_key);
+    let recipient_key = Curve25519PublicKey::from(secret_key);
+    let shared_secret = secret_key.diffie_hellman(&ephemeral_public_key);
+
+    let key = AesHmacSha2Key::from_shared_secret(
+        shared_secret.as_bytes(),
+        &[ephemeral_public_key.as_bytes().as_slice(), recipient_key.as_bytes()].concat(),
+        KEY_EXPORT_HKDF_INFO,
+    );
+    key.verify_mac(&decoded[0..ciphertext_end], &mac).map_err(|_| KeyExportError::InvalidMac)?;
+
+    let ciphertext = decoded[ciphertext_start..ciphertext_end].to_owned();
+    let mut decrypted = String::from_utf8(key.decrypt(ciphertext, &iv))?;
+
+    let ret: Result<Vec<ExportedRoomKey>, _> = serde_json::from_str(&decrypted);
+
+    decrypted.zeroize();
+
+    let mut keys = ret?;
+
+    // Anyone who knows the public key can create such an export, so don't trust
+    // the sender data it might contain.
+    for key in &mut keys {
+        key.sender_data = None;
+    }
+
+    Ok(keys)
+}
+
+/// Read an armored key export, returning the base64 encoded payload between
+/// the header and the footer.
+fn read_payload(input: &mut impl Read) -> Result<String, KeyExportError> {
+    let mut x: String = String::new();
+
+    input.read_to_string(&mut x)?;
+
+    if !(x.trim_start().starts_with(HEADER) && x.trim_end().ends_with(FOOTER)) {
+        return Err(KeyExportError::InvalidHeaders);
+    }
+
+    Ok(x.lines().filter(|l| !(l.starts_with(HEADER) || l.starts_with(FOOTER))).collect())
+}
+
+fn encrypt_room_key_export_helper(
+    keys: &[ExportedRoomKey],
+    passphrase: &str,
+    rounds: u32,
+    version: u8,
 ) -> Result<String, SerdeError> {
     let mut plaintext = serde_json::to_string(keys)?.into_bytes();
-    let ciphertext = encrypt_helper(&plaintext, passphrase, rounds);
+    let ciphertext = encrypt_helper(&plaintext, passphrase, rounds, version);
 
     plaintext.zeroize();
 
     Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
 }
 
-fn encrypt_helper(plaintext: &[u8], passphrase: &str, rounds: u32) -> String {
+fn encrypt_helper(plaintext: &[u8], passphrase: &str, rounds: u32, version: u8) -> String {
     let mut salt = [0u8; SALT_SIZE];
     let mut rng = thread_rng();
 
@@ -157,7 +378,7 @@ fn encrypt_helper(plaintext: &[u8], passphrase: &str, rounds: u32) -> String {
     let (ciphertext, initialization_vector) = key.encrypt(plaintext.to_owned());
 
     let mut payload = [
-        VERSION.to_be_bytes().as_slice(),
+        version.to_be_bytes().as_slice(),
         &salt,
         &initialization_vector,
         rounds.to_be_bytes().as_slice(),
@@ -171,7 +392,11 @@ fn encrypt_helper(plaintext: &[u8], passphrase: &str, rounds: u32) -> String {
     base64_encode(payload)
 }
 
-fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExportError> {
+fn decrypt_helper(
+    ciphertext: &str,
+    passphrase: &str,
+    max_rounds: u32,
+) -> Result<(u8, String), KeyExportError> {
     let decoded = base64_decode(ciphertext)?;
 
     let mut decoded = Cursor::new(decoded);
@@ -184,7 +409,7 @@ fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExpor
     decoded.read_exact(&mut salt)?;
     decoded.read_exact(&mut iv)?;
 
-    let rounds = decoded.read_u32::<BigEndian>()?;
+    let rounds = decoded.read_u32::<BigEndian>()?.min(max_rounds);
     let ciphertext_start = decoded.position() as usize;
 
     decoded.seek(SeekFrom::End(-32))?;
@@ -194,7 +419,7 @@ fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExpor
 
     let mut decoded = decoded.into_inner();
 
-    if version != VERSION {
+    if version != VERSION && version != VERSION_WITH_SENDER_DATA {
         return Err(KeyExportError::UnsupportedVersion);
     }
 
@@ -205,22 +430,22 @@ fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExpor
     let plaintext = key.decrypt(ciphertext.to_owned(), &iv);
     let ret = String::from_utf8(plaintext);
 
-    Ok(ret?)
+    Ok((version, ret?))
 }
 
 #[cfg(all(test, not(target_family = "wasm")))]
 mod proptests {
     use proptest::prelude::*;
 
-    use super::{decrypt_helper, encrypt_helper};
+    use super::{decrypt_helper, encrypt_helper, VERSION};
 
     proptest! {
         #[test]
         fn proptest_encrypt_cycle(plaintext in prop::string::string_regex(".*").unwrap()) {
             let plaintext_bytes = plaintext.clone().into_bytes();
 
-            let ciphertext = encrypt_helper(&plaintext_bytes, "test", 1);
-            let decrypted = decrypt_helper(&ciphertext, "test").unwrap();
+            let ciphertext = encrypt_helper(&plaintext_bytes, "test", 1, VERSION);
+            let (_, decrypted) = decrypt_helper(&ciphertext, "test", u32::MAX).unwrap();
 
             prop_assert!(plaintext == decrypted);
         }
@@ -234,16 +459,19 @@ mod tests {
         io::Cursor,
     };
 
+    use assert_matches2::assert_matches;
     use indoc::indoc;
     use matrix_sdk_test::async_test;
-    use ruma::{room_id, user_id};
+    use ruma::{device_id, room_id, user_id};
+    use vodozemac::{Curve25519PublicKey, Curve25519SecretKey, Ed25519PublicKey};
 
     use super::{
-        base64_decode, decrypt_helper, decrypt_room_key_export, encrypt_helper,
-        encrypt_room_key_export,
+        base64_decode, decrypt_helper, decrypt_room_key_export, decrypt_room_key_export_with_key,
+        encrypt_helper, encrypt_room_key_export, encrypt_room_key_export_for_key,
+        encrypt_room_key_export_v2, KeyExportError, VERSION,
     };
     use crate::{
-        error::OlmResult, machine::test_helpers::get_prepared_machine_test_helper,
+        error::OlmResult, machine::test_helpers::get_prepared_machine_test_helper, olm::SenderData,
         RoomKeyImportResult,
     };
 
@@ -281,9 +509,10 @@ mod tests {
         let data = "It's a secret to everybody";
         let bytes = data.to_owned().into_bytes();
 
-        let encrypted = encrypt_helper(&bytes, PASSPHRASE, 10);
-        let decrypted = decrypt_helper(&encrypted, PASSPHRASE).unwrap();
+        let encrypted = encrypt_helper(&bytes, PASSPHRASE, 10, VERSION);
+        let (version, decrypted) = decrypt_helper(&encrypted, PASSPHRASE, u32::MAX).unwrap();
 
+        assert_eq!(version, VERSION);
         assert_eq!(data, decrypted);
     }
 
@@ -368,6 +597,90 @@ mod tests {
         Ok(())
     }
 
+    #[async_test]
+    async fn test_v2_export_carries_sender_data() -> OlmResult<()> {
+        let (machine, _) =
+            get_prepared_machine_test_helper(user_id!("@alice:localhost"), false).await;
+        let room_id = room_id!("!test:localhost");
+        let session = machine.create_inbound_session_test_helper(room_id).await?;
+
+        let master_key =
+            Ed25519PublicKey::from_base64("F4P7f1Z0RjbiZMgHk1xBCG3KC4/Ng9PmxLJ4hQ13sHA").unwrap();
+        let sender_data = SenderData::sender_verified(
+            user_id!("@alice:localhost"),
+            device_id!("ALICE"),
+            master_key,
+        );
+
+        let mut key = session.export().await;
+        key.sender_data = Some(sender_data.clone());
+        let export = vec![key];
+
+        // A version 1 export drops the sender data.
+        let encrypted = encrypt_room_key_export(&export, PASSPHRASE, 1).unwrap();
+        let decrypted = decrypt_room_key_export(Cursor::new(encrypted), PASSPHRASE).unwrap();
+        assert!(decrypted[0].sender_data.is_none());
+
+        // While a version 2 export keeps it.
+        let encrypted = encrypt_room_key_export_v2(&export, PASSPHRASE, 1).unwrap();
+        let decrypted = decrypt_room_key_export(Cursor::new(encrypted), PASSPHRASE).unwrap();
+        assert_eq!(decrypted[0].sender_data, Some(sender_data.clone()));
+
+        let (other_machine, _) =
+            get_prepared_machine_test_helper(user_id!("@bob:localhost"), false).await;
+        other_machine.store().import_exported_room_keys(decrypted, |_, _| {}).await?;
+
+        let imported = other_machine
+            .store()
+            .get_inbound_group_session(room_id, session.session_id())
+            .await?
+            .expect("The session should have been imported");
+        // The exported sender data isn't trusted when importing the session.
+        assert_eq!(imported.sender_data, SenderData::default());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_export_for_key() -> OlmResult<()> {
+        let (machine, _) =
+            get_prepared_machine_test_helper(user_id!("@alice:localhost"), false).await;
+        let room_id = room_id!("!test:localhost");
+        machine.create_inbound_session_test_helper(room_id).await?;
+        let export = machine.store().export_room_keys(|s| s.room_id() == room_id).await?;
+
+        let secret_key = Curve25519SecretKey::new();
+        let encrypted =
+            encrypt_room_key_export_for_key(&export, Curve25519PublicKey::from(&secret_key))
+                .unwrap();
+
+        let decrypted =
+            decrypt_room_key_export_with_key(Cursor::new(encrypted.clone()), &secret_key).unwrap();
+        assert_eq!(decrypted.len(), export.len());
+        assert_eq!(decrypted[0].session_id, export[0].session_id);
+        assert_eq!(decrypted[0].session_key.to_base64(), export[0].session_key.to_base64());
+        // The sender data isn't trusted for exports encrypted for a public key.
+        assert!(export[0].sender_data.is_some());
+        assert!(decrypted[0].sender_data.is_none());
+
+        // Another key can't decrypt the export.
+        assert_matches!(
+            decrypt_room_key_export_with_key(
+                Cursor::new(encrypted.clone()),
+                &Curve25519SecretKey::new()
+            ),
+            Err(KeyExportError::InvalidMac)
+        );
+
+        // Neither can a passphrase.
+        assert_matches!(
+            decrypt_room_key_export(Cursor::new(encrypted), PASSPHRASE),
+            Err(KeyExportError::UnsupportedVersion)
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_real_decrypt() {
         let reader = Cursor::new(TEST_EXPORT);
@@ -4,4 +4,9 @@ mod key_export;
 pub use attachments::{
     AttachmentDecryptor, AttachmentEncryptor, DecryptorError, MediaEncryptionInfo,
 };
-pub use key_export::{decrypt_room_key_export, encrypt_room_key_export, KeyExportError};
+#[cfg(feature = "fuzzing")]
+pub(crate) use key_export::decrypt_room_key_export_with_max_rounds;
+pub use key_export::{
+    decrypt_room_key_export, decrypt_room_key_export_with_key, encrypt_room_key_export,
+    encrypt_room_key_export_for_key, encrypt_room_key_export_v2, KeyExportError,
+};
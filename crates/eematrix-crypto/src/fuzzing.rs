@@ -0,0 +1,373 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Entry points for fuzzing the parsing surface of the crypto crate.
+//!
+//! Every [`FuzzTarget`] feeds arbitrary bytes into one of the places where we
+//! deserialize data we got from the store, from the user or from the server.
+//! Invalid input must be rejected with an error, a panic is a bug.
+//!
+//! A `cargo-fuzz` target only needs to forward its input:
+//!
+//! ```ignore
+//! #![no_main]
+//!
+//! use libfuzzer_sys::fuzz_target;
+//! use matrix_sdk_crypto::fuzzing::FuzzTarget;
+//!
+//! fuzz_target!(|data: &[u8]| FuzzTarget::PickledSession.run(data));
+//! ```
+//!
+//! The `fuzz` directory of this crate contains such a target for every
+//! [`FuzzTarget`], named after [`FuzzTarget::name()`]. They can be run from the
+//! crate directory using e.g. `cargo +nightly fuzz run pickled_session`.
+//!
+//! Fuzzers find their way through the parsers much faster if they start out
+//! with valid inputs. Those can be generated using [`FuzzTarget::seed()`],
+//! which uses the given [`Unstructured`] data to vary the generated objects.
+
+use std::sync::OnceLock;
+
+use arbitrary::{Arbitrary, Unstructured};
+use futures_util::FutureExt;
+use ruma::{user_id, EventEncryptionAlgorithm, OwnedDeviceId, OwnedRoomId, RoomId};
+use serde_json::json;
+use vodozemac::{
+    base64_encode,
+    megolm::{GroupSession, InboundGroupSession as InnerInboundGroupSession, SessionConfig},
+    Curve25519SecretKey,
+};
+
+use crate::{
+    encrypt_room_key_export,
+    file_encryption::decrypt_room_key_export_with_max_rounds,
+    olm::{
+        Account, ExportedRoomKey, InboundGroupSession, PickledAccount, PickledInboundGroupSession,
+        PickledSession, Session,
+    },
+    types::{events::room_key_withheld::RoomKeyWithheldEvent, DeviceKeys, SecretsBundle},
+    DeviceData,
+};
+
+/// The passphrase used to decrypt the inputs of the
+/// [`FuzzTarget::RoomKeyExport`] target.
+pub const ROOM_KEY_EXPORT_PASSPHRASE: &str = "fuzzing";
+
+/// The number of PBKDF2 rounds used by the room key exports generated by
+/// [`FuzzTarget::seed()`], kept low so the fuzzer doesn't spend its time
+/// deriving keys.
+///
+/// The [`FuzzTarget::RoomKeyExport`] target clamps the rounds of its inputs to
+/// this number as well, otherwise an input claiming billions of rounds would
+/// keep the fuzzer busy for hours.
+const ROOM_KEY_EXPORT_ROUNDS: u32 = 10;
+
+/// A deserialization entry point which can be fuzzed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Arbitrary)]
+pub enum FuzzTarget {
+    /// A [`PickledAccount`], as it's found in the store, restored into an
+    /// [`Account`].
+    PickledAccount,
+    /// A [`PickledSession`], as it's found in the store, restored into an Olm
+    /// [`Session`].
+    PickledSession,
+    /// A [`PickledInboundGroupSession`], as it's found in the store, restored
+    /// into an [`InboundGroupSession`].
+    PickledInboundGroupSession,
+    /// A room key export file, decrypted using the
+    /// [`ROOM_KEY_EXPORT_PASSPHRASE`], whose keys are turned into
+    /// [`InboundGroupSession`]s.
+    RoomKeyExport,
+    /// An `m.room_key.withheld` to-device event.
+    RoomKeyWithheldEvent,
+    /// A [`SecretsBundle`], as it's received when logging in using a QR code.
+    SecretsBundle,
+}
+
+impl FuzzTarget {
+    /// All the fuzz targets.
+    pub const ALL: [FuzzTarget; 6] = [
+        FuzzTarget::PickledAccount,
+        FuzzTarget::PickledSession,
+        FuzzTarget::PickledInboundGroupSession,
+        FuzzTarget::RoomKeyExport,
+        FuzzTarget::RoomKeyWithheldEvent,
+        FuzzTarget::SecretsBundle,
+    ];
+
+    /// The name of the fuzz target, usable as the name of a `cargo-fuzz`
+    /// target or of a corpus directory.
+    pub fn name(self) -> &'static str {
+        match self {
+            FuzzTarget::PickledAccount => "pickled_account",
+            FuzzTarget::PickledSession => "pickled_session",
+            FuzzTarget::PickledInboundGroupSession => "pickled_inbound_group_session",
+            FuzzTarget::RoomKeyExport => "room_key_export",
+            FuzzTarget::RoomKeyWithheldEvent => "room_key_withheld_event",
+            FuzzTarget::SecretsBundle => "secrets_bundle",
+        }
+    }
+
+    /// Feed the given bytes into the deserialization entry point of this
+    /// target.
+    ///
+    /// Errors are ignored, the only thing this checks is that the input
+    /// doesn't make us panic.
+    pub fn run(self, data: &[u8]) {
+        match self {
+            FuzzTarget::PickledAccount => {
+                if let Ok(pickle) = serde_json::from_slice::<PickledAccount>(data) {
+                    if let Ok(account) = Account::from_pickle(pickle) {
+                        let _ = account.keys_for_upload();
+                    }
+                }
+            }
+            FuzzTarget::PickledSession => {
+                if let Ok(pickle) = serde_json::from_slice::<PickledSession>(data) {
+                    if let Ok(session) = Session::from_pickle(fuzzing_device_keys(), pickle) {
+                        let _ = session.session_id();
+                    }
+                }
+            }
+            FuzzTarget::PickledInboundGroupSession => {
+                if let Ok(pickle) = serde_json::from_slice::<PickledInboundGroupSession>(data) {
+                    if let Ok(session) = InboundGroupSession::from_pickle(pickle) {
+                        let _ = session.first_known_index();
+                    }
+                }
+            }
+            FuzzTarget::RoomKeyExport => {
+                if let Ok(keys) = decrypt_room_key_export_with_max_rounds(
+                    data,
+                    ROOM_KEY_EXPORT_PASSPHRASE,
+                    ROOM_KEY_EXPORT_ROUNDS,
+                ) {
+                    for key in &keys {
+                        let _ = InboundGroupSession::from_export(key);
+                    }
+                }
+            }
+            FuzzTarget::RoomKeyWithheldEvent => {
+                if let Ok(event) = serde_json::from_slice::<RoomKeyWithheldEvent>(data) {
+                    let _ = serde_json::to_vec(&event);
+                }
+            }
+            FuzzTarget::SecretsBundle => {
+                if let Ok(bundle) = serde_json::from_slice::<SecretsBundle>(data) {
+                    let _ = serde_json::to_vec(&bundle);
+                }
+            }
+        }
+    }
+
+    /// Generate a valid input for this target, which can be added to the
+    /// corpus of the fuzzer.
+    ///
+    /// The given [`Unstructured`] data decides about the variable parts of the
+    /// input, like identifiers and the number of keys. Key material is
+    /// generated randomly.
+    pub fn seed(self, u: &mut Unstructured<'_>) -> arbitrary::Result<Vec<u8>> {
+        let seed = match self {
+            FuzzTarget::PickledAccount => {
+                let mut account = arbitrary_account(u)?;
+
+                let key_count = u.int_in_range(0..=10)?;
+                account.generate_one_time_keys(key_count);
+
+                if u.arbitrary()? {
+                    account.mark_keys_as_published();
+                }
+
+                serde_json::to_vec(&account.pickle())
+            }
+            FuzzTarget::PickledSession => {
+                let account = arbitrary_account(u)?;
+                let mut other = arbitrary_account(u)?;
+
+                other.generate_one_time_keys(1);
+                let device = DeviceData::from_account(&other);
+
+                let session = account
+                    .create_outbound_session(
+                        &device,
+                        &other.signed_one_time_keys(),
+                        account.device_keys(),
+                    )
+                    .expect("We should be able to create a session using a fresh one-time key");
+
+                serde_json::to_vec(&session.pickle().now_or_never().expect(
+                    "Pickling a session which isn't shared with anybody shouldn't need to wait",
+                ))
+            }
+            FuzzTarget::PickledInboundGroupSession => {
+                let session = InboundGroupSession::from_export(&arbitrary_room_key(u)?)
+                    .expect("We should be able to import a freshly exported room key");
+
+                serde_json::to_vec(&session.pickle().now_or_never().expect(
+                    "Pickling a session which isn't shared with anybody shouldn't need to wait",
+                ))
+            }
+            FuzzTarget::RoomKeyExport => {
+                let key_count = u.int_in_range(0..=3)?;
+                let keys = (0..key_count)
+                    .map(|_| arbitrary_room_key(u))
+                    .collect::<arbitrary::Result<Vec<_>>>()?;
+
+                return Ok(encrypt_room_key_export(
+                    &keys,
+                    ROOM_KEY_EXPORT_PASSPHRASE,
+                    ROOM_KEY_EXPORT_ROUNDS,
+                )
+                .expect("We should be able to serialize room keys")
+                .into_bytes());
+            }
+            FuzzTarget::RoomKeyWithheldEvent => {
+                let code = u.choose(&[
+                    "m.blacklisted",
+                    "m.unverified",
+                    "m.unauthorised",
+                    "m.unavailable",
+                    "m.no_olm",
+                ])?;
+
+                let mut content = json!({
+                    "algorithm": "m.megolm.v1.aes-sha2",
+                    "sender_key": base64_encode(<[u8; 32]>::arbitrary(u)?),
+                    "code": code,
+                    "reason": String::arbitrary(u)?,
+                });
+
+                if *code != "m.no_olm" {
+                    content["room_id"] = json!(arbitrary_room_id(u)?);
+                    content["session_id"] = json!(base64_encode(<[u8; 32]>::arbitrary(u)?));
+                }
+
+                serde_json::to_vec(&json!({
+                    "sender": "@alice:example.org",
+                    "content": content,
+                    "type": "m.room_key.withheld",
+                }))
+            }
+            FuzzTarget::SecretsBundle => {
+                let mut bundle = json!({
+                    "cross_signing": {
+                        "master_key": base64_encode(<[u8; 32]>::arbitrary(u)?),
+                        "self_signing_key": base64_encode(<[u8; 32]>::arbitrary(u)?),
+                        "user_signing_key": base64_encode(<[u8; 32]>::arbitrary(u)?),
+                    },
+                });
+
+                if u.arbitrary()? {
+                    bundle["backup"] = json!({
+                        "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+                        "backup_version": u.int_in_range(1..=100u32)?.to_string(),
+                        "key": base64_encode(<[u8; 32]>::arbitrary(u)?),
+                    });
+                }
+
+                serde_json::to_vec(&bundle)
+            }
+        };
+
+        Ok(seed.expect("We should be able to serialize the generated input"))
+    }
+}
+
+/// The device keys of the account the [`FuzzTarget::PickledSession`] target
+/// restores Olm sessions for.
+fn fuzzing_device_keys() -> DeviceKeys {
+    static DEVICE_KEYS: OnceLock<DeviceKeys> = OnceLock::new();
+
+    DEVICE_KEYS.get_or_init(|| Account::new(user_id!("@fuzzing:localhost")).device_keys()).clone()
+}
+
+fn arbitrary_identifier(u: &mut Unstructured<'_>) -> arbitrary::Result<String> {
+    let len = u.int_in_range(1..=16)?;
+
+    (0..len).map(|_| u.int_in_range(b'A'..=b'Z').map(char::from)).collect()
+}
+
+fn arbitrary_room_id(u: &mut Unstructured<'_>) -> arbitrary::Result<OwnedRoomId> {
+    let room_id = format!("!{}:example.org", arbitrary_identifier(u)?);
+
+    Ok(RoomId::parse(room_id).expect("We should always generate valid room IDs"))
+}
+
+fn arbitrary_account(u: &mut Unstructured<'_>) -> arbitrary::Result<Account> {
+    let device_id: OwnedDeviceId = arbitrary_identifier(u)?.into();
+
+    Ok(Account::with_device_id(user_id!("@alice:example.org"), &device_id))
+}
+
+fn arbitrary_room_key(u: &mut Unstructured<'_>) -> arbitrary::Result<ExportedRoomKey> {
+    let group_session = GroupSession::new(SessionConfig::version_1());
+    let session =
+        InnerInboundGroupSession::new(&group_session.session_key(), SessionConfig::version_1());
+
+    let forwarding_chain_len = u.int_in_range(0..=2)?;
+
+    Ok(ExportedRoomKey {
+        algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
+        room_id: arbitrary_room_id(u)?,
+        sender_key: Curve25519SecretKey::new().public_key(),
+        session_id: group_session.session_id(),
+        session_key: session.export_at_first_known_index(),
+        sender_claimed_keys: Default::default(),
+        forwarding_curve25519_key_chain: (0..forwarding_chain_len)
+            .map(|_| Curve25519SecretKey::new().public_key())
+            .collect(),
+        shared_history: u.arbitrary()?,
+        sender_data: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use arbitrary::Unstructured;
+    use vodozemac::base64_encode;
+
+    use super::FuzzTarget;
+
+    #[test]
+    fn test_seeds_are_valid_inputs() {
+        let data: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let mut u = Unstructured::new(&data);
+
+        for target in FuzzTarget::ALL {
+            let seed = target.seed(&mut u).unwrap();
+            assert!(!seed.is_empty(), "The seed for {} shouldn't be empty", target.name());
+
+            target.run(&seed);
+            target.run(&seed[..seed.len() / 2]);
+        }
+    }
+
+    #[test]
+    fn test_room_key_export_rounds_are_clamped() {
+        // A version 1 export with a zeroed salt and IV, claiming the maximal
+        // number of rounds, followed by a zeroed ciphertext and MAC.
+        let mut payload = vec![1u8];
+        payload.extend([0u8; 32]);
+        payload.extend(u32::MAX.to_be_bytes());
+        payload.extend([0u8; 48]);
+
+        let export = format!(
+            "-----BEGIN MEGOLM SESSION DATA-----\n{}\n-----END MEGOLM SESSION DATA-----",
+            base64_encode(payload)
+        );
+
+        // Without the clamping, deriving the key would take hours.
+        FuzzTarget::RoomKeyExport.run(export.as_bytes());
+    }
+}
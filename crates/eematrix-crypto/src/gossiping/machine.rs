@@ -21,7 +21,7 @@
 // let the users introspect that object.
 
 use std::{
-    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, VecDeque},
     mem,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -35,13 +35,16 @@ use ruma::{
     events::secret::request::{
         RequestAction, SecretName, ToDeviceSecretRequestEvent as SecretRequestEvent,
     },
-    DeviceId, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedTransactionId, OwnedUserId, RoomId,
-    TransactionId, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedTransactionId,
+    OwnedUserId, RoomId, TransactionId, UserId,
 };
 use tracing::{debug, field::debug, info, instrument, trace, warn, Span};
 use vodozemac::{megolm::SessionOrdering, Curve25519PublicKey};
 
-use super::{GossipRequest, GossippedSecret, RequestEvent, RequestInfo, SecretInfo, WaitQueue};
+use super::{
+    GossipRequest, GossippedSecret, KeyRequestState, KeyRequestStatus, RequestEvent, RequestInfo,
+    SecretInfo, WaitQueue,
+};
 use crate::{
     error::{EventError, OlmError, OlmResult},
     identities::IdentityManager,
@@ -83,6 +86,16 @@ pub(crate) struct GossipMachineInner {
     /// Whether we should send out `m.room_key_request` messages.
     room_key_requests_enabled: AtomicBool,
 
+    /// Whether we handle incoming requests and send out our own at all, see
+    /// [`OlmMachineFeatures::gossiping`].
+    ///
+    /// [`OlmMachineFeatures::gossiping`]: crate::OlmMachineFeatures::gossiping
+    gossiping_enabled: AtomicBool,
+
+    /// The maximum number of times a forwarded room key may have been
+    /// forwarded for us to accept it, `None` if there is no limit.
+    max_forwarding_depth: StdRwLock<Option<usize>>,
+
     identity_manager: IdentityManager,
 }
 
@@ -110,6 +123,8 @@ impl GossipMachine {
                 users_for_key_claim,
                 room_key_forwarding_enabled,
                 room_key_requests_enabled,
+                gossiping_enabled: AtomicBool::new(true),
+                max_forwarding_depth: Default::default(),
                 identity_manager,
             }),
         }
@@ -141,16 +156,66 @@ impl GossipMachine {
         self.inner.room_key_requests_enabled.load(Ordering::SeqCst)
     }
 
-    /// Load stored outgoing requests that were not yet sent out.
+    /// Turn gossiping on or off as a whole.
+    ///
+    /// Turning it off also turns off room key requests and forwarding.
+    pub(crate) fn set_gossiping_enabled(&self, enabled: bool) {
+        self.inner.gossiping_enabled.store(enabled, Ordering::SeqCst);
+
+        if !enabled {
+            self.inner.room_key_requests_enabled.store(false, Ordering::SeqCst);
+            self.inner.room_key_forwarding_enabled.store(false, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn is_gossiping_enabled(&self) -> bool {
+        self.inner.gossiping_enabled.load(Ordering::SeqCst)
+    }
+
+    /// Configure the maximum number of times a forwarded room key may have
+    /// been forwarded for us to accept it.
+    pub fn set_max_forwarding_depth(&self, max_depth: Option<usize>) {
+        *self.inner.max_forwarding_depth.write() = max_depth;
+    }
+
+    /// Get the maximum number of times a forwarded room key may have been
+    /// forwarded for us to accept it.
+    pub fn max_forwarding_depth(&self) -> Option<usize> {
+        *self.inner.max_forwarding_depth.read()
+    }
+
+    /// Load stored outgoing requests that were not yet sent out, and which
+    /// aren't backing off after a failure.
     async fn load_outgoing_requests(&self) -> Result<Vec<OutgoingRequest>, CryptoStoreError> {
+        let now = self.inner.store.clock().now();
+
+        Ok(self
+            .due_requests(now)
+            .await?
+            .into_iter()
+            .map(|info| info.to_request(self.device_id()))
+            .collect())
+    }
+
+    /// Get the stored outgoing requests which were not yet sent out, and may
+    /// be sent out at `now`.
+    ///
+    /// Requests which failed to be sent out, see
+    /// [`GossipMachine::mark_outgoing_request_as_failed()`], are only due
+    /// once their [`GossipRequestRetry::next_retry_at`] has passed.
+    ///
+    /// [`GossipRequestRetry::next_retry_at`]: super::GossipRequestRetry::next_retry_at
+    pub async fn due_requests(
+        &self,
+        now: MilliSecondsSinceUnixEpoch,
+    ) -> Result<Vec<GossipRequest>, CryptoStoreError> {
         Ok(self
             .inner
             .store
             .get_unsent_secret_requests()
             .await?
             .into_iter()
-            .filter(|i| !i.sent_out)
-            .map(|info| info.to_request(self.device_id()))
+            .filter(|i| !i.sent_out && i.retry.is_due(now))
             .collect())
     }
 
@@ -164,13 +229,25 @@ impl GossipMachine {
         &self.inner.store.static_account().device_id
     }
 
+    /// Get the room keys and secrets we are forwarding to other devices,
+    /// which weren't sent out yet.
+    ///
+    /// Unlike our own key requests, these are only kept in memory.
+    pub fn outgoing_key_forwards(&self) -> Vec<OutgoingRequest> {
+        self.inner.outgoing_requests.read().values().cloned().collect()
+    }
+
     pub async fn outgoing_to_device_requests(
         &self,
     ) -> Result<Vec<OutgoingRequest>, CryptoStoreError> {
-        let mut key_requests = self.load_outgoing_requests().await?;
-        let key_forwards: Vec<OutgoingRequest> =
-            self.inner.outgoing_requests.read().values().cloned().collect();
-        key_requests.extend(key_forwards);
+        let mut key_requests = Vec::new();
+
+        // The key claims are still needed to unwedge sessions if gossiping is
+        // turned off, our own requests stay queued up.
+        if self.is_gossiping_enabled() {
+            key_requests = self.load_outgoing_requests().await?;
+            key_requests.extend(self.outgoing_key_forwards());
+        }
 
         let users_for_key_claim: BTreeMap<_, _> = self
             .inner
@@ -211,6 +288,8 @@ impl GossipMachine {
         // so.
         if event.sender() == self.user_id() && event.requesting_device_id() == self.device_id() {
             trace!("Received a secret request event from ourselves, ignoring")
+        } else if !self.is_gossiping_enabled() {
+            trace!("Received a secret request event, but gossiping is disabled, ignoring")
         } else {
             let request_info = event.to_request_info();
             self.inner.incoming_key_requests.write().insert(request_info, event);
@@ -718,6 +797,7 @@ impl GossipMachine {
 
         if let Some(request) = request {
             let cancel = request.to_cancellation(self.device_id());
+            self.update_key_request_status(&request, KeyRequestState::Unsent).await?;
             let request = request.to_request(self.device_id());
 
             Ok((Some(cancel), request))
@@ -728,6 +808,54 @@ impl GossipMachine {
         }
     }
 
+    /// Create a new outgoing key request for the key with the given session
+    /// id, which is only sent to the given devices of a single user.
+    ///
+    /// An existing request for the key is replaced by the new one. If it was
+    /// already sent out, a cancel request for it is returned as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `event` - The event for which we would like to request the room key.
+    ///
+    /// * `recipient` - The user owning the devices.
+    ///
+    /// * `devices` - The devices the request should be sent to.
+    pub async fn request_key_from(
+        &self,
+        room_id: &RoomId,
+        event: &EncryptedEvent,
+        recipient: &UserId,
+        devices: BTreeSet<OwnedDeviceId>,
+    ) -> Result<(Option<OutgoingRequest>, OutgoingRequest), MegolmError> {
+        let secret_info =
+            event.room_key_info(room_id).ok_or(EventError::UnsupportedAlgorithm)?.into();
+
+        let cancel = if let Some(request) =
+            self.inner.store.get_secret_request_by_info(&secret_info).await?
+        {
+            self.inner.outgoing_requests.write().remove(&request.request_id);
+            self.delete_key_info(&request).await?;
+
+            request.sent_out.then(|| request.to_cancellation(self.device_id()))
+        } else {
+            None
+        };
+
+        let request = GossipRequest {
+            request_recipient: recipient.to_owned(),
+            request_id: TransactionId::new(),
+            info: secret_info,
+            sent_out: false,
+            recipient_devices: Some(devices),
+            retry: Default::default(),
+        };
+
+        Ok((cancel, self.save_key_request(request).await?))
+    }
+
     /// Create outgoing secret requests for the given
     pub fn request_missing_secrets(
         own_user_id: &UserId,
@@ -755,14 +883,191 @@ impl GossipMachine {
             request_id: TransactionId::new(),
             info: key_info,
             sent_out: false,
+            recipient_devices: None,
+            retry: Default::default(),
         };
 
+        self.save_key_request(request).await
+    }
+
+    async fn save_key_request(
+        &self,
+        request: GossipRequest,
+    ) -> Result<OutgoingRequest, CryptoStoreError> {
         let outgoing_request = request.to_request(self.device_id());
+        self.update_key_request_status(&request, KeyRequestState::Unsent).await?;
         self.save_outgoing_key_info(request).await?;
 
         Ok(outgoing_request)
     }
 
+    /// The key under which the keys of the statuses of finished room key
+    /// requests are persisted, oldest first.
+    const FINISHED_KEY_REQUESTS_KEY: &'static str = "finished_key_requests";
+
+    /// The maximum number of statuses of finished room key requests we keep,
+    /// older ones are deleted.
+    const MAX_FINISHED_KEY_REQUESTS: usize = 1000;
+
+    fn key_request_status_key(room_id: &RoomId, session_id: &str) -> String {
+        format!("key_request_status:{room_id}:{session_id}")
+    }
+
+    /// Get the status of our outgoing room key request for the given session.
+    ///
+    /// Returns `None` if we never requested the room key of the session.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `session_id` - The id that uniquely identifies the session.
+    pub async fn key_request_status(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<KeyRequestStatus>, CryptoStoreError> {
+        self.inner.store.get_value(&Self::key_request_status_key(room_id, session_id)).await
+    }
+
+    /// Persist the new state of the given outgoing request.
+    ///
+    /// Only room key requests are tracked, requests for other secrets are
+    /// ignored.
+    async fn update_key_request_status(
+        &self,
+        request: &GossipRequest,
+        state: KeyRequestState,
+    ) -> Result<(), CryptoStoreError> {
+        let SecretInfo::KeyRequest(info) = &request.info else {
+            return Ok(());
+        };
+
+        let key = Self::key_request_status_key(info.room_id(), info.session_id());
+
+        let recipient_devices = if let (KeyRequestState::Pending, Some(devices)) =
+            (&state, &request.recipient_devices)
+        {
+            devices.clone()
+        } else if state == KeyRequestState::Pending {
+            // The request is sent to all the devices of the recipient, remember which
+            // ones we knew about at that point.
+            self.inner
+                .store
+                .get_user_devices(&request.request_recipient)
+                .await?
+                .keys()
+                .filter(|device_id| *device_id != self.device_id())
+                .map(ToOwned::to_owned)
+                .collect()
+        } else {
+            self.inner
+                .store
+                .get_value::<KeyRequestStatus>(&key)
+                .await?
+                .filter(|status| status.request_id == request.request_id)
+                .map(|status| status.recipient_devices)
+                .unwrap_or_default()
+        };
+
+        let status = KeyRequestStatus {
+            request_id: request.request_id.clone(),
+            recipient: request.request_recipient.clone(),
+            recipient_devices,
+            state,
+            updated_at: self.inner.store.clock().now(),
+        };
+
+        self.inner.store.set_value(&key, &status).await?;
+
+        if status.state.is_finished() {
+            self.forget_old_key_request_statuses(key).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remember that the status stored under the given key belongs to a
+    /// finished request, and delete the statuses of the oldest finished
+    /// requests once there are too many of them.
+    async fn forget_old_key_request_statuses(&self, key: String) -> Result<(), CryptoStoreError> {
+        let mut finished = self
+            .inner
+            .store
+            .get_value::<VecDeque<String>>(Self::FINISHED_KEY_REQUESTS_KEY)
+            .await?
+            .unwrap_or_default();
+
+        finished.retain(|k| *k != key);
+        finished.push_back(key);
+
+        while finished.len() > Self::MAX_FINISHED_KEY_REQUESTS {
+            let Some(old_key) = finished.pop_front() else {
+                break;
+            };
+
+            // The room key might have been requested again since.
+            let status = self.inner.store.get_value::<KeyRequestStatus>(&old_key).await?;
+
+            if status.is_some_and(|s| s.state.is_finished()) {
+                self.inner.store.remove_custom_value(&old_key).await?;
+            }
+        }
+
+        self.inner.store.set_value(Self::FINISHED_KEY_REQUESTS_KEY, &finished).await
+    }
+
+    /// Cancel our outgoing room key request for the given session.
+    ///
+    /// This will queue up a request cancellation if the request was already
+    /// sent out.
+    ///
+    /// Returns `false` if there is no request for the session waiting for an
+    /// answer.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `session_id` - The id that uniquely identifies the session.
+    pub async fn cancel_key_request(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<bool, CryptoStoreError> {
+        let Some(status) = self.key_request_status(room_id, session_id).await? else {
+            return Ok(false);
+        };
+
+        if !matches!(status.state, KeyRequestState::Unsent | KeyRequestState::Pending) {
+            return Ok(false);
+        }
+
+        let Some(request) =
+            self.inner.store.get_outgoing_secret_requests(&status.request_id).await?
+        else {
+            return Ok(false);
+        };
+
+        trace!(
+            recipient = ?request.request_recipient,
+            request_id = ?request.request_id,
+            "Cancelling an outgoing room key request"
+        );
+
+        self.inner.outgoing_requests.write().remove(&request.request_id);
+        self.delete_key_info(&request).await?;
+
+        if request.sent_out {
+            let cancel = request.to_cancellation(self.device_id());
+            self.inner.outgoing_requests.write().insert(cancel.request_id.clone(), cancel);
+        }
+
+        self.update_key_request_status(&request, KeyRequestState::Cancelled).await?;
+
+        Ok(true)
+    }
+
     /// Create a new outgoing key request for the key with the given session id.
     ///
     /// This will queue up a new to-device request and store the key info so
@@ -823,6 +1128,7 @@ impl GossipMachine {
                 "Marking outgoing secret request as sent"
             );
             info.sent_out = true;
+            self.update_key_request_status(&info, KeyRequestState::Pending).await?;
             self.save_outgoing_key_info(info).await?;
         }
 
@@ -831,6 +1137,41 @@ impl GossipMachine {
         Ok(())
     }
 
+    /// Record that sending out the outgoing request with the given ID failed.
+    ///
+    /// The request is left out of the outgoing requests until its
+    /// [`GossipRequestRetry::next_retry_at`], which backs off exponentially
+    /// with every failed attempt.
+    ///
+    /// [`GossipRequestRetry::next_retry_at`]: super::GossipRequestRetry::next_retry_at
+    pub async fn mark_outgoing_request_as_failed(
+        &self,
+        id: &TransactionId,
+        error: &str,
+    ) -> Result<(), CryptoStoreError> {
+        let Some(mut info) = self.inner.store.get_outgoing_secret_requests(id).await? else {
+            return Ok(());
+        };
+
+        if info.sent_out {
+            return Ok(());
+        }
+
+        info.retry.fail(error.to_owned(), self.inner.store.clock().now());
+
+        debug!(
+            recipient = ?info.request_recipient,
+            request_type = info.request_type(),
+            request_id = ?info.request_id,
+            attempts = info.retry.attempts,
+            next_retry_at = ?info.retry.next_retry_at,
+            error,
+            "Failed to send out a secret request, backing off"
+        );
+
+        self.save_outgoing_key_info(info).await
+    }
+
     /// Mark the given outgoing key info as done.
     ///
     /// This will queue up a request cancellation.
@@ -972,11 +1313,37 @@ impl GossipMachine {
     ) -> Result<Option<InboundGroupSession>, CryptoStoreError> {
         match InboundGroupSession::try_from(event) {
             Ok(session) => {
+                let session = session.with_forwarder(sender_key);
+
+                if let Some(max_depth) = self.max_forwarding_depth() {
+                    if session.forwarding_depth() > max_depth {
+                        warn!(
+                            ?sender_key,
+                            room_id = ?session.room_id(),
+                            session_id = session.session_id(),
+                            forwarding_depth = session.forwarding_depth(),
+                            max_depth,
+                            "Received a forwarded room key which has been forwarded too many times",
+                        );
+
+                        return Ok(None);
+                    }
+                }
+
                 if self.inner.store.compare_group_session(&session).await?
                     == SessionOrdering::Better
                 {
                     self.mark_as_done(info).await?;
 
+                    let device_id = self
+                        .inner
+                        .store
+                        .get_device_from_curve_key(&info.request_recipient, sender_key)
+                        .await?
+                        .map(|device| device.device_id().to_owned());
+                    self.update_key_request_status(info, KeyRequestState::Answered { device_id })
+                        .await?;
+
                     info!(
                         ?sender_key,
                         claimed_sender_key = ?session.sender_key(),
@@ -1011,14 +1378,23 @@ impl GossipMachine {
         &self,
         info: &GossipRequest,
         sender_key: Curve25519PublicKey,
+        event: &DecryptedForwardedRoomKeyEvent,
     ) -> Result<bool, CryptoStoreError> {
         let device =
             self.inner.store.get_device_from_curve_key(&info.request_recipient, sender_key).await?;
 
-        if let Some(device) = device {
-            Ok(device.user_id() == self.user_id() && device.is_verified())
+        let Some(device) = device else {
+            return Ok(false);
+        };
+
+        if device.user_id() == self.user_id() {
+            Ok(device.is_verified())
         } else {
-            Ok(false)
+            // Requests to other users are only sent to the device which created
+            // the session, so the device must forward a session it created itself.
+            Ok(info.recipient_devices.as_ref().is_some_and(|d| d.contains(device.device_id()))
+                && !device.is_blacklisted()
+                && event.content.claimed_sender_key() == Some(sender_key))
         }
     }
 
@@ -1052,7 +1428,7 @@ impl GossipMachine {
             return Ok(None);
         };
 
-        if self.should_accept_forward(&request, sender_key).await? {
+        if self.should_accept_forward(&request, sender_key, event).await? {
             self.accept_forwarded_room_key(&request, sender_key, event).await
         } else {
             warn!(
@@ -1097,7 +1473,7 @@ impl GossipMachine {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{collections::BTreeSet, sync::Arc};
 
     #[cfg(feature = "automatic-room-key-forwarding")]
     use assert_matches::assert_matches;
@@ -1110,6 +1486,7 @@ mod tests {
         },
         room_id,
         serde::Raw,
+        to_device::DeviceIdOrAllDevices,
         user_id, DeviceId, RoomId, UserId,
     };
     use tokio::sync::Mutex;
@@ -1118,7 +1495,7 @@ mod tests {
     #[cfg(feature = "automatic-room-key-forwarding")]
     use crate::{
         gossiping::KeyForwardDecision,
-        olm::OutboundGroupSession,
+        olm::{InboundGroupSession, OutboundGroupSession},
         store::{types::DeviceChanges, CryptoStore},
         types::requests::AnyOutgoingRequest,
         types::{
@@ -1131,6 +1508,7 @@ mod tests {
         EncryptionSettings,
     };
     use crate::{
+        gossiping::KeyRequestState,
         identities::{DeviceData, IdentityManager, LocalTrust},
         olm::{Account, PrivateCrossSigningIdentity},
         session_manager::GroupSessionCache,
@@ -1418,6 +1796,106 @@ mod tests {
         assert!(cancel.is_some());
     }
 
+    #[async_test]
+    async fn test_key_request_status() {
+        let machine = get_machine_test_helper().await;
+        let account = account();
+
+        let (outbound, session) = account.create_group_session_pair_with_defaults(room_id()).await;
+        let content = outbound.encrypt("m.dummy", &message_like_event_content!({})).await;
+        let event = wrap_encrypted_content(machine.user_id(), content);
+
+        let status = machine.key_request_status(room_id(), session.session_id()).await.unwrap();
+        assert!(status.is_none());
+
+        let (_, request) = machine.request_key(session.room_id(), &event).await.unwrap();
+
+        let status =
+            machine.key_request_status(room_id(), session.session_id()).await.unwrap().unwrap();
+        assert_eq!(status.request_id, request.request_id);
+        assert_eq!(status.recipient, machine.user_id());
+        assert_eq!(status.state, KeyRequestState::Unsent);
+
+        machine.mark_outgoing_request_as_sent(&request.request_id).await.unwrap();
+
+        let status =
+            machine.key_request_status(room_id(), session.session_id()).await.unwrap().unwrap();
+        assert_eq!(status.state, KeyRequestState::Pending);
+
+        // Cancelling the request queues up a cancellation since it was sent out.
+        assert!(machine.cancel_key_request(room_id(), session.session_id()).await.unwrap());
+        assert_eq!(machine.outgoing_to_device_requests().await.unwrap().len(), 1);
+
+        let status =
+            machine.key_request_status(room_id(), session.session_id()).await.unwrap().unwrap();
+        assert_eq!(status.request_id, request.request_id);
+        assert_eq!(status.state, KeyRequestState::Cancelled);
+
+        // There is nothing left to cancel.
+        assert!(!machine.cancel_key_request(room_id(), session.session_id()).await.unwrap());
+
+        // Once enough other requests finished, the status is deleted.
+        for i in 0..GossipMachine::MAX_FINISHED_KEY_REQUESTS {
+            let key = GossipMachine::key_request_status_key(room_id(), &i.to_string());
+            machine.inner.store.set_value(&key, &status).await.unwrap();
+            machine.forget_old_key_request_statuses(key).await.unwrap();
+        }
+
+        assert!(machine
+            .key_request_status(room_id(), session.session_id())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[async_test]
+    async fn test_request_key_from_devices() {
+        let machine = get_machine_test_helper().await;
+        let account = account();
+
+        let (outbound, session) = account.create_group_session_pair_with_defaults(room_id()).await;
+        let content = outbound.encrypt("m.dummy", &message_like_event_content!({})).await;
+        let event = wrap_encrypted_content(machine.user_id(), content);
+
+        let devices = BTreeSet::from([alice2_device_id().to_owned()]);
+        let (cancel, request) =
+            machine.request_key_from(room_id(), &event, alice_id(), devices.clone()).await.unwrap();
+        assert!(cancel.is_none());
+
+        // The request is only sent to the given device.
+        let recipients = &request.request().to_device().unwrap().messages[alice_id()];
+        assert_eq!(recipients.len(), 1);
+        assert!(
+            recipients.contains_key(&DeviceIdOrAllDevices::DeviceId(alice2_device_id().to_owned()))
+        );
+
+        machine.mark_outgoing_request_as_sent(&request.request_id).await.unwrap();
+
+        let status =
+            machine.key_request_status(room_id(), session.session_id()).await.unwrap().unwrap();
+        assert_eq!(status.state, KeyRequestState::Pending);
+        assert_eq!(status.recipient_devices, devices);
+
+        // Requesting the key again replaces the request, and cancels the old one on
+        // the same device.
+        let (cancel, new_request) =
+            machine.request_key_from(room_id(), &event, alice_id(), devices).await.unwrap();
+        let cancel = cancel.expect("The request was sent out, so it should be cancelled");
+        let recipients = &cancel.request().to_device().unwrap().messages[alice_id()];
+        assert!(
+            recipients.contains_key(&DeviceIdOrAllDevices::DeviceId(alice2_device_id().to_owned()))
+        );
+
+        assert_ne!(new_request.request_id, request.request_id);
+        assert!(machine
+            .inner
+            .store
+            .get_outgoing_secret_requests(&request.request_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
     #[async_test]
     #[cfg(feature = "automatic-room-key-forwarding")]
     async fn test_create_key_request() {
@@ -1592,6 +2070,67 @@ mod tests {
         assert_eq!(second_session.unwrap().first_known_index(), 0);
     }
 
+    #[async_test]
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    async fn test_max_forwarding_depth() {
+        let machine = get_machine_test_helper().await;
+        let account = account();
+
+        let second_account = alice_2_account();
+        let alice_device = DeviceData::from_account(&second_account);
+        let forwarder_key = alice_device.curve25519_key().unwrap();
+
+        alice_device.set_trust_state(LocalTrust::Verified);
+        let devices = std::slice::from_ref(&alice_device);
+        machine.inner.store.save_device_data(devices).await.unwrap();
+
+        let (outbound, session) = account.create_group_session_pair_with_defaults(room_id()).await;
+        let content = outbound.encrypt("m.dummy", &message_like_event_content!({})).await;
+        let room_event = wrap_encrypted_content(machine.user_id(), content);
+
+        machine.create_outgoing_key_request(session.room_id(), &room_event).await.unwrap();
+        let requests = machine.outgoing_to_device_requests().await.unwrap();
+        machine.mark_outgoing_request_as_sent(&requests[0].request_id).await.unwrap();
+
+        // The key has already been forwarded once before it was forwarded to us.
+        let mut export = session.export().await;
+        export.forwarding_curve25519_key_chain = vec![account.identity_keys().curve25519];
+        let content: ForwardedRoomKeyContent = export.try_into().unwrap();
+        let event = DecryptedOlmV1Event::new(
+            alice_id(),
+            alice_id(),
+            alice_device.ed25519_key().unwrap(),
+            None,
+            content,
+        );
+
+        machine.set_max_forwarding_depth(Some(1));
+        assert_eq!(machine.max_forwarding_depth(), Some(1));
+        assert!(machine.receive_forwarded_room_key(forwarder_key, &event).await.unwrap().is_none());
+
+        machine.set_max_forwarding_depth(Some(2));
+        let forwarded = machine
+            .receive_forwarded_room_key(forwarder_key, &event)
+            .await
+            .unwrap()
+            .expect("A key which was forwarded twice should be accepted");
+
+        assert_eq!(forwarded.forwarding_depth(), 2);
+        assert_eq!(
+            forwarded.forwarding_chain(),
+            [account.identity_keys().curve25519, forwarder_key]
+        );
+
+        // The chain survives a round trip through the store and is included in
+        // exports.
+        let unpickled = InboundGroupSession::from_pickle(forwarded.pickle().await).unwrap();
+        assert_eq!(unpickled.forwarding_chain(), forwarded.forwarding_chain());
+        assert_eq!(
+            unpickled.export().await.forwarding_curve25519_key_chain,
+            forwarded.forwarding_chain()
+        );
+    }
+
     #[async_test]
     #[cfg(feature = "automatic-room-key-forwarding")]
     async fn test_should_share_key() {
@@ -1782,7 +2321,20 @@ mod tests {
             .unwrap()
             .unwrap();
 
-        assert_eq!(session.session_id(), group_session.session_id())
+        assert_eq!(session.session_id(), group_session.session_id());
+        assert_eq!(session.forwarding_depth(), 1);
+        assert_eq!(session.forwarding_chain(), [decrypted.result.sender_key]);
+
+        // The status of the request tells us which device answered it.
+        let status = alice_machine
+            .key_request_status(room_id(), group_session.session_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            status.state,
+            KeyRequestState::Answered { device_id: Some(bob_machine.device_id().to_owned()) }
+        );
     }
 
     #[async_test]
@@ -1855,6 +2407,67 @@ mod tests {
         assert!(session.is_none(), "We should not receive a room key from another user");
     }
 
+    #[async_test]
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    async fn test_accept_forward_from_the_session_creator() {
+        let (alice_machine, group_session, bob_machine) = machines_for_key_share_test_helper(
+            bob_id(),
+            true,
+            EventEncryptionAlgorithm::MegolmV1AesSha2,
+        )
+        .await;
+
+        // Request the room key from the device of bob which created it, instead of
+        // our own devices.
+        let content = group_session.encrypt("m.dummy", &message_like_event_content!({})).await;
+        let room_event = wrap_encrypted_content(bob_machine.user_id(), content);
+        let devices = BTreeSet::from([bob_machine.device_id().to_owned()]);
+        let (cancel, request) = alice_machine
+            .request_key_from(room_id(), &room_event, bob_id(), devices)
+            .await
+            .unwrap();
+        assert!(cancel.is_none(), "The automatic request wasn't sent out yet");
+
+        let event = request_to_event(bob_id(), alice_id(), &request);
+        alice_machine.mark_outgoing_request_as_sent(&request.request_id).await.unwrap();
+
+        // Receive the room key request from alice.
+        bob_machine.receive_incoming_key_request(&event);
+        {
+            let bob_cache = bob_machine.inner.store.cache().await.unwrap();
+            bob_machine.collect_incoming_key_requests(&bob_cache).await.unwrap();
+        }
+
+        // Get the answer and convert it to a encrypted to-device event.
+        let requests = bob_machine.outgoing_to_device_requests().await.unwrap();
+        let request = &requests[0];
+        let event: EncryptedToDeviceEvent = request_to_event(alice_id(), bob_id(), request);
+
+        let decrypted = alice_machine
+            .inner
+            .store
+            .with_transaction(|mut tr| async {
+                let res = tr
+                    .account()
+                    .await?
+                    .decrypt_to_device_event(&alice_machine.inner.store, &event)
+                    .await?;
+                Ok((tr, res))
+            })
+            .await
+            .unwrap();
+        let AnyDecryptedOlmEvent::ForwardedRoomKey(ev) = &*decrypted.result.event else {
+            panic!("Invalid decrypted event type");
+        };
+
+        let session = alice_machine
+            .receive_forwarded_room_key(decrypted.result.sender_key, ev)
+            .await
+            .unwrap()
+            .expect("We should accept the room key from the device which created it");
+        assert_eq!(session.session_id(), group_session.session_id());
+    }
+
     #[async_test]
     #[cfg(feature = "automatic-room-key-forwarding")]
     async fn test_key_share_cycle_megolm_v1() {
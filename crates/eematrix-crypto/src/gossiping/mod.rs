@@ -17,6 +17,7 @@ mod machine;
 use std::{
     collections::{BTreeMap, BTreeSet},
     sync::Arc,
+    time::Duration,
 };
 
 pub(crate) use machine::GossipMachine;
@@ -32,7 +33,8 @@ use ruma::{
     },
     serde::Raw,
     to_device::DeviceIdOrAllDevices,
-    DeviceId, OwnedDeviceId, OwnedTransactionId, OwnedUserId, TransactionId, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedTransactionId, OwnedUserId,
+    TransactionId, UInt, UserId,
 };
 use serde::{Deserialize, Serialize};
 
@@ -85,6 +87,49 @@ pub enum KeyForwardDecision {
     ChangedSenderKey,
 }
 
+/// The state of an outgoing `m.room_key_request`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum KeyRequestState {
+    /// The request was created but it hasn't been sent out yet.
+    Unsent,
+    /// The request was sent out and we are waiting for one of the recipients
+    /// to answer it.
+    Pending,
+    /// The room key was forwarded to us by one of the recipients.
+    Answered {
+        /// The device that forwarded the room key, if we know it.
+        device_id: Option<OwnedDeviceId>,
+    },
+    /// The request was cancelled before anyone answered it.
+    Cancelled,
+}
+
+impl KeyRequestState {
+    /// Is the request done, i.e. was it answered or cancelled?
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Answered { .. } | Self::Cancelled)
+    }
+}
+
+/// The status of an outgoing `m.room_key_request`.
+///
+/// The status is persisted in the store, so it can be used to tell users that
+/// we're requesting a room key from their other devices across restarts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyRequestStatus {
+    /// The unique id of the key request.
+    pub request_id: OwnedTransactionId,
+    /// The user the request was sent to.
+    pub recipient: OwnedUserId,
+    /// The devices of the recipient that we knew about when the request was
+    /// sent out.
+    pub recipient_devices: BTreeSet<OwnedDeviceId>,
+    /// The current state of the request.
+    pub state: KeyRequestState,
+    /// When the state of the request last changed.
+    pub updated_at: MilliSecondsSinceUnixEpoch,
+}
+
 /// A struct describing an outgoing key request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GossipRequest {
@@ -96,6 +141,51 @@ pub struct GossipRequest {
     pub info: SecretInfo,
     /// Has the request been sent out.
     pub sent_out: bool,
+    /// The devices of the recipient the request is sent to, `None` if it's
+    /// sent to all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient_devices: Option<BTreeSet<OwnedDeviceId>>,
+    /// The failed attempts at sending out the request.
+    #[serde(default)]
+    pub retry: GossipRequestRetry,
+}
+
+/// The failed attempts at sending out a [`GossipRequest`].
+///
+/// A request which failed to be sent out is retried with an exponential
+/// backoff: it's left out of the outgoing requests until
+/// [`GossipRequestRetry::next_retry_at`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GossipRequestRetry {
+    /// How many times in a row sending out the request failed.
+    pub attempts: u32,
+    /// The error of the last failed attempt.
+    pub last_error: Option<String>,
+    /// When the request may be sent out again, `None` if it never failed.
+    pub next_retry_at: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl GossipRequestRetry {
+    const INITIAL_DELAY: Duration = Duration::from_secs(10);
+    const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+    /// Record another failed attempt, which happened at `now`.
+    pub(crate) fn fail(&mut self, error: String, now: MilliSecondsSinceUnixEpoch) {
+        self.attempts = self.attempts.saturating_add(1);
+        self.last_error = Some(error);
+
+        let delay = Self::INITIAL_DELAY
+            .saturating_mul(2u32.saturating_pow(self.attempts - 1))
+            .min(Self::MAX_DELAY);
+        let retry_at = u64::from(now.get()).saturating_add(delay.as_millis() as u64);
+
+        self.next_retry_at = Some(MilliSecondsSinceUnixEpoch(UInt::new_saturating(retry_at)));
+    }
+
+    /// Whether the request may be sent out at `now`.
+    pub fn is_due(&self, now: MilliSecondsSinceUnixEpoch) -> bool {
+        self.next_retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
 }
 
 /// An enum over the various secret request types we can have.
@@ -143,6 +233,8 @@ impl GossipRequest {
             request_id: TransactionId::new(),
             info: secret_name.into(),
             sent_out: false,
+            recipient_devices: None,
+            retry: Default::default(),
         }
     }
 
@@ -165,13 +257,29 @@ impl GossipRequest {
                     .expect("We can always serialize a room key request info")
                     .cast();
 
-                ToDeviceRequest::with_id_raw(
-                    &self.request_recipient,
-                    DeviceIdOrAllDevices::AllDevices,
-                    content,
-                    ToDeviceEventType::RoomKeyRequest,
-                    self.request_id.clone(),
-                )
+                if let Some(devices) = &self.recipient_devices {
+                    let device_messages = devices
+                        .iter()
+                        .map(|d| (DeviceIdOrAllDevices::DeviceId(d.to_owned()), content.clone()))
+                        .collect();
+
+                    ToDeviceRequest {
+                        event_type: ToDeviceEventType::RoomKeyRequest,
+                        txn_id: self.request_id.clone(),
+                        messages: BTreeMap::from([(
+                            self.request_recipient.clone(),
+                            device_messages,
+                        )]),
+                    }
+                } else {
+                    ToDeviceRequest::with_id_raw(
+                        &self.request_recipient,
+                        DeviceIdOrAllDevices::AllDevices,
+                        content,
+                        ToDeviceEventType::RoomKeyRequest,
+                        self.request_id.clone(),
+                    )
+                }
             }
             SecretInfo::SecretRequest(s) => {
                 let content =
@@ -212,9 +320,11 @@ impl GossipRequest {
             }
         };
 
-        let request = ToDeviceRequest::with_id(
+        // An empty list of devices cancels the request on all the devices of the
+        // recipient.
+        let request = ToDeviceRequest::for_recipients(
             &self.request_recipient,
-            DeviceIdOrAllDevices::AllDevices,
+            self.recipient_devices.iter().flatten().cloned().collect(),
             &content,
             TransactionId::new(),
         );
@@ -20,8 +20,9 @@ use std::{
 };
 
 use futures_util::future::join_all;
-use itertools::Itertools;
-use matrix_sdk_common::{executor::spawn, failures_cache::FailuresCache};
+use matrix_sdk_common::{
+    executor::spawn, failures_cache::FailuresCache, locks::RwLock as StdRwLock,
+};
 use ruma::{
     api::client::keys::get_keys::v3::Response as KeysQueryResponse, serde::Raw, OwnedDeviceId,
     OwnedServerName, OwnedTransactionId, OwnedUserId, ServerName, TransactionId, UserId,
@@ -31,15 +32,21 @@ use tracing::{debug, enabled, info, instrument, trace, warn, Level};
 
 use crate::{
     error::OlmResult,
-    identities::{DeviceData, OtherUserIdentityData, OwnUserIdentityData, UserIdentityData},
+    identities::{
+        DeviceData, KeyQueryValidator, KeyValidation, OtherUserIdentityData, OwnUserIdentityData,
+        UserIdentityData,
+    },
     olm::{
         sender_data_finder::SessionDeviceCheckError, InboundGroupSession,
         PrivateCrossSigningIdentity, SenderDataFinder, SenderDataType,
     },
     store::{
         caches::{SequenceNumber, StoreCache, StoreCacheGuard},
-        types::{Changes, DeviceChanges, IdentityChanges, UserKeyQueryResult},
-        KeyQueryManager, Result as StoreResult, Store,
+        types::{
+            Changes, DeviceChanges, IdentityChanges, IdentityDispute, KeyQueryPriority,
+            UserKeyQueryResult,
+        },
+        ChangesBuilder, KeyQueryManager, Result as StoreResult, Store,
     },
     types::{
         requests::KeysQueryRequest, CrossSigningKey, DeviceKeys, MasterPubkey, SelfSigningPubkey,
@@ -54,11 +61,12 @@ enum DeviceChange {
     None,
 }
 
-/// This enum helps us to distinguish between the changed and unchanged
+/// This enum helps us to distinguish between the new, changed and unchanged
 /// identity case.
 /// An unchanged identity means same cross signing keys as well as same
 /// set of signatures on the master key.
 enum IdentityUpdateResult {
+    New(UserIdentityData),
     Updated(UserIdentityData),
     Unchanged(UserIdentityData),
 }
@@ -76,19 +84,21 @@ pub(crate) struct IdentityManager {
 
     /// Details of the current "in-flight" key query request, if any
     keys_query_request_details: Arc<Mutex<Option<KeysQueryRequestDetails>>>,
+
+    /// The hook cross-checking the keys of `/keys/query` responses against a
+    /// second source, if any.
+    validator: Arc<StdRwLock<Option<Arc<dyn KeyQueryValidator>>>>,
 }
 
 /// Details of an in-flight key query request
 #[derive(Debug, Clone, Default)]
 struct KeysQueryRequestDetails {
-    /// The sequence number, to be passed to
+    /// The users waiting for a key query are broken up into one or more
+    /// batches, each sent out as a KeysQueryRequest with its own request id.
+    /// We record the outstanding request ids here, together with the sequence
+    /// number of their batch, to be passed to
     /// `Store.mark_tracked_users_as_up_to_date`.
-    sequence_number: SequenceNumber,
-
-    /// A single batch of queries returned by the Store is broken up into one or
-    /// more actual KeysQueryRequests, each with their own request id. We
-    /// record the outstanding request ids here.
-    request_ids: HashSet<OwnedTransactionId>,
+    requests: HashMap<OwnedTransactionId, SequenceNumber>,
 }
 
 // Helper type to handle key query response
@@ -99,8 +109,6 @@ struct KeySetInfo {
 }
 
 impl IdentityManager {
-    const MAX_KEY_QUERY_USERS: usize = 250;
-
     pub fn new(store: Store) -> Self {
         let keys_query_request_details = Mutex::new(None);
 
@@ -109,9 +117,16 @@ impl IdentityManager {
             key_query_manager: Default::default(),
             failures: Default::default(),
             keys_query_request_details: keys_query_request_details.into(),
+            validator: Default::default(),
         }
     }
 
+    /// Install a hook cross-checking the keys of `/keys/query` responses, or
+    /// remove the current one.
+    pub(crate) fn set_validator(&self, validator: Option<Arc<dyn KeyQueryValidator>>) {
+        *self.validator.write() = validator;
+    }
+
     fn user_id(&self) -> &UserId {
         &self.store.static_account().user_id
     }
@@ -168,6 +183,11 @@ impl IdentityManager {
 
         self.store.save_changes(changes).await?;
 
+        let validator = self.validator.read().clone();
+        if let Some(validator) = validator {
+            self.validate_keys(validator.as_ref(), response).await?;
+        }
+
         // Update the sender data on any existing inbound group sessions based on the
         // changes in this response.
         //
@@ -192,13 +212,7 @@ impl IdentityManager {
         let sequence_number = {
             let mut request_details = self.keys_query_request_details.lock().await;
 
-            request_details.as_mut().and_then(|details| {
-                if details.request_ids.remove(request_id) {
-                    Some(details.sequence_number)
-                } else {
-                    None
-                }
-            })
+            request_details.as_mut().and_then(|details| details.requests.remove(request_id))
         };
 
         if let Some(sequence_number) = sequence_number {
@@ -220,6 +234,57 @@ impl IdentityManager {
         Ok((devices, identities))
     }
 
+    /// Cross-check the keys of every user in the given `/keys/query` response
+    /// with the [`KeyQueryValidator`], and record or forget the disputes about
+    /// them.
+    ///
+    /// The users are cross-checked concurrently, so a slow second source
+    /// doesn't hold up the processing of the response once per user.
+    async fn validate_keys(
+        &self,
+        validator: &dyn KeyQueryValidator,
+        response: &KeysQueryResponse,
+    ) -> StoreResult<()> {
+        let users: BTreeSet<_> =
+            response.device_keys.keys().chain(response.master_keys.keys()).collect();
+
+        let tasks = users.into_iter().map(|user_id| async move {
+            let master_key = response
+                .master_keys
+                .get(user_id)
+                .and_then(|key| key.deserialize_as::<MasterPubkey>().ok());
+            let devices: Vec<DeviceKeys> = response
+                .device_keys
+                .get(user_id)
+                .into_iter()
+                .flat_map(|devices| devices.values())
+                .filter_map(|keys| keys.deserialize_as::<DeviceKeys>().ok())
+                .collect();
+
+            (user_id, validator.validate(user_id, master_key.as_ref(), &devices).await)
+        });
+
+        // The disputes are all saved under a single key, so they are recorded one
+        // after the other.
+        for (user_id, validation) in join_all(tasks).await {
+            match validation {
+                KeyValidation::Confirmed => {
+                    self.store.resolve_identity_dispute(user_id).await?;
+                }
+                KeyValidation::Disputed { master_key, devices, reason } => {
+                    let disputed_at = self.store.clock().now();
+                    let dispute = IdentityDispute { master_key, devices, reason, disputed_at };
+                    self.store.dispute_identity(user_id, dispute).await?;
+                }
+                KeyValidation::Unknown => {
+                    trace!(?user_id, "The keys of the user couldn't be cross-checked");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     async fn update_or_create_device(
         store: Store,
         device_keys: DeviceKeys,
@@ -492,19 +557,33 @@ impl IdentityManager {
                     Ok(IdentityUpdateResult::Unchanged(identity.into()))
                 }
             }
-            UserIdentityData::Other(mut identity) => {
-                let has_changed = identity.update(
-                    master_key,
-                    self_signing,
-                    maybe_verified_own_identity.map(|o| o.user_signing_key()),
-                )?;
+            UserIdentityData::Other(identity) => Self::update_other_identity(
+                identity,
+                master_key,
+                self_signing,
+                maybe_verified_own_identity,
+            ),
+        }
+    }
 
-                if has_changed {
-                    Ok(IdentityUpdateResult::Updated(identity.into()))
-                } else {
-                    Ok(IdentityUpdateResult::Unchanged(identity.into()))
-                }
-            }
+    /// Update the identity of another user with the keys from a `/keys/query`
+    /// response, see [`IdentityManager::handle_changed_identity`].
+    fn update_other_identity(
+        mut identity: OtherUserIdentityData,
+        master_key: MasterPubkey,
+        self_signing: SelfSigningPubkey,
+        maybe_verified_own_identity: Option<&OwnUserIdentity>,
+    ) -> Result<IdentityUpdateResult, SignatureError> {
+        let has_changed = identity.update(
+            master_key,
+            self_signing,
+            maybe_verified_own_identity.map(|o| o.user_signing_key()),
+        )?;
+
+        if has_changed {
+            Ok(IdentityUpdateResult::Updated(identity.into()))
+        } else {
+            Ok(IdentityUpdateResult::Unchanged(identity.into()))
         }
     }
 
@@ -551,16 +630,26 @@ impl IdentityManager {
             *changed_private_identity = self.check_private_identity(&identity).await;
             Ok(identity.into())
         } else {
-            // First time seen, create the identity. The current MSK will be pinned.
-            let identity = OtherUserIdentityData::new(master_key, self_signing)?;
-            let is_verified = maybe_verified_own_identity
-                .is_some_and(|own_user_identity| own_user_identity.is_identity_signed(&identity));
-            if is_verified {
-                identity.mark_as_previously_verified();
-            }
+            Self::create_other_identity(master_key, self_signing, maybe_verified_own_identity)
+        }
+    }
 
-            Ok(identity.into())
+    /// Create the identity of another user which we see for the first time,
+    /// see [`IdentityManager::handle_new_identity`].
+    fn create_other_identity(
+        master_key: MasterPubkey,
+        self_signing: SelfSigningPubkey,
+        maybe_verified_own_identity: Option<&OwnUserIdentity>,
+    ) -> Result<UserIdentityData, SignatureError> {
+        // First time seen, create the identity. The current MSK will be pinned.
+        let identity = OtherUserIdentityData::new(master_key, self_signing)?;
+        let is_verified = maybe_verified_own_identity
+            .is_some_and(|own_user_identity| own_user_identity.is_identity_signed(&identity));
+        if is_verified {
+            identity.mark_as_previously_verified();
         }
+
+        Ok(identity.into())
     }
 
     /// Try to deserialize the master key and self-signing key of an
@@ -679,7 +768,7 @@ impl IdentityManager {
             warn!(?user_id, "User ID mismatch in one of the cross signing keys");
         } else if let Some(i) = self.store.get_user_identity(&user_id).await? {
             // an identity we knew about before, which is being updated
-            match self
+            let result = self
                 .handle_changed_identity(
                     response,
                     maybe_verified_own_identity,
@@ -688,23 +777,12 @@ impl IdentityManager {
                     i,
                     changed_private_identity,
                 )
-                .await
-            {
-                Ok(IdentityUpdateResult::Updated(identity)) => {
-                    trace!(?identity, "Updated a user identity");
-                    changes.changed.push(identity);
-                }
-                Ok(IdentityUpdateResult::Unchanged(identity)) => {
-                    trace!(?identity, "Received an unchanged user identity");
-                    changes.unchanged.push(identity);
-                }
-                Err(e) => {
-                    warn!(error = ?e, "Couldn't update an existing user identity");
-                }
-            }
+                .await;
+
+            Self::record_identity_update(changes, result);
         } else {
             // an identity we did not know about before
-            match self
+            let result = self
                 .handle_new_identity(
                     response,
                     maybe_verified_own_identity,
@@ -713,20 +791,92 @@ impl IdentityManager {
                     changed_private_identity,
                 )
                 .await
-            {
-                Ok(identity) => {
-                    trace!(?identity, "Created new user identity");
-                    changes.new.push(identity);
-                }
-                Err(e) => {
-                    warn!(error = ?e, "Couldn't create new user identity");
-                }
-            }
+                .map(IdentityUpdateResult::New);
+
+            Self::record_identity_update(changes, result);
         };
 
         Ok(())
     }
 
+    /// Process the cross-signing keys of another user from a `/keys/query`
+    /// response.
+    ///
+    /// Unlike our own identity, the identity of another user can be checked
+    /// without looking at the rest of the response, so this is done for all
+    /// the users of a response in parallel, see
+    /// [`IdentityManager::handle_cross_signing_keys`].
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store to look up the existing identity in.
+    /// * `maybe_verified_own_identity` - Own verified identity if any to check
+    ///   verification status of updated identity.
+    /// * `key_set_info` - The identity info as returned by the `/keys/query`
+    ///   response.
+    #[instrument(skip_all, fields(user_id))]
+    async fn update_or_create_other_identity(
+        store: Store,
+        maybe_verified_own_identity: Option<OwnUserIdentity>,
+        key_set_info: KeySetInfo,
+    ) -> StoreResult<IdentityChanges> {
+        let KeySetInfo { user_id, master_key, self_signing } = key_set_info;
+        let maybe_verified_own_identity = maybe_verified_own_identity.as_ref();
+        let mut changes = IdentityChanges::default();
+
+        if master_key.user_id() != user_id || self_signing.user_id() != user_id {
+            warn!(?user_id, "User ID mismatch in one of the cross signing keys");
+        } else if let Some(i) = store.get_user_identity(&user_id).await? {
+            // an identity we knew about before, which is being updated
+            let result = match i {
+                UserIdentityData::Other(identity) => Self::update_other_identity(
+                    identity,
+                    master_key,
+                    self_signing,
+                    maybe_verified_own_identity,
+                ),
+                // Our own identity is handled by `update_or_create_identity`.
+                UserIdentityData::Own(_) => Err(SignatureError::UserIdMismatch),
+            };
+
+            Self::record_identity_update(&mut changes, result);
+        } else {
+            // an identity we did not know about before
+            let result =
+                Self::create_other_identity(master_key, self_signing, maybe_verified_own_identity)
+                    .map(IdentityUpdateResult::New);
+
+            Self::record_identity_update(&mut changes, result);
+        }
+
+        Ok(changes)
+    }
+
+    /// Sort the result of processing the cross-signing keys of a user into the
+    /// new, changed or unchanged identities of `changes`.
+    fn record_identity_update(
+        changes: &mut IdentityChanges,
+        result: Result<IdentityUpdateResult, SignatureError>,
+    ) {
+        match result {
+            Ok(IdentityUpdateResult::New(identity)) => {
+                trace!(?identity, "Created new user identity");
+                changes.new.push(identity);
+            }
+            Ok(IdentityUpdateResult::Updated(identity)) => {
+                trace!(?identity, "Updated a user identity");
+                changes.changed.push(identity);
+            }
+            Ok(IdentityUpdateResult::Unchanged(identity)) => {
+                trace!(?identity, "Received an unchanged user identity");
+                changes.unchanged.push(identity);
+            }
+            Err(e) => {
+                warn!(error = ?e, "Couldn't update or create a user identity");
+            }
+        }
+    }
+
     /// Handle the cross signing keys part of a key query response.
     ///
     /// # Arguments
@@ -758,6 +908,8 @@ impl IdentityManager {
             .and_then(UserIdentity::own)
             .filter(|own| own.is_verified());
 
+        let mut tasks = Vec::new();
+
         for (user_id, master_key) in &response.master_keys {
             // Get the master and self-signing key for each identity; those are required for
             // every user identity type. If we don't have those we skip over.
@@ -769,14 +921,31 @@ impl IdentityManager {
 
             let key_set_info = KeySetInfo { user_id: user_id.clone(), master_key, self_signing };
 
-            self.update_or_create_identity(
-                response,
-                &mut changes,
-                &mut changed_identity,
-                maybe_own_verified_identity.as_ref(),
-                key_set_info,
-            )
-            .await?;
+            if user_id == self.user_id() {
+                // Our own identity needs the user-signing key from the response and a
+                // comparison with our private identity, so handle it here.
+                self.update_or_create_identity(
+                    response,
+                    &mut changes,
+                    &mut changed_identity,
+                    maybe_own_verified_identity.as_ref(),
+                    key_set_info,
+                )
+                .await?;
+            } else {
+                tasks.push(spawn(Self::update_or_create_other_identity(
+                    self.store.clone(),
+                    maybe_own_verified_identity.clone(),
+                    key_set_info,
+                )));
+            }
+        }
+
+        // Check the signatures of the identities of other users in parallel, a response
+        // can contain hundreds of them after joining a large room.
+        for result in join_all(tasks).await {
+            let change_fragment = result.expect("Panic while updating a user identity")?;
+            changes.extend(change_fragment);
         }
 
         Ok((changes, changed_identity))
@@ -816,78 +985,90 @@ impl IdentityManager {
 
     /// Get a list of key query requests needed.
     ///
-    /// # Returns
+    /// This is the same as [`IdentityManager::key_query_requests`], but returns
+    /// the requests as a map of the request ID to the `/keys/query` request.
+    pub async fn users_for_key_query(
+        &self,
+    ) -> StoreResult<BTreeMap<OwnedTransactionId, KeysQueryRequest>> {
+        Ok(self.key_query_requests().await?.into_iter().collect())
+    }
+
+    /// Get the key query requests needed, in the order they should be sent
+    /// out.
     ///
-    /// A map of a request ID to the `/keys/query` request.
+    /// The users whose device lists are outdated are split up into batches of
+    /// [`KeyQueryManager::batch_size`] users. The users for which a task is
+    /// waiting, e.g. because a message can't be sent before their devices are
+    /// known, are part of the first requests.
     ///
     /// The response of a successful key query requests needs to be passed to
     /// the [`OlmMachine`] with the [`receive_keys_query_response`].
     ///
     /// [`receive_keys_query_response`]: Self::receive_keys_query_response
-    pub async fn users_for_key_query(
+    pub async fn key_query_requests(
         &self,
-    ) -> StoreResult<BTreeMap<OwnedTransactionId, KeysQueryRequest>> {
+    ) -> StoreResult<Vec<(OwnedTransactionId, KeysQueryRequest)>> {
         // Forget about any previous key queries in flight.
         *self.keys_query_request_details.lock().await = None;
 
+        // We don't want to create a single `/keys/query` request with an infinite
+        // amount of users. Some servers will likely bail out after a
+        // certain amount of users and the responses will be large. In the
+        // case of a transmission error, we'll have to retransmit the large
+        // response.
+        //
+        // So the users are split into batches, each of which becomes a separate
+        // `/keys/query` request.
+        //
+        // Users that are part of the `FailuresCache` are skipped. The cache, which is
+        // a TTL cache, remembers users for which a previous `/key/query` request has
+        // failed. We don't retry a `/keys/query` for such users for a
+        // certain amount of time.
+        let filter = |user: &UserId| !self.failures.contains(user.server_name());
+
         // We always want to track our own user, but in case we aren't in an encrypted
         // room yet, we won't be tracking ourselves yet. This ensures we are always
         // tracking ourselves.
         //
         // The check for emptiness is done first for performance.
-        let (users, sequence_number) = {
+        let batches = {
             let cache = self.store.cache().await?;
             let key_query_manager = self.key_query_manager.synced(&cache).await?;
 
-            let (users, sequence_number) = key_query_manager.users_for_key_query().await;
+            let (users, _) = key_query_manager.users_for_key_query().await;
 
             if users.is_empty() && !key_query_manager.tracked_users().contains(self.user_id()) {
                 key_query_manager.mark_user_as_changed(self.user_id()).await?;
-                key_query_manager.users_for_key_query().await
-            } else {
-                (users, sequence_number)
             }
+
+            key_query_manager.user_batches_for_key_query(filter).await
         };
 
-        if users.is_empty() {
-            Ok(BTreeMap::new())
-        } else {
-            // Let's remove users that are part of the `FailuresCache`. The cache, which is
-            // a TTL cache, remembers users for which a previous `/key/query` request has
-            // failed. We don't retry a `/keys/query` for such users for a
-            // certain amount of time.
-            let users = users.into_iter().filter(|u| !self.failures.contains(u.server_name()));
-
-            // We don't want to create a single `/keys/query` request with an infinite
-            // amount of users. Some servers will likely bail out after a
-            // certain amount of users and the responses will be large. In the
-            // case of a transmission error, we'll have to retransmit the large
-            // response.
-            //
-            // Convert the set of users into multiple /keys/query requests.
-            let requests: BTreeMap<_, _> = users
-                .chunks(Self::MAX_KEY_QUERY_USERS)
-                .into_iter()
-                .map(|user_chunk| {
-                    let request_id = TransactionId::new();
-                    let request = KeysQueryRequest::new(user_chunk);
+        let mut requests = Vec::with_capacity(batches.len());
+        let mut request_details = KeysQueryRequestDetails::default();
 
-                    debug!(?request_id, users = ?request.device_keys.keys(), "Created a /keys/query request");
+        for (users, sequence_number) in batches {
+            let request_id = TransactionId::new();
+            let request = KeysQueryRequest::new(users.into_iter());
 
-                    (request_id, request)
-                })
-                .collect();
+            debug!(
+                ?request_id,
+                %sequence_number,
+                users = ?request.device_keys.keys(),
+                "Created a /keys/query request"
+            );
 
-            // Collect the request IDs, these will be used later in the
-            // `receive_keys_query_response()` method to figure out if the user can be
-            // marked as up-to-date/non-dirty.
-            let request_ids = requests.keys().cloned().collect();
-            let request_details = KeysQueryRequestDetails { sequence_number, request_ids };
+            // The request IDs will be used later in the `receive_keys_query_response()`
+            // method to figure out if the user can be marked as up-to-date/non-dirty.
+            request_details.requests.insert(request_id.clone(), sequence_number);
+            requests.push((request_id, request));
+        }
 
+        if !requests.is_empty() {
             *self.keys_query_request_details.lock().await = Some(request_details);
-
-            Ok(requests)
         }
+
+        Ok(requests)
     }
 
     /// Receive the list of users that contained changed devices from the
@@ -914,6 +1095,20 @@ impl IdentityManager {
         self.key_query_manager.synced(&cache).await?.update_tracked_users(users.into_iter()).await
     }
 
+    /// See the docs for [`OlmMachine::mark_user_as_changed_with_priority()`].
+    pub async fn mark_user_as_changed_with_priority(
+        &self,
+        user_id: &UserId,
+        priority: KeyQueryPriority,
+    ) -> StoreResult<()> {
+        let cache = self.store.cache().await?;
+        self.key_query_manager
+            .synced(&cache)
+            .await?
+            .mark_user_as_changed_with_priority(user_id, priority)
+            .await
+    }
+
     /// Retrieve a list of a user's current devices, so we can encrypt a message
     /// to them.
     ///
@@ -1051,6 +1246,9 @@ impl IdentityManager {
         &self,
         device_changes: &DeviceChanges,
     ) -> Result<(), CryptoStoreError> {
+        // Collect all the updated sessions, so that they get saved in one go.
+        let mut changes = self.store.begin_changes();
+
         for device in device_changes.new.iter().chain(device_changes.changed.iter()) {
             // 1. Look for InboundGroupSessions from the device whose sender_data is
             //    UnknownDevice. For such sessions, we now have the device, and can update
@@ -1060,8 +1258,12 @@ impl IdentityManager {
             // worried about races leading us to getting stuck in the
             // UnknownDevice state, so we'll paper over that by doing this check
             // on device updates too.
-            self.update_sender_data_for_sessions_for_device(device, SenderDataType::UnknownDevice)
-                .await?;
+            self.update_sender_data_for_sessions_for_device(
+                &mut changes,
+                device,
+                SenderDataType::UnknownDevice,
+            )
+            .await?;
 
             // 2. If, and only if, the device is now correctly cross-signed (ie,
             //    device.is_cross_signed_by_owner() is true, and we have the master
@@ -1074,19 +1276,27 @@ impl IdentityManager {
             // it's *way* easier just to use the same logic.
             let device_owner_identity = self.store.get_user_identity(device.user_id()).await?;
             if device_owner_identity.is_some_and(|id| device.is_cross_signed_by_owner(&id)) {
-                self.update_sender_data_for_sessions_for_device(device, SenderDataType::DeviceInfo)
-                    .await?;
+                self.update_sender_data_for_sessions_for_device(
+                    &mut changes,
+                    device,
+                    SenderDataType::DeviceInfo,
+                )
+                .await?;
             }
         }
 
-        Ok(())
+        changes.commit().await
     }
 
     /// Given a device, look for [`InboundGroupSession`]s whose sender data is
     /// in the given state, and update it.
-    #[instrument(skip(self))]
+    ///
+    /// The updated sessions are added to `changes`, it's up to the caller to
+    /// commit them.
+    #[instrument(skip(self, changes))]
     async fn update_sender_data_for_sessions_for_device(
         &self,
+        changes: &mut ChangesBuilder,
         device: &DeviceData,
         sender_data_type: SenderDataType,
     ) -> Result<(), CryptoStoreError> {
@@ -1116,7 +1326,7 @@ impl IdentityManager {
                 last_session_id = Some(session.session_id().to_owned());
                 self.update_sender_data_for_session(session, device).await?;
             }
-            self.store.save_inbound_group_sessions(&sessions).await?;
+            changes.add_inbound_group_sessions(sessions);
         }
     }
 
@@ -1518,13 +1728,19 @@ pub(crate) mod testing {
 
 #[cfg(test)]
 pub(crate) mod tests {
-    use std::ops::Deref;
+    use std::{
+        collections::BTreeSet,
+        ops::Deref,
+        sync::{Arc, Mutex as StdMutex},
+        time::Duration,
+    };
 
+    use async_trait::async_trait;
     use futures_util::pin_mut;
     use matrix_sdk_test::{async_test, ruma_response_from_json, test_json};
     use ruma::{
-        api::client::keys::get_keys::v3::Response as KeysQueryResponse, device_id, user_id,
-        TransactionId,
+        api::client::keys::get_keys::v3::Response as KeysQueryResponse, device_id, owned_device_id,
+        user_id, TransactionId, UserId,
     };
     use serde_json::json;
     use stream_assert::{assert_closed, assert_pending, assert_ready};
@@ -1535,8 +1751,9 @@ pub(crate) mod tests {
     use crate::{
         identities::manager::testing::{other_key_query_cross_signed, own_key_query},
         olm::PrivateCrossSigningIdentity,
-        store::types::Changes,
-        CrossSigningKeyExport, OlmMachine,
+        store::types::{Changes, KeyQueryPriority, UserKeyQueryResult},
+        types::{DeviceKeys, MasterPubkey},
+        CrossSigningKeyExport, KeyQueryValidator, KeyValidation, OlmMachine,
     };
 
     fn key_query_with_failures() -> KeysQueryResponse {
@@ -1611,6 +1828,109 @@ pub(crate) mod tests {
         assert!(identity.is_device_signed(&device));
     }
 
+    /// A validator returning the queued up results, in order.
+    #[derive(Debug)]
+    struct QueuedValidator(StdMutex<Vec<KeyValidation>>);
+
+    #[cfg_attr(target_family = "wasm", async_trait(?Send))]
+    #[cfg_attr(not(target_family = "wasm"), async_trait)]
+    impl KeyQueryValidator for QueuedValidator {
+        async fn validate(
+            &self,
+            user_id: &UserId,
+            master_key: Option<&MasterPubkey>,
+            devices: &[DeviceKeys],
+        ) -> KeyValidation {
+            assert_eq!(user_id, other_user_id());
+            assert!(master_key.is_some());
+            assert_eq!(devices.len(), 1);
+
+            self.0.lock().unwrap().remove(0)
+        }
+    }
+
+    #[async_test]
+    async fn test_key_query_validator() {
+        let manager = manager_test_helper(user_id(), device_id()).await;
+        let other_user = other_user_id();
+        let disputed_devices = BTreeSet::from([owned_device_id!("SKISMLNIMH")]);
+
+        manager.set_validator(Some(Arc::new(QueuedValidator(StdMutex::new(vec![
+            KeyValidation::Disputed {
+                master_key: false,
+                devices: disputed_devices.clone(),
+                reason: "The notary knows other keys".to_owned(),
+            },
+            KeyValidation::Unknown,
+            KeyValidation::Confirmed,
+        ])))));
+
+        // Disputed keys are still stored, but the dispute is recorded.
+        manager
+            .receive_keys_query_response(&TransactionId::new(), &other_key_query())
+            .await
+            .unwrap();
+
+        let devices = manager.store.get_user_devices(other_user).await.unwrap();
+        assert_eq!(devices.devices().count(), 1);
+
+        let dispute = manager.store.identity_dispute(other_user).await.unwrap().unwrap();
+        assert!(!dispute.master_key);
+        assert_eq!(dispute.devices, disputed_devices);
+        assert_eq!(dispute.reason, "The notary knows other keys");
+
+        // If the second source can't be asked, the dispute is kept.
+        manager
+            .receive_keys_query_response(&TransactionId::new(), &other_key_query())
+            .await
+            .unwrap();
+        assert!(manager.store.identity_dispute(other_user).await.unwrap().is_some());
+
+        // Once the keys are confirmed, the dispute is forgotten.
+        manager
+            .receive_keys_query_response(&TransactionId::new(), &other_key_query())
+            .await
+            .unwrap();
+        assert!(manager.store.identity_disputes().await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn test_manager_key_query_response_with_multiple_identities() {
+        use test_json::keys_query_sets::IdentityChangeDataSet as DataSet;
+
+        let manager = manager_test_helper(user_id(), device_id()).await;
+
+        // Combine the keys of two other users into a single response.
+        let mut response = other_key_query();
+        let bob_response = DataSet::key_query_with_identity_a();
+        response.device_keys.extend(bob_response.device_keys);
+        response.master_keys.extend(bob_response.master_keys);
+        response.self_signing_keys.extend(bob_response.self_signing_keys);
+        response.user_signing_keys.extend(bob_response.user_signing_keys);
+
+        let (device_changes, identity_changes) =
+            manager.receive_keys_query_response(&TransactionId::new(), &response).await.unwrap();
+
+        assert_eq!(device_changes.new.len(), 2);
+        assert_eq!(identity_changes.new.len(), 2);
+
+        for (user, device) in
+            [(other_user_id(), device_id!("SKISMLNIMH")), (DataSet::user_id(), DataSet::device_a())]
+        {
+            let device = manager.store.get_device_data(user, device).await.unwrap().unwrap();
+            let identity = manager.store.get_user_identity(user).await.unwrap().unwrap();
+            assert!(identity.other().unwrap().is_device_signed(&device));
+        }
+
+        // Receiving the same response again leaves both identities unchanged.
+        let (_, identity_changes) =
+            manager.receive_keys_query_response(&TransactionId::new(), &response).await.unwrap();
+
+        assert!(identity_changes.new.is_empty());
+        assert!(identity_changes.changed.is_empty());
+        assert_eq!(identity_changes.unchanged.len(), 2);
+    }
+
     #[async_test]
     async fn test_manager_own_key_query_response() {
         let manager = manager_test_helper(user_id(), device_id()).await;
@@ -1795,6 +2115,92 @@ pub(crate) mod tests {
         assert!(!queries.iter().any(|(_, r)| r.device_keys.contains_key(alice)));
     }
 
+    #[async_test]
+    async fn test_key_query_batching() {
+        let manager = manager_test_helper(user_id(), device_id()).await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+
+        manager.update_tracked_users([user_id(), alice, bob, carol]).await.unwrap();
+        manager.key_query_manager.set_batch_size(2);
+
+        // A task waits for Carol's devices, e.g. because it wants to send her a message.
+        let cache = manager.store.cache().await.unwrap();
+        let wait = manager.key_query_manager.wait_if_user_key_query_pending(
+            cache,
+            Duration::from_secs(10),
+            carol,
+        );
+        pin_mut!(wait);
+        assert!(futures_util::poll!(wait.as_mut()).is_pending());
+
+        let requests = manager.key_query_requests().await.unwrap();
+
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|(_, r)| r.device_keys.len() == 2));
+        assert!(requests[0].1.device_keys.contains_key(carol));
+
+        // Every batch is marked as up-to-date on its own.
+        let response = ruma_response_from_json(&json!({ "device_keys": { carol: {} } }));
+        manager.receive_keys_query_response(&requests[0].0, &response).await.unwrap();
+        assert!(matches!(wait.await.unwrap(), UserKeyQueryResult::WasPending));
+
+        let users: Vec<_> = manager
+            .key_query_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, r)| r.device_keys.into_keys())
+            .collect();
+        assert!(!users.contains(&carol.to_owned()));
+        assert!(users.contains(&alice.to_owned()));
+    }
+
+    #[async_test]
+    async fn test_key_query_priorities() {
+        let manager = manager_test_helper(user_id(), device_id()).await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+
+        manager.update_tracked_users([user_id(), alice, bob, carol]).await.unwrap();
+        manager.key_query_manager.set_batch_size(1);
+
+        manager
+            .mark_user_as_changed_with_priority(carol, KeyQueryPriority::VisibleRoom)
+            .await
+            .unwrap();
+        manager
+            .mark_user_as_changed_with_priority(bob, KeyQueryPriority::BlockingSend)
+            .await
+            .unwrap();
+        // Marking the user again with a lower priority doesn't lower it.
+        manager
+            .mark_user_as_changed_with_priority(bob, KeyQueryPriority::Background)
+            .await
+            .unwrap();
+
+        let requests = manager.key_query_requests().await.unwrap();
+        let users: Vec<_> =
+            requests.iter().flat_map(|(_, r)| r.device_keys.keys().cloned()).collect();
+
+        assert_eq!(users.len(), 4);
+        assert_eq!(users[..2], [bob.to_owned(), carol.to_owned()]);
+
+        // Once the user is up-to-date, the priority is forgotten.
+        let response = ruma_response_from_json(&json!({ "device_keys": { bob: {} } }));
+        manager.receive_keys_query_response(&requests[0].0, &response).await.unwrap();
+        manager
+            .mark_user_as_changed_with_priority(bob, KeyQueryPriority::Background)
+            .await
+            .unwrap();
+
+        let requests = manager.key_query_requests().await.unwrap();
+        assert!(requests[0].1.device_keys.contains_key(carol));
+        assert!(requests.last().unwrap().1.device_keys.contains_key(bob));
+    }
+
     #[async_test]
     async fn test_failure_handling() {
         let manager = manager_test_helper(user_id(), device_id()).await;
@@ -2197,9 +2603,9 @@ pub(crate) mod tests {
 
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
@@ -2402,9 +2808,9 @@ pub(crate) mod tests {
         // Marking our own identity as trusted should update the existing identities
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
@@ -44,6 +44,7 @@ pub(crate) mod device;
 pub(crate) mod manager;
 pub(crate) mod room_identity_state;
 pub(crate) mod user;
+pub(crate) mod validator;
 
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -57,6 +58,7 @@ pub use user::{
     OtherUserIdentity, OtherUserIdentityData, OwnUserIdentity, OwnUserIdentityData, UserIdentity,
     UserIdentityData,
 };
+pub use validator::{KeyQueryValidator, KeyValidation};
 
 // These methods are only here because Serialize and Deserialize don't seem to
 // be implemented for WASM.
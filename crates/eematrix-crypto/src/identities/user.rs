@@ -37,7 +37,10 @@ use tracing::{error, info};
 use crate::{
     error::SignatureError,
     store::{
-        types::{Changes, IdentityChanges},
+        types::{
+            Changes, IdentityChanges, VerificationAuditEntry, VerificationAuditEvent,
+            VerificationWithdrawalReason,
+        },
         Store,
     },
     types::{
@@ -124,6 +127,9 @@ impl UserIdentity {
     /// Reset the flag that records that the identity has been verified, thus
     /// clearing [`UserIdentity::was_previously_verified`] and
     /// [`UserIdentity::has_verification_violation`].
+    ///
+    /// This is recorded as a [`VerificationWithdrawalReason::Manual`]
+    /// withdrawal, see [`UserIdentity::withdraw_verification_with_reason`].
     pub async fn withdraw_verification(&self) -> Result<(), CryptoStoreError> {
         match self {
             UserIdentity::Own(u) => u.withdraw_verification().await,
@@ -131,6 +137,23 @@ impl UserIdentity {
         }
     }
 
+    /// Same as [`UserIdentity::withdraw_verification`], but records the given
+    /// reason for the withdrawal.
+    ///
+    /// If the identity was verified before, the withdrawal is added to the
+    /// verification audit log, see [`Store::verification_audit_log`].
+    /// The updated identity is also sent out over
+    /// [`Store::user_identities_stream`].
+    pub async fn withdraw_verification_with_reason(
+        &self,
+        reason: VerificationWithdrawalReason,
+    ) -> Result<(), CryptoStoreError> {
+        match self {
+            UserIdentity::Own(u) => u.withdraw_verification_with_reason(reason).await,
+            UserIdentity::Other(u) => u.withdraw_verification_with_reason(reason).await,
+        }
+    }
+
     /// Remember this identity, ensuring it does not result in a pin violation.
     ///
     /// When we first see a user, we assume their cryptographic identity has not
@@ -286,17 +309,62 @@ impl OwnUserIdentity {
 
     /// Remove the requirement for this identity to be verified.
     pub async fn withdraw_verification(&self) -> Result<(), CryptoStoreError> {
+        self.withdraw_verification_with_reason(VerificationWithdrawalReason::Manual).await
+    }
+
+    /// Remove the requirement for this identity to be verified, and record
+    /// why it was removed.
+    ///
+    /// See [`UserIdentity::withdraw_verification_with_reason`].
+    pub async fn withdraw_verification_with_reason(
+        &self,
+        reason: VerificationWithdrawalReason,
+    ) -> Result<(), CryptoStoreError> {
+        // Only a verification violation is turned back into an unverified
+        // identity, anything else isn't an actual withdrawal.
+        let was_verified = self.inner.has_verification_violation();
         self.inner.withdraw_verification();
-        let to_save = UserIdentityData::Own(self.inner.clone());
-        let changes = Changes {
-            identities: IdentityChanges { changed: vec![to_save], ..Default::default() },
-            ..Default::default()
-        };
-        self.verification_machine.store.inner().save_changes(changes).await?;
-        Ok(())
+
+        save_withdrawn_verification(
+            &self.verification_machine,
+            UserIdentityData::Own(self.inner.clone()),
+            was_verified.then_some(reason),
+        )
+        .await
     }
 }
 
+/// Save an identity whose verification was withdrawn.
+///
+/// If the identity was verified before, an entry recording the given reason
+/// is added to the verification audit log, in the same changes.
+async fn save_withdrawn_verification(
+    verification_machine: &VerificationMachine,
+    identity: UserIdentityData,
+    reason: Option<VerificationWithdrawalReason>,
+) -> Result<(), CryptoStoreError> {
+    let store = verification_machine.store.inner();
+
+    let verification_audit_log = reason
+        .map(|reason| {
+            VerificationAuditEntry::new(
+                identity.master_key(),
+                VerificationAuditEvent::VerificationWithdrawn(reason),
+                store.clock().now(),
+            )
+        })
+        .into_iter()
+        .collect();
+
+    let changes = Changes {
+        identities: IdentityChanges { changed: vec![identity], ..Default::default() },
+        verification_audit_log,
+        ..Default::default()
+    };
+
+    store.save_changes(changes).await
+}
+
 /// Struct representing a cross signing identity of a user.
 ///
 /// This is the user identity of a user that isn't our own. Other users will
@@ -432,19 +500,32 @@ impl OtherUserIdentity {
 
     /// Remove the requirement for this identity to be verified.
     pub async fn withdraw_verification(&self) -> Result<(), CryptoStoreError> {
+        self.withdraw_verification_with_reason(VerificationWithdrawalReason::Manual).await
+    }
+
+    /// Remove the requirement for this identity to be verified, and record
+    /// why it was removed.
+    ///
+    /// See [`UserIdentity::withdraw_verification_with_reason`].
+    pub async fn withdraw_verification_with_reason(
+        &self,
+        reason: VerificationWithdrawalReason,
+    ) -> Result<(), CryptoStoreError> {
         info!(
             master_key = ?self.master_key.get_first_key(),
             user = ?self.user_id(),
+            ?reason,
             "Withdrawing verification status and pinning current identity"
         );
+        let was_verified = self.inner.was_previously_verified();
         self.inner.withdraw_verification();
-        let to_save = UserIdentityData::Other(self.inner.clone());
-        let changes = Changes {
-            identities: IdentityChanges { changed: vec![to_save], ..Default::default() },
-            ..Default::default()
-        };
-        self.verification_machine.store.inner().save_changes(changes).await?;
-        Ok(())
+
+        save_withdrawn_verification(
+            &self.verification_machine,
+            UserIdentityData::Other(self.inner.clone()),
+            was_verified.then_some(reason),
+        )
+        .await
     }
 
     /// Test helper that marks that an identity has been previously verified and
@@ -1412,6 +1493,7 @@ pub(crate) mod tests {
     use std::{collections::HashMap, sync::Arc};
 
     use assert_matches::assert_matches;
+    use futures_util::{pin_mut, FutureExt, StreamExt};
     use matrix_sdk_test::{async_test, test_json};
     use ruma::{device_id, user_id, TransactionId};
     use serde_json::{json, Value};
@@ -1420,7 +1502,7 @@ pub(crate) mod tests {
     use super::{
         testing::{device, get_other_identity, get_own_identity},
         OtherUserIdentityDataSerializerV2, OwnUserIdentityData, OwnUserIdentityVerifiedState,
-        UserIdentityData,
+        UserIdentity, UserIdentityData,
     };
     use crate::{
         identities::{
@@ -1432,7 +1514,10 @@ pub(crate) mod tests {
             Device,
         },
         olm::{Account, PrivateCrossSigningIdentity},
-        store::{CryptoStoreWrapper, MemoryStore},
+        store::{
+            types::{VerificationAuditEvent, VerificationWithdrawalReason},
+            CryptoStoreWrapper, MemoryStore,
+        },
         types::{CrossSigningKey, MasterPubkey, SelfSigningPubkey, Signatures, UserSigningPubkey},
         verification::VerificationMachine,
         CrossSigningKeyExport, OlmMachine, OtherUserIdentityData,
@@ -1800,6 +1885,37 @@ pub(crate) mod tests {
         assert!(other_identity.inner.has_pin_violation());
     }
 
+    #[async_test]
+    async fn test_pin_violations_stream() {
+        use test_json::keys_query_sets::IdentityChangeDataSet as DataSet;
+
+        let my_user_id = user_id!("@me:localhost");
+        let machine = OlmMachine::new(my_user_id, device_id!("ABCDEFGH")).await;
+        machine.bootstrap_cross_signing(false).await.unwrap();
+
+        let stream = machine.store().pin_violations_stream();
+        pin_mut!(stream);
+
+        let keys_query = DataSet::key_query_with_identity_a();
+        machine.mark_request_as_sent(&TransactionId::new(), &keys_query).await.unwrap();
+
+        // Seeing the identity for the first time pins it.
+        assert!(stream.next().now_or_never().is_none());
+
+        // Simulate an identity change
+        let keys_query = DataSet::key_query_with_identity_b();
+        machine.mark_request_as_sent(&TransactionId::new(), &keys_query).await.unwrap();
+
+        let violation = stream.next().now_or_never().flatten().expect("A violation should be sent");
+        assert_eq!(violation.identity.user_id(), DataSet::user_id());
+        assert!(!violation.verification_violation);
+        assert!(violation.identity.identity_needs_user_approval());
+
+        // Pinning the new identity resolves the violation.
+        violation.identity.pin_current_master_key().await.unwrap();
+        assert!(stream.next().now_or_never().is_none());
+    }
+
     #[async_test]
     async fn test_resolve_identity_pin_violation_with_withdraw_verification() {
         use test_json::keys_query_sets::IdentityChangeDataSet as DataSet;
@@ -1839,6 +1955,66 @@ pub(crate) mod tests {
         assert!(!other_identity.inner.has_pin_violation());
     }
 
+    #[async_test]
+    async fn test_withdraw_verification_with_reason() {
+        use test_json::keys_query_sets::IdentityChangeDataSet as DataSet;
+
+        let my_user_id = user_id!("@me:localhost");
+        let machine = OlmMachine::new(my_user_id, device_id!("ABCDEFGH")).await;
+        machine.bootstrap_cross_signing(false).await.unwrap();
+
+        let keys_query = DataSet::key_query_with_identity_a();
+        let txn_id = TransactionId::new();
+        machine.mark_request_as_sent(&txn_id, &keys_query).await.unwrap();
+
+        let other_user_id = DataSet::user_id();
+        let other_identity =
+            machine.get_identity(other_user_id, None).await.unwrap().unwrap().other().unwrap();
+        other_identity.mark_as_previously_verified().await.unwrap();
+
+        // The identity becoming verified is recorded.
+        let audit_log = machine.store().verification_audit_log(other_user_id).await.unwrap();
+        assert_eq!(audit_log.len(), 1);
+        assert_eq!(audit_log[0].event, VerificationAuditEvent::Verified);
+
+        let stream = machine.store().user_identities_stream();
+        pin_mut!(stream);
+
+        UserIdentity::Other(other_identity.clone())
+            .withdraw_verification_with_reason(VerificationWithdrawalReason::KeyChange)
+            .await
+            .unwrap();
+
+        // The withdrawal is sent out over the identities stream.
+        let updates = stream.next().now_or_never().flatten().unwrap();
+        assert!(updates.changed.contains_key(other_user_id));
+
+        // Withdrawing the verification of an identity which isn't verified
+        // anymore isn't recorded.
+        other_identity.withdraw_verification().await.unwrap();
+
+        let audit_log = machine.store().verification_audit_log(other_user_id).await.unwrap();
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[1].user_id, other_user_id);
+        assert_eq!(
+            audit_log[1].event,
+            VerificationAuditEvent::VerificationWithdrawn(VerificationWithdrawalReason::KeyChange)
+        );
+        assert_eq!(audit_log[1].master_key, other_identity.master_key().get_first_key());
+
+        // A plain withdrawal is recorded as a manual one.
+        other_identity.mark_as_previously_verified().await.unwrap();
+        other_identity.withdraw_verification().await.unwrap();
+
+        let audit_log = machine.store().verification_audit_log(other_user_id).await.unwrap();
+        assert_eq!(audit_log.len(), 4);
+        assert_eq!(audit_log[2].event, VerificationAuditEvent::Verified);
+        assert_eq!(
+            audit_log[3].event,
+            VerificationAuditEvent::VerificationWithdrawn(VerificationWithdrawalReason::Manual)
+        );
+    }
+
     #[async_test]
     async fn test_resolve_identity_verification_violation_with_withdraw() {
         use test_json::keys_query_sets::VerificationViolationTestData as DataSet;
@@ -1851,9 +2027,9 @@ pub(crate) mod tests {
 
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
@@ -1891,9 +2067,9 @@ pub(crate) mod tests {
 
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
@@ -1932,9 +2108,9 @@ pub(crate) mod tests {
 
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
@@ -0,0 +1,88 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeSet, fmt};
+
+use async_trait::async_trait;
+use matrix_sdk_common::{SendOutsideWasm, SyncOutsideWasm};
+use ruma::{OwnedDeviceId, UserId};
+
+use crate::types::{DeviceKeys, MasterPubkey};
+
+/// The outcome of cross-checking the keys of a user with a
+/// [`KeyQueryValidator`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyValidation {
+    /// The second source knows the keys, and they match.
+    Confirmed,
+
+    /// The second source knows other keys for the user.
+    ///
+    /// The identity of the user is marked as disputed in the store, see
+    /// [`Store::identity_dispute()`].
+    ///
+    /// [`Store::identity_dispute()`]: crate::store::Store::identity_dispute
+    Disputed {
+        /// Whether the master key of the user didn't match.
+        master_key: bool,
+
+        /// The devices whose keys didn't match.
+        devices: BTreeSet<OwnedDeviceId>,
+
+        /// A human readable description of the mismatch, e.g. the name of the
+        /// source.
+        reason: String,
+    },
+
+    /// The second source couldn't be asked, or doesn't know the user.
+    ///
+    /// A dispute which was recorded before is left as it is.
+    Unknown,
+}
+
+/// A hook cross-checking the keys returned by a `/keys/query` request against
+/// a second source, like a key transparency log or a notary server.
+///
+/// The validator can be installed using
+/// [`OlmMachine::set_key_query_validator()`]. The keys are still stored if
+/// they are disputed, the dispute is recorded next to them so it can be shown
+/// to the user.
+///
+/// The users of a `/keys/query` response are cross-checked concurrently, but
+/// the response isn't fully processed until all of them were, so the validator
+/// should time out quickly if the second source isn't reachable, and return
+/// [`KeyValidation::Unknown`].
+///
+/// [`OlmMachine::set_key_query_validator()`]: crate::OlmMachine::set_key_query_validator
+#[cfg_attr(target_family = "wasm", async_trait(?Send))]
+#[cfg_attr(not(target_family = "wasm"), async_trait)]
+pub trait KeyQueryValidator: fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// Cross-check the keys the homeserver returned for the given user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user the keys belong to.
+    ///
+    /// * `master_key` - The master cross-signing key of the user, if the
+    ///   response contained one.
+    ///
+    /// * `devices` - The keys of the devices of the user which were part of
+    ///   the response.
+    async fn validate(
+        &self,
+        user_id: &UserId,
+        master_key: Option<&MasterPubkey>,
+        devices: &[DeviceKeys],
+    ) -> KeyValidation;
+}
@@ -18,15 +18,23 @@
 #![warn(missing_docs, missing_debug_implementations)]
 #![cfg_attr(target_family = "wasm", allow(clippy::arc_with_non_send_sync))]
 
+pub mod appservice;
 pub mod backups;
+#[cfg(all(feature = "blocking", not(target_family = "wasm")))]
+pub mod blocking;
 mod ciphers;
+pub mod clock;
 pub mod dehydrated_devices;
 mod error;
 mod file_encryption;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 mod gossiping;
 mod identities;
 mod machine;
 pub mod olm;
+#[cfg(feature = "mnemonic")]
+pub mod recovery;
 pub mod secret_storage;
 mod session_manager;
 pub mod store;
@@ -37,6 +45,10 @@ mod verification;
 #[cfg(any(test, feature = "testing"))]
 /// Testing facilities and helpers for crypto tests
 pub mod testing {
+    mod fake_homeserver;
+
+    pub use fake_homeserver::FakeHomeserver;
+
     pub use crate::identities::{
         device::testing::get_device,
         user::testing::{
@@ -78,19 +90,27 @@ impl RoomKeyImportResult {
 }
 
 pub use error::{
-    EventError, MegolmError, OlmError, SessionCreationError, SessionRecipientCollectionError,
-    SetRoomSettingsError, SignatureError,
+    EventError, MegolmError, OlmError, PayloadValidationError, SessionCreationError,
+    SessionRecipientCollectionError, SetRoomSettingsError, SignatureError,
 };
 pub use file_encryption::{
-    decrypt_room_key_export, encrypt_room_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, KeyExportError, MediaEncryptionInfo,
+    decrypt_room_key_export, decrypt_room_key_export_with_key, encrypt_room_key_export,
+    encrypt_room_key_export_for_key, encrypt_room_key_export_v2, AttachmentDecryptor,
+    AttachmentEncryptor, DecryptorError, KeyExportError, MediaEncryptionInfo,
+};
+pub use gossiping::{
+    GossipRequest, GossipRequestRetry, GossippedSecret, KeyRequestState, KeyRequestStatus,
 };
-pub use gossiping::{GossipRequest, GossippedSecret};
 pub use identities::{
-    Device, DeviceData, LocalTrust, OtherUserIdentity, OtherUserIdentityData, OwnUserIdentity,
-    OwnUserIdentityData, UserDevices, UserIdentity, UserIdentityData,
+    Device, DeviceData, KeyQueryValidator, KeyValidation, LocalTrust, OtherUserIdentity,
+    OtherUserIdentityData, OwnUserIdentity, OwnUserIdentityData, UserDevices, UserIdentity,
+    UserIdentityData,
+};
+pub use machine::{
+    CrossSigningBootstrapPlan, CrossSigningBootstrapRequests, CrossSigningReset,
+    CustomToDeviceEventHandler, EncryptionSyncChanges, JsonSigner, OlmMachine, OlmMachineBuilder,
+    OlmMachineFeatures,
 };
-pub use machine::{CrossSigningBootstrapRequests, EncryptionSyncChanges, OlmMachine};
 use matrix_sdk_common::deserialized_responses::{DecryptedRoomEvent, UnableToDecryptInfo};
 #[cfg(feature = "qrcode")]
 pub use matrix_sdk_qrcode;
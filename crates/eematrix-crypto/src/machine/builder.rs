@@ -0,0 +1,220 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The [`OlmMachineBuilder`], which creates an [`OlmMachine`] with optional
+//! subsystems turned off.
+
+use std::{fmt, sync::Arc};
+
+use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+
+use super::OlmMachine;
+use crate::store::{
+    DynCryptoStore, IntoCryptoStore, RateLimits, Result as StoreResult, SecretStorageBackend,
+    ValueCodec,
+};
+#[cfg(doc)]
+use crate::{backups::BackupMachine, dehydrated_devices::DehydratedDevices, store::MemoryStore};
+
+/// The optional subsystems of an [`OlmMachine`].
+///
+/// All the subsystems are enabled by default. Constrained deployments, like
+/// bots which only need to encrypt and decrypt messages, can turn them off
+/// using the [`OlmMachineBuilder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct OlmMachineFeatures {
+    /// Whether room keys can be backed up to the server, see
+    /// [`BackupMachine`].
+    ///
+    /// If disabled, a backup key found in the store isn't used, and
+    /// [`BackupMachine::enable_backup_v1()`] doesn't activate the backup.
+    pub backups: bool,
+
+    /// Whether we answer the room key and secret requests of other devices,
+    /// and send out our own.
+    ///
+    /// If disabled, incoming requests are ignored and no requests are sent
+    /// out automatically. Room keys can still be requested explicitly with
+    /// [`OlmMachine::request_room_key()`].
+    pub gossiping: bool,
+
+    /// Whether dehydrated devices can be created and rehydrated, see
+    /// [`DehydratedDevices`].
+    ///
+    /// If disabled, [`DehydratedDevices::create()`] and
+    /// [`DehydratedDevices::rehydrate()`] return
+    /// [`DehydrationError::Disabled`].
+    ///
+    /// [`DehydrationError::Disabled`]: crate::dehydrated_devices::DehydrationError::Disabled
+    pub dehydrated_devices: bool,
+}
+
+impl Default for OlmMachineFeatures {
+    fn default() -> Self {
+        Self { backups: true, gossiping: true, dehydrated_devices: true }
+    }
+}
+
+/// A builder for an [`OlmMachine`], see [`OlmMachine::builder()`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use matrix_sdk_crypto::OlmMachine;
+/// use ruma::{device_id, user_id};
+/// # async {
+/// let machine = OlmMachine::builder(user_id!("@bot:example.org"), device_id!("KIOSK"))
+///     .backups(false)
+///     .gossiping(false)
+///     .dehydrated_devices(false)
+///     .build()
+///     .await?;
+/// # anyhow::Ok(()) };
+/// ```
+pub struct OlmMachineBuilder {
+    pub(super) user_id: OwnedUserId,
+    pub(super) device_id: OwnedDeviceId,
+    pub(super) store: Option<Arc<DynCryptoStore>>,
+    pub(super) custom_account: Option<vodozemac::olm::Account>,
+    pub(super) secret_storage: Option<Arc<dyn SecretStorageBackend>>,
+    pub(super) value_codec: Option<ValueCodec>,
+    pub(super) features: OlmMachineFeatures,
+    pub(super) rate_limits: RateLimits,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for OlmMachineBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OlmMachineBuilder")
+            .field("user_id", &self.user_id)
+            .field("device_id", &self.device_id)
+            .field("value_codec", &self.value_codec)
+            .field("features", &self.features)
+            .field("rate_limits", &self.rate_limits)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OlmMachineBuilder {
+    pub(super) fn new(user_id: &UserId, device_id: &DeviceId) -> Self {
+        Self {
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            store: None,
+            custom_account: None,
+            secret_storage: None,
+            value_codec: None,
+            features: OlmMachineFeatures::default(),
+            rate_limits: RateLimits::default(),
+        }
+    }
+
+    /// Use the given [`CryptoStore`] to persist the encryption keys.
+    ///
+    /// If the store already contains encryption keys for the user/device pair
+    /// of the builder those will be re-used. Otherwise new ones will be
+    /// created and stored.
+    ///
+    /// If no store is given, the keys are only kept in a [`MemoryStore`], and
+    /// are lost once the machine is dropped.
+    ///
+    /// [`CryptoStore`]: crate::store::CryptoStore
+    pub fn store(mut self, store: impl IntoCryptoStore) -> Self {
+        self.store = Some(store.into_crypto_store());
+        self
+    }
+
+    /// Use a custom [`vodozemac::olm::Account`] for the identity and one-time
+    /// keys of the machine.
+    ///
+    /// If an account already exists in the store for the user/device pair of
+    /// the builder, [`OlmMachineBuilder::build()`] fails. This is useful if
+    /// one wishes to create identity keys before knowing the user/device IDs,
+    /// e.g., to use the identity key as the device ID.
+    pub fn custom_account(mut self, account: vodozemac::olm::Account) -> Self {
+        self.custom_account = Some(account);
+        self
+    }
+
+    /// Keep the backup decryption key in the given [`SecretStorageBackend`]
+    /// instead of the store.
+    ///
+    /// A backup decryption key which is already in the store is moved into
+    /// the backend when the machine is built.
+    pub fn secret_storage(mut self, secret_storage: Arc<dyn SecretStorageBackend>) -> Self {
+        self.secret_storage = Some(secret_storage);
+        self
+    }
+
+    /// Serialize the custom values of the store, like the ones stored with
+    /// [`Store::set_value()`], in the given format instead of MessagePack.
+    ///
+    /// Values which were stored in another format are still read, and
+    /// rewritten in the given format when they're loaded.
+    ///
+    /// [`Store::set_value()`]: crate::store::Store::set_value
+    pub fn value_codec(mut self, value_codec: ValueCodec) -> Self {
+        self.value_codec = Some(value_codec);
+        self
+    }
+
+    /// Enable or disable the backups of room keys, see
+    /// [`OlmMachineFeatures::backups`].
+    pub fn backups(mut self, enabled: bool) -> Self {
+        self.features.backups = enabled;
+        self
+    }
+
+    /// Enable or disable the gossiping of room keys and secrets, see
+    /// [`OlmMachineFeatures::gossiping`].
+    pub fn gossiping(mut self, enabled: bool) -> Self {
+        self.features.gossiping = enabled;
+        self
+    }
+
+    /// Enable or disable dehydrated devices, see
+    /// [`OlmMachineFeatures::dehydrated_devices`].
+    pub fn dehydrated_devices(mut self, enabled: bool) -> Self {
+        self.features.dehydrated_devices = enabled;
+        self
+    }
+
+    /// Limit the number of key claims, key queries and to-device requests the
+    /// machine creates, so a buggy caller can't hammer the homeserver.
+    ///
+    /// The state of the limits is kept in the store, so they hold across
+    /// restarts. Requests exceeding a limit fail with
+    /// [`CryptoStoreError::RateLimited`], or are left out of
+    /// [`OlmMachine::outgoing_requests()`], see [`RateLimits`] for the details.
+    ///
+    /// [`OlmMachine::outgoing_requests()`]: crate::OlmMachine::outgoing_requests
+    ///
+    /// [`CryptoStoreError::RateLimited`]: crate::CryptoStoreError::RateLimited
+    pub fn rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    /// Create the [`OlmMachine`].
+    ///
+    /// Returns [`CryptoStoreError::MismatchedAccount`] if the store contains
+    /// the account of another user/device pair, or if a custom account was
+    /// given while the store already contains one.
+    ///
+    /// [`CryptoStoreError::MismatchedAccount`]: crate::CryptoStoreError::MismatchedAccount
+    pub async fn build(self) -> StoreResult<OlmMachine> {
+        OlmMachine::from_builder(self).await
+    }
+}
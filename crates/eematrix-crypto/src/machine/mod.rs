@@ -13,11 +13,12 @@
 // limitations under the License.
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     sync::Arc,
     time::Duration,
 };
 
+use futures_core::Stream;
 use itertools::Itertools;
 #[cfg(feature = "experimental-send-custom-to-device")]
 use matrix_sdk_common::deserialized_responses::WithheldCode;
@@ -26,9 +27,10 @@ use matrix_sdk_common::{
         AlgorithmInfo, DecryptedRoomEvent, DeviceLinkProblem, EncryptionInfo,
         ProcessedToDeviceEvent, UnableToDecryptInfo, UnableToDecryptReason,
         UnsignedDecryptionResult, UnsignedEventLocation, VerificationLevel, VerificationState,
+        WithheldSender,
     },
     locks::RwLock as StdRwLock,
-    BoxFuture,
+    BoxFuture, SendOutsideWasm, SyncOutsideWasm,
 };
 use ruma::{
     api::client::{
@@ -44,13 +46,14 @@ use ruma::{
     assign,
     events::{
         secret::request::SecretName, AnyMessageLikeEvent, AnyMessageLikeEventContent,
-        AnyToDeviceEvent, MessageLikeEventContent,
+        AnySyncTimelineEvent, AnyToDeviceEvent, MessageLikeEventContent,
     },
     serde::{JsonObject, Raw},
-    DeviceId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedDeviceKeyId,
-    OwnedTransactionId, OwnedUserId, RoomId, TransactionId, UInt, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm,
+    OwnedDeviceId, OwnedDeviceKeyId, OwnedTransactionId, OwnedUserId, RoomId, TransactionId, UInt,
+    UserId,
 };
-use serde_json::{value::to_raw_value, Value};
+use serde_json::{json, value::to_raw_value, Value};
 use tokio::sync::Mutex;
 use tracing::{
     debug, error,
@@ -62,26 +65,37 @@ use vodozemac::{
     Curve25519PublicKey, Ed25519Signature,
 };
 
+pub use self::builder::{OlmMachineBuilder, OlmMachineFeatures};
+use self::{
+    one_time_key_pool::OneTimeKeyPoolMonitor, room_key_acks::RoomKeyAcks,
+    to_device_batcher::ToDeviceBatcher,
+};
 use crate::{
     backups::{BackupMachine, MegolmV1BackupKey},
+    clock::Clock,
     dehydrated_devices::{DehydratedDevices, DehydrationError},
     error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult, SetRoomSettingsError},
-    gossiping::GossipMachine,
-    identities::{user::UserIdentity, Device, IdentityManager, UserDevices},
+    gossiping::{GossipMachine, GossipRequest, KeyRequestStatus},
+    identities::{user::UserIdentity, Device, IdentityManager, KeyQueryValidator, UserDevices},
     olm::{
-        Account, CrossSigningStatus, EncryptionSettings, IdentityKeys, InboundGroupSession,
-        KnownSenderData, OlmDecryptionInfo, PrivateCrossSigningIdentity, SenderData,
-        SenderDataFinder, SessionType, StaticAccountData,
+        utility::to_signable_json, Account, CrossSigningBackends, CrossSigningStatus,
+        EncryptionSettings, IdentityKeys, InboundGroupSession, KnownSenderData, OlmDecryptionInfo,
+        OneTimeKeyPoolHealth, OutboundGroupSessionInfo, PickledCrossSigningIdentity,
+        PrivateCrossSigningIdentity, SenderData, SenderDataFinder, SenderTrustExplanation,
+        SessionType, StaticAccountData, VerifyJson,
     },
     session_manager::{GroupSessionManager, SessionManager},
     store::{
         caches::StoreCache,
         types::{
-            Changes, CrossSigningKeyExport, DeviceChanges, IdentityChanges, PendingChanges,
-            RoomKeyInfo, RoomSettings, StoredRoomKeyBundleData,
+            Changes, CrossSigningKeyExport, CryptoStatistics, DeletedRoomCryptoState,
+            DeviceChanges, FallbackKeyRotationPolicy, IdentityChanges, KeyQueryPriority,
+            PendingChanges, RoomKeyBundleAcceptancePolicy, RoomKeyInfo, RoomRotationPolicy,
+            RoomSettings, SessionPruningPolicy, ShredReport, StoredRoomKeyBundleData,
         },
-        CryptoStoreWrapper, IntoCryptoStore, MemoryStore, Result as StoreResult, SecretImportError,
-        Store, StoreTransaction,
+        CryptoStoreWrapper, IntoCryptoStore, MemoryStore, RateLimitedRequest, RateLimiter,
+        RateLimits, Result as StoreResult, SecretImportError, SessionCreationLimit, Store,
+        StoreMetricsObserver, StoreTransaction, CURRENT_GENERATION_STORE_KEY,
     },
     types::{
         events::{
@@ -92,19 +106,20 @@ use crate::{
                 ToDeviceEncryptedEventContent,
             },
             room_key::{MegolmV1AesSha2Content, RoomKeyContent},
+            room_key_ack::RoomKeyAckContent,
             room_key_bundle::RoomKeyBundleContent,
             room_key_withheld::{
                 MegolmV1AesSha2WithheldContent, RoomKeyWithheldContent, RoomKeyWithheldEvent,
             },
-            ToDeviceEvent, ToDeviceEvents,
+            CryptoContextInfo, EventType, ToDeviceEvent, ToDeviceEvents, UtdCause, UtdStoreInfo,
         },
         requests::{
-            AnyIncomingResponse, KeysQueryRequest, OutgoingRequest, ToDeviceRequest,
-            UploadSigningKeysRequest,
+            AnyIncomingResponse, AnyOutgoingRequest, KeysQueryRequest, OutgoingRequest,
+            ToDeviceRequest, UploadSigningKeysRequest,
         },
-        EventEncryptionAlgorithm, Signatures,
+        EventEncryptionAlgorithm, Signature, Signatures,
     },
-    utilities::timestamp_to_iso8601,
+    utilities::{timestamp_to_iso8601, LatencyTimer},
     verification::{Verification, VerificationMachine, VerificationRequest},
     CollectStrategy, CryptoStoreError, DecryptionSettings, DeviceData, LocalTrust,
     RoomEventDecryptionResult, SignatureError, TrustRequirement,
@@ -146,6 +161,27 @@ pub struct OlmMachineInner {
     identity_manager: IdentityManager,
     /// A state machine that handles creating room key backups.
     backup_machine: BackupMachine,
+    /// Merges the to-device requests returned by
+    /// [`OlmMachine::outgoing_requests()`] into batches.
+    to_device_batcher: ToDeviceBatcher,
+    /// The `m.room_key.ack` acknowledgements we need to send out, see
+    /// [`OlmMachine::set_room_key_acks_enabled()`].
+    room_key_acks: RoomKeyAcks,
+    /// Notifies listeners when we are running low on one-time keys on the
+    /// server, see [`OlmMachine::one_time_key_pool_stream()`].
+    one_time_key_pool: OneTimeKeyPoolMonitor,
+    /// The to-device requests which were queued up before a restart, and which
+    /// haven't reached the server yet, see
+    /// [`OlmMachine::persist_to_device_requests()`].
+    restored_to_device_requests: StdRwLock<BTreeMap<OwnedTransactionId, OutgoingRequest>>,
+    /// The handlers for decrypted to-device events of a custom type, by event
+    /// type.
+    custom_to_device_handlers: StdRwLock<HashMap<String, Arc<dyn CustomToDeviceEventHandler>>>,
+    /// The optional subsystems which are enabled, see [`OlmMachineBuilder`].
+    features: OlmMachineFeatures,
+    /// Limits the outgoing requests we create, see
+    /// [`OlmMachineBuilder::rate_limits()`].
+    rate_limiter: RateLimiter,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -159,8 +195,8 @@ impl std::fmt::Debug for OlmMachine {
 }
 
 impl OlmMachine {
-    const CURRENT_GENERATION_STORE_KEY: &'static str = "generation-counter";
     const HAS_MIGRATED_VERIFICATION_LATCH: &'static str = "HAS_MIGRATED_VERIFICATION_LATCH";
+    const PENDING_CROSS_SIGNING_RESET: &'static str = "PENDING_CROSS_SIGNING_RESET";
 
     /// Create a new memory based OlmMachine.
     ///
@@ -178,6 +214,18 @@ impl OlmMachine {
             .expect("Reading and writing to the memory store always succeeds")
     }
 
+    /// Create an [`OlmMachineBuilder`], which allows to configure the store of
+    /// the machine and to turn off optional subsystems, like backups.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that owns this machine.
+    ///
+    /// * `device_id` - The unique id of the device that owns this machine.
+    pub fn builder(user_id: &UserId, device_id: &DeviceId) -> OlmMachineBuilder {
+        OlmMachineBuilder::new(user_id, device_id)
+    }
+
     pub(crate) async fn rehydrate(
         &self,
         pickle_key: &[u8; 32],
@@ -208,6 +256,8 @@ impl OlmMachine {
             identity_manager,
             self.store().private_identity(),
             None,
+            self.inner.features,
+            RateLimits::default(),
         ))
     }
 
@@ -232,6 +282,8 @@ impl OlmMachine {
         identity_manager: IdentityManager,
         user_identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
         maybe_backup_key: Option<MegolmV1BackupKey>,
+        features: OlmMachineFeatures,
+        rate_limits: RateLimits,
     ) -> Self {
         let group_session_manager = GroupSessionManager::new(store.clone());
 
@@ -243,10 +295,18 @@ impl OlmMachine {
             users_for_key_claim.clone(),
         );
 
+        if !features.gossiping {
+            key_request_machine.set_gossiping_enabled(false);
+        }
+
         let session_manager =
             SessionManager::new(users_for_key_claim, key_request_machine.clone(), store.clone());
 
-        let backup_machine = BackupMachine::new(store.clone(), maybe_backup_key);
+        let backup_machine = if features.backups {
+            BackupMachine::new(store.clone(), maybe_backup_key)
+        } else {
+            BackupMachine::new_disabled(store.clone())
+        };
 
         let inner = Arc::new(OlmMachineInner {
             user_id: store.user_id().to_owned(),
@@ -259,6 +319,13 @@ impl OlmMachine {
             key_request_machine,
             identity_manager,
             backup_machine,
+            to_device_batcher: ToDeviceBatcher::default(),
+            room_key_acks: RoomKeyAcks::default(),
+            one_time_key_pool: OneTimeKeyPoolMonitor::default(),
+            restored_to_device_requests: Default::default(),
+            custom_to_device_handlers: Default::default(),
+            features,
+            rate_limiter: RateLimiter::new(rate_limits),
         });
 
         Self { inner }
@@ -291,14 +358,61 @@ impl OlmMachine {
     ///   user/device IDs, e.g., to use the identity key as the device ID.
     ///
     /// [`CryptoStore`]: crate::store::CryptoStore
-    #[instrument(skip(store, custom_account), fields(ed25519_key, curve25519_key))]
     pub async fn with_store(
         user_id: &UserId,
         device_id: &DeviceId,
         store: impl IntoCryptoStore,
         custom_account: Option<vodozemac::olm::Account>,
     ) -> StoreResult<Self> {
-        let store = store.into_crypto_store();
+        let mut builder = Self::builder(user_id, device_id).store(store);
+
+        if let Some(custom_account) = custom_account {
+            builder = builder.custom_account(custom_account);
+        }
+
+        builder.build().await
+    }
+
+    /// Restore an [`OlmMachine`] from a fixture created by
+    /// [`MemoryStore::export_fixture()`].
+    ///
+    /// This allows tests to start from a known end-to-end encryption state,
+    /// instead of going through the key exchange with other machines every
+    /// time. The fixture is imported into a new [`MemoryStore`], and the user
+    /// and device IDs are taken from the account it contains.
+    ///
+    /// Returns [`CryptoStoreError::AccountUnset`] if the fixture doesn't
+    /// contain an account.
+    #[cfg(any(test, feature = "testing"))]
+    pub async fn from_fixture(fixture: &str) -> StoreResult<Self> {
+        let store = MemoryStore::import_fixture(fixture)?;
+        let account = store.get_static_account().ok_or(CryptoStoreError::AccountUnset)?;
+
+        Self::with_store(&account.user_id, &account.device_id, store, None).await
+    }
+
+    #[instrument(
+        skip_all,
+        fields(
+            user_id = ?builder.user_id,
+            device_id = ?builder.device_id,
+            ed25519_key,
+            curve25519_key,
+        )
+    )]
+    async fn from_builder(builder: OlmMachineBuilder) -> StoreResult<Self> {
+        let OlmMachineBuilder {
+            user_id,
+            device_id,
+            store,
+            custom_account,
+            secret_storage,
+            value_codec,
+            features,
+            rate_limits,
+        } = builder;
+        let (user_id, device_id): (&UserId, &DeviceId) = (&user_id, &device_id);
+        let store = store.unwrap_or_else(|| MemoryStore::new().into_crypto_store());
 
         let static_account = match store.load_account().await? {
             Some(account) => {
@@ -368,11 +482,22 @@ impl OlmMachine {
             }
         };
 
+        let mut store = CryptoStoreWrapper::new(user_id, device_id, store);
+
+        if let Some(secret_storage) = secret_storage {
+            store = store.with_secret_storage(secret_storage);
+        }
+
+        if let Some(value_codec) = value_codec {
+            store = store.with_value_codec(value_codec);
+        }
+
         // FIXME: This is a workaround for `regenerate_olm` clearing the backup
         // state. Ideally, backups should not get automatically enabled since
         // the `OlmMachine` doesn't get enough info from the homeserver for this
         // to work reliably.
-        let saved_keys = store.load_backup_keys().await?;
+        let saved_keys =
+            if features.backups { store.load_backup_keys().await? } else { Default::default() };
         let maybe_backup_key = saved_keys.decryption_key.and_then(|k| {
             if let Some(version) = saved_keys.backup_version {
                 let megolm_v1_backup_key = k.megolm_v1_public_key();
@@ -384,7 +509,7 @@ impl OlmMachine {
         });
 
         let identity = Arc::new(Mutex::new(identity));
-        let store = Arc::new(CryptoStoreWrapper::new(user_id, device_id, store));
+        let store = Arc::new(store);
 
         let (verification_machine, store, identity_manager) =
             Self::new_helper_prelude(store, static_account, identity.clone());
@@ -393,14 +518,43 @@ impl OlmMachine {
         // mechanism (at the store wrapper layer).
         Self::migration_post_verified_latch_support(&store, &identity_manager).await?;
 
-        Ok(Self::new_helper(
+        let machine = Self::new_helper(
             device_id,
             store,
             verification_machine,
             identity_manager,
             identity,
             maybe_backup_key,
-        ))
+            features,
+            rate_limits,
+        );
+
+        machine.restore_to_device_requests().await?;
+
+        Ok(machine)
+    }
+
+    /// Load the to-device requests which were queued up before a restart, so
+    /// they are returned by [`OlmMachine::outgoing_requests()`] again.
+    async fn restore_to_device_requests(&self) -> StoreResult<()> {
+        let requests = self.inner.store.pending_to_device_requests().await?;
+
+        if !requests.is_empty() {
+            info!(count = requests.len(), "Restored to-device requests queued up before a restart");
+        }
+
+        *self.inner.restored_to_device_requests.write() = requests
+            .into_iter()
+            .map(|(request_id, request)| {
+                let request = OutgoingRequest {
+                    request_id: request_id.clone(),
+                    request: Arc::new(request.into()),
+                };
+                (request_id, request)
+            })
+            .collect();
+
+        Ok(())
     }
 
     // The sdk now support verified identity change detection.
@@ -470,6 +624,18 @@ impl OlmMachine {
         Ok(self.inner.identity_manager.key_query_manager.synced(&cache).await?.tracked_users())
     }
 
+    /// Get the optional subsystems which are enabled for this machine, see
+    /// [`OlmMachineBuilder`].
+    pub fn features(&self) -> OlmMachineFeatures {
+        self.inner.features
+    }
+
+    /// Get the limits of the outgoing requests of this machine, see
+    /// [`OlmMachineBuilder::rate_limits()`].
+    pub fn rate_limits(&self) -> RateLimits {
+        self.inner.rate_limiter.limits()
+    }
+
     /// Enable or disable room key requests.
     ///
     /// Room key requests allow the device to request room keys that it might
@@ -511,14 +677,434 @@ impl OlmMachine {
         self.inner.key_request_machine.is_room_key_forwarding_enabled()
     }
 
+    /// Set the maximum number of times a forwarded room key may have been
+    /// forwarded before it reached us for us to accept it.
+    ///
+    /// A key which was forwarded to us by the device that received it from
+    /// its creator has a depth of 1, see
+    /// [`InboundGroupSession::forwarding_depth()`]. Forwarded keys which were
+    /// forwarded more often are ignored. By default, there is no limit.
+    ///
+    /// See also [`OlmMachine::max_forwarding_depth`].
+    pub fn set_max_forwarding_depth(&self, max_depth: Option<usize>) {
+        self.inner.key_request_machine.set_max_forwarding_depth(max_depth)
+    }
+
+    /// Get the maximum number of times a forwarded room key may have been
+    /// forwarded before it reached us for us to accept it.
+    ///
+    /// See also [`OlmMachine::set_max_forwarding_depth`].
+    pub fn max_forwarding_depth(&self) -> Option<usize> {
+        self.inner.key_request_machine.max_forwarding_depth()
+    }
+
+    /// Enable or disable recording saved sessions, devices and identities in
+    /// the change journal of the store.
+    ///
+    /// Other processes sharing the store can then use
+    /// [`CryptoStore::load_change_journal()`] to find out which objects this
+    /// machine modified since they last looked, instead of reloading
+    /// everything. The change journal is disabled by default.
+    ///
+    /// [`CryptoStore::load_change_journal()`]: crate::store::CryptoStore::load_change_journal
+    pub fn set_change_journal_enabled(&self, enabled: bool) {
+        self.inner.store.crypto_store().set_change_journal_enabled(enabled)
+    }
+
+    /// Is the change journal of the store enabled?
+    ///
+    /// See also [`OlmMachine::set_change_journal_enabled`].
+    pub fn is_change_journal_enabled(&self) -> bool {
+        self.inner.store.crypto_store().is_change_journal_enabled()
+    }
+
+    /// Set the maximum number of users a single `/keys/query` request, as
+    /// returned by [`OlmMachine::outgoing_requests()`], contains.
+    ///
+    /// If more users need to be queried, they are split up into multiple
+    /// requests. The users whose devices need to be known before a message can
+    /// be sent are part of the first requests.
+    ///
+    /// Defaults to 250 users.
+    pub fn set_key_query_batch_size(&self, batch_size: usize) {
+        self.inner.identity_manager.key_query_manager.set_batch_size(batch_size)
+    }
+
+    /// Set the policy deciding which Olm sessions are removed when
+    /// [`Store::prune_sessions()`] is called.
+    ///
+    /// By default, no sessions are ever removed.
+    ///
+    /// See also [`OlmMachine::session_pruning_policy`].
+    pub fn set_session_pruning_policy(&self, policy: SessionPruningPolicy) {
+        self.inner.store.set_session_pruning_policy(policy)
+    }
+
+    /// Get the policy deciding which Olm sessions are removed when
+    /// [`Store::prune_sessions()`] is called.
+    ///
+    /// See also [`OlmMachine::set_session_pruning_policy`].
+    pub fn session_pruning_policy(&self) -> SessionPruningPolicy {
+        self.inner.store.session_pruning_policy()
+    }
+
+    /// Set the rules deciding whether a room key bundle, which another member
+    /// of a room sent us to share its history, is accepted by
+    /// [`Store::receive_room_key_bundle()`].
+    ///
+    /// By default, only bundles sent by a cross-signed device of a member who
+    /// is allowed to share the history of the room are accepted.
+    ///
+    /// See also [`OlmMachine::room_key_bundle_acceptance_policy`].
+    pub fn set_room_key_bundle_acceptance_policy(&self, policy: RoomKeyBundleAcceptancePolicy) {
+        self.inner.store.set_room_key_bundle_acceptance_policy(policy)
+    }
+
+    /// Get the rules deciding whether a room key bundle is accepted by
+    /// [`Store::receive_room_key_bundle()`].
+    ///
+    /// See also [`OlmMachine::set_room_key_bundle_acceptance_policy`].
+    pub fn room_key_bundle_acceptance_policy(&self) -> RoomKeyBundleAcceptancePolicy {
+        self.inner.store.room_key_bundle_acceptance_policy()
+    }
+
+    /// Set the number of new Olm sessions a single device, identified by its
+    /// Curve25519 key, may create with us within a period of time, `None`
+    /// removes the limit.
+    ///
+    /// Every new session uses up one of our one-time keys, devices creating
+    /// more sessions are reported by the [`Store::suspicious_activity_stream()`],
+    /// and their pre-key messages are rejected if
+    /// [`SessionCreationLimit::reject`] is set. The limit defaults to
+    /// [`SessionCreationLimit::default()`], which only reports devices.
+    ///
+    /// See also [`OlmMachine::session_creation_limit`].
+    pub fn set_session_creation_limit(&self, limit: Option<SessionCreationLimit>) {
+        self.inner.store.set_session_creation_limit(limit)
+    }
+
+    /// Get the number of new Olm sessions a single device may create with us
+    /// within a period of time.
+    ///
+    /// See also [`OlmMachine::set_session_creation_limit`].
+    pub fn session_creation_limit(&self) -> Option<SessionCreationLimit> {
+        self.inner.store.session_creation_limit()
+    }
+
+    /// Set the source of the current time used by this machine.
+    ///
+    /// The clock decides when outbound group sessions expire, when a wedged
+    /// Olm session may be replaced, and which sessions are removed by
+    /// [`Store::prune_sessions()`] and
+    /// [`Store::apply_room_key_retention()`]. It's also used to timestamp the
+    /// Olm sessions and room keys we create or receive, to time out
+    /// verifications and to back off room keys which failed to be backed up.
+    ///
+    /// The clock is handed down to the store as well, the [`MemoryStore`] uses
+    /// it for its leased locks. Stores whose leases are shared with other
+    /// processes keep using the system time.
+    ///
+    /// [`MemoryStore`]: crate::store::MemoryStore
+    ///
+    /// Defaults to the [`SystemClock`](crate::clock::SystemClock).
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.inner.store.set_clock(clock)
+    }
+
+    /// Get the source of the current time used by this machine.
+    ///
+    /// See also [`OlmMachine::set_clock`].
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.store.clock()
+    }
+
+    /// Get counters describing the end-to-end encryption activity of our
+    /// account, for example to report its health as part of the telemetry of a
+    /// client.
+    ///
+    /// The counters are persisted in the store and accumulate over the whole
+    /// lifetime of the account, see [`CryptoStatistics`] for the details.
+    pub async fn statistics(&self) -> StoreResult<CryptoStatistics> {
+        self.inner.store.statistics().await
+    }
+
+    /// Install an observer which is notified about the operations of the
+    /// crypto store, for example to export metrics about them.
+    ///
+    /// Passing `None` removes the currently installed observer.
+    pub fn set_store_metrics_observer(&self, observer: Option<Arc<dyn StoreMetricsObserver>>) {
+        self.inner.store.crypto_store().set_metrics_observer(observer)
+    }
+
+    /// Install a hook cross-checking the keys returned by `/keys/query`
+    /// requests against a second source, like a key transparency log.
+    ///
+    /// Users whose keys don't match are recorded as disputed, see
+    /// [`Store::identity_dispute()`].
+    ///
+    /// Passing `None` removes the currently installed validator.
+    pub fn set_key_query_validator(&self, validator: Option<Arc<dyn KeyQueryValidator>>) {
+        self.inner.identity_manager.set_validator(validator)
+    }
+
+    /// Register a handler for decrypted to-device events of the given custom
+    /// type, e.g. `com.example.provisioning`.
+    ///
+    /// Encrypted to-device events whose type isn't known to the crypto crate
+    /// are otherwise only returned from
+    /// [`OlmMachine::receive_sync_changes()`]. The handler is additionally
+    /// given the [`EncryptionInfo`] of the event, which tells whether the
+    /// sender's device is verified.
+    ///
+    /// Registering a handler for a type which already has one replaces it.
+    pub fn register_custom_to_device_event_handler(
+        &self,
+        event_type: impl Into<String>,
+        handler: Arc<dyn CustomToDeviceEventHandler>,
+    ) {
+        self.inner.custom_to_device_handlers.write().insert(event_type.into(), handler);
+    }
+
+    /// Remove the handler for decrypted to-device events of the given custom
+    /// type, see [`OlmMachine::register_custom_to_device_event_handler()`].
+    ///
+    /// Returns the handler which was removed, if any.
+    pub fn remove_custom_to_device_event_handler(
+        &self,
+        event_type: &str,
+    ) -> Option<Arc<dyn CustomToDeviceEventHandler>> {
+        self.inner.custom_to_device_handlers.write().remove(event_type)
+    }
+
+    /// Enable or disable the experimental `m.room_key.ack` acknowledgements of
+    /// room keys.
+    ///
+    /// While enabled, an Olm-encrypted `m.room_key.ack` to-device message is
+    /// sent back to the sender of every room key we receive and store, as part
+    /// of [`OlmMachine::outgoing_requests()`]. In turn, the acknowledgements
+    /// other devices send for our own room keys are recorded and can be
+    /// inspected using [`OlmMachine::room_key_acknowledgements()`], or the
+    /// [`OutboundGroupSessionInfo::acknowledged_by`] field of
+    /// [`OlmMachine::outbound_session_info()`] for the current room key of a
+    /// room.
+    ///
+    /// This isn't part of the spec and is only meant to measure how reliably
+    /// room keys are delivered. At most 1000 acknowledgements wait to be sent
+    /// out at a time, and the acknowledgements of up to 100 room keys are
+    /// kept, in memory only, forgetting the oldest room keys first. Disabled by
+    /// default.
+    pub fn set_room_key_acks_enabled(&self, enabled: bool) {
+        self.inner.room_key_acks.set_enabled(enabled);
+    }
+
+    /// Are room keys acknowledged, see
+    /// [`OlmMachine::set_room_key_acks_enabled()`]?
+    pub fn room_key_acks_enabled(&self) -> bool {
+        self.inner.room_key_acks.is_enabled()
+    }
+
+    /// Set the policy deciding when our fallback key is rotated.
+    ///
+    /// The policy is persisted together with our account. By default, the
+    /// fallback key is rotated once it's older than a week.
+    ///
+    /// See also [`OlmMachine::rotate_fallback_key_now()`].
+    pub async fn set_fallback_key_rotation_policy(
+        &self,
+        policy: FallbackKeyRotationPolicy,
+    ) -> StoreResult<()> {
+        let mut transaction = self.inner.store.transaction().await;
+        transaction.account().await?.set_fallback_key_rotation_policy(policy);
+        transaction.commit().await
+    }
+
+    /// Get the policy deciding when our fallback key is rotated.
+    ///
+    /// See also [`OlmMachine::set_fallback_key_rotation_policy()`].
+    pub async fn fallback_key_rotation_policy(&self) -> StoreResult<FallbackKeyRotationPolicy> {
+        let cache = self.inner.store.cache().await?;
+        let account = cache.account().await?;
+
+        Ok(account.fallback_key_rotation_policy().clone())
+    }
+
+    /// Rotate our fallback key right away, no matter what the
+    /// [`FallbackKeyRotationPolicy`] says.
+    ///
+    /// This can be used if the private part of the fallback key might have
+    /// been compromised. The new fallback key is uploaded by the next keys
+    /// upload request returned by [`OlmMachine::outgoing_requests()`].
+    ///
+    /// If a new fallback key is waiting to be uploaded already, it's kept, so
+    /// the fallback key which is still published on the server keeps working
+    /// until it's replaced.
+    pub async fn rotate_fallback_key_now(&self) -> StoreResult<()> {
+        let mut transaction = self.inner.store.transaction().await;
+        transaction.account().await?.rotate_fallback_key();
+        transaction.commit().await
+    }
+
+    /// Set the number of one-time keys on the server below which the
+    /// [`OlmMachine::one_time_key_pool_stream()`] yields an update.
+    ///
+    /// The default, zero, disables the updates.
+    pub fn set_one_time_key_low_watermark(&self, low_watermark: u64) {
+        self.inner.one_time_key_pool.set_low_watermark(low_watermark);
+    }
+
+    /// The number of one-time keys on the server below which the
+    /// [`OlmMachine::one_time_key_pool_stream()`] yields an update.
+    pub fn one_time_key_low_watermark(&self) -> u64 {
+        self.inner.one_time_key_pool.low_watermark()
+    }
+
+    /// Get the current state of our pool of one-time keys.
+    ///
+    /// The server-side count is the one the server reported in the last sync
+    /// response, see [`OlmMachine::receive_sync_changes()`].
+    pub async fn one_time_key_pool_health(&self) -> StoreResult<OneTimeKeyPoolHealth> {
+        let cache = self.inner.store.cache().await?;
+        let account = cache.account().await?;
+
+        Ok(account.one_time_key_pool_health())
+    }
+
+    /// Get a stream of [`OneTimeKeyPoolHealth`] updates, yielded every time
+    /// the server reports a lower one-time key count which is below the low
+    /// watermark, see [`OlmMachine::set_one_time_key_low_watermark()`].
+    ///
+    /// This allows applications which are rarely online to sync, and upload
+    /// new one-time keys, before the server runs out of them. Missing
+    /// one-time keys means that other devices can't establish new Olm sessions
+    /// with us, other than by using our fallback key.
+    pub fn one_time_key_pool_stream(&self) -> impl Stream<Item = OneTimeKeyPoolHealth> {
+        self.inner.one_time_key_pool.stream()
+    }
+
+    /// Set the size of the cache holding the plaintext of decrypted room
+    /// events, in bytes.
+    ///
+    /// The cache avoids decrypting the same event again, e.g. when a timeline
+    /// is rendered again or a push notification is processed for an event
+    /// that was already decrypted. It is enabled by default with a size of
+    /// 1 MiB, passing a size of zero disables it.
+    pub fn set_decrypted_event_cache_size(&self, size: usize) {
+        self.inner.store.crypto_store().set_decrypted_event_cache_capacity(size)
+    }
+
+    /// Freeze the crypto state, e.g. because the server soft logged out our
+    /// device.
+    ///
+    /// After a soft logout the access token is invalid, but the device keeps
+    /// existing on the server, so the client should log in again using the
+    /// same device ID instead of creating a new device. Freezing the machine
+    /// makes sure nothing changes in the meantime:
+    ///
+    /// * [`OlmMachine::outgoing_requests()`] returns no requests. The requests
+    ///   which are queued up to be sent out are kept, together with the IDs of
+    ///   the batches they were merged into, and are returned again once the
+    ///   machine is thawed.
+    /// * All writes to the store are rejected with a
+    ///   [`CryptoStoreError::ReadOnly`] error, which means that
+    ///   [`OlmMachine::receive_sync_changes()`],
+    ///   [`OlmMachine::mark_request_as_sent()`] and encrypting new messages
+    ///   fail. The Olm and Megolm sessions stay intact and can still be used
+    ///   to decrypt events, the Megolm message indices they use are only
+    ///   remembered in memory.
+    ///
+    /// The frozen state isn't persisted, use [`OlmMachine::thaw()`] once the
+    /// user has authenticated again.
+    pub fn freeze(&self) {
+        info!("Freezing the crypto store");
+
+        self.inner.store.crypto_store().set_frozen(true);
+    }
+
+    /// Thaw the crypto state which was frozen using [`OlmMachine::freeze()`],
+    /// after the user has authenticated again.
+    ///
+    /// The new login must have used the device ID of this machine, otherwise
+    /// a [`CryptoStoreError::MismatchedAccount`] error is returned and the
+    /// machine stays frozen. Logging in as a new device requires a new
+    /// `OlmMachine` with its own store.
+    ///
+    /// A machine which was [shredded](OlmMachine::shred) can't be thawed, a
+    /// [`CryptoStoreError::Shredded`] error is returned.
+    pub fn thaw(&self, device_id: &DeviceId) -> StoreResult<()> {
+        if self.is_shredded() {
+            return Err(CryptoStoreError::Shredded);
+        }
+
+        if device_id != self.device_id() {
+            return Err(CryptoStoreError::MismatchedAccount {
+                expected: (self.user_id().to_owned(), self.device_id().to_owned()),
+                got: (self.user_id().to_owned(), device_id.to_owned()),
+            });
+        }
+
+        info!("Thawing the crypto store");
+
+        self.inner.store.crypto_store().set_frozen(false);
+
+        Ok(())
+    }
+
+    /// Is the crypto state frozen, see [`OlmMachine::freeze()`]?
+    pub fn is_frozen(&self) -> bool {
+        self.inner.store.crypto_store().is_frozen()
+    }
+
+    /// Was the crypto state wiped, see [`OlmMachine::shred()`]?
+    pub fn is_shredded(&self) -> bool {
+        self.inner.store.crypto_store().is_shredded()
+    }
+
+    /// Wipe the crypto state of this device, e.g. because a remote wipe was
+    /// requested.
+    ///
+    /// This deletes the account, the private cross-signing keys, the backup
+    /// decryption key, the dehydrated device pickle key, the secrets inbox,
+    /// the custom values, and all the Olm and Megolm sessions from the store,
+    /// see [`CryptoStore::shred()`]. Stores which support it overwrite the deleted
+    /// data instead of only unlinking it, this is reported in the returned
+    /// [`ShredReport`].
+    ///
+    /// The private cross-signing keys are removed from memory as well, and the
+    /// machine is [frozen](OlmMachine::freeze) for good so that nothing gets
+    /// written back to the store. The machine can't be used anymore afterwards
+    /// and should be dropped.
+    ///
+    /// [`CryptoStore::shred()`]: crate::store::CryptoStore::shred
+    pub async fn shred(&self) -> StoreResult<ShredReport> {
+        warn!("Shredding the crypto store");
+
+        self.freeze();
+        self.inner.user_identity.lock().await.clear().await;
+
+        let report = self.inner.store.shred().await?;
+
+        info!(?report, "Shredded the crypto store");
+
+        Ok(report)
+    }
+
     /// Get the outgoing requests that need to be sent out.
     ///
     /// This returns a list of [`OutgoingRequest`]. Those requests need to be
     /// sent out to the server and the responses need to be passed back to
     /// the state machine using [`mark_request_as_sent`].
     ///
+    /// Pending to-device requests of the same event type are merged into
+    /// batches of up to 100 messages. A batch keeps its request ID until it's
+    /// marked as sent, so retrying it after a failure doesn't deliver the
+    /// messages twice.
+    ///
     /// [`mark_request_as_sent`]: #method.mark_request_as_sent
     pub async fn outgoing_requests(&self) -> StoreResult<Vec<OutgoingRequest>> {
+        if self.is_frozen() {
+            return Ok(Vec::new());
+        }
+
         let mut requests = Vec::new();
 
         {
@@ -532,21 +1118,134 @@ impl OlmMachine {
             }
         }
 
-        for request in self
-            .inner
-            .identity_manager
-            .users_for_key_query()
-            .await?
-            .into_iter()
-            .map(|(request_id, r)| OutgoingRequest { request_id, request: Arc::new(r.into()) })
+        // Throttled key queries are left out, the users stay marked as outdated so
+        // the queries are created again once the limit allows it.
+        let rate_limiter = &self.inner.rate_limiter;
+        let key_queries = self.inner.identity_manager.key_query_requests().await?;
+
+        match rate_limiter
+            .acquire(&self.inner.store, RateLimitedRequest::KeysQuery, key_queries.len())
+            .await
         {
-            requests.push(request);
+            Ok(count) => {
+                requests.extend(key_queries.into_iter().take(count).map(|(request_id, r)| {
+                    OutgoingRequest { request_id, request: Arc::new(r.into()) }
+                }));
+            }
+            Err(CryptoStoreError::RateLimited(error)) => {
+                debug!(retry_after = ?error.retry_after, "Not creating key queries for now");
+            }
+            Err(error) => return Err(error),
+        }
+
+        let mut to_device_requests = self.inner.verification_machine.outgoing_messages();
+        to_device_requests
+            .append(&mut self.inner.key_request_machine.outgoing_to_device_requests().await?);
+        to_device_requests.append(&mut self.inner.room_key_acks.outgoing_requests());
+
+        self.persist_to_device_requests().await?;
+
+        // The requests restored after a restart are only added if the
+        // component which created them didn't queue them up again itself.
+        let restored: Vec<_> = self
+            .inner
+            .restored_to_device_requests
+            .read()
+            .values()
+            .filter(|restored| {
+                to_device_requests.iter().all(|r| r.request_id != restored.request_id)
+            })
+            .cloned()
+            .collect();
+        to_device_requests.extend(restored);
+
+        // Throttled to-device requests are left out as well, their owners keep
+        // them until they are sent out.
+        let to_device_requests =
+            rate_limiter.admit_to_device_requests(&self.inner.store, to_device_requests).await?;
+        requests.extend(to_device_requests);
+        requests.append(&mut self.outgoing_signature_requests().await?);
+
+        Ok(self.inner.to_device_batcher.batch(requests))
+    }
+
+    /// Persist the to-device requests which are only queued up in memory,
+    /// so they survive a restart.
+    ///
+    /// These are the verification messages, the room keys and secrets we
+    /// forward, and the `m.room_key.ack` acknowledgements. Other to-device
+    /// requests, like our own key requests and room key shares, are already
+    /// persisted by the components which create them.
+    ///
+    /// The persisted requests are replaced, so requests which were sent out,
+    /// or dropped by the component which created them, aren't restored.
+    async fn persist_to_device_requests(&self) -> StoreResult<()> {
+        if self.is_frozen() {
+            return Ok(());
         }
 
+        let mut requests: Vec<_> =
+            self.inner.restored_to_device_requests.read().values().cloned().collect();
         requests.append(&mut self.inner.verification_machine.outgoing_messages());
-        requests.append(&mut self.inner.key_request_machine.outgoing_to_device_requests().await?);
+        requests.append(&mut self.inner.key_request_machine.outgoing_key_forwards());
+        requests.append(&mut self.inner.room_key_acks.outgoing_requests());
+
+        let requests = requests
+            .into_iter()
+            .filter_map(|r| match r.request() {
+                AnyOutgoingRequest::ToDeviceRequest(request) => {
+                    Some((r.request_id.clone(), request.clone()))
+                }
+                _ => None,
+            })
+            .collect();
 
-        Ok(requests)
+        self.inner.store.persist_to_device_requests(requests).await
+    }
+
+    /// Queue up a signature upload, so it's retried until it reaches the
+    /// server.
+    ///
+    /// This can be used for the requests returned by, for example,
+    /// [`Device::verify()`] or [`Sas::confirm()`], instead of sending them
+    /// directly. If sending such a request fails, the signatures would
+    /// otherwise be lost.
+    ///
+    /// The upload is persisted in the store, and returned by
+    /// [`OlmMachine::outgoing_signature_requests()`] and
+    /// [`OlmMachine::outgoing_requests()`] until it's marked as sent using
+    /// [`OlmMachine::mark_request_as_sent()`].
+    ///
+    /// Returns the request ID under which the upload will be sent.
+    ///
+    /// [`Sas::confirm()`]: crate::Sas::confirm
+    pub async fn queue_signature_upload(
+        &self,
+        request: UploadSignaturesRequest,
+    ) -> StoreResult<OwnedTransactionId> {
+        self.inner.store.queue_signature_upload(&request).await
+    }
+
+    /// Get the queued up signature uploads which haven't reached the server
+    /// yet, see [`OlmMachine::queue_signature_upload()`].
+    ///
+    /// A signature upload keeps its request ID until it's marked as sent using
+    /// [`OlmMachine::mark_request_as_sent()`], so it can be retried as often as
+    /// needed, for example after a network error or if the server rate
+    /// limited us. The uploads are part of [`OlmMachine::outgoing_requests()`]
+    /// as well.
+    pub async fn outgoing_signature_requests(&self) -> StoreResult<Vec<OutgoingRequest>> {
+        Ok(self
+            .inner
+            .store
+            .pending_signature_uploads()
+            .await?
+            .into_iter()
+            .map(|(request_id, request)| OutgoingRequest {
+                request_id,
+                request: Arc::new(request.into()),
+            })
+            .collect())
     }
 
     /// Generate an "out-of-band" key query request for the given set of users.
@@ -590,6 +1289,10 @@ impl OlmMachine {
         request_id: &TransactionId,
         response: impl Into<AnyIncomingResponse<'a>>,
     ) -> OlmResult<()> {
+        if self.is_frozen() {
+            return Err(CryptoStoreError::ReadOnly.into());
+        }
+
         match response.into() {
             AnyIncomingResponse::KeysUpload(response) => {
                 Box::pin(self.receive_keys_upload_response(response)).await?;
@@ -609,7 +1312,18 @@ impl OlmMachine {
             AnyIncomingResponse::SigningKeysUpload(_) => {
                 Box::pin(self.receive_cross_signing_upload_response()).await?;
             }
-            AnyIncomingResponse::SignatureUpload(_) => {
+            AnyIncomingResponse::SignatureUpload(response) => {
+                if self.inner.store.remove_signature_upload(request_id).await?
+                    && !response.failures.is_empty()
+                {
+                    // Retrying the upload won't help if the server rejected the signatures.
+                    warn!(
+                        ?request_id,
+                        failures = ?response.failures,
+                        "The server rejected some of our queued up signatures"
+                    );
+                }
+
                 self.inner.verification_machine.mark_request_as_sent(request_id);
             }
             AnyIncomingResponse::RoomMessage(_) => {
@@ -623,6 +1337,120 @@ impl OlmMachine {
         Ok(())
     }
 
+    /// Record that sending out the request with the given ID, as returned by
+    /// [`OlmMachine::outgoing_requests()`], failed, e.g. because of a network
+    /// error.
+    ///
+    /// Room key and secret requests are then left out of the outgoing requests
+    /// for a while, backing off exponentially with every failed attempt, see
+    /// [`OlmMachine::due_gossip_requests()`]. The other requests are returned
+    /// again right away.
+    pub async fn mark_request_as_failed(
+        &self,
+        request_id: &TransactionId,
+        error: &str,
+    ) -> StoreResult<()> {
+        if self.is_frozen() {
+            return Err(CryptoStoreError::ReadOnly);
+        }
+
+        for request_id in self.inner.to_device_batcher.request_ids(request_id) {
+            self.inner
+                .key_request_machine
+                .mark_outgoing_request_as_failed(&request_id, error)
+                .await?;
+        }
+
+        self.inner.to_device_batcher.mark_as_failed(request_id);
+
+        Ok(())
+    }
+
+    /// Get the room key and secret requests which weren't sent out yet, and
+    /// may be sent out at `now`.
+    ///
+    /// A request which failed to be sent out, see
+    /// [`OlmMachine::mark_request_as_failed()`], isn't due until its
+    /// [`GossipRequestRetry::next_retry_at`], which also tells how many times
+    /// it failed and why.
+    ///
+    /// [`GossipRequestRetry::next_retry_at`]: crate::GossipRequestRetry::next_retry_at
+    pub async fn due_gossip_requests(
+        &self,
+        now: MilliSecondsSinceUnixEpoch,
+    ) -> StoreResult<Vec<GossipRequest>> {
+        self.inner.key_request_machine.due_requests(now).await
+    }
+
+    /// Start resetting our cross signing identity.
+    ///
+    /// This creates a new cross signing identity, but unlike
+    /// [`OlmMachine::bootstrap_cross_signing()`] the current identity is kept
+    /// until the new public keys have been uploaded. Uploading them usually
+    /// requires user interactive auth, so the returned [`CrossSigningReset`]
+    /// can be held on to while the auth is completed.
+    ///
+    /// The new identity is persisted in the store, so the reset can be resumed
+    /// using [`OlmMachine::pending_cross_signing_reset()`] after a restart.
+    /// Starting a new reset replaces a pending one.
+    ///
+    /// **Warning**: once finished, this will replace any existing cross
+    /// signing keys and thus will reset the trust between all the devices.
+    pub async fn reset_cross_signing(&self) -> Result<CrossSigningReset, SignatureError> {
+        let (identity, _, _) = {
+            let cache = self.inner.store.cache().await?;
+            let account = cache.account().await?;
+            account.bootstrap_cross_signing().await
+        };
+
+        self.store().set_value(Self::PENDING_CROSS_SIGNING_RESET, &identity.pickle().await).await?;
+
+        info!("Started a cross signing reset");
+
+        self.cross_signing_reset(identity).await
+    }
+
+    /// Get the cross signing reset that was started using
+    /// [`OlmMachine::reset_cross_signing()`] and hasn't been finished or
+    /// cancelled yet, if any.
+    pub async fn pending_cross_signing_reset(
+        &self,
+    ) -> Result<Option<CrossSigningReset>, SignatureError> {
+        let Some(pickle) = self
+            .store()
+            .get_value::<PickledCrossSigningIdentity>(Self::PENDING_CROSS_SIGNING_RESET)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let identity = PrivateCrossSigningIdentity::from_pickle(pickle)
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        Ok(Some(self.cross_signing_reset(identity).await?))
+    }
+
+    async fn cross_signing_reset(
+        &self,
+        identity: PrivateCrossSigningIdentity,
+    ) -> Result<CrossSigningReset, SignatureError> {
+        let upload_signing_keys_req = identity.as_upload_request().await;
+        let upload_signatures_req =
+            identity.sign_account(self.inner.store.static_account()).await?;
+        let upload_keys_req =
+            self.upload_device_keys().await?.map(|(_, request)| OutgoingRequest::from(request));
+
+        Ok(CrossSigningReset {
+            machine: self.clone(),
+            identity,
+            requests: CrossSigningBootstrapRequests {
+                upload_keys_req,
+                upload_signing_keys_req,
+                upload_signatures_req,
+            },
+        })
+    }
+
     /// Mark the cross signing identity as shared.
     async fn receive_cross_signing_upload_response(&self) -> StoreResult<()> {
         let identity = self.inner.user_identity.lock().await;
@@ -712,6 +1540,40 @@ impl OlmMachine {
         })
     }
 
+    /// Find out which steps are needed to fully set up cross signing and key
+    /// backup for this device, without changing anything.
+    ///
+    /// This can be used to present the user with a checklist before calling
+    /// [`OlmMachine::bootstrap_cross_signing()`] and enabling a key backup
+    /// using the [`BackupMachine`].
+    pub async fn cross_signing_bootstrap_plan(&self) -> StoreResult<CrossSigningBootstrapPlan> {
+        let identity = self.inner.user_identity.lock().await.clone();
+
+        let create_cross_signing_keys = identity.is_empty().await;
+        let upload_cross_signing_keys = create_cross_signing_keys || !identity.shared();
+
+        let upload_device_keys = {
+            let cache = self.store().cache().await?;
+            !cache.account().await?.shared()
+        };
+
+        let sign_own_device = !self
+            .store()
+            .get_device(self.user_id(), self.device_id())
+            .await?
+            .is_some_and(|device| device.is_cross_signed_by_owner());
+
+        let enable_backup = !self.backup_machine().enabled().await;
+
+        Ok(CrossSigningBootstrapPlan {
+            create_cross_signing_keys,
+            upload_cross_signing_keys,
+            upload_device_keys,
+            sign_own_device,
+            enable_backup,
+        })
+    }
+
     /// Upload the device keys for this [`OlmMachine`].
     ///
     /// **Warning**: Do not use this method if
@@ -781,7 +1643,54 @@ impl OlmMachine {
         &self,
         users: impl Iterator<Item = &UserId>,
     ) -> StoreResult<Option<(OwnedTransactionId, KeysClaimRequest)>> {
-        self.inner.session_manager.get_missing_sessions(users).await
+        let rate_limiter = &self.inner.rate_limiter;
+        rate_limiter.acquire(&self.inner.store, RateLimitedRequest::KeysClaim, 1).await?;
+
+        let request = self.inner.session_manager.get_missing_sessions(users).await;
+        let created = usize::from(matches!(request, Ok(Some(_))));
+        rate_limiter.settle(&self.inner.store, RateLimitedRequest::KeysClaim, 1, created).await;
+
+        request
+    }
+
+    /// Get a key claiming request to establish Olm sessions with the devices
+    /// of the given room members, ahead of sharing a room key with them.
+    ///
+    /// Sharing the first room key in a large room can take a long time, since
+    /// one-time keys need to be claimed for all the devices we don't share an
+    /// Olm session with yet. Calling this method when a room is opened, i.e.
+    /// before the user hits send, lets this happen in the background.
+    ///
+    /// Devices we already have an Olm session with are remembered as warm for
+    /// the room, so pre-warming a room again is cheap. This bookkeeping is
+    /// only kept in memory and is purely an optimisation.
+    ///
+    /// Returns None if no key claiming request needs to be sent out. The same
+    /// rules as for [`OlmMachine::get_missing_sessions`] apply: only one key
+    /// claiming request should be in flight at a time, and the response needs
+    /// to be passed to [`mark_request_as_sent`].
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room that should be pre-warmed.
+    ///
+    /// * `users` - The members of the room.
+    ///
+    /// [`mark_request_as_sent`]: #method.mark_request_as_sent
+    #[instrument(skip(self, users))]
+    pub async fn prewarm_room(
+        &self,
+        room_id: &RoomId,
+        users: impl Iterator<Item = &UserId>,
+    ) -> StoreResult<Option<(OwnedTransactionId, KeysClaimRequest)>> {
+        let rate_limiter = &self.inner.rate_limiter;
+        rate_limiter.acquire(&self.inner.store, RateLimitedRequest::KeysClaim, 1).await?;
+
+        let request = self.inner.session_manager.prewarm_room(room_id, users).await;
+        let created = usize::from(matches!(request, Ok(Some(_))));
+        rate_limiter.settle(&self.inner.store, RateLimitedRequest::KeysClaim, 1, created).await;
+
+        request
     }
 
     /// Receive a successful `/keys/query` response.
@@ -915,11 +1824,11 @@ impl OlmMachine {
                         .await?;
 
                 session.sender_data = sender_data;
+                session.set_received_at(self.store().clock().now_secs());
 
                 match self.store().compare_group_session(&session).await? {
                     SessionOrdering::Better => {
                         info!("Received a new megolm room key");
-
                         Ok(Some(session))
                     }
                     comparison_result => {
@@ -985,11 +1894,40 @@ impl OlmMachine {
             DeviceData::try_from(sender_device_keys).expect("failed to verify sender device keys");
         let sender_device = self.store().wrap_device_data(sender_device_data).await?;
 
-        changes.received_room_key_bundles.push(StoredRoomKeyBundleData {
+        let mut data = StoredRoomKeyBundleData {
             sender_user: event.sender.clone(),
             sender_data: SenderData::from_device(&sender_device),
             bundle_data: event.content.clone(),
-        });
+            received_chunks: Vec::new(),
+        };
+
+        // The bundle was split into multiple chunks, save this one together with the
+        // ones we already received, which may still be part of the pending changes.
+        if data.bundle_data.chunk.is_some() {
+            let pending = changes.received_room_key_bundles.iter().rposition(|pending| {
+                pending.sender_user == data.sender_user
+                    && pending.bundle_data.room_id == data.bundle_data.room_id
+            });
+
+            let previous = match pending {
+                Some(index) => Some(changes.received_room_key_bundles.remove(index)),
+                None => {
+                    self.store()
+                        .get_received_room_key_bundle_data(
+                            &data.bundle_data.room_id,
+                            &data.sender_user,
+                        )
+                        .await?
+                }
+            };
+
+            match Store::merge_received_room_key_bundle_chunk(previous, data) {
+                Some(merged) => data = merged,
+                None => return Ok(()),
+            }
+        }
+
+        changes.received_room_key_bundles.push(data);
         Ok(())
     }
 
@@ -1090,6 +2028,12 @@ impl OlmMachine {
     ///
     /// * `event_type` - The plaintext type of the event.
     ///
+    /// # Errors
+    ///
+    /// Returns [`MegolmError::InvalidPayload`] if the content is rejected by
+    /// the validation configured in the [`EncryptionSettings`] the room key
+    /// was shared with.
+    ///
     /// # Panics
     ///
     /// Panics if a group session for the given room wasn't shared beforehand.
@@ -1099,7 +2043,12 @@ impl OlmMachine {
         event_type: &str,
         content: &Raw<AnyMessageLikeEventContent>,
     ) -> MegolmResult<Raw<RoomEncryptedEventContent>> {
-        self.inner.group_session_manager.encrypt(room_id, event_type, content).await
+        let encrypted =
+            self.inner.group_session_manager.encrypt(room_id, event_type, content).await?;
+
+        self.store().record_statistics(|statistics| statistics.messages_encrypted += 1);
+
+        Ok(encrypted)
     }
 
     /// Forces the currently active room key, which is used to encrypt messages,
@@ -1116,6 +2065,61 @@ impl OlmMachine {
         self.inner.group_session_manager.invalidate_group_session(room_id).await
     }
 
+    /// Delete all the crypto state of the given room.
+    ///
+    /// This removes the room keys, the outbound group session, the withheld
+    /// info, the received room key bundles and the settings of the room, for
+    /// example once the user forgot it, as well as the acknowledgements of its
+    /// room keys which weren't sent yet. A new outbound group session will be
+    /// created if a message is encrypted for the room again.
+    ///
+    /// See [`Store::delete_room_crypto_state()`] for more details.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room whose crypto state should be deleted.
+    pub async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<DeletedRoomCryptoState> {
+        self.inner.group_session_manager.session_cache().remove(room_id);
+        self.inner.room_key_acks.remove_room(room_id);
+        self.store().delete_room_crypto_state(room_id).await
+    }
+
+    /// Get details about the outbound group session which is currently used to
+    /// encrypt messages in the given room.
+    ///
+    /// Returns `None` if no session was created for the room yet.
+    pub async fn outbound_session_info(
+        &self,
+        room_id: &RoomId,
+    ) -> Option<OutboundGroupSessionInfo> {
+        let session = self.inner.group_session_manager.session_cache().get_or_load(room_id).await?;
+
+        let mut info = session.info();
+        info.acknowledged_by =
+            self.inner.room_key_acks.acknowledgements(room_id, session.session_id());
+
+        Some(info)
+    }
+
+    /// Get the devices which acknowledged the receipt of one of our room keys,
+    /// see [`OlmMachine::set_room_key_acks_enabled()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the room key belongs to.
+    ///
+    /// * `session_id` - The id of the room key.
+    pub fn room_key_acknowledgements(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>> {
+        self.inner.room_key_acks.acknowledgements(room_id, session_id)
+    }
+
     /// Get to-device requests to share a room key with users in a room.
     ///
     /// # Arguments
@@ -1134,14 +2138,67 @@ impl OlmMachine {
     /// and the responses need to be passed back to the state machine with
     /// [`mark_request_as_sent`], using the to-device `txn_id` as `request_id`.
     ///
-    /// [`mark_request_as_sent`]: #method.mark_request_as_sent
-    pub async fn share_room_key(
+    /// [`mark_request_as_sent`]: #method.mark_request_as_sent
+    pub async fn share_room_key(
+        &self,
+        room_id: &RoomId,
+        users: impl Iterator<Item = &UserId>,
+        encryption_settings: impl Into<EncryptionSettings>,
+    ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
+        let mut encryption_settings = encryption_settings.into();
+        encryption_settings.sharing_strategy =
+            self.resolve_collect_strategy(room_id, encryption_settings.sharing_strategy).await?;
+
+        self.acquire_to_device_token().await?;
+
+        let result = self
+            .inner
+            .group_session_manager
+            .share_room_key(room_id, users, encryption_settings)
+            .await;
+        self.settle_to_device_tokens(result.as_ref().map_or(0, Vec::len)).await;
+
+        result
+    }
+
+    /// Take a token from the [`RateLimits::to_device`] bucket before creating
+    /// to-device requests.
+    ///
+    /// Once the requests were created, the remaining tokens are taken using
+    /// [`OlmMachine::settle_to_device_tokens()`].
+    async fn acquire_to_device_token(&self) -> StoreResult<()> {
+        self.inner.rate_limiter.acquire(&self.inner.store, RateLimitedRequest::ToDevice, 1).await?;
+        Ok(())
+    }
+
+    /// Take the tokens of the given number of created to-device requests from
+    /// the [`RateLimits::to_device`] bucket, after one was taken by
+    /// [`OlmMachine::acquire_to_device_token()`].
+    async fn settle_to_device_tokens(&self, count: usize) {
+        self.inner
+            .rate_limiter
+            .settle(&self.inner.store, RateLimitedRequest::ToDevice, 1, count)
+            .await
+    }
+
+    /// Resolve the [`CollectStrategy`] which should be used to share a room key
+    /// in the given room.
+    ///
+    /// If the room, or all rooms, should only be encrypted for trusted devices,
+    /// this is [`CollectStrategy::OnlyTrustedDevices`]. Otherwise it's the
+    /// strategy set for the room using
+    /// [`OlmMachine::set_room_sharing_strategy()`], falling back to the given
+    /// strategy.
+    async fn resolve_collect_strategy(
         &self,
         room_id: &RoomId,
-        users: impl Iterator<Item = &UserId>,
-        encryption_settings: impl Into<EncryptionSettings>,
-    ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
-        self.inner.group_session_manager.share_room_key(room_id, users, encryption_settings).await
+        strategy: CollectStrategy,
+    ) -> StoreResult<CollectStrategy> {
+        if self.room_only_allow_trusted_devices(room_id).await? {
+            Ok(CollectStrategy::OnlyTrustedDevices)
+        } else {
+            Ok(self.room_sharing_strategy(room_id).await?.unwrap_or(strategy))
+        }
     }
 
     /// Encrypts the given content using Olm for each of the given devices.
@@ -1164,6 +2221,8 @@ impl OlmMachine {
         event_type: &str,
         content: &Value,
     ) -> OlmResult<(Vec<ToDeviceRequest>, Vec<(DeviceData, WithheldCode)>)> {
+        self.acquire_to_device_token().await?;
+
         // TODO: Use a `CollectStrategy` arguments to filter our devices depending on
         // safety settings (like not sending to insecure devices).
         let mut changes = Changes::default();
@@ -1174,6 +2233,9 @@ impl OlmMachine {
             .encrypt_content_for_devices(devices, event_type, content.clone(), &mut changes)
             .await;
 
+        self.settle_to_device_tokens(result.as_ref().map_or(0, |(requests, _)| requests.len()))
+            .await;
+
         // Persist any changes we might have collected.
         if !changes.is_empty() {
             let session_count = changes.sessions.len();
@@ -1188,6 +2250,84 @@ impl OlmMachine {
 
         result
     }
+
+    /// Encrypts a custom to-device event for a single device of the given user,
+    /// using Olm.
+    ///
+    /// This is a convenience wrapper around
+    /// [`OlmMachine::encrypt_content_for_devices`], the same requirements
+    /// apply: the 1-to-1 session must be established before, using the
+    /// [`OlmMachine::get_missing_sessions`] method, and the returned request
+    /// should be sent out as soon as possible.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user the event should be sent to.
+    ///
+    /// * `device_id` - The ID of the device the event should be sent to.
+    ///
+    /// * `event_type` - The type of the event, which will be encrypted.
+    ///
+    /// * `content` - The content of the event, which will be encrypted.
+    ///
+    /// Returns an [`OlmError::MissingSession`] error if the device is unknown
+    /// or there is no Olm session with it.
+    #[cfg(feature = "experimental-send-custom-to-device")]
+    pub async fn encrypt_custom_to_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        event_type: &str,
+        content: &Value,
+    ) -> OlmResult<ToDeviceRequest> {
+        let Some(device) = self.inner.store.get_device_data(user_id, device_id).await? else {
+            warn!(?user_id, ?device_id, "Can't encrypt a custom to-device event, unknown device");
+            return Err(OlmError::MissingSession);
+        };
+
+        let (mut requests, withheld) =
+            self.encrypt_content_for_devices(vec![device], event_type, content).await?;
+
+        if let Some((_, code)) = withheld.first() {
+            warn!(?user_id, ?device_id, ?code, "Couldn't encrypt a custom to-device event");
+            return Err(OlmError::MissingSession);
+        }
+
+        requests.pop().ok_or(OlmError::MissingSession)
+    }
+
+    /// Re-share the room keys of the given room that were withheld from the
+    /// devices of the given user because they were blacklisted or unverified.
+    ///
+    /// This should be called after a device of the user has been verified, the
+    /// sessions are shared starting from the message index they had when they
+    /// were withheld, so the device can't decrypt messages it wasn't allowed to
+    /// see at the time. Only the most recent room keys withheld from a device
+    /// are remembered.
+    ///
+    /// # Arguments
+    ///
+    /// `room_id` - The room id of the room the room keys belong to.
+    ///
+    /// `user_id` - The user whose verified devices should receive the room
+    /// keys.
+    ///
+    /// # Returns
+    ///
+    /// List of the to-device requests that need to be sent out to the server.
+    pub async fn reshare_withheld_keys(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> OlmResult<Vec<ToDeviceRequest>> {
+        self.acquire_to_device_token().await?;
+
+        let result = self.inner.group_session_manager.reshare_withheld_keys(room_id, user_id).await;
+        self.settle_to_device_tokens(result.as_ref().map_or(0, Vec::len)).await;
+
+        result
+    }
+
     /// Collect the devices belonging to the given user, and send the details of
     /// a room key bundle to those devices.
     ///
@@ -1198,10 +2338,16 @@ impl OlmMachine {
         collect_strategy: &CollectStrategy,
         bundle_data: RoomKeyBundleContent,
     ) -> OlmResult<Vec<ToDeviceRequest>> {
-        self.inner
+        self.acquire_to_device_token().await?;
+
+        let result = self
+            .inner
             .group_session_manager
             .share_room_key_bundle_data(user_id, collect_strategy, bundle_data)
-            .await
+            .await;
+        self.settle_to_device_tokens(result.as_ref().map_or(0, Vec::len)).await;
+
+        result
     }
 
     /// Receive an unencrypted verification event.
@@ -1286,8 +2432,30 @@ impl OlmMachine {
                 debug!("Received a room key bundle event {:?}", e);
                 self.receive_room_key_bundle_data(e, changes).await?;
             }
-            AnyDecryptedOlmEvent::Custom(_) => {
-                warn!("Received an unexpected encrypted to-device event");
+            AnyDecryptedOlmEvent::Custom(e) if e.event_type == RoomKeyAckContent::EVENT_TYPE => {
+                if let Some(sender_device) = &decrypted.result.encryption_info.sender_device {
+                    self.receive_room_key_ack(
+                        &decrypted.result.encryption_info.sender,
+                        sender_device,
+                        &decrypted.result.raw_event,
+                    )
+                    .await?;
+                } else {
+                    warn!("Received a room key acknowledgement from an unknown device");
+                }
+            }
+            AnyDecryptedOlmEvent::Custom(e) => {
+                let handler =
+                    self.inner.custom_to_device_handlers.read().get(&e.event_type).cloned();
+
+                if let Some(handler) = handler {
+                    handler.handle_event(
+                        &decrypted.result.raw_event,
+                        &decrypted.result.encryption_info,
+                    );
+                } else {
+                    warn!("Received an unexpected encrypted to-device event");
+                }
             }
         }
 
@@ -1302,11 +2470,23 @@ impl OlmMachine {
 
     /// Mark an outgoing to-device requests as sent.
     async fn mark_to_device_request_as_sent(&self, request_id: &TransactionId) -> StoreResult<()> {
-        self.inner.verification_machine.mark_request_as_sent(request_id);
-        self.inner.key_request_machine.mark_outgoing_request_as_sent(request_id).await?;
-        self.inner.group_session_manager.mark_request_as_sent(request_id).await?;
-        self.inner.session_manager.mark_outgoing_request_as_sent(request_id);
-        Ok(())
+        // The request might have been a batch of requests, which are all sent now. The
+        // batch is only forgotten once every one of them has been marked as sent, so
+        // that a failure partway through can be retried with the same request ID.
+        for request_id in self.inner.to_device_batcher.request_ids(request_id) {
+            let request_id = request_id.as_ref();
+
+            self.inner.verification_machine.mark_request_as_sent(request_id);
+            self.inner.key_request_machine.mark_outgoing_request_as_sent(request_id).await?;
+            self.inner.group_session_manager.mark_request_as_sent(request_id).await?;
+            self.inner.session_manager.mark_outgoing_request_as_sent(request_id);
+            self.inner.room_key_acks.mark_request_as_sent(request_id);
+            self.inner.restored_to_device_requests.write().remove(request_id);
+        }
+
+        self.inner.to_device_batcher.mark_as_sent(request_id);
+
+        self.persist_to_device_requests().await
     }
 
     /// Get a verification object for the given user id with the given flow id.
@@ -1328,6 +2508,17 @@ impl OlmMachine {
         self.inner.verification_machine.get_requests(user_id)
     }
 
+    /// Receive the verification requests which were cancelled automatically,
+    /// because they timed out, as a [`Stream`].
+    ///
+    /// Verification requests time out ten minutes after they were created.
+    /// Timed out requests get cancelled while the sync changes are processed,
+    /// see [`OlmMachine::receive_sync_changes()`], so clients don't need to
+    /// run their own timers.
+    pub fn verification_state_stream(&self) -> impl Stream<Item = VerificationRequest> {
+        self.inner.verification_machine.verification_state_stream()
+    }
+
     async fn handle_to_device_event(&self, changes: &mut Changes, event: &ToDeviceEvents) {
         use crate::types::events::ToDeviceEvents::*;
 
@@ -1350,6 +2541,117 @@ impl OlmMachine {
         }
     }
 
+    /// Record the receipt of one of our room keys, which was acknowledged by
+    /// an Olm-encrypted `m.room_key.ack` message of the given device.
+    #[instrument(skip_all, fields(?sender, ?sender_device))]
+    async fn receive_room_key_ack(
+        &self,
+        sender: &UserId,
+        sender_device: &DeviceId,
+        event: &Raw<AnyToDeviceEvent>,
+    ) -> OlmResult<()> {
+        if !self.room_key_acks_enabled() {
+            return Ok(());
+        }
+
+        let content = match event.get_field::<RoomKeyAckContent>("content") {
+            Ok(Some(content)) => content,
+            Ok(None) | Err(_) => {
+                warn!("Received an invalid room key acknowledgement");
+                return Ok(());
+            }
+        };
+
+        let own_curve25519_key = self.identity_keys().curve25519;
+        let is_own_room_key = self
+            .store()
+            .get_inbound_group_session(&content.room_id, &content.session_id)
+            .await?
+            .is_some_and(|session| session.sender_key() == own_curve25519_key);
+
+        if is_own_room_key {
+            debug!("A room key was acknowledged");
+
+            self.inner.room_key_acks.record_ack(
+                &content.room_id,
+                &content.session_id,
+                sender,
+                sender_device,
+            );
+        } else {
+            debug!(
+                room_id = ?content.room_id,
+                session_id = content.session_id,
+                "Received an acknowledgement for a room key we didn't create"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Queue up the Olm-encrypted `m.room_key.ack` acknowledgements for the
+    /// room keys we received and stored, see
+    /// [`OlmMachine::set_room_key_acks_enabled()`].
+    async fn queue_room_key_acks(
+        &self,
+        events: &[ProcessedToDeviceEvent],
+        stored: &[RoomKeyInfo],
+    ) -> OlmResult<()> {
+        let mut used_sessions = Vec::new();
+
+        for event in events {
+            let ProcessedToDeviceEvent::Decrypted { raw, encryption_info } = event else {
+                continue;
+            };
+
+            let Some((room_id, session_id)) = room_key_acks::room_key_ids(raw) else {
+                continue;
+            };
+
+            let Some(sender_device) = &encryption_info.sender_device else {
+                continue;
+            };
+
+            if !stored.iter().any(|info| info.room_id == room_id && info.session_id == session_id) {
+                continue;
+            }
+
+            if !self.inner.room_key_acks.has_capacity() {
+                warn!("Too many room key acknowledgements are waiting to be sent out");
+                break;
+            }
+
+            let Some(device) =
+                self.store().get_device(&encryption_info.sender, sender_device).await?
+            else {
+                continue;
+            };
+
+            let content = RoomKeyAckContent::new(room_id.clone(), session_id);
+
+            match device.encrypt(RoomKeyAckContent::EVENT_TYPE, content).await {
+                Ok((used_session, content)) => {
+                    used_sessions.push(used_session);
+                    self.inner.room_key_acks.queue_ack(
+                        &encryption_info.sender,
+                        sender_device,
+                        room_id,
+                        content,
+                    );
+                }
+                Err(e) => warn!("Couldn't encrypt a room key acknowledgement: {e}"),
+            }
+        }
+
+        if !used_sessions.is_empty() {
+            self.store()
+                .save_changes(Changes { sessions: used_sessions, ..Default::default() })
+                .await?;
+        }
+
+        Ok(())
+    }
+
     fn record_message_id(event: &Raw<AnyToDeviceEvent>) {
         use serde::Deserialize;
 
@@ -1448,7 +2750,13 @@ impl OlmMachine {
         // New sessions modify the account so we need to save that
         // one as well.
         match decrypted.session {
-            SessionType::New(s) | SessionType::Existing(s) => {
+            SessionType::New(s) => {
+                // The session is saved together with the changes of the sync,
+                // which persists this count as well.
+                self.store().record_statistics(|statistics| statistics.olm_sessions_created += 1);
+                changes.sessions.push(s);
+            }
+            SessionType::Existing(s) => {
                 changes.sessions.push(s);
             }
         }
@@ -1519,6 +2827,13 @@ impl OlmMachine {
     /// This will decrypt and handle to-device events returning the decrypted
     /// versions of them.
     ///
+    /// The `next_batch_token` of the sync changes is saved together with the
+    /// result of processing them. If the same batch is received again, e.g.
+    /// because the sync was retried after a crash, its to-device events are
+    /// skipped, so they don't use up one-time keys or create new Olm sessions
+    /// a second time. The device list changes and the one-time key counts are
+    /// still handled, since doing so again is harmless.
+    ///
     /// To decrypt an event from the room timeline, call [`decrypt_room_event`].
     ///
     /// # Arguments
@@ -1531,15 +2846,25 @@ impl OlmMachine {
     /// # Returns
     ///
     /// A tuple of (decrypted to-device events, updated room keys).
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(duration_ms, count))]
     pub async fn receive_sync_changes(
         &self,
         sync_changes: EncryptionSyncChanges<'_>,
     ) -> OlmResult<(Vec<ProcessedToDeviceEvent>, Vec<RoomKeyInfo>)> {
+        if self.is_frozen() {
+            return Err(CryptoStoreError::ReadOnly.into());
+        }
+
+        // The count is the number of to-device events we received.
+        let mut timer = LatencyTimer::start("receive_sync_changes");
+        timer.set_count(sync_changes.to_device_events.len());
+
         let mut store_transaction = self.inner.store.transaction().await;
+        let previous_key_count = store_transaction.account().await?.uploaded_key_count();
 
         let (events, changes) =
             self.preprocess_sync_changes(&mut store_transaction, sync_changes).await?;
+        let key_pool_health = store_transaction.account().await?.one_time_key_pool_health();
 
         // Technically save_changes also does the same work, so if it's slow we could
         // refactor this to do it only once.
@@ -1549,6 +2874,22 @@ impl OlmMachine {
         self.store().save_changes(changes).await?;
         store_transaction.commit().await?;
 
+        // Only tell the listeners about the new one-time key count once it's saved.
+        self.inner.one_time_key_pool.report(previous_key_count, key_pool_health);
+
+        if !room_key_updates.is_empty() {
+            let count = room_key_updates.len() as u64;
+            self.store().record_statistics(|statistics| statistics.megolm_sessions_stored += count);
+        }
+
+        if self.room_key_acks_enabled() {
+            self.queue_room_key_acks(&events, &room_key_updates).await?;
+        }
+
+        // The sync may have queued up verification messages, key forwards and
+        // acknowledgements, persist them right away.
+        self.persist_to_device_requests().await?;
+
         Ok((events, room_key_updates))
     }
 
@@ -1600,12 +2941,26 @@ impl OlmMachine {
             error!(error = ?e, "Error marking a tracked user as changed");
         }
 
-        for raw_event in sync_changes.to_device_events {
-            let processed_event =
-                Box::pin(self.receive_to_device_event(transaction, &mut changes, raw_event)).await;
+        if self.is_replayed_sync_batch(sync_changes.next_batch_token.as_deref()).await? {
+            // The to-device events of this batch were already processed, e.g. because
+            // the sync response is replayed after a crash. Decrypting them again would
+            // try to create new Olm sessions from one-time keys which are already used
+            // up, and unwedge the sessions which were created the first time around.
+            if !sync_changes.to_device_events.is_empty() {
+                info!(
+                    count = sync_changes.to_device_events.len(),
+                    "Skipping the to-device events of an already processed sync batch"
+                );
+            }
+        } else {
+            for raw_event in sync_changes.to_device_events {
+                let processed_event =
+                    Box::pin(self.receive_to_device_event(transaction, &mut changes, raw_event))
+                        .await;
 
-            if let Some(processed_event) = processed_event {
-                events.push(processed_event);
+                if let Some(processed_event) = processed_event {
+                    events.push(processed_event);
+                }
             }
         }
 
@@ -1621,6 +2976,17 @@ impl OlmMachine {
         Ok((events, changes))
     }
 
+    /// Whether the sync batch leading to the given next-batch token was
+    /// already processed, i.e. whether the token is the last one which was
+    /// saved by [`OlmMachine::receive_sync_changes()`].
+    async fn is_replayed_sync_batch(&self, next_batch_token: Option<&str>) -> StoreResult<bool> {
+        let Some(next_batch_token) = next_batch_token else {
+            return Ok(false);
+        };
+
+        Ok(self.store().next_batch_token().await?.as_deref() == Some(next_batch_token))
+    }
+
     /// Request a room key from our devices.
     ///
     /// This method will return a request cancellation and a new key request if
@@ -1646,6 +3012,135 @@ impl OlmMachine {
         self.inner.key_request_machine.request_key(room_id, &event).await
     }
 
+    /// Request a room key from the given devices only.
+    ///
+    /// Unlike [`OlmMachine::request_room_key()`], which sends the request to
+    /// all our devices, the request is only sent to the given devices. They
+    /// need to belong to a single user, [`OlmMachine::room_key_request_devices()`]
+    /// can be used to pick them. The session ID and the sender key of the room
+    /// key are taken from the event.
+    ///
+    /// A forwarded room key is accepted from our own devices if they are
+    /// verified, and from the devices of other users only if they created the
+    /// room key themselves.
+    ///
+    /// This method will return a request cancellation and a new key request if
+    /// the key was already requested and the request was sent out, otherwise it
+    /// will return just the key request. The request cancellation *must* be
+    /// sent out before the request is sent out.
+    ///
+    /// Returns [`MegolmError::InvalidKeyRequestRecipients`] if no devices are
+    /// given, or if they belong to more than one user.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event for which we would like to request the room key.
+    ///
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `devices` - The devices the request should be sent to.
+    pub async fn request_room_key_from(
+        &self,
+        event: &Raw<EncryptedEvent>,
+        room_id: &RoomId,
+        devices: &[Device],
+    ) -> MegolmResult<(Option<OutgoingRequest>, OutgoingRequest)> {
+        let Some(recipient) = devices.first().map(|d| d.user_id().to_owned()) else {
+            return Err(MegolmError::InvalidKeyRequestRecipients);
+        };
+
+        if devices.iter().any(|d| d.user_id() != recipient) {
+            return Err(MegolmError::InvalidKeyRequestRecipients);
+        }
+
+        let event = event.deserialize()?;
+        let device_ids = devices.iter().map(|d| d.device_id().to_owned()).collect();
+
+        self.inner
+            .key_request_machine
+            .request_key_from(room_id, &event, &recipient, device_ids)
+            .await
+    }
+
+    /// Pick the devices the room key of the given event should be requested
+    /// from, see [`OlmMachine::request_room_key_from()`].
+    ///
+    /// Our own verified devices are preferred, since they'll share the room
+    /// keys they have with us. Only if we don't have any, the device of the
+    /// sender which created the room key is picked, if we know it.
+    ///
+    /// Returns an empty list if there is no suitable device.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event for which we would like to request the room key.
+    pub async fn room_key_request_devices(
+        &self,
+        event: &Raw<EncryptedEvent>,
+    ) -> MegolmResult<Vec<Device>> {
+        let event = event.deserialize()?;
+
+        let own_devices: Vec<_> = self
+            .store()
+            .get_user_devices(self.user_id())
+            .await?
+            .devices()
+            .filter(|d| d.device_id() != self.device_id() && d.is_verified())
+            .collect();
+
+        if !own_devices.is_empty() {
+            return Ok(own_devices);
+        }
+
+        // Only Megolm v1 events tell us which device created the room key.
+        let RoomEventEncryptionScheme::MegolmV1AesSha2(content) = &event.content.scheme else {
+            return Ok(Vec::new());
+        };
+
+        let sender_device =
+            self.store().get_device_from_curve_key(&event.sender, content.sender_key).await?;
+
+        Ok(sender_device.filter(|d| !d.is_blacklisted()).into_iter().collect())
+    }
+
+    /// Get the status of our outgoing room key request for the given session.
+    ///
+    /// This can be used to tell users that we're requesting the room key from
+    /// their other devices. Returns `None` if we never requested the room key,
+    /// or if the request finished a long time ago.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `session_id` - The id that uniquely identifies the session.
+    pub async fn room_key_request_status(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> StoreResult<Option<KeyRequestStatus>> {
+        self.inner.key_request_machine.key_request_status(room_id, session_id).await
+    }
+
+    /// Cancel our outgoing room key request for the given session.
+    ///
+    /// A request cancellation will be queued up if the request was already
+    /// sent out. Returns `false` if there was no request waiting for an
+    /// answer.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room where the key is used in.
+    ///
+    /// * `session_id` - The id that uniquely identifies the session.
+    pub async fn cancel_room_key_request(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> StoreResult<bool> {
+        self.inner.key_request_machine.cancel_key_request(room_id, session_id).await
+    }
+
     /// Find whether an event decrypted via the supplied session is verified,
     /// and provide explanation of what is missing/wrong if not.
     ///
@@ -1665,25 +3160,7 @@ impl OlmMachine {
     ) -> MegolmResult<(VerificationState, Option<OwnedDeviceId>)> {
         let sender_data = self.get_or_update_sender_data(session, sender).await?;
 
-        // If the user ID in the sender data doesn't match that in the event envelope,
-        // this event is not from who it appears to be from.
-        //
-        // If `sender_data.user_id()` returns `None`, that means we don't have any
-        // information about the owner of the session (i.e. we have
-        // `SenderData::UnknownDevice`); in that case we fall through to the
-        // logic in `sender_data_to_verification_state` which will pick an appropriate
-        // `DeviceLinkProblem` for `VerificationLevel::None`.
-        let (verification_state, device_id) = match sender_data.user_id() {
-            Some(i) if i != sender => {
-                (VerificationState::Unverified(VerificationLevel::MismatchedSender), None)
-            }
-
-            Some(_) | None => {
-                sender_data_to_verification_state(sender_data, session.has_been_imported())
-            }
-        };
-
-        Ok((verification_state, device_id))
+        Ok(room_event_verification_state(sender_data, session.has_been_imported(), sender))
     }
 
     /// Get an up-to-date [`SenderData`] for the given session, suitable for
@@ -1781,7 +3258,8 @@ impl OlmMachine {
     /// # Returns
     ///
     /// A bool result saying if actual secrets were missing and have been
-    /// requested
+    /// requested. Nothing is requested if gossiping is disabled, see
+    /// [`OlmMachineFeatures::gossiping`].
     ///
     /// # Examples
     //
@@ -1796,6 +3274,11 @@ impl OlmMachine {
     /// # anyhow::Ok(()) };
     /// ```
     pub async fn query_missing_secrets_from_other_sessions(&self) -> StoreResult<bool> {
+        if !self.inner.features.gossiping {
+            debug!("Gossiping is disabled, not requesting the missing secrets");
+            return Ok(false);
+        }
+
         let identity = self.inner.user_identity.lock().await;
         let mut secrets = identity.get_missing_secrets().await;
 
@@ -1878,38 +3361,89 @@ impl OlmMachine {
         // sender key in the event is deprecated, so let's record it now.
         Span::current().record("sender_key", debug(session.sender_key()));
 
-        let result = session.decrypt(event).await;
-        match result {
-            Ok((decrypted_event, _)) => {
-                let encryption_info = self.get_encryption_info(&session, &event.sender).await?;
-
-                self.check_sender_trust_requirement(
-                    &session,
-                    &encryption_info,
-                    &decryption_settings.sender_device_trust_requirement,
-                )?;
-
-                Ok((decrypted_event, encryption_info))
-            }
-            Err(error) => Err(
-                if let MegolmError::Decryption(DecryptionError::UnknownMessageIndex(_, _)) = error {
-                    let withheld_code = self
-                        .inner
-                        .store
-                        .get_withheld_info(room_id, content.session_id())
-                        .await?
-                        .map(|e| e.content.withheld_code());
+        // The encryption info and the trust requirement are checked even if the
+        // plaintext comes from the cache, the sender's trust might have changed
+        // since the event was first decrypted.
+        let store = self.inner.store.crypto_store();
+        let ciphertext = content.ciphertext().to_bytes();
 
-                    if withheld_code.is_some() {
-                        // Partially withheld, report with a withheld code if we have one.
-                        MegolmError::MissingRoomKey(withheld_code)
-                    } else {
-                        error
+        let decrypted_event = match store.get_decrypted_event(&event.event_id, &ciphertext) {
+            Some(decrypted_event) => decrypted_event,
+            None => {
+                let (decrypted_event, message_index) = match session.decrypt(event).await {
+                    Ok(result) => result,
+                    Err(error) => {
+                        if let MegolmError::Decryption(DecryptionError::UnknownMessageIndex(..)) =
+                            error
+                        {
+                            let (withheld_code, withheld_by) =
+                                self.get_withheld_info(room_id, content.session_id()).await?;
+
+                            if withheld_code.is_some() {
+                                // Partially withheld, report with a withheld code if we have one.
+                                return Err(MegolmError::MissingRoomKey(
+                                    withheld_code,
+                                    withheld_by,
+                                ));
+                            }
+                        }
+
+                        return Err(error);
                     }
-                } else {
-                    error
-                },
-            ),
+                };
+
+                self.check_megolm_message_index(room_id, &session, message_index, event).await?;
+                store.cache_decrypted_event(&event.event_id, &ciphertext, decrypted_event.clone());
+
+                decrypted_event
+            }
+        };
+
+        let encryption_info = self.get_encryption_info(&session, &event.sender).await?;
+
+        self.check_sender_trust_requirement(
+            &session,
+            &encryption_info,
+            &decryption_settings.sender_device_trust_requirement,
+        )?;
+
+        Ok((decrypted_event, encryption_info))
+    }
+
+    /// Check that the message index used to encrypt the given event wasn't
+    /// already used by another event.
+    ///
+    /// The first event using a message index is recorded in the store, if a
+    /// different event uses the same index later on, returns
+    /// [`MegolmError::ReplayedMessage`].
+    async fn check_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session: &InboundGroupSession,
+        message_index: u32,
+        event: &EncryptedEvent,
+    ) -> MegolmResult<()> {
+        let recorded = self
+            .inner
+            .store
+            .crypto_store()
+            .record_megolm_message_index(
+                room_id,
+                session.session_id(),
+                message_index,
+                &event.event_id,
+            )
+            .await?;
+
+        match recorded {
+            Some(event_id) if event_id != event.event_id => {
+                warn!(
+                    recorded_event_id = ?event_id,
+                    "The message index was already used by another event, the event was replayed"
+                );
+                Err(MegolmError::ReplayedMessage(event_id))
+            }
+            _ => Ok(()),
         }
     }
 
@@ -2009,17 +3543,32 @@ impl OlmMachine {
         match self.store().get_inbound_group_session(room_id, session_id).await? {
             Some(session) => Ok(session),
             None => {
-                let withheld_code = self
-                    .inner
-                    .store
-                    .get_withheld_info(room_id, session_id)
-                    .await?
-                    .map(|e| e.content.withheld_code());
-                Err(MegolmError::MissingRoomKey(withheld_code))
+                let (withheld_code, withheld_by) =
+                    self.get_withheld_info(room_id, session_id).await?;
+                Err(MegolmError::MissingRoomKey(withheld_code, withheld_by))
             }
         }
     }
 
+    /// Look up whether we were told that the room key with the given session
+    /// ID was withheld from us, and if so, why and by whom.
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> MegolmResult<(Option<WithheldCode>, Option<WithheldSender>)> {
+        let Some(event) = self.inner.store.get_withheld_info(room_id, session_id).await? else {
+            return Ok((None, None));
+        };
+
+        let sender = WithheldSender {
+            device_id: event.content.from_device().map(ToOwned::to_owned),
+            user_id: event.sender,
+        };
+
+        Ok((Some(event.content.withheld_code()), Some(sender)))
+    }
+
     /// Attempt to decrypt an event from a room timeline, returning information
     /// on the failure if it fails.
     ///
@@ -2040,7 +3589,11 @@ impl OlmMachine {
         room_id: &RoomId,
         decryption_settings: &DecryptionSettings,
     ) -> Result<RoomEventDecryptionResult, CryptoStoreError> {
-        match self.decrypt_room_event_inner(raw_event, room_id, true, decryption_settings).await {
+        let result =
+            self.decrypt_room_event_inner(raw_event, room_id, true, decryption_settings).await;
+        self.record_decryption_result(raw_event, &result);
+
+        match result {
             Ok(decrypted) => Ok(RoomEventDecryptionResult::Decrypted(decrypted)),
             Err(err) => Ok(RoomEventDecryptionResult::UnableToDecrypt(megolm_error_to_utd_info(
                 raw_event, err,
@@ -2061,10 +3614,23 @@ impl OlmMachine {
         room_id: &RoomId,
         decryption_settings: &DecryptionSettings,
     ) -> MegolmResult<DecryptedRoomEvent> {
-        self.decrypt_room_event_inner(event, room_id, true, decryption_settings).await
+        let result = self.decrypt_room_event_inner(event, room_id, true, decryption_settings).await;
+        self.record_decryption_result(event, &result);
+
+        result
+    }
+
+    /// Count the result of decrypting a room event in our E2EE statistics.
+    fn record_decryption_result(
+        &self,
+        event: &Raw<EncryptedEvent>,
+        result: &MegolmResult<DecryptedRoomEvent>,
+    ) {
+        let event_id = event.get_field("event_id").ok().flatten();
+        self.store().record_decryption(event_id, result.is_ok());
     }
 
-    #[instrument(name = "decrypt_room_event", skip_all, fields(?room_id, event_id, origin_server_ts, sender, algorithm, session_id, message_index, sender_key))]
+    #[instrument(name = "decrypt_room_event", skip_all, fields(?room_id, event_id, origin_server_ts, sender, algorithm, session_id, message_index, sender_key, duration_ms))]
     async fn decrypt_room_event_inner(
         &self,
         event: &Raw<EncryptedEvent>,
@@ -2072,6 +3638,7 @@ impl OlmMachine {
         decrypt_unsigned: bool,
         decryption_settings: &DecryptionSettings,
     ) -> MegolmResult<DecryptedRoomEvent> {
+        let _timer = LatencyTimer::start("decrypt_room_event");
         let event = event.deserialize()?;
 
         Span::current()
@@ -2108,7 +3675,7 @@ impl OlmMachine {
             match e {
                 // Optimisation should we request if we received a withheld code?
                 // Maybe for some code there is no point
-                MegolmError::MissingRoomKey(_)
+                MegolmError::MissingRoomKey(..)
                 | MegolmError::Decryption(DecryptionError::UnknownMessageIndex(_, _)) => {
                     self.inner
                         .key_request_machine
@@ -2265,6 +3832,73 @@ impl OlmMachine {
             .is_some())
     }
 
+    /// Get the information about the crypto state of this device which
+    /// [`UtdCause::determine`] needs.
+    ///
+    /// We can't ask the server whether a key backup exists from here, so the
+    /// backup is considered to exist on the server if we know its version.
+    pub async fn crypto_context_info(&self) -> StoreResult<CryptoContextInfo> {
+        let this_device_is_verified = self
+            .get_device(self.user_id(), self.device_id(), None)
+            .await?
+            .is_some_and(|device| device.is_cross_signing_trusted());
+
+        let backup_version = match self.backup_machine().backup_version().await {
+            Some(version) => Some(version),
+            None => self.store().load_backup_keys().await?.backup_version,
+        };
+
+        Ok(CryptoContextInfo {
+            device_creation_ts: self.device_creation_time(),
+            this_device_is_verified,
+            backup_exists_on_server: backup_version.is_some(),
+            is_backup_configured: self.backup_machine().enabled().await,
+        })
+    }
+
+    /// Work out why the given event couldn't be decrypted, for reporting it
+    /// to the user or as part of the telemetry of a client.
+    ///
+    /// This uses [`UtdCause::determine_with_store_info`], with the state of
+    /// this device given by [`OlmMachine::crypto_context_info`], and what the
+    /// store knows about the room key of the event by now: whether it was
+    /// received since, or withheld by its sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `raw_event` - The event which couldn't be decrypted.
+    /// * `room_id` - The ID of the room where the event was sent to.
+    /// * `unable_to_decrypt_info` - Why the decryption failed, as returned by
+    ///   [`OlmMachine::try_decrypt_room_event`].
+    pub async fn determine_utd_cause(
+        &self,
+        raw_event: &Raw<AnySyncTimelineEvent>,
+        room_id: &RoomId,
+        unable_to_decrypt_info: &UnableToDecryptInfo,
+    ) -> StoreResult<UtdCause> {
+        let crypto_context_info = self.crypto_context_info().await?;
+
+        let mut store_info =
+            UtdStoreInfo { session_is_known: false, withheld_code: None, now: self.clock().now() };
+
+        if let Some(session_id) = &unable_to_decrypt_info.session_id {
+            store_info.session_is_known =
+                self.store().get_inbound_group_session(room_id, session_id).await?.is_some();
+            store_info.withheld_code = self
+                .store()
+                .get_withheld_info(room_id, session_id)
+                .await?
+                .map(|event| event.content.withheld_code());
+        }
+
+        Ok(UtdCause::determine_with_store_info(
+            raw_event,
+            crypto_context_info,
+            &store_info,
+            unable_to_decrypt_info,
+        ))
+    }
+
     /// Get encryption info for a decrypted timeline event.
     ///
     /// This recalculates the [`EncryptionInfo`] data that is returned by
@@ -2326,6 +3960,83 @@ impl OlmMachine {
         self.get_encryption_info(&session, sender).await
     }
 
+    /// Explain how the trust in the sender of a room event was derived.
+    ///
+    /// The returned [`SenderTrustExplanation`] describes every link of the
+    /// chain between the room key that was used to encrypt the event and the
+    /// sender's identity: the device which sent us the room key, whether that
+    /// device is signed by the sender's self-signing key, and whether the
+    /// sender's master key is verified. Clients can use it to explain why a
+    /// message is, or isn't, trusted.
+    ///
+    /// Like [`OlmMachine::get_room_event_encryption_info()`], this is based
+    /// on the current verification status of the sender. Returns an error if
+    /// the room key can't be found.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to explain the sender trust of.
+    /// * `room_id` - The ID of the room where the event was sent to.
+    #[instrument(skip(self, event), fields(event_id, sender, session_id))]
+    pub async fn explain_sender_trust(
+        &self,
+        event: &Raw<EncryptedEvent>,
+        room_id: &RoomId,
+    ) -> MegolmResult<SenderTrustExplanation> {
+        let event = event.deserialize()?;
+
+        let content: SupportedEventEncryptionSchemes<'_> = match &event.content.scheme {
+            RoomEventEncryptionScheme::MegolmV1AesSha2(c) => c.into(),
+            #[cfg(feature = "experimental-algorithms")]
+            RoomEventEncryptionScheme::MegolmV2AesSha2(c) => c.into(),
+            RoomEventEncryptionScheme::Unknown(_) => {
+                return Err(EventError::UnsupportedAlgorithm.into());
+            }
+        };
+
+        Span::current()
+            .record("sender", debug(&event.sender))
+            .record("event_id", debug(&event.event_id))
+            .record("session_id", content.session_id());
+
+        let session =
+            self.get_inbound_group_session_or_error(room_id, content.session_id()).await?;
+        let sender_data = self.get_or_update_sender_data(&session, &event.sender).await?;
+
+        // Only the sender data of devices which aren't cross-signed contains the
+        // device keys, otherwise look the device up to find its Ed25519 key.
+        let device_key = match &sender_data {
+            SenderData::UnknownDevice { .. } => None,
+            SenderData::DeviceInfo { device_keys, .. } => device_keys.ed25519_key(),
+            SenderData::VerificationViolation(known)
+            | SenderData::SenderUnverified(known)
+            | SenderData::SenderVerified(known) => match &known.device_id {
+                Some(device_id) => self
+                    .inner
+                    .store
+                    .get_device_data(&known.user_id, device_id)
+                    .await?
+                    .and_then(|d| d.ed25519_key()),
+                None => None,
+            },
+        };
+
+        let (verification_state, _) = room_event_verification_state(
+            sender_data.clone(),
+            session.has_been_imported(),
+            &event.sender,
+        );
+
+        Ok(SenderTrustExplanation::new(
+            &event.sender,
+            &sender_data,
+            session.has_been_imported(),
+            session.sender_key(),
+            device_key,
+            verification_state,
+        ))
+    }
+
     /// Update the list of tracked users.
     ///
     /// The OlmMachine maintains a list of users whose devices we are keeping
@@ -2361,6 +4072,26 @@ impl OlmMachine {
             .await
     }
 
+    /// Flag the given user as needing a `/keys/query` with the given priority.
+    ///
+    /// The users with a higher priority are part of the first `/keys/query`
+    /// requests returned by [`OlmMachine::outgoing_requests()`], so that e.g.
+    /// the members of the room a message is being sent to aren't starved by
+    /// device list changes in background rooms. The users for which a task is
+    /// waiting for a `/keys/query` to complete are always treated as
+    /// [`KeyQueryPriority::BlockingSend`].
+    ///
+    /// The user is added to the list of tracked users if needed. The priority
+    /// can only be raised, it's forgotten once the device list of the user is
+    /// up-to-date again.
+    pub async fn mark_user_as_changed_with_priority(
+        &self,
+        user_id: &UserId,
+        priority: KeyQueryPriority,
+    ) -> StoreResult<()> {
+        self.inner.identity_manager.mark_user_as_changed_with_priority(user_id, priority).await
+    }
+
     async fn wait_if_user_pending(
         &self,
         user_id: &UserId,
@@ -2516,6 +4247,24 @@ impl OlmMachine {
         self.store().import_cross_signing_keys(export).await
     }
 
+    /// Use the given [`SigningBackend`]s, e.g. a hardware security module or
+    /// an OS keystore, for the private parts of our cross signing keys.
+    ///
+    /// Only signature requests are passed to the backends, the private keys
+    /// never need to leave them. The public keys of the backends need to match
+    /// the public keys that were uploaded to the server.
+    ///
+    /// Keys held by a backend aren't persisted in the crypto store, so this
+    /// needs to be called every time the `OlmMachine` is created.
+    ///
+    /// [`SigningBackend`]: crate::olm::SigningBackend
+    pub async fn import_cross_signing_backends(
+        &self,
+        backends: CrossSigningBackends,
+    ) -> Result<CrossSigningStatus, SecretImportError> {
+        self.store().import_cross_signing_backends(backends).await
+    }
+
     async fn sign_with_master_key(
         &self,
         message: &str,
@@ -2556,6 +4305,122 @@ impl OlmMachine {
         Ok(signatures)
     }
 
+    /// Sign a JSON object of the given type using our device key and if
+    /// available cross signing master key.
+    ///
+    /// The content isn't signed as it is, it's wrapped in an object together
+    /// with the type of the payload:
+    ///
+    /// ```json
+    /// {
+    ///     "type": "org.example.payload",
+    ///     "content": { ... },
+    ///     "signatures": { ... }
+    /// }
+    /// ```
+    ///
+    /// This way the signatures can't be used for any of the objects the spec
+    /// defines, like device keys or cross signing keys. The type needs to be
+    /// namespaced using the Java package naming convention, types starting
+    /// with `m.` are rejected.
+    ///
+    /// The object is canonicalized as described in the [spec] before it's
+    /// signed. Returns the signed JSON object, which can be checked using
+    /// [`OlmMachine::verify_json()`].
+    ///
+    /// [spec]: https://spec.matrix.org/unstable/appendices/#signing-json
+    pub async fn sign_json(
+        &self,
+        payload_type: &str,
+        content: JsonObject,
+    ) -> Result<Value, SignatureError> {
+        check_signed_payload_type(payload_type)?;
+
+        let mut value = json!({ "type": payload_type, "content": content });
+        let canonical_json = to_signable_json(value.clone())?;
+
+        let mut signatures = Signatures::new();
+
+        for (user_id, user_signatures) in self.sign(&canonical_json).await? {
+            for (key_id, signature) in user_signatures {
+                if let Ok(Signature::Ed25519(signature)) = signature {
+                    signatures.add_signature(user_id.clone(), key_id, signature);
+                }
+            }
+        }
+
+        value["signatures"] = serde_json::to_value(signatures)?;
+
+        Ok(value)
+    }
+
+    /// Check that a JSON object signed using [`OlmMachine::sign_json()`] has
+    /// the given type and carries a valid signature from the given signer.
+    ///
+    /// The public key of the signer is looked up in the store, the devices or
+    /// the cross signing identity of the user need to be known, i.e. a
+    /// `/keys/query` request for the user needs to have been made.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that claims to have signed the object.
+    ///
+    /// * `signer` - The key of the user that should have signed the object.
+    ///
+    /// * `payload_type` - The type the signed object should have.
+    ///
+    /// * `value` - The signed JSON object.
+    ///
+    /// Returns an empty result if the signature check succeeded, otherwise a
+    /// [`SignatureError`] indicating why the check failed.
+    pub async fn verify_json(
+        &self,
+        user_id: &UserId,
+        signer: JsonSigner,
+        payload_type: &str,
+        value: &Value,
+    ) -> Result<(), SignatureError> {
+        check_signed_payload_type(payload_type)?;
+
+        let object = value.as_object().ok_or(SignatureError::NotAnObject)?;
+
+        if object.get("type").and_then(Value::as_str) != Some(payload_type) {
+            return Err(SignatureError::InvalidPayloadType(payload_type.to_owned()));
+        }
+
+        let signatures =
+            object.get("signatures").cloned().ok_or(SignatureError::NoSignatureFound)?;
+        let signatures: Signatures = serde_json::from_value(signatures)?;
+        let canonical_json = to_signable_json(value.clone())?;
+
+        match signer {
+            JsonSigner::Device(device_id) => {
+                let device = self
+                    .store()
+                    .get_device(user_id, &device_id)
+                    .await?
+                    .ok_or(SignatureError::MissingSigningKey)?;
+                let key = device.ed25519_key().ok_or(SignatureError::MissingSigningKey)?;
+                let key_id = DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, &device_id);
+
+                key.verify_canonicalized_json(user_id, &key_id, &signatures, &canonical_json)
+            }
+            JsonSigner::MasterKey => {
+                let identity = self
+                    .store()
+                    .get_identity(user_id)
+                    .await?
+                    .ok_or(SignatureError::MissingSigningKey)?;
+                let master_key = match &identity {
+                    UserIdentity::Own(identity) => identity.master_key(),
+                    UserIdentity::Other(identity) => identity.master_key(),
+                };
+
+                master_key.has_signed_raw(&signatures, &canonical_json)
+            }
+        }
+    }
+
     /// Get a reference to the backup related state machine.
     ///
     /// This state machine can be used to incrementally backup all room keys to
@@ -2575,27 +4440,20 @@ impl OlmMachine {
         // scope.
         let mut gen_guard = generation.lock().await;
 
-        let prev_generation =
-            self.inner.store.get_custom_value(Self::CURRENT_GENERATION_STORE_KEY).await?;
+        let store = self.inner.store.crypto_store();
+        let prev_generation = store.load_generation().await?;
 
         let gen = match prev_generation {
-            Some(val) => {
-                // There was a value in the store. We need to signal that we're a different
-                // process, so we don't just reuse the value but increment it.
-                u64::from_le_bytes(val.try_into().map_err(|_| {
-                    CryptoStoreError::InvalidLockGeneration("invalid format".to_owned())
-                })?)
-                .wrapping_add(1)
-            }
+            // There was a value in the store. We need to signal that we're a different
+            // process, so we don't just reuse the value but increment it.
+            Some(val) => val.wrapping_add(1),
             None => 0,
         };
 
         tracing::debug!("Initialising crypto store generation at {}", gen);
 
-        self.inner
-            .store
-            .set_custom_value(Self::CURRENT_GENERATION_STORE_KEY, gen.to_le_bytes().to_vec())
-            .await?;
+        store.set_custom_value(CURRENT_GENERATION_STORE_KEY, gen.to_le_bytes().to_vec()).await?;
+        store.set_known_generation(gen);
 
         *gen_guard = Some(gen);
 
@@ -2637,20 +4495,11 @@ impl OlmMachine {
         // - or we couldn't, and then another process was holding onto the database's
         //   lock, thus
         // has written a generation counter in there.
-        let actual_gen = self
-            .inner
-            .store
-            .get_custom_value(Self::CURRENT_GENERATION_STORE_KEY)
-            .await?
-            .ok_or_else(|| {
+        let actual_gen =
+            self.inner.store.crypto_store().load_generation().await?.ok_or_else(|| {
                 CryptoStoreError::InvalidLockGeneration("counter missing in store".to_owned())
             })?;
 
-        let actual_gen =
-            u64::from_le_bytes(actual_gen.try_into().map_err(|_| {
-                CryptoStoreError::InvalidLockGeneration("invalid format".to_owned())
-            })?);
-
         let new_gen = match gen_guard.as_ref() {
             Some(expected_gen) => {
                 if actual_gen == *expected_gen {
@@ -2676,10 +4525,12 @@ impl OlmMachine {
         // Update known value.
         *gen_guard = Some(new_gen);
 
-        // Update value in database.
+        // Update value in database. Note that we don't tell the store about the new
+        // value on purpose: the store notices that the generation changed, and
+        // reloads its caches the next time they are accessed.
         self.inner
             .store
-            .set_custom_value(Self::CURRENT_GENERATION_STORE_KEY, new_gen.to_le_bytes().to_vec())
+            .set_custom_value(CURRENT_GENERATION_STORE_KEY, new_gen.to_le_bytes().to_vec())
             .await?;
 
         Ok((true, new_gen))
@@ -2710,6 +4561,9 @@ impl OlmMachine {
     /// If the settings are valid, they will be persisted to the crypto store.
     /// These settings are not used directly by this library, but the saved
     /// settings can be retrieved via [`OlmMachine::room_settings`].
+    ///
+    /// The [`RoomSettings::sharing_strategy`] of existing settings is kept, it
+    /// can only be changed using [`OlmMachine::set_room_sharing_strategy()`].
     pub async fn set_room_settings(
         &self,
         room_id: &RoomId,
@@ -2738,7 +4592,14 @@ impl OlmMachine {
         //
         // [E2EE implementation guide]: https://matrix.org/docs/matrix-concepts/end-to-end-encryption/#handling-an-m-room-encryption-state-event
         if let Some(old_settings) = old_settings {
-            if old_settings != *new_settings {
+            // The sharing strategy isn't part of the `m.room.encryption` event, it's
+            // configured using `set_room_sharing_strategy()` and kept as it is.
+            let new_settings = RoomSettings {
+                sharing_strategy: old_settings.sharing_strategy.clone(),
+                ..new_settings.clone()
+            };
+
+            if old_settings != new_settings {
                 return Err(SetRoomSettingsError::EncryptionDowngrade);
             } else {
                 // nothing to do here
@@ -2773,6 +4634,115 @@ impl OlmMachine {
         Ok(())
     }
 
+    /// Check whether room keys for the given room are only shared with trusted
+    /// devices.
+    ///
+    /// This is the case if the flag has been set for this room using
+    /// [`OlmMachine::set_room_only_allow_trusted_devices()`], or for all rooms
+    /// using [`Store::set_only_allow_trusted_devices()`].
+    pub async fn room_only_allow_trusted_devices(&self, room_id: &RoomId) -> StoreResult<bool> {
+        let store = &self.inner.store;
+
+        Ok(store.get_only_allow_trusted_devices().await?
+            || store.get_room_only_allow_trusted_devices(room_id).await?)
+    }
+
+    /// Set a flag whether room keys for the given room should only be shared
+    /// with trusted devices, or whether untrusted devices should receive them
+    /// as well.
+    ///
+    /// If the flag is set, [`OlmMachine::share_room_key()`] uses the
+    /// [`CollectStrategy::OnlyTrustedDevices`] strategy for this room,
+    /// regardless of the given [`EncryptionSettings`]. The flag for all rooms,
+    /// set using [`Store::set_only_allow_trusted_devices()`], takes precedence.
+    pub async fn set_room_only_allow_trusted_devices(
+        &self,
+        room_id: &RoomId,
+        only_allow_trusted_devices: bool,
+    ) -> StoreResult<()> {
+        self.inner
+            .store
+            .set_room_only_allow_trusted_devices(room_id, only_allow_trusted_devices)
+            .await
+    }
+
+    /// Get the [`CollectStrategy`] set for the given room using
+    /// [`OlmMachine::set_room_sharing_strategy()`], if any.
+    pub async fn room_sharing_strategy(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<Option<CollectStrategy>> {
+        self.inner.store.get_room_sharing_strategy(room_id).await
+    }
+
+    /// Set the [`CollectStrategy`] which should be used to share room keys in
+    /// the given room, or remove it by passing `None`.
+    ///
+    /// [`OlmMachine::share_room_key()`] uses this strategy for the room instead
+    /// of the one of the [`EncryptionSettings`] it was given. For example, the
+    /// room can be configured to share its room keys with the user identities
+    /// of its members, using [`CollectStrategy::IdentityBasedStrategy`], while
+    /// other rooms are shared with all devices. The flag set using
+    /// [`OlmMachine::set_room_only_allow_trusted_devices()`] takes precedence.
+    ///
+    /// The strategy is stored as the [`RoomSettings::sharing_strategy`].
+    pub async fn set_room_sharing_strategy(
+        &self,
+        room_id: &RoomId,
+        strategy: Option<CollectStrategy>,
+    ) -> StoreResult<()> {
+        self.inner.store.set_room_sharing_strategy(room_id, strategy).await
+    }
+
+    /// Get the rotation policy overrides for the given room.
+    ///
+    /// These can be modified via [`OlmMachine::set_room_rotation_policy()`].
+    pub async fn room_rotation_policy(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<Option<RoomRotationPolicy>> {
+        self.inner.store.get_room_rotation_policy(room_id).await
+    }
+
+    /// Override when the outbound group session of the given room gets
+    /// rotated.
+    ///
+    /// [`OlmMachine::share_room_key()`] uses the given rotation period and
+    /// message count instead of the ones of the [`EncryptionSettings`] it was
+    /// given, and rotates the current session if it was created with different
+    /// criteria. Passing `None` for both removes the overrides for the room,
+    /// in which case the current session is discarded, so the next one follows
+    /// the criteria of the [`EncryptionSettings`] again.
+    ///
+    /// # Arguments
+    ///
+    /// `room_id` - The room id of the room the policy applies to.
+    ///
+    /// `rotation_period` - How long a session should be used before rotating
+    /// it.
+    ///
+    /// `rotation_period_msgs` - How many messages should be encrypted with a
+    /// session before rotating it.
+    pub async fn set_room_rotation_policy(
+        &self,
+        room_id: &RoomId,
+        rotation_period: Option<Duration>,
+        rotation_period_msgs: Option<u64>,
+    ) -> StoreResult<()> {
+        let had_policy = self.inner.store.get_room_rotation_policy(room_id).await?.is_some();
+
+        self.inner
+            .store
+            .set_room_rotation_policy(room_id, rotation_period, rotation_period_msgs)
+            .await?;
+
+        if had_policy && rotation_period.is_none() && rotation_period_msgs.is_none() {
+            self.discard_room_key(room_id).await?;
+        }
+
+        Ok(())
+    }
+
     /// Returns whether this `OlmMachine` is the same another one.
     ///
     /// Useful for testing purposes only.
@@ -2802,6 +4772,30 @@ impl OlmMachine {
     }
 }
 
+/// Determine the verification state of a room event sent by `sender`, which
+/// was decrypted using a Megolm session with the given [`SenderData`].
+fn room_event_verification_state(
+    sender_data: SenderData,
+    session_has_been_imported: bool,
+    sender: &UserId,
+) -> (VerificationState, Option<OwnedDeviceId>) {
+    // If the user ID in the sender data doesn't match that in the event envelope,
+    // this event is not from who it appears to be from.
+    //
+    // If `sender_data.user_id()` returns `None`, that means we don't have any
+    // information about the owner of the session (i.e. we have
+    // `SenderData::UnknownDevice`); in that case we fall through to the
+    // logic in `sender_data_to_verification_state` which will pick an appropriate
+    // `DeviceLinkProblem` for `VerificationLevel::None`.
+    match sender_data.user_id() {
+        Some(i) if i != sender => {
+            (VerificationState::Unverified(VerificationLevel::MismatchedSender), None)
+        }
+
+        Some(_) | None => sender_data_to_verification_state(sender_data, session_has_been_imported),
+    }
+}
+
 fn sender_data_to_verification_state(
     sender_data: SenderData,
     session_has_been_imported: bool,
@@ -2862,6 +4856,139 @@ pub struct CrossSigningBootstrapRequests {
     pub upload_signatures_req: UploadSignaturesRequest,
 }
 
+/// The steps needed to fully set up cross signing and key backup for our
+/// device, as returned by [`OlmMachine::cross_signing_bootstrap_plan()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CrossSigningBootstrapPlan {
+    /// We don't have any private cross signing keys, new ones need to be
+    /// created using [`OlmMachine::bootstrap_cross_signing()`].
+    pub create_cross_signing_keys: bool,
+
+    /// The public cross signing keys haven't been uploaded to the server yet.
+    pub upload_cross_signing_keys: bool,
+
+    /// The keys of our device haven't been uploaded to the server yet.
+    pub upload_device_keys: bool,
+
+    /// Our device hasn't been signed by our self-signing key yet, other users
+    /// and devices won't trust it.
+    pub sign_own_device: bool,
+
+    /// No key backup is enabled, room keys aren't backed up to the server.
+    pub enable_backup: bool,
+}
+
+impl CrossSigningBootstrapPlan {
+    /// Is everything set up already, i.e. are no steps needed?
+    pub fn is_complete(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A reset of our cross signing identity, started using
+/// [`OlmMachine::reset_cross_signing()`], which is waiting for the new public
+/// keys to be uploaded.
+///
+/// The [`requests()`] should be sent out in the same order as the ones of
+/// [`OlmMachine::bootstrap_cross_signing()`]. Once the request to upload the
+/// cross signing keys succeeded, possibly after the user completed the user
+/// interactive auth, the reset needs to be completed using [`finish()`] before
+/// the signatures are uploaded.
+///
+/// [`requests()`]: Self::requests
+/// [`finish()`]: Self::finish
+#[derive(Debug, Clone)]
+pub struct CrossSigningReset {
+    machine: OlmMachine,
+    identity: PrivateCrossSigningIdentity,
+    requests: CrossSigningBootstrapRequests,
+}
+
+impl CrossSigningReset {
+    /// The requests which need to be sent out to upload the new identity.
+    pub fn requests(&self) -> &CrossSigningBootstrapRequests {
+        &self.requests
+    }
+
+    /// Replace our cross signing identity with the new one, now that its public
+    /// keys have been uploaded.
+    pub async fn finish(self) -> Result<(), SignatureError> {
+        let public = self.identity.to_public_identity().await?;
+
+        self.identity.mark_as_shared();
+        *self.machine.inner.user_identity.lock().await = self.identity.clone();
+
+        self.machine
+            .store()
+            .save_changes(Changes {
+                identities: IdentityChanges { new: vec![public.into()], ..Default::default() },
+                private_identity: Some(self.identity),
+                ..Default::default()
+            })
+            .await?;
+
+        self.machine.store().remove_custom_value(OlmMachine::PENDING_CROSS_SIGNING_RESET).await?;
+
+        info!("Finished the cross signing reset");
+
+        Ok(())
+    }
+
+    /// Give up on the reset, keeping our current cross signing identity.
+    pub async fn cancel(self) -> StoreResult<()> {
+        self.machine.store().remove_custom_value(OlmMachine::PENDING_CROSS_SIGNING_RESET).await?;
+
+        info!("Cancelled the cross signing reset");
+
+        Ok(())
+    }
+}
+
+/// Check that the type of a payload signed using [`OlmMachine::sign_json()`] is
+/// namespaced, e.g. `org.example.payload`, and isn't reserved for the spec.
+fn check_signed_payload_type(payload_type: &str) -> Result<(), SignatureError> {
+    let namespaced =
+        payload_type.contains('.') && payload_type.split('.').all(|part| !part.is_empty());
+
+    if namespaced && !payload_type.starts_with("m.") {
+        Ok(())
+    } else {
+        Err(SignatureError::InvalidPayloadType(payload_type.to_owned()))
+    }
+}
+
+/// The key that is expected to have signed a JSON object, used by
+/// [`OlmMachine::verify_json()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonSigner {
+    /// The Ed25519 key of the device with the given ID.
+    Device(OwnedDeviceId),
+    /// The cross signing master key of the user.
+    MasterKey,
+}
+
+/// A handler for decrypted to-device events of a custom type.
+///
+/// The handler can be registered using
+/// [`OlmMachine::register_custom_to_device_event_handler()`].
+///
+/// The handler is called inline while the to-device events of a sync are
+/// processed, so it should return quickly, e.g. by handing the event over to
+/// another task.
+pub trait CustomToDeviceEventHandler: std::fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// Called when an encrypted to-device event of the registered type was
+    /// decrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The decrypted to-device event.
+    ///
+    /// * `encryption_info` - Information about the sender of the event. The
+    ///   sender and their device should only be trusted if the
+    ///   `verification_state` is `Verified`.
+    fn handle_event(&self, event: &Raw<AnyToDeviceEvent>, encryption_info: &EncryptionInfo);
+}
+
 /// Data contained from a sync response and that needs to be processed by the
 /// OlmMachine.
 #[derive(Debug)]
@@ -2894,8 +5021,8 @@ fn megolm_error_to_utd_info(
     let reason = match error {
         EventError(_) => UnableToDecryptReason::MalformedEncryptedEvent,
         Decode(_) => UnableToDecryptReason::MalformedEncryptedEvent,
-        MissingRoomKey(maybe_withheld) => {
-            UnableToDecryptReason::MissingMegolmSession { withheld_code: maybe_withheld }
+        MissingRoomKey(withheld_code, withheld_by) => {
+            UnableToDecryptReason::MissingMegolmSession { withheld_code, withheld_by }
         }
         Decryption(DecryptionError::UnknownMessageIndex(_, _)) => {
             UnableToDecryptReason::UnknownMegolmMessageIndex
@@ -2904,6 +5031,7 @@ fn megolm_error_to_utd_info(
         JsonError(_) => UnableToDecryptReason::PayloadDeserializationFailure,
         MismatchedIdentityKeys(_) => UnableToDecryptReason::MismatchedIdentityKeys,
         SenderIdentityNotTrusted(level) => UnableToDecryptReason::SenderIdentityNotTrusted(level),
+        ReplayedMessage(_) => UnableToDecryptReason::ReplayedMegolmMessage,
 
         // Pass through crypto store errors, which indicate a problem with our
         // application, rather than a UTD.
@@ -2952,6 +5080,11 @@ impl From<DecryptToDeviceError> for OlmError {
     }
 }
 
+mod builder;
+mod one_time_key_pool;
+mod room_key_acks;
+mod to_device_batcher;
+
 #[cfg(test)]
 pub(crate) mod test_helpers;
 
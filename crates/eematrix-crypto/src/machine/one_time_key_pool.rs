@@ -0,0 +1,89 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Monitoring of the number of one-time keys the server holds, see
+//! [`OlmMachine::one_time_key_pool_stream()`].
+//!
+//! [`OlmMachine::one_time_key_pool_stream()`]: crate::OlmMachine::one_time_key_pool_stream
+
+use std::{
+    future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures_core::Stream;
+use futures_util::StreamExt;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{debug, warn};
+
+use crate::olm::OneTimeKeyPoolHealth;
+
+/// Notifies listeners when the server-side one-time key count drops below the
+/// low watermark.
+#[derive(Debug)]
+pub(crate) struct OneTimeKeyPoolMonitor {
+    /// The count below which listeners get notified, zero disables the
+    /// notifications.
+    low_watermark: AtomicU64,
+    sender: broadcast::Sender<OneTimeKeyPoolHealth>,
+}
+
+impl Default for OneTimeKeyPoolMonitor {
+    fn default() -> Self {
+        Self { low_watermark: AtomicU64::new(0), sender: broadcast::Sender::new(10) }
+    }
+}
+
+impl OneTimeKeyPoolMonitor {
+    pub fn set_low_watermark(&self, low_watermark: u64) {
+        self.low_watermark.store(low_watermark, Ordering::SeqCst);
+    }
+
+    pub fn low_watermark(&self) -> u64 {
+        self.low_watermark.load(Ordering::SeqCst)
+    }
+
+    /// Notify the listeners if the server-side count dropped below the low
+    /// watermark, now that the server reported a new count.
+    ///
+    /// # Arguments
+    ///
+    /// * `previous_count` - The server-side count before the server reported
+    ///   the new one.
+    ///
+    /// * `health` - The state of the pool after the new count was reported.
+    pub fn report(&self, previous_count: u64, health: OneTimeKeyPoolHealth) {
+        let count = health.remaining_server_side;
+
+        if count < previous_count && count < self.low_watermark() {
+            debug!(previous_count, count, "The one-time key count dropped below the low watermark");
+
+            // Ignore the result. It can only fail if there are no listeners.
+            let _ = self.sender.send(health);
+        }
+    }
+
+    pub fn stream(&self) -> impl Stream<Item = OneTimeKeyPoolHealth> {
+        BroadcastStream::new(self.sender.subscribe()).filter_map(|result| {
+            future::ready(match result {
+                Ok(health) => Some(health),
+                Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                    warn!("one_time_key_pool_stream missed {lag} updates");
+                    None
+                }
+            })
+        })
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The experimental `m.room_key.ack` acknowledgements of received room keys,
+//! see [`OlmMachine::set_room_key_acks_enabled()`].
+//!
+//! [`OlmMachine::set_room_key_acks_enabled()`]: crate::OlmMachine::set_room_key_acks_enabled
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use matrix_sdk_common::locks::RwLock as StdRwLock;
+use ruma::{
+    events::AnyToDeviceEvent, serde::Raw, DeviceId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId,
+    OwnedUserId, RoomId, TransactionId, UserId,
+};
+use serde::Deserialize;
+use tracing::trace;
+
+use crate::types::{
+    events::{room::encrypted::ToDeviceEncryptedEventContent, room_key::RoomKeyContent, EventType},
+    requests::{OutgoingRequest, ToDeviceRequest},
+};
+
+/// The maximum number of acknowledgements which wait to be sent out, further
+/// room keys aren't acknowledged until some of them were sent.
+const MAX_OUTGOING_ACKS: usize = 1000;
+
+/// The maximum number of our own room keys whose acknowledgements are
+/// remembered, the acknowledgements of the oldest room keys are forgotten
+/// first.
+const MAX_ACKNOWLEDGED_SESSIONS: usize = 100;
+
+/// The devices which acknowledged the receipt of a room key, by user.
+pub(crate) type Acknowledgements = BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>;
+
+/// Keeps track of the `m.room_key.ack` messages we need to send out, and of
+/// the ones we received for our own room keys.
+#[derive(Debug, Default)]
+pub(crate) struct RoomKeyAcks {
+    /// Whether room keys are acknowledged, and acknowledgements are tracked.
+    enabled: AtomicBool,
+    /// The acknowledgements which haven't been sent out yet, with the room of
+    /// the acknowledged room key.
+    outgoing_requests: StdRwLock<BTreeMap<OwnedTransactionId, (OwnedRoomId, OutgoingRequest)>>,
+    /// The acknowledgements we received for our own room keys.
+    received: StdRwLock<ReceivedAcks>,
+}
+
+/// The acknowledgements we received, by room and session ID, see
+/// [`RoomKeyAcks::record_ack()`].
+#[derive(Debug, Default)]
+struct ReceivedAcks {
+    sessions: BTreeMap<(OwnedRoomId, String), Acknowledgements>,
+    /// The keys of `sessions`, oldest first.
+    order: VecDeque<(OwnedRoomId, String)>,
+}
+
+impl RoomKeyAcks {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Can another acknowledgement be queued up, or are too many of them
+    /// waiting to be sent out already?
+    pub fn has_capacity(&self) -> bool {
+        self.outgoing_requests.read().len() < MAX_OUTGOING_ACKS
+    }
+
+    /// Queue up the encrypted acknowledgement of a room key of the given room,
+    /// for the device which sent it to us.
+    pub fn queue_ack(
+        &self,
+        recipient: &UserId,
+        recipient_device: &DeviceId,
+        room_id: OwnedRoomId,
+        content: Raw<ToDeviceEncryptedEventContent>,
+    ) {
+        let event_type = content.event_type().to_owned();
+        let request = ToDeviceRequest::new(
+            recipient,
+            recipient_device.to_owned(),
+            &event_type,
+            content.cast(),
+        );
+
+        let request_id = request.txn_id.clone();
+
+        trace!(?request_id, ?recipient, ?recipient_device, "Queued up a room key acknowledgement");
+
+        let request =
+            OutgoingRequest { request_id: request_id.clone(), request: Arc::new(request.into()) };
+        self.outgoing_requests.write().insert(request_id, (room_id, request));
+    }
+
+    pub fn outgoing_requests(&self) -> Vec<OutgoingRequest> {
+        self.outgoing_requests.read().values().map(|(_, request)| request.clone()).collect()
+    }
+
+    pub fn mark_request_as_sent(&self, request_id: &TransactionId) {
+        self.outgoing_requests.write().remove(request_id);
+    }
+
+    /// Record that the given device acknowledged the receipt of one of our
+    /// room keys.
+    pub fn record_ack(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) {
+        let mut received = self.received.write();
+        let key = (room_id.to_owned(), session_id.to_owned());
+
+        if !received.sessions.contains_key(&key) {
+            if received.order.len() >= MAX_ACKNOWLEDGED_SESSIONS {
+                if let Some(oldest) = received.order.pop_front() {
+                    received.sessions.remove(&oldest);
+                }
+            }
+
+            received.order.push_back(key.clone());
+        }
+
+        received
+            .sessions
+            .entry(key)
+            .or_default()
+            .entry(user_id.to_owned())
+            .or_default()
+            .insert(device_id.to_owned());
+    }
+
+    /// Get the devices which acknowledged the receipt of the given room key.
+    pub fn acknowledgements(&self, room_id: &RoomId, session_id: &str) -> Acknowledgements {
+        self.received
+            .read()
+            .sessions
+            .get(&(room_id.to_owned(), session_id.to_owned()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Drop all the acknowledgements which haven't been sent out yet.
+    pub fn clear(&self) {
+        self.outgoing_requests.write().clear();
+    }
+
+    /// Drop the acknowledgements of the room keys of the given room, the ones
+    /// which haven't been sent out yet as well as the received ones.
+    pub fn remove_room(&self, room_id: &RoomId) {
+        self.outgoing_requests.write().retain(|_, (ack_room_id, _)| ack_room_id != room_id);
+
+        let mut received = self.received.write();
+        received.sessions.retain(|(ack_room_id, _), _| ack_room_id != room_id);
+        received.order.retain(|(ack_room_id, _)| ack_room_id != room_id);
+    }
+}
+
+/// The room ID and the session ID of a received `m.room_key` event, if the
+/// given event is one.
+pub(crate) fn room_key_ids(event: &Raw<AnyToDeviceEvent>) -> Option<(OwnedRoomId, String)> {
+    #[derive(Deserialize)]
+    struct ContentStub {
+        room_id: OwnedRoomId,
+        session_id: String,
+    }
+
+    #[derive(Deserialize)]
+    struct EventStub<'a> {
+        #[serde(rename = "type")]
+        event_type: &'a str,
+        content: ContentStub,
+    }
+
+    let event: EventStub<'_> = event.deserialize_as().ok()?;
+
+    (event.event_type == RoomKeyContent::EVENT_TYPE)
+        .then_some((event.content.room_id, event.content.session_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{device_id, room_id, serde::Raw, user_id};
+    use serde_json::json;
+
+    use super::{RoomKeyAcks, MAX_ACKNOWLEDGED_SESSIONS, MAX_OUTGOING_ACKS};
+
+    #[test]
+    fn test_outgoing_acks_are_bounded() {
+        let acks = RoomKeyAcks::default();
+        let room_id = room_id!("!test:localhost");
+        let content =
+            Raw::new(&json!({ "algorithm": "m.olm.v1.curve25519-aes-sha2" })).unwrap().cast();
+
+        for _ in 0..MAX_OUTGOING_ACKS {
+            assert!(acks.has_capacity());
+            acks.queue_ack(
+                user_id!("@bob:localhost"),
+                device_id!("BOBDEVICE"),
+                room_id.to_owned(),
+                content.clone(),
+            );
+        }
+
+        assert!(!acks.has_capacity());
+
+        let request_id = acks.outgoing_requests()[0].request_id.clone();
+        acks.mark_request_as_sent(&request_id);
+        assert!(acks.has_capacity());
+
+        acks.remove_room(room_id);
+        assert!(acks.outgoing_requests().is_empty());
+    }
+
+    #[test]
+    fn test_oldest_acknowledgements_are_forgotten() {
+        let acks = RoomKeyAcks::default();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id!("@bob:localhost");
+
+        for i in 0..=MAX_ACKNOWLEDGED_SESSIONS {
+            acks.record_ack(room_id, &format!("session_{i}"), user_id, device_id!("BOBDEVICE"));
+        }
+
+        assert!(acks.acknowledgements(room_id, "session_0").is_empty());
+        assert!(
+            acks.acknowledgements(room_id, "session_1")[user_id].contains(device_id!("BOBDEVICE"))
+        );
+
+        // Further acknowledgements of a tracked room key don't evict anything.
+        acks.record_ack(room_id, "session_1", user_id, device_id!("OTHERDEVICE"));
+        assert_eq!(acks.acknowledgements(room_id, "session_1")[user_id].len(), 2);
+        assert!(!acks.acknowledgements(room_id, "session_2").is_empty());
+
+        acks.remove_room(room_id);
+        assert!(acks.acknowledgements(room_id, "session_1").is_empty());
+    }
+}
@@ -35,7 +35,7 @@ use crate::{
         },
         tests,
     },
-    olm::{InboundGroupSession, OutboundGroupSession, SenderData},
+    olm::{InboundGroupSession, OutboundGroupSession, SenderData, SenderTrustStep},
     store::types::{Changes, IdentityChanges},
     types::{
         events::{
@@ -212,6 +212,112 @@ async fn test_decryption_verification_state() {
     assert_shield!(encryption_info, Red, Grey);
 }
 
+#[async_test]
+async fn test_explain_sender_trust() {
+    let (alice, bob) = get_machine_pair_with_setup_sessions_test_helper(
+        tests::alice_id(),
+        tests::user_id(),
+        false,
+    )
+    .await;
+    let room_id = room_id!("!test:example.org");
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        tests::to_device_requests_to_content(to_device_requests),
+    );
+
+    let group_session = bob
+        .store()
+        .with_transaction(|mut tr| async {
+            let res = bob.decrypt_to_device_event(&mut tr, &event, &mut Changes::default()).await?;
+            Ok((tr, res))
+        })
+        .await
+        .unwrap()
+        .inbound_group_session
+        .unwrap();
+
+    let sender_key = group_session.sender_key();
+    bob.store().save_inbound_group_sessions(&[group_session]).await.unwrap();
+
+    let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+    let encrypted_content = alice
+        .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content))
+        .await
+        .unwrap();
+
+    let event = json!({
+        "event_id": "$xxxxx:example.org",
+        "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+        "sender": alice.user_id(),
+        "type": "m.room.encrypted",
+        "content": encrypted_content,
+    });
+    let event = json_convert(&event).unwrap();
+
+    let device_key = SenderTrustStep::DeviceKey {
+        device_id: Some(alice.device_id().to_owned()),
+        ed25519_key: Some(alice.identity_keys().ed25519),
+    };
+
+    // Alice's device isn't cross-signed yet.
+    let explanation = bob.explain_sender_trust(&event, room_id).await.unwrap();
+    assert_eq!(explanation.sender_key, sender_key);
+    assert_eq!(
+        explanation.verification_state,
+        VerificationState::Unverified(VerificationLevel::UnsignedDevice)
+    );
+    assert_eq!(explanation.steps, vec![device_key.clone(), SenderTrustStep::NotCrossSigned]);
+
+    // Once Alice signs her device, her master key is pinned but not verified.
+    tests::setup_cross_signing_for_machine_test_helper(&alice, &bob).await;
+    tests::sign_alice_device_for_machine_test_helper(&alice, &bob).await;
+
+    let master_key = bob
+        .get_identity(alice.user_id(), None)
+        .await
+        .unwrap()
+        .unwrap()
+        .other()
+        .unwrap()
+        .master_key()
+        .get_first_key()
+        .unwrap();
+    let cross_signed =
+        SenderTrustStep::CrossSigned { user_id: alice.user_id().to_owned(), master_key };
+
+    let explanation = bob.explain_sender_trust(&event, room_id).await.unwrap();
+    assert_eq!(
+        explanation.verification_state,
+        VerificationState::Unverified(VerificationLevel::UnverifiedIdentity)
+    );
+    assert_eq!(
+        explanation.steps,
+        vec![device_key.clone(), cross_signed.clone(), SenderTrustStep::MasterKeyPinned]
+    );
+
+    // And once we verify Alice, the whole chain is trusted. Sessions of unverified
+    // senders aren't recalculated, so forget what we know about the sender.
+    mark_alice_identity_as_verified_test_helper(&alice, &bob).await;
+
+    let mut session = load_session(&bob, room_id, &event).await.unwrap().unwrap();
+    session.sender_data = SenderData::unknown();
+    save_session(&bob, session).await.unwrap();
+
+    let explanation = bob.explain_sender_trust(&event, room_id).await.unwrap();
+    assert_eq!(explanation.verification_state, VerificationState::Verified);
+    assert_eq!(
+        explanation.steps,
+        vec![device_key, cross_signed, SenderTrustStep::MasterKeyVerified]
+    );
+}
+
 async fn load_session(
     machine: &OlmMachine,
     room_id: &RoomId,
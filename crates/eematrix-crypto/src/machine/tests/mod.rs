@@ -12,32 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, iter, ops::Not, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, iter, num::NonZeroU32, ops::Not, sync::Arc, time::Duration};
 
 use assert_matches2::{assert_let, assert_matches};
-use futures_util::{pin_mut, FutureExt, StreamExt};
+use futures_util::{future, pin_mut, FutureExt, StreamExt};
 use itertools::Itertools;
 use matrix_sdk_common::{
     deserialized_responses::{
         AlgorithmInfo, ProcessedToDeviceEvent, UnableToDecryptInfo, UnableToDecryptReason,
         UnsignedDecryptionResult, UnsignedEventLocation, VerificationLevel, VerificationState,
-        WithheldCode,
+        WithheldCode, WithheldSender,
     },
     executor::spawn,
 };
 use matrix_sdk_test::{async_test, message_like_event_content, ruma_response_from_json, test_json};
 use ruma::{
     api::client::{
-        keys::{get_keys, upload_keys},
+        keys::{get_keys, upload_keys, upload_signatures},
         sync::sync_events::DeviceLists,
+        to_device::send_event_to_device::v3::Response as ToDeviceResponse,
     },
     device_id,
     events::{
         room::message::{
             AddMentions, MessageType, Relation, ReplyWithinThread, RoomMessageEventContent,
         },
-        AnyMessageLikeEvent, AnyMessageLikeEventContent, AnyToDeviceEvent, MessageLikeEvent,
-        OriginalMessageLikeEvent, ToDeviceEventType,
+        AnyMessageLikeEvent, AnyMessageLikeEventContent, AnySyncTimelineEvent, AnyToDeviceEvent,
+        MessageLikeEvent, OriginalMessageLikeEvent, ToDeviceEventType,
     },
     room_id,
     serde::Raw,
@@ -50,8 +51,10 @@ use vodozemac::{
     Ed25519PublicKey,
 };
 
-use super::CrossSigningBootstrapRequests;
+use super::{CrossSigningBootstrapPlan, CrossSigningBootstrapRequests, CrossSigningReset};
 use crate::{
+    clock::MockClock,
+    dehydrated_devices::DehydrationError,
     error::{EventError, OlmResult},
     machine::{
         test_helpers::{
@@ -59,27 +62,33 @@ use crate::{
             get_machine_pair_with_session_using_store,
             get_machine_pair_with_setup_sessions_test_helper, get_prepared_machine_test_helper,
         },
-        EncryptionSyncChanges, OlmMachine,
+        tests::megolm_sender_data::receive_to_device_event,
+        EncryptionSyncChanges, JsonSigner, OlmMachine, OlmMachineFeatures,
     },
-    olm::{BackedUpRoomKey, ExportedRoomKey, SenderData, VerifyJson},
+    olm::{BackedUpRoomKey, ExportedRoomKey, SenderData, ShareInfo, VerifyJson},
     session_manager::CollectStrategy,
     store::{
-        types::{BackupDecryptionKey, Changes, DeviceChanges, PendingChanges, RoomKeyInfo},
-        CryptoStore, MemoryStore,
+        types::{
+            BackupDecryptionKey, Changes, CryptoStatistics, DeviceChanges,
+            FallbackKeyRotationPolicy, PendingChanges, RoomKeyInfo,
+        },
+        CryptoStore, MemoryStore, RateLimit, RateLimitedError, RateLimitedRequest, RateLimits,
     },
     types::{
         events::{
             room::encrypted::{EncryptedToDeviceEvent, ToDeviceEncryptedEventContent},
+            room_key_ack::RoomKeyAckContent,
             room_key_withheld::{MegolmV1AesSha2WithheldContent, RoomKeyWithheldContent},
-            ToDeviceEvent,
+            ToDeviceEvent, UtdCause,
         },
-        requests::{AnyOutgoingRequest, ToDeviceRequest},
+        requests::{AnyOutgoingRequest, OutgoingRequest, ToDeviceRequest},
         DeviceKeys, SignedKey, SigningKeys,
     },
     utilities::json_convert,
     verification::tests::bob_id,
-    Account, DecryptionSettings, DeviceData, EncryptionSettings, LocalTrust, MegolmError, OlmError,
-    RoomEventDecryptionResult, TrustRequirement,
+    Account, CryptoStoreError, DecryptionSettings, DeviceData, EncryptionSettings, LocalTrust,
+    MegolmError, OlmError, PayloadValidationError, RoomEventDecryptionResult,
+    SessionRecipientCollectionError, SignatureError, TrustRequirement,
 };
 
 mod decryption_verification_state;
@@ -133,6 +142,18 @@ pub fn to_device_requests_to_content(
         .unwrap()
 }
 
+/// A stand-in for an encrypted `m.room_key.ack` message, to fill the queue of
+/// room key acknowledgements with.
+fn encrypted_room_key_ack() -> Raw<ToDeviceEncryptedEventContent> {
+    Raw::new(&json!({
+        "algorithm": "m.olm.v1.curve25519-aes-sha2",
+        "sender_key": "YVxQ/wD0Q5mpfLuxqvzI1+Qpb+Srl5R9aw6cJz7Xp1g",
+        "ciphertext": {},
+    }))
+    .unwrap()
+    .cast()
+}
+
 #[async_test]
 async fn test_create_olm_machine() {
     let test_start_ts = MilliSecondsSinceUnixEpoch::now();
@@ -239,6 +260,90 @@ async fn test_session_invalidation() {
         .invalidated());
 }
 
+#[async_test]
+async fn test_cross_signing_bootstrap_plan() {
+    let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+
+    let plan = machine.cross_signing_bootstrap_plan().await.unwrap();
+    assert_eq!(
+        plan,
+        CrossSigningBootstrapPlan {
+            create_cross_signing_keys: true,
+            upload_cross_signing_keys: true,
+            upload_device_keys: true,
+            sign_own_device: true,
+            enable_backup: true,
+        }
+    );
+    assert!(!plan.is_complete());
+
+    // Bootstrapping creates the keys, but nothing has been uploaded yet.
+    machine.bootstrap_cross_signing(false).await.unwrap();
+
+    let plan = machine.cross_signing_bootstrap_plan().await.unwrap();
+    assert!(!plan.create_cross_signing_keys);
+    assert!(plan.upload_cross_signing_keys);
+    assert!(plan.upload_device_keys);
+    assert!(plan.sign_own_device);
+    assert!(plan.enable_backup);
+
+    let backup_key = BackupDecryptionKey::new().unwrap().megolm_v1_public_key();
+    backup_key.set_version("1".to_owned());
+    machine.backup_machine().enable_backup_v1(backup_key).await.unwrap();
+
+    assert!(!machine.cross_signing_bootstrap_plan().await.unwrap().enable_backup);
+}
+
+#[async_test]
+async fn test_sign_and_verify_json() {
+    let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+    machine.bootstrap_cross_signing(false).await.unwrap();
+
+    let payload_type = "org.example.payload";
+    let content = json!({ "payload": "Hello world" }).as_object().unwrap().to_owned();
+
+    let signed = machine.sign_json(payload_type, content.clone()).await.unwrap();
+    assert_eq!(signed["type"], payload_type);
+    assert_eq!(signed["content"]["payload"], "Hello world");
+
+    let device = JsonSigner::Device(alice_device_id().to_owned());
+    machine.verify_json(user_id(), device.clone(), payload_type, &signed).await.unwrap();
+    machine.verify_json(user_id(), JsonSigner::MasterKey, payload_type, &signed).await.unwrap();
+
+    // Unsigned data isn't covered by the signatures.
+    let mut modified = signed.clone();
+    modified["unsigned"] = json!({ "age": 2 });
+    machine.verify_json(user_id(), device.clone(), payload_type, &modified).await.unwrap();
+
+    let mut tampered = signed.clone();
+    tampered["content"]["payload"] = json!("Goodbye world");
+    machine.verify_json(user_id(), device.clone(), payload_type, &tampered).await.unwrap_err();
+    machine
+        .verify_json(user_id(), JsonSigner::MasterKey, payload_type, &tampered)
+        .await
+        .unwrap_err();
+
+    // The signature is only valid for the type it was made for.
+    assert_matches!(
+        machine.verify_json(user_id(), device, "org.example.other", &signed).await,
+        Err(SignatureError::InvalidPayloadType(_))
+    );
+
+    // Types which aren't namespaced, or which are reserved for the spec, can't be
+    // signed, so the signatures can't be used for e.g. device keys.
+    for payload_type in ["payload", "m.device_keys", "org..payload"] {
+        assert_matches!(
+            machine.sign_json(payload_type, content.clone()).await,
+            Err(SignatureError::InvalidPayloadType(_))
+        );
+    }
+
+    assert_matches!(
+        machine.verify_json(bob_id(), JsonSigner::MasterKey, payload_type, &modified).await,
+        Err(SignatureError::MissingSigningKey)
+    );
+}
+
 #[test]
 fn test_invalid_signature() {
     let account = Account::with_device_id(user_id(), alice_device_id());
@@ -379,6 +484,38 @@ async fn test_query_keys_for_users() {
     assert!(request.device_keys.contains_key(alice_id));
 }
 
+#[async_test]
+async fn test_signature_upload_queue() {
+    let (machine, _) = get_prepared_machine_test_helper(user_id(), false).await;
+    let signed_keys = [(machine.user_id().to_owned(), upload_signatures::v3::SignedKeys::new())];
+    let request = upload_signatures::v3::Request::new(signed_keys.into());
+    let request_id = machine.queue_signature_upload(request.clone()).await.unwrap();
+
+    // The upload keeps its request ID until it's marked as sent
+    for _ in 0..2 {
+        let requests = machine.outgoing_signature_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].request_id(), &*request_id);
+    }
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert!(requests.iter().any(|r| r.request_id() == &*request_id));
+
+    let response: upload_signatures::v3::Response = ruma_response_from_json(&json!({}));
+    machine.mark_request_as_sent(&request_id, &response).await.unwrap();
+    assert!(machine.outgoing_signature_requests().await.unwrap().is_empty());
+
+    // Concurrent uploads don't overwrite each other
+    let (first, second) = future::join(
+        machine.queue_signature_upload(request.clone()),
+        machine.queue_signature_upload(request),
+    )
+    .await;
+    let requests = machine.outgoing_signature_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().any(|r| r.request_id() == &*first.unwrap()));
+    assert!(requests.iter().any(|r| r.request_id() == &*second.unwrap()));
+}
+
 #[async_test]
 async fn test_missing_sessions_calculation() {
     let (machine, _) = get_machine_after_query_test_helper().await;
@@ -554,6 +691,26 @@ async fn create_dehydrated_machine_and_pair() -> (OlmMachine, OlmMachine) {
     .await
 }
 
+#[async_test]
+async fn test_olm_machine_from_fixture() {
+    let store = Arc::new(MemoryStore::new());
+    let machine =
+        OlmMachine::with_store(alice_id(), alice_device_id(), store.clone(), None).await.unwrap();
+    let room_id = room_id!("!test:example.org");
+    machine.create_outbound_group_session_with_defaults_test_helper(room_id).await.unwrap();
+
+    let fixture = store.export_fixture().await.unwrap();
+    let restored = OlmMachine::from_fixture(&fixture).await.unwrap();
+
+    assert_eq!(restored.user_id(), alice_id());
+    assert_eq!(restored.device_id(), alice_device_id());
+    assert_eq!(restored.identity_keys().ed25519, machine.identity_keys().ed25519);
+
+    let room_keys = restored.store().export_room_keys(|_| true).await.unwrap();
+    assert_eq!(room_keys.len(), 1);
+    assert_eq!(room_keys[0].room_id, room_id);
+}
+
 #[async_test]
 async fn test_request_missing_secrets() {
     let (alice, _) = get_machine_pair_with_session(alice_id(), bob_id(), false).await;
@@ -613,6 +770,224 @@ async fn test_request_missing_secrets_cross_signed() {
     assert!(!should_query_secrets_now);
 }
 
+#[async_test]
+async fn test_builder_features() {
+    let machine = OlmMachine::builder(alice_id(), alice_device_id()).build().await.unwrap();
+    assert_eq!(machine.features(), OlmMachineFeatures::default());
+    assert!(machine.features().backups);
+
+    let machine = OlmMachine::builder(alice_id(), alice_device_id())
+        .backups(false)
+        .gossiping(false)
+        .dehydrated_devices(false)
+        .build()
+        .await
+        .unwrap();
+
+    let features = machine.features();
+    assert!(!features.backups);
+    assert!(!features.gossiping);
+    assert!(!features.dehydrated_devices);
+
+    // A backup key isn't activated.
+    let backup_key = BackupDecryptionKey::new().unwrap().megolm_v1_public_key();
+    backup_key.set_version("1".to_owned());
+    machine.backup_machine().enable_backup_v1(backup_key).await.unwrap();
+    assert!(!machine.backup_machine().enabled().await);
+
+    // No secrets are requested from our other devices.
+    assert!(!machine.are_room_key_requests_enabled());
+    assert!(!machine.query_missing_secrets_from_other_sessions().await.unwrap());
+
+    // Dehydrated devices can't be created.
+    assert_matches!(machine.dehydrated_devices().create().await, Err(DehydrationError::Disabled));
+}
+
+#[async_test]
+async fn test_rate_limits() {
+    let limits = RateLimits::default()
+        .keys_claim(RateLimit::new(NonZeroU32::MIN, Duration::from_secs(30)))
+        .keys_query(RateLimit::new(NonZeroU32::MIN, Duration::from_secs(60)))
+        .to_device(RateLimit::new(NonZeroU32::MIN, Duration::from_secs(10)));
+
+    let machine = OlmMachine::builder(alice_id(), alice_device_id())
+        .rate_limits(limits)
+        .build()
+        .await
+        .unwrap();
+    let clock = Arc::new(MockClock::new(MilliSecondsSinceUnixEpoch::now()));
+    machine.set_clock(clock.clone());
+    assert_eq!(machine.rate_limits(), limits);
+
+    let key_query_count = |requests: &[OutgoingRequest]| {
+        requests.iter().filter(|r| matches!(r.request(), AnyOutgoingRequest::KeysQuery(_))).count()
+    };
+
+    // The first key query goes out, and uses up the limit.
+    assert_eq!(key_query_count(&machine.outgoing_requests().await.unwrap()), 1);
+
+    // The next one is left out, the other requests still go out.
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_eq!(key_query_count(&requests), 0);
+    assert!(!requests.is_empty());
+
+    // Once a token was added back, the key query goes out again.
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(key_query_count(&machine.outgoing_requests().await.unwrap()), 1);
+
+    // A key claim which isn't needed doesn't use up the limit.
+    assert_matches!(machine.get_missing_sessions(iter::empty()).await, Ok(None));
+
+    // A throttled key claim fails with a typed error.
+    let store = machine.store();
+    machine.inner.rate_limiter.acquire(store, RateLimitedRequest::KeysClaim, 1).await.unwrap();
+    assert_matches!(
+        machine.get_missing_sessions(iter::empty()).await,
+        Err(CryptoStoreError::RateLimited(RateLimitedError {
+            request_type: RateLimitedRequest::KeysClaim,
+            retry_after,
+        })) if retry_after == Duration::from_secs(30)
+    );
+
+    // Only the to-device requests which fit into the limit go out.
+    let to_device_messages = |requests: &[OutgoingRequest]| {
+        requests
+            .iter()
+            .filter_map(|r| match r.request() {
+                AnyOutgoingRequest::ToDeviceRequest(request) => Some(request.message_count()),
+                _ => None,
+            })
+            .sum::<usize>()
+    };
+
+    for device_id in [device_id!("BOBDEVICE"), device_id!("CARLDEVICE")] {
+        machine.inner.room_key_acks.queue_ack(
+            user_id(),
+            device_id,
+            room_id!("!test:example.org").to_owned(),
+            encrypted_room_key_ack(),
+        );
+    }
+
+    assert_eq!(to_device_messages(&machine.outgoing_requests().await.unwrap()), 1);
+
+    // The admitted request is returned again until it's sent out, without
+    // taking another token.
+    assert_eq!(to_device_messages(&machine.outgoing_requests().await.unwrap()), 1);
+
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(to_device_messages(&machine.outgoing_requests().await.unwrap()), 2);
+}
+
+#[async_test]
+async fn test_gossip_request_backoff() {
+    let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+    let clock = Arc::new(MockClock::new(MilliSecondsSinceUnixEpoch(uint!(1_000_000))));
+    machine.set_clock(clock.clone());
+
+    assert!(machine.query_missing_secrets_from_other_sessions().await.unwrap());
+
+    let to_device_requests = |requests: Vec<OutgoingRequest>| {
+        requests
+            .into_iter()
+            .filter(|r| matches!(r.request(), AnyOutgoingRequest::ToDeviceRequest(_)))
+            .collect::<Vec<_>>()
+    };
+
+    let requests = to_device_requests(machine.outgoing_requests().await.unwrap());
+    assert!(!requests.is_empty());
+
+    for request in &requests {
+        machine.mark_request_as_failed(request.request_id(), "connection refused").await.unwrap();
+    }
+
+    // The failed requests aren't due, nor sent out, until the delay has passed.
+    let now = MilliSecondsSinceUnixEpoch(uint!(1_000_000));
+    assert!(machine.due_gossip_requests(now).await.unwrap().is_empty());
+    assert!(to_device_requests(machine.outgoing_requests().await.unwrap()).is_empty());
+
+    let retry_at = MilliSecondsSinceUnixEpoch(uint!(1_010_000));
+    let due = machine.due_gossip_requests(retry_at).await.unwrap();
+    assert!(!due.is_empty());
+
+    for request in &due {
+        assert_eq!(request.retry.attempts, 1);
+        assert_eq!(request.retry.last_error.as_deref(), Some("connection refused"));
+        assert_eq!(request.retry.next_retry_at, Some(retry_at));
+    }
+
+    // Every failure doubles the delay.
+    clock.set(retry_at);
+    let requests = to_device_requests(machine.outgoing_requests().await.unwrap());
+    assert!(!requests.is_empty());
+
+    for request in &requests {
+        machine.mark_request_as_failed(request.request_id(), "connection refused").await.unwrap();
+    }
+
+    let now = MilliSecondsSinceUnixEpoch(uint!(1_029_999));
+    assert!(machine.due_gossip_requests(now).await.unwrap().is_empty());
+
+    let retry_at = MilliSecondsSinceUnixEpoch(uint!(1_030_000));
+    let due = machine.due_gossip_requests(retry_at).await.unwrap();
+    assert!(due.iter().all(|r| r.retry.attempts == 2));
+    assert!(!due.is_empty());
+}
+
+#[cfg(feature = "experimental-send-custom-to-device")]
+#[async_test]
+async fn test_encrypt_custom_to_device() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+
+    let content = json!({ "provisioning": "data" });
+
+    let request = alice
+        .encrypt_custom_to_device(bob.user_id(), bob.device_id(), "io.example.custom", &content)
+        .await
+        .unwrap();
+
+    assert_eq!(request.event_type, ToDeviceEventType::RoomEncrypted);
+    assert!(request.messages.contains_key(bob.user_id()));
+
+    // Bob is able to decrypt the event and gets back the original type and content.
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(vec![Arc::new(request)]),
+    );
+    let event = json_convert(&event).unwrap();
+
+    let (decrypted, _) = bob
+        .receive_sync_changes(EncryptionSyncChanges {
+            to_device_events: vec![event],
+            changed_devices: &Default::default(),
+            one_time_keys_counts: &Default::default(),
+            unused_fallback_keys: None,
+            next_batch_token: None,
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(decrypted.len(), 1);
+    assert_let!(ProcessedToDeviceEvent::Decrypted { raw, encryption_info } = &decrypted[0]);
+    assert_eq!(raw.deserialize().unwrap().event_type().to_string(), "io.example.custom");
+    assert_eq!(raw.get_field::<serde_json::Value>("content").unwrap(), Some(content.clone()));
+    assert_eq!(encryption_info.sender, alice.user_id().to_owned());
+    assert_eq!(encryption_info.sender_device.as_deref(), Some(alice.device_id()));
+
+    assert_matches!(
+        alice
+            .encrypt_custom_to_device(
+                bob.user_id(),
+                device_id!("UNKNOWN"),
+                "io.example.custom",
+                &content
+            )
+            .await,
+        Err(OlmError::MissingSession)
+    );
+}
+
 #[async_test]
 async fn test_megolm_encryption() {
     let (alice, bob) =
@@ -704,6 +1079,308 @@ async fn test_megolm_encryption() {
     }
 }
 
+#[async_test]
+async fn test_megolm_replay_detection() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(to_device_requests),
+    );
+
+    let group_session = bob
+        .store()
+        .with_transaction(|mut tr| async {
+            let res = bob.decrypt_to_device_event(&mut tr, &event, &mut Changes::default()).await?;
+            Ok((tr, res))
+        })
+        .await
+        .unwrap()
+        .inbound_group_session
+        .unwrap();
+    bob.store().save_inbound_group_sessions(&[group_session]).await.unwrap();
+
+    let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+    let encrypted_content = alice
+        .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content))
+        .await
+        .unwrap();
+
+    let event = |event_id: &str| {
+        json_convert(&json!({
+            "event_id": event_id,
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": encrypted_content,
+        }))
+        .unwrap()
+    };
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+
+    // Decrypting the same event twice is fine.
+    bob.decrypt_room_event(&event("$original"), room_id, &decryption_settings).await.unwrap();
+    bob.decrypt_room_event(&event("$original"), room_id, &decryption_settings).await.unwrap();
+
+    // But reusing the message index in another event is a replay.
+    let error = bob
+        .decrypt_room_event(&event("$replayed"), room_id, &decryption_settings)
+        .await
+        .unwrap_err();
+    assert_let!(MegolmError::ReplayedMessage(event_id) = error);
+    assert_eq!(event_id, "$original");
+
+    let decryption_result = bob
+        .try_decrypt_room_event(&event("$replayed"), room_id, &decryption_settings)
+        .await
+        .unwrap();
+    assert_let!(RoomEventDecryptionResult::UnableToDecrypt(utd_info) = decryption_result);
+    assert_eq!(utd_info.reason, UnableToDecryptReason::ReplayedMegolmMessage);
+}
+
+#[async_test]
+async fn test_statistics() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+    let encrypted_content = alice
+        .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content))
+        .await
+        .unwrap();
+
+    let event = json_convert(&json!({
+        "event_id": "$xxxxx:example.org",
+        "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+        "sender": alice.user_id(),
+        "type": "m.room.encrypted",
+        "content": encrypted_content,
+    }))
+    .unwrap();
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+
+    // The room key didn't arrive yet, retrying doesn't count the event twice.
+    for _ in 0..2 {
+        bob.decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap_err();
+    }
+
+    let bob_statistics = bob.statistics().await.unwrap();
+    assert_eq!(bob_statistics.undecryptable_messages, 1);
+    assert_eq!(bob_statistics.messages_decrypted, 0);
+
+    let to_device = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(to_device_requests),
+    );
+    receive_to_device_event(&bob, &to_device).await;
+
+    // Once the room key arrived, the event moves to the decrypted messages.
+    bob.decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap();
+
+    let alice_statistics = alice.statistics().await.unwrap();
+    assert_eq!(alice_statistics.megolm_sessions_stored, 1);
+    assert_eq!(alice_statistics.messages_encrypted, 1);
+
+    let bob_statistics = bob.statistics().await.unwrap();
+    assert_eq!(bob_statistics.megolm_sessions_stored, 1);
+    assert_eq!(bob_statistics.messages_decrypted, 1);
+    assert_eq!(bob_statistics.undecryptable_messages, 0);
+    assert_eq!(bob_statistics.messages_encrypted, 0);
+
+    // The counts are persisted together with the next changes.
+    bob.store().save_changes(Changes::default()).await.unwrap();
+    let persisted: CryptoStatistics =
+        bob.store().get_value("crypto_statistics").await.unwrap().unwrap();
+    assert_eq!(persisted, bob_statistics);
+}
+
+#[async_test]
+async fn test_room_only_allow_trusted_devices() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+    let other_room_id = room_id!("!other:example.org");
+
+    assert!(!alice.room_only_allow_trusted_devices(room_id).await.unwrap());
+
+    alice.set_room_only_allow_trusted_devices(room_id, true).await.unwrap();
+    assert!(alice.room_only_allow_trusted_devices(room_id).await.unwrap());
+    assert!(alice.room_settings(room_id).await.unwrap().unwrap().only_allow_trusted_devices);
+    assert!(!alice.room_only_allow_trusted_devices(other_room_id).await.unwrap());
+
+    // Bob's device isn't trusted, so the room key is withheld from it, even though
+    // the settings allow sharing it with all devices.
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    assert_eq!(to_device_requests.len(), 1);
+    assert_eq!(to_device_requests[0].event_type.to_string(), "m.room_key.withheld");
+
+    // The other room isn't affected.
+    let to_device_requests = alice
+        .share_room_key(other_room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    assert_eq!(to_device_requests.len(), 1);
+    assert_eq!(to_device_requests[0].event_type.to_string(), "m.room.encrypted");
+
+    // The global flag applies to all rooms.
+    alice.set_room_only_allow_trusted_devices(room_id, false).await.unwrap();
+    assert!(!alice.room_only_allow_trusted_devices(room_id).await.unwrap());
+    alice.store().set_only_allow_trusted_devices(true).await.unwrap();
+    assert!(alice.room_only_allow_trusted_devices(room_id).await.unwrap());
+}
+
+#[async_test]
+async fn test_room_sharing_strategy() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+    let other_room_id = room_id!("!other:example.org");
+
+    assert!(alice.room_sharing_strategy(room_id).await.unwrap().is_none());
+
+    alice
+        .set_room_sharing_strategy(room_id, Some(CollectStrategy::IdentityBasedStrategy))
+        .await
+        .unwrap();
+    assert_eq!(
+        alice.room_sharing_strategy(room_id).await.unwrap(),
+        Some(CollectStrategy::IdentityBasedStrategy)
+    );
+
+    // The identity based strategy is used for the room, even though the settings
+    // allow sharing the room key with all devices. It requires our own
+    // cross-signing to be set up, which isn't the case here.
+    assert_matches!(
+        alice
+            .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+            .await,
+        Err(OlmError::SessionRecipientCollectionError(
+            SessionRecipientCollectionError::CrossSigningNotSetup
+        ))
+    );
+
+    // The other room isn't affected.
+    let to_device_requests = alice
+        .share_room_key(other_room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    assert_eq!(to_device_requests[0].event_type.to_string(), "m.room.encrypted");
+
+    alice.set_room_sharing_strategy(room_id, None).await.unwrap();
+    assert!(alice.room_sharing_strategy(room_id).await.unwrap().is_none());
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    assert_eq!(to_device_requests[0].event_type.to_string(), "m.room.encrypted");
+}
+
+#[async_test]
+async fn test_encryption_payload_validation() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let encryption_settings = EncryptionSettings {
+        max_content_size: Some(256),
+        strict_relation_validation: true,
+        ..Default::default()
+    };
+
+    alice.share_room_key(room_id, iter::once(bob.user_id()), encryption_settings).await.unwrap();
+
+    // A well-formed reply is encrypted.
+    let content = message_like_event_content!({
+        "msgtype": "m.text",
+        "body": "It's a secret to everybody",
+        "m.relates_to": {
+            "m.in_reply_to": { "event_id": "$someevent:example.org" },
+        },
+    });
+    alice.encrypt_room_event_raw(room_id, "m.room.message", &content).await.unwrap();
+
+    // A content which is too large is rejected.
+    let content = message_like_event_content!({
+        "msgtype": "m.text",
+        "body": "A".repeat(256),
+    });
+    let error =
+        alice.encrypt_room_event_raw(room_id, "m.room.message", &content).await.unwrap_err();
+    assert_matches!(
+        error,
+        MegolmError::InvalidPayload(PayloadValidationError::ContentTooLarge { max_size: 256, .. })
+    );
+
+    // An annotation without a key is rejected.
+    let content = message_like_event_content!({
+        "m.relates_to": {
+            "rel_type": "m.annotation",
+            "event_id": "$someevent:example.org",
+        },
+    });
+    let error = alice.encrypt_room_event_raw(room_id, "m.reaction", &content).await.unwrap_err();
+    assert_matches!(
+        error,
+        MegolmError::InvalidPayload(PayloadValidationError::MalformedRelation(_))
+    );
+
+    // An edit of an invalid event ID is rejected.
+    let content = message_like_event_content!({
+        "msgtype": "m.text",
+        "body": "* edited",
+        "m.relates_to": {
+            "rel_type": "m.replace",
+            "event_id": "not an event ID",
+        },
+    });
+    let error =
+        alice.encrypt_room_event_raw(room_id, "m.room.message", &content).await.unwrap_err();
+    assert_matches!(
+        error,
+        MegolmError::InvalidPayload(PayloadValidationError::MalformedRelation(_))
+    );
+
+    // Changing the validation settings applies them to the current room key,
+    // without rotating it.
+    let session_id = alice
+        .inner
+        .group_session_manager
+        .get_outbound_group_session(room_id)
+        .unwrap()
+        .session_id()
+        .to_owned();
+    alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let session = alice.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+    assert_eq!(session.session_id(), session_id);
+    assert_eq!(session.settings().max_content_size, None);
+    alice.encrypt_room_event_raw(room_id, "m.room.message", &content).await.unwrap();
+}
+
 #[async_test]
 async fn test_withheld_unverified() {
     let (alice, bob) =
@@ -773,40 +1450,130 @@ async fn test_withheld_unverified() {
 
     let content = RoomMessageEventContent::text_plain(plaintext);
 
-    let content = alice
-        .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content.clone()))
-        .await
-        .unwrap();
+    let content = alice
+        .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content.clone()))
+        .await
+        .unwrap();
+
+    let room_event = json!({
+        "event_id": "$xxxxx:example.org",
+        "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+        "sender": alice.user_id(),
+        "type": "m.room.encrypted",
+        "content": content,
+    });
+    let room_event = json_convert(&room_event).unwrap();
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+    let decrypt_result = bob.decrypt_room_event(&room_event, room_id, &decryption_settings).await;
+
+    assert_matches!(&decrypt_result, Err(MegolmError::MissingRoomKey(Some(_), Some(_))));
+
+    let err = decrypt_result.err().unwrap();
+    assert_let!(MegolmError::MissingRoomKey(Some(WithheldCode::Unverified), Some(sender)) = err);
+    assert_eq!(sender.user_id, alice.user_id());
+    assert_eq!(sender.device_id.as_deref(), Some(alice.device_id()));
+
+    // Also check `try_decrypt_room_event`.
+    let decrypt_result =
+        bob.try_decrypt_room_event(&room_event, room_id, &decryption_settings).await.unwrap();
+    assert_let!(RoomEventDecryptionResult::UnableToDecrypt(utd_info) = decrypt_result);
+    assert!(utd_info.session_id.is_some());
+    assert_eq!(
+        utd_info.reason,
+        UnableToDecryptReason::MissingMegolmSession {
+            withheld_code: Some(WithheldCode::Unverified),
+            withheld_by: Some(WithheldSender {
+                user_id: alice.user_id().to_owned(),
+                device_id: Some(alice.device_id().to_owned()),
+            }),
+        }
+    );
+}
+
+#[async_test]
+async fn test_determine_utd_cause() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+    let now = MilliSecondsSinceUnixEpoch::now();
+    let clock = Arc::new(MockClock::new(now));
+    bob.set_clock(clock.clone());
+
+    // Alice shares a room key, but Bob doesn't receive it yet.
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let content = alice
+        .encrypt_room_event(room_id, RoomMessageEventContent::text_plain("It's a secret"))
+        .await
+        .unwrap();
+    let room_event = json!({
+        "event_id": "$xxxxx:example.org",
+        "origin_server_ts": now,
+        "sender": alice.user_id(),
+        "type": "m.room.encrypted",
+        "content": content,
+    });
+    let raw_event: Raw<AnySyncTimelineEvent> = json_convert(&room_event).unwrap();
+    let room_event = json_convert(&room_event).unwrap();
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+    let decrypt_result =
+        bob.try_decrypt_room_event(&room_event, room_id, &decryption_settings).await.unwrap();
+    assert_let!(RoomEventDecryptionResult::UnableToDecrypt(utd_info) = decrypt_result);
+
+    // The message was just sent, so the room key may still be on its way.
+    let cause = bob.determine_utd_cause(&raw_event, room_id, &utd_info).await.unwrap();
+    assert_eq!(cause, UtdCause::KeyNotYetReceived);
+
+    // After a while, we don't expect it anymore.
+    clock.advance(Duration::from_secs(10 * 60));
+    let cause = bob.determine_utd_cause(&raw_event, room_id, &utd_info).await.unwrap();
+    assert_eq!(cause, UtdCause::Unknown);
+
+    // Once the room key arrived, decrypting the message again should work.
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(to_device_requests),
+    );
+    bob.receive_sync_changes(EncryptionSyncChanges {
+        to_device_events: vec![json_convert(&event).unwrap()],
+        changed_devices: &Default::default(),
+        one_time_keys_counts: &Default::default(),
+        unused_fallback_keys: None,
+        next_batch_token: None,
+    })
+    .await
+    .unwrap();
+
+    let cause = bob.determine_utd_cause(&raw_event, room_id, &utd_info).await.unwrap();
+    assert_eq!(cause, UtdCause::KeyNotYetReceived);
 
-    let room_event = json!({
-        "event_id": "$xxxxx:example.org",
-        "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+    // A message sent before Bob's device existed can't be expected to be
+    // decryptable without a key backup.
+    let historical_event = json!({
+        "event_id": "$yyyyy:example.org",
+        "origin_server_ts": 1000,
         "sender": alice.user_id(),
         "type": "m.room.encrypted",
         "content": content,
     });
-    let room_event = json_convert(&room_event).unwrap();
-
-    let decryption_settings =
-        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
-    let decrypt_result = bob.decrypt_room_event(&room_event, room_id, &decryption_settings).await;
-
-    assert_matches!(&decrypt_result, Err(MegolmError::MissingRoomKey(Some(_))));
-
-    let err = decrypt_result.err().unwrap();
-    assert_matches!(err, MegolmError::MissingRoomKey(Some(WithheldCode::Unverified)));
+    let historical_event: Raw<AnySyncTimelineEvent> = json_convert(&historical_event).unwrap();
+    let utd_info = UnableToDecryptInfo {
+        session_id: Some("unknown".to_owned()),
+        reason: UnableToDecryptReason::MissingMegolmSession {
+            withheld_code: None,
+            withheld_by: None,
+        },
+    };
 
-    // Also check `try_decrypt_room_event`.
-    let decrypt_result =
-        bob.try_decrypt_room_event(&room_event, room_id, &decryption_settings).await.unwrap();
-    assert_let!(RoomEventDecryptionResult::UnableToDecrypt(utd_info) = decrypt_result);
-    assert!(utd_info.session_id.is_some());
-    assert_eq!(
-        utd_info.reason,
-        UnableToDecryptReason::MissingMegolmSession {
-            withheld_code: Some(WithheldCode::Unverified)
-        }
-    );
+    let cause = bob.determine_utd_cause(&historical_event, room_id, &utd_info).await.unwrap();
+    assert_eq!(cause, UtdCause::HistoricalMessageAndBackupIsDisabled);
 }
 
 /// Test what happens when we feed an unencrypted event into the decryption
@@ -1369,6 +2136,49 @@ async fn test_olm_machine_with_custom_account() {
     );
 }
 
+#[async_test]
+async fn test_resumable_cross_signing_reset() {
+    async fn master_key(machine: &OlmMachine) -> Ed25519PublicKey {
+        let identity = machine.inner.user_identity.lock().await;
+        identity.master_public_key().await.and_then(|k| k.get_first_key()).unwrap()
+    }
+
+    fn requested_master_key(reset: &CrossSigningReset) -> Ed25519PublicKey {
+        let master_key = reset.requests().upload_signing_keys_req.master_key.as_ref().unwrap();
+        master_key.get_first_key_and_id().unwrap().1
+    }
+
+    let store = Arc::new(MemoryStore::new());
+
+    let alice =
+        OlmMachine::with_store(user_id(), alice_device_id(), store.clone(), None).await.unwrap();
+    alice.bootstrap_cross_signing(false).await.unwrap();
+    let old_master_key = master_key(&alice).await;
+
+    assert!(alice.pending_cross_signing_reset().await.unwrap().is_none());
+
+    let reset = alice.reset_cross_signing().await.unwrap();
+    let new_master_key = requested_master_key(&reset);
+    assert_ne!(new_master_key, old_master_key);
+
+    // The old identity stays in place until the reset is finished.
+    assert_eq!(master_key(&alice).await, old_master_key);
+
+    // The reset survives a restart.
+    drop(reset);
+    drop(alice);
+    let alice = OlmMachine::with_store(user_id(), alice_device_id(), store, None).await.unwrap();
+
+    let reset = alice.pending_cross_signing_reset().await.unwrap().unwrap();
+    assert_eq!(requested_master_key(&reset), new_master_key);
+
+    reset.finish().await.unwrap();
+
+    assert_eq!(master_key(&alice).await, new_master_key);
+    assert!(alice.cross_signing_status().await.is_complete());
+    assert!(alice.pending_cross_signing_reset().await.unwrap().is_none());
+}
+
 #[async_test]
 async fn test_unsigned_decryption() {
     let (alice, bob) =
@@ -1486,7 +2296,10 @@ async fn test_unsigned_decryption() {
         replace_encryption_result,
         UnsignedDecryptionResult::UnableToDecrypt(UnableToDecryptInfo {
             session_id: Some(second_room_key_session_id),
-            reason: UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+            reason: UnableToDecryptReason::MissingMegolmSession {
+                withheld_code: None,
+                withheld_by: None,
+            },
         })
     );
 
@@ -1592,7 +2405,10 @@ async fn test_unsigned_decryption() {
         thread_encryption_result,
         UnsignedDecryptionResult::UnableToDecrypt(UnableToDecryptInfo {
             session_id: Some(third_room_key_session_id),
-            reason: UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+            reason: UnableToDecryptReason::MissingMegolmSession {
+                withheld_code: None,
+                withheld_by: None,
+            },
         })
     );
 
@@ -1677,6 +2493,467 @@ async fn test_mark_all_tracked_users_as_dirty() {
     });
 }
 
+#[async_test]
+async fn test_freeze_and_thaw() {
+    let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+    let identity_keys = machine.identity_keys();
+
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_let!(Some(upload) = requests.first());
+    assert_matches!(upload.request(), AnyOutgoingRequest::KeysUpload(_));
+
+    machine.inner.room_key_acks.queue_ack(
+        alice_id(),
+        device_id!("BOBDEVICE"),
+        room_id!("!test:example.org").to_owned(),
+        encrypted_room_key_ack(),
+    );
+
+    let to_device_request_id = |requests: &[OutgoingRequest]| {
+        requests
+            .iter()
+            .find(|r| matches!(r.request(), AnyOutgoingRequest::ToDeviceRequest(_)))
+            .map(|r| r.request_id.clone())
+    };
+
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_let!(Some(ack_request_id) = to_device_request_id(&requests));
+
+    machine.freeze();
+    assert!(machine.is_frozen());
+
+    // No requests are sent out and nothing is written to the store.
+    assert!(machine.outgoing_requests().await.unwrap().is_empty());
+    assert_matches!(
+        machine.mark_request_as_sent(&upload.request_id, &keys_upload_response()).await,
+        Err(OlmError::Store(CryptoStoreError::ReadOnly))
+    );
+    assert_matches!(
+        machine.store().save_changes(Changes::default()).await,
+        Err(CryptoStoreError::ReadOnly)
+    );
+
+    // The data in the store can still be read.
+    assert!(machine.get_device(user_id(), alice_device_id(), None).await.unwrap().is_some());
+
+    // Only a login using the same device can thaw the machine.
+    assert_matches!(
+        machine.thaw(device_id!("OTHERDEVICE")),
+        Err(CryptoStoreError::MismatchedAccount { .. })
+    );
+    assert!(machine.is_frozen());
+
+    machine.thaw(alice_device_id()).unwrap();
+    assert!(!machine.is_frozen());
+
+    // The requests which were queued up before the machine was frozen are
+    // returned again.
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_let!(Some(upload) = requests.first());
+    assert_matches!(upload.request(), AnyOutgoingRequest::KeysUpload(_));
+    assert_eq!(to_device_request_id(&requests), Some(ack_request_id));
+    machine.mark_request_as_sent(&upload.request_id, &keys_upload_response()).await.unwrap();
+
+    assert_eq!(machine.identity_keys().curve25519, identity_keys.curve25519);
+}
+
+#[async_test]
+async fn test_decryption_while_frozen() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(to_device_requests),
+    );
+
+    let group_session = bob
+        .store()
+        .with_transaction(|mut tr| async {
+            let res = bob.decrypt_to_device_event(&mut tr, &event, &mut Changes::default()).await?;
+            Ok((tr, res))
+        })
+        .await
+        .unwrap()
+        .inbound_group_session
+        .unwrap();
+    bob.store().save_inbound_group_sessions(&[group_session]).await.unwrap();
+
+    let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+    let encrypted_content = alice
+        .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content))
+        .await
+        .unwrap();
+
+    let event = |event_id: &str| {
+        json_convert(&json!({
+            "event_id": event_id,
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": encrypted_content,
+        }))
+        .unwrap()
+    };
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+
+    bob.freeze();
+
+    // Events can still be decrypted, even though the message index can't be
+    // written to the store.
+    bob.decrypt_room_event(&event("$original"), room_id, &decryption_settings).await.unwrap();
+
+    // Replays are still detected, using the indices remembered in memory.
+    let error = bob
+        .decrypt_room_event(&event("$replayed"), room_id, &decryption_settings)
+        .await
+        .unwrap_err();
+    assert_let!(MegolmError::ReplayedMessage(event_id) = error);
+    assert_eq!(event_id, "$original");
+}
+
+#[async_test]
+async fn test_shred() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    alice.bootstrap_cross_signing(false).await.unwrap();
+    alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let report = alice.shred().await.unwrap();
+
+    assert!(report.account_deleted);
+    assert!(report.sessions_deleted > 0);
+    assert_eq!(report.inbound_group_sessions_deleted, 1);
+    assert!(report.secure_delete);
+
+    // The machine is frozen for good, and its private keys are gone from memory.
+    assert!(alice.is_frozen());
+    assert!(alice.is_shredded());
+    assert_matches!(alice.thaw(alice.device_id()), Err(CryptoStoreError::Shredded));
+    assert!(alice.is_frozen());
+    assert!(!alice.cross_signing_status().await.has_master);
+
+    // Nothing secret is left in the store.
+    let store = alice.store().crypto_store();
+    assert!(store.load_account().await.unwrap().is_none());
+    assert!(store.load_identity().await.unwrap().is_none());
+    assert!(store.get_session_sender_keys().await.unwrap().is_empty());
+    assert!(store.get_inbound_group_sessions().await.unwrap().is_empty());
+    assert!(store.get_outbound_group_session(room_id).await.unwrap().is_none());
+}
+
+#[async_test]
+async fn test_room_key_acks() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    alice.set_room_key_acks_enabled(true);
+    bob.set_room_key_acks_enabled(true);
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    for request in &to_device_requests {
+        alice.mark_request_as_sent(&request.txn_id, &ToDeviceResponse::new()).await.unwrap();
+    }
+
+    let info = alice.outbound_session_info(room_id).await.unwrap();
+    assert!(info.acknowledged_by.is_empty());
+
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(to_device_requests),
+    );
+
+    bob.receive_sync_changes(EncryptionSyncChanges {
+        to_device_events: vec![json_convert(&event).unwrap()],
+        changed_devices: &Default::default(),
+        one_time_keys_counts: &Default::default(),
+        unused_fallback_keys: None,
+        next_batch_token: None,
+    })
+    .await
+    .unwrap();
+
+    // Bob acknowledges the receipt of the room key, using an encrypted
+    // message.
+    let ack = bob
+        .outgoing_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .find_map(|r| match r.request() {
+            AnyOutgoingRequest::ToDeviceRequest(r) if r.messages.contains_key(alice.user_id()) => {
+                Some(r.clone())
+            }
+            _ => None,
+        })
+        .expect("Bob should acknowledge the room key");
+    assert_eq!(ack.event_type, ToDeviceEventType::RoomEncrypted);
+
+    // A spoofed, unencrypted acknowledgement is ignored.
+    let spoofed = ToDeviceEvent::new(
+        bob.user_id().to_owned(),
+        RoomKeyAckContent::new(room_id.to_owned(), info.session_id.clone()),
+    );
+    let event = ToDeviceEvent::new(
+        bob.user_id().to_owned(),
+        to_device_requests_to_content(vec![Arc::new(ack)]),
+    );
+
+    let (events, _) = alice
+        .receive_sync_changes(EncryptionSyncChanges {
+            to_device_events: vec![json_convert(&spoofed).unwrap()],
+            changed_devices: &Default::default(),
+            one_time_keys_counts: &Default::default(),
+            unused_fallback_keys: None,
+            next_batch_token: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(alice.room_key_acknowledgements(room_id, &info.session_id).is_empty());
+
+    let (events, _) = alice
+        .receive_sync_changes(EncryptionSyncChanges {
+            to_device_events: vec![json_convert(&event).unwrap()],
+            changed_devices: &Default::default(),
+            one_time_keys_counts: &Default::default(),
+            unused_fallback_keys: None,
+            next_batch_token: None,
+        })
+        .await
+        .unwrap();
+
+    assert_let!(Some(ProcessedToDeviceEvent::Decrypted { raw, .. }) = events.first());
+    let content = raw.get_field::<RoomKeyAckContent>("content").unwrap().unwrap();
+    assert_eq!(content.room_id, room_id);
+    assert_eq!(content.session_id, info.session_id);
+
+    let info = alice.outbound_session_info(room_id).await.unwrap();
+    assert!(info.acknowledged_by[bob.user_id()].contains(bob.device_id()));
+
+    // The acknowledgements are kept after the room key is rotated.
+    let session_id = info.session_id;
+    alice.discard_room_key(room_id).await.unwrap();
+    let acknowledgements = alice.room_key_acknowledgements(room_id, &session_id);
+    assert!(acknowledgements[bob.user_id()].contains(bob.device_id()));
+}
+
+#[async_test]
+async fn test_to_device_requests_survive_a_restart() {
+    fn to_device_requests(requests: Vec<OutgoingRequest>) -> Vec<OutgoingRequest> {
+        requests
+            .into_iter()
+            .filter(|r| matches!(r.request(), AnyOutgoingRequest::ToDeviceRequest(_)))
+            .collect()
+    }
+
+    let store = Arc::new(MemoryStore::new());
+
+    let alice =
+        OlmMachine::with_store(user_id(), alice_device_id(), store.clone(), None).await.unwrap();
+    assert!(to_device_requests(alice.outgoing_requests().await.unwrap()).is_empty());
+
+    alice.inner.room_key_acks.queue_ack(
+        alice_id(),
+        device_id!("BOBDEVICE"),
+        room_id!("!test:example.org").to_owned(),
+        encrypted_room_key_ack(),
+    );
+
+    let requests = to_device_requests(alice.outgoing_requests().await.unwrap());
+    assert_eq!(requests.len(), 1);
+
+    // The request is sent out again after a restart.
+    drop(alice);
+    let alice =
+        OlmMachine::with_store(user_id(), alice_device_id(), store.clone(), None).await.unwrap();
+
+    let requests = to_device_requests(alice.outgoing_requests().await.unwrap());
+    assert_let!(Some(request) = requests.first());
+    assert_let!(AnyOutgoingRequest::ToDeviceRequest(to_device) = request.request());
+    assert_eq!(to_device.event_type, ToDeviceEventType::RoomEncrypted);
+
+    alice.mark_request_as_sent(&request.request_id, &ToDeviceResponse::new()).await.unwrap();
+    assert!(to_device_requests(alice.outgoing_requests().await.unwrap()).is_empty());
+
+    // Once the request reached the server, it's gone for good.
+    drop(alice);
+    let alice =
+        OlmMachine::with_store(user_id(), alice_device_id(), store.clone(), None).await.unwrap();
+    assert!(to_device_requests(alice.outgoing_requests().await.unwrap()).is_empty());
+
+    // Requests which are dropped by the component which queued them up aren't
+    // restored either.
+    alice.inner.room_key_acks.queue_ack(
+        alice_id(),
+        device_id!("BOBDEVICE"),
+        room_id!("!test:example.org").to_owned(),
+        encrypted_room_key_ack(),
+    );
+    assert_eq!(to_device_requests(alice.outgoing_requests().await.unwrap()).len(), 1);
+
+    alice.inner.room_key_acks.clear();
+    assert!(to_device_requests(alice.outgoing_requests().await.unwrap()).is_empty());
+
+    drop(alice);
+    let alice = OlmMachine::with_store(user_id(), alice_device_id(), store, None).await.unwrap();
+    assert!(to_device_requests(alice.outgoing_requests().await.unwrap()).is_empty());
+}
+
+#[async_test]
+async fn test_session_share_history() {
+    let (alice, bob) =
+        get_machine_pair_with_setup_sessions_test_helper(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let now = MilliSecondsSinceUnixEpoch(uint!(1_000_000));
+    alice.set_clock(Arc::new(MockClock::new(now)));
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let session_id = alice.outbound_session_info(room_id).await.unwrap().session_id;
+    assert!(alice.store().session_share_history(room_id, &session_id).await.unwrap().is_empty());
+
+    for request in &to_device_requests {
+        alice.mark_request_as_sent(&request.txn_id, &ToDeviceResponse::new()).await.unwrap();
+    }
+
+    let history = alice.store().session_share_history(room_id, &session_id).await.unwrap();
+    assert_eq!(history.len(), 1);
+
+    let record = &history[0];
+    assert_eq!(record.session_id, session_id);
+    assert_eq!(record.user_id, bob.user_id());
+    assert_eq!(record.device_id, bob.device_id());
+    assert_eq!(record.shared_at, now);
+    assert_let!(ShareInfo::Shared(shared_with) = &record.share_info);
+    assert_eq!(shared_with.message_index, 0);
+
+    // The history is kept after the session is rotated.
+    alice.discard_room_key(room_id).await.unwrap();
+    let history = alice.store().session_share_history(room_id, &session_id).await.unwrap();
+    assert_eq!(history.len(), 1);
+}
+
+#[async_test]
+async fn test_fallback_key_rotation() {
+    let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+
+    assert_eq!(
+        machine.fallback_key_rotation_policy().await.unwrap(),
+        FallbackKeyRotationPolicy::default()
+    );
+
+    let policy =
+        FallbackKeyRotationPolicy { max_age: Duration::from_secs(3600), max_uses: Some(5) };
+    machine.set_fallback_key_rotation_policy(policy.clone()).await.unwrap();
+    assert_eq!(machine.fallback_key_rotation_policy().await.unwrap(), policy);
+
+    // Publish our initial keys.
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_let!(Some(upload) = requests.first());
+    machine.mark_request_as_sent(&upload.request_id, &keys_upload_response()).await.unwrap();
+
+    // Rotating the fallback key makes us upload the new one.
+    machine.rotate_fallback_key_now().await.unwrap();
+
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_let!(Some(upload) = requests.first());
+    assert_let!(AnyOutgoingRequest::KeysUpload(request) = upload.request());
+    assert_eq!(request.fallback_keys.len(), 1);
+    let fallback_key_ids: Vec<_> = request.fallback_keys.keys().cloned().collect();
+
+    // Rotating it again before it was uploaded keeps the new one.
+    machine.rotate_fallback_key_now().await.unwrap();
+
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_let!(Some(upload) = requests.first());
+    assert_let!(AnyOutgoingRequest::KeysUpload(request) = upload.request());
+    assert!(request.fallback_keys.keys().eq(&fallback_key_ids));
+}
+
+#[async_test]
+async fn test_one_time_key_pool_stream() {
+    let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+    assert_eq!(machine.one_time_key_low_watermark(), 0);
+
+    machine.set_one_time_key_low_watermark(10);
+    let stream = machine.one_time_key_pool_stream();
+    pin_mut!(stream);
+
+    let update_key_count = |count: u8| {
+        let machine = &machine;
+
+        async move {
+            let key_counts =
+                BTreeMap::from([(OneTimeKeyAlgorithm::SignedCurve25519, count.into())]);
+
+            machine
+                .receive_sync_changes(EncryptionSyncChanges {
+                    to_device_events: Vec::new(),
+                    changed_devices: &Default::default(),
+                    one_time_keys_counts: &key_counts,
+                    unused_fallback_keys: None,
+                    next_batch_token: None,
+                })
+                .await
+                .expect("We should be able to update our one-time key counts");
+        }
+    };
+
+    // A count above the low watermark doesn't yield an update.
+    update_key_count(20).await;
+    assert!(stream.next().now_or_never().is_none());
+
+    update_key_count(5).await;
+    let health = stream.next().now_or_never().flatten().expect("We should get an update");
+    assert_eq!(health.remaining_server_side, 5);
+    assert_eq!(health, machine.one_time_key_pool_health().await.unwrap());
+
+    // The count didn't drop any further, so no new update is yielded.
+    update_key_count(5).await;
+    assert!(stream.next().now_or_never().is_none());
+
+    update_key_count(4).await;
+    let health = stream.next().now_or_never().flatten().expect("We should get an update");
+    assert_eq!(health.remaining_server_side, 4);
+
+    // Uploading new keys is tracked in the number of published keys.
+    let requests = machine.outgoing_requests().await.unwrap();
+    assert_let!(Some(upload) = requests.first());
+    assert_matches!(upload.request(), AnyOutgoingRequest::KeysUpload(_));
+
+    let unpublished = machine.one_time_key_pool_health().await.unwrap().unpublished;
+    assert!(unpublished > 0);
+
+    machine.mark_request_as_sent(&upload.request_id, &keys_upload_response()).await.unwrap();
+
+    let health = machine.one_time_key_pool_health().await.unwrap();
+    assert_eq!(health.published, unpublished);
+    assert_eq!(health.unpublished, 0);
+}
+
 #[async_test]
 async fn test_verified_latch_migration() {
     let store = MemoryStore::new();
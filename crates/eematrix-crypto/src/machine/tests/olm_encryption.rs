@@ -17,7 +17,8 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use assert_matches2::assert_let;
+use assert_matches2::{assert_let, assert_matches};
+use futures_util::{pin_mut, FutureExt, StreamExt};
 use matrix_sdk_common::deserialized_responses::ProcessedToDeviceEvent;
 use matrix_sdk_test::async_test;
 use ruma::{
@@ -33,12 +34,13 @@ use crate::{
         test_helpers::{
             create_session, get_machine_pair, get_machine_pair_with_session,
             get_machine_pair_with_setup_sessions_test_helper,
+            send_and_receive_encrypted_to_device_test_helper,
         },
         tests,
         tests::megolm_sender_data::receive_to_device_event,
     },
     olm::utility::SignJson,
-    store::types::Changes,
+    store::{types::Changes, SessionCreationLimit, SuspiciousActivity},
     types::{events::ToDeviceEvent, DeviceKeys},
     DeviceData, OlmMachine,
 };
@@ -299,3 +301,41 @@ async fn test_decrypt_to_device_message_with_unsigned_sender_keys() {
     assert_let!(ProcessedToDeviceEvent::UnableToDecrypt(event) = event);
     assert_eq!(event.get_field("type").unwrap(), Some("m.room.encrypted"));
 }
+
+#[async_test]
+async fn test_session_creation_limit() {
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    let limit = SessionCreationLimit::new(0, Duration::from_secs(60)).rejecting();
+    bob.set_session_creation_limit(Some(limit));
+    assert_eq!(bob.session_creation_limit(), Some(limit));
+
+    let stream = bob.store().suspicious_activity_stream();
+    pin_mut!(stream);
+
+    // Bob rejects the new session and reports Alice's device.
+    let event =
+        send_and_receive_encrypted_to_device_test_helper(&alice, &bob, "m.dummy", json!({})).await;
+    assert_matches!(event, ProcessedToDeviceEvent::UnableToDecrypt(_));
+
+    assert_let!(
+        Some(SuspiciousActivity::SessionSpam { sender, sender_key, limit: reported_limit }) =
+            stream.next().now_or_never().flatten()
+    );
+    assert_eq!(sender, alice.user_id());
+    assert_eq!(sender_key, alice.identity_keys().curve25519);
+    assert_eq!(reported_limit, limit);
+
+    // The device is only reported once.
+    let event =
+        send_and_receive_encrypted_to_device_test_helper(&alice, &bob, "m.dummy", json!({})).await;
+    assert_matches!(event, ProcessedToDeviceEvent::UnableToDecrypt(_));
+    assert_matches!(stream.next().now_or_never(), None);
+
+    // Without a limit, the session is created.
+    bob.set_session_creation_limit(None);
+    let event =
+        send_and_receive_encrypted_to_device_test_helper(&alice, &bob, "m.dummy", json!({})).await;
+    assert_matches!(event, ProcessedToDeviceEvent::Decrypted { .. });
+}
@@ -12,10 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+
 use assert_matches2::{assert_let, assert_matches};
 use insta::assert_json_snapshot;
-use matrix_sdk_common::deserialized_responses::{
-    AlgorithmInfo, ProcessedToDeviceEvent, VerificationLevel, VerificationState,
+use matrix_sdk_common::{
+    deserialized_responses::{
+        AlgorithmInfo, EncryptionInfo, ProcessedToDeviceEvent, VerificationLevel, VerificationState,
+    },
+    locks::Mutex,
 };
 use matrix_sdk_test::async_test;
 use ruma::{events::AnyToDeviceEvent, serde::Raw, to_device::DeviceIdOrAllDevices};
@@ -36,7 +41,8 @@ use crate::{
     },
     utilities::json_convert,
     verification::tests::bob_id,
-    DeviceData, EncryptionSyncChanges, LocalTrust, OlmError, OlmMachine,
+    CustomToDeviceEventHandler, DeviceData, EncryptionSyncChanges, LocalTrust, OlmError,
+    OlmMachine,
 };
 
 #[async_test]
@@ -136,6 +142,67 @@ async fn test_send_encrypted_to_device() {
     );
 }
 
+#[async_test]
+async fn test_replayed_sync_batch_is_skipped() {
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    let changed_devices = Default::default();
+    let one_time_keys_counts = Default::default();
+    let sync_changes = |event, next_batch_token: &str| EncryptionSyncChanges {
+        to_device_events: vec![event],
+        changed_devices: &changed_devices,
+        one_time_keys_counts: &one_time_keys_counts,
+        unused_fallback_keys: None,
+        next_batch_token: Some(next_batch_token.to_owned()),
+    };
+
+    // The first time the batch is received, its event is decrypted.
+    let event = encrypt_to_device_event(&alice, &bob, 0).await;
+    let (decrypted, _) = bob.receive_sync_changes(sync_changes(event.clone(), "t1")).await.unwrap();
+    assert_eq!(decrypted.len(), 1);
+    assert_matches!(&decrypted[0], ProcessedToDeviceEvent::Decrypted { .. });
+    assert_eq!(bob.store().next_batch_token().await.unwrap().as_deref(), Some("t1"));
+
+    // When the same batch is replayed, its event is skipped.
+    let (decrypted, _) = bob.receive_sync_changes(sync_changes(event, "t1")).await.unwrap();
+    assert!(decrypted.is_empty());
+
+    // The events of the next batch are decrypted again.
+    let event = encrypt_to_device_event(&alice, &bob, 1).await;
+    let (decrypted, _) = bob.receive_sync_changes(sync_changes(event, "t2")).await.unwrap();
+    assert_eq!(decrypted.len(), 1);
+    assert_matches!(&decrypted[0], ProcessedToDeviceEvent::Decrypted { .. });
+    assert_eq!(bob.store().next_batch_token().await.unwrap().as_deref(), Some("t2"));
+}
+
+/// Encrypt a custom to-device event from `alice` to `bob`, with the given
+/// index as content.
+async fn encrypt_to_device_event(
+    alice: &OlmMachine,
+    bob: &OlmMachine,
+    index: u32,
+) -> Raw<AnyToDeviceEvent> {
+    let device = alice.get_device(bob.user_id(), bob.device_id(), None).await.unwrap().unwrap();
+    let raw_encrypted = device
+        .encrypt_event_raw("m.new_device", &json!({ "index": index }))
+        .await
+        .expect("Should have encrypted the content");
+
+    let request = ToDeviceRequest::new(
+        bob.user_id(),
+        DeviceIdOrAllDevices::DeviceId(bob.device_id().to_owned()),
+        "m.room.encrypted",
+        raw_encrypted.cast(),
+    );
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        tests::to_device_requests_to_content(vec![request.into()]),
+    );
+
+    json_convert(&event).unwrap()
+}
+
 #[async_test]
 async fn test_receive_custom_encrypted_to_device_fails_if_device_unknown() {
     // When decrypting a custom to device, we expect the recipient to know the
@@ -528,3 +595,65 @@ async fn test_send_encrypted_to_device_no_session() {
 
     assert_matches!(encryption_result, Err(OlmError::MissingSession));
 }
+
+/// A [`CustomToDeviceEventHandler`] remembering the events it was given.
+#[derive(Debug, Default)]
+struct RecordingHandler {
+    events: Mutex<Vec<(Raw<AnyToDeviceEvent>, EncryptionInfo)>>,
+}
+
+impl CustomToDeviceEventHandler for RecordingHandler {
+    fn handle_event(&self, event: &Raw<AnyToDeviceEvent>, encryption_info: &EncryptionInfo) {
+        self.events.lock().push((event.clone(), encryption_info.clone()));
+    }
+}
+
+#[async_test]
+async fn test_custom_to_device_event_handler() {
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    let handler = Arc::new(RecordingHandler::default());
+    bob.register_custom_to_device_event_handler("com.example.provisioning", handler.clone());
+
+    let content = json!({ "token": "secret" });
+    send_and_receive_encrypted_to_device_test_helper(
+        &alice,
+        &bob,
+        "com.example.provisioning",
+        content.clone(),
+    )
+    .await;
+
+    // Events of other types aren't given to the handler.
+    send_and_receive_encrypted_to_device_test_helper(&alice, &bob, "com.example.other", json!({}))
+        .await;
+
+    {
+        let events = handler.events.lock();
+        assert_eq!(events.len(), 1);
+
+        let (event, encryption_info) = &events[0];
+        let event = event.deserialize_as::<Value>().unwrap();
+        assert_eq!(event["type"], "com.example.provisioning");
+        assert_eq!(event["content"], content);
+
+        assert_eq!(encryption_info.sender, alice.user_id().to_owned());
+        assert_eq!(encryption_info.sender_device, Some(alice.device_id().to_owned()));
+        assert_matches!(
+            &encryption_info.verification_state,
+            VerificationState::Unverified(VerificationLevel::UnsignedDevice)
+        );
+    }
+
+    // Once the handler is removed, it isn't called anymore.
+    assert!(bob.remove_custom_to_device_event_handler("com.example.provisioning").is_some());
+    send_and_receive_encrypted_to_device_test_helper(
+        &alice,
+        &bob,
+        "com.example.provisioning",
+        content,
+    )
+    .await;
+    assert_eq!(handler.events.lock().len(), 1);
+}
@@ -0,0 +1,326 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Batching of the to-device requests returned by
+//! [`OlmMachine::outgoing_requests()`].
+//!
+//! The different state machines each create their own, usually small,
+//! to-device requests. The [`ToDeviceBatcher`] merges pending requests of the
+//! same event type into fewer, larger requests, and remembers which requests
+//! were merged, so that marking the merged request as sent marks all the
+//! original requests as sent.
+//!
+//! [`OlmMachine::outgoing_requests()`]: crate::OlmMachine::outgoing_requests
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use matrix_sdk_common::locks::RwLock as StdRwLock;
+use ruma::{to_device::DeviceIdOrAllDevices, OwnedTransactionId, OwnedUserId, TransactionId};
+use tracing::trace;
+
+use crate::types::requests::{AnyOutgoingRequest, OutgoingRequest, ToDeviceRequest};
+
+/// The maximum number of messages a merged to-device request may contain.
+const MAX_MESSAGES_PER_REQUEST: usize = 100;
+
+/// Merges pending to-device requests into batches, see the module docs.
+#[derive(Debug, Default)]
+pub(crate) struct ToDeviceBatcher {
+    /// The batches we handed out which haven't been marked as sent yet, the
+    /// IDs of the merged requests mapped to the IDs of the original requests.
+    batches: StdRwLock<HashMap<OwnedTransactionId, Vec<OwnedTransactionId>>>,
+}
+
+/// A batch of to-device requests which is being built.
+struct Batch {
+    requests: Vec<OutgoingRequest>,
+    recipients: HashSet<(OwnedUserId, DeviceIdOrAllDevices)>,
+    message_count: usize,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Self { requests: Vec::new(), recipients: HashSet::new(), message_count: 0 }
+    }
+
+    /// Can the given request be merged into this batch?
+    ///
+    /// A request can't contain two messages for the same device, and the
+    /// requests of a batch need to share the event type.
+    fn accepts(&self, request: &ToDeviceRequest) -> bool {
+        let Some(first) = self.requests.first().and_then(to_device_request) else {
+            return true;
+        };
+
+        first.event_type == request.event_type
+            && self.message_count + request.message_count() <= MAX_MESSAGES_PER_REQUEST
+            && !recipients(request).any(|r| self.recipients.contains(&r))
+    }
+
+    fn push(&mut self, request: OutgoingRequest) {
+        if let Some(to_device) = to_device_request(&request) {
+            self.recipients.extend(recipients(to_device));
+            self.message_count += to_device.message_count();
+        }
+
+        self.requests.push(request);
+    }
+}
+
+fn to_device_request(request: &OutgoingRequest) -> Option<&ToDeviceRequest> {
+    match request.request() {
+        AnyOutgoingRequest::ToDeviceRequest(r) => Some(r),
+        _ => None,
+    }
+}
+
+fn recipients(
+    request: &ToDeviceRequest,
+) -> impl Iterator<Item = (OwnedUserId, DeviceIdOrAllDevices)> + '_ {
+    request.messages.iter().flat_map(|(user_id, messages)| {
+        messages.keys().map(move |device| (user_id.to_owned(), device.to_owned()))
+    })
+}
+
+impl ToDeviceBatcher {
+    /// Merge the to-device requests among the given requests into batches.
+    ///
+    /// The other requests are returned as they are, ahead of the to-device
+    /// requests. The to-device requests keep their relative order.
+    ///
+    /// Requests which were merged before and haven't been marked as sent or as
+    /// failed yet are merged the same way again, using the same request ID, so
+    /// that sending the request again doesn't deliver the messages twice.
+    pub fn batch(&self, requests: Vec<OutgoingRequest>) -> Vec<OutgoingRequest> {
+        let (mut outgoing, to_device): (Vec<_>, Vec<_>) =
+            requests.into_iter().partition(|r| to_device_request(r).is_none());
+
+        let mut pending: BTreeMap<OwnedTransactionId, OutgoingRequest> =
+            to_device.iter().map(|r| (r.request_id.clone(), r.clone())).collect();
+
+        let mut batches = self.batches.write();
+
+        // First re-create the batches we already handed out. Requests which are gone,
+        // e.g. because they were marked as sent before marking the rest of the batch
+        // failed, are left out, the batch keeps its ID so the server doesn't deliver
+        // the messages twice. Batches without any remaining requests are forgotten.
+        batches.retain(|_, request_ids| request_ids.iter().any(|id| pending.contains_key(id)));
+
+        for (batch_id, request_ids) in batches.iter() {
+            let requests = request_ids.iter().filter_map(|id| pending.remove(id)).collect();
+            outgoing.push(merge(batch_id.to_owned(), requests));
+        }
+
+        // Then batch up the requests we didn't see before.
+        let mut new_batches = Vec::new();
+        let mut current = Batch::new();
+
+        for request in to_device.into_iter().filter(|r| pending.contains_key(&r.request_id)) {
+            if to_device_request(&request).is_some_and(|r| !current.accepts(r)) {
+                new_batches.push(std::mem::replace(&mut current, Batch::new()));
+            }
+
+            current.push(request);
+        }
+
+        new_batches.push(current);
+
+        for batch in new_batches {
+            match batch.requests.len() {
+                0 => {}
+                // There's nothing to merge, send out the request as it is.
+                1 => outgoing.extend(batch.requests),
+                _ => {
+                    let batch_id = TransactionId::new();
+                    let request_ids = batch.requests.iter().map(|r| r.request_id.clone()).collect();
+
+                    trace!(?batch_id, ?request_ids, "Merged to-device requests into a batch");
+
+                    batches.insert(batch_id.clone(), request_ids);
+                    outgoing.push(merge(batch_id, batch.requests));
+                }
+            }
+        }
+
+        outgoing
+    }
+
+    /// Get the IDs of the requests the request with the given ID consists of.
+    ///
+    /// This is the IDs of the original requests if the given ID belongs to a
+    /// batch, otherwise it's the given ID.
+    pub fn request_ids(&self, request_id: &TransactionId) -> Vec<OwnedTransactionId> {
+        self.batches.read().get(request_id).cloned().unwrap_or_else(|| vec![request_id.to_owned()])
+    }
+
+    /// Forget the batch with the given ID, once all of its requests have been
+    /// marked as sent.
+    pub fn mark_as_sent(&self, request_id: &TransactionId) {
+        self.batches.write().remove(request_id);
+    }
+
+    /// Forget the batch with the given ID, once all of its requests have been
+    /// marked as failed.
+    ///
+    /// The requests which are still pending are merged into a new batch the
+    /// next time they are returned.
+    pub fn mark_as_failed(&self, request_id: &TransactionId) {
+        if self.batches.write().remove(request_id).is_some() {
+            trace!(batch_id = ?request_id, "Forgetting a failed batch of to-device requests");
+        }
+    }
+}
+
+/// Merge the given to-device requests, which need to share their event type and
+/// must not have messages for the same device, into a single request.
+fn merge(batch_id: OwnedTransactionId, requests: Vec<OutgoingRequest>) -> OutgoingRequest {
+    let mut merged: Option<ToDeviceRequest> = None;
+
+    for request in requests.iter().filter_map(to_device_request) {
+        match &mut merged {
+            Some(merged) => {
+                for (user_id, messages) in &request.messages {
+                    merged
+                        .messages
+                        .entry(user_id.to_owned())
+                        .or_default()
+                        .extend(messages.iter().map(|(d, c)| (d.to_owned(), c.to_owned())));
+                }
+            }
+            None => {
+                merged = Some(ToDeviceRequest {
+                    event_type: request.event_type.clone(),
+                    txn_id: batch_id.clone(),
+                    messages: request.messages.clone(),
+                })
+            }
+        }
+    }
+
+    let merged = merged.expect("A batch should contain at least one to-device request");
+
+    OutgoingRequest { request_id: batch_id, request: Arc::new(merged.into()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ruma::{events::AnyToDeviceEventContent, serde::Raw, user_id, OwnedDeviceId};
+    use serde_json::json;
+
+    use super::{ToDeviceBatcher, MAX_MESSAGES_PER_REQUEST};
+    use crate::types::requests::{AnyOutgoingRequest, OutgoingRequest, ToDeviceRequest};
+
+    fn to_device_request(device: &str, event_type: &str) -> OutgoingRequest {
+        let content: Raw<AnyToDeviceEventContent> =
+            Raw::new(&json!({ "body": device })).unwrap().cast();
+        let request = ToDeviceRequest::new(
+            user_id!("@bob:localhost"),
+            OwnedDeviceId::from(device),
+            event_type,
+            content,
+        );
+
+        OutgoingRequest { request_id: request.txn_id.clone(), request: Arc::new(request.into()) }
+    }
+
+    fn message_count(request: &OutgoingRequest) -> usize {
+        match request.request() {
+            AnyOutgoingRequest::ToDeviceRequest(r) => r.message_count(),
+            _ => panic!("Expected a to-device request"),
+        }
+    }
+
+    #[test]
+    fn test_batching() {
+        let batcher = ToDeviceBatcher::default();
+
+        let requests = vec![
+            to_device_request("A", "m.room.encrypted"),
+            to_device_request("B", "m.room.encrypted"),
+            // The same device can't be part of a request twice.
+            to_device_request("A", "m.room.encrypted"),
+            // Neither can a different event type.
+            to_device_request("C", "m.room_key_request"),
+        ];
+        let request_ids: Vec<_> = requests.iter().map(|r| r.request_id.clone()).collect();
+
+        let batched = batcher.batch(requests.clone());
+
+        assert_eq!(batched.len(), 3);
+        assert_eq!(message_count(&batched[0]), 2);
+        assert_eq!(batched[1].request_id, request_ids[2]);
+        assert_eq!(batched[2].request_id, request_ids[3]);
+
+        // Retrying gives us the same batch.
+        let retried = batcher.batch(requests.clone());
+        assert_eq!(retried[0].request_id, batched[0].request_id);
+
+        // If only a part of the batch is still pending, e.g. because marking the
+        // batch as sent failed partway through, the batch keeps its ID.
+        let retried = batcher.batch(requests[1..].to_vec());
+        assert_eq!(retried[0].request_id, batched[0].request_id);
+        assert_eq!(message_count(&retried[0]), 1);
+
+        // The batch consists of the original requests until it's marked as sent.
+        assert_eq!(batcher.request_ids(&batched[0].request_id), request_ids[0..2].to_vec());
+        assert_eq!(batcher.request_ids(&request_ids[2]), [request_ids[2].clone()]);
+
+        batcher.mark_as_sent(&batched[0].request_id);
+        assert_eq!(batcher.request_ids(&batched[0].request_id), [batched[0].request_id.clone()]);
+    }
+
+    #[test]
+    fn test_failed_batch_is_forgotten() {
+        let batcher = ToDeviceBatcher::default();
+
+        let requests = vec![
+            to_device_request("A", "m.room.encrypted"),
+            to_device_request("B", "m.room.encrypted"),
+        ];
+        let batched = batcher.batch(requests.clone());
+        assert_eq!(batched.len(), 1);
+
+        batcher.mark_as_failed(&batched[0].request_id);
+        assert_eq!(batcher.request_ids(&batched[0].request_id), [batched[0].request_id.clone()]);
+
+        // The requests are merged into a new batch, the failed one isn't kept around.
+        let retried = batcher.batch(requests);
+        assert_eq!(retried.len(), 1);
+        assert_ne!(retried[0].request_id, batched[0].request_id);
+        assert_eq!(batcher.batches.read().len(), 1);
+    }
+
+    #[test]
+    fn test_batch_size_limit() {
+        let batcher = ToDeviceBatcher::default();
+
+        let requests: Vec<_> = (0..MAX_MESSAGES_PER_REQUEST + 1)
+            .map(|i| to_device_request(&format!("DEVICE{i}"), "m.room.encrypted"))
+            .collect();
+
+        let batched = batcher.batch(requests);
+
+        assert_eq!(batched.len(), 2);
+        assert_eq!(message_count(&batched[0]), MAX_MESSAGES_PER_REQUEST);
+        assert_eq!(message_count(&batched[1]), 1);
+
+        // A single request isn't merged, so it keeps its ID.
+        assert_eq!(batcher.request_ids(&batched[1].request_id), [batched[1].request_id.clone()]);
+    }
+}
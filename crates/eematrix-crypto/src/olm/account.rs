@@ -17,7 +17,6 @@ use std::{
     fmt,
     ops::{Deref, Not as _},
     sync::Arc,
-    time::Duration,
 };
 
 use hkdf::Hkdf;
@@ -70,7 +69,7 @@ use crate::{
     identities::DeviceData,
     olm::SenderData,
     store::{
-        types::{Changes, DeviceChanges},
+        types::{Changes, DeviceChanges, FallbackKeyRotationPolicy},
         Store,
     },
     types::{
@@ -210,26 +209,45 @@ impl StaticAccountData {
         room_id: &RoomId,
         settings: EncryptionSettings,
         own_sender_data: SenderData,
+    ) -> Result<(OutboundGroupSession, InboundGroupSession), MegolmSessionCreationError> {
+        self.create_group_session_pair_at(
+            room_id,
+            settings,
+            own_sender_data,
+            SecondsSinceUnixEpoch::now(),
+        )
+        .await
+    }
+
+    /// Create a group session pair, created at the given time, see
+    /// [`StaticAccountData::create_group_session_pair()`].
+    pub(crate) async fn create_group_session_pair_at(
+        &self,
+        room_id: &RoomId,
+        settings: EncryptionSettings,
+        own_sender_data: SenderData,
+        now: SecondsSinceUnixEpoch,
     ) -> Result<(OutboundGroupSession, InboundGroupSession), MegolmSessionCreationError> {
         trace!(?room_id, algorithm = settings.algorithm.as_str(), "Creating a new room key");
 
         let visibility = settings.history_visibility.clone();
+        let shared_history = settings.shared_history();
         let algorithm = settings.algorithm.to_owned();
 
-        let outbound = OutboundGroupSession::new(
+        let outbound = OutboundGroupSession::new_at(
             self.device_id.clone(),
             self.identity_keys.clone(),
             room_id,
             settings,
+            now,
         )?;
 
         let identity_keys = &self.identity_keys;
 
         let sender_key = identity_keys.curve25519;
         let signing_key = identity_keys.ed25519;
-        let shared_history = shared_history_from_history_visibility(&visibility);
 
-        let inbound = InboundGroupSession::new(
+        let mut inbound = InboundGroupSession::new(
             sender_key,
             signing_key,
             room_id,
@@ -239,6 +257,7 @@ impl StaticAccountData {
             Some(visibility),
             shared_history,
         )?;
+        inbound.set_received_at(now);
 
         Ok((outbound, inbound))
     }
@@ -355,6 +374,9 @@ pub struct Account {
     /// needs to set this for us, depending on the count we will suggest the
     /// client to upload new keys.
     uploaded_signed_key_count: u64,
+    /// The number of one-time keys we have published over the lifetime of the
+    /// account.
+    published_one_time_key_count: u64,
     /// The timestamp of the last time we generated a fallback key. Fallback
     /// keys are rotated in a time-based manner. This field records when we
     /// either generated our first fallback key or rotated one.
@@ -363,6 +385,35 @@ pub struct Account {
     /// from a `AccountPickle` that didn't use time-based fallback key
     /// rotation.
     fallback_creation_timestamp: Option<MilliSecondsSinceUnixEpoch>,
+    /// The policy deciding when our fallback key is rotated.
+    fallback_key_rotation_policy: FallbackKeyRotationPolicy,
+    /// The public part of our most recent fallback key.
+    ///
+    /// Will be `None` if we never created a fallback key, or if we're migrating
+    /// from a `PickledAccount` that didn't track it, in which case the uses of
+    /// the fallback key aren't counted until it's rotated.
+    fallback_key: Option<Curve25519PublicKey>,
+    /// The number of Olm sessions other devices created using our most recent
+    /// fallback key.
+    fallback_key_use_count: u64,
+}
+
+/// The state of the one-time key pool of an [`Account`], see
+/// [`OlmMachine::one_time_key_pool_health()`].
+///
+/// [`OlmMachine::one_time_key_pool_health()`]: crate::OlmMachine::one_time_key_pool_health
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OneTimeKeyPoolHealth {
+    /// The number of one-time keys which were published over the lifetime of
+    /// the account.
+    pub published: u64,
+    /// The number of our one-time keys the server holds, as last reported by
+    /// the server.
+    pub remaining_server_side: u64,
+    /// The number of one-time keys which were generated but not uploaded yet.
+    pub unpublished: u64,
+    /// The maximum number of one-time keys the account can hold.
+    pub max_one_time_keys: u64,
 }
 
 impl Deref for Account {
@@ -393,6 +444,10 @@ pub struct PickledAccount {
     pub dehydrated: bool,
     /// The number of uploaded one-time keys we have on the server.
     pub uploaded_signed_key_count: u64,
+    /// The number of one-time keys we have published over the lifetime of the
+    /// account.
+    #[serde(default)]
+    pub published_one_time_key_count: u64,
     /// The local time creation of this account (milliseconds since epoch), used
     /// as creation time of own device
     #[serde(default = "default_account_creation_time")]
@@ -400,6 +455,15 @@ pub struct PickledAccount {
     /// The timestamp of the last time we generated a fallback key.
     #[serde(default)]
     pub fallback_key_creation_timestamp: Option<MilliSecondsSinceUnixEpoch>,
+    /// The policy deciding when our fallback key is rotated.
+    #[serde(default)]
+    pub fallback_key_rotation_policy: FallbackKeyRotationPolicy,
+    /// The public part of our most recent fallback key.
+    #[serde(default)]
+    pub fallback_key: Option<Curve25519PublicKey>,
+    /// The number of Olm sessions created using our most recent fallback key.
+    #[serde(default)]
+    pub fallback_key_use_count: u64,
 }
 
 fn default_account_creation_time() -> MilliSecondsSinceUnixEpoch {
@@ -451,7 +515,11 @@ impl Account {
             inner: Box::new(account),
             shared: false,
             uploaded_signed_key_count: 0,
+            published_one_time_key_count: 0,
             fallback_creation_timestamp: None,
+            fallback_key_rotation_policy: Default::default(),
+            fallback_key: None,
+            fallback_key_use_count: 0,
         }
     }
 
@@ -603,33 +671,75 @@ impl Account {
     }
 
     /// Generate a new fallback key iff a unpublished one isn't already inside
-    /// of vodozemac and if the currently active one expired.
+    /// of vodozemac and if the currently active one needs to be rotated
+    /// according to the [`FallbackKeyRotationPolicy`].
     ///
     /// The former is checked using [`Account::fallback_key().is_empty()`],
     /// which is a hashmap that gets cleared by the
     /// [`Account::mark_keys_as_published()`] call.
     pub(crate) fn generate_fallback_key_if_needed(&mut self) {
-        if self.inner.fallback_key().is_empty() && self.fallback_key_expired() {
-            let removed_fallback_key = self.inner.generate_fallback_key();
-            self.fallback_creation_timestamp = Some(MilliSecondsSinceUnixEpoch::now());
+        if self.inner.fallback_key().is_empty()
+            && (self.fallback_key_expired() || self.fallback_key_used_up())
+        {
+            self.rotate_fallback_key();
+        }
+    }
 
-            debug!(
-                ?removed_fallback_key,
-                "The fallback key either expired or we didn't have one: generated a new fallback key.",
-            );
+    /// Generate a new fallback key, no matter if the currently active one
+    /// needs to be rotated.
+    ///
+    /// Nothing happens if there already is a new fallback key which wasn't
+    /// published yet. Rotating again would make vodozemac forget the fallback
+    /// key which is still published on the server, so the Olm sessions other
+    /// devices create using it would fail.
+    pub(crate) fn rotate_fallback_key(&mut self) {
+        if !self.inner.fallback_key().is_empty() {
+            debug!("Not rotating the fallback key, the new one wasn't published yet");
+            return;
         }
+
+        let removed_fallback_key = self.inner.generate_fallback_key();
+
+        self.fallback_creation_timestamp = Some(MilliSecondsSinceUnixEpoch::now());
+        self.fallback_key = self.inner.fallback_key().into_values().next();
+        self.fallback_key_use_count = 0;
+
+        debug!(
+            ?removed_fallback_key,
+            "The fallback key either needed to be rotated or we didn't have one: generated a new \
+             fallback key.",
+        );
+    }
+
+    /// Get the policy deciding when our fallback key is rotated.
+    pub fn fallback_key_rotation_policy(&self) -> &FallbackKeyRotationPolicy {
+        &self.fallback_key_rotation_policy
+    }
+
+    /// Set the policy deciding when our fallback key is rotated.
+    pub(crate) fn set_fallback_key_rotation_policy(&mut self, policy: FallbackKeyRotationPolicy) {
+        self.fallback_key_rotation_policy = policy;
+    }
+
+    /// Get the number of Olm sessions other devices created using our most
+    /// recent fallback key.
+    pub fn fallback_key_use_count(&self) -> u64 {
+        self.fallback_key_use_count
+    }
+
+    /// Check if our most recent fallback key has been used by as many Olm
+    /// sessions as the [`FallbackKeyRotationPolicy`] allows.
+    fn fallback_key_used_up(&self) -> bool {
+        self.fallback_key_rotation_policy
+            .max_uses
+            .is_some_and(|max_uses| self.fallback_key_use_count >= max_uses)
     }
 
     /// Check if our most recent fallback key has expired.
     ///
-    /// We consider the fallback key to be expired if it's older than a week.
-    /// This is the lower bound for the recommended signed pre-key bundle
-    /// rotation interval in the X3DH spec[1].
-    ///
-    /// [1]: https://signal.org/docs/specifications/x3dh/#publishing-keys
+    /// We consider the fallback key to be expired if it's older than the
+    /// maximum age of the [`FallbackKeyRotationPolicy`], a week by default.
     fn fallback_key_expired(&self) -> bool {
-        const FALLBACK_KEY_MAX_AGE: Duration = Duration::from_secs(3600 * 24 * 7);
-
         if let Some(time) = self.fallback_creation_timestamp {
             // `to_system_time()` returns `None` if the the UNIX_EPOCH + `time` doesn't fit
             // into a i64. This will likely never happen, but let's rotate the
@@ -648,8 +758,8 @@ impl Account {
             // Alright, our times are normal and we know how much time elapsed since the
             // last time we created/rotated a fallback key.
             //
-            // If the key is older than a week, then we rotate it.
-            elapsed > FALLBACK_KEY_MAX_AGE
+            // If the key is older than the maximum age, then we rotate it.
+            elapsed > self.fallback_key_rotation_policy.max_age
         } else {
             // We never created a fallback key, or we're migrating to the time-based
             // fallback key rotation, so let's generate a new fallback key.
@@ -677,9 +787,20 @@ impl Account {
 
     /// Mark the current set of one-time keys as being published.
     pub fn mark_keys_as_published(&mut self) {
+        self.published_one_time_key_count += self.one_time_keys().len() as u64;
         self.inner.mark_keys_as_published();
     }
 
+    /// Get the state of the one-time key pool of the account.
+    pub fn one_time_key_pool_health(&self) -> OneTimeKeyPoolHealth {
+        OneTimeKeyPoolHealth {
+            published: self.published_one_time_key_count,
+            remaining_server_side: self.uploaded_key_count(),
+            unpublished: self.one_time_keys().len() as u64,
+            max_one_time_keys: self.max_one_time_keys() as u64,
+        }
+    }
+
     /// Sign the given string using the accounts signing key.
     ///
     /// Returns the signature as a base64 encoded string.
@@ -698,8 +819,12 @@ impl Account {
             shared: self.shared(),
             dehydrated: self.static_data.dehydrated,
             uploaded_signed_key_count: self.uploaded_key_count(),
+            published_one_time_key_count: self.published_one_time_key_count,
             creation_local_time: self.static_data.creation_local_time,
             fallback_key_creation_timestamp: self.fallback_creation_timestamp,
+            fallback_key_rotation_policy: self.fallback_key_rotation_policy.clone(),
+            fallback_key: self.fallback_key,
+            fallback_key_use_count: self.fallback_key_use_count,
         }
     }
 
@@ -780,7 +905,11 @@ impl Account {
             inner: Box::new(account),
             shared: pickle.shared,
             uploaded_signed_key_count: pickle.uploaded_signed_key_count,
+            published_one_time_key_count: pickle.published_one_time_key_count,
             fallback_creation_timestamp: pickle.fallback_key_creation_timestamp,
+            fallback_key_rotation_policy: pickle.fallback_key_rotation_policy,
+            fallback_key: pickle.fallback_key,
+            fallback_key_use_count: pickle.fallback_key_use_count,
         })
     }
 
@@ -956,9 +1085,28 @@ impl Account {
         fallback_used: bool,
         our_device_keys: DeviceKeys,
     ) -> Session {
-        let session = self.inner.create_outbound_session(config, identity_key, one_time_key);
+        self.create_outbound_session_helper_at(
+            config,
+            identity_key,
+            one_time_key,
+            fallback_used,
+            our_device_keys,
+            SecondsSinceUnixEpoch::now(),
+        )
+    }
 
-        let now = SecondsSinceUnixEpoch::now();
+    /// Create a new session with another account given a one-time key, created
+    /// at the given time, see [`Account::create_outbound_session_helper()`].
+    fn create_outbound_session_helper_at(
+        &self,
+        config: SessionConfig,
+        identity_key: Curve25519PublicKey,
+        one_time_key: Curve25519PublicKey,
+        fallback_used: bool,
+        our_device_keys: DeviceKeys,
+        now: SecondsSinceUnixEpoch,
+    ) -> Session {
+        let session = self.inner.create_outbound_session(config, identity_key, one_time_key);
         let session_id = session.session_id();
 
         Session {
@@ -1025,6 +1173,25 @@ impl Account {
         device: &DeviceData,
         key_map: &OneTimeKeys,
         our_device_keys: DeviceKeys,
+    ) -> Result<Session, SessionCreationError> {
+        self.create_outbound_session_at(
+            device,
+            key_map,
+            our_device_keys,
+            SecondsSinceUnixEpoch::now(),
+        )
+    }
+
+    /// Create a new session with another account given a one-time key and a
+    /// device, created at the given time, see
+    /// [`Account::create_outbound_session()`].
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn create_outbound_session_at(
+        &self,
+        device: &DeviceData,
+        key_map: &OneTimeKeys,
+        our_device_keys: DeviceKeys,
+        now: SecondsSinceUnixEpoch,
     ) -> Result<Session, SessionCreationError> {
         let pre_key_bundle = Self::find_pre_key_bundle(device, key_map)?;
 
@@ -1049,12 +1216,13 @@ impl Account {
                 let one_time_key = key.key();
                 let config = device.olm_session_config();
 
-                Ok(self.create_outbound_session_helper(
+                Ok(self.create_outbound_session_helper_at(
                     config,
                     identity_key,
                     one_time_key,
                     is_fallback,
                     our_device_keys,
+                    now,
                 ))
             }
         }
@@ -1079,12 +1247,36 @@ impl Account {
         their_identity_key: Curve25519PublicKey,
         our_device_keys: DeviceKeys,
         message: &PreKeyMessage,
+    ) -> Result<InboundCreationResult, SessionCreationError> {
+        self.create_inbound_session_at(
+            their_identity_key,
+            our_device_keys,
+            message,
+            SecondsSinceUnixEpoch::now(),
+        )
+    }
+
+    /// Create a new session with another account given a pre-key Olm message,
+    /// created at the given time, see [`Account::create_inbound_session()`].
+    pub(crate) fn create_inbound_session_at(
+        &mut self,
+        their_identity_key: Curve25519PublicKey,
+        our_device_keys: DeviceKeys,
+        message: &PreKeyMessage,
+        now: SecondsSinceUnixEpoch,
     ) -> Result<InboundCreationResult, SessionCreationError> {
         Span::current().record("session_id", debug(message.session_id()));
         trace!("Creating a new Olm session from a pre-key message");
 
         let result = self.inner.create_inbound_session(their_identity_key, message)?;
-        let now = SecondsSinceUnixEpoch::now();
+
+        if self.fallback_key == Some(message.one_time_key()) {
+            self.fallback_key_use_count += 1;
+            debug!(
+                use_count = self.fallback_key_use_count,
+                "The new Olm session was created using our fallback key"
+            );
+        }
         let session_id = result.session.session_id();
 
         debug!(session=?result.session, "Decrypted an Olm message from a new Olm session");
@@ -1345,17 +1537,28 @@ impl Account {
                     }
                 }
 
+                let now: u64 = store.clock().now().get().into();
+                let spam_detector = store.session_spam_detector();
+
+                if !spam_detector.check(sender, sender_key, now) {
+                    return Err(OlmError::SessionCreationThrottled(sender.to_owned(), sender_key));
+                }
+
                 let device_keys = store.get_own_device().await?.as_device_keys().clone();
-                let result =
-                    match self.create_inbound_session(sender_key, device_keys, prekey_message) {
-                        Ok(r) => r,
-                        Err(e) => {
-                            warn!(
-                                "Failed to create a new Olm session from a pre-key message: {e:?}"
-                            );
-                            return Err(OlmError::SessionWedged(sender.to_owned(), sender_key));
-                        }
-                    };
+                let result = match self.create_inbound_session_at(
+                    sender_key,
+                    device_keys,
+                    prekey_message,
+                    store.clock().now_secs(),
+                ) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Failed to create a new Olm session from a pre-key message: {e:?}");
+                        return Err(OlmError::SessionWedged(sender.to_owned(), sender_key));
+                    }
+                };
+
+                spam_detector.record(sender_key, now);
 
                 // We need to add the new session to the session cache, otherwise
                 // we might try to create the same session again.
@@ -1721,6 +1924,7 @@ mod tests {
     use super::Account;
     use crate::{
         olm::{account::shared_history_from_history_visibility, SignedJsonObject},
+        store::types::FallbackKeyRotationPolicy,
         types::{DeviceKeys, SignedKey},
         DeviceData, EncryptionSettings,
     };
@@ -1836,6 +2040,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fallback_key_rotation_policy() {
+        let mut account = Account::with_device_id(user_id(), device_id());
+        let one_time_keys = BTreeMap::from([(OneTimeKeyAlgorithm::SignedCurve25519, 50u8.into())]);
+
+        account.set_fallback_key_rotation_policy(FallbackKeyRotationPolicy {
+            max_uses: Some(2),
+            ..Default::default()
+        });
+
+        account.update_key_counts(&one_time_keys, Some(&[]));
+        let (_, _, fallback_keys) = account.keys_for_upload();
+        assert!(!fallback_keys.is_empty(), "We should upload our initial fallback key");
+        let first_fallback_key = account.fallback_key;
+        assert!(first_fallback_key.is_some());
+        account.mark_keys_as_published();
+
+        account.fallback_key_use_count = 1;
+        account.update_key_counts(&one_time_keys, Some(&[]));
+        let (_, _, fallback_keys) = account.keys_for_upload();
+        assert!(
+            fallback_keys.is_empty(),
+            "The fallback key shouldn't be rotated before it was used as often as the policy allows"
+        );
+
+        account.fallback_key_use_count = 2;
+        account.update_key_counts(&one_time_keys, Some(&[]));
+        let (_, _, fallback_keys) = account.keys_for_upload();
+        assert!(!fallback_keys.is_empty(), "A used up fallback key should be rotated");
+        assert_ne!(account.fallback_key, first_fallback_key);
+        assert_eq!(account.fallback_key_use_count(), 0);
+
+        // The policy and the state of the fallback key survive a pickling roundtrip.
+        let account = Account::from_pickle(account.pickle()).unwrap();
+        assert_eq!(account.fallback_key_rotation_policy().max_uses, Some(2));
+        assert_ne!(account.fallback_key, first_fallback_key);
+        assert!(account.fallback_key.is_some());
+    }
+
     #[test]
     fn test_fallback_key_signing() -> Result<()> {
         let key = vodozemac::Curve25519PublicKey::from_base64(
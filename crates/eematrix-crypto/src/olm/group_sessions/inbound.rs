@@ -24,7 +24,7 @@ use std::{
 
 use ruma::{
     events::room::history_visibility::HistoryVisibility, serde::JsonObject, DeviceKeyAlgorithm,
-    OwnedRoomId, RoomId,
+    OwnedRoomId, RoomId, SecondsSinceUnixEpoch,
 };
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
@@ -45,7 +45,7 @@ use crate::types::events::room_key::RoomKeyContent;
 use crate::{
     error::{EventError, MegolmResult},
     types::{
-        deserialize_curve_key,
+        deserialize_curve_key, deserialize_curve_key_vec,
         events::{
             forwarded_room_key::{
                 ForwardedMegolmV1AesSha2Content, ForwardedMegolmV2AesSha2Content,
@@ -56,7 +56,7 @@ use crate::{
             room_key,
         },
         room_history::HistoricRoomKey,
-        serialize_curve_key, EventEncryptionAlgorithm, SigningKeys,
+        serialize_curve_key, serialize_curve_key_vec, EventEncryptionAlgorithm, SigningKeys,
     },
 };
 // TODO: add creation times to the inbound group sessions so we can export
@@ -217,6 +217,15 @@ pub struct InboundGroupSession {
     ///
     /// [MSC3061]: https://github.com/matrix-org/matrix-spec-proposals/pull/3061
     shared_history: bool,
+
+    /// When we received this [`InboundGroupSession`], if known. Sessions that
+    /// were stored before we started to record this don't have a timestamp.
+    received_at: Option<SecondsSinceUnixEpoch>,
+
+    /// The Curve25519 keys of the devices which forwarded this
+    /// [`InboundGroupSession`] before it reached us, in forwarding order.
+    /// Empty if we received the session directly from its creator.
+    forwarding_chain: Arc<Vec<Curve25519PublicKey>>,
 }
 
 impl InboundGroupSession {
@@ -291,6 +300,8 @@ impl InboundGroupSession {
             algorithm: encryption_algorithm.into(),
             backed_up: AtomicBool::new(false).into(),
             shared_history,
+            received_at: Some(SecondsSinceUnixEpoch::now()),
+            forwarding_chain: Default::default(),
         })
     }
 
@@ -356,6 +367,8 @@ impl InboundGroupSession {
             history_visibility: self.history_visibility.as_ref().clone(),
             algorithm: (*self.algorithm).to_owned(),
             shared_history: self.shared_history,
+            received_at: self.received_at,
+            forwarding_chain: self.forwarding_chain.as_ref().clone(),
         }
     }
 
@@ -405,10 +418,11 @@ impl InboundGroupSession {
             room_id: self.room_id().to_owned(),
             sender_key: self.creator_info.curve25519_key,
             session_id: self.session_id().to_owned(),
-            forwarding_curve25519_key_chain: vec![],
+            forwarding_curve25519_key_chain: self.forwarding_chain.as_ref().clone(),
             sender_claimed_keys: (*self.creator_info.signing_keys).clone(),
             session_key,
             shared_history: self.shared_history,
+            sender_data: Some(self.sender_data.clone()),
         }
     }
 
@@ -435,6 +449,8 @@ impl InboundGroupSession {
             history_visibility,
             algorithm,
             shared_history,
+            received_at,
+            forwarding_chain,
         } = pickle;
 
         let session: InnerSession = pickle.into();
@@ -456,6 +472,8 @@ impl InboundGroupSession {
             algorithm: algorithm.into(),
             imported,
             shared_history,
+            received_at,
+            forwarding_chain: forwarding_chain.into(),
         })
     }
 
@@ -486,6 +504,50 @@ impl InboundGroupSession {
         self.imported
     }
 
+    /// When did we receive this session?
+    ///
+    /// Returns `None` for sessions which were stored before we started to
+    /// record when sessions are received.
+    pub fn received_at(&self) -> Option<SecondsSinceUnixEpoch> {
+        self.received_at
+    }
+
+    /// Override when we received this session, for example with the time of
+    /// a custom [`Clock`](crate::clock::Clock).
+    pub(crate) fn set_received_at(&mut self, received_at: SecondsSinceUnixEpoch) {
+        self.received_at = Some(received_at);
+    }
+
+    /// The Curve25519 keys of the devices which forwarded this session before
+    /// it reached us, the device that sent it to us being the last one.
+    ///
+    /// Empty if we received the session directly from its creator. Sessions
+    /// imported from a file or the server-side backup keep the chain of the
+    /// exported session.
+    pub fn forwarding_chain(&self) -> &[Curve25519PublicKey] {
+        &self.forwarding_chain
+    }
+
+    /// The number of times this session has been forwarded before it reached
+    /// us.
+    ///
+    /// The more often a session has been forwarded, the more devices had to be
+    /// trusted to pass it on faithfully, so clients might want to treat
+    /// deeply forwarded sessions as less trustworthy.
+    pub fn forwarding_depth(&self) -> usize {
+        self.forwarding_chain.len()
+    }
+
+    /// Record that this session was forwarded to us by the device with the
+    /// given Curve25519 key.
+    pub(crate) fn with_forwarder(mut self, forwarder: Curve25519PublicKey) -> Self {
+        let mut forwarding_chain = self.forwarding_chain.as_ref().clone();
+        forwarding_chain.push(forwarder);
+        self.forwarding_chain = forwarding_chain.into();
+
+        self
+    }
+
     /// Check if the [`InboundGroupSession`] is better than the given other
     /// [`InboundGroupSession`]
     pub async fn compare(&self, other: &InboundGroupSession) -> SessionOrdering {
@@ -666,6 +728,18 @@ pub struct PickledInboundGroupSession {
     /// [MSC3061]: https://github.com/matrix-org/matrix-spec-proposals/pull/3061
     #[serde(default)]
     pub shared_history: bool,
+    /// When we received the session, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<SecondsSinceUnixEpoch>,
+    /// The Curve25519 keys of the devices which forwarded the session before
+    /// it reached us.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_curve_key_vec",
+        serialize_with = "serialize_curve_key_vec"
+    )]
+    pub forwarding_chain: Vec<Curve25519PublicKey>,
 }
 
 fn default_algorithm() -> EventEncryptionAlgorithm {
@@ -706,6 +780,8 @@ impl TryFrom<&HistoricRoomKey> for InboundGroupSession {
             algorithm: algorithm.to_owned().into(),
             backed_up: AtomicBool::from(false).into(),
             shared_history: true,
+            received_at: Some(SecondsSinceUnixEpoch::now()),
+            forwarding_chain: Default::default(),
         })
     }
 }
@@ -721,8 +797,11 @@ impl TryFrom<&ExportedRoomKey> for InboundGroupSession {
             session_id,
             session_key,
             sender_claimed_keys,
-            forwarding_curve25519_key_chain: _,
+            forwarding_curve25519_key_chain,
             shared_history,
+            // Anyone who knows the passphrase of a key export can put whatever
+            // they like in here, don't let it vouch for the sender.
+            sender_data: _,
         } = key;
 
         let config = OutboundGroupSession::session_config(algorithm)?;
@@ -736,8 +815,6 @@ impl TryFrom<&ExportedRoomKey> for InboundGroupSession {
                 curve25519_key: *sender_key,
                 signing_keys: sender_claimed_keys.to_owned().into(),
             },
-            // TODO: In future, exported keys should contain sender data that we can use here.
-            // See https://github.com/matrix-org/matrix-rust-sdk/issues/3548
             sender_data: SenderData::default(),
             history_visibility: None.into(),
             first_known_index,
@@ -746,6 +823,8 @@ impl TryFrom<&ExportedRoomKey> for InboundGroupSession {
             algorithm: algorithm.to_owned().into(),
             backed_up: AtomicBool::from(false).into(),
             shared_history: *shared_history,
+            received_at: Some(SecondsSinceUnixEpoch::now()),
+            forwarding_chain: forwarding_curve25519_key_chain.clone().into(),
         })
     }
 }
@@ -777,6 +856,8 @@ impl From<&ForwardedMegolmV1AesSha2Content> for InboundGroupSession {
             algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2.into(),
             backed_up: AtomicBool::from(false).into(),
             shared_history: false,
+            received_at: Some(SecondsSinceUnixEpoch::now()),
+            forwarding_chain: value.forwarding_curve25519_key_chain.clone().into(),
         }
     }
 }
@@ -804,6 +885,8 @@ impl From<&ForwardedMegolmV2AesSha2Content> for InboundGroupSession {
             algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2.into(),
             backed_up: AtomicBool::from(false).into(),
             shared_history: false,
+            received_at: Some(SecondsSinceUnixEpoch::now()),
+            forwarding_chain: Default::default(),
         }
     }
 }
@@ -866,6 +949,7 @@ mod tests {
             ".pickle.signing_key" => "[signing_key]",
             ".sender_key" => "[sender_key]",
             ".signing_key.ed25519" => "[ed25519_key]",
+            ".received_at" => "[received_at]",
         });
     }
 
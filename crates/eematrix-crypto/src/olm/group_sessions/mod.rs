@@ -23,9 +23,12 @@ pub(crate) mod sender_data_finder;
 pub use inbound::{InboundGroupSession, PickledInboundGroupSession};
 pub(crate) use outbound::ShareState;
 pub use outbound::{
-    EncryptionSettings, OutboundGroupSession, PickledOutboundGroupSession, ShareInfo,
+    EncryptionSettings, OutboundGroupSession, OutboundGroupSessionInfo,
+    PickledOutboundGroupSession, ShareInfo,
+};
+pub use sender_data::{
+    KnownSenderData, SenderData, SenderDataType, SenderTrustExplanation, SenderTrustStep,
 };
-pub use sender_data::{KnownSenderData, SenderData, SenderDataType};
 use thiserror::Error;
 pub use vodozemac::megolm::{ExportedSessionKey, SessionKey};
 use vodozemac::{megolm::SessionKeyDecodeError, Curve25519PublicKey};
@@ -108,6 +111,18 @@ pub struct ExportedRoomKey {
     /// [MSC3061]: https://github.com/matrix-org/matrix-spec-proposals/pull/3061
     #[serde(default, rename = "org.matrix.msc3061.shared_history")]
     pub shared_history: bool,
+
+    /// Information about the sender of the session, and how much we trusted
+    /// them when the session was exported.
+    ///
+    /// This is only kept in version 2 key exports, see
+    /// [`encrypt_room_key_export_v2()`]. It is informational, the export isn't
+    /// authenticated, so imported sessions always start out with unknown
+    /// sender data, which is recalculated once they decrypt an event.
+    ///
+    /// [`encrypt_room_key_export_v2()`]: crate::encrypt_room_key_export_v2
+    #[serde(default, rename = "io.eematrix.sender_data", skip_serializing_if = "Option::is_none")]
+    pub sender_data: Option<SenderData>,
 }
 
 impl ExportedRoomKey {
@@ -137,6 +152,7 @@ impl ExportedRoomKey {
             sender_claimed_keys,
             forwarding_curve25519_key_chain,
             shared_history,
+            sender_data: None,
         }
     }
 }
@@ -265,6 +281,7 @@ impl From<ExportedRoomKey> for BackedUpRoomKey {
             sender_claimed_keys,
             forwarding_curve25519_key_chain,
             shared_history,
+            sender_data: _,
         } = value;
 
         Self {
@@ -300,6 +317,7 @@ impl TryFrom<ForwardedRoomKeyContent> for ExportedRoomKey {
                     sender_key: content.claimed_sender_key,
                     session_key: content.session_key,
                     shared_history: false,
+                    sender_data: None,
                 })
             }
             #[cfg(feature = "experimental-algorithms")]
@@ -312,6 +330,7 @@ impl TryFrom<ForwardedRoomKeyContent> for ExportedRoomKey {
                 sender_key: content.claimed_sender_key,
                 session_key: content.session_key,
                 shared_history: false,
+                sender_data: None,
             }),
             ForwardedRoomKeyContent::Unknown(c) => Err(SessionExportError::Algorithm(c.algorithm)),
         }
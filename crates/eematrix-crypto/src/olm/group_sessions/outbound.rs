@@ -30,11 +30,12 @@ use ruma::{
         room::{encryption::RoomEncryptionEventContent, history_visibility::HistoryVisibility},
         AnyMessageLikeEventContent,
     },
-    serde::Raw,
-    DeviceId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId,
-    SecondsSinceUnixEpoch, TransactionId, UserId,
+    serde::{JsonObject, Raw},
+    DeviceId, EventId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId,
+    SecondsSinceUnixEpoch, TransactionId, UInt, UserId,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
 use vodozemac::{megolm::SessionConfig, Curve25519PublicKey};
@@ -62,7 +63,7 @@ use crate::{
         requests::ToDeviceRequest,
         EventEncryptionAlgorithm,
     },
-    DeviceData,
+    DeviceData, PayloadValidationError,
 };
 
 const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
@@ -104,6 +105,21 @@ pub struct EncryptionSettings {
     /// Default will send to all devices.
     #[serde(default)]
     pub sharing_strategy: CollectStrategy,
+    /// The maximum size, in bytes, of the serialized content of an event
+    /// before it gets encrypted.
+    ///
+    /// Larger events are rejected with
+    /// [`PayloadValidationError::ContentTooLarge`]. No limit is enforced if
+    /// this is `None`, which is the default.
+    #[serde(default)]
+    pub max_content_size: Option<usize>,
+    /// Whether the `m.relates_to` field of events should be checked against
+    /// the schema of its relation type before they get encrypted.
+    ///
+    /// Malformed relations are rejected with
+    /// [`PayloadValidationError::MalformedRelation`]. Defaults to `false`.
+    #[serde(default)]
+    pub strict_relation_validation: bool,
 }
 
 impl Default for EncryptionSettings {
@@ -114,6 +130,8 @@ impl Default for EncryptionSettings {
             rotation_period_msgs: ROTATION_MESSAGES,
             history_visibility: HistoryVisibility::Shared,
             sharing_strategy: CollectStrategy::default(),
+            max_content_size: None,
+            strict_relation_validation: false,
         }
     }
 }
@@ -137,7 +155,95 @@ impl EncryptionSettings {
             rotation_period_msgs,
             history_visibility,
             sharing_strategy,
+            max_content_size: None,
+            strict_relation_validation: false,
+        }
+    }
+
+    /// Whether room keys created with these settings are marked as
+    /// `shared_history`, i.e. may be shared with users who join the room
+    /// later on, as defined in [MSC4268].
+    ///
+    /// This follows from the history visibility of the room: keys are only
+    /// marked as `shared_history` if the history is `shared` or
+    /// `world_readable`.
+    ///
+    /// [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
+    pub fn shared_history(&self) -> bool {
+        shared_history_from_history_visibility(&self.history_visibility)
+    }
+
+    /// Check that the given content may be encrypted with these settings.
+    ///
+    /// This enforces the [`EncryptionSettings::max_content_size`] and the
+    /// [`EncryptionSettings::strict_relation_validation`] settings.
+    pub(crate) fn validate_content(
+        &self,
+        content: &Raw<AnyMessageLikeEventContent>,
+    ) -> Result<(), PayloadValidationError> {
+        if let Some(max_size) = self.max_content_size {
+            let size = content.json().get().len();
+
+            if size > max_size {
+                return Err(PayloadValidationError::ContentTooLarge { size, max_size });
+            }
         }
+
+        if self.strict_relation_validation {
+            let content: JsonObject = serde_json::from_str(content.json().get())
+                .map_err(|e| PayloadValidationError::MalformedRelation(e.to_string()))?;
+
+            if let Some(relates_to) = content.get("m.relates_to") {
+                validate_relation(relates_to).map_err(PayloadValidationError::MalformedRelation)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check an `m.relates_to` field against the schema of its relation type.
+///
+/// Unknown relation types are accepted as long as the relation is an object
+/// with a string `rel_type`.
+fn validate_relation(relates_to: &Value) -> Result<(), String> {
+    fn event_id(relation: &JsonObject, field: &str) -> Result<(), String> {
+        let event_id = relation
+            .get("event_id")
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("`{field}` is missing an `event_id`"))?;
+
+        <&EventId>::try_from(event_id)
+            .map(|_| ())
+            .map_err(|e| format!("`{field}` contains an invalid `event_id`: {e}"))
+    }
+
+    let relation = relates_to.as_object().ok_or("`m.relates_to` isn't an object")?;
+
+    if let Some(in_reply_to) = relation.get("m.in_reply_to") {
+        let in_reply_to = in_reply_to.as_object().ok_or("`m.in_reply_to` isn't an object")?;
+        event_id(in_reply_to, "m.in_reply_to")?;
+    }
+
+    let Some(rel_type) = relation.get("rel_type") else {
+        // A plain reply doesn't have a relation type.
+        return Ok(());
+    };
+
+    let rel_type = rel_type.as_str().ok_or("`rel_type` isn't a string")?;
+
+    match rel_type {
+        "m.replace" | "m.thread" | "m.reference" => event_id(relation, rel_type),
+        "m.annotation" => {
+            event_id(relation, rel_type)?;
+
+            if relation.get("key").is_some_and(Value::is_string) {
+                Ok(())
+            } else {
+                Err("`m.annotation` is missing a `key`".to_owned())
+            }
+        }
+        _ => Ok(()),
     }
 }
 
@@ -206,6 +312,38 @@ pub struct SharedWith {
     pub olm_wedging_index: SequenceNumber,
 }
 
+/// A snapshot of the state of an [`OutboundGroupSession`], returned by
+/// [`OlmMachine::outbound_session_info()`].
+///
+/// Useful to show details about the current encryption session of a room, for
+/// example in a debugging screen.
+///
+/// [`OlmMachine::outbound_session_info()`]: crate::OlmMachine::outbound_session_info
+#[derive(Clone, Debug)]
+pub struct OutboundGroupSessionInfo {
+    /// The unique ID of the session.
+    pub session_id: String,
+    /// The time the session was created.
+    pub creation_time: SecondsSinceUnixEpoch,
+    /// The number of messages that were encrypted using the session.
+    pub message_count: u64,
+    /// The number of messages after which the session will be rotated.
+    pub rotation_message_count: u64,
+    /// The time after which the session will be rotated.
+    pub rotation_deadline: SecondsSinceUnixEpoch,
+    /// The devices the room key was successfully sent to.
+    pub shared_with: BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>,
+    /// The devices which acknowledged the receipt of the room key using an
+    /// Olm-encrypted `m.room_key.ack` message, see
+    /// [`OlmMachine::set_room_key_acks_enabled()`].
+    ///
+    /// [`OlmMachine::set_room_key_acks_enabled()`]: crate::OlmMachine::set_room_key_acks_enabled
+    pub acknowledged_by: BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>,
+    /// Whether the session was invalidated and will be rotated the next time
+    /// a room key is shared.
+    pub invalidated: bool,
+}
+
 /// A read-only view into the device sharing state of an
 /// [`OutboundGroupSession`].
 pub(crate) struct SharingView<'a> {
@@ -322,6 +460,18 @@ impl OutboundGroupSession {
         identity_keys: Arc<IdentityKeys>,
         room_id: &RoomId,
         settings: EncryptionSettings,
+    ) -> Result<Self, SessionCreationError> {
+        Self::new_at(device_id, identity_keys, room_id, settings, SecondsSinceUnixEpoch::now())
+    }
+
+    /// Create a new outbound group session for the given room, created at the
+    /// given time, see [`OutboundGroupSession::new()`].
+    pub(crate) fn new_at(
+        device_id: OwnedDeviceId,
+        identity_keys: Arc<IdentityKeys>,
+        room_id: &RoomId,
+        settings: EncryptionSettings,
+        creation_time: SecondsSinceUnixEpoch,
     ) -> Result<Self, SessionCreationError> {
         let config = Self::session_config(&settings.algorithm)?;
 
@@ -334,7 +484,7 @@ impl OutboundGroupSession {
             device_id,
             account_identity_keys: identity_keys,
             session_id: session_id.into(),
-            creation_time: SecondsSinceUnixEpoch::now(),
+            creation_time,
             message_count: Arc::new(AtomicU64::new(0)),
             shared: Arc::new(AtomicBool::new(false)),
             invalidated: Arc::new(AtomicBool::new(false)),
@@ -385,6 +535,38 @@ impl OutboundGroupSession {
         &self.settings
     }
 
+    /// Get a copy of this session which validates the content of events using
+    /// the [`EncryptionSettings::max_content_size`] and
+    /// [`EncryptionSettings::strict_relation_validation`] of the given
+    /// settings.
+    ///
+    /// Unlike the other settings, these don't affect the room key, so they
+    /// can be changed without rotating the session. The copy shares the
+    /// state of this session.
+    pub(crate) fn with_content_validation(&self, settings: &EncryptionSettings) -> Self {
+        let settings = EncryptionSettings {
+            max_content_size: settings.max_content_size,
+            strict_relation_validation: settings.strict_relation_validation,
+            ..self.settings().clone()
+        };
+
+        Self { settings: Arc::new(settings), ..self.clone() }
+    }
+
+    /// Whether the room key of this session is marked as `shared_history`.
+    ///
+    /// See [`EncryptionSettings::shared_history()`].
+    pub fn shared_history(&self) -> bool {
+        self.settings.shared_history()
+    }
+
+    /// Get the users/devices which receive the session, or a withheld code,
+    /// with the request with the given request id, if the request is still
+    /// waiting to be sent out.
+    pub(crate) fn pending_share_info(&self, request_id: &TransactionId) -> Option<ShareInfoSet> {
+        self.to_share_with_set.read().get(request_id).map(|(_, share_info)| share_info.clone())
+    }
+
     /// Mark the request with the given request id as sent.
     ///
     /// This removes the request from the queue and marks the set of
@@ -519,9 +701,9 @@ impl OutboundGroupSession {
         Raw::new(&content).expect("m.room.encrypted event content can always be serialized")
     }
 
-    fn elapsed(&self) -> bool {
+    fn elapsed(&self, now: SecondsSinceUnixEpoch) -> bool {
         let creation_time = Duration::from_secs(self.creation_time.get().into());
-        let now = Duration::from_secs(SecondsSinceUnixEpoch::now().get().into());
+        let now = Duration::from_secs(now.get().into());
         now.checked_sub(creation_time)
             .map(|elapsed| elapsed >= self.safe_rotation_period())
             .unwrap_or(true)
@@ -543,20 +725,67 @@ impl OutboundGroupSession {
         }
     }
 
+    /// Get a snapshot of the state of this session.
+    pub fn info(&self) -> OutboundGroupSessionInfo {
+        let rotation_period = self.safe_rotation_period().as_secs();
+        let rotation_deadline = SecondsSinceUnixEpoch(UInt::new_saturating(
+            u64::from(self.creation_time.get()).saturating_add(rotation_period),
+        ));
+
+        let shared_with = self
+            .shared_with_set
+            .read()
+            .iter()
+            .map(|(user_id, devices)| {
+                let devices = devices
+                    .iter()
+                    .filter(|(_, info)| matches!(info, ShareInfo::Shared(_)))
+                    .map(|(device_id, _)| device_id.to_owned())
+                    .collect::<BTreeSet<_>>();
+
+                (user_id.to_owned(), devices)
+            })
+            .filter(|(_, devices)| !devices.is_empty())
+            .collect();
+
+        OutboundGroupSessionInfo {
+            session_id: self.session_id().to_owned(),
+            creation_time: self.creation_time,
+            message_count: self.message_count.load(Ordering::SeqCst),
+            rotation_message_count: self.safe_rotation_period_msgs(),
+            rotation_deadline,
+            shared_with,
+            // The acknowledgements are tracked by the `OlmMachine`.
+            acknowledged_by: Default::default(),
+            invalidated: self.invalidated(),
+        }
+    }
+
     /// Check if the session has expired and if it should be rotated.
     ///
     /// A session will expire after some time or if enough messages have been
     /// encrypted using it.
     pub fn expired(&self) -> bool {
+        self.expired_at(SecondsSinceUnixEpoch::now())
+    }
+
+    /// Check if the session has expired at the given point in time, see
+    /// [`OutboundGroupSession::expired()`].
+    pub(crate) fn expired_at(&self, now: SecondsSinceUnixEpoch) -> bool {
         let count = self.message_count.load(Ordering::SeqCst);
-        // We clamp the rotation period for message counts to be between 1 and
-        // 10000. The Megolm session should be usable for at least 1 message,
-        // and at most 10000 messages. Realistically Megolm uses u32 for it's
-        // internal counter and one could use the Megolm session for up to
-        // u32::MAX messages, but we're staying on the safe side of things.
-        let rotation_period_msgs = self.settings.rotation_period_msgs.clamp(1, 10_000);
-
-        count >= rotation_period_msgs || self.elapsed()
+
+        count >= self.safe_rotation_period_msgs() || self.elapsed(now)
+    }
+
+    /// Returns the rotation_period_msgs that was set for this session, clamped
+    /// to be between 1 and 10000.
+    ///
+    /// The Megolm session should be usable for at least 1 message, and at most
+    /// 10000 messages. Realistically Megolm uses u32 for it's internal counter
+    /// and one could use the Megolm session for up to u32::MAX messages, but
+    /// we're staying on the safe side of things.
+    fn safe_rotation_period_msgs(&self) -> u64 {
+        self.settings.rotation_period_msgs.clamp(1, 10_000)
     }
 
     /// Has the session been invalidated.
@@ -611,8 +840,7 @@ impl OutboundGroupSession {
 
     pub(crate) async fn as_content(&self) -> RoomKeyContent {
         let session_key = self.session_key().await;
-        let shared_history =
-            shared_history_from_history_visibility(&self.settings.history_visibility);
+        let shared_history = self.shared_history();
 
         RoomKeyContent::MegolmV1AesSha2(
             MegolmV1AesSha2RoomKeyContent::new(
@@ -797,15 +1025,17 @@ pub struct PickledOutboundGroupSession {
 mod tests {
     use std::time::Duration;
 
+    use matrix_sdk_test::async_test;
     use ruma::{
+        device_id,
         events::room::{
             encryption::RoomEncryptionEventContent, history_visibility::HistoryVisibility,
         },
-        uint, EventEncryptionAlgorithm,
+        room_id, uint, user_id, EventEncryptionAlgorithm,
     };
 
     use super::{EncryptionSettings, ShareState, ROTATION_MESSAGES, ROTATION_PERIOD};
-    use crate::CollectStrategy;
+    use crate::{olm::SenderData, Account, CollectStrategy};
 
     #[test]
     fn test_encryption_settings_conversion() {
@@ -833,6 +1063,35 @@ mod tests {
         assert_eq!(settings.rotation_period_msgs, 500);
     }
 
+    #[async_test]
+    async fn test_shared_history_follows_history_visibility() {
+        let account =
+            Account::with_device_id(user_id!("@alice:example.org"), device_id!("DEVICEID"))
+                .static_data;
+        let room_id = room_id!("!test_room:example.org");
+
+        for (history_visibility, shared_history) in [
+            (HistoryVisibility::WorldReadable, true),
+            (HistoryVisibility::Shared, true),
+            (HistoryVisibility::Invited, false),
+            (HistoryVisibility::Joined, false),
+        ] {
+            let settings = EncryptionSettings {
+                history_visibility: history_visibility.clone(),
+                ..Default::default()
+            };
+            assert_eq!(settings.shared_history(), shared_history);
+
+            let (outbound, inbound) = account
+                .create_group_session_pair(room_id, settings, SenderData::unknown())
+                .await
+                .unwrap();
+
+            assert_eq!(outbound.shared_history(), shared_history, "{history_visibility:?}");
+            assert_eq!(inbound.shared_history(), shared_history, "{history_visibility:?}");
+        }
+    }
+
     /// Ensure that the `ShareState` PartialOrd instance orders according to
     /// specificity of the value.
     #[test]
@@ -14,10 +14,11 @@
 
 use std::{cmp::Ordering, fmt};
 
+use matrix_sdk_common::deserialized_responses::VerificationState;
 use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
 use serde::{de, de::Visitor, Deserialize, Deserializer, Serialize};
 use tracing::error;
-use vodozemac::Ed25519PublicKey;
+use vodozemac::{Curve25519PublicKey, Ed25519PublicKey};
 
 use crate::{
     types::{serialize_ed25519_key, DeviceKeys},
@@ -328,6 +329,176 @@ impl SenderData {
             }
         }
     }
+
+    /// Return our best guess of the device which sent us the associated
+    /// megolm session.
+    ///
+    /// Returns `None` for `SenderData::UnknownDevice`, and if the device ID
+    /// wasn't recorded.
+    pub(crate) fn device_id(&self) -> Option<&DeviceId> {
+        match &self {
+            SenderData::UnknownDevice { .. } => None,
+            SenderData::DeviceInfo { device_keys, .. } => Some(&device_keys.device_id),
+            SenderData::VerificationViolation(known_sender_data)
+            | SenderData::SenderUnverified(known_sender_data)
+            | SenderData::SenderVerified(known_sender_data) => {
+                known_sender_data.device_id.as_deref()
+            }
+        }
+    }
+}
+
+/// A link in the chain of trust between a room key and the identity of the
+/// sender of the events encrypted with it, see [`SenderTrustExplanation`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SenderTrustStep {
+    /// The room key was imported, from a key backup or a key export, so we
+    /// can't be sure which device created it.
+    RoomKeyImported,
+
+    /// The room key was received before we started collecting trust
+    /// information about room keys.
+    LegacyRoomKey,
+
+    /// We couldn't find the device which created the room key.
+    DeviceNotFound,
+
+    /// We found a device using the identity key of the room key, but the
+    /// device doesn't own the room key.
+    DeviceDoesNotOwnRoomKey,
+
+    /// The room key was created by the device with the given ID and Ed25519
+    /// key.
+    DeviceKey {
+        /// The ID of the device, if known.
+        device_id: Option<OwnedDeviceId>,
+        /// The Ed25519 key of the device, if known.
+        ed25519_key: Option<Ed25519PublicKey>,
+    },
+
+    /// The device isn't signed by the self-signing key of its owner.
+    NotCrossSigned,
+
+    /// The device is signed by the self-signing key of its owner, which is in
+    /// turn signed by the given master key.
+    CrossSigned {
+        /// The owner of the device.
+        user_id: OwnedUserId,
+        /// The master key of the owner of the device.
+        master_key: Ed25519PublicKey,
+    },
+
+    /// We have verified the master key of the owner of the device.
+    MasterKeyVerified,
+
+    /// The master key of the owner of the device is pinned, i.e. it was
+    /// accepted when we first saw it, but we haven't verified it.
+    MasterKeyPinned,
+
+    /// We had verified a previous master key of the owner of the device, but
+    /// the current one isn't verified.
+    MasterKeyVerificationViolation,
+
+    /// The device belongs to a different user than the sender of the event.
+    SenderMismatch {
+        /// The owner of the device.
+        owner: OwnedUserId,
+    },
+}
+
+/// An explanation of how the trust in the sender of a room event was derived,
+/// for example to tell the user why a message is, or isn't, trusted.
+///
+/// Returned by
+/// [`OlmMachine::explain_sender_trust()`](crate::OlmMachine::explain_sender_trust).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SenderTrustExplanation {
+    /// The verification state resulting from the chain of trust, as found in
+    /// the `EncryptionInfo` of the event.
+    pub verification_state: VerificationState,
+
+    /// The Curve25519 key of the device which created the room key.
+    pub sender_key: Curve25519PublicKey,
+
+    /// The links of the chain of trust, from the room key to the identity of
+    /// the sender.
+    pub steps: Vec<SenderTrustStep>,
+}
+
+impl SenderTrustExplanation {
+    pub(crate) fn new(
+        sender: &UserId,
+        sender_data: &SenderData,
+        session_has_been_imported: bool,
+        sender_key: Curve25519PublicKey,
+        device_key: Option<Ed25519PublicKey>,
+        verification_state: VerificationState,
+    ) -> Self {
+        let mut steps = Vec::new();
+
+        if session_has_been_imported {
+            steps.push(SenderTrustStep::RoomKeyImported);
+        }
+
+        let known_sender_steps = |known: &KnownSenderData| {
+            [
+                SenderTrustStep::DeviceKey {
+                    device_id: known.device_id.clone(),
+                    ed25519_key: device_key,
+                },
+                SenderTrustStep::CrossSigned {
+                    user_id: known.user_id.clone(),
+                    master_key: *known.master_key,
+                },
+            ]
+        };
+
+        match sender_data {
+            SenderData::UnknownDevice { legacy_session, owner_check_failed } => {
+                if *legacy_session {
+                    steps.push(SenderTrustStep::LegacyRoomKey);
+                }
+
+                steps.push(if *owner_check_failed {
+                    SenderTrustStep::DeviceDoesNotOwnRoomKey
+                } else {
+                    SenderTrustStep::DeviceNotFound
+                });
+            }
+            SenderData::DeviceInfo { device_keys, legacy_session } => {
+                if *legacy_session {
+                    steps.push(SenderTrustStep::LegacyRoomKey);
+                }
+
+                steps.push(SenderTrustStep::DeviceKey {
+                    device_id: Some(device_keys.device_id.clone()),
+                    ed25519_key: device_keys.ed25519_key(),
+                });
+                steps.push(SenderTrustStep::NotCrossSigned);
+            }
+            SenderData::VerificationViolation(known) => {
+                steps.extend(known_sender_steps(known));
+                steps.push(SenderTrustStep::MasterKeyVerificationViolation);
+            }
+            SenderData::SenderUnverified(known) => {
+                steps.extend(known_sender_steps(known));
+                steps.push(SenderTrustStep::MasterKeyPinned);
+            }
+            SenderData::SenderVerified(known) => {
+                steps.extend(known_sender_steps(known));
+                steps.push(SenderTrustStep::MasterKeyVerified);
+            }
+        }
+
+        if let Some(owner) = sender_data.user_id() {
+            if owner != sender {
+                steps.push(SenderTrustStep::SenderMismatch { owner });
+            }
+        }
+
+        Self { verification_state, sender_key, steps }
+    }
 }
 
 /// Used when deserialising and the sender_data property is missing.
@@ -23,7 +23,9 @@ mod session;
 mod signing;
 pub(crate) mod utility;
 
-pub use account::{Account, OlmMessageHash, PickledAccount, StaticAccountData};
+pub use account::{
+    Account, OlmMessageHash, OneTimeKeyPoolHealth, PickledAccount, StaticAccountData,
+};
 pub(crate) use account::{OlmDecryptionInfo, SessionType};
 pub(crate) use group_sessions::{
     sender_data_finder::{self, SenderDataFinder},
@@ -31,11 +33,15 @@ pub(crate) use group_sessions::{
 };
 pub use group_sessions::{
     BackedUpRoomKey, EncryptionSettings, ExportedRoomKey, InboundGroupSession, KnownSenderData,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession, SenderData,
-    SenderDataType, SessionCreationError, SessionExportError, SessionKey, ShareInfo,
+    OutboundGroupSession, OutboundGroupSessionInfo, PickledInboundGroupSession,
+    PickledOutboundGroupSession, SenderData, SenderDataType, SenderTrustExplanation,
+    SenderTrustStep, SessionCreationError, SessionExportError, SessionKey, ShareInfo,
 };
 pub use session::{PickledSession, Session};
-pub use signing::{CrossSigningStatus, PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
+pub use signing::{
+    CrossSigningBackends, CrossSigningStatus, PickledCrossSigningIdentity,
+    PrivateCrossSigningIdentity, SigningBackend,
+};
 pub(crate) use utility::{SignedJsonObject, VerifyJson};
 pub use vodozemac::{olm::IdentityKeys, Curve25519PublicKey};
 
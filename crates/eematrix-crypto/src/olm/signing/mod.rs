@@ -19,7 +19,9 @@ use std::sync::{
     Arc,
 };
 
-pub use pk_signing::{MasterSigning, PickledSignings, SelfSigning, SigningError, UserSigning};
+pub use pk_signing::{
+    MasterSigning, PickledSignings, SelfSigning, SigningBackend, SigningError, UserSigning,
+};
 use ruma::{
     api::client::keys::upload_signatures::v3::{Request as SignatureUploadRequest, SignedKeys},
     events::secret::request::SecretName,
@@ -34,8 +36,8 @@ use crate::{
     error::SignatureError,
     store::SecretImportError,
     types::{
-        requests::UploadSigningKeysRequest, DeviceKeys, MasterPubkey, SelfSigningPubkey,
-        UserSigningPubkey,
+        requests::UploadSigningKeysRequest, DeviceKeys, MasterPubkey, SecretString,
+        SelfSigningPubkey, UserSigningPubkey,
     },
     Account, DeviceData, OtherUserIdentityData, OwnUserIdentity, OwnUserIdentityData,
 };
@@ -117,6 +119,20 @@ impl CrossSigningStatus {
     }
 }
 
+/// The [`SigningBackend`]s holding the private parts of our cross signing
+/// keys, see [`OlmMachine::import_cross_signing_backends()`].
+///
+/// [`OlmMachine::import_cross_signing_backends()`]: crate::OlmMachine::import_cross_signing_backends
+#[derive(Clone, Debug, Default)]
+pub struct CrossSigningBackends {
+    /// The backend holding the master key.
+    pub master_key: Option<Arc<dyn SigningBackend>>,
+    /// The backend holding the self signing key.
+    pub self_signing_key: Option<Arc<dyn SigningBackend>>,
+    /// The backend holding the user signing key.
+    pub user_signing_key: Option<Arc<dyn SigningBackend>>,
+}
+
 impl PrivateCrossSigningIdentity {
     /// Get the user id that this identity belongs to.
     pub fn user_id(&self) -> &UserId {
@@ -190,22 +206,23 @@ impl PrivateCrossSigningIdentity {
 
     /// Export the seed of the private cross signing key
     ///
-    /// The exported seed will be encoded as unpadded base64.
+    /// The exported seed will be encoded as unpadded base64. Keys held by a
+    /// [`SigningBackend`] can't be exported.
     ///
     /// # Arguments
     ///
     /// * `secret_name` - The type of the cross signing key that should be
     ///   exported.
-    pub async fn export_secret(&self, secret_name: &SecretName) -> Option<String> {
+    pub async fn export_secret(&self, secret_name: &SecretName) -> Option<SecretString> {
         match secret_name {
             SecretName::CrossSigningMasterKey => {
-                self.master_key.lock().await.as_ref().map(|m| m.export_seed())
+                self.master_key.lock().await.as_ref().and_then(|m| m.export_seed())
             }
             SecretName::CrossSigningUserSigningKey => {
-                self.user_signing_key.lock().await.as_ref().map(|m| m.export_seed())
+                self.user_signing_key.lock().await.as_ref().and_then(|m| m.export_seed())
             }
             SecretName::CrossSigningSelfSigningKey => {
-                self.self_signing_key.lock().await.as_ref().map(|m| m.export_seed())
+                self.self_signing_key.lock().await.as_ref().and_then(|m| m.export_seed())
             }
             _ => None,
         }
@@ -316,6 +333,50 @@ impl PrivateCrossSigningIdentity {
         Ok(())
     }
 
+    /// Use the given [`SigningBackend`]s for the private parts of the cross
+    /// signing keys.
+    ///
+    /// The public keys of the backends need to match the public keys that
+    /// were uploaded to the server.
+    pub(crate) async fn import_backends(
+        &self,
+        public_identity: &OwnUserIdentityData,
+        backends: CrossSigningBackends,
+    ) -> Result<(), SecretImportError> {
+        let user_id = self.user_id().to_owned();
+
+        let master = backends.master_key.map(|b| MasterSigning::from_backend(user_id.clone(), b));
+        let self_signing =
+            backends.self_signing_key.map(|b| SelfSigning::from_backend(user_id.clone(), b));
+        let user_signing =
+            backends.user_signing_key.map(|b| UserSigning::from_backend(user_id.clone(), b));
+
+        if master.as_ref().is_some_and(|k| public_identity.master_key() != k.public_key())
+            || self_signing
+                .as_ref()
+                .is_some_and(|k| public_identity.self_signing_key() != k.public_key())
+            || user_signing
+                .as_ref()
+                .is_some_and(|k| public_identity.user_signing_key() != k.public_key())
+        {
+            return Err(SecretImportError::MismatchedPublicKeys);
+        }
+
+        if let Some(master) = master {
+            *self.master_key.lock().await = Some(master);
+        }
+
+        if let Some(self_signing) = self_signing {
+            *self.self_signing_key.lock().await = Some(self_signing);
+        }
+
+        if let Some(user_signing) = user_signing {
+            *self.user_signing_key.lock().await = Some(user_signing);
+        }
+
+        Ok(())
+    }
+
     /// Remove our private cross signing key if the public keys differ from
     /// what's found in the [`OwnUserIdentityData`].
     pub(crate) async fn clear_if_differs(
@@ -339,6 +400,13 @@ impl PrivateCrossSigningIdentity {
         result
     }
 
+    /// Remove all our private cross signing keys.
+    pub(crate) async fn clear(&self) {
+        *self.master_key.lock().await = None;
+        *self.user_signing_key.lock().await = None;
+        *self.self_signing_key.lock().await = None;
+    }
+
     pub(crate) async fn get_public_identity_diff(
         &self,
         public_identity: &OwnUserIdentityData,
@@ -408,9 +476,9 @@ impl PrivateCrossSigningIdentity {
         let mut self_signing = self_signing_private_key.public_key().to_owned();
         let mut user_signing = user_signing_private_key.public_key().to_owned();
 
-        master_private_key.sign_subkey(master.as_mut());
-        master_private_key.sign_subkey(self_signing.as_mut());
-        master_private_key.sign_subkey(user_signing.as_mut());
+        master_private_key.sign_subkey(master.as_mut())?;
+        master_private_key.sign_subkey(self_signing.as_mut())?;
+        master_private_key.sign_subkey(user_signing.as_mut())?;
 
         Ok((master, self_signing, user_signing))
     }
@@ -490,13 +558,12 @@ impl PrivateCrossSigningIdentity {
     }
 
     pub(crate) async fn sign(&self, message: &str) -> Result<Ed25519Signature, SignatureError> {
-        Ok(self
-            .master_key
+        self.master_key
             .lock()
             .await
             .as_ref()
             .ok_or(SignatureError::MissingSigningKey)?
-            .sign(message))
+            .sign(message)
     }
 
     /// Create a new identity for the given Olm Account.
@@ -575,6 +642,8 @@ impl PrivateCrossSigningIdentity {
 
     /// Store the cross signing identity as a pickle.
     ///
+    /// Keys held by a [`SigningBackend`] aren't part of the pickle.
+    ///
     /// # Arguments
     ///
     /// * `pickle_key` - The key that should be used to encrypt the signing
@@ -584,11 +653,11 @@ impl PrivateCrossSigningIdentity {
     ///
     /// This will panic if the provided pickle key isn't 32 bytes long.
     pub async fn pickle(&self) -> PickledCrossSigningIdentity {
-        let master_key = self.master_key.lock().await.as_ref().map(|m| m.pickle());
+        let master_key = self.master_key.lock().await.as_ref().and_then(|m| m.pickle());
 
-        let self_signing_key = self.self_signing_key.lock().await.as_ref().map(|m| m.pickle());
+        let self_signing_key = self.self_signing_key.lock().await.as_ref().and_then(|m| m.pickle());
 
-        let user_signing_key = self.user_signing_key.lock().await.as_ref().map(|m| m.pickle());
+        let user_signing_key = self.user_signing_key.lock().await.as_ref().and_then(|m| m.pickle());
 
         let keys = PickledSignings { master_key, user_signing_key, self_signing_key };
 
@@ -636,17 +705,46 @@ impl PrivateCrossSigningIdentity {
 mod tests {
     use std::sync::Arc;
 
+    use assert_matches::assert_matches;
     use matrix_sdk_test::async_test;
-    use ruma::{device_id, user_id, CanonicalJsonValue, DeviceKeyAlgorithm, DeviceKeyId, UserId};
+    use ruma::{
+        device_id, events::secret::request::SecretName, user_id, CanonicalJsonValue,
+        DeviceKeyAlgorithm, DeviceKeyId, UserId,
+    };
     use serde_json::json;
+    use vodozemac::{Ed25519PublicKey, Ed25519SecretKey, Ed25519Signature};
 
-    use super::{pk_signing::Signing, PrivateCrossSigningIdentity};
+    use super::{
+        pk_signing::Signing, CrossSigningBackends, PrivateCrossSigningIdentity, SigningBackend,
+    };
     use crate::{
+        error::SignatureError,
         identities::{DeviceData, OtherUserIdentityData},
         olm::{Account, SignedJsonObject, VerifyJson},
+        store::SecretImportError,
         types::Signatures,
     };
 
+    /// A [`SigningBackend`] which keeps its key in memory.
+    #[derive(Debug)]
+    struct TestBackend(Ed25519SecretKey);
+
+    impl TestBackend {
+        fn new(seed: &str) -> Arc<dyn SigningBackend> {
+            Arc::new(Self(Ed25519SecretKey::from_base64(seed).unwrap()))
+        }
+    }
+
+    impl SigningBackend for TestBackend {
+        fn public_key(&self) -> Ed25519PublicKey {
+            self.0.public_key()
+        }
+
+        fn sign(&self, message: &[u8]) -> Result<Ed25519Signature, SignatureError> {
+            Ok(self.0.sign(message))
+        }
+    }
+
     fn user_id() -> &'static UserId {
         user_id!("@example:localhost")
     }
@@ -664,7 +762,7 @@ mod tests {
         let canonicalized: CanonicalJsonValue = json.try_into().unwrap();
         let canonicalized = canonicalized.to_string();
 
-        let signature = signing.sign(&canonicalized);
+        let signature = signing.sign(&canonicalized).unwrap();
         let mut signatures = Signatures::new();
         signatures.add_signature(user_id.to_owned(), key_id.clone(), signature);
 
@@ -678,7 +776,7 @@ mod tests {
     #[test]
     fn test_pickling_signing() {
         let signing = Signing::new();
-        let pickled = signing.pickle();
+        let pickled = signing.pickle().unwrap();
 
         let unpickled = Signing::from_pickle(pickled).unwrap();
 
@@ -788,4 +886,63 @@ mod tests {
 
         user_signing.public_key().verify_master_key(bob_public.master_key()).unwrap();
     }
+
+    #[async_test]
+    async fn test_signing_backends() {
+        let account = Account::with_device_id(user_id(), device_id!("DEVICEID"));
+        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+        let public_identity = identity.to_public_identity().await.unwrap();
+
+        let master_seed = identity.export_secret(&SecretName::CrossSigningMasterKey).await.unwrap();
+        let self_signing_seed =
+            identity.export_secret(&SecretName::CrossSigningSelfSigningKey).await.unwrap();
+        let user_signing_seed =
+            identity.export_secret(&SecretName::CrossSigningUserSigningKey).await.unwrap();
+
+        let backends = CrossSigningBackends {
+            master_key: Some(TestBackend::new(master_seed.expose_secret())),
+            self_signing_key: Some(TestBackend::new(self_signing_seed.expose_secret())),
+            user_signing_key: Some(TestBackend::new(user_signing_seed.expose_secret())),
+        };
+
+        let backed = PrivateCrossSigningIdentity::empty(user_id());
+        backed.import_backends(&public_identity, backends).await.unwrap();
+
+        assert!(backed.status().await.is_complete());
+        assert_eq!(
+            backed.to_public_identity().await.unwrap().master_key(),
+            public_identity.master_key()
+        );
+
+        // Our devices get signed by the backend holding the self-signing key.
+        let mut device = DeviceData::from_account(&account);
+        let mut device_keys = device.as_device_keys().to_owned();
+        backed.sign_device_keys(&mut device_keys).await.unwrap();
+        device.update_device(&device_keys).unwrap();
+
+        public_identity.self_signing_key().verify_device(&device).unwrap();
+
+        // The private keys never leave the backends, so they can't be exported or
+        // pickled.
+        assert!(backed.export_secret(&SecretName::CrossSigningMasterKey).await.is_none());
+
+        let unpickled = PrivateCrossSigningIdentity::from_pickle(backed.pickle().await).unwrap();
+        assert!(unpickled.is_empty().await);
+        assert!(!unpickled.has_master_key().await);
+
+        // Backends holding keys which don't match our public identity are rejected.
+        let other = PrivateCrossSigningIdentity::new(user_id().to_owned());
+        let other_seed = other.export_secret(&SecretName::CrossSigningMasterKey).await.unwrap();
+        let backends = CrossSigningBackends {
+            master_key: Some(TestBackend::new(other_seed.expose_secret())),
+            ..Default::default()
+        };
+
+        assert_matches!(
+            PrivateCrossSigningIdentity::empty(user_id())
+                .import_backends(&public_identity, backends)
+                .await,
+            Err(SecretImportError::MismatchedPublicKeys)
+        );
+    }
 }
@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{fmt, sync::Arc};
+
+use matrix_sdk_common::{SendOutsideWasm, SyncOutsideWasm};
 use ruma::{encryption::KeyUsage, DeviceKeyAlgorithm, DeviceKeyId, OwnedUserId};
 use serde::{Deserialize, Serialize};
 use serde_json::{Error as JsonError, Value};
@@ -21,10 +24,10 @@ use zeroize::Zeroize;
 
 use crate::{
     error::SignatureError,
-    olm::utility::SignJson,
+    olm::utility::{to_signable_json, SignJson},
     types::{
-        CrossSigningKey, DeviceKeys, MasterPubkey, SelfSigningPubkey, Signatures, SigningKeys,
-        UserSigningPubkey,
+        CrossSigningKey, DeviceKeys, MasterPubkey, SecretString, SelfSigningPubkey, Signatures,
+        SigningKeys, UserSigningPubkey,
     },
     OtherUserIdentityData,
 };
@@ -45,9 +48,35 @@ pub enum SigningError {
     Json(#[from] JsonError),
 }
 
-#[derive(Serialize, Deserialize)]
+/// A backend holding the private part of one of our cross-signing keys, for
+/// example in a hardware security module or an OS keystore.
+///
+/// Only signature requests pass through the [`PrivateCrossSigningIdentity`],
+/// the private key never has to leave the backend. Keys held by a backend
+/// aren't pickled into the crypto store, so the backends need to be imported
+/// using [`OlmMachine::import_cross_signing_backends()`] every time the
+/// `OlmMachine` is created.
+///
+/// [`PrivateCrossSigningIdentity`]: crate::olm::PrivateCrossSigningIdentity
+/// [`OlmMachine::import_cross_signing_backends()`]: crate::OlmMachine::import_cross_signing_backends
+pub trait SigningBackend: fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// Get the public part of the Ed25519 key this backend signs with.
+    fn public_key(&self) -> Ed25519PublicKey;
+
+    /// Sign the given message with the private part of the Ed25519 key.
+    fn sign(&self, message: &[u8]) -> Result<Ed25519Signature, SignatureError>;
+}
+
+/// The private part of a cross-signing key.
+enum SigningKey {
+    /// The key is held in memory, and pickled into the crypto store.
+    Local(Ed25519SecretKey),
+    /// The key is held by an external [`SigningBackend`].
+    Backend(Arc<dyn SigningBackend>),
+}
+
 pub struct Signing {
-    inner: Ed25519SecretKey,
+    inner: SigningKey,
     public_key: Ed25519PublicKey,
 }
 
@@ -66,7 +95,10 @@ impl PartialEq for Signing {
 
 impl SignJson for Signing {
     fn sign_json(&self, value: Value) -> Result<Ed25519Signature, SignatureError> {
-        self.inner.sign_json(value)
+        match &self.inner {
+            SigningKey::Local(key) => key.sign_json(value),
+            SigningKey::Backend(backend) => backend.sign(to_signable_json(value)?.as_bytes()),
+        }
     }
 }
 
@@ -107,7 +139,8 @@ impl MasterSigning {
         let mut key = Self { inner, public_key };
         let mut cross_signing_key = key.public_key.as_ref().to_owned();
 
-        key.sign_subkey(&mut cross_signing_key);
+        key.sign_subkey(&mut cross_signing_key)
+            .expect("A freshly created master key can always sign its own public key");
         key.public_key = cross_signing_key
             .try_into()
             .expect("A freshly signed master key can be converted into a MasterPubkey");
@@ -120,7 +153,7 @@ impl MasterSigning {
         let mut public_key =
             user.cross_signing_key(self.public_key.user_id().to_owned(), KeyUsage::UserSigning);
 
-        self.sign_subkey(&mut public_key);
+        self.sign_subkey(&mut public_key).expect("Can't sign our freshly created user-signing key");
 
         let user = UserSigning {
             inner: user,
@@ -132,7 +165,7 @@ impl MasterSigning {
         let self_signing = Signing::new();
         let mut public_key = self_signing
             .cross_signing_key(self.public_key.user_id().to_owned(), KeyUsage::SelfSigning);
-        self.sign_subkey(&mut public_key);
+        self.sign_subkey(&mut public_key).expect("Can't sign our freshly created self-signing key");
 
         let self_signing = SelfSigning {
             inner: self_signing,
@@ -152,13 +185,13 @@ impl MasterSigning {
         &mut self.public_key
     }
 
-    pub fn pickle(&self) -> PickledMasterSigning {
-        let pickle = self.inner.pickle();
+    pub fn pickle(&self) -> Option<PickledMasterSigning> {
+        let pickle = self.inner.pickle()?;
         let public_key = self.public_key.clone();
-        PickledMasterSigning { pickle, public_key }
+        Some(PickledMasterSigning { pickle, public_key })
     }
 
-    pub fn export_seed(&self) -> String {
+    pub fn export_seed(&self) -> Option<SecretString> {
         self.inner.to_base64()
     }
 
@@ -172,6 +205,16 @@ impl MasterSigning {
         Ok(Self { inner, public_key })
     }
 
+    pub fn from_backend(user_id: OwnedUserId, backend: Arc<dyn SigningBackend>) -> Self {
+        let inner = Signing::from_backend(backend);
+        let public_key = inner
+            .cross_signing_key(user_id, KeyUsage::Master)
+            .try_into()
+            .expect("A master key can always be created from a signing backend");
+
+        Self { inner, public_key }
+    }
+
     pub fn from_pickle(pickle: PickledMasterSigning) -> Result<Self, SigningError> {
         let inner = Signing::from_pickle(pickle.pickle)?;
         let public_key = pickle.public_key;
@@ -179,15 +222,15 @@ impl MasterSigning {
         Ok(Self { inner, public_key })
     }
 
-    pub fn sign(&self, message: &str) -> Ed25519Signature {
+    pub fn sign(&self, message: &str) -> Result<Ed25519Signature, SignatureError> {
         self.inner.sign(message)
     }
 
-    pub fn sign_subkey(&self, subkey: &mut CrossSigningKey) {
+    pub fn sign_subkey(&self, subkey: &mut CrossSigningKey) -> Result<(), SignatureError> {
         #[allow(clippy::needless_borrows_for_generic_args)]
         // XXX: false positive, see https://github.com/rust-lang/rust-clippy/issues/12856
         let json_subkey = serde_json::to_value(&subkey).expect("Can't serialize cross signing key");
-        let signature = self.inner.sign_json(json_subkey).expect("Can't sign cross signing keys");
+        let signature = self.inner.sign_json(json_subkey)?;
 
         subkey.signatures.add_signature(
             self.public_key.user_id().to_owned(),
@@ -197,21 +240,23 @@ impl MasterSigning {
             ),
             signature,
         );
+
+        Ok(())
     }
 }
 
 impl UserSigning {
-    pub fn pickle(&self) -> PickledUserSigning {
-        let pickle = self.inner.pickle();
+    pub fn pickle(&self) -> Option<PickledUserSigning> {
+        let pickle = self.inner.pickle()?;
         let public_key = self.public_key.clone();
-        PickledUserSigning { pickle, public_key }
+        Some(PickledUserSigning { pickle, public_key })
     }
 
     pub fn public_key(&self) -> &UserSigningPubkey {
         &self.public_key
     }
 
-    pub fn export_seed(&self) -> String {
+    pub fn export_seed(&self) -> Option<SecretString> {
         self.inner.to_base64()
     }
 
@@ -225,6 +270,16 @@ impl UserSigning {
         Ok(Self { inner, public_key })
     }
 
+    pub fn from_backend(user_id: OwnedUserId, backend: Arc<dyn SigningBackend>) -> Self {
+        let inner = Signing::from_backend(backend);
+        let public_key = inner
+            .cross_signing_key(user_id, KeyUsage::UserSigning)
+            .try_into()
+            .expect("A user-signing key can always be created from a signing backend");
+
+        Self { inner, public_key }
+    }
+
     pub fn sign_user(
         &self,
         user: &OtherUserIdentityData,
@@ -266,17 +321,17 @@ impl UserSigning {
 }
 
 impl SelfSigning {
-    pub(crate) fn pickle(&self) -> PickledSelfSigning {
-        let pickle = self.inner.pickle();
+    pub(crate) fn pickle(&self) -> Option<PickledSelfSigning> {
+        let pickle = self.inner.pickle()?;
         let public_key = self.public_key.clone();
-        PickledSelfSigning { pickle, public_key }
+        Some(PickledSelfSigning { pickle, public_key })
     }
 
     pub fn public_key(&self) -> &SelfSigningPubkey {
         &self.public_key
     }
 
-    pub fn export_seed(&self) -> String {
+    pub fn export_seed(&self) -> Option<SecretString> {
         self.inner.to_base64()
     }
 
@@ -290,6 +345,16 @@ impl SelfSigning {
         Ok(Self { inner, public_key })
     }
 
+    pub fn from_backend(user_id: OwnedUserId, backend: Arc<dyn SigningBackend>) -> Self {
+        let inner = Signing::from_backend(backend);
+        let public_key = inner
+            .cross_signing_key(user_id, KeyUsage::SelfSigning)
+            .try_into()
+            .expect("A self-signing key can always be created from a signing backend");
+
+        Self { inner, public_key }
+    }
+
     pub(crate) fn sign_device(&self, device_keys: &mut DeviceKeys) -> Result<(), SignatureError> {
         #[allow(clippy::needless_borrows_for_generic_args)]
         // XXX: false positive, see https://github.com/rust-lang/rust-clippy/issues/12856
@@ -347,7 +412,13 @@ impl Signing {
     fn new_helper(secret_key: Ed25519SecretKey) -> Self {
         let public_key = secret_key.public_key();
 
-        Signing { inner: secret_key, public_key }
+        Signing { inner: SigningKey::Local(secret_key), public_key }
+    }
+
+    pub fn from_backend(backend: Arc<dyn SigningBackend>) -> Self {
+        let public_key = backend.public_key();
+
+        Signing { inner: SigningKey::Backend(backend), public_key }
     }
 
     pub fn from_base64(key: &str) -> Result<Self, KeyError> {
@@ -359,17 +430,28 @@ impl Signing {
         Ok(Self::new_helper(pickle.0))
     }
 
-    pub fn to_base64(&self) -> String {
-        self.inner.to_base64()
+    /// Export the private key as unpadded base64, returns `None` if the key is
+    /// held by a [`SigningBackend`].
+    pub fn to_base64(&self) -> Option<SecretString> {
+        match &self.inner {
+            SigningKey::Local(key) => Some(key.to_base64().into()),
+            SigningKey::Backend(_) => None,
+        }
     }
 
-    pub fn pickle(&self) -> PickledSigning {
-        let mut bytes = self.inner.to_bytes();
+    /// Pickle the private key, returns `None` if the key is held by a
+    /// [`SigningBackend`].
+    pub fn pickle(&self) -> Option<PickledSigning> {
+        let SigningKey::Local(key) = &self.inner else {
+            return None;
+        };
+
+        let mut bytes = key.to_bytes();
         let ret = PickledSigning(Ed25519SecretKey::from_slice(&bytes));
 
         bytes.zeroize();
 
-        ret
+        Some(ret)
     }
 
     pub fn public_key(&self) -> Ed25519PublicKey {
@@ -382,13 +464,16 @@ impl Signing {
                 DeviceKeyAlgorithm::Ed25519,
                 self.public_key().to_base64().as_str().into(),
             ),
-            self.inner.public_key().into(),
+            self.public_key.into(),
         )]);
 
         CrossSigningKey::new(user_id, vec![usage], keys, Default::default())
     }
 
-    pub fn sign(&self, message: &str) -> Ed25519Signature {
-        self.inner.sign(message.as_bytes())
+    pub fn sign(&self, message: &str) -> Result<Ed25519Signature, SignatureError> {
+        match &self.inner {
+            SigningKey::Local(key) => Ok(key.sign(message.as_bytes())),
+            SigningKey::Backend(backend) => backend.sign(message.as_bytes()),
+        }
     }
 }
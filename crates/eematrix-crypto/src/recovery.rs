@@ -0,0 +1,180 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Human-friendly representations of recovery keys.
+//!
+//! Besides the Base58 representation defined in the [spec], the
+//! [`BackupDecryptionKey`] and the [`SecretStorageKey`] can be encoded as a
+//! mnemonic: a phrase of 24 words taken from the English [BIP-39] word list.
+//! The last word contains a checksum, so typos in a recovery phrase are
+//! detected before the key is used.
+//!
+//! Mnemonics aren't part of the Matrix spec, other clients won't understand
+//! them. They are only meant to be presented to the user, clients should
+//! always accept the Base58 representation as well.
+//!
+//! [spec]: https://spec.matrix.org/v1.8/client-server-api/#key-representation
+//! [BIP-39]: https://github.com/bitcoin/bips/blob/master/bip-0039.mediawiki
+//! [`BackupDecryptionKey`]: crate::store::types::BackupDecryptionKey
+//! [`SecretStorageKey`]: crate::secret_storage::SecretStorageKey
+
+use bip39::{Language, Mnemonic};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{ciphers::KEY_SIZE, types::SecretString};
+
+/// The number of words of the mnemonic of a recovery key.
+pub const MNEMONIC_WORD_COUNT: usize = 24;
+
+/// Error type for the decoding of a recovery key from a mnemonic.
+#[derive(Debug, Error)]
+pub enum MnemonicError {
+    /// The phrase contains a word which isn't part of the word list.
+    #[error("The word at position {0} of the recovery phrase isn't known")]
+    UnknownWord(usize),
+    /// The phrase doesn't contain the expected number of words.
+    #[error("The recovery phrase has an invalid number of words: expected {0}, got {1}")]
+    WordCount(usize, usize),
+    /// The checksum contained in the phrase didn't match.
+    #[error("The checksum of the recovery phrase doesn't match")]
+    Checksum,
+    /// The recovery phrase is a valid mnemonic, but the key it encodes isn't
+    /// valid for the given secret storage key description.
+    #[error(transparent)]
+    SecretStorageKey(#[from] crate::secret_storage::DecodeError),
+}
+
+impl From<bip39::Error> for MnemonicError {
+    fn from(error: bip39::Error) -> Self {
+        match error {
+            bip39::Error::UnknownWord(position) => Self::UnknownWord(position),
+            bip39::Error::BadWordCount(count) => Self::WordCount(MNEMONIC_WORD_COUNT, count),
+            // We only use a single language and always encode keys of the same
+            // size, anything else means the phrase has been tampered with.
+            bip39::Error::InvalidChecksum
+            | bip39::Error::BadEntropyBitCount(_)
+            | bip39::Error::AmbiguousLanguages(_) => Self::Checksum,
+        }
+    }
+}
+
+/// Encode the given key as a mnemonic of [`MNEMONIC_WORD_COUNT`] words,
+/// separated by spaces.
+pub fn encode_mnemonic(key: &[u8; KEY_SIZE]) -> SecretString {
+    let mut mnemonic = Mnemonic::from_entropy_in(Language::English, key)
+        .expect("A 32 byte key should always be encodable as a mnemonic");
+    let phrase = mnemonic.to_string();
+
+    mnemonic.zeroize();
+
+    phrase.into()
+}
+
+/// Decode a key from the given mnemonic, validating its checksum.
+///
+/// The words may be separated by any amount of whitespace, and are matched
+/// case-insensitively.
+pub fn decode_mnemonic(phrase: &str) -> Result<Box<[u8; KEY_SIZE]>, MnemonicError> {
+    let word_count = phrase.split_whitespace().count();
+
+    if word_count != MNEMONIC_WORD_COUNT {
+        return Err(MnemonicError::WordCount(MNEMONIC_WORD_COUNT, word_count));
+    }
+
+    let normalized = Zeroizing::new(
+        phrase.split_whitespace().map(str::to_lowercase).collect::<Vec<_>>().join(" "),
+    );
+    let mut mnemonic = Mnemonic::parse_in_normalized(Language::English, &normalized)?;
+    let (mut entropy, length) = mnemonic.to_entropy_array();
+
+    mnemonic.zeroize();
+
+    if length != KEY_SIZE {
+        entropy.zeroize();
+        return Err(MnemonicError::WordCount(MNEMONIC_WORD_COUNT, word_count));
+    }
+
+    let mut key = Box::new([0u8; KEY_SIZE]);
+    key.copy_from_slice(&entropy[..KEY_SIZE]);
+    entropy.zeroize();
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::{decode_mnemonic, encode_mnemonic, MnemonicError, MNEMONIC_WORD_COUNT};
+    use crate::{secret_storage::SecretStorageKey, store::types::BackupDecryptionKey};
+
+    #[test]
+    fn test_mnemonic_roundtrip() {
+        let key = [7u8; 32];
+
+        let phrase = encode_mnemonic(&key);
+        assert_eq!(phrase.split(' ').count(), MNEMONIC_WORD_COUNT);
+
+        let decoded = decode_mnemonic(&phrase).unwrap();
+        assert_eq!(*decoded, key);
+
+        // Whitespace and case don't matter.
+        let mangled = format!("  {}\n", phrase.to_uppercase().replace(' ', "   "));
+        let decoded = decode_mnemonic(&mangled).unwrap();
+        assert_eq!(*decoded, key);
+    }
+
+    #[test]
+    fn test_mnemonic_validation() {
+        let phrase = encode_mnemonic(&[0u8; 32]);
+        let mut words: Vec<_> = phrase.split(' ').collect();
+
+        // The all-zero key encodes as 23 times the first word of the list
+        // followed by a checksum word, swapping the last word for the first
+        // one breaks the checksum.
+        words[MNEMONIC_WORD_COUNT - 1] = words[0];
+        assert_matches!(decode_mnemonic(&words.join(" ")), Err(MnemonicError::Checksum));
+
+        words[3] = "matrix-rust-sdk";
+        assert_matches!(decode_mnemonic(&words.join(" ")), Err(MnemonicError::UnknownWord(3)));
+
+        assert_matches!(
+            decode_mnemonic(&words[..12].join(" ")),
+            Err(MnemonicError::WordCount(MNEMONIC_WORD_COUNT, 12))
+        );
+    }
+
+    #[test]
+    fn test_recovery_key_mnemonics() {
+        let backup_key = BackupDecryptionKey::new().unwrap();
+        let restored =
+            BackupDecryptionKey::from_mnemonic(backup_key.to_mnemonic().expose_secret()).unwrap();
+        assert_eq!(restored.to_base58(), backup_key.to_base58());
+
+        let key = SecretStorageKey::new();
+        let content = key.event_content().to_owned();
+        let restored =
+            SecretStorageKey::from_mnemonic(key.to_mnemonic().expose_secret(), content.clone())
+                .unwrap();
+        assert_eq!(restored.to_base58(), key.to_base58());
+
+        // The phrase of another key doesn't pass the MAC check of this one.
+        let other_key = SecretStorageKey::new();
+        assert_matches!(
+            SecretStorageKey::from_mnemonic(other_key.to_mnemonic().expose_secret(), content),
+            Err(MnemonicError::SecretStorageKey(_))
+        );
+    }
+}
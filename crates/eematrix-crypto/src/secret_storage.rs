@@ -47,7 +47,10 @@ use subtle::ConstantTimeEq;
 use thiserror::Error;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use crate::ciphers::{AesHmacSha2Key, HmacSha256Mac, IV_SIZE, KEY_SIZE, MAC_SIZE};
+use crate::{
+    ciphers::{AesHmacSha2Key, HmacSha256Mac, IV_SIZE, KEY_SIZE, MAC_SIZE},
+    types::SecretString,
+};
 
 /// Error type for the decoding of a [`SecretStorageKey`].
 ///
@@ -458,15 +461,35 @@ impl SecretStorageKey {
         Ok(key)
     }
 
+    /// Restore a [`SecretStorageKey`] from a recovery phrase created by
+    /// [`SecretStorageKey::to_mnemonic()`] and the description of the key.
+    ///
+    /// Like [`SecretStorageKey::from_account_data()`], this checks that the
+    /// key encoded in the phrase matches the description. See the
+    /// [`recovery`](crate::recovery) module for details about the format.
+    #[cfg(feature = "mnemonic")]
+    pub fn from_mnemonic(
+        phrase: &str,
+        content: SecretStorageKeyEventContent,
+    ) -> Result<Self, crate::recovery::MnemonicError> {
+        let secret_key = crate::recovery::decode_mnemonic(phrase)?;
+        let key = Self { storage_key_info: content, secret_key };
+        key.check_zero_message()?;
+
+        Ok(key)
+    }
+
+    /// Export the [`SecretStorageKey`] as a recovery phrase of 24 words.
+    #[cfg(feature = "mnemonic")]
+    pub fn to_mnemonic(&self) -> SecretString {
+        crate::recovery::encode_mnemonic(&self.secret_key)
+    }
+
     /// Export the [`SecretStorageKey`] as a base58-encoded string as defined in
     /// the [spec].
     ///
-    /// *Note*: This returns a copy of the private key material of the
-    /// [`SecretStorageKey`] as a string. The caller needs to ensure that this
-    /// string is zeroized.
-    ///
     /// [spec]: https://spec.matrix.org/v1.8/client-server-api/#key-representation
-    pub fn to_base58(&self) -> String {
+    pub fn to_base58(&self) -> SecretString {
         const DISPLAY_CHUNK_SIZE: usize = 4;
 
         let mut bytes = Box::new([0u8; Self::DECODED_BASE58_KEY_LEN]);
@@ -496,7 +519,7 @@ impl SecretStorageKey {
             .collect::<Vec<_>>()
             .join(" ");
 
-        ret
+        ret.into()
     }
 
     /// Encrypt a given secret string as a Secrets Storage secret with the
@@ -663,7 +686,7 @@ mod test {
 
         let base58_key = key.to_base58();
 
-        let key = SecretStorageKey::from_account_data(&base58_key, content)
+        let key = SecretStorageKey::from_account_data(base58_key.expose_secret(), content)
             .expect("We should be able to restore our secret storage key");
 
         let decrypted = key.decrypt(&encrypted, &secret_name).expect(
@@ -748,7 +771,7 @@ mod test {
         let base58_key = key.to_base58();
 
         assert_matches!(
-            SecretStorageKey::from_account_data(&base58_key, content),
+            SecretStorageKey::from_account_data(base58_key.expose_secret(), content),
             Err(DecodeError::Mac(_)),
             "Using the wrong base58 key should throw a MAC error"
         );
@@ -34,7 +34,7 @@ use ruma::{
     DeviceId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId, TransactionId,
     UserId,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 pub(crate) use share_strategy::CollectRecipientsResult;
 pub use share_strategy::CollectStrategy;
 use tracing::{debug, error, info, instrument, trace, warn, Instrument};
@@ -46,7 +46,10 @@ use crate::{
         InboundGroupSession, OutboundGroupSession, SenderData, SenderDataFinder, Session,
         ShareInfo, ShareState,
     },
-    store::{types::Changes, CryptoStoreWrapper, Result as StoreResult, Store},
+    store::{
+        types::{Changes, SessionShareRecord},
+        CryptoStoreWrapper, Result as StoreResult, Store,
+    },
     types::{
         events::{
             room::encrypted::{RoomEncryptedEventContent, ToDeviceEncryptedEventContent},
@@ -55,6 +58,7 @@ use crate::{
         },
         requests::ToDeviceRequest,
     },
+    utilities::LatencyTimer,
     Device, DeviceData, EncryptionSettings, OlmError,
 };
 
@@ -120,6 +124,13 @@ impl GroupSessionCache {
         self.sessions.read().get(room_id).cloned()
     }
 
+    /// Remove the outbound group session of the given room from the cache,
+    /// including the requests that are still being shared.
+    pub(crate) fn remove(&self, room_id: &RoomId) -> Option<OutboundGroupSession> {
+        self.sessions_being_shared.write().retain(|_, s| s.room_id() != room_id);
+        self.sessions.write().remove(room_id)
+    }
+
     /// Returns whether any session is withheld with the given device and code.
     fn has_session_withheld_to(&self, device: &DeviceData, code: &WithheldCode) -> bool {
         self.sessions.read().values().any(|s| s.sharing_view().is_withheld_to(device, code))
@@ -134,6 +145,21 @@ impl GroupSessionCache {
     }
 }
 
+/// A room key that was withheld from a device because the device was
+/// blacklisted or unverified.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct WithheldRoomKey {
+    /// The ID of the withheld session.
+    session_id: String,
+    /// The message index of the session at the time it was withheld, the
+    /// session will be re-shared starting from this index.
+    message_index: u32,
+}
+
+/// The room keys we withheld in a room, grouped by the user and device they
+/// were withheld from.
+type WithheldRoomKeys = BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, Vec<WithheldRoomKey>>>;
+
 #[derive(Debug, Clone)]
 pub(crate) struct GroupSessionManager {
     /// Store for the encryption keys.
@@ -147,6 +173,10 @@ pub(crate) struct GroupSessionManager {
 impl GroupSessionManager {
     const MAX_TO_DEVICE_MESSAGES: usize = 250;
 
+    /// The maximum number of withheld room keys we remember per device, older
+    /// ones are forgotten and won't be re-shared.
+    const MAX_WITHHELD_ROOM_KEYS_PER_DEVICE: usize = 50;
+
     pub fn new(store: Store) -> Self {
         Self { store: store.clone(), sessions: GroupSessionCache::new(store) }
     }
@@ -170,10 +200,27 @@ impl GroupSessionManager {
             return Ok(());
         };
 
+        let share_info = session.pending_share_info(request_id).unwrap_or_default();
         let no_olm = session.mark_request_as_sent(request_id);
 
+        let shared_at = self.store.clock().now();
         let mut changes = Changes::default();
 
+        // The share history is saved together with the session, so it can't
+        // miss the recipients of a room key which is marked as shared.
+        for (user_id, devices) in share_info {
+            changes.session_share_records.extend(devices.into_iter().map(
+                |(device_id, share_info)| SessionShareRecord {
+                    room_id: session.room_id().to_owned(),
+                    session_id: session.session_id().to_owned(),
+                    user_id: user_id.clone(),
+                    device_id,
+                    share_info,
+                    shared_at,
+                },
+            ));
+        }
+
         for (user_id, devices) in &no_olm {
             for device_id in devices {
                 let device = self.store.get_device(user_id, device_id).await;
@@ -211,6 +258,8 @@ impl GroupSessionManager {
 
         assert!(!session.expired(), "Session expired");
 
+        session.settings().validate_content(content)?;
+
         let content = session.encrypt(event_type, content).await;
 
         let mut changes = Changes::default();
@@ -232,10 +281,17 @@ impl GroupSessionManager {
         let (outbound, inbound) = self
             .store
             .static_account()
-            .create_group_session_pair(room_id, settings, own_sender_data)
+            .create_group_session_pair_at(
+                room_id,
+                settings,
+                own_sender_data,
+                self.store.clock().now_secs(),
+            )
             .await
             .map_err(|_| EventError::UnsupportedAlgorithm)?;
 
+        self.store.record_statistics(|statistics| statistics.megolm_sessions_stored += 1);
+
         self.sessions.insert(outbound.clone());
         Ok((outbound, inbound))
     }
@@ -251,7 +307,7 @@ impl GroupSessionManager {
         // If there is no session or the session has expired or is invalid,
         // create a new one.
         if let Some(s) = outbound_session {
-            if s.expired() || s.invalidated() {
+            if s.expired_at(self.store.clock().now_secs()) || s.invalidated() {
                 self.create_outbound_group_session(room_id, settings, own_sender_data)
                     .await
                     .map(|(o, i)| (o, i.into()))
@@ -415,6 +471,32 @@ impl GroupSessionManager {
         })
     }
 
+    /// Apply the content validation of the given settings to the given
+    /// session, if it changed, see
+    /// [`OutboundGroupSession::with_content_validation()`].
+    fn update_content_validation(
+        &self,
+        outbound: OutboundGroupSession,
+        settings: &EncryptionSettings,
+        changes: &mut Changes,
+    ) -> OutboundGroupSession {
+        let current = outbound.settings();
+
+        if current.max_content_size == settings.max_content_size
+            && current.strict_relation_validation == settings.strict_relation_validation
+        {
+            return outbound;
+        }
+
+        debug!("Applying the changed content validation settings to the current room key");
+
+        let outbound = outbound.with_content_validation(settings);
+        self.sessions.insert(outbound.clone());
+        changes.outbound_group_sessions.push(outbound.clone());
+
+        outbound
+    }
+
     async fn encrypt_for_devices(
         &self,
         recipient_devices: Vec<DeviceData>,
@@ -573,6 +655,157 @@ impl GroupSessionManager {
         Ok(())
     }
 
+    /// Remember the devices we withheld the room key from because they were
+    /// blacklisted or unverified, so that the key can be re-shared once they
+    /// become verified.
+    async fn record_withheld_room_keys(
+        &self,
+        room_id: &RoomId,
+        group_session: &OutboundGroupSession,
+        withheld_devices: &[(DeviceData, WithheldCode)],
+    ) -> OlmResult<()> {
+        let mut withheld_devices = withheld_devices
+            .iter()
+            .filter(|(_, code)| {
+                matches!(code, WithheldCode::Blacklisted | WithheldCode::Unverified)
+            })
+            .peekable();
+
+        if withheld_devices.peek().is_none() {
+            return Ok(());
+        }
+
+        let key = Store::withheld_room_keys_key(room_id);
+        let mut withheld_keys =
+            self.store.get_value::<WithheldRoomKeys>(&key).await?.unwrap_or_default();
+
+        let session_id = group_session.session_id();
+        let message_index = group_session.message_index().await;
+        let mut changed = false;
+
+        for (device, _) in withheld_devices {
+            let keys = withheld_keys
+                .entry(device.user_id().to_owned())
+                .or_default()
+                .entry(device.device_id().to_owned())
+                .or_default();
+
+            if !keys.iter().any(|k| k.session_id == session_id) {
+                keys.push(WithheldRoomKey { session_id: session_id.to_owned(), message_index });
+                changed = true;
+            }
+
+            if keys.len() > Self::MAX_WITHHELD_ROOM_KEYS_PER_DEVICE {
+                let excess = keys.len() - Self::MAX_WITHHELD_ROOM_KEYS_PER_DEVICE;
+                keys.drain(..excess);
+            }
+        }
+
+        if changed {
+            self.store.set_value(&key, &withheld_keys).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-share the room keys we withheld in the given room from devices of
+    /// the given user which have since become verified.
+    ///
+    /// Sessions are shared starting from the message index they had when they
+    /// were withheld. Records for devices we don't have an Olm session with
+    /// are kept, so the key can be re-shared once a session is established.
+    ///
+    /// Returns a list of to-device requests which must be sent.
+    #[instrument(skip(self))]
+    pub async fn reshare_withheld_keys(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> OlmResult<Vec<ToDeviceRequest>> {
+        let key = Store::withheld_room_keys_key(room_id);
+
+        let Some(mut withheld_keys) = self.store.get_value::<WithheldRoomKeys>(&key).await? else {
+            return Ok(Vec::new());
+        };
+        let Some(devices) = withheld_keys.get_mut(user_id) else {
+            return Ok(Vec::new());
+        };
+
+        let mut changes = Changes::default();
+        let mut requests = Vec::new();
+
+        for (device_id, keys) in devices.iter_mut() {
+            let Some(device) = self.store.get_device(user_id, device_id).await? else {
+                continue;
+            };
+
+            if device.is_blacklisted() || !device.is_verified() {
+                continue;
+            }
+
+            let mut remaining = Vec::new();
+
+            for withheld_key in keys.drain(..) {
+                let Some(session) =
+                    self.store.get_inbound_group_session(room_id, &withheld_key.session_id).await?
+                else {
+                    warn!(
+                        session_id = %withheld_key.session_id,
+                        "Can't re-share a withheld room key, the session is missing"
+                    );
+                    continue;
+                };
+
+                match device
+                    .encrypt_room_key_for_forwarding(session, Some(withheld_key.message_index))
+                    .await
+                {
+                    Ok((used_session, content)) => {
+                        let event_type = content.event_type().to_owned();
+
+                        requests.push(ToDeviceRequest::new(
+                            user_id,
+                            device_id.to_owned(),
+                            &event_type,
+                            content.cast(),
+                        ));
+                        changes.sessions.push(used_session);
+                    }
+                    Err(OlmError::MissingSession) => {
+                        debug!(
+                            ?device_id,
+                            "Not re-sharing a withheld room key, no Olm session was found"
+                        );
+                        remaining.push(withheld_key);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            *keys = remaining;
+        }
+
+        devices.retain(|_, keys| !keys.is_empty());
+
+        if devices.is_empty() {
+            withheld_keys.remove(user_id);
+        }
+
+        if !changes.is_empty() {
+            self.store.save_changes(changes).await?;
+        }
+
+        if withheld_keys.is_empty() {
+            self.store.remove_custom_value(&key).await?;
+        } else {
+            self.store.set_value(&key, &withheld_keys).await?;
+        }
+
+        info!(request_count = requests.len(), "Re-sharing withheld room keys");
+
+        Ok(requests)
+    }
+
     fn log_room_key_sharing_result(requests: &[Arc<ToDeviceRequest>]) {
         for request in requests {
             let message_list = Self::to_device_request_to_log_list(request);
@@ -626,21 +859,31 @@ impl GroupSessionManager {
     ///
     /// `encryption_settings` - The settings that should be used for
     /// the room key.
-    #[instrument(skip(self, users, encryption_settings), fields(session_id))]
+    #[instrument(skip(self, users, encryption_settings), fields(session_id, duration_ms, count))]
     pub async fn share_room_key(
         &self,
         room_id: &RoomId,
         users: impl Iterator<Item = &UserId>,
         encryption_settings: impl Into<EncryptionSettings>,
     ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
+        // The count is the number of to-device requests we need to send out.
+        let mut timer = LatencyTimer::start("share_room_key");
+
         trace!("Checking if a room key needs to be shared");
 
         let account = self.store.static_account();
         let device = self.store.get_device(account.user_id(), account.device_id()).await?;
 
-        let encryption_settings = encryption_settings.into();
+        let mut encryption_settings = encryption_settings.into();
         let mut changes = Changes::default();
 
+        // Apply the rotation criteria which were configured for this room.
+        let rotation_policy = self.store.get_room_rotation_policy(room_id).await?;
+
+        if let Some(rotation_policy) = &rotation_policy {
+            rotation_policy.apply(&mut encryption_settings);
+        }
+
         // Try to get an existing session or create a new one.
         let (outbound, inbound) = self
             .get_or_create_outbound_session(
@@ -680,9 +923,28 @@ impl GroupSessionManager {
         let CollectRecipientsResult { should_rotate, devices, mut withheld_devices } =
             self.collect_session_recipients(users, &encryption_settings, &outbound).await?;
 
+        // If the rotation policy of the room was changed after the current session
+        // was created, the session should follow the new policy.
+        let rotation_policy_changed = rotation_policy.is_some()
+            && (outbound.settings().rotation_period != encryption_settings.rotation_period
+                || outbound.settings().rotation_period_msgs
+                    != encryption_settings.rotation_period_msgs);
+
+        if rotation_policy_changed {
+            debug!("Rotating room key since the rotation policy of the room changed");
+        }
+
+        // The content validation doesn't affect the room key, so a change of it is
+        // applied to the current session right away instead of with the next rotation.
+        let outbound = if should_rotate || rotation_policy_changed {
+            outbound
+        } else {
+            self.update_content_validation(outbound, &encryption_settings, &mut changes)
+        };
+
         let outbound = self
             .maybe_rotate_group_session(
-                should_rotate,
+                should_rotate || rotation_policy_changed,
                 room_id,
                 outbound,
                 encryption_settings,
@@ -723,6 +985,10 @@ impl GroupSessionManager {
         // Merge the withheld recipients.
         withheld_devices.extend(unable_to_encrypt_devices);
 
+        // Remember the devices that didn't receive the room key because they aren't
+        // trusted, so we can re-share it with them once they get verified.
+        self.record_withheld_room_keys(room_id, &outbound, &withheld_devices).await?;
+
         // Now handle and add the withheld recipients to the resulting requests to the
         // `OutboundGroupSession`.
         self.handle_withheld_devices(&outbound, withheld_devices)?;
@@ -755,6 +1021,8 @@ impl GroupSessionManager {
             );
         }
 
+        timer.set_count(requests.len());
+
         Ok(requests)
     }
 
@@ -1046,7 +1314,7 @@ mod tests {
             history_visibility::HistoryVisibility, EncryptedFileInit, JsonWebKey, JsonWebKeyInit,
         },
         owned_room_id, room_id,
-        serde::Base64,
+        serde::{Base64, Raw},
         to_device::DeviceIdOrAllDevices,
         user_id, DeviceId, OneTimeKeyAlgorithm, OwnedMxcUri, TransactionId, UInt, UserId,
     };
@@ -1058,7 +1326,11 @@ mod tests {
             test_helpers::get_machine_pair_with_setup_sessions_test_helper, EncryptionSyncChanges,
         },
         olm::{Account, SenderData},
-        session_manager::{group_sessions::CollectRecipientsResult, CollectStrategy},
+        session_manager::{
+            group_sessions::{CollectRecipientsResult, GroupSessionManager, WithheldRoomKeys},
+            CollectStrategy,
+        },
+        store::{types::RoomRotationPolicy, Store},
         types::{
             events::{
                 room::encrypted::EncryptedToDeviceEvent,
@@ -1382,6 +1654,108 @@ mod tests {
         assert!(should_rotate);
     }
 
+    #[async_test]
+    async fn test_room_rotation_policy() {
+        let machine = machine_with_shared_room_key_test_helper().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+        let response = ToDeviceResponse::new();
+
+        let first_session =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+
+        machine.set_room_rotation_policy(room_id, None, Some(1)).await.unwrap();
+        assert_eq!(
+            machine.room_rotation_policy(room_id).await.unwrap(),
+            Some(RoomRotationPolicy { rotation_period: None, rotation_period_msgs: Some(1) })
+        );
+
+        // The policy changed, so the current session gets rotated.
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let requests =
+            machine.share_room_key(room_id, users, EncryptionSettings::default()).await.unwrap();
+        for request in requests {
+            machine.mark_request_as_sent(&request.txn_id, &response).await.unwrap();
+        }
+
+        let second_session =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+        assert_ne!(first_session.session_id(), second_session.session_id());
+        assert_eq!(second_session.settings().rotation_period_msgs, 1);
+
+        // Sharing again doesn't rotate the session as long as it wasn't used.
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        machine.share_room_key(room_id, users, EncryptionSettings::default()).await.unwrap();
+        assert_eq!(
+            second_session.session_id(),
+            machine
+                .inner
+                .group_session_manager
+                .get_outbound_group_session(room_id)
+                .unwrap()
+                .session_id()
+        );
+
+        // After a single message the session expires according to the policy.
+        let content = Raw::new(&json!({ "body": "Hello", "msgtype": "m.text" })).unwrap().cast();
+        machine.encrypt_room_event_raw(room_id, "m.room.message", &content).await.unwrap();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        machine.share_room_key(room_id, users, EncryptionSettings::default()).await.unwrap();
+
+        let third_session =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+        assert_ne!(second_session.session_id(), third_session.session_id());
+
+        // Removing the policy brings back the rotation criteria of the settings.
+        machine.set_room_rotation_policy(room_id, None, None).await.unwrap();
+        assert!(machine.room_rotation_policy(room_id).await.unwrap().is_none());
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        machine.share_room_key(room_id, users, EncryptionSettings::default()).await.unwrap();
+
+        let fourth_session =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+        assert_ne!(third_session.session_id(), fourth_session.session_id());
+        assert_eq!(
+            fourth_session.settings().rotation_period_msgs,
+            EncryptionSettings::default().rotation_period_msgs
+        );
+    }
+
+    #[async_test]
+    async fn test_outbound_session_info() {
+        let machine = machine_with_shared_room_key_test_helper().await;
+        let room_id = room_id!("!test:localhost");
+
+        assert!(machine.outbound_session_info(room_id!("!unknown:localhost")).await.is_none());
+
+        let outbound =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+        let info = machine.outbound_session_info(room_id).await.unwrap();
+
+        assert_eq!(info.session_id, outbound.session_id());
+        assert_eq!(info.creation_time, outbound.creation_time);
+        assert_eq!(info.message_count, 0);
+        assert_eq!(info.rotation_message_count, 100);
+        assert_eq!(
+            u64::from(info.rotation_deadline.get()),
+            u64::from(info.creation_time.get()) + 7 * 24 * 60 * 60
+        );
+        assert!(!info.invalidated);
+
+        // The room key was shared with all the devices which had an Olm session.
+        let shared_with = &info.shared_with[user_id!("@example:localhost")];
+        assert!(shared_with.contains(device_id!("MWVTUXDNNM")));
+        assert!(!info.shared_with.contains_key(alice_id()));
+
+        let content = Raw::new(&json!({ "body": "Hello", "msgtype": "m.text" })).unwrap().cast();
+        machine.encrypt_room_event_raw(room_id, "m.room.message", &content).await.unwrap();
+
+        let info = machine.outbound_session_info(room_id).await.unwrap();
+        assert_eq!(info.message_count, 1);
+    }
+
     #[async_test]
     async fn test_key_recipient_collecting() {
         // The user id comes from the fact that the keys_query.json file uses
@@ -1539,6 +1913,85 @@ mod tests {
         assert!(has_blacklist);
     }
 
+    #[async_test]
+    async fn test_reshare_withheld_keys_after_verification() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let settings = EncryptionSettings {
+            sharing_strategy: CollectStrategy::OnlyTrustedDevices,
+            ..Default::default()
+        };
+
+        let user_id = user_id!("@example:localhost");
+        let device_id = device_id!("MWVTUXDNNM");
+        let device = machine.get_device(user_id, device_id, None).await.unwrap().unwrap();
+        device.set_local_trust(LocalTrust::BlackListed).await.unwrap();
+
+        machine.share_room_key(room_id, users, settings).await.unwrap();
+
+        // The device is still blacklisted, nothing should be re-shared.
+        let requests = machine.reshare_withheld_keys(room_id, user_id).await.unwrap();
+        assert!(requests.is_empty());
+
+        device.set_local_trust(LocalTrust::Verified).await.unwrap();
+
+        let requests = machine.reshare_withheld_keys(room_id, user_id).await.unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let request = &requests[0];
+        assert_eq!(request.event_type, "m.room.encrypted".into());
+        assert!(request.messages[user_id]
+            .contains_key(&DeviceIdOrAllDevices::from(device_id.to_owned())));
+
+        // The key was re-shared, so it shouldn't be shared again.
+        let requests = machine.reshare_withheld_keys(room_id, user_id).await.unwrap();
+        assert!(requests.is_empty());
+    }
+
+    #[async_test]
+    async fn test_withheld_room_keys_are_bounded() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+        let settings = EncryptionSettings {
+            sharing_strategy: CollectStrategy::OnlyTrustedDevices,
+            ..Default::default()
+        };
+
+        let user_id = user_id!("@example:localhost");
+        let device_id = device_id!("MWVTUXDNNM");
+        let device = machine.get_device(user_id, device_id, None).await.unwrap().unwrap();
+        device.set_local_trust(LocalTrust::BlackListed).await.unwrap();
+
+        let mut session_ids = Vec::new();
+
+        for _ in 0..=GroupSessionManager::MAX_WITHHELD_ROOM_KEYS_PER_DEVICE {
+            machine.discard_room_key(room_id).await.unwrap();
+
+            let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+            machine.share_room_key(room_id, users, settings.clone()).await.unwrap();
+
+            let session =
+                machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+            session_ids.push(session.session_id().to_owned());
+        }
+
+        // Only the most recent withheld room keys are remembered.
+        let withheld_keys: WithheldRoomKeys = machine
+            .store()
+            .get_value(&Store::withheld_room_keys_key(room_id))
+            .await
+            .unwrap()
+            .unwrap();
+        let remembered: Vec<_> =
+            withheld_keys[user_id][device_id].iter().map(|k| k.session_id.clone()).collect();
+
+        assert_eq!(remembered, session_ids[1..]);
+    }
+
     #[async_test]
     async fn test_no_olm_withheld_only_sent_once() {
         let keys_query = keys_query_response();
@@ -1786,6 +2239,7 @@ mod tests {
                 v: "".to_owned(),
             })
             .into(),
+            chunk: None,
         };
 
         let requests = alice
@@ -35,7 +35,7 @@ use crate::{Device, UserIdentity};
 
 /// Strategy to collect the devices that should receive room keys for the
 /// current discussion.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 #[cfg_attr(feature = "uniffi", derive(uniffi::Enum))]
 #[serde(from = "CollectStrategyDeserializationHelper")]
 pub enum CollectStrategy {
@@ -62,6 +62,11 @@ pub enum CollectStrategy {
     /// Share based on identity. Only distribute to devices signed by their
     /// owner. If a user has no published identity he will not receive
     /// any room keys.
+    ///
+    /// Can be enabled for a single room using
+    /// [`OlmMachine::set_room_sharing_strategy()`].
+    ///
+    /// [`OlmMachine::set_room_sharing_strategy()`]: crate::OlmMachine::set_room_sharing_strategy
     IdentityBasedStrategy,
 
     /// Only share keys with devices that we "trust". A device is trusted if any
@@ -226,12 +231,23 @@ pub(crate) async fn collect_recipients_for_share_strategy(
 
     let own_identity = store.get_user_identity(store.user_id()).await?.and_then(|i| i.into_own());
 
+    // Devices on the blocklist never get the room key, regardless of the
+    // strategy, and neither do the devices we keep failing to establish an Olm
+    // session with.
+    let blocklist = store.blocked_devices().await?;
+    let unreachable = store.unreachable_devices().await?;
+
     // Get the recipient and withheld devices, based on the collection strategy.
     match share_strategy {
         CollectStrategy::AllDevices => {
             for user_id in users {
                 trace!(?user_id, "CollectStrategy::AllDevices: Considering recipient devices",);
-                let user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                let mut user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                result.withheld_devices.extend(take_excluded_devices(
+                    &mut user_devices,
+                    blocklist.get(user_id),
+                    unreachable.get(user_id),
+                ));
                 let device_owner_identity = store.get_user_identity(user_id).await?;
 
                 let recipient_devices = split_devices_for_user_for_all_devices_strategy(
@@ -251,7 +267,12 @@ pub(crate) async fn collect_recipients_for_share_strategy(
                     ?user_id,
                     "CollectStrategy::ErrorOnVerifiedUserProblem: Considering recipient devices"
                 );
-                let user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                let mut user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                result.withheld_devices.extend(take_excluded_devices(
+                    &mut user_devices,
+                    blocklist.get(user_id),
+                    unreachable.get(user_id),
+                ));
 
                 let device_owner_identity = store.get_user_identity(user_id).await?;
 
@@ -319,7 +340,12 @@ pub(crate) async fn collect_recipients_for_share_strategy(
                     ?user_id,
                     "CollectStrategy::IdentityBasedStrategy: Considering recipient devices"
                 );
-                let user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                let mut user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                result.withheld_devices.extend(take_excluded_devices(
+                    &mut user_devices,
+                    blocklist.get(user_id),
+                    unreachable.get(user_id),
+                ));
 
                 let device_owner_identity = store.get_user_identity(user_id).await?;
 
@@ -347,7 +373,12 @@ pub(crate) async fn collect_recipients_for_share_strategy(
                     ?user_id,
                     "CollectStrategy::OnlyTrustedDevices: Considering recipient devices"
                 );
-                let user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                let mut user_devices = store.get_device_data_for_user_filtered(user_id).await?;
+                result.withheld_devices.extend(take_excluded_devices(
+                    &mut user_devices,
+                    blocklist.get(user_id),
+                    unreachable.get(user_id),
+                ));
                 let device_owner_identity = store.get_user_identity(user_id).await?;
 
                 let recipient_devices = split_devices_for_user_for_only_trusted_devices(
@@ -376,6 +407,26 @@ pub(crate) async fn collect_recipients_for_share_strategy(
     Ok(result)
 }
 
+/// Remove the devices on the device blocklist and the unreachable devices from
+/// the given devices of a user, returning them along with the withheld code
+/// they should receive.
+///
+/// See [`Store::block_device()`] and [`Store::mark_devices_as_unreachable()`].
+fn take_excluded_devices(
+    user_devices: &mut HashMap<OwnedDeviceId, DeviceData>,
+    blocked_devices: Option<&BTreeSet<OwnedDeviceId>>,
+    unreachable_devices: Option<&BTreeSet<OwnedDeviceId>>,
+) -> Vec<(DeviceData, WithheldCode)> {
+    let blocked = blocked_devices.into_iter().flatten().map(|d| (d, WithheldCode::Blacklisted));
+    let unreachable =
+        unreachable_devices.into_iter().flatten().map(|d| (d, WithheldCode::Unavailable));
+
+    blocked
+        .chain(unreachable)
+        .filter_map(|(device_id, code)| Some((user_devices.remove(device_id)?, code)))
+        .collect()
+}
+
 /// Update this [`CollectRecipientsResult`] with the device list for a specific
 /// user.
 fn update_recipients_for_user(
@@ -756,9 +807,9 @@ mod tests {
         // Also import the private cross signing keys
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
@@ -940,6 +991,57 @@ mod tests {
         assert_eq!(code, &WithheldCode::Unverified);
     }
 
+    #[async_test]
+    async fn test_blocked_devices_are_withheld_regardless_of_trust() {
+        let machine = test_machine().await;
+        import_known_users_to_test_machine(&machine).await;
+
+        let dan_id = KeyDistributionTestData::dan_id();
+        let dan_signed_device_id = KeyDistributionTestData::dan_signed_device_id();
+
+        machine.store().block_device(dan_id, dan_signed_device_id).await.unwrap();
+        assert!(machine.store().is_device_blocked(dan_id, dan_signed_device_id).await.unwrap());
+
+        let encryption_settings = EncryptionSettings {
+            sharing_strategy: CollectStrategy::OnlyTrustedDevices,
+            ..Default::default()
+        };
+        let group_session = create_test_outbound_group_session(&machine, &encryption_settings);
+
+        let share_result = collect_session_recipients(
+            machine.store(),
+            iter::once(dan_id),
+            &encryption_settings,
+            &group_session,
+        )
+        .await
+        .unwrap();
+
+        // The signed device of Dan would get the key, but it is blocked.
+        assert!(share_result.devices.get(dan_id).unwrap().is_empty());
+        let (_, code) = share_result
+            .withheld_devices
+            .iter()
+            .find(|(d, _)| d.device_id() == dan_signed_device_id)
+            .expect("The blocked device should receive a withheld code");
+        assert_eq!(code, &WithheldCode::Blacklisted);
+
+        // Once unblocked, it gets the key again.
+        machine.store().unblock_device(dan_id, dan_signed_device_id).await.unwrap();
+        assert!(machine.store().blocked_devices().await.unwrap().is_empty());
+
+        let share_result = collect_session_recipients(
+            machine.store(),
+            iter::once(dan_id),
+            &encryption_settings,
+            &group_session,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(share_result.devices.get(dan_id).unwrap().len(), 1);
+    }
+
     /// Test that [`collect_session_recipients`] returns an error if there are
     /// unsigned devices belonging to verified users, when
     /// `error_on_verified_user_problem` is set.
@@ -1137,9 +1239,9 @@ mod tests {
         // Import the secret parts of our own cross-signing keys.
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
@@ -1879,13 +1981,13 @@ mod tests {
         // should succeed.
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: KeyDistributionTestData::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: KeyDistributionTestData::SELF_SIGNING_KEY_PRIVATE_EXPORT
-                    .to_owned()
-                    .into(),
-                user_signing_key: KeyDistributionTestData::USER_SIGNING_KEY_PRIVATE_EXPORT
-                    .to_owned()
-                    .into(),
+                master_key: Some(KeyDistributionTestData::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(
+                    KeyDistributionTestData::SELF_SIGNING_KEY_PRIVATE_EXPORT.into(),
+                ),
+                user_signing_key: Some(
+                    KeyDistributionTestData::USER_SIGNING_KEY_PRIVATE_EXPORT.into(),
+                ),
             })
             .await
             .unwrap();
@@ -2257,9 +2359,9 @@ mod tests {
         // Import the secret parts of our own cross-signing keys.
         machine
             .import_cross_signing_keys(CrossSigningKeyExport {
-                master_key: DataSet::MASTER_KEY_PRIVATE_EXPORT.to_owned().into(),
-                self_signing_key: DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
-                user_signing_key: DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.to_owned().into(),
+                master_key: Some(DataSet::MASTER_KEY_PRIVATE_EXPORT.into()),
+                self_signing_key: Some(DataSet::SELF_SIGNING_KEY_PRIVATE_EXPORT.into()),
+                user_signing_key: Some(DataSet::USER_SIGNING_KEY_PRIVATE_EXPORT.into()),
             })
             .await
             .unwrap();
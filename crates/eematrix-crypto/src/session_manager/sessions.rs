@@ -26,7 +26,7 @@ use ruma::{
     assign,
     events::dummy::ToDeviceDummyEventContent,
     DeviceId, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedOneTimeKeyId, OwnedServerName,
-    OwnedTransactionId, OwnedUserId, SecondsSinceUnixEpoch, ServerName, TransactionId, UserId,
+    OwnedTransactionId, OwnedUserId, RoomId, ServerName, TransactionId, UserId,
 };
 use tracing::{debug, error, info, instrument, warn};
 use vodozemac::Curve25519PublicKey;
@@ -78,6 +78,12 @@ pub(crate) struct SessionManager {
 impl SessionManager {
     const KEY_CLAIM_TIMEOUT: Duration = Duration::from_secs(10);
     const UNWEDGING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    /// How many times in a row claiming a one-time key for a device may fail
+    /// before we consider the device to be unreachable.
+    const UNREACHABLE_DEVICE_FAILURES: u8 = 3;
+    /// For how long we don't try to claim a one-time key for an unreachable
+    /// device.
+    const UNREACHABLE_DEVICE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
     pub fn new(
         users_for_key_claim: Arc<StdRwLock<BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>>>,
@@ -111,7 +117,7 @@ impl SessionManager {
                 info!(sender_key = ?curve_key, "Marking session to be unwedged");
 
                 let creation_time = Duration::from_secs(session.creation_time.get().into());
-                let now = Duration::from_secs(SecondsSinceUnixEpoch::now().get().into());
+                let now = Duration::from_secs(self.store.clock().now_secs().get().into());
 
                 let should_unwedge = now
                     .checked_sub(creation_time)
@@ -211,6 +217,7 @@ impl SessionManager {
         let mut timed_out_devices_by_user: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
 
         let unfailed_users = users.filter(|u| !self.failures.contains(u.server_name()));
+        let unreachable_devices = self.store.unreachable_devices().await?;
 
         // Get the current list of devices for each user.
         let devices_by_user = Box::pin(
@@ -245,7 +252,10 @@ impl SessionManager {
                         true
                     };
 
-                    let is_timed_out = self.is_user_timed_out(&user_id, &device_id);
+                    let is_timed_out = self.is_user_timed_out(&user_id, &device_id)
+                        || unreachable_devices
+                            .get(&user_id)
+                            .is_some_and(|devices| devices.contains(&device_id));
 
                     if is_missing && is_timed_out {
                         timed_out_devices_by_user
@@ -315,10 +325,119 @@ impl SessionManager {
         Ok(result)
     }
 
+    /// Get a key claiming request for the devices of the given room members
+    /// we don't have an Olm session with yet, see
+    /// [`crate::OlmMachine::prewarm_room`].
+    ///
+    /// Devices we already have an Olm session with are remembered as warm for
+    /// the room, so they can be skipped without touching the store when the
+    /// room is pre-warmed again.
+    pub async fn prewarm_room(
+        &self,
+        room_id: &RoomId,
+        users: impl Iterator<Item = &UserId>,
+    ) -> StoreResult<Option<(OwnedTransactionId, KeysClaimRequest)>> {
+        let devices_by_user = Box::pin(
+            self.key_request_machine.identity_manager().get_user_devices_for_encryption(users),
+        )
+        .await?;
+
+        let candidates: Vec<_> = {
+            let cache = self.store.cache().await?;
+
+            devices_by_user
+                .into_iter()
+                .flat_map(|(user_id, devices)| {
+                    devices
+                        .into_iter()
+                        .map(move |(device_id, device)| (user_id.clone(), device_id, device))
+                })
+                .filter(|(_, _, device)| device.supports_olm())
+                .filter_map(|(user_id, device_id, device)| {
+                    let sender_key = device.curve25519_key()?;
+                    (!cache.is_device_warm(room_id, &user_id, &device_id, sender_key))
+                        .then_some((user_id, device_id, sender_key))
+                })
+                .collect()
+        };
+
+        let mut warm_devices = Vec::new();
+        let mut cold_users = BTreeSet::new();
+
+        for (user_id, device_id, sender_key) in candidates {
+            let has_session = match self.store.get_sessions(&sender_key.to_base64()).await? {
+                Some(sessions) => !sessions.lock().await.is_empty(),
+                None => false,
+            };
+
+            if has_session {
+                warm_devices.push((user_id, device_id, sender_key));
+            } else {
+                cold_users.insert(user_id);
+            }
+        }
+
+        debug!(?room_id, warm_devices = warm_devices.len(), ?cold_users, "Pre-warming a room");
+
+        self.store.cache().await?.mark_devices_as_warm(room_id, warm_devices);
+
+        if cold_users.is_empty() {
+            Ok(None)
+        } else {
+            self.get_missing_sessions(cold_users.iter().map(|u| u.as_ref())).await
+        }
+    }
+
     fn is_user_timed_out(&self, user_id: &UserId, device_id: &DeviceId) -> bool {
         self.failed_devices.read().get(user_id).is_some_and(|d| d.contains(device_id))
     }
 
+    /// Remember that we couldn't establish an Olm session with the given
+    /// devices, so we back off from claiming one-time keys for them.
+    ///
+    /// Returns the devices for which this failed too often in a row, which
+    /// should be marked as unreachable, see
+    /// [`Store::mark_devices_as_unreachable()`].
+    fn record_failed_devices(
+        &self,
+        devices: impl IntoIterator<Item = (OwnedUserId, OwnedDeviceId)>,
+    ) -> Vec<(OwnedUserId, OwnedDeviceId)> {
+        let mut failed_devices = self.failed_devices.write();
+        let mut unreachable_devices = Vec::new();
+
+        for (user_id, device_id) in devices {
+            let user_cache = failed_devices.entry(user_id.clone()).or_default();
+            user_cache.insert(device_id.clone());
+
+            // The failure count doesn't include the first failure.
+            let failures = user_cache.failure_count(&device_id).map(|c| c.saturating_add(1));
+
+            if failures.is_some_and(|f| f >= Self::UNREACHABLE_DEVICE_FAILURES) {
+                unreachable_devices.push((user_id, device_id));
+            }
+        }
+
+        unreachable_devices
+    }
+
+    /// Don't try to claim one-time keys for the given devices for a while,
+    /// see [`Store::mark_devices_as_unreachable()`].
+    async fn mark_devices_as_unreachable(
+        &self,
+        devices: Vec<(OwnedUserId, OwnedDeviceId)>,
+    ) -> StoreResult<()> {
+        if devices.is_empty() {
+            return Ok(());
+        }
+
+        warn!(
+            unreachable_devices = ?devices,
+            "Claiming a one-time key keeps failing for some devices, not trying again for a while"
+        );
+
+        self.store.mark_devices_as_unreachable(devices, Self::UNREACHABLE_DEVICE_TTL).await
+    }
+
     /// This method will try to figure out for which devices a one-time key was
     /// requested but is not present in the response.
     ///
@@ -334,6 +453,9 @@ impl SessionManager {
     /// be put in the failures cache so we don't retry to claim a one-time
     /// key right away next time the user tries to send a message.
     ///
+    /// Returns the devices which should be marked as unreachable, see
+    /// [`SessionManager::record_failed_devices()`].
+    ///
     /// [spec]: https://spec.matrix.org/unstable/client-server-api/#post_matrixclientv3keysclaim
     fn handle_otk_exhaustion_failure(
         &self,
@@ -343,7 +465,7 @@ impl SessionManager {
             &OwnedUserId,
             BTreeMap<&OwnedDeviceId, BTreeSet<&OwnedOneTimeKeyId>>,
         >,
-    ) {
+    ) -> Vec<(OwnedUserId, OwnedDeviceId)> {
         // First check that the response is for the request we were expecting.
         let request = {
             let mut guard = self.current_key_claim_request.write();
@@ -408,13 +530,16 @@ impl SessionManager {
                     "Tried to create new Olm sessions, but the signed one-time key was missing for some devices",
                 );
 
-                let mut failed_devices_lock = self.failed_devices.write();
+                let missing_devices =
+                    missing_devices_by_user.into_iter().flat_map(|(user_id, device_set)| {
+                        device_set.into_iter().map(move |device_id| (user_id.clone(), device_id))
+                    });
 
-                for (user_id, device_set) in missing_devices_by_user {
-                    failed_devices_lock.entry(user_id.clone()).or_default().extend(device_set);
-                }
+                return self.record_failed_devices(missing_devices);
             }
         };
+
+        Vec::new()
     }
 
     /// Receive a successful key claim response and create new Olm sessions with
@@ -462,7 +587,9 @@ impl SessionManager {
 
         // Add the user/device pairs that don't have any one-time keys to the failures
         // cache.
-        self.handle_otk_exhaustion_failure(request_id, &failed_servers, &one_time_keys);
+        let unreachable_devices =
+            self.handle_otk_exhaustion_failure(request_id, &failed_servers, &one_time_keys);
+        self.mark_devices_as_unreachable(unreachable_devices).await?;
         // Add the failed servers to the failures cache.
         self.failures.extend(failed_servers);
         // Remove the servers we successfully contacted from the failures cache.
@@ -498,6 +625,7 @@ impl SessionManager {
 
         let mut changes = Changes::default();
         let mut new_sessions: BTreeMap<&UserId, BTreeMap<&DeviceId, SessionInfo>> = BTreeMap::new();
+        let mut failed_devices = Vec::new();
         let mut store_transaction = self.store.transaction().await;
 
         for (user_id, user_devices) in &response.one_time_keys {
@@ -524,23 +652,21 @@ impl SessionManager {
 
                 let account = store_transaction.account().await?;
                 let device_keys = self.store.get_own_device().await?.as_device_keys().clone();
-                let session = match account.create_outbound_session(&device, key_map, device_keys) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        warn!(
-                            ?user_id, ?device_id, error = ?e,
-                            "Error creating Olm session"
-                        );
-
-                        self.failed_devices
-                            .write()
-                            .entry(user_id.to_owned())
-                            .or_default()
-                            .insert(device_id.to_owned());
-
-                        continue;
-                    }
-                };
+                let now = self.store.clock().now_secs();
+                let session =
+                    match account.create_outbound_session_at(&device, key_map, device_keys, now) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!(
+                                ?user_id, ?device_id, error = ?e,
+                                "Error creating Olm session"
+                            );
+
+                            failed_devices.push((user_id.to_owned(), device_id.to_owned()));
+
+                            continue;
+                        }
+                    };
 
                 self.key_request_machine.retry_keyshare(user_id, device_id);
 
@@ -560,9 +686,24 @@ impl SessionManager {
         }
 
         store_transaction.commit().await?;
+        let created_count = changes.sessions.len() as u64;
         self.store.save_changes(changes).await?;
         info!(sessions = ?new_sessions, "Established new Olm sessions");
 
+        if created_count > 0 {
+            self.store
+                .record_statistics(|statistics| statistics.olm_sessions_created += created_count);
+            self.store.flush_statistics().await;
+        }
+
+        let unreachable_devices = self.record_failed_devices(failed_devices);
+        self.mark_devices_as_unreachable(unreachable_devices).await?;
+        self.store
+            .mark_devices_as_reachable(new_sessions.iter().flat_map(|(user_id, devices)| {
+                devices.keys().map(move |device_id| (*user_id, *device_id))
+            }))
+            .await?;
+
         for (user, device_map) in new_sessions {
             if let Some(user_cache) = self.failed_devices.read().get(user) {
                 user_cache.remove(device_map.into_keys());
@@ -594,7 +735,7 @@ mod tests {
     use matrix_sdk_test::{async_test, ruma_response_from_json};
     use ruma::{
         api::client::keys::claim_keys::v3::Response as KeyClaimResponse, device_id,
-        owned_server_name, user_id, DeviceId, OwnedUserId, UserId,
+        owned_server_name, room_id, user_id, DeviceId, OwnedUserId, UserId,
     };
     use serde_json::json;
     use tokio::sync::Mutex;
@@ -718,6 +859,51 @@ mod tests {
         assert!(manager.get_missing_sessions(iter::once(bob.user_id())).await.unwrap().is_none());
     }
 
+    #[async_test]
+    async fn test_prewarm_room() {
+        let (manager, _identity_manager) = session_manager_test_helper().await;
+        let room_id = room_id!("!test:localhost");
+        let mut bob = bob_account();
+
+        let bob_device = DeviceData::from_account(&bob);
+        let sender_key = bob_device.curve25519_key().unwrap();
+
+        manager.store.save_device_data(&[bob_device]).await.unwrap();
+
+        // We don't have a session with Bob's device yet, so pre-warming the room claims
+        // a one-time key for it.
+        let (txn_id, request) =
+            manager.prewarm_room(room_id, iter::once(bob.user_id())).await.unwrap().unwrap();
+
+        assert!(request.one_time_keys.contains_key(bob.user_id()));
+
+        bob.generate_one_time_keys(1);
+        let one_time = bob.signed_one_time_keys();
+        bob.mark_keys_as_published();
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys
+            .entry(bob.user_id().to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(bob.device_id().to_owned(), one_time);
+
+        let response = KeyClaimResponse::new(one_time_keys);
+        manager.receive_keys_claim_response(&txn_id, &response).await.unwrap();
+
+        // Now that the session exists the room is warm, and the device is remembered
+        // as such.
+        assert!(manager.prewarm_room(room_id, iter::once(bob.user_id())).await.unwrap().is_none());
+
+        let cache = manager.store.cache().await.unwrap();
+        assert!(cache.is_device_warm(room_id, bob.user_id(), bob.device_id(), sender_key));
+        assert!(!cache.is_device_warm(
+            room_id!("!other:localhost"),
+            bob.user_id(),
+            bob.device_id(),
+            sender_key
+        ));
+    }
+
     #[async_test]
     async fn test_session_creation_waits_for_keys_query() {
         let (manager, identity_manager) = session_manager_test_helper().await;
@@ -819,6 +1005,43 @@ mod tests {
         assert!(result.is_none(), "get_missing_sessions returned Some(...)");
     }
 
+    #[async_test]
+    async fn test_session_unwedging_uses_clock() {
+        use ruma::MilliSecondsSinceUnixEpoch;
+
+        use crate::clock::MockClock;
+
+        let (manager, _identity_manager) = session_manager_test_helper().await;
+        let mut bob = bob_account();
+
+        let (_, session) = manager
+            .store
+            .with_transaction(|mut tr| async {
+                let manager_account = tr.account().await.unwrap();
+                let res = bob.create_session_for_test_helper(manager_account).await;
+                Ok((tr, res))
+            })
+            .await
+            .unwrap();
+
+        let bob_device = DeviceData::from_account(&bob);
+        manager.store.save_device_data(std::slice::from_ref(&bob_device)).await.unwrap();
+        manager.store.save_sessions(&[session]).await.unwrap();
+
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch::now());
+        manager.store.set_clock(Arc::new(clock.clone()));
+        let curve_key = bob_device.curve25519_key().unwrap();
+
+        // The session was just created, so it isn't replaced yet.
+        manager.mark_device_as_wedged(bob_device.user_id(), curve_key).await.unwrap();
+        assert!(!manager.is_device_wedged(&bob_device));
+
+        // Once the clock passed the unwedging interval, it is.
+        clock.advance(Duration::from_secs(2 * 60 * 60));
+        manager.mark_device_as_wedged(bob_device.user_id(), curve_key).await.unwrap();
+        assert!(manager.is_device_wedged(&bob_device));
+    }
+
     // This test doesn't run on macos because we're modifying the session
     // creation time so we can get around the UNWEDGING_INTERVAL.
     #[async_test]
@@ -961,6 +1184,78 @@ mod tests {
         })).await;
     }
 
+    #[async_test]
+    async fn test_devices_become_unreachable_after_repeated_failures() {
+        use ruma::MilliSecondsSinceUnixEpoch;
+
+        use crate::clock::MockClock;
+
+        let alice = user_id!("@alice:example.org");
+        let alice_account = Account::with_device_id(alice, "DEVICEID".into());
+        let alice_device = DeviceData::from_account(&alice_account);
+
+        let (manager, _identity_manager) = session_manager_test_helper().await;
+        manager.store.save_device_data(&[alice_device]).await.unwrap();
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch::now());
+        manager.store.set_clock(Arc::new(clock.clone()));
+
+        let response = ruma_response_from_json(&json!({ "one_time_keys": {}, "failures": {} }));
+
+        // Claiming a one-time key for Alice's device fails a couple of times in a row.
+        for _ in 0..SessionManager::UNREACHABLE_DEVICE_FAILURES {
+            let (txn_id, _) =
+                manager.get_missing_sessions(iter::once(alice)).await.unwrap().unwrap();
+            manager.receive_keys_claim_response(&txn_id, &response).await.unwrap();
+            manager
+                .failed_devices
+                .read()
+                .get(alice)
+                .unwrap()
+                .expire(&alice_account.device_id().to_owned());
+        }
+
+        // So we don't try again, even though the short term backoff has expired.
+        let unreachable_devices = manager.store.unreachable_devices().await.unwrap();
+        assert!(unreachable_devices[alice].contains(alice_account.device_id()));
+        assert!(manager.get_missing_sessions(iter::once(alice)).await.unwrap().is_none());
+
+        // Until a day has passed.
+        clock.advance(Duration::from_secs(25 * 60 * 60));
+        assert!(manager.store.unreachable_devices().await.unwrap().is_empty());
+        assert!(manager.get_missing_sessions(iter::once(alice)).await.unwrap().is_some());
+    }
+
+    #[async_test]
+    async fn test_devices_with_olm_session_are_reachable() {
+        let alice = user_id!("@alice:example.org");
+        let mut alice_account = Account::with_device_id(alice, "DEVICEID".into());
+        let alice_device = DeviceData::from_account(&alice_account);
+
+        let (manager, _identity_manager) = session_manager_test_helper().await;
+        manager.store.save_device_data(&[alice_device]).await.unwrap();
+        manager
+            .store
+            .mark_devices_as_unreachable(
+                [(alice.to_owned(), alice_account.device_id().to_owned())],
+                SessionManager::UNREACHABLE_DEVICE_TTL,
+            )
+            .await
+            .unwrap();
+        assert!(manager.store.unreachable_devices().await.unwrap().contains_key(alice));
+
+        // Alice's device created an Olm session with us, e.g. by sending us a pre-key
+        // message, so it isn't unreachable anymore.
+        let mut our_account = Account::with_device_id(user_id(), device_id());
+        let (session, _) = our_account.create_session_for_test_helper(&mut alice_account).await;
+        manager
+            .store
+            .save_changes(Changes { sessions: vec![session], ..Default::default() })
+            .await
+            .unwrap();
+
+        assert!(manager.store.unreachable_devices().await.unwrap().is_empty());
+    }
+
     /// Helper for failed_devices_handling.
     ///
     /// Takes an invalid /keys/claim response for Alice's device DEVICEID and
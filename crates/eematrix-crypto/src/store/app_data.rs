@@ -0,0 +1,146 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed application data kept in the crypto store, see [`Store::app_data()`].
+
+use std::{collections::BTreeSet, fmt, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    types::{AppDataChange, Changes},
+    value_codec::ValueCodec,
+    CryptoStoreError, Result, Store,
+};
+
+/// A typed view on the application data of a single namespace in the crypto
+/// store, created using [`Store::app_data()`].
+///
+/// The entries are kept apart from the custom values, so they can't collide
+/// with the values the SDK stores for itself, and every namespace has its own
+/// keys. The entries of a namespace can be listed and deleted all at once.
+pub struct AppData<T> {
+    store: Store,
+    namespace: String,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for AppData<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppData").field("namespace", &self.namespace).finish_non_exhaustive()
+    }
+}
+
+impl<T> Clone for AppData<T> {
+    fn clone(&self) -> Self {
+        Self { store: self.store.clone(), namespace: self.namespace.clone(), _value: PhantomData }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> AppData<T> {
+    pub(super) fn new(store: Store, namespace: &str) -> Result<Self> {
+        if namespace.is_empty() {
+            return Err(CryptoStoreError::EmptyAppDataNamespace);
+        }
+
+        Ok(Self { store, namespace: namespace.to_owned(), _value: PhantomData })
+    }
+
+    /// The namespace of the entries.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Get the entry with the given key.
+    pub async fn get(&self, key: &str) -> Result<Option<T>> {
+        let Some(value) = self.store.get_app_data(&self.namespace, key).await? else {
+            return Ok(None);
+        };
+        let (deserialized, _) = ValueCodec::decode(&value)?;
+        Ok(Some(deserialized))
+    }
+
+    /// Store the given value under the given key, replacing the previous entry
+    /// if there was one.
+    pub async fn set(&self, key: &str, value: &T) -> Result<()> {
+        let value = self.store.serialize_value(value)?;
+        self.save([self.change(key, Some(value))]).await
+    }
+
+    /// Remove the entry with the given key, if there is one.
+    pub async fn remove(&self, key: &str) -> Result<()> {
+        self.save([self.change(key, None)]).await
+    }
+
+    /// Get the keys of all the entries of the namespace.
+    pub async fn keys(&self) -> Result<BTreeSet<String>> {
+        Ok(self.store.get_app_data_keys(&self.namespace).await?.into_iter().collect())
+    }
+
+    /// Remove all the entries of the namespace.
+    pub async fn clear(&self) -> Result<()> {
+        let keys = self.keys().await?;
+        self.save(keys.iter().map(|key| self.change(key, None))).await
+    }
+
+    /// Move the values stored using [`Store::set_value()`] into the namespace.
+    ///
+    /// This lets applications migrate the values they stored before
+    /// namespaces existed. The store can't list the custom values, so every
+    /// value is given as a pair of its legacy key and the key it should have
+    /// in the namespace. Entries already present under the new keys are
+    /// replaced, and missing legacy values are skipped.
+    ///
+    /// The entries are all stored at once, the legacy values are removed
+    /// afterwards. If the migration is interrupted, running it again picks up
+    /// the legacy values which are left.
+    ///
+    /// Returns the number of values which were moved.
+    pub async fn migrate_legacy_values(&self, keys: &[(&str, &str)]) -> Result<usize> {
+        let mut changes = Vec::new();
+        let mut legacy_keys = Vec::new();
+
+        for (legacy_key, key) in keys {
+            let Some(value) = self.store.get_value::<T>(legacy_key).await? else {
+                continue;
+            };
+
+            let value = self.store.serialize_value(&value)?;
+            changes.push(self.change(key, Some(value)));
+            legacy_keys.push(*legacy_key);
+        }
+
+        self.save(changes).await?;
+
+        for legacy_key in &legacy_keys {
+            self.store.remove_custom_value(legacy_key).await?;
+        }
+
+        Ok(legacy_keys.len())
+    }
+
+    fn change(&self, key: &str, value: Option<Vec<u8>>) -> AppDataChange {
+        AppDataChange { namespace: self.namespace.clone(), key: key.to_owned(), value }
+    }
+
+    async fn save(&self, app_data: impl IntoIterator<Item = AppDataChange>) -> Result<()> {
+        let changes = Changes { app_data: app_data.into_iter().collect(), ..Default::default() };
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        self.store.save_changes(changes).await
+    }
+}
@@ -18,7 +18,8 @@
 //! `CryptoStore`.
 
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     fmt::Display,
     ops::Deref,
     sync::{
@@ -27,13 +28,18 @@ use std::{
     },
 };
 
-use matrix_sdk_common::locks::RwLock as StdRwLock;
-use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+use matrix_sdk_common::locks::{Mutex as StdMutex, RwLock as StdRwLock};
+use ruma::{
+    serde::JsonObject, DeviceId, EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId,
+    RoomId, SecondsSinceUnixEpoch, UserId,
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::sync::{Mutex, MutexGuard, OwnedRwLockReadGuard, RwLock};
 use tracing::{field::display, instrument, trace, Span};
+use vodozemac::Curve25519PublicKey;
 
-use super::{CryptoStoreError, CryptoStoreWrapper};
+use super::{types::KeyQueryPriority, CryptoStoreError, CryptoStoreWrapper};
 use crate::{identities::DeviceData, olm::Session, Account};
 
 /// In-memory store for Olm Sessions.
@@ -133,6 +139,201 @@ impl DeviceStore {
             .map(|(key, value)| (key.to_owned(), value.clone()))
             .collect()
     }
+
+    /// Get a copy of every device in the store, regardless of its owner.
+    pub(crate) fn all_devices(&self) -> Vec<DeviceData> {
+        self.entries.read().values().flat_map(BTreeMap::values).cloned().collect()
+    }
+}
+
+/// The default capacity of the [`DecryptedEventCache`], in bytes.
+pub(crate) const DEFAULT_DECRYPTED_EVENT_CACHE_CAPACITY: usize = 1024 * 1024;
+
+/// In-memory cache holding the plaintext of decrypted room events, by event
+/// ID.
+///
+/// Every entry remembers a hash of the ciphertext it was decrypted from, so
+/// another event reusing the ID of a cached event isn't served from the cache.
+///
+/// The size of the cache is bounded by the total length of the cached
+/// ciphertexts, which is roughly the size of the plaintexts. Once the capacity
+/// is reached, the oldest entries are evicted. A capacity of zero disables the
+/// cache.
+#[derive(Debug)]
+pub(crate) struct DecryptedEventCache {
+    inner: StdMutex<DecryptedEventCacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct DecryptedEventCacheInner {
+    capacity: usize,
+    size: usize,
+    entries: HashMap<OwnedEventId, DecryptedEventCacheEntry>,
+    /// The cached event IDs, oldest first.
+    order: VecDeque<OwnedEventId>,
+}
+
+#[derive(Debug)]
+struct DecryptedEventCacheEntry {
+    ciphertext_hash: [u8; 32],
+    size: usize,
+    plaintext: JsonObject,
+}
+
+impl DecryptedEventCacheInner {
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let Some(event_id) = self.order.pop_front() else { break };
+
+            if let Some(entry) = self.entries.remove(&event_id) {
+                self.size -= entry.size;
+            }
+        }
+    }
+}
+
+impl DecryptedEventCache {
+    /// Create a new empty cache with the given capacity, in bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self { inner: StdMutex::new(DecryptedEventCacheInner { capacity, ..Default::default() }) }
+    }
+
+    /// Is the cache enabled, i.e. is its capacity not zero.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock().capacity > 0
+    }
+
+    /// Change the capacity of the cache, evicting entries if needed.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock();
+        inner.capacity = capacity;
+        inner.evict();
+    }
+
+    /// Remove all the entries from the cache.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.entries.clear();
+        inner.order.clear();
+        inner.size = 0;
+    }
+
+    /// Get the plaintext of the event with the given ID, if it was decrypted
+    /// from the given ciphertext.
+    pub fn get(&self, event_id: &EventId, ciphertext: &[u8]) -> Option<JsonObject> {
+        let inner = self.inner.lock();
+        let entry = inner.entries.get(event_id)?;
+
+        (entry.ciphertext_hash == hash_ciphertext(ciphertext)).then(|| entry.plaintext.clone())
+    }
+
+    /// Add the plaintext of an event, decrypted from the given ciphertext, to
+    /// the cache.
+    pub fn insert(&self, event_id: &EventId, ciphertext: &[u8], plaintext: JsonObject) {
+        let mut inner = self.inner.lock();
+        let size = ciphertext.len();
+
+        if size > inner.capacity {
+            return;
+        }
+
+        let entry = DecryptedEventCacheEntry {
+            ciphertext_hash: hash_ciphertext(ciphertext),
+            size,
+            plaintext,
+        };
+
+        if let Some(previous) = inner.entries.insert(event_id.to_owned(), entry) {
+            inner.size -= previous.size;
+        } else {
+            inner.order.push_back(event_id.to_owned());
+        }
+
+        inner.size += size;
+        inner.evict();
+    }
+}
+
+fn hash_ciphertext(ciphertext: &[u8]) -> [u8; 32] {
+    Sha256::digest(ciphertext).into()
+}
+
+/// The default number of entries the [`MegolmMessageIndexCache`] holds.
+pub(crate) const DEFAULT_MEGOLM_MESSAGE_INDEX_CACHE_CAPACITY: usize = 10_000;
+
+/// In-memory cache of the Megolm message indices which were recorded in the
+/// store, and the event which first used them.
+///
+/// Every decryption of a room event records its message index, this cache
+/// makes sure that decrypting the same event again doesn't have to go to the
+/// store. Once the capacity is reached, the oldest entries are evicted.
+#[derive(Debug)]
+pub(crate) struct MegolmMessageIndexCache {
+    inner: StdMutex<MegolmMessageIndexCacheInner>,
+}
+
+type MegolmMessageIndexKey = (OwnedRoomId, String, u32);
+
+#[derive(Debug, Default)]
+struct MegolmMessageIndexCacheInner {
+    capacity: usize,
+    entries: HashMap<MegolmMessageIndexKey, OwnedEventId>,
+    /// The cached keys, oldest first.
+    order: VecDeque<MegolmMessageIndexKey>,
+}
+
+impl MegolmMessageIndexCache {
+    /// Create a new empty cache holding at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: StdMutex::new(MegolmMessageIndexCacheInner { capacity, ..Default::default() }),
+        }
+    }
+
+    /// Remove all the entries from the cache.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.entries.clear();
+        inner.order.clear();
+    }
+
+    /// Get the event which was recorded for the given message index, if it is
+    /// cached.
+    pub fn get(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+    ) -> Option<OwnedEventId> {
+        let key = (room_id.to_owned(), session_id.to_owned(), message_index);
+        self.inner.lock().entries.get(&key).cloned()
+    }
+
+    /// Remember the event which was recorded for the given message index.
+    pub fn insert(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) {
+        let mut inner = self.inner.lock();
+
+        if inner.capacity == 0 {
+            return;
+        }
+
+        let key = (room_id.to_owned(), session_id.to_owned(), message_index);
+
+        if inner.entries.insert(key.clone(), event_id.to_owned()).is_none() {
+            inner.order.push_back(key);
+        }
+
+        while inner.entries.len() > inner.capacity {
+            let Some(oldest) = inner.order.pop_front() else { break };
+            inner.entries.remove(&oldest);
+        }
+    }
 }
 
 /// A numeric type that can represent an infinite ordered sequence.
@@ -209,6 +410,10 @@ pub(super) struct UsersForKeyQuery {
     /// they were added to the list
     user_map: HashMap<OwnedUserId, SequenceNumber>,
 
+    /// The priority of the users pending a lookup, if it was raised above
+    /// [`KeyQueryPriority::Background`].
+    user_priorities: HashMap<OwnedUserId, KeyQueryPriority>,
+
     /// A list of tasks waiting for key queries to complete.
     ///
     /// We expect this list to remain fairly short, so don't bother partitioning
@@ -219,12 +424,25 @@ pub(super) struct UsersForKeyQuery {
 impl UsersForKeyQuery {
     /// Record a new user that requires a key query
     pub(super) fn insert_user(&mut self, user: &UserId) {
+        self.insert_user_with_priority(user, KeyQueryPriority::Background);
+    }
+
+    /// Record a new user that requires a key query with the given priority.
+    ///
+    /// The priority of a user is only ever raised, until the user is
+    /// up-to-date again.
+    pub(super) fn insert_user_with_priority(&mut self, user: &UserId, priority: KeyQueryPriority) {
         let sequence_number = self.next_sequence_number;
 
-        trace!(?user, %sequence_number, "Flagging user for key query");
+        trace!(?user, %sequence_number, ?priority, "Flagging user for key query");
 
         self.user_map.insert(user.to_owned(), sequence_number);
         self.next_sequence_number.increment();
+
+        if priority > KeyQueryPriority::Background {
+            let current = self.user_priorities.entry(user.to_owned()).or_default();
+            *current = (*current).max(priority);
+        }
     }
 
     /// Record that a user has received an update with the given sequence
@@ -288,6 +506,7 @@ impl UsersForKeyQuery {
             } else {
                 trace!("User now up-to-date");
                 self.user_map.remove(user);
+                self.user_priorities.remove(user);
                 true
             }
         } else {
@@ -304,6 +523,57 @@ impl UsersForKeyQuery {
         (self.user_map.keys().cloned().collect(), sequence_number)
     }
 
+    /// Split the users waiting for a key query into batches of at most
+    /// `batch_size` users, skipping the users for which `filter` returns
+    /// false.
+    ///
+    /// The users with the highest [`KeyQueryPriority`] are put into the first
+    /// batches. Users for which a task is waiting, e.g. because a message can't
+    /// be sent before their devices are known, are treated as
+    /// [`KeyQueryPriority::BlockingSend`]. Each batch comes with the sequence
+    /// number of the latest invalidation of one of its users, which should be
+    /// returned in `maybe_remove_user` once the batch has been queried.
+    pub(super) fn batches_for_key_query(
+        &self,
+        batch_size: usize,
+        filter: impl Fn(&UserId) -> bool,
+    ) -> Vec<(Vec<OwnedUserId>, SequenceNumber)> {
+        let waited_for: HashSet<OwnedUserId> = self
+            .tasks_awaiting_key_query
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|waiter| waiter.user.clone())
+            .collect();
+
+        let priority = |user: &UserId| {
+            if waited_for.contains(user) {
+                KeyQueryPriority::BlockingSend
+            } else {
+                self.user_priorities.get(user).copied().unwrap_or_default()
+            }
+        };
+
+        let mut users: Vec<_> = self.user_map.iter().filter(|(user, _)| filter(user)).collect();
+
+        // Users with the highest priority first, then the ones which were invalidated
+        // first.
+        users.sort_by_key(|(user, sequence_number)| (Reverse(priority(user)), **sequence_number));
+
+        users
+            .chunks(batch_size.max(1))
+            .map(|batch| {
+                let sequence_number = batch
+                    .iter()
+                    .map(|(_, sequence_number)| **sequence_number)
+                    .max()
+                    .expect("A batch always contains at least one user");
+                let users = batch.iter().map(|(user, _)| (*user).to_owned()).collect();
+
+                (users, sequence_number)
+            })
+            .collect()
+    }
+
     /// Check if a key query is pending for a user, and register for a wakeup if
     /// so.
     ///
@@ -336,13 +606,110 @@ pub(crate) struct StoreCache {
     pub(super) tracked_users: StdRwLock<BTreeSet<OwnedUserId>>,
     pub(super) loaded_tracked_users: RwLock<bool>,
     pub(super) account: Mutex<Option<Account>>,
+    /// The devices we know to have an Olm session with, per room, see
+    /// [`OlmMachine::prewarm_room()`].
+    ///
+    /// The devices are stored together with their Curve25519 key, so a device
+    /// which changed its key isn't considered to be warm anymore.
+    ///
+    /// [`OlmMachine::prewarm_room()`]: crate::OlmMachine::prewarm_room
+    pub(super) warm_devices: StdRwLock<WarmDevices>,
+    /// The devices for which claiming a one-time key keeps failing, see
+    /// [`Store::mark_devices_as_unreachable()`].
+    ///
+    /// `None` until the devices have been loaded from the store.
+    ///
+    /// [`Store::mark_devices_as_unreachable()`]: super::Store::mark_devices_as_unreachable
+    pub(super) unreachable_devices: StdRwLock<Option<UnreachableDevices>>,
+}
+
+/// The devices we know to have an Olm session with, for the most recently
+/// pre-warmed rooms.
+#[derive(Debug, Default)]
+pub(crate) struct WarmDevices {
+    rooms: BTreeMap<OwnedRoomId, BTreeMap<(OwnedUserId, OwnedDeviceId), Curve25519PublicKey>>,
+    /// The rooms in `rooms`, least recently pre-warmed first.
+    order: VecDeque<OwnedRoomId>,
+}
+
+impl WarmDevices {
+    /// The maximum number of rooms we remember the warm devices of, the least
+    /// recently pre-warmed rooms are forgotten first.
+    const MAX_ROOMS: usize = 100;
+
+    fn get(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Option<&Curve25519PublicKey> {
+        self.rooms.get(room_id)?.get(&(user_id.to_owned(), device_id.to_owned()))
+    }
+
+    fn insert(
+        &mut self,
+        room_id: &RoomId,
+        devices: impl IntoIterator<Item = (OwnedUserId, OwnedDeviceId, Curve25519PublicKey)>,
+    ) {
+        self.order.retain(|r| r != room_id);
+        self.order.push_back(room_id.to_owned());
+
+        let room_devices = self.rooms.entry(room_id.to_owned()).or_default();
+
+        for (user_id, device_id, sender_key) in devices {
+            room_devices.insert((user_id, device_id), sender_key);
+        }
+
+        while self.order.len() > Self::MAX_ROOMS {
+            if let Some(room_id) = self.order.pop_front() {
+                self.rooms.remove(&room_id);
+            }
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.rooms.clear();
+        self.order.clear();
+    }
 }
 
+/// Devices for which claiming a one-time key keeps failing, mapped to the time
+/// until which we don't try to claim one again.
+pub(crate) type UnreachableDevices =
+    BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, SecondsSinceUnixEpoch>>;
+
 impl StoreCache {
     pub(crate) fn store_wrapper(&self) -> &CryptoStoreWrapper {
         self.store.as_ref()
     }
 
+    /// Is the given device known to have an Olm session for the given room,
+    /// using the given Curve25519 key?
+    pub(crate) fn is_device_warm(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        sender_key: Curve25519PublicKey,
+    ) -> bool {
+        self.warm_devices
+            .read()
+            .get(room_id, user_id, device_id)
+            .is_some_and(|key| *key == sender_key)
+    }
+
+    /// Remember that we have an Olm session with the given devices, for the
+    /// given room.
+    ///
+    /// Only the devices of the most recently pre-warmed rooms are remembered.
+    pub(crate) fn mark_devices_as_warm(
+        &self,
+        room_id: &RoomId,
+        devices: impl IntoIterator<Item = (OwnedUserId, OwnedDeviceId, Curve25519PublicKey)>,
+    ) {
+        self.warm_devices.write().insert(room_id, devices);
+    }
+
     /// Returns a reference to the `Account`.
     ///
     /// Either load the account from the cache, or the store if missing from
@@ -406,7 +773,14 @@ mod tests {
     use matrix_sdk_test::async_test;
     use proptest::prelude::*;
 
-    use super::{DeviceStore, SequenceNumber, SessionStore};
+    use ruma::{device_id, event_id, room_id, serde::JsonObject, user_id, OwnedRoomId};
+    use serde_json::json;
+    use vodozemac::Curve25519PublicKey;
+
+    use super::{
+        DecryptedEventCache, DeviceStore, MegolmMessageIndexCache, SequenceNumber, SessionStore,
+        WarmDevices,
+    };
     use crate::{
         identities::device::testing::get_device, olm::tests::get_account_and_session_test_helper,
     };
@@ -443,6 +817,85 @@ mod tests {
         assert_eq!(&session, loaded_session);
     }
 
+    #[test]
+    fn test_decrypted_event_cache() {
+        let plaintext = |body: &str| -> JsonObject {
+            serde_json::from_value(json!({ "type": "m.room.message", "content": { "body": body } }))
+                .unwrap()
+        };
+
+        let cache = DecryptedEventCache::new(10);
+
+        cache.insert(event_id!("$first"), b"aaaa", plaintext("first"));
+        assert_eq!(cache.get(event_id!("$first"), b"aaaa"), Some(plaintext("first")));
+
+        // Another ciphertext reusing the event ID isn't served from the cache.
+        assert_eq!(cache.get(event_id!("$first"), b"bbbb"), None);
+
+        // Entries which don't fit anymore evict the oldest ones.
+        cache.insert(event_id!("$second"), b"bbbb", plaintext("second"));
+        cache.insert(event_id!("$third"), b"cccc", plaintext("third"));
+        assert_eq!(cache.get(event_id!("$first"), b"aaaa"), None);
+        assert_eq!(cache.get(event_id!("$second"), b"bbbb"), Some(plaintext("second")));
+        assert_eq!(cache.get(event_id!("$third"), b"cccc"), Some(plaintext("third")));
+
+        // Entries bigger than the cache are never stored.
+        cache.insert(event_id!("$big"), b"too big for it", plaintext("big"));
+        assert_eq!(cache.get(event_id!("$big"), b"too big for it"), None);
+
+        // A capacity of zero disables the cache.
+        cache.set_capacity(0);
+        assert!(!cache.is_enabled());
+        assert_eq!(cache.get(event_id!("$third"), b"cccc"), None);
+        cache.insert(event_id!("$fourth"), b"dddd", plaintext("fourth"));
+        assert_eq!(cache.get(event_id!("$fourth"), b"dddd"), None);
+    }
+
+    #[test]
+    fn test_megolm_message_index_cache() {
+        let room_id = room_id!("!test:localhost");
+        let cache = MegolmMessageIndexCache::new(2);
+
+        cache.insert(room_id, "session", 0, event_id!("$first"));
+        assert_eq!(cache.get(room_id, "session", 0).as_deref(), Some(event_id!("$first")));
+        assert_eq!(cache.get(room_id, "session", 1), None);
+        assert_eq!(cache.get(room_id, "other_session", 0), None);
+
+        // Once the cache is full, the oldest entries are evicted.
+        cache.insert(room_id, "session", 1, event_id!("$second"));
+        cache.insert(room_id, "session", 2, event_id!("$third"));
+        assert_eq!(cache.get(room_id, "session", 0), None);
+        assert_eq!(cache.get(room_id, "session", 1).as_deref(), Some(event_id!("$second")));
+        assert_eq!(cache.get(room_id, "session", 2).as_deref(), Some(event_id!("$third")));
+
+        cache.clear();
+        assert_eq!(cache.get(room_id, "session", 2), None);
+    }
+
+    #[test]
+    fn test_warm_devices() {
+        let user_id = user_id!("@alice:localhost");
+        let device_id = device_id!("ALICEDEVICE");
+        let sender_key = Curve25519PublicKey::from([0; 32]);
+        let device = || (user_id.to_owned(), device_id.to_owned(), sender_key);
+
+        let first_room = room_id!("!first:localhost");
+        let mut warm_devices = WarmDevices::default();
+        warm_devices.insert(first_room, [device()]);
+        assert_eq!(warm_devices.get(first_room, user_id, device_id), Some(&sender_key));
+
+        // Once too many rooms were pre-warmed, the least recently pre-warmed one is
+        // forgotten.
+        for i in 0..WarmDevices::MAX_ROOMS {
+            let room_id = OwnedRoomId::try_from(format!("!room{i}:localhost")).unwrap();
+            warm_devices.insert(&room_id, [device()]);
+        }
+
+        assert_eq!(warm_devices.get(first_room, user_id, device_id), None);
+        assert_eq!(warm_devices.rooms.len(), WarmDevices::MAX_ROOMS);
+        assert_eq!(warm_devices.order.len(), WarmDevices::MAX_ROOMS);
+    }
+
     #[async_test]
     async fn test_device_store() {
         let device = get_device();
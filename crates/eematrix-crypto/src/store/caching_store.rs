@@ -0,0 +1,828 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A [`CryptoStore`] adapter which keeps the most recently used devices,
+//! identities and room keys in memory, see [`CachingStore`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hash,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use matrix_sdk_common::locks::Mutex as StdMutex;
+use ruma::{
+    events::secret::request::SecretName, DeviceId, EventId, OwnedDeviceId, OwnedEventId,
+    OwnedRoomId, OwnedUserId, RoomId, SecondsSinceUnixEpoch, TransactionId, UserId,
+};
+use vodozemac::Curve25519PublicKey;
+
+use super::{
+    types::{
+        BackupKeys, ChangeJournalRecord, Changes, CompactionReport, DehydratedDeviceKey,
+        DeletedRoomCryptoState, PendingChanges, QuarantinedEntry, RoomKeyCounts, RoomKeySummary,
+        RoomSettings, SessionShareRecord, ShredReport, StoredRoomKeyBundleData, TrackedUser,
+        VerificationAuditEntry,
+    },
+    CryptoStore,
+};
+use crate::{
+    clock::Clock,
+    olm::{
+        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
+        SenderDataType, Session,
+    },
+    types::events::room_key_withheld::RoomKeyWithheldEvent,
+    Account, DeviceData, GossipRequest, GossippedSecret, SecretInfo, UserIdentityData,
+};
+
+/// The number of entries the caches of a [`CachingStore`] can hold.
+///
+/// A limit of zero disables the corresponding cache.
+#[derive(Clone, Copy, Debug)]
+pub struct CachingStoreLimits {
+    /// The number of devices to keep in memory.
+    pub devices: usize,
+    /// The number of user identities to keep in memory.
+    pub identities: usize,
+    /// The number of inbound group sessions, i.e. room keys, to keep in
+    /// memory.
+    pub inbound_group_sessions: usize,
+}
+
+impl Default for CachingStoreLimits {
+    fn default() -> Self {
+        Self { devices: 1000, identities: 1000, inbound_group_sessions: 1000 }
+    }
+}
+
+/// A [`CryptoStore`] which wraps another store and keeps the most recently
+/// used devices, user identities and inbound group sessions in memory.
+///
+/// File-backed stores have to hit the disk, and decrypt the stored value, for
+/// every lookup. Those three kinds of data are looked up for almost every
+/// event we decrypt, so caching them saves a lot of store round-trips.
+///
+/// Every write which goes through this store evicts the data it touches from
+/// the caches, so the cached data never gets out of date as long as the
+/// wrapped store isn't modified behind the back of the `CachingStore`.
+pub struct CachingStore<S> {
+    inner: S,
+    caches: StdMutex<Caches>,
+}
+
+impl<S: fmt::Debug> fmt::Debug for CachingStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachingStore").field("inner", &self.inner).finish_non_exhaustive()
+    }
+}
+
+impl<S: CryptoStore> CachingStore<S> {
+    /// Wrap the given store, using the default [`CachingStoreLimits`].
+    pub fn new(store: S) -> Self {
+        Self::with_limits(store, CachingStoreLimits::default())
+    }
+
+    /// Wrap the given store, keeping at most the given number of entries in
+    /// memory.
+    pub fn with_limits(store: S, limits: CachingStoreLimits) -> Self {
+        let caches = Caches {
+            generation: 0,
+            devices: LruCache::new(limits.devices),
+            identities: LruCache::new(limits.identities),
+            inbound_group_sessions: LruCache::new(limits.inbound_group_sessions),
+        };
+
+        Self { inner: store, caches: StdMutex::new(caches) }
+    }
+
+    /// Get the wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Drop everything that is cached in memory.
+    pub fn clear_caches(&self) {
+        self.caches.lock().invalidate(|caches| {
+            caches.devices.clear();
+            caches.identities.clear();
+            caches.inbound_group_sessions.clear();
+        });
+    }
+
+    /// The current generation of the caches, see [`Caches::generation`].
+    fn generation(&self) -> u64 {
+        self.caches.lock().generation
+    }
+
+    /// Remember a value loaded from the wrapped store, unless the caches were
+    /// invalidated since the given generation.
+    fn remember(&self, generation: u64, f: impl FnOnce(&mut Caches)) {
+        let mut caches = self.caches.lock();
+
+        if caches.generation == generation {
+            f(&mut caches);
+        }
+    }
+
+    /// Evict the devices, identities and sessions contained in the given
+    /// changes from the caches.
+    fn invalidate_changes(&self, keys: ChangedKeys) {
+        self.caches.lock().invalidate(|caches| {
+            for key in &keys.devices {
+                caches.devices.remove(key);
+            }
+
+            for key in &keys.identities {
+                caches.identities.remove(key);
+            }
+
+            for key in &keys.inbound_group_sessions {
+                caches.inbound_group_sessions.remove(key);
+            }
+        });
+    }
+}
+
+/// The keys of the cached data touched by a write.
+#[derive(Default)]
+struct ChangedKeys {
+    devices: Vec<(OwnedUserId, OwnedDeviceId)>,
+    identities: Vec<OwnedUserId>,
+    inbound_group_sessions: Vec<(OwnedRoomId, String)>,
+}
+
+impl ChangedKeys {
+    fn from_changes(changes: &Changes) -> Self {
+        let devices = &changes.devices;
+        let identities = &changes.identities;
+
+        Self {
+            devices: devices
+                .new
+                .iter()
+                .chain(&devices.changed)
+                .chain(&devices.deleted)
+                .map(|d| (d.user_id().to_owned(), d.device_id().to_owned()))
+                .collect(),
+            identities: identities
+                .new
+                .iter()
+                .chain(&identities.changed)
+                .chain(&identities.unchanged)
+                .map(|i| i.user_id().to_owned())
+                .collect(),
+            inbound_group_sessions: session_keys(&changes.inbound_group_sessions),
+        }
+    }
+}
+
+fn session_keys(sessions: &[InboundGroupSession]) -> Vec<(OwnedRoomId, String)> {
+    sessions.iter().map(|s| (s.room_id().to_owned(), s.session_id().to_owned())).collect()
+}
+
+#[derive(Debug)]
+struct Caches {
+    /// Incremented every time something is evicted from the caches.
+    ///
+    /// A value loaded from the wrapped store is only added to the caches if
+    /// the generation didn't change while it was being loaded, otherwise a
+    /// value which was concurrently overwritten could end up in the caches.
+    generation: u64,
+    devices: LruCache<(OwnedUserId, OwnedDeviceId), DeviceData>,
+    identities: LruCache<OwnedUserId, UserIdentityData>,
+    inbound_group_sessions: LruCache<(OwnedRoomId, String), InboundGroupSession>,
+}
+
+impl Caches {
+    fn invalidate(&mut self, f: impl FnOnce(&mut Self)) {
+        self.generation = self.generation.wrapping_add(1);
+        f(self);
+    }
+}
+
+/// A map with a fixed capacity which evicts its least recently used entry
+/// when it's full.
+#[derive(Debug)]
+struct LruCache<K, V> {
+    capacity: usize,
+    /// Incremented on every access, the entry with the lowest tick is the
+    /// least recently used one.
+    tick: u64,
+    entries: HashMap<K, (u64, V)>,
+    /// The keys of the entries, by the tick of their last access.
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> LruCache<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, tick: 0, entries: HashMap::new(), order: BTreeMap::new() }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        let tick = self.next_tick();
+        let (last_used, value) = self.entries.get_mut(key)?;
+
+        self.order.remove(last_used);
+        self.order.insert(tick, key.clone());
+        *last_used = tick;
+
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let tick = self.next_tick();
+
+        if let Some((last_used, _)) = self.entries.insert(key.clone(), (tick, value)) {
+            self.order.remove(&last_used);
+        }
+
+        self.order.insert(tick, key);
+
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((last_used, _)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg_attr(target_family = "wasm", async_trait(?Send))]
+#[cfg_attr(not(target_family = "wasm"), async_trait)]
+impl<S: CryptoStore> CryptoStore for CachingStore<S> {
+    type Error = S::Error;
+
+    async fn load_account(&self) -> Result<Option<Account>, Self::Error> {
+        self.inner.load_account().await
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>, Self::Error> {
+        self.inner.load_identity().await
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<(), Self::Error> {
+        let keys = ChangedKeys::from_changes(&changes);
+        let result = self.inner.save_changes(changes).await;
+        // Evict the entries even if the write failed, we don't know what made it to
+        // the wrapped store.
+        self.invalidate_changes(keys);
+
+        result
+    }
+
+    async fn save_pending_changes(&self, changes: PendingChanges) -> Result<(), Self::Error> {
+        self.inner.save_pending_changes(changes).await
+    }
+
+    async fn save_inbound_group_sessions(
+        &self,
+        sessions: Vec<InboundGroupSession>,
+        backed_up_to_version: Option<&str>,
+    ) -> Result<(), Self::Error> {
+        let keys =
+            ChangedKeys { inbound_group_sessions: session_keys(&sessions), ..Default::default() };
+        let result = self.inner.save_inbound_group_sessions(sessions, backed_up_to_version).await;
+        self.invalidate_changes(keys);
+
+        result
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Vec<Session>>, Self::Error> {
+        self.inner.get_sessions(sender_key).await
+    }
+
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>, Self::Error> {
+        self.inner.get_session_sender_keys().await
+    }
+
+    async fn delete_sessions(
+        &self,
+        sender_key: &str,
+        session_ids: &[String],
+    ) -> Result<(), Self::Error> {
+        self.inner.delete_sessions(sender_key, session_ids).await
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>, Self::Error> {
+        let key = (room_id.to_owned(), session_id.to_owned());
+
+        if let Some(session) = self.caches.lock().inbound_group_sessions.get(&key) {
+            return Ok(Some(session));
+        }
+
+        let generation = self.generation();
+        let session = self.inner.get_inbound_group_session(room_id, session_id).await?;
+
+        if let Some(session) = &session {
+            self.remember(generation, |caches| {
+                caches.inbound_group_sessions.insert(key, session.clone())
+            });
+        }
+
+        Ok(session)
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>, Self::Error> {
+        self.inner.get_withheld_info(room_id, session_id).await
+    }
+
+    async fn record_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>, Self::Error> {
+        self.inner.record_megolm_message_index(room_id, session_id, message_index, event_id).await
+    }
+
+    async fn get_session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>, Self::Error> {
+        self.inner.get_session_share_history(room_id, session_id).await
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        self.inner.get_inbound_group_sessions().await
+    }
+
+    async fn quarantine_undecodable_inbound_group_sessions(
+        &self,
+    ) -> Result<Vec<QuarantinedEntry>, Self::Error> {
+        // Undecodable sessions can't have been loaded, so they aren't cached.
+        self.inner.quarantine_undecodable_inbound_group_sessions().await
+    }
+
+    async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>, Self::Error> {
+        self.inner.get_quarantined_entries().await
+    }
+
+    async fn restore_quarantined_entries(&self) -> Result<usize, Self::Error> {
+        // Only decoded sessions are cached, so there's nothing to invalidate.
+        self.inner.restore_quarantined_entries().await
+    }
+
+    async fn delete_inbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+        session_ids: &[String],
+    ) -> Result<(), Self::Error> {
+        let keys = ChangedKeys {
+            inbound_group_sessions: session_ids
+                .iter()
+                .map(|session_id| (room_id.to_owned(), session_id.clone()))
+                .collect(),
+            ..Default::default()
+        };
+        let result = self.inner.delete_inbound_group_sessions(room_id, session_ids).await;
+        self.invalidate_changes(keys);
+
+        result
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        self.inner.get_inbound_group_sessions_batch(after, limit).await
+    }
+
+    async fn inbound_group_session_counts(
+        &self,
+        backup_version: Option<&str>,
+    ) -> Result<RoomKeyCounts, Self::Error> {
+        self.inner.inbound_group_session_counts(backup_version).await
+    }
+
+    async fn get_inbound_group_sessions_for_device_batch(
+        &self,
+        curve_key: Curve25519PublicKey,
+        sender_data_type: SenderDataType,
+        after_session_id: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        self.inner
+            .get_inbound_group_sessions_for_device_batch(
+                curve_key,
+                sender_data_type,
+                after_session_id,
+                limit,
+            )
+            .await
+    }
+
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        self.inner.get_inbound_group_sessions_for_room(room_id, since).await
+    }
+
+    async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>, Self::Error> {
+        self.inner.get_room_key_summaries().await
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        backup_version: &str,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        self.inner.inbound_group_sessions_for_backup(backup_version, limit).await
+    }
+
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &self,
+        backup_version: &str,
+        room_and_session_ids: &[(&RoomId, &str)],
+    ) -> Result<(), Self::Error> {
+        let keys = ChangedKeys {
+            inbound_group_sessions: room_and_session_ids
+                .iter()
+                .map(|(room_id, session_id)| ((*room_id).to_owned(), (*session_id).to_owned()))
+                .collect(),
+            ..Default::default()
+        };
+        let result = self
+            .inner
+            .mark_inbound_group_sessions_as_backed_up(backup_version, room_and_session_ids)
+            .await;
+        self.invalidate_changes(keys);
+
+        result
+    }
+
+    async fn reset_backup_state(&self) -> Result<(), Self::Error> {
+        let result = self.inner.reset_backup_state().await;
+        // The backup state of every session changes, so drop all of them.
+        self.caches.lock().invalidate(|caches| caches.inbound_group_sessions.clear());
+
+        result
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys, Self::Error> {
+        self.inner.load_backup_keys().await
+    }
+
+    async fn delete_backup_decryption_key(&self) -> Result<(), Self::Error> {
+        self.inner.delete_backup_decryption_key().await
+    }
+
+    async fn load_dehydrated_device_pickle_key(
+        &self,
+    ) -> Result<Option<DehydratedDeviceKey>, Self::Error> {
+        self.inner.load_dehydrated_device_pickle_key().await
+    }
+
+    async fn delete_dehydrated_device_pickle_key(&self) -> Result<(), Self::Error> {
+        self.inner.delete_dehydrated_device_pickle_key().await
+    }
+
+    async fn get_outbound_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>, Self::Error> {
+        self.inner.get_outbound_group_session(room_id).await
+    }
+
+    async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>, Self::Error> {
+        self.inner.load_tracked_users().await
+    }
+
+    async fn load_tracked_users_paged(
+        &self,
+        after: Option<&UserId>,
+        limit: usize,
+    ) -> Result<Vec<TrackedUser>, Self::Error> {
+        self.inner.load_tracked_users_paged(after, limit).await
+    }
+
+    async fn save_tracked_users(&self, users: &[(&UserId, bool)]) -> Result<(), Self::Error> {
+        self.inner.save_tracked_users(users).await
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<DeviceData>, Self::Error> {
+        let key = (user_id.to_owned(), device_id.to_owned());
+
+        if let Some(device) = self.caches.lock().devices.get(&key) {
+            return Ok(Some(device));
+        }
+
+        let generation = self.generation();
+        let device = self.inner.get_device(user_id, device_id).await?;
+
+        if let Some(device) = &device {
+            self.remember(generation, |caches| caches.devices.insert(key, device.clone()));
+        }
+
+        Ok(device)
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<OwnedDeviceId, DeviceData>, Self::Error> {
+        self.inner.get_user_devices(user_id).await
+    }
+
+    async fn get_own_device(&self) -> Result<DeviceData, Self::Error> {
+        self.inner.get_own_device().await
+    }
+
+    async fn get_user_identity(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<UserIdentityData>, Self::Error> {
+        let key = user_id.to_owned();
+
+        if let Some(identity) = self.caches.lock().identities.get(&key) {
+            return Ok(Some(identity));
+        }
+
+        let generation = self.generation();
+        let identity = self.inner.get_user_identity(user_id).await?;
+
+        if let Some(identity) = &identity {
+            self.remember(generation, |caches| caches.identities.insert(key, identity.clone()));
+        }
+
+        Ok(identity)
+    }
+
+    async fn get_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>, Self::Error> {
+        self.inner.get_verification_audit_log(user_id).await
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool, Self::Error> {
+        self.inner.is_message_known(message_hash).await
+    }
+
+    async fn get_outgoing_secret_requests(
+        &self,
+        request_id: &TransactionId,
+    ) -> Result<Option<GossipRequest>, Self::Error> {
+        self.inner.get_outgoing_secret_requests(request_id).await
+    }
+
+    async fn get_secret_request_by_info(
+        &self,
+        secret_info: &SecretInfo,
+    ) -> Result<Option<GossipRequest>, Self::Error> {
+        self.inner.get_secret_request_by_info(secret_info).await
+    }
+
+    async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>, Self::Error> {
+        self.inner.get_unsent_secret_requests().await
+    }
+
+    async fn delete_outgoing_secret_requests(
+        &self,
+        request_id: &TransactionId,
+    ) -> Result<(), Self::Error> {
+        self.inner.delete_outgoing_secret_requests(request_id).await
+    }
+
+    async fn get_secrets_from_inbox(
+        &self,
+        secret_name: &SecretName,
+    ) -> Result<Vec<GossippedSecret>, Self::Error> {
+        self.inner.get_secrets_from_inbox(secret_name).await
+    }
+
+    async fn delete_secrets_from_inbox(&self, secret_name: &SecretName) -> Result<(), Self::Error> {
+        self.inner.delete_secrets_from_inbox(secret_name).await
+    }
+
+    async fn get_room_settings(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<RoomSettings>, Self::Error> {
+        self.inner.get_room_settings(room_id).await
+    }
+
+    async fn get_received_room_key_bundle_data(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<StoredRoomKeyBundleData>, Self::Error> {
+        self.inner.get_received_room_key_bundle_data(room_id, user_id).await
+    }
+
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get_custom_value(key).await
+    }
+
+    async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner.set_custom_value(key, value).await
+    }
+
+    async fn remove_custom_value(&self, key: &str) -> Result<(), Self::Error> {
+        self.inner.remove_custom_value(key).await
+    }
+
+    async fn get_app_data(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get_app_data(namespace, key).await
+    }
+
+    async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>, Self::Error> {
+        self.inner.get_app_data_keys(namespace).await
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool, Self::Error> {
+        self.inner.try_take_leased_lock(lease_duration_ms, key, holder).await
+    }
+
+    fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.inner.set_clock(clock)
+    }
+
+    async fn next_batch_token(&self) -> Result<Option<String>, Self::Error> {
+        self.inner.next_batch_token().await
+    }
+
+    async fn compact(&self) -> Result<CompactionReport, Self::Error> {
+        let result = self.inner.compact().await;
+        // Compaction may remove data we have cached.
+        self.clear_caches();
+
+        result
+    }
+
+    async fn load_change_journal(
+        &self,
+        since: u64,
+    ) -> Result<Vec<ChangeJournalRecord>, Self::Error> {
+        self.inner.load_change_journal(since).await
+    }
+
+    async fn truncate_change_journal(&self, up_to: u64) -> Result<(), Self::Error> {
+        self.inner.truncate_change_journal(up_to).await
+    }
+
+    async fn shred(&self) -> Result<ShredReport, Self::Error> {
+        let result = self.inner.shred().await;
+        // The cached inbound group sessions are gone from the store.
+        self.clear_caches();
+
+        result
+    }
+
+    async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+        custom_value_keys: &[String],
+    ) -> Result<DeletedRoomCryptoState, Self::Error> {
+        let deleted = self.inner.delete_room_crypto_state(room_id, custom_value_keys).await?;
+
+        // The inbound group sessions are the only deleted data which is cached.
+        let keys = ChangedKeys {
+            inbound_group_sessions: deleted
+                .room_keys
+                .iter()
+                .map(|info| (info.room_id.clone(), info.session_id.clone()))
+                .collect(),
+            ..Default::default()
+        };
+        self.invalidate_changes(keys);
+
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_test::async_test;
+    use ruma::{device_id, user_id};
+
+    use super::{CachingStore, CachingStoreLimits, LruCache};
+    use crate::{
+        store::{
+            types::{Changes, DeviceChanges},
+            CryptoStore, MemoryStore,
+        },
+        Account, DeviceData, LocalTrust,
+    };
+
+    #[test]
+    fn test_lru_cache_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        // Using `a` makes `b` the least recently used entry.
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.insert("c", 3);
+
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(3));
+
+        cache.remove(&"a");
+        assert_eq!(cache.get(&"a"), None);
+
+        let mut disabled = LruCache::new(0);
+        disabled.insert("a", 1);
+        assert_eq!(disabled.get(&"a"), None);
+    }
+
+    #[async_test]
+    async fn test_caching_store_invalidates_on_save() {
+        let store = CachingStore::with_limits(
+            MemoryStore::new(),
+            CachingStoreLimits { devices: 10, ..Default::default() },
+        );
+
+        let account = Account::with_device_id(user_id!("@alice:localhost"), device_id!("ALICE"));
+        let device = DeviceData::from_account(&account);
+        let changes = Changes {
+            devices: DeviceChanges { new: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        // The first lookup loads the device from the wrapped store and caches it.
+        let loaded = store.get_device(device.user_id(), device.device_id()).await.unwrap().unwrap();
+        assert_eq!(loaded.local_trust_state(), LocalTrust::Unset);
+        assert_eq!(store.caches.lock().devices.entries.len(), 1);
+
+        // Saving a changed device through the caching store evicts the stale copy.
+        let changed = DeviceData::from_account(&account);
+        changed.set_trust_state(LocalTrust::Verified);
+        let changes = Changes {
+            devices: DeviceChanges { changed: vec![changed], ..Default::default() },
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        assert!(store.caches.lock().devices.entries.is_empty());
+
+        let loaded = store.get_device(device.user_id(), device.device_id()).await.unwrap().unwrap();
+        assert_eq!(loaded.local_trust_state(), LocalTrust::Verified);
+
+        // Deleting the device removes it from the cache as well.
+        let changes = Changes {
+            devices: DeviceChanges { deleted: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        assert!(store.get_device(device.user_id(), device.device_id()).await.unwrap().is_none());
+    }
+}
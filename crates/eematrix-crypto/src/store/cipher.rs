@@ -0,0 +1,136 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use matrix_sdk_common::{SendOutsideWasm, SyncOutsideWasm};
+
+use super::CryptoStoreError;
+
+/// A cipher that persistent stores use to encrypt the data they hold, such as
+/// pickled accounts and sessions, before it's written to disk.
+///
+/// By default, stores use a [`matrix_sdk_store_encryption::StoreCipher`]
+/// whose key is derived from a passphrase. Implementing this trait allows the
+/// key material to live somewhere else instead, for example in a TPM, the
+/// Secure Enclave, or the Android Keystore.
+///
+/// The encrypted values are persisted by the store, so an implementation must
+/// be able to decrypt everything it previously encrypted, including across
+/// restarts.
+pub trait StoreCipher: fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// Encrypt a value before it's written to the store.
+    ///
+    /// The plaintext may contain pickled secrets, implementations should
+    /// zeroize it once it's encrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `plaintext` - The serialized value that should be encrypted.
+    fn encrypt(&self, plaintext: Vec<u8>) -> Result<Vec<u8>, CryptoStoreError>;
+
+    /// Decrypt a value which was encrypted using [`StoreCipher::encrypt()`],
+    /// after it has been read from the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` - The encrypted value, as it was returned by
+    ///   [`StoreCipher::encrypt()`].
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoStoreError>;
+
+    /// Hash a key before it's used in the store, so that the store doesn't
+    /// leak the keys it holds, e.g. room or user IDs.
+    ///
+    /// The hash must be deterministic: the same key in the same table must
+    /// always produce the same hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `table_name` - The name of the table the key belongs to, this is
+    ///   used to produce different hashes for the same key in different tables.
+    ///
+    /// * `key` - The key that should be hashed.
+    fn hash_key(&self, table_name: &str, key: &[u8]) -> [u8; 32];
+}
+
+/// A wrapper that implements [`StoreCipher`] for the built-in,
+/// passphrase-based [`matrix_sdk_store_encryption::StoreCipher`].
+///
+/// The values are encrypted in the same format as they were before the
+/// [`StoreCipher`] trait was introduced, so existing stores stay readable.
+pub struct DefaultStoreCipher(matrix_sdk_store_encryption::StoreCipher);
+
+impl DefaultStoreCipher {
+    /// Wrap the given [`matrix_sdk_store_encryption::StoreCipher`].
+    pub fn new(cipher: matrix_sdk_store_encryption::StoreCipher) -> Self {
+        Self(cipher)
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for DefaultStoreCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultStoreCipher").finish_non_exhaustive()
+    }
+}
+
+impl StoreCipher for DefaultStoreCipher {
+    fn encrypt(&self, plaintext: Vec<u8>) -> Result<Vec<u8>, CryptoStoreError> {
+        let encrypted = self.0.encrypt_value_data(plaintext).map_err(CryptoStoreError::backend)?;
+        rmp_serde::to_vec_named(&encrypted).map_err(CryptoStoreError::backend)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoStoreError> {
+        let encrypted = rmp_serde::from_slice(ciphertext).map_err(CryptoStoreError::backend)?;
+        self.0.decrypt_value_data(encrypted).map_err(CryptoStoreError::backend)
+    }
+
+    fn hash_key(&self, table_name: &str, key: &[u8]) -> [u8; 32] {
+        self.0.hash_key(table_name, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_store_encryption::StoreCipher as InnerCipher;
+
+    use super::{DefaultStoreCipher, StoreCipher};
+
+    #[test]
+    fn test_default_cipher_round_trip() {
+        let cipher = DefaultStoreCipher::new(InnerCipher::new().unwrap());
+
+        let encrypted = cipher.encrypt(b"It's a secret to everybody".to_vec()).unwrap();
+        assert_ne!(encrypted, b"It's a secret to everybody");
+
+        let decrypted = cipher.decrypt(&encrypted).unwrap();
+        assert_eq!(decrypted, b"It's a secret to everybody");
+    }
+
+    #[test]
+    fn test_default_cipher_is_compatible_with_the_store_cipher_format() {
+        let inner = InnerCipher::new().unwrap();
+        let export = inner.export_with_key(&[0u8; 32]).unwrap();
+        let cipher = DefaultStoreCipher::new(inner);
+
+        // Values encrypted by the inner cipher directly, like the stores used to do,
+        // can be decrypted.
+        let inner = InnerCipher::import_with_key(&[0u8; 32], &export).unwrap();
+        let encrypted =
+            rmp_serde::to_vec_named(&inner.encrypt_value_data(b"value".to_vec()).unwrap()).unwrap();
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), b"value");
+
+        assert_eq!(cipher.hash_key("table", b"key"), inner.hash_key("table", b"key"));
+    }
+}
@@ -1,24 +1,54 @@
-use std::{future, ops::Deref, sync::Arc};
+use std::{
+    future::{self, Future},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use futures_core::Stream;
 use futures_util::StreamExt;
-use matrix_sdk_common::store_locks::CrossProcessStoreLock;
-use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+#[cfg(all(target_family = "wasm", feature = "js"))]
+use matrix_sdk_common::store_locks::WebLocksBackingStore;
+use matrix_sdk_common::{locks::RwLock as StdRwLock, store_locks::CrossProcessStoreLock};
+use ruma::{
+    serde::JsonObject, time::Instant, DeviceId, EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId,
+    OwnedUserId, RoomId, SecondsSinceUnixEpoch, UserId,
+};
 use tokio::sync::{broadcast, Mutex};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
-use tracing::{debug, trace, warn};
+use tracing::{debug, instrument, trace, warn};
+use vodozemac::Curve25519PublicKey;
 
 use super::{
-    caches::SessionStore, types::RoomKeyBundleInfo, DeviceChanges, IdentityChanges,
-    LockableCryptoStore,
+    caches::{
+        DecryptedEventCache, MegolmMessageIndexCache, SessionStore,
+        DEFAULT_DECRYPTED_EVENT_CACHE_CAPACITY, DEFAULT_MEGOLM_MESSAGE_INDEX_CACHE_CAPACITY,
+    },
+    metrics::{StoreCacheKind, StoreMetricsObserver, StoreOperation},
+    secret_storage::SecretStorageBackend,
+    types::{
+        BackupKeys, ChangeJournalEntry, DeletedRoomCryptoState, RoomKeyBundleInfo, ShredReport,
+        VerificationAuditEntry, VerificationAuditEvent,
+    },
+    value_codec::ValueCodec,
+    DeviceChanges, IdentityChanges, LockableCryptoStore, ReadOnlyStore,
 };
 use crate::{
-    olm::InboundGroupSession,
+    clock::{Clock, SystemClock},
+    identities::DeviceData,
+    olm::{InboundGroupSession, SenderDataType},
     store,
     store::{Changes, DynCryptoStore, IntoCryptoStore, RoomKeyInfo, RoomKeyWithheldInfo},
+    utilities::LatencyTimer,
     CryptoStoreError, GossippedSecret, OwnUserIdentityData, Session, UserIdentityData,
 };
 
+/// The key under which the cross-process generation counter is stored as a
+/// custom value.
+pub(crate) const CURRENT_GENERATION_STORE_KEY: &str = "generation-counter";
+
 /// A wrapper for crypto store implementations that adds update notifiers.
 ///
 /// This is shared between [`StoreInner`] and
@@ -30,13 +60,27 @@ pub(crate) struct CryptoStoreWrapper {
 
     store: Arc<DynCryptoStore>,
 
+    /// A read-only view of `store`, which is handed out instead of it while
+    /// the store is frozen.
+    read_only_store: Arc<DynCryptoStore>,
+
     /// A cache for the Olm Sessions.
     sessions: SessionStore,
 
+    /// A cache for the plaintext of decrypted room events.
+    decrypted_events: DecryptedEventCache,
+
+    /// A cache for the Megolm message indices recorded in the store.
+    megolm_message_indices: MegolmMessageIndexCache,
+
     /// The sender side of a broadcast stream that is notified whenever we get
     /// an update to an inbound group session.
     room_keys_received_sender: broadcast::Sender<Vec<RoomKeyInfo>>,
 
+    /// The sender side of a broadcast stream that is notified whenever inbound
+    /// group sessions are deleted from the store.
+    room_keys_deleted_sender: broadcast::Sender<Vec<RoomKeyInfo>>,
+
     /// The sender side of a broadcast stream that is notified whenever we
     /// receive an `m.room_key.withheld` message.
     room_keys_withheld_received_sender: broadcast::Sender<Vec<RoomKeyWithheldInfo>>,
@@ -53,11 +97,50 @@ pub(crate) struct CryptoStoreWrapper {
     /// The sender side of a broadcast channel which sends out information about
     /// historic room key bundles we have received.
     historic_room_key_bundles_broadcaster: broadcast::Sender<RoomKeyBundleInfo>,
+
+    /// The last value of the cross-process generation counter we have seen,
+    /// if any.
+    known_generation: StdRwLock<Option<u64>>,
+
+    /// Set whenever the cross-process lock has been acquired, which means that
+    /// another process might have written to the store since we last looked
+    /// at the generation counter.
+    generation_check_pending: Arc<AtomicBool>,
+
+    /// The observer that is notified about store operations, if any.
+    metrics_observer: StdRwLock<Option<Arc<dyn StoreMetricsObserver>>>,
+
+    /// The backend storing the backup decryption key instead of the store, if
+    /// any.
+    secret_storage: Option<Arc<dyn SecretStorageBackend>>,
+
+    /// The format custom values are serialized in.
+    value_codec: ValueCodec,
+
+    /// Whether saved sessions, devices and identities are recorded in the
+    /// change journal of the store.
+    change_journal_enabled: AtomicBool,
+
+    /// Whether entries which can't be decoded are moved to the quarantine of
+    /// the store instead of failing the whole operation.
+    lenient: AtomicBool,
+
+    /// The source of the current time, see [`CryptoStoreWrapper::clock()`].
+    clock: StdRwLock<Arc<dyn Clock>>,
+
+    /// Whether all writes to the store are rejected, see
+    /// [`OlmMachine::freeze()`](crate::OlmMachine::freeze).
+    frozen: AtomicBool,
+
+    /// Whether the store was shredded, which freezes it for good, see
+    /// [`OlmMachine::shred()`](crate::OlmMachine::shred).
+    shredded: AtomicBool,
 }
 
 impl CryptoStoreWrapper {
     pub(crate) fn new(user_id: &UserId, device_id: &DeviceId, store: impl IntoCryptoStore) -> Self {
         let room_keys_received_sender = broadcast::Sender::new(10);
+        let room_keys_deleted_sender = broadcast::Sender::new(10);
         let room_keys_withheld_received_sender = broadcast::Sender::new(10);
         let secrets_broadcaster = broadcast::Sender::new(10);
         // The identities broadcaster is responsible for user identities as well as
@@ -65,17 +148,277 @@ impl CryptoStoreWrapper {
         let identities_broadcaster = broadcast::Sender::new(20);
         let historic_room_key_bundles_broadcaster = broadcast::Sender::new(10);
 
+        let store = store.into_crypto_store();
+        let read_only_store = ReadOnlyStore::new(store.clone()).into_crypto_store();
+
         Self {
             user_id: user_id.to_owned(),
             device_id: device_id.to_owned(),
-            store: store.into_crypto_store(),
+            store,
+            read_only_store,
             sessions: SessionStore::new(),
+            decrypted_events: DecryptedEventCache::new(DEFAULT_DECRYPTED_EVENT_CACHE_CAPACITY),
+            megolm_message_indices: MegolmMessageIndexCache::new(
+                DEFAULT_MEGOLM_MESSAGE_INDEX_CACHE_CAPACITY,
+            ),
             room_keys_received_sender,
+            room_keys_deleted_sender,
             room_keys_withheld_received_sender,
             secrets_broadcaster,
             identities_broadcaster,
             historic_room_key_bundles_broadcaster,
+            known_generation: StdRwLock::new(None),
+            generation_check_pending: Arc::new(AtomicBool::new(false)),
+            metrics_observer: StdRwLock::new(None),
+            secret_storage: None,
+            value_codec: ValueCodec::default(),
+            change_journal_enabled: AtomicBool::new(false),
+            lenient: AtomicBool::new(false),
+            clock: StdRwLock::new(Arc::new(SystemClock)),
+            frozen: AtomicBool::new(false),
+            shredded: AtomicBool::new(false),
+        }
+    }
+
+    /// Store the backup decryption key in the given [`SecretStorageBackend`]
+    /// instead of the store.
+    pub(crate) fn with_secret_storage(mut self, backend: Arc<dyn SecretStorageBackend>) -> Self {
+        self.secret_storage = Some(backend);
+        self
+    }
+
+    /// Serialize custom values using the given [`ValueCodec`].
+    pub(crate) fn with_value_codec(mut self, codec: ValueCodec) -> Self {
+        self.value_codec = codec;
+        self
+    }
+
+    /// The format custom values are serialized in.
+    pub(crate) fn value_codec(&self) -> ValueCodec {
+        self.value_codec
+    }
+
+    /// Enable or disable recording saved sessions, devices and identities in
+    /// the change journal of the store.
+    pub(crate) fn set_change_journal_enabled(&self, enabled: bool) {
+        self.change_journal_enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Are saved sessions, devices and identities recorded in the change
+    /// journal of the store?
+    pub(crate) fn is_change_journal_enabled(&self) -> bool {
+        self.change_journal_enabled.load(Ordering::SeqCst)
+    }
+
+    /// Enable or disable moving entries which can't be decoded to the
+    /// quarantine of the store, instead of failing the operation which tried
+    /// to load them.
+    pub(crate) fn set_lenient(&self, lenient: bool) {
+        self.lenient.store(lenient, Ordering::SeqCst);
+    }
+
+    /// Are entries which can't be decoded moved to the quarantine of the
+    /// store?
+    pub(crate) fn is_lenient(&self) -> bool {
+        self.lenient.load(Ordering::SeqCst)
+    }
+
+    /// Get the source of the current time used by the crypto machine.
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.read().clone()
+    }
+
+    /// Set the source of the current time used by the crypto machine, and
+    /// hand it down to the store, see [`CryptoStore::set_clock()`].
+    ///
+    /// [`CryptoStore::set_clock()`]: crate::store::CryptoStore::set_clock
+    pub(crate) fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.store.set_clock(clock.clone());
+        *self.clock.write() = clock;
+    }
+
+    /// Freeze or thaw the store.
+    ///
+    /// While the store is frozen all writes are rejected with a
+    /// [`CryptoStoreError::ReadOnly`] error, reads work as usual. A store which
+    /// was shredded stays frozen.
+    pub(crate) fn set_frozen(&self, frozen: bool) {
+        self.frozen.store(frozen || self.is_shredded(), Ordering::SeqCst);
+    }
+
+    /// Was the store shredded?
+    pub(crate) fn is_shredded(&self) -> bool {
+        self.shredded.load(Ordering::SeqCst)
+    }
+
+    /// Is the store frozen?
+    pub(crate) fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    /// Return an error if the store is frozen.
+    fn ensure_not_frozen(&self) -> store::Result<()> {
+        if self.is_frozen() {
+            Err(CryptoStoreError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Install an observer which is notified about store operations, or
+    /// remove the current one.
+    pub(crate) fn set_metrics_observer(&self, observer: Option<Arc<dyn StoreMetricsObserver>>) {
+        *self.metrics_observer.write() = observer;
+    }
+
+    /// Run the given store operation, reporting how long it took to the
+    /// metrics observer, if there is one.
+    async fn observe<T>(
+        &self,
+        operation: StoreOperation,
+        future: impl Future<Output = store::Result<T>>,
+    ) -> store::Result<T> {
+        let Some(observer) = self.metrics_observer.read().clone() else {
+            return future.await;
+        };
+
+        let start = Instant::now();
+        let result = future.await;
+        observer.on_operation(operation, start.elapsed(), result.is_ok());
+
+        result
+    }
+
+    /// Report a lookup in one of our caches to the metrics observer, if there
+    /// is one.
+    fn observe_cache_lookup(&self, cache: StoreCacheKind, hit: bool) {
+        if let Some(observer) = self.metrics_observer.read().as_ref() {
+            observer.on_cache_lookup(cache, hit);
+        }
+    }
+
+    /// Set the capacity of the decrypted event cache, in bytes. A capacity of
+    /// zero disables the cache.
+    pub(crate) fn set_decrypted_event_cache_capacity(&self, capacity: usize) {
+        self.decrypted_events.set_capacity(capacity);
+    }
+
+    /// Get the plaintext of a previously decrypted room event from the cache,
+    /// if it was decrypted from the given ciphertext.
+    pub(crate) fn get_decrypted_event(
+        &self,
+        event_id: &EventId,
+        ciphertext: &[u8],
+    ) -> Option<JsonObject> {
+        if !self.decrypted_events.is_enabled() {
+            return None;
+        }
+
+        let plaintext = self.decrypted_events.get(event_id, ciphertext);
+        self.observe_cache_lookup(StoreCacheKind::DecryptedEvents, plaintext.is_some());
+
+        plaintext
+    }
+
+    /// Add the plaintext of a decrypted room event to the cache.
+    pub(crate) fn cache_decrypted_event(
+        &self,
+        event_id: &EventId,
+        ciphertext: &[u8],
+        plaintext: JsonObject,
+    ) {
+        self.decrypted_events.insert(event_id, ciphertext, plaintext);
+    }
+
+    /// Record that the given event used a Megolm message index, returning the
+    /// event which used it first if that was recorded before.
+    ///
+    /// Indices which were recorded or looked up before are answered from
+    /// memory, so decrypting the same event again doesn't write to the store.
+    ///
+    /// While the store is frozen the indices are only recorded in memory, so
+    /// events can still be decrypted, replays of events which were decrypted
+    /// in another process or before a restart aren't detected until then.
+    pub(crate) async fn record_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> store::Result<Option<OwnedEventId>> {
+        if let Some(recorded) = self.megolm_message_indices.get(room_id, session_id, message_index)
+        {
+            return Ok(Some(recorded));
+        }
+
+        let recorded = if self.is_frozen() {
+            None
+        } else {
+            self.store
+                .record_megolm_message_index(room_id, session_id, message_index, event_id)
+                .await?
+        };
+
+        self.megolm_message_indices.insert(
+            room_id,
+            session_id,
+            message_index,
+            recorded.as_deref().unwrap_or(event_id),
+        );
+
+        Ok(recorded)
+    }
+
+    /// Load the cross-process generation counter from the store.
+    pub(crate) async fn load_generation(&self) -> store::Result<Option<u64>> {
+        let Some(generation) = self.store.get_custom_value(CURRENT_GENERATION_STORE_KEY).await?
+        else {
+            return Ok(None);
+        };
+
+        let generation =
+            u64::from_le_bytes(generation.try_into().map_err(|_| {
+                CryptoStoreError::InvalidLockGeneration("invalid format".to_owned())
+            })?);
+
+        Ok(Some(generation))
+    }
+
+    /// Remember a generation counter value that this process wrote itself, so
+    /// it isn't mistaken for a write from another process.
+    pub(crate) fn set_known_generation(&self, generation: u64) {
+        *self.known_generation.write() = Some(generation);
+    }
+
+    /// Check whether another process has written to the store since the last
+    /// time we looked.
+    ///
+    /// The generation counter is only loaded from the store if the
+    /// cross-process lock has been acquired since the last check, otherwise
+    /// no other process could have written to the store.
+    ///
+    /// If a change is detected, the Olm session cache is cleared.
+    pub(crate) async fn was_modified_by_another_process(&self) -> store::Result<bool> {
+        if !self.generation_check_pending.swap(false, Ordering::SeqCst) {
+            return Ok(false);
         }
+
+        let Some(generation) = self.load_generation().await? else {
+            return Ok(false);
+        };
+
+        let previous = self.known_generation.write().replace(generation);
+        let modified = previous.is_some_and(|previous| previous != generation);
+
+        if modified {
+            debug!(
+                previous,
+                generation, "The store was modified by another process, clearing the caches"
+            );
+            self.sessions.clear().await;
+        }
+
+        Ok(modified)
     }
 
     /// Save the set of changes to the store.
@@ -86,7 +429,23 @@ impl CryptoStoreWrapper {
     /// # Arguments
     ///
     /// * `changes` - The set of changes that should be stored.
-    pub async fn save_changes(&self, changes: Changes) -> store::Result<()> {
+    #[instrument(skip_all, fields(duration_ms))]
+    pub async fn save_changes(&self, mut changes: Changes) -> store::Result<()> {
+        self.ensure_not_frozen()?;
+
+        let _timer = LatencyTimer::start("save_changes");
+
+        if let Some(backend) = &self.secret_storage {
+            if let Some(key) = changes.backup_decryption_key.take() {
+                backend.save_backup_decryption_key(&self.user_id, &self.device_id, &key).await?;
+            }
+        }
+
+        if self.is_change_journal_enabled() {
+            let entries = changes.change_journal_entries();
+            changes.change_journal.extend(entries);
+        }
+
         let room_key_updates: Vec<_> =
             changes.inbound_group_sessions.iter().map(RoomKeyInfo::from).collect();
 
@@ -113,6 +472,11 @@ impl CryptoStoreWrapper {
             .and_then(|i| i.own())
             .is_some_and(|own| own.is_verified());
 
+        let newly_verified = self
+            .newly_verified_identities(&changes.identities, own_identity_was_verified_before_change)
+            .await?;
+        changes.verification_audit_log.extend(newly_verified);
+
         let secrets = changes.secrets.to_owned();
         let devices = changes.devices.to_owned();
         let identities = changes.identities.to_owned();
@@ -135,7 +499,7 @@ impl CryptoStoreWrapper {
             }
         }
 
-        self.store.save_changes(changes).await?;
+        self.observe(StoreOperation::SaveChanges, self.store.save_changes(changes)).await?;
 
         // If we updated our own public identity, log it for debugging purposes
         if tracing::level_enabled!(tracing::Level::DEBUG) {
@@ -212,12 +576,51 @@ impl CryptoStoreWrapper {
         Ok(())
     }
 
+    /// Create the verification audit log entries of the identities in the
+    /// given changes that become verified by saving them.
+    async fn newly_verified_identities(
+        &self,
+        identities: &IdentityChanges,
+        own_identity_was_verified_before_change: bool,
+    ) -> store::Result<Vec<VerificationAuditEntry>> {
+        let mut entries = Vec::new();
+
+        for identity in identities.new.iter().chain(&identities.changed) {
+            let newly_verified = match identity {
+                UserIdentityData::Own(own) => {
+                    own.is_verified() && !own_identity_was_verified_before_change
+                }
+                UserIdentityData::Other(other) => {
+                    other.was_previously_verified()
+                        && !self
+                            .store
+                            .get_user_identity(other.user_id())
+                            .await?
+                            .as_ref()
+                            .and_then(|i| i.other())
+                            .is_some_and(|stored| stored.was_previously_verified())
+                }
+            };
+
+            if newly_verified {
+                entries.push(VerificationAuditEntry::new(
+                    identity.master_key(),
+                    VerificationAuditEvent::Verified,
+                    self.clock().now(),
+                ));
+            }
+        }
+
+        Ok(entries)
+    }
+
     async fn check_all_identities_and_update_was_previously_verified_flag_if_needed(
         &self,
         own_identity_after: &OwnUserIdentityData,
     ) -> Result<(), CryptoStoreError> {
         let tracked_users = self.store.load_tracked_users().await?;
         let mut updated_identities: Vec<UserIdentityData> = Default::default();
+        let mut verification_audit_log = Vec::new();
         for tracked_user in tracked_users {
             if let Some(other_identity) = self
                 .store
@@ -232,6 +635,11 @@ impl CryptoStoreWrapper {
                     trace!(?tracked_user.user_id, "Marking set verified_latch to true.");
                     other_identity.mark_as_previously_verified();
                     updated_identities.push(other_identity.clone().into());
+                    verification_audit_log.push(VerificationAuditEntry::new(
+                        other_identity.master_key(),
+                        VerificationAuditEvent::Verified,
+                        self.clock().now(),
+                    ));
                 }
             }
         }
@@ -242,6 +650,7 @@ impl CryptoStoreWrapper {
             self.store
                 .save_changes(Changes {
                     identities: identity_changes.clone(),
+                    verification_audit_log,
                     ..Default::default()
                 })
                 .await?;
@@ -256,6 +665,64 @@ impl CryptoStoreWrapper {
         Ok(())
     }
 
+    /// Load the backup keys, taking the decryption key from the
+    /// [`SecretStorageBackend`] if one is used.
+    ///
+    /// A decryption key which was stored before the backend was selected is
+    /// moved into the backend, and deleted from the store afterwards.
+    pub async fn load_backup_keys(&self) -> store::Result<BackupKeys> {
+        let mut backup_keys = self.store.load_backup_keys().await?;
+
+        if let Some(backend) = &self.secret_storage {
+            let stored_key = backup_keys.decryption_key.take();
+            backup_keys.decryption_key =
+                backend.load_backup_decryption_key(&self.user_id, &self.device_id).await?;
+
+            if let Some(stored_key) = stored_key {
+                if backup_keys.decryption_key.is_none() {
+                    backend
+                        .save_backup_decryption_key(&self.user_id, &self.device_id, &stored_key)
+                        .await?;
+                    backup_keys.decryption_key = Some(stored_key);
+                }
+
+                // The backend has the key now, so it's safe to delete it. If
+                // this fails, the key is deleted the next time it's loaded.
+                if let Err(error) = self.store.delete_backup_decryption_key().await {
+                    warn!(
+                        ?error,
+                        "Couldn't delete the backup decryption key which was moved to the backend"
+                    );
+                }
+            }
+        }
+
+        Ok(backup_keys)
+    }
+
+    /// Delete the account, the sessions and all the private keys, see
+    /// [`OlmMachine::shred()`](crate::OlmMachine::shred).
+    ///
+    /// Unlike other writes, this isn't rejected while the store is frozen. The
+    /// store is frozen for good afterwards, even if shredding it failed.
+    pub(crate) async fn shred(&self) -> store::Result<ShredReport> {
+        self.shredded.store(true, Ordering::SeqCst);
+        self.set_frozen(true);
+
+        self.sessions.clear().await;
+        self.decrypted_events.clear();
+        self.megolm_message_indices.clear();
+
+        // The store deletes everything else in a single transaction.
+        let report = self.store.shred().await?;
+
+        if let Some(backend) = &self.secret_storage {
+            backend.delete_backup_decryption_key(&self.user_id, &self.device_id).await?;
+        }
+
+        Ok(report)
+    }
+
     pub async fn get_sessions(
         &self,
         sender_key: &str,
@@ -266,11 +733,14 @@ impl CryptoStoreWrapper {
             let mut entries = self.sessions.entries.write().await;
 
             let sessions = entries.get(sender_key);
+            self.observe_cache_lookup(StoreCacheKind::Sessions, sessions.is_some());
 
             if sessions.is_some() {
                 sessions.cloned()
             } else {
-                let sessions = self.store.get_sessions(sender_key).await?;
+                let sessions = self
+                    .observe(StoreOperation::GetSessions, self.store.get_sessions(sender_key))
+                    .await?;
                 let sessions = Arc::new(Mutex::new(sessions.unwrap_or_default()));
 
                 entries.insert(sender_key.to_owned(), sessions.clone());
@@ -278,12 +748,172 @@ impl CryptoStoreWrapper {
                 Some(sessions)
             }
         } else {
+            self.observe_cache_lookup(StoreCacheKind::Sessions, true);
             sessions
         };
 
         Ok(sessions)
     }
 
+    /// Get the device for the given user with the given device ID.
+    ///
+    /// This shadows [`CryptoStore::get_device()`], so the lookup can be
+    /// reported to the metrics observer.
+    ///
+    /// [`CryptoStore::get_device()`]: crate::store::CryptoStore::get_device
+    pub async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> store::Result<Option<DeviceData>> {
+        self.observe(StoreOperation::GetDevice, self.store.get_device(user_id, device_id)).await
+    }
+
+    /// Run the given loader of inbound group sessions.
+    ///
+    /// If the store is lenient and the loader fails, the sessions which can't
+    /// be decoded are moved to the quarantine of the store and the loader is
+    /// run again.
+    async fn load_inbound_group_sessions_leniently<T, F, Fut>(&self, load: F) -> store::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = store::Result<T>>,
+    {
+        let error = match load().await {
+            Ok(sessions) => return Ok(sessions),
+            Err(error) if self.is_lenient() => error,
+            Err(error) => return Err(error),
+        };
+
+        let quarantined = self.store.quarantine_undecodable_inbound_group_sessions().await?;
+
+        if quarantined.is_empty() {
+            return Err(error);
+        }
+
+        warn!(
+            ?error,
+            count = quarantined.len(),
+            "Moved inbound group sessions which couldn't be decoded to the quarantine"
+        );
+
+        load().await
+    }
+
+    /// Get the inbound group session with the given room ID and session ID.
+    ///
+    /// This shadows [`CryptoStore::get_inbound_group_session()`], see
+    /// [`CryptoStoreWrapper::get_inbound_group_sessions()`] for the lenient
+    /// loading.
+    ///
+    /// [`CryptoStore::get_inbound_group_session()`]: crate::store::CryptoStore::get_inbound_group_session
+    pub async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> store::Result<Option<InboundGroupSession>> {
+        self.load_inbound_group_sessions_leniently(move || {
+            self.store.get_inbound_group_session(room_id, session_id)
+        })
+        .await
+    }
+
+    /// Get all the inbound group sessions we have stored.
+    ///
+    /// This shadows [`CryptoStore::get_inbound_group_sessions()`]: if the
+    /// store is lenient and loading the sessions fails, the sessions which
+    /// can't be decoded are moved to the quarantine of the store and the
+    /// remaining ones are loaded again.
+    ///
+    /// The other loaders of inbound group sessions of this wrapper behave the
+    /// same way.
+    ///
+    /// [`CryptoStore::get_inbound_group_sessions()`]: crate::store::CryptoStore::get_inbound_group_sessions
+    pub async fn get_inbound_group_sessions(&self) -> store::Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions_leniently(move || self.store.get_inbound_group_sessions())
+            .await
+    }
+
+    /// Get a batch of the inbound group sessions we have stored.
+    ///
+    /// This shadows [`CryptoStore::get_inbound_group_sessions_batch()`], see
+    /// [`CryptoStoreWrapper::get_inbound_group_sessions()`] for the lenient
+    /// loading.
+    ///
+    /// [`CryptoStore::get_inbound_group_sessions_batch()`]: crate::store::CryptoStore::get_inbound_group_sessions_batch
+    pub async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> store::Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions_leniently(move || {
+            self.store.get_inbound_group_sessions_batch(after.clone(), limit)
+        })
+        .await
+    }
+
+    /// Get a batch of the inbound group sessions for the given device.
+    ///
+    /// This shadows
+    /// [`CryptoStore::get_inbound_group_sessions_for_device_batch()`], see
+    /// [`CryptoStoreWrapper::get_inbound_group_sessions()`] for the lenient
+    /// loading.
+    ///
+    /// [`CryptoStore::get_inbound_group_sessions_for_device_batch()`]: crate::store::CryptoStore::get_inbound_group_sessions_for_device_batch
+    pub async fn get_inbound_group_sessions_for_device_batch(
+        &self,
+        curve_key: Curve25519PublicKey,
+        sender_data_type: SenderDataType,
+        after_session_id: Option<String>,
+        limit: usize,
+    ) -> store::Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions_leniently(move || {
+            self.store.get_inbound_group_sessions_for_device_batch(
+                curve_key,
+                sender_data_type,
+                after_session_id.clone(),
+                limit,
+            )
+        })
+        .await
+    }
+
+    /// Get the inbound group sessions of the given room.
+    ///
+    /// This shadows [`CryptoStore::get_inbound_group_sessions_for_room()`],
+    /// see [`CryptoStoreWrapper::get_inbound_group_sessions()`] for the
+    /// lenient loading.
+    ///
+    /// [`CryptoStore::get_inbound_group_sessions_for_room()`]: crate::store::CryptoStore::get_inbound_group_sessions_for_room
+    pub async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> store::Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions_leniently(move || {
+            self.store.get_inbound_group_sessions_for_room(room_id, since)
+        })
+        .await
+    }
+
+    /// Get a batch of the inbound group sessions which need to be backed up.
+    ///
+    /// This shadows [`CryptoStore::inbound_group_sessions_for_backup()`], see
+    /// [`CryptoStoreWrapper::get_inbound_group_sessions()`] for the lenient
+    /// loading.
+    ///
+    /// [`CryptoStore::inbound_group_sessions_for_backup()`]: crate::store::CryptoStore::inbound_group_sessions_for_backup
+    pub async fn inbound_group_sessions_for_backup(
+        &self,
+        backup_version: &str,
+        limit: usize,
+    ) -> store::Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions_leniently(move || {
+            self.store.inbound_group_sessions_for_backup(backup_version, limit)
+        })
+        .await
+    }
+
     /// Save a list of inbound group sessions to the store.
     ///
     /// # Arguments
@@ -294,13 +924,36 @@ impl CryptoStoreWrapper {
     ///
     /// Note: some implementations ignore `backup_version` and assume the
     /// current backup version, which is normally the same.
+    #[instrument(skip_all, fields(duration_ms, count))]
     pub async fn save_inbound_group_sessions(
         &self,
         sessions: Vec<InboundGroupSession>,
         backed_up_to_version: Option<&str>,
     ) -> store::Result<()> {
+        self.ensure_not_frozen()?;
+
+        let mut timer = LatencyTimer::start("save_inbound_group_sessions");
+        timer.set_count(sessions.len());
+
         let room_key_updates: Vec<_> = sessions.iter().map(RoomKeyInfo::from).collect();
-        self.store.save_inbound_group_sessions(sessions, backed_up_to_version).await?;
+
+        if self.is_change_journal_enabled() {
+            // Go through `save_changes()`, so the sessions and their journal
+            // entries are saved in the same transaction.
+            let change_journal = sessions.iter().map(ChangeJournalEntry::from).collect();
+
+            self.store
+                .save_changes(Changes {
+                    inbound_group_sessions: sessions,
+                    inbound_group_sessions_backed_up_to: backed_up_to_version
+                        .map(ToOwned::to_owned),
+                    change_journal,
+                    ..Default::default()
+                })
+                .await?;
+        } else {
+            self.store.save_inbound_group_sessions(sessions, backed_up_to_version).await?;
+        }
 
         if !room_key_updates.is_empty() {
             // Ignore the result. It can only fail if there are no listeners.
@@ -323,6 +976,63 @@ impl CryptoStoreWrapper {
         BroadcastStream::new(self.room_keys_received_sender.subscribe())
     }
 
+    /// Delete the given inbound group sessions, which all belong to the given
+    /// room, from the store.
+    ///
+    /// Also responsible for sending an update to the
+    /// `room_keys_deleted_sender` broadcast stream.
+    pub async fn delete_room_keys(
+        &self,
+        room_id: &RoomId,
+        room_keys: Vec<RoomKeyInfo>,
+    ) -> store::Result<()> {
+        self.ensure_not_frozen()?;
+
+        let session_ids: Vec<_> = room_keys.iter().map(|k| k.session_id.clone()).collect();
+        self.store.delete_inbound_group_sessions(room_id, &session_ids).await?;
+        // The store forgot the message indices of the deleted sessions.
+        self.megolm_message_indices.clear();
+
+        let _ = self.room_keys_deleted_sender.send(room_keys);
+
+        Ok(())
+    }
+
+    /// Delete all the crypto state of the given room, see
+    /// [`CryptoStore::delete_room_crypto_state()`].
+    ///
+    /// [`CryptoStore::delete_room_crypto_state()`]: crate::store::CryptoStore::delete_room_crypto_state
+    ///
+    /// The deleted room keys are sent to the `room_keys_deleted_sender`
+    /// broadcast stream.
+    pub async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+        custom_value_keys: &[String],
+    ) -> store::Result<DeletedRoomCryptoState> {
+        self.ensure_not_frozen()?;
+
+        let deleted = self.store.delete_room_crypto_state(room_id, custom_value_keys).await?;
+        // The store forgot the message indices of the deleted sessions.
+        self.megolm_message_indices.clear();
+
+        if !deleted.room_keys.is_empty() {
+            let _ = self.room_keys_deleted_sender.send(deleted.room_keys.clone());
+        }
+
+        Ok(deleted)
+    }
+
+    /// Receive notifications of room keys being deleted as a [`Stream`].
+    ///
+    /// If the reader of the stream lags too far behind an error will be sent to
+    /// the reader.
+    pub fn room_keys_deleted_stream(
+        &self,
+    ) -> impl Stream<Item = Result<Vec<RoomKeyInfo>, BroadcastStreamRecvError>> {
+        BroadcastStream::new(self.room_keys_deleted_sender.subscribe())
+    }
+
     /// Receive notifications of received `m.room_key.withheld` messages.
     ///
     /// Each time an `m.room_key.withheld` is received and stored, an update
@@ -395,7 +1105,36 @@ impl CryptoStoreWrapper {
         lock_key: String,
         lock_value: String,
     ) -> CrossProcessStoreLock<LockableCryptoStore> {
-        CrossProcessStoreLock::new(LockableCryptoStore(self.store.clone()), lock_key, lock_value)
+        CrossProcessStoreLock::new(
+            LockableCryptoStore {
+                store: self.store.clone(),
+                generation_check_pending: self.generation_check_pending.clone(),
+                #[cfg(all(target_family = "wasm", feature = "js"))]
+                web_locks: None,
+            },
+            lock_key,
+            lock_value,
+        )
+    }
+
+    /// Creates a `CrossProcessStoreLock` for this store using the Web Locks
+    /// API, whose names are prefixed with the given prefix.
+    #[cfg(all(target_family = "wasm", feature = "js"))]
+    pub(crate) fn create_web_store_lock(
+        &self,
+        prefix: String,
+        lock_key: String,
+        lock_value: String,
+    ) -> CrossProcessStoreLock<LockableCryptoStore> {
+        CrossProcessStoreLock::new(
+            LockableCryptoStore {
+                store: self.store.clone(),
+                generation_check_pending: self.generation_check_pending.clone(),
+                web_locks: Some(WebLocksBackingStore::new(prefix)),
+            },
+            lock_key,
+            lock_value,
+        )
     }
 }
 
@@ -403,17 +1142,201 @@ impl Deref for CryptoStoreWrapper {
     type Target = DynCryptoStore;
 
     fn deref(&self) -> &Self::Target {
-        self.store.deref()
+        if self.is_frozen() {
+            self.read_only_store.deref()
+        } else {
+            self.store.deref()
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
     use matrix_sdk_test::async_test;
-    use ruma::user_id;
+    use ruma::{device_id, event_id, user_id};
+    use serde_json::json;
 
     use super::*;
-    use crate::machine::test_helpers::get_machine_pair_with_setup_sessions_test_helper;
+    use crate::{
+        machine::test_helpers::get_machine_pair_with_setup_sessions_test_helper,
+        store::{types::BackupDecryptionKey, CryptoStore, MemoryStore},
+        OlmMachine,
+    };
+
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        operations: StdRwLock<Vec<(StoreOperation, bool)>>,
+        cache_lookups: StdRwLock<Vec<(StoreCacheKind, bool)>>,
+    }
+
+    impl StoreMetricsObserver for RecordingObserver {
+        fn on_operation(&self, operation: StoreOperation, _: Duration, succeeded: bool) {
+            self.operations.write().push((operation, succeeded));
+        }
+
+        fn on_cache_lookup(&self, cache: StoreCacheKind, hit: bool) {
+            self.cache_lookups.write().push((cache, hit));
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct InMemorySecretStorage {
+        key: StdRwLock<Option<BackupDecryptionKey>>,
+    }
+
+    impl InMemorySecretStorage {
+        fn key(&self) -> Option<String> {
+            self.key.read().as_ref().map(|k| k.to_base64())
+        }
+    }
+
+    #[cfg_attr(target_family = "wasm", async_trait(?Send))]
+    #[cfg_attr(not(target_family = "wasm"), async_trait)]
+    impl SecretStorageBackend for InMemorySecretStorage {
+        async fn save_backup_decryption_key(
+            &self,
+            _: &UserId,
+            _: &DeviceId,
+            key: &BackupDecryptionKey,
+        ) -> store::Result<()> {
+            *self.key.write() = Some(key.clone());
+            Ok(())
+        }
+
+        async fn load_backup_decryption_key(
+            &self,
+            _: &UserId,
+            _: &DeviceId,
+        ) -> store::Result<Option<BackupDecryptionKey>> {
+            Ok(self.key.read().clone())
+        }
+
+        async fn delete_backup_decryption_key(
+            &self,
+            _: &UserId,
+            _: &DeviceId,
+        ) -> store::Result<()> {
+            *self.key.write() = None;
+            Ok(())
+        }
+    }
+
+    #[async_test]
+    async fn test_secret_storage_backend() {
+        let user_id = user_id!("@alice:example.com");
+        let store = Arc::new(MemoryStore::new());
+
+        // Given a backup decryption key which is already in the store
+        let old_key = BackupDecryptionKey::new().unwrap();
+        store
+            .save_changes(Changes {
+                backup_decryption_key: Some(old_key.clone()),
+                backup_version: Some("1".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        // When a machine using a secret storage backend is created
+        let backend = Arc::new(InMemorySecretStorage::default());
+        let machine = OlmMachine::builder(user_id, device_id!("DEVICE"))
+            .store(store.clone())
+            .secret_storage(backend.clone())
+            .build()
+            .await
+            .unwrap();
+
+        // Then the key is moved into the backend, and deleted from the store
+        assert_eq!(backend.key(), Some(old_key.to_base64()));
+        let backup_keys = store.load_backup_keys().await.unwrap();
+        assert!(backup_keys.decryption_key.is_none());
+        assert_eq!(backup_keys.backup_version.as_deref(), Some("1"));
+
+        // And new keys only end up in the backend
+        let new_key = BackupDecryptionKey::new().unwrap();
+        machine
+            .store()
+            .save_changes(Changes {
+                backup_decryption_key: Some(new_key.clone()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(backend.key(), Some(new_key.to_base64()));
+        assert!(store.load_backup_keys().await.unwrap().decryption_key.is_none());
+        assert_eq!(
+            machine.store().load_backup_keys().await.unwrap().decryption_key.map(|k| k.to_base64()),
+            Some(new_key.to_base64())
+        );
+    }
+
+    #[async_test]
+    async fn test_metrics_observer() {
+        let user_id = user_id!("@alice:example.com");
+        let (first, second) =
+            get_machine_pair_with_setup_sessions_test_helper(user_id, user_id, false).await;
+
+        let sender_key = second.identity_keys().curve25519.to_base64();
+        let store = &first.store().inner.store;
+
+        let observer = Arc::new(RecordingObserver::default());
+        first.set_store_metrics_observer(Some(observer.clone() as _));
+
+        // Given a sender key whose sessions are cached, and one whose sessions aren't
+        store.get_sessions(&sender_key).await.unwrap();
+        store.get_sessions("not cached").await.unwrap();
+        store.get_device(user_id, first.device_id()).await.unwrap();
+        store.save_changes(Changes::default()).await.unwrap();
+
+        // Then the cache lookups and store operations were reported
+        assert_eq!(
+            *observer.cache_lookups.read(),
+            [(StoreCacheKind::Sessions, true), (StoreCacheKind::Sessions, false)]
+        );
+        assert_eq!(
+            *observer.operations.read(),
+            [
+                (StoreOperation::GetSessions, true),
+                (StoreOperation::GetDevice, true),
+                (StoreOperation::SaveChanges, true),
+            ]
+        );
+
+        // And nothing is reported anymore once the observer is removed
+        first.set_store_metrics_observer(None);
+        store.save_changes(Changes::default()).await.unwrap();
+        assert_eq!(observer.operations.read().len(), 3);
+    }
+
+    #[async_test]
+    async fn test_decrypted_event_cache() {
+        let user_id = user_id!("@alice:example.com");
+        let (machine, _) =
+            get_machine_pair_with_setup_sessions_test_helper(user_id, user_id, false).await;
+        let store = &machine.store().inner.store;
+
+        let observer = Arc::new(RecordingObserver::default());
+        machine.set_store_metrics_observer(Some(observer.clone() as _));
+
+        let plaintext: JsonObject = serde_json::from_value(json!({ "type": "m.dummy" })).unwrap();
+        store.cache_decrypted_event(event_id!("$event"), b"ciphertext", plaintext.clone());
+
+        // Given a cached event, the plaintext is only returned for the same ciphertext
+        assert_eq!(store.get_decrypted_event(event_id!("$event"), b"ciphertext"), Some(plaintext));
+        assert_eq!(store.get_decrypted_event(event_id!("$event"), b"other"), None);
+
+        // And nothing is returned nor reported once the cache is disabled
+        machine.set_decrypted_event_cache_size(0);
+        assert_eq!(store.get_decrypted_event(event_id!("$event"), b"ciphertext"), None);
+        assert_eq!(
+            *observer.cache_lookups.read(),
+            [(StoreCacheKind::DecryptedEvents, true), (StoreCacheKind::DecryptedEvents, false)]
+        );
+    }
 
     #[async_test]
     async fn test_cache_cleared_after_device_update() {
@@ -455,4 +1378,43 @@ mod test {
             "The session should no longer be in the cache after our own device keys changed"
         );
     }
+
+    #[async_test]
+    async fn test_caches_cleared_after_another_process_wrote() {
+        let user_id = user_id!("@alice:example.com");
+        let (first, second) =
+            get_machine_pair_with_setup_sessions_test_helper(user_id, user_id, false).await;
+
+        let sender_key = second.identity_keys().curve25519.to_base64();
+        let store = first.store();
+
+        let generation = Mutex::new(None);
+        first.initialize_crypto_store_generation(&generation).await.unwrap();
+
+        // Given the account and a session are in the caches
+        let _ = store.cache().await.unwrap().account().await.unwrap();
+        assert!(store.inner.cache.read().await.account.lock().await.is_some());
+        assert!(store.inner.store.sessions.get(&sender_key).await.is_some());
+
+        // When another process bumps the generation counter
+        store
+            .set_custom_value(CURRENT_GENERATION_STORE_KEY, 42u64.to_le_bytes().to_vec())
+            .await
+            .unwrap();
+
+        // Then nothing happens as long as we didn't acquire the cross-process lock
+        let _ = store.cache().await.unwrap();
+        assert!(store.inner.cache.read().await.account.lock().await.is_some());
+
+        // And when we acquire the lock, the caches are cleared
+        let lock = store.create_store_lock("lock".to_owned(), "first".to_owned());
+        let _guard = lock.try_lock_once().await.unwrap().expect("We should get the lock");
+
+        let _ = store.cache().await.unwrap();
+        assert!(store.inner.cache.read().await.account.lock().await.is_none());
+        assert!(store.inner.store.sessions.get(&sender_key).await.is_none());
+
+        // And the account can be loaded from the store again
+        let _ = store.cache().await.unwrap().account().await.unwrap();
+    }
 }
@@ -18,6 +18,7 @@ use ruma::{IdParseError, OwnedDeviceId, OwnedUserId};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
+use super::RateLimitedError;
 use crate::olm::SessionCreationError;
 
 /// A `CryptoStore` specific result type.
@@ -44,6 +45,11 @@ pub enum CryptoStoreError {
         got: (OwnedUserId, OwnedDeviceId),
     },
 
+    /// The store contains an account for the same device, but with different
+    /// identity keys.
+    #[error("the account in the store has different identity keys than the expected account")]
+    MismatchedIdentityKeys,
+
     /// An IO error occurred.
     #[error(transparent)]
     Io(#[from] IoError),
@@ -82,6 +88,31 @@ pub enum CryptoStoreError {
     /// An error due to an invalid generation in a cross-process locking scheme.
     #[error("invalid lock generation: {0}")]
     InvalidLockGeneration(String),
+
+    /// A write was attempted on a read-only store, see
+    /// [`ReadOnlyStore`](super::ReadOnlyStore), or on the store of a frozen
+    /// [`OlmMachine`](crate::OlmMachine), see
+    /// [`OlmMachine::freeze()`](crate::OlmMachine::freeze).
+    #[error("the store is read-only")]
+    ReadOnly,
+
+    /// The store of the [`OlmMachine`](crate::OlmMachine) was wiped using
+    /// [`OlmMachine::shred()`](crate::OlmMachine::shred), it can't be used
+    /// anymore.
+    #[error("the store was shredded")]
+    Shredded,
+
+    /// A request wasn't created because the [`RateLimits`] configured for the
+    /// [`OlmMachine`](crate::OlmMachine) were exceeded.
+    ///
+    /// [`RateLimits`]: super::RateLimits
+    #[error(transparent)]
+    RateLimited(#[from] RateLimitedError),
+
+    /// Application data was requested for an empty namespace, see
+    /// [`Store::app_data()`](super::Store::app_data).
+    #[error("the namespace of application data can't be empty")]
+    EmptyAppDataNamespace,
 }
 
 impl CryptoStoreError {
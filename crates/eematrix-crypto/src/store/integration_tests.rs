@@ -33,14 +33,15 @@
 macro_rules! cryptostore_integration_tests {
     () => {
         mod cryptostore_integration_tests {
-            use std::collections::{BTreeMap, HashMap};
+            use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
             use std::time::Duration;
 
             use assert_matches::assert_matches;
             use matrix_sdk_test::async_test;
             use ruma::{
-                device_id, events::secret::request::SecretName, room_id, serde::Raw,
-                to_device::DeviceIdOrAllDevices, user_id, DeviceId, RoomId, TransactionId, UserId,
+                device_id, event_id, events::secret::request::SecretName, room_id, serde::Raw,
+                to_device::DeviceIdOrAllDevices, uint, user_id, DeviceId,
+                MilliSecondsSinceUnixEpoch, RoomId, TransactionId, UserId,
             };
             use serde_json::value::to_raw_value;
             use serde_json::json;
@@ -48,12 +49,17 @@ macro_rules! cryptostore_integration_tests {
             use $crate::{
                 olm::{
                     Account, Curve25519PublicKey, InboundGroupSession, OlmMessageHash,
-                    PrivateCrossSigningIdentity, SenderData, SenderDataType, Session
+                    PrivateCrossSigningIdentity, SenderData, SenderDataType, Session, ShareInfo,
                 },
                 store::{
                     types::{
-                        BackupDecryptionKey, Changes, DehydratedDeviceKey, DeviceChanges,
-                        IdentityChanges, PendingChanges, StoredRoomKeyBundleData, RoomSettings,
+                        AppDataChange, BackupDecryptionKey, ChangeJournalEntry, Changes,
+                        DehydratedDeviceKey,
+                        DeviceChanges,
+                        IdentityChanges, PendingChanges, StoredRoomKeyBundleData, RoomKeyInfo,
+                        RoomKeySummary, RoomSettings, SessionShareRecord, VerificationAuditEntry,
+                        VerificationAuditEvent, VerificationWithdrawalReason,
+                        VERIFICATION_AUDIT_LOG_CAPACITY,
                     },
                     CryptoStore, GossipRequest,
                 },
@@ -65,7 +71,7 @@ macro_rules! cryptostore_integration_tests {
                         room_key_request::MegolmV1AesSha2Content,
                         room_key_withheld::{
                             CommonWithheldCodeContent, MegolmV1AesSha2WithheldContent,
-                            RoomKeyWithheldContent,
+                            RoomKeyWithheldContent, RoomKeyWithheldEvent,
                         },
                         room_key_bundle::RoomKeyBundleContent,
                         secret_send::SecretSendContent,
@@ -76,7 +82,7 @@ macro_rules! cryptostore_integration_tests {
                     EventEncryptionAlgorithm,
                 },
                 vodozemac::megolm::{GroupSession, SessionConfig}, DeviceData, GossippedSecret, LocalTrust,  SecretInfo,
-                TrackedUser,
+                CollectStrategy, GossipRequestRetry, TrackedUser,
             };
 
             use super::get_store;
@@ -448,6 +454,29 @@ macro_rules! cryptostore_integration_tests {
                 assert_eq!(to_back_up, vec![session]);
             }
 
+            #[async_test]
+            async fn test_quarantine_keeps_decodable_inbound_group_sessions() {
+                let (account, store) =
+                    get_loaded_store("quarantine_keeps_decodable_inbound_group_sessions").await;
+
+                let room_id = &room_id!("!test:localhost");
+                let (_, session) = account.create_group_session_pair_with_defaults(room_id).await;
+
+                store
+                    .save_inbound_group_sessions(vec![session.clone()], None)
+                    .await
+                    .expect("could not save sessions");
+
+                // All our sessions can be decoded, so there's nothing to quarantine.
+                let quarantined =
+                    store.quarantine_undecodable_inbound_group_sessions().await.unwrap();
+                assert!(quarantined.is_empty());
+                assert!(store.get_quarantined_entries().await.unwrap().is_empty());
+                assert_eq!(store.restore_quarantined_entries().await.unwrap(), 0);
+
+                assert_eq!(store.get_inbound_group_sessions().await.unwrap(), vec![session]);
+            }
+
             #[async_test]
             async fn test_mark_inbound_group_sessions_as_backed_up() {
                 // Given a store exists with multiple unbacked-up sessions
@@ -567,6 +596,113 @@ macro_rules! cryptostore_integration_tests {
                 assert_eq!(store.inbound_group_session_counts(None).await.unwrap().total, 1);
             }
 
+            #[async_test]
+            async fn test_fetch_inbound_group_sessions_for_room() {
+                let (account, store) =
+                    get_loaded_store("fetch_inbound_group_sessions_for_room").await;
+                let room_id = room_id!("!test:localhost");
+                let now = u64::from(ruma::SecondsSinceUnixEpoch::now().get());
+
+                // Given room keys received an hour ago, just now, and one for which we
+                // don't know when it was received, as well as one in another room.
+                let mut sessions = Vec::new();
+                for received_at in [Some(now - 60 * 60), Some(now), None] {
+                    let (_, session) =
+                        account.create_group_session_pair_with_defaults(room_id).await;
+                    let mut pickle = session.pickle().await;
+                    pickle.received_at = received_at
+                        .map(|ts| ruma::SecondsSinceUnixEpoch(ts.try_into().unwrap()));
+                    sessions.push(InboundGroupSession::from_pickle(pickle).unwrap());
+                }
+                let (_, other_room_session) = account
+                    .create_group_session_pair_with_defaults(room_id!("!other:localhost"))
+                    .await;
+
+                let changes = Changes {
+                    inbound_group_sessions: [sessions.clone(), vec![other_room_session]].concat(),
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.expect("Can't save group sessions");
+
+                // Then we get all the sessions of the room if no time is given...
+                let mut fetched: Vec<_> = store
+                    .get_inbound_group_sessions_for_room(room_id, None)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|s| s.session_id().to_owned())
+                    .collect();
+                fetched.sort();
+                let mut expected: Vec<_> =
+                    sessions.iter().map(|s| s.session_id().to_owned()).collect();
+                expected.sort();
+                assert_eq!(fetched, expected);
+
+                // ... and only the recent one otherwise.
+                let since = ruma::SecondsSinceUnixEpoch((now - 60).try_into().unwrap());
+                let fetched =
+                    store.get_inbound_group_sessions_for_room(room_id, Some(since)).await.unwrap();
+                assert_eq!(fetched.len(), 1);
+                assert_eq!(fetched[0].session_id(), sessions[1].session_id());
+
+                assert!(store
+                    .get_inbound_group_sessions_for_room(room_id!("!unknown:localhost"), None)
+                    .await
+                    .unwrap()
+                    .is_empty());
+            }
+
+            #[async_test]
+            async fn test_room_key_summaries() {
+                let (account, store) = get_loaded_store("room_key_summaries").await;
+                let room_id = room_id!("!test:localhost");
+                let other_room_id = room_id!("!other:localhost");
+                let now = u64::from(ruma::SecondsSinceUnixEpoch::now().get());
+                let timestamp = |ts: u64| ruma::SecondsSinceUnixEpoch(ts.try_into().unwrap());
+
+                assert!(store.get_room_key_summaries().await.unwrap().is_empty());
+
+                // Given room keys received an hour ago, just now, and one for which we
+                // don't know when it was received, as well as one in another room for
+                // which we don't know it either.
+                let mut sessions = Vec::new();
+                for (room_id, received_at) in [
+                    (room_id, Some(now - 60 * 60)),
+                    (room_id, Some(now)),
+                    (room_id, None),
+                    (other_room_id, None),
+                ] {
+                    let (_, session) =
+                        account.create_group_session_pair_with_defaults(room_id).await;
+                    let mut pickle = session.pickle().await;
+                    pickle.received_at = received_at.map(timestamp);
+                    sessions.push(InboundGroupSession::from_pickle(pickle).unwrap());
+                }
+
+                let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+                store.save_changes(changes).await.expect("Can't save group sessions");
+
+                // Then every room is summarized, sorted by room ID.
+                let summaries = store.get_room_key_summaries().await.unwrap();
+                assert_eq!(
+                    summaries,
+                    vec![
+                        RoomKeySummary {
+                            room_id: other_room_id.to_owned(),
+                            session_count: 1,
+                            earliest_received_at: None,
+                            latest_received_at: None,
+                        },
+                        RoomKeySummary {
+                            room_id: room_id.to_owned(),
+                            session_count: 3,
+                            earliest_received_at: Some(timestamp(now - 60 * 60)),
+                            latest_received_at: Some(timestamp(now)),
+                        },
+                    ]
+                );
+            }
+
             #[async_test]
             async fn test_fetch_inbound_group_sessions_for_device() {
                 // Given a store exists, containing inbound group sessions from different devices
@@ -668,6 +804,50 @@ macro_rules! cryptostore_integration_tests {
                 );
             }
 
+            #[async_test]
+            async fn test_fetch_inbound_group_sessions_in_batches() {
+                // Given a store containing a few inbound group sessions
+                let (account, store) =
+                    get_loaded_store("fetch_inbound_group_sessions_in_batches").await;
+
+                let dev = Curve25519PublicKey::from_base64(
+                    "wjLpTLRqbqBzLs63aYaEv2Boi6cFEbbM/sSRQ2oAKk4"
+                ).unwrap();
+
+                let mut sessions = Vec::new();
+                for _ in 0..5 {
+                    sessions.push(create_session(&account, &dev, SenderDataType::UnknownDevice).await);
+                }
+
+                let changes = Changes {
+                    inbound_group_sessions: sessions.clone(),
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.expect("Can't save group sessions");
+
+                // When we fetch them in batches of two, until we get an empty batch
+                let mut fetched = Vec::new();
+                let mut after = None;
+                loop {
+                    let mut batch = store
+                        .get_inbound_group_sessions_batch(after, 2)
+                        .await
+                        .expect("Failed to get a batch of sessions");
+
+                    let Some(last_session) = batch.last() else {
+                        break;
+                    };
+
+                    assert!(batch.len() <= 2, "The batch should respect the limit");
+                    after = Some((last_session.room_id().to_owned(), last_session.session_id().to_owned()));
+
+                    fetched.append(&mut batch);
+                }
+
+                // Then every session was returned exactly once
+                assert_session_lists_eq(fetched, sessions, "batched sessions");
+            }
+
             /// Assert that two lists of sessions are the same, modulo ordering.
             ///
             /// There is no requirement for `get_inbound_group_sessions_for_device_batch` to
@@ -726,6 +906,36 @@ macro_rules! cryptostore_integration_tests {
                 check_loaded_users(loaded);
             }
 
+            #[async_test]
+            async fn test_tracked_users_paged() {
+                let (_account, store) = get_loaded_store("tracked_users_paged").await;
+
+                let users: Vec<_> = (0..5)
+                    .map(|i| UserId::parse(format!("@user{i}:example.org")).unwrap())
+                    .collect();
+                let tracked: Vec<_> = users.iter().map(|u| (&**u, true)).collect();
+                store.save_tracked_users(&tracked).await.unwrap();
+
+                // Walk through the tracked users two at a time.
+                let mut loaded = HashSet::new();
+                let mut after = None;
+
+                loop {
+                    let page = store.load_tracked_users_paged(after.as_deref(), 2).await.unwrap();
+                    assert!(page.len() <= 2, "A page should respect the limit");
+
+                    let Some(last) = page.last() else { break };
+                    after = Some(last.user_id.clone());
+
+                    for user in page {
+                        assert!(user.dirty);
+                        assert!(loaded.insert(user.user_id), "Users shouldn't be repeated");
+                    }
+                }
+
+                assert_eq!(loaded, users.into_iter().collect());
+            }
+
             #[async_test]
             async fn test_device_saving() {
                 let dir = "device_saving";
@@ -959,6 +1169,12 @@ macro_rules! cryptostore_integration_tests {
                     request_id: id.clone(),
                     info: info.clone(),
                     sent_out: false,
+                    recipient_devices: None,
+                    retry: GossipRequestRetry {
+                        attempts: 1,
+                        last_error: Some("connection refused".to_owned()),
+                        next_retry_at: Some(MilliSecondsSinceUnixEpoch(uint!(1_010_000))),
+                    },
                 };
 
                 assert!(store.get_outgoing_secret_requests(&id).await.unwrap().is_none());
@@ -981,6 +1197,8 @@ macro_rules! cryptostore_integration_tests {
                     request_id: id.clone(),
                     info: info.clone(),
                     sent_out: true,
+                    recipient_devices: Some(BTreeSet::from([account.device_id().to_owned()])),
+                    retry: Default::default(),
                 };
 
                 let mut changes = Changes::default();
@@ -988,8 +1206,10 @@ macro_rules! cryptostore_integration_tests {
                 store.save_changes(changes).await.unwrap();
 
                 assert!(store.get_unsent_secret_requests().await.unwrap().is_empty());
-                let stored_request = store.get_outgoing_secret_requests(&id).await.unwrap();
-                assert_eq!(Some(request), stored_request);
+                let stored_request =
+                    store.get_outgoing_secret_requests(&id).await.unwrap().unwrap();
+                assert_eq!(request.recipient_devices, stored_request.recipient_devices);
+                assert_eq!(request, stored_request);
 
                 store.delete_outgoing_secret_requests(&id).await.unwrap();
 
@@ -1020,6 +1240,8 @@ macro_rules! cryptostore_integration_tests {
                     request_id: id.clone(),
                     info: info.clone(),
                     sent_out: true,
+                    recipient_devices: None,
+                    retry: Default::default(),
                 };
 
                 let mut event = DecryptedSecretSendEvent {
@@ -1032,7 +1254,7 @@ macro_rules! cryptostore_integration_tests {
                         ed25519: account.identity_keys().ed25519,
                     },
                     sender_device_keys: None,
-                    content: SecretSendContent::new(id.to_owned(), secret.to_owned()),
+                    content: SecretSendContent::new(id.to_owned(), secret.into()),
                 };
 
                 let value = GossippedSecret {
@@ -1052,7 +1274,7 @@ macro_rules! cryptostore_integration_tests {
 
                 let restored = store.get_secrets_from_inbox(&SecretName::RecoveryKey).await.unwrap();
                 let first_secret = restored.first().expect("We should have restored a secret now");
-                assert_eq!(first_secret.event.content.secret, secret);
+                assert_eq!(first_secret.event.content.secret.expose_secret(), secret);
                 assert_eq!(restored.len(), 1, "We should only have one secret stored for now");
 
                 event.content.request_id = TransactionId::new();
@@ -1156,6 +1378,246 @@ macro_rules! cryptostore_integration_tests {
                 assert!(is_withheld.is_none());
             }
 
+            #[async_test]
+            async fn test_megolm_message_index_recording() {
+                let (_, store) = get_loaded_store("megolm_message_index_recording").await;
+
+                let room_id = room_id!("!test:localhost");
+                let event_id = event_id!("$first");
+                let other_event_id = event_id!("$second");
+
+                let recorded = store
+                    .record_megolm_message_index(room_id, "session", 0, event_id)
+                    .await
+                    .unwrap();
+                assert!(recorded.is_none());
+
+                // Recording the same event again returns it.
+                let recorded = store
+                    .record_megolm_message_index(room_id, "session", 0, event_id)
+                    .await
+                    .unwrap();
+                assert_eq!(recorded.as_deref(), Some(event_id));
+
+                // A different event using the same index doesn't replace the first one.
+                let recorded = store
+                    .record_megolm_message_index(room_id, "session", 0, other_event_id)
+                    .await
+                    .unwrap();
+                assert_eq!(recorded.as_deref(), Some(event_id));
+
+                // Other indices, sessions and rooms are tracked separately.
+                let recorded = store
+                    .record_megolm_message_index(room_id, "session", 1, other_event_id)
+                    .await
+                    .unwrap();
+                assert!(recorded.is_none());
+
+                let recorded = store
+                    .record_megolm_message_index(room_id, "other_session", 0, other_event_id)
+                    .await
+                    .unwrap();
+                assert!(recorded.is_none());
+
+                let recorded = store
+                    .record_megolm_message_index(
+                        room_id!("!other:localhost"),
+                        "session",
+                        0,
+                        other_event_id,
+                    )
+                    .await
+                    .unwrap();
+                assert!(recorded.is_none());
+
+                // Deleting the session forgets its recorded indices, but not the ones of
+                // other sessions.
+                store
+                    .delete_inbound_group_sessions(room_id, &["session".to_owned()])
+                    .await
+                    .unwrap();
+
+                let recorded = store
+                    .record_megolm_message_index(room_id, "session", 0, other_event_id)
+                    .await
+                    .unwrap();
+                assert!(recorded.is_none());
+
+                let recorded = store
+                    .record_megolm_message_index(room_id, "other_session", 0, event_id)
+                    .await
+                    .unwrap();
+                assert_eq!(recorded.as_deref(), Some(other_event_id));
+            }
+
+            #[async_test]
+            async fn test_session_share_history() {
+                let (_, store) = get_loaded_store("session_share_history").await;
+
+                let room_id = room_id!("!test:localhost");
+                let record = |session_id: &str, device_id: &DeviceId, shared_at| {
+                    SessionShareRecord {
+                        room_id: room_id.to_owned(),
+                        session_id: session_id.to_owned(),
+                        user_id: user_id!("@bob:localhost").to_owned(),
+                        device_id: device_id.to_owned(),
+                        share_info: ShareInfo::new_withheld(WithheldCode::Unverified),
+                        shared_at: MilliSecondsSinceUnixEpoch(shared_at),
+                    }
+                };
+
+                let history = store.get_session_share_history(room_id, "session").await.unwrap();
+                assert!(history.is_empty());
+
+                // Records are appended, the ones which were saved before are kept.
+                let changes = Changes {
+                    session_share_records: vec![
+                        record("session", device_id!("FIRST"), uint!(1)),
+                        record("other_session", device_id!("FIRST"), uint!(1)),
+                    ],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                let changes = Changes {
+                    session_share_records: vec![record("session", device_id!("SECOND"), uint!(2))],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                let history = store.get_session_share_history(room_id, "session").await.unwrap();
+                let devices: Vec<_> =
+                    history.iter().map(|record| record.device_id.as_str()).collect();
+                assert_eq!(devices, ["FIRST", "SECOND"]);
+
+                // The history goes together with the session, the one of other sessions is
+                // kept.
+                store
+                    .delete_inbound_group_sessions(room_id, &["session".to_owned()])
+                    .await
+                    .unwrap();
+
+                let history = store.get_session_share_history(room_id, "session").await.unwrap();
+                assert!(history.is_empty());
+                let history =
+                    store.get_session_share_history(room_id, "other_session").await.unwrap();
+                assert_eq!(history.len(), 1);
+            }
+
+            #[async_test]
+            async fn test_verification_audit_log() {
+                let (_, store) = get_loaded_store("verification_audit_log").await;
+
+                let user_id = user_id!("@bob:localhost");
+                let entry = |timestamp: u32| VerificationAuditEntry {
+                    user_id: user_id.to_owned(),
+                    master_key: None,
+                    event: VerificationAuditEvent::Verified,
+                    timestamp: MilliSecondsSinceUnixEpoch(timestamp.into()),
+                };
+
+                assert!(store.get_verification_audit_log(user_id).await.unwrap().is_empty());
+
+                // Entries are appended, the ones which were saved before are kept.
+                let changes =
+                    Changes { verification_audit_log: vec![entry(0)], ..Default::default() };
+                store.save_changes(changes).await.unwrap();
+                let changes = Changes {
+                    verification_audit_log: vec![VerificationAuditEntry {
+                        event: VerificationAuditEvent::VerificationWithdrawn(
+                            VerificationWithdrawalReason::Manual,
+                        ),
+                        ..entry(1)
+                    }],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                let audit_log = store.get_verification_audit_log(user_id).await.unwrap();
+                assert_eq!(audit_log.len(), 2);
+                assert_eq!(audit_log[0].event, VerificationAuditEvent::Verified);
+                assert_eq!(
+                    audit_log[1].event,
+                    VerificationAuditEvent::VerificationWithdrawn(
+                        VerificationWithdrawalReason::Manual,
+                    ),
+                );
+
+                // Once the log is full, the oldest entries are dropped.
+                let changes = Changes {
+                    verification_audit_log: (2..)
+                        .take(VERIFICATION_AUDIT_LOG_CAPACITY)
+                        .map(entry)
+                        .collect(),
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                let audit_log = store.get_verification_audit_log(user_id).await.unwrap();
+                assert_eq!(audit_log.len(), VERIFICATION_AUDIT_LOG_CAPACITY);
+                assert_eq!(audit_log[0].timestamp, MilliSecondsSinceUnixEpoch(uint!(2)));
+
+                // The log of other users is separate.
+                let other_user_id = user_id!("@carol:localhost");
+                assert!(store.get_verification_audit_log(other_user_id).await.unwrap().is_empty());
+            }
+
+            #[async_test]
+            async fn test_app_data() {
+                let (_, store) = get_loaded_store("app_data").await;
+
+                let change = |namespace: &str, key: &str, value: Option<&[u8]>| AppDataChange {
+                    namespace: namespace.to_owned(),
+                    key: key.to_owned(),
+                    value: value.map(ToOwned::to_owned),
+                };
+
+                assert!(store.get_app_data("settings", "theme").await.unwrap().is_none());
+                assert!(store.get_app_data_keys("settings").await.unwrap().is_empty());
+
+                let changes = Changes {
+                    app_data: vec![
+                        change("settings", "theme", Some(b"dark")),
+                        change("settings", "language", Some(b"en")),
+                        change("drafts", "theme", Some(b"a draft")),
+                    ],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                // The namespaces don't collide with each other.
+                assert_eq!(
+                    store.get_app_data("settings", "theme").await.unwrap().as_deref(),
+                    Some(b"dark".as_slice())
+                );
+                assert_eq!(
+                    store.get_app_data("drafts", "theme").await.unwrap().as_deref(),
+                    Some(b"a draft".as_slice())
+                );
+
+                let mut keys = store.get_app_data_keys("settings").await.unwrap();
+                keys.sort();
+                assert_eq!(keys, ["language", "theme"]);
+
+                // Entries are replaced and removed.
+                let changes = Changes {
+                    app_data: vec![
+                        change("settings", "theme", Some(b"light")),
+                        change("settings", "language", None),
+                    ],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                assert_eq!(
+                    store.get_app_data("settings", "theme").await.unwrap().as_deref(),
+                    Some(b"light".as_slice())
+                );
+                assert!(store.get_app_data("settings", "language").await.unwrap().is_none());
+                assert_eq!(store.get_app_data_keys("settings").await.unwrap(), ["theme"]);
+                assert_eq!(store.get_app_data_keys("drafts").await.unwrap(), ["theme"]);
+            }
+
             #[async_test]
             async fn test_room_settings_saving() {
                 let (_, store) = get_loaded_store("room_settings_saving").await;
@@ -1166,6 +1628,7 @@ macro_rules! cryptostore_integration_tests {
                     only_allow_trusted_devices: true,
                     session_rotation_period: Some(Duration::from_secs(10)),
                     session_rotation_period_messages: Some(123),
+                    sharing_strategy: Some(CollectStrategy::IdentityBasedStrategy),
                 };
 
                 let room_2 = room_id!("!test_2:localhost");
@@ -1221,6 +1684,24 @@ macro_rules! cryptostore_integration_tests {
                 assert!(restored.backup_version.is_some(), "The backup version should now be Some as well");
             }
 
+            #[async_test]
+            async fn test_delete_backup_decryption_key() {
+                let (_account, store) = get_loaded_store("delete_backup_decryption_key").await;
+
+                let changes = Changes {
+                    backup_decryption_key: Some(BackupDecryptionKey::new().unwrap()),
+                    backup_version: Some("some_version".to_owned()),
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                store.delete_backup_decryption_key().await.unwrap();
+
+                let restored = store.load_backup_keys().await.unwrap();
+                assert!(restored.decryption_key.is_none(), "The backup decryption key should be deleted");
+                assert_eq!(restored.backup_version.as_deref(), Some("some_version"));
+            }
+
             #[async_test]
             async fn test_dehydration_pickle_key_saving() {
                 let (_account, store) = get_loaded_store("dehydration_pickle_key_saving").await;
@@ -1280,6 +1761,147 @@ macro_rules! cryptostore_integration_tests {
                 assert_eq!(None, loaded_2);
             }
 
+            #[async_test]
+            async fn test_compaction() {
+                let (_, store) = get_loaded_store("compaction").await;
+
+                for i in 0..100 {
+                    store.set_custom_value(&format!("key_{i}"), vec![0; 1024]).await.unwrap();
+                }
+                for i in 1..100 {
+                    store.remove_custom_value(&format!("key_{i}")).await.unwrap();
+                }
+
+                let report = store.compact().await.unwrap();
+
+                // Stores which can measure their size must have reclaimed the removed values.
+                if let (Some(before), Some(after)) = (report.size_before, report.size_after) {
+                    assert!(after < before, "The store didn't shrink: {before} -> {after}");
+                    assert_eq!(report.reclaimed(), Some(before - after));
+                } else {
+                    assert!(report.reclaimed().is_none());
+                }
+
+                // Compacting the store doesn't lose any data.
+                assert_eq!(store.get_custom_value("key_0").await.unwrap(), Some(vec![0; 1024]));
+                assert!(store.get_custom_value("key_1").await.unwrap().is_none());
+                assert!(store.load_account().await.unwrap().is_some());
+            }
+
+            #[async_test]
+            async fn test_change_journal() {
+                let (account, store) = get_loaded_store("change_journal").await;
+
+                assert!(store.load_change_journal(0).await.unwrap().is_empty());
+
+                let room_id = room_id!("!test:localhost");
+                let entry = |session_id: &str| ChangeJournalEntry::InboundGroupSession {
+                    room_id: room_id.to_owned(),
+                    session_id: session_id.to_owned(),
+                };
+                let identity = ChangeJournalEntry::Identity { user_id: account.user_id().to_owned() };
+
+                let changes = Changes {
+                    change_journal: vec![entry("first"), entry("second")],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                let changes = Changes { change_journal: vec![identity.clone()], ..Default::default() };
+                store.save_changes(changes).await.unwrap();
+
+                let records = store.load_change_journal(0).await.unwrap();
+                assert_eq!(records.len(), 3);
+                assert_eq!(records[0].entry, entry("first"));
+                assert_eq!(records[1].entry, entry("second"));
+                assert_eq!(records[2].entry, identity);
+                assert!(records[0].sequence_number < records[1].sequence_number);
+                assert!(records[1].sequence_number < records[2].sequence_number);
+
+                // Only the records after the given sequence number are returned.
+                let since = records[1].sequence_number;
+                let delta = store.load_change_journal(since).await.unwrap();
+                assert_eq!(delta, records[2..]);
+
+                // Truncating removes the records up to and including the given
+                // sequence number, but the journal keeps counting from the last
+                // record.
+                store.truncate_change_journal(since).await.unwrap();
+                assert_eq!(store.load_change_journal(0).await.unwrap(), records[2..]);
+
+                let changes = Changes { change_journal: vec![entry("third")], ..Default::default() };
+                store.save_changes(changes).await.unwrap();
+
+                let delta = store.load_change_journal(records[2].sequence_number).await.unwrap();
+                assert_eq!(delta.len(), 1);
+                assert_eq!(delta[0].entry, entry("third"));
+                assert!(delta[0].sequence_number > records[2].sequence_number);
+
+                store.truncate_change_journal(delta[0].sequence_number).await.unwrap();
+                assert!(store.load_change_journal(0).await.unwrap().is_empty());
+            }
+
+            #[async_test]
+            async fn test_shred() {
+                let store = get_store("shred", None, true).await;
+                let (account, session) = get_account_and_session().await;
+                store
+                    .save_pending_changes(PendingChanges { account: Some(account.deep_clone()) })
+                    .await
+                    .expect("Can't save account");
+
+                let room_id = room_id!("!test:localhost");
+                let (outbound, inbound) =
+                    account.create_group_session_pair_with_defaults(room_id).await;
+
+                let changes = Changes {
+                    sessions: vec![session.clone()],
+                    inbound_group_sessions: vec![inbound],
+                    outbound_group_sessions: vec![outbound],
+                    devices: DeviceChanges {
+                        new: vec![DeviceData::from_account(&account)],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                store.set_custom_value("custom", b"value".to_vec()).await.unwrap();
+                let event_id = event_id!("$event");
+                store.record_megolm_message_index(room_id, "session", 0, event_id).await.unwrap();
+
+                let report = store.shred().await.unwrap();
+                assert!(report.account_deleted);
+                assert_eq!(report.sessions_deleted, 1);
+                assert_eq!(report.inbound_group_sessions_deleted, 1);
+
+                // The secrets are gone.
+                assert!(store.load_account().await.unwrap().is_none());
+                let sender_key = session.sender_key.to_base64();
+                let sessions = store.get_sessions(&sender_key).await.unwrap();
+                assert!(sessions.unwrap_or_default().is_empty());
+                assert!(store.get_inbound_group_sessions().await.unwrap().is_empty());
+                assert!(store.get_outbound_group_session(room_id).await.unwrap().is_none());
+                assert!(store.get_custom_value("custom").await.unwrap().is_none());
+
+                // The message indices of the deleted sessions are forgotten.
+                let recorded = store
+                    .record_megolm_message_index(room_id, "session", 0, event_id)
+                    .await
+                    .unwrap();
+                assert!(recorded.is_none());
+
+                // But the public data is kept.
+                let device = store.get_device(account.user_id(), account.device_id()).await;
+                assert!(device.unwrap().is_some());
+
+                // There's nothing left to delete the second time.
+                let report = store.shred().await.unwrap();
+                assert!(!report.account_deleted);
+                assert_eq!(report.sessions_deleted, 0);
+                assert_eq!(report.inbound_group_sessions_deleted, 0);
+            }
+
             #[async_test]
             async fn test_received_room_key_bundle() {
                 let store = get_store("received_room_key_bundle", None, true).await;
@@ -1308,7 +1930,9 @@ macro_rules! cryptostore_integration_tests {
                         bundle_data: RoomKeyBundleContent {
                             room_id: room_id!("!room:example.org").to_owned(),
                             file,
+                            chunk: None,
                         },
+                        received_chunks: Vec::new(),
                     }
                 }
 
@@ -1328,6 +1952,215 @@ macro_rules! cryptostore_integration_tests {
                     test_room, user_id!("@alice:example.com")
                 ).await.unwrap().expect("Did not get any bundle data");
                 assert_eq!(bundle.bundle_data.file.url.to_string(), "alice2");
+                assert!(bundle.received_chunks.is_empty());
+
+                // The chunks received before the last one are saved with it
+                let mut chunked = make_bundle_data(user_id!("@alice:example.com"), "alice3");
+                chunked.received_chunks.push(
+                    make_bundle_data(user_id!("@alice:example.com"), "alice4").bundle_data,
+                );
+                let changes =
+                    Changes { received_room_key_bundles: vec![chunked], ..Default::default() };
+                store.save_changes(changes).await.unwrap();
+
+                let bundle = store.get_received_room_key_bundle_data(
+                    test_room, user_id!("@alice:example.com")
+                ).await.unwrap().expect("Did not get any bundle data");
+                assert_eq!(bundle.bundle_data.file.url.to_string(), "alice3");
+                assert_eq!(bundle.received_chunks.len(), 1);
+                assert_eq!(bundle.received_chunks[0].file.url.to_string(), "alice4");
+            }
+
+            #[async_test]
+            async fn test_delete_room_crypto_state() {
+                let (account, store) = get_loaded_store("delete_room_crypto_state").await;
+                let room_id = room_id!("!deleted:localhost");
+                let other_room_id = room_id!("!kept:localhost");
+
+                fn withheld_event(
+                    sender: &UserId,
+                    room_id: &RoomId,
+                    session_id: &str,
+                ) -> RoomKeyWithheldEvent {
+                    let content = RoomKeyWithheldContent::MegolmV1AesSha2(
+                        MegolmV1AesSha2WithheldContent::Unverified(
+                            CommonWithheldCodeContent::new(
+                                room_id.to_owned(),
+                                session_id.into(),
+                                Curve25519PublicKey::from_base64(
+                                    "9n7mdWKOjr9c4NTlG6zV8dbFtNK79q9vZADoh7nMUwA",
+                                )
+                                .unwrap(),
+                                "DEVICEID".into(),
+                            )
+                            .into(),
+                        ),
+                    );
+                    ToDeviceEvent::new(sender.to_owned(), content)
+                }
+
+                fn bundle_data(sender_user: &UserId, room_id: &RoomId) -> StoredRoomKeyBundleData {
+                    let jwk = ruma::events::room::JsonWebKeyInit {
+                        kty: "oct".to_owned(),
+                        key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+                        alg: "A256CTR".to_owned(),
+                        k: ruma::serde::Base64::new(vec![0u8; 0]),
+                        ext: true,
+                    }.into();
+
+                    let file = ruma::events::room::EncryptedFileInit {
+                        url: ruma::OwnedMxcUri::from("mxc://localhost/bundle"),
+                        key: jwk,
+                        iv: ruma::serde::Base64::new(vec![0u8; 0]),
+                        hashes: Default::default(),
+                        v: "".to_owned(),
+                    }.into();
+
+                    StoredRoomKeyBundleData {
+                        sender_user: sender_user.to_owned(),
+                        sender_data: SenderData::unknown(),
+                        bundle_data: RoomKeyBundleContent {
+                            room_id: room_id.to_owned(),
+                            file,
+                            chunk: None,
+                        },
+                        received_chunks: Vec::new(),
+                    }
+                }
+
+                let (outbound, inbound) =
+                    account.create_group_session_pair_with_defaults(room_id).await;
+                let (other_outbound, other_inbound) =
+                    account.create_group_session_pair_with_defaults(other_room_id).await;
+                let share_record = |room_id: &RoomId, session_id: &str| SessionShareRecord {
+                    room_id: room_id.to_owned(),
+                    session_id: session_id.to_owned(),
+                    user_id: user_id!("@bob:localhost").to_owned(),
+                    device_id: device_id!("BOBDEVICE").to_owned(),
+                    share_info: ShareInfo::new_withheld(WithheldCode::Unverified),
+                    shared_at: MilliSecondsSinceUnixEpoch(uint!(1)),
+                };
+
+                let mut withheld_session_info: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+                for (room_id, session_id) in
+                    [(room_id, "session_1"), (room_id, "session_2"), (other_room_id, "session_3")]
+                {
+                    let event = withheld_event(account.user_id(), room_id, session_id);
+                    withheld_session_info
+                        .entry(room_id.to_owned())
+                        .or_default()
+                        .insert(session_id.to_owned(), event);
+                }
+
+                let changes = Changes {
+                    inbound_group_sessions: vec![inbound.clone(), other_inbound.clone()],
+                    outbound_group_sessions: vec![outbound, other_outbound],
+                    withheld_session_info,
+                    room_settings: HashMap::from([
+                        (room_id.to_owned(), RoomSettings::default()),
+                        (other_room_id.to_owned(), RoomSettings::default()),
+                    ]),
+                    received_room_key_bundles: vec![
+                        bundle_data(user_id!("@bob:localhost"), room_id),
+                        bundle_data(user_id!("@alice:localhost"), room_id),
+                        bundle_data(user_id!("@alice:localhost"), other_room_id),
+                    ],
+                    session_share_records: vec![
+                        share_record(room_id, inbound.session_id()),
+                        share_record(other_room_id, other_inbound.session_id()),
+                    ],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                for (room_id, session_id) in [
+                    (room_id, inbound.session_id()),
+                    (other_room_id, other_inbound.session_id()),
+                ] {
+                    store
+                        .record_megolm_message_index(room_id, session_id, 0, event_id!("$event"))
+                        .await
+                        .unwrap();
+                }
+
+                store.set_custom_value("deleted_room_value", b"value".to_vec()).await.unwrap();
+                store.set_custom_value("kept_room_value", b"value".to_vec()).await.unwrap();
+
+                let deleted = store
+                    .delete_room_crypto_state(room_id, &["deleted_room_value".to_owned()])
+                    .await
+                    .unwrap();
+                assert_eq!(deleted.room_keys, [RoomKeyInfo::from(&inbound)]);
+                assert!(deleted.outbound_group_session_deleted);
+                assert_eq!(deleted.withheld_deleted, 2);
+                assert_eq!(
+                    deleted.room_key_bundle_senders,
+                    [user_id!("@alice:localhost").to_owned(), user_id!("@bob:localhost").to_owned()]
+                );
+
+                assert!(store
+                    .get_inbound_group_session(room_id, inbound.session_id())
+                    .await
+                    .unwrap()
+                    .is_none());
+                assert!(store.get_outbound_group_session(room_id).await.unwrap().is_none());
+                assert!(store.get_withheld_info(room_id, "session_1").await.unwrap().is_none());
+                assert!(store.get_room_settings(room_id).await.unwrap().is_none());
+                assert!(store
+                    .get_received_room_key_bundle_data(room_id, user_id!("@bob:localhost"))
+                    .await
+                    .unwrap()
+                    .is_none());
+                let history =
+                    store.get_session_share_history(room_id, inbound.session_id()).await.unwrap();
+                assert!(history.is_empty());
+                let recorded = store
+                    .record_megolm_message_index(
+                        room_id,
+                        inbound.session_id(),
+                        0,
+                        event_id!("$other"),
+                    )
+                    .await
+                    .unwrap();
+                assert!(recorded.is_none());
+                assert!(store.get_custom_value("deleted_room_value").await.unwrap().is_none());
+
+                // The other room is left alone.
+                assert!(store
+                    .get_inbound_group_session(other_room_id, other_inbound.session_id())
+                    .await
+                    .unwrap()
+                    .is_some());
+                assert!(store.get_room_settings(other_room_id).await.unwrap().is_some());
+                let history = store
+                    .get_session_share_history(other_room_id, other_inbound.session_id())
+                    .await
+                    .unwrap();
+                assert_eq!(history.len(), 1);
+                let recorded = store
+                    .record_megolm_message_index(
+                        other_room_id,
+                        other_inbound.session_id(),
+                        0,
+                        event_id!("$other"),
+                    )
+                    .await
+                    .unwrap();
+                assert!(recorded.is_some());
+                assert!(store.get_custom_value("kept_room_value").await.unwrap().is_some());
+                assert!(store.get_outbound_group_session(other_room_id).await.unwrap().is_some());
+                let withheld = store.get_withheld_info(other_room_id, "session_3").await.unwrap();
+                assert!(withheld.is_some());
+                assert!(store
+                    .get_received_room_key_bundle_data(other_room_id, user_id!("@alice:localhost"))
+                    .await
+                    .unwrap()
+                    .is_some());
+
+                // There's nothing left to delete the second time.
+                let deleted = store.delete_room_crypto_state(room_id, &[]).await.unwrap();
+                assert_eq!(deleted, Default::default());
             }
 
             fn session_info(session: &InboundGroupSession) -> (&RoomId, &str) {
@@ -1377,6 +2210,7 @@ macro_rules! cryptostore_integration_tests_time {
         mod cryptostore_integration_tests_time {
             use std::time::Duration;
 
+            use matrix_sdk_common::sleep::sleep;
             use matrix_sdk_test::async_test;
             use $crate::store::CryptoStore as _;
 
@@ -1406,26 +2240,26 @@ macro_rules! cryptostore_integration_tests_time {
                 assert!(!acquired5);
 
                 // That's a nice test we got here, go take a little nap.
-                tokio::time::sleep(Duration::from_millis(50)).await;
+                sleep(Duration::from_millis(50)).await;
 
                 // Still too early.
                 let acquired55 = store.try_take_leased_lock(300, "key", "bob").await.unwrap();
                 assert!(!acquired55);
 
                 // Ok you can take another nap then.
-                tokio::time::sleep(Duration::from_millis(250)).await;
+                sleep(Duration::from_millis(250)).await;
 
                 // At some point, we do get the lock.
                 let acquired6 = store.try_take_leased_lock(0, "key", "bob").await.unwrap();
                 assert!(acquired6);
 
-                tokio::time::sleep(Duration::from_millis(1)).await;
+                sleep(Duration::from_millis(1)).await;
 
                 // The other gets it almost immediately too.
                 let acquired7 = store.try_take_leased_lock(0, "key", "alice").await.unwrap();
                 assert!(acquired7);
 
-                tokio::time::sleep(Duration::from_millis(1)).await;
+                sleep(Duration::from_millis(1)).await;
 
                 // But when we take a longer lease...
                 let acquired8 = store.try_take_leased_lock(300, "key", "bob").await.unwrap();
@@ -13,19 +13,20 @@
 // limitations under the License.
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    convert::Infallible,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    mem,
     sync::Arc,
 };
 
 use async_trait::async_trait;
-use matrix_sdk_common::{
-    locks::RwLock as StdRwLock, store_locks::memory_store_helper::try_take_leased_lock,
-};
+use matrix_sdk_common::locks::RwLock as StdRwLock;
+use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
-    events::secret::request::SecretName, time::Instant, DeviceId, OwnedDeviceId, OwnedRoomId,
-    OwnedTransactionId, OwnedUserId, RoomId, TransactionId, UserId,
+    events::secret::request::SecretName, DeviceId, EventId, OwnedDeviceId, OwnedEventId,
+    OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId, SecondsSinceUnixEpoch, TransactionId,
+    UserId,
 };
+use serde::{Deserialize, Serialize};
 use tokio::sync::{Mutex, RwLock};
 use tracing::warn;
 use vodozemac::Curve25519PublicKey;
@@ -33,19 +34,24 @@ use vodozemac::Curve25519PublicKey;
 use super::{
     caches::DeviceStore,
     types::{
-        BackupKeys, Changes, DehydratedDeviceKey, PendingChanges, RoomKeyCounts, RoomSettings,
-        StoredRoomKeyBundleData, TrackedUser,
+        BackupDecryptionKey, BackupKeys, ChangeJournalEntry, ChangeJournalRecord, Changes,
+        CompactionReport, DehydratedDeviceKey, DeletedRoomCryptoState, PendingChanges,
+        QuarantinedEntry, QuarantinedEntryKind, RoomKeyCounts, RoomKeyInfo, RoomKeySummary,
+        RoomSettings, SessionShareRecord, ShredReport, StoredRoomKeyBundleData, TrackedUser,
+        VerificationAuditEntry, VERIFICATION_AUDIT_LOG_CAPACITY,
     },
-    Account, CryptoStore, InboundGroupSession, Session,
+    Account, CryptoStore, CryptoStoreError, InboundGroupSession, Session,
 };
 use crate::{
+    clock::{Clock, SystemClock},
     gossiping::{GossipRequest, GossippedSecret, SecretInfo},
     identities::{DeviceData, UserIdentityData},
     olm::{
-        OutboundGroupSession, PickledAccount, PickledInboundGroupSession, PickledSession,
+        OutboundGroupSession, PickledAccount, PickledCrossSigningIdentity,
+        PickledInboundGroupSession, PickledOutboundGroupSession, PickledSession,
         PrivateCrossSigningIdentity, SenderDataType, StaticAccountData,
     },
-    types::events::room_key_withheld::RoomKeyWithheldEvent,
+    types::{events::room_key_withheld::RoomKeyWithheldEvent, SecretString},
 };
 
 fn encode_key_info(info: &SecretInfo) -> String {
@@ -60,7 +66,7 @@ fn encode_key_info(info: &SecretInfo) -> String {
 type SessionId = String;
 
 /// The "version" of a backup - newtype wrapper around a String.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct BackupVersion(String);
 
 impl BackupVersion {
@@ -73,15 +79,32 @@ impl BackupVersion {
     }
 }
 
+/// The records of the change journal which haven't been truncated yet.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+struct ChangeJournal {
+    last_sequence_number: u64,
+    records: Vec<ChangeJournalRecord>,
+}
+
+impl ChangeJournal {
+    fn append(&mut self, entries: Vec<ChangeJournalEntry>) {
+        for entry in entries {
+            self.last_sequence_number += 1;
+            self.records
+                .push(ChangeJournalRecord { sequence_number: self.last_sequence_number, entry });
+        }
+    }
+}
+
 /// An in-memory only store that will forget all the E2EE key once it's dropped.
 #[derive(Default, Debug)]
 pub struct MemoryStore {
     static_account: Arc<StdRwLock<Option<StaticAccountData>>>,
 
-    account: StdRwLock<Option<String>>,
+    account: StdRwLock<Option<SecretString>>,
     // Map of sender_key to map of session_id to serialized pickle
-    sessions: StdRwLock<BTreeMap<String, BTreeMap<String, String>>>,
-    inbound_group_sessions: StdRwLock<BTreeMap<OwnedRoomId, HashMap<String, String>>>,
+    sessions: StdRwLock<BTreeMap<String, BTreeMap<String, SecretString>>>,
+    inbound_group_sessions: StdRwLock<BTreeMap<OwnedRoomId, HashMap<String, SecretString>>>,
 
     /// Map room id -> session id -> backup order number
     /// The latest backup in which this session is stored. Equivalent to
@@ -98,8 +121,18 @@ pub struct MemoryStore {
     outgoing_key_requests: StdRwLock<HashMap<OwnedTransactionId, GossipRequest>>,
     key_requests_by_info: StdRwLock<HashMap<String, OwnedTransactionId>>,
     direct_withheld_info: StdRwLock<HashMap<OwnedRoomId, HashMap<String, RoomKeyWithheldEvent>>>,
+    /// Map room id -> session id -> message index -> event id
+    megolm_message_indices: StdRwLock<MegolmMessageIndices>,
+    /// Map room id -> session id -> share records
+    session_share_history: StdRwLock<SessionShareHistory>,
+    /// Map user id -> verification audit entries, oldest first
+    verification_audit_log: StdRwLock<HashMap<OwnedUserId, VecDeque<VerificationAuditEntry>>>,
     custom_values: StdRwLock<HashMap<String, Vec<u8>>>,
-    leases: StdRwLock<HashMap<String, (String, Instant)>>,
+    /// Map namespace -> key -> value
+    app_data: StdRwLock<AppDataEntries>,
+    /// Map lock key -> (holder, expiration time in milliseconds since the unix
+    /// epoch)
+    leases: StdRwLock<HashMap<String, (String, u64)>>,
     secret_inbox: StdRwLock<HashMap<String, Vec<GossippedSecret>>>,
     backup_keys: RwLock<BackupKeys>,
     dehydrated_device_pickle_key: RwLock<Option<DehydratedDeviceKey>>,
@@ -107,6 +140,9 @@ pub struct MemoryStore {
     room_settings: StdRwLock<HashMap<OwnedRoomId, RoomSettings>>,
     room_key_bundles:
         StdRwLock<HashMap<OwnedRoomId, HashMap<OwnedUserId, StoredRoomKeyBundleData>>>,
+    change_journal: StdRwLock<ChangeJournal>,
+    quarantined_entries: StdRwLock<Vec<MemoryQuarantinedEntry>>,
+    clock: StdRwLock<Option<Arc<dyn Clock>>>,
 
     save_changes_lock: Arc<Mutex<()>>,
 }
@@ -117,7 +153,23 @@ impl MemoryStore {
         Self::default()
     }
 
-    fn get_static_account(&self) -> Option<StaticAccountData> {
+    /// Create a new empty `MemoryStore` which uses the given [`Clock`] to
+    /// decide when leased locks expire.
+    ///
+    /// The clock is replaced by the one of the [`OlmMachine`] using this
+    /// store, see [`OlmMachine::set_clock()`].
+    ///
+    /// [`OlmMachine`]: crate::OlmMachine
+    /// [`OlmMachine::set_clock()`]: crate::OlmMachine::set_clock
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { clock: StdRwLock::new(Some(clock)), ..Default::default() }
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.read().clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+
+    pub(crate) fn get_static_account(&self) -> Option<StaticAccountData> {
         self.static_account.read().clone()
     }
 
@@ -142,7 +194,7 @@ impl MemoryStore {
             // insert or replace if exists
             entry.insert(
                 session_id,
-                serde_json::to_string(&pickle).expect("Failed to serialize olm session"),
+                serde_json::to_string(&pickle).expect("Failed to serialize olm session").into(),
             );
         }
     }
@@ -157,6 +209,12 @@ impl MemoryStore {
         *self.private_identity.write() = private_identity;
     }
 
+    /// Decode a serialized [`InboundGroupSession`].
+    fn deserialize_inbound_group_session(ser: &SecretString) -> Result<InboundGroupSession> {
+        let pickle: PickledInboundGroupSession = serde_json::from_str(ser.expose_secret())?;
+        Ok(InboundGroupSession::from_pickle(pickle)?)
+    }
+
     /// Return all the [`InboundGroupSession`]s we have, paired with the
     /// `backed_up_to` value for each one (or "" where it is missing, which
     /// should never happen).
@@ -181,18 +239,383 @@ impl MemoryStore {
             })
             .collect())
     }
+
+    /// Serialize the whole content of this store into a blob encrypted with
+    /// the given passphrase.
+    ///
+    /// This allows short-lived processes to persist their crypto state without
+    /// pulling in a database backend. The store can be restored using
+    /// [`MemoryStore::import_encrypted()`].
+    ///
+    /// Leases held by cross-process locks are not part of the snapshot.
+    pub async fn export_encrypted(
+        &self,
+        passphrase: &str,
+    ) -> std::result::Result<Vec<u8>, CryptoStoreError> {
+        let snapshot = self.snapshot().await;
+
+        let cipher = StoreCipher::new().map_err(CryptoStoreError::backend)?;
+        let ciphertext = cipher.encrypt_value(&snapshot).map_err(CryptoStoreError::backend)?;
+        let cipher = cipher.export(passphrase).map_err(CryptoStoreError::backend)?;
+
+        let encrypted = EncryptedSnapshot { version: SNAPSHOT_VERSION, cipher, ciphertext };
+
+        rmp_serde::to_vec_named(&encrypted).map_err(CryptoStoreError::backend)
+    }
+
+    /// Serialize the whole content of this store into a deterministic,
+    /// unencrypted JSON document, meant to be checked in as a test fixture.
+    ///
+    /// Two stores with the same content produce the same fixture, so that
+    /// reproducible end-to-end encryption tests can start from a known state
+    /// instead of running the full key exchange between two machines. The
+    /// store can be restored using [`MemoryStore::import_fixture()`].
+    ///
+    /// **Warning**: The fixture contains the private keys of the account in
+    /// plaintext, it must only be used with test accounts.
+    #[cfg(any(test, feature = "testing"))]
+    pub async fn export_fixture(&self) -> std::result::Result<String, CryptoStoreError> {
+        let fixture = Fixture { version: SNAPSHOT_VERSION, snapshot: self.snapshot().await };
+
+        // Going through a `serde_json::Value` sorts all the maps by key, the
+        // lists in the snapshot are already sorted.
+        let value = serde_json::to_value(&fixture)?;
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+
+    /// Restore a store from a fixture that was created using
+    /// [`MemoryStore::export_fixture()`].
+    #[cfg(any(test, feature = "testing"))]
+    pub fn import_fixture(fixture: &str) -> std::result::Result<Self, CryptoStoreError> {
+        let fixture: Fixture = serde_json::from_str(fixture)?;
+
+        if fixture.version != SNAPSHOT_VERSION {
+            return Err(CryptoStoreError::UnsupportedDatabaseVersion(
+                fixture.version.into(),
+                SNAPSHOT_VERSION.into(),
+            ));
+        }
+
+        Self::from_snapshot(fixture.snapshot)
+    }
+
+    /// Collect the whole content of this store, with all the lists sorted so
+    /// that the same content always results in the same snapshot.
+    async fn snapshot(&self) -> Snapshot {
+        let outbound_group_sessions: Vec<_> =
+            self.outbound_group_sessions.read().values().cloned().collect();
+        let mut pickled_outbound_group_sessions = Vec::with_capacity(outbound_group_sessions.len());
+        for session in outbound_group_sessions {
+            pickled_outbound_group_sessions.push(session.pickle().await);
+        }
+
+        let private_identity = self.private_identity.read().clone();
+        let private_identity = match private_identity {
+            Some(identity) => Some(identity.pickle().await),
+            None => None,
+        };
+
+        let backup_keys = self.backup_keys.read().await.clone();
+
+        let mut tracked_users: Vec<_> = self.tracked_users.read().values().cloned().collect();
+        tracked_users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+        let mut outgoing_key_requests: Vec<_> =
+            self.outgoing_key_requests.read().values().cloned().collect();
+        outgoing_key_requests.sort_by(|a, b| a.request_id.cmp(&b.request_id));
+
+        let mut room_key_bundles: Vec<_> =
+            self.room_key_bundles.read().values().flat_map(HashMap::values).cloned().collect();
+        room_key_bundles.sort_by(|a, b| {
+            (&a.bundle_data.room_id, &a.sender_user).cmp(&(&b.bundle_data.room_id, &b.sender_user))
+        });
+
+        Snapshot {
+            account: self.account.read().clone(),
+            sessions: self.sessions.read().clone(),
+            inbound_group_sessions: self.inbound_group_sessions.read().clone(),
+            inbound_group_sessions_backed_up_to: self
+                .inbound_group_sessions_backed_up_to
+                .read()
+                .iter()
+                .map(|(room_id, sessions)| {
+                    let sessions = sessions
+                        .iter()
+                        .map(|(session_id, version)| {
+                            (session_id.clone(), version.as_str().to_owned())
+                        })
+                        .collect();
+                    (room_id.clone(), sessions)
+                })
+                .collect(),
+            outbound_group_sessions: pickled_outbound_group_sessions,
+            private_identity,
+            tracked_users,
+            olm_hashes: self
+                .olm_hashes
+                .read()
+                .iter()
+                .map(|(sender_key, hashes)| (sender_key.clone(), hashes.iter().cloned().collect()))
+                .collect(),
+            devices: self.devices.all_devices(),
+            identities: self.identities.read().clone(),
+            outgoing_key_requests,
+            direct_withheld_info: self.direct_withheld_info.read().clone(),
+            megolm_message_indices: self.megolm_message_indices.read().clone(),
+            session_share_history: self.session_share_history.read().clone(),
+            verification_audit_log: self.verification_audit_log.read().clone(),
+            custom_values: self.custom_values.read().clone(),
+            app_data: self.app_data.read().clone(),
+            secret_inbox: self.secret_inbox.read().clone(),
+            backup_decryption_key: backup_keys.decryption_key,
+            backup_version: backup_keys.backup_version,
+            dehydrated_device_pickle_key: self.dehydrated_device_pickle_key.read().await.clone(),
+            next_batch_token: self.next_batch_token.read().await.clone(),
+            room_settings: self.room_settings.read().clone(),
+            room_key_bundles,
+            quarantined_entries: self.quarantined_entries.read().clone(),
+            change_journal: self.change_journal.read().clone(),
+        }
+    }
+
+    /// Restore a store from a blob that was created using
+    /// [`MemoryStore::export_encrypted()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt the snapshot.
+    ///
+    /// * `encrypted` - The encrypted snapshot.
+    pub fn import_encrypted(
+        passphrase: &str,
+        encrypted: &[u8],
+    ) -> std::result::Result<Self, CryptoStoreError> {
+        let encrypted: EncryptedSnapshot =
+            rmp_serde::from_slice(encrypted).map_err(CryptoStoreError::backend)?;
+
+        if encrypted.version != SNAPSHOT_VERSION {
+            return Err(CryptoStoreError::UnsupportedDatabaseVersion(
+                encrypted.version.into(),
+                SNAPSHOT_VERSION.into(),
+            ));
+        }
+
+        let cipher = StoreCipher::import(passphrase, &encrypted.cipher)
+            .map_err(CryptoStoreError::backend)?;
+        let snapshot: Snapshot =
+            cipher.decrypt_value(&encrypted.ciphertext).map_err(CryptoStoreError::backend)?;
+
+        Self::from_snapshot(snapshot)
+    }
+
+    /// Create a store holding the content of the given snapshot.
+    fn from_snapshot(snapshot: Snapshot) -> std::result::Result<Self, CryptoStoreError> {
+        let static_account = snapshot
+            .account
+            .as_ref()
+            .map(|account| -> std::result::Result<_, CryptoStoreError> {
+                let pickle: PickledAccount = serde_json::from_str(account.expose_secret())?;
+                Ok(Account::from_pickle(pickle)?.static_data().clone())
+            })
+            .transpose()?;
+
+        let mut outbound_group_sessions = BTreeMap::new();
+        if let Some(account) = &static_account {
+            for pickle in snapshot.outbound_group_sessions {
+                let session = OutboundGroupSession::from_pickle(
+                    account.device_id.clone(),
+                    account.identity_keys.clone(),
+                    pickle,
+                )?;
+                outbound_group_sessions.insert(session.room_id().to_owned(), session);
+            }
+        } else if !snapshot.outbound_group_sessions.is_empty() {
+            warn!("The snapshot contains outbound group sessions but no account, dropping them");
+        }
+
+        let private_identity = snapshot
+            .private_identity
+            .map(PrivateCrossSigningIdentity::from_pickle)
+            .transpose()
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        let devices = DeviceStore::new();
+        for device in snapshot.devices {
+            devices.add(device);
+        }
+
+        let key_requests_by_info = snapshot
+            .outgoing_key_requests
+            .iter()
+            .map(|request| (encode_key_info(&request.info), request.request_id.clone()))
+            .collect();
+        let outgoing_key_requests = snapshot
+            .outgoing_key_requests
+            .into_iter()
+            .map(|request| (request.request_id.clone(), request))
+            .collect();
+
+        let inbound_group_sessions_backed_up_to = snapshot
+            .inbound_group_sessions_backed_up_to
+            .into_iter()
+            .map(|(room_id, sessions)| {
+                let sessions = sessions
+                    .into_iter()
+                    .map(|(session_id, version)| (session_id, BackupVersion(version)))
+                    .collect();
+                (room_id, sessions)
+            })
+            .collect();
+
+        let mut room_key_bundles: HashMap<OwnedRoomId, HashMap<OwnedUserId, _>> = HashMap::new();
+        for bundle in snapshot.room_key_bundles {
+            room_key_bundles
+                .entry(bundle.bundle_data.room_id.clone())
+                .or_default()
+                .insert(bundle.sender_user.clone(), bundle);
+        }
+
+        Ok(Self {
+            static_account: Arc::new(StdRwLock::new(static_account)),
+            account: StdRwLock::new(snapshot.account),
+            sessions: StdRwLock::new(snapshot.sessions),
+            inbound_group_sessions: StdRwLock::new(snapshot.inbound_group_sessions),
+            inbound_group_sessions_backed_up_to: StdRwLock::new(
+                inbound_group_sessions_backed_up_to,
+            ),
+            outbound_group_sessions: StdRwLock::new(outbound_group_sessions),
+            private_identity: StdRwLock::new(private_identity),
+            tracked_users: StdRwLock::new(
+                snapshot
+                    .tracked_users
+                    .into_iter()
+                    .map(|user| (user.user_id.clone(), user))
+                    .collect(),
+            ),
+            olm_hashes: StdRwLock::new(
+                snapshot
+                    .olm_hashes
+                    .into_iter()
+                    .map(|(sender_key, hashes)| (sender_key, hashes.into_iter().collect()))
+                    .collect(),
+            ),
+            devices,
+            identities: StdRwLock::new(snapshot.identities),
+            outgoing_key_requests: StdRwLock::new(outgoing_key_requests),
+            key_requests_by_info: StdRwLock::new(key_requests_by_info),
+            direct_withheld_info: StdRwLock::new(snapshot.direct_withheld_info),
+            megolm_message_indices: StdRwLock::new(snapshot.megolm_message_indices),
+            session_share_history: StdRwLock::new(snapshot.session_share_history),
+            verification_audit_log: StdRwLock::new(snapshot.verification_audit_log),
+            custom_values: StdRwLock::new(snapshot.custom_values),
+            app_data: StdRwLock::new(snapshot.app_data),
+            leases: Default::default(),
+            secret_inbox: StdRwLock::new(snapshot.secret_inbox),
+            backup_keys: RwLock::new(BackupKeys {
+                decryption_key: snapshot.backup_decryption_key,
+                backup_version: snapshot.backup_version,
+            }),
+            dehydrated_device_pickle_key: RwLock::new(snapshot.dehydrated_device_pickle_key),
+            next_batch_token: RwLock::new(snapshot.next_batch_token),
+            room_settings: StdRwLock::new(snapshot.room_settings),
+            room_key_bundles: StdRwLock::new(room_key_bundles),
+            change_journal: StdRwLock::new(snapshot.change_journal),
+            quarantined_entries: StdRwLock::new(snapshot.quarantined_entries),
+            clock: Default::default(),
+            save_changes_lock: Default::default(),
+        })
+    }
 }
 
-type Result<T> = std::result::Result<T, Infallible>;
+/// The current version of the format produced by
+/// [`MemoryStore::export_encrypted()`].
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// The unencrypted envelope of a [`MemoryStore`] snapshot.
+#[derive(Serialize, Deserialize)]
+struct EncryptedSnapshot {
+    /// The version of the snapshot format.
+    version: u8,
+    /// The [`StoreCipher`] that was used to encrypt the snapshot, itself
+    /// encrypted using the passphrase.
+    cipher: Vec<u8>,
+    /// The encrypted [`Snapshot`].
+    ciphertext: Vec<u8>,
+}
+
+/// The unencrypted envelope of a [`MemoryStore`] fixture.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    /// The version of the snapshot format.
+    version: u8,
+    /// The content of the store.
+    snapshot: Snapshot,
+}
+
+/// The content of a [`MemoryStore`] snapshot, before encryption.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    account: Option<SecretString>,
+    sessions: BTreeMap<String, BTreeMap<String, SecretString>>,
+    inbound_group_sessions: BTreeMap<OwnedRoomId, HashMap<String, SecretString>>,
+    inbound_group_sessions_backed_up_to: HashMap<OwnedRoomId, HashMap<SessionId, String>>,
+    outbound_group_sessions: Vec<PickledOutboundGroupSession>,
+    private_identity: Option<PickledCrossSigningIdentity>,
+    tracked_users: Vec<TrackedUser>,
+    olm_hashes: BTreeMap<String, BTreeSet<String>>,
+    devices: Vec<DeviceData>,
+    identities: HashMap<OwnedUserId, String>,
+    outgoing_key_requests: Vec<GossipRequest>,
+    direct_withheld_info: HashMap<OwnedRoomId, HashMap<String, RoomKeyWithheldEvent>>,
+    #[serde(default)]
+    megolm_message_indices: MegolmMessageIndices,
+    #[serde(default)]
+    session_share_history: SessionShareHistory,
+    #[serde(default)]
+    verification_audit_log: HashMap<OwnedUserId, VecDeque<VerificationAuditEntry>>,
+    custom_values: HashMap<String, Vec<u8>>,
+    #[serde(default)]
+    app_data: AppDataEntries,
+    secret_inbox: HashMap<String, Vec<GossippedSecret>>,
+    backup_decryption_key: Option<BackupDecryptionKey>,
+    backup_version: Option<String>,
+    dehydrated_device_pickle_key: Option<DehydratedDeviceKey>,
+    next_batch_token: Option<String>,
+    room_settings: HashMap<OwnedRoomId, RoomSettings>,
+    room_key_bundles: Vec<StoredRoomKeyBundleData>,
+    #[serde(default)]
+    quarantined_entries: Vec<MemoryQuarantinedEntry>,
+    #[serde(default)]
+    change_journal: ChangeJournal,
+}
+
+/// A [`QuarantinedEntry`] of the [`MemoryStore`], along with the place it was
+/// found at, so it can be restored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MemoryQuarantinedEntry {
+    room_id: OwnedRoomId,
+    session_id: String,
+    backed_up_to: Option<BackupVersion>,
+    entry: QuarantinedEntry,
+}
+
+type MegolmMessageIndices = HashMap<OwnedRoomId, HashMap<String, HashMap<u32, OwnedEventId>>>;
+
+type SessionShareHistory = HashMap<OwnedRoomId, HashMap<String, Vec<SessionShareRecord>>>;
+
+type AppDataEntries = HashMap<String, BTreeMap<String, Vec<u8>>>;
+
+type Result<T> = std::result::Result<T, CryptoStoreError>;
 
 #[cfg_attr(target_family = "wasm", async_trait(?Send))]
 #[cfg_attr(not(target_family = "wasm"), async_trait)]
 impl CryptoStore for MemoryStore {
-    type Error = Infallible;
+    type Error = CryptoStoreError;
 
     async fn load_account(&self) -> Result<Option<Account>> {
         let pickled_account: Option<PickledAccount> = self.account.read().as_ref().map(|acc| {
-            serde_json::from_str(acc)
+            serde_json::from_str(acc.expose_secret())
                 .expect("Deserialization failed: invalid pickled account JSON format")
         });
 
@@ -216,6 +639,142 @@ impl CryptoStore for MemoryStore {
         Ok(self.next_batch_token.read().await.clone())
     }
 
+    async fn compact(&self) -> Result<CompactionReport> {
+        // There's no file to shrink, but we can give the memory of removed entries
+        // back to the allocator.
+        {
+            let mut inbound_group_sessions = self.inbound_group_sessions.write();
+            inbound_group_sessions.retain(|_, sessions| !sessions.is_empty());
+            inbound_group_sessions.values_mut().for_each(HashMap::shrink_to_fit);
+        }
+
+        {
+            let mut backed_up_to = self.inbound_group_sessions_backed_up_to.write();
+            backed_up_to.retain(|_, sessions| !sessions.is_empty());
+            backed_up_to.values_mut().for_each(HashMap::shrink_to_fit);
+            backed_up_to.shrink_to_fit();
+        }
+
+        self.tracked_users.write().shrink_to_fit();
+        self.olm_hashes.write().shrink_to_fit();
+        self.identities.write().shrink_to_fit();
+        self.outgoing_key_requests.write().shrink_to_fit();
+        self.key_requests_by_info.write().shrink_to_fit();
+        self.direct_withheld_info.write().shrink_to_fit();
+        self.custom_values.write().shrink_to_fit();
+        self.leases.write().shrink_to_fit();
+        self.secret_inbox.write().shrink_to_fit();
+        self.room_settings.write().shrink_to_fit();
+        self.room_key_bundles.write().shrink_to_fit();
+
+        // The memory used by the store isn't tracked, so we can't report sizes.
+        Ok(CompactionReport::default())
+    }
+
+    async fn load_change_journal(&self, since: u64) -> Result<Vec<ChangeJournalRecord>> {
+        Ok(self
+            .change_journal
+            .read()
+            .records
+            .iter()
+            .filter(|record| record.sequence_number > since)
+            .cloned()
+            .collect())
+    }
+
+    async fn truncate_change_journal(&self, up_to: u64) -> Result<()> {
+        self.change_journal.write().records.retain(|record| record.sequence_number > up_to);
+        Ok(())
+    }
+
+    async fn shred(&self) -> Result<ShredReport> {
+        let _guard = self.save_changes_lock.lock().await;
+
+        // The pickles, like the other secrets, zeroize themselves when they're
+        // dropped.
+        let account_deleted = self.account.write().take().is_some();
+
+        let sessions = mem::take(&mut *self.sessions.write());
+        let sessions_deleted = sessions.values().map(BTreeMap::len).sum();
+
+        let inbound_group_sessions = mem::take(&mut *self.inbound_group_sessions.write());
+        let inbound_group_sessions_deleted =
+            inbound_group_sessions.values().map(HashMap::len).sum();
+
+        self.inbound_group_sessions_backed_up_to.write().clear();
+        self.outbound_group_sessions.write().clear();
+        *self.private_identity.write() = None;
+        *self.backup_keys.write().await = BackupKeys::default();
+        *self.dehydrated_device_pickle_key.write().await = None;
+        self.secret_inbox.write().clear();
+        self.room_key_bundles.write().clear();
+        self.megolm_message_indices.write().clear();
+        self.session_share_history.write().clear();
+        self.verification_audit_log.write().clear();
+        self.change_journal.write().records.clear();
+        self.quarantined_entries.write().clear();
+        self.custom_values.write().clear();
+        self.app_data.write().clear();
+
+        Ok(ShredReport {
+            account_deleted,
+            sessions_deleted,
+            inbound_group_sessions_deleted,
+            secure_delete: true,
+        })
+    }
+
+    async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+        custom_value_keys: &[String],
+    ) -> Result<DeletedRoomCryptoState> {
+        let _guard = self.save_changes_lock.lock().await;
+
+        let room_keys = self
+            .inbound_group_sessions
+            .write()
+            .remove(room_id)
+            .unwrap_or_default()
+            .into_values()
+            // Sessions which can't be decoded anymore are deleted all the same, we just
+            // can't report them.
+            .filter_map(|ser| Self::deserialize_inbound_group_session(&ser).ok())
+            .map(|session| RoomKeyInfo::from(&session))
+            .collect();
+        self.inbound_group_sessions_backed_up_to.write().remove(room_id);
+        self.megolm_message_indices.write().remove(room_id);
+        self.session_share_history.write().remove(room_id);
+        self.room_settings.write().remove(room_id);
+
+        {
+            let mut custom_values = self.custom_values.write();
+            for key in custom_value_keys {
+                custom_values.remove(key);
+            }
+        }
+
+        let outbound_group_session_deleted =
+            self.outbound_group_sessions.write().remove(room_id).is_some();
+        let withheld_deleted =
+            self.direct_withheld_info.write().remove(room_id).map_or(0, |info| info.len());
+
+        let mut room_key_bundle_senders: Vec<_> = self
+            .room_key_bundles
+            .write()
+            .remove(room_id)
+            .map(|bundles| bundles.into_keys().collect())
+            .unwrap_or_default();
+        room_key_bundle_senders.sort();
+
+        Ok(DeletedRoomCryptoState {
+            room_keys,
+            outbound_group_session_deleted,
+            withheld_deleted,
+            room_key_bundle_senders,
+        })
+    }
+
     async fn save_pending_changes(&self, changes: PendingChanges) -> Result<()> {
         let _guard = self.save_changes_lock.lock().await;
 
@@ -229,6 +788,7 @@ impl CryptoStore for MemoryStore {
         *self.account.write() = pickled_account.map(|pickle| {
             serde_json::to_string(&pickle)
                 .expect("Serialization failed: invalid pickled account JSON format")
+                .into()
         });
 
         Ok(())
@@ -245,7 +805,11 @@ impl CryptoStore for MemoryStore {
         }
         self.save_sessions(pickled_session);
 
-        self.save_inbound_group_sessions(changes.inbound_group_sessions, None).await?;
+        self.save_inbound_group_sessions(
+            changes.inbound_group_sessions,
+            changes.inbound_group_sessions_backed_up_to.as_deref(),
+        )
+        .await?;
         self.save_outbound_group_sessions(changes.outbound_group_sessions);
         self.save_private_identity(changes.private_identity);
 
@@ -335,6 +899,49 @@ impl CryptoStore for MemoryStore {
             }
         }
 
+        if !changes.change_journal.is_empty() {
+            self.change_journal.write().append(changes.change_journal);
+        }
+
+        if !changes.session_share_records.is_empty() {
+            let mut history = self.session_share_history.write();
+            for record in changes.session_share_records {
+                history
+                    .entry(record.room_id.clone())
+                    .or_default()
+                    .entry(record.session_id.clone())
+                    .or_default()
+                    .push(record);
+            }
+        }
+
+        if !changes.verification_audit_log.is_empty() {
+            let mut audit_log = self.verification_audit_log.write();
+            for entry in changes.verification_audit_log {
+                let entries = audit_log.entry(entry.user_id.clone()).or_default();
+                entries.push_back(entry);
+
+                if entries.len() > VERIFICATION_AUDIT_LOG_CAPACITY {
+                    entries.pop_front();
+                }
+            }
+        }
+
+        if !changes.app_data.is_empty() {
+            let mut app_data = self.app_data.write();
+            for change in changes.app_data {
+                let entries = app_data.entry(change.namespace).or_default();
+                match change.value {
+                    Some(value) => {
+                        entries.insert(change.key, value);
+                    }
+                    None => {
+                        entries.remove(&change.key);
+                    }
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -373,7 +980,8 @@ impl CryptoStore for MemoryStore {
                 .insert(
                     session.session_id().to_owned(),
                     serde_json::to_string(&pickle)
-                        .expect("Pickle pickle data should serialize to json"),
+                        .expect("Pickle pickle data should serialize to json")
+                        .into(),
                 );
         }
         Ok(())
@@ -385,8 +993,9 @@ impl CryptoStore for MemoryStore {
         if let Some(pickles) = self.sessions.read().get(sender_key) {
             let mut sessions: Vec<Session> = Vec::new();
             for serialized_pickle in pickles.values() {
-                let pickle: PickledSession = serde_json::from_str(serialized_pickle.as_str())
-                    .expect("Pickle pickle deserialization should work");
+                let pickle: PickledSession =
+                    serde_json::from_str(serialized_pickle.expose_secret())
+                        .expect("Pickle pickle deserialization should work");
                 let session = Session::from_pickle(device_keys.clone(), pickle)
                     .expect("Expect from pickle to always work");
                 sessions.push(session);
@@ -397,23 +1006,37 @@ impl CryptoStore for MemoryStore {
         }
     }
 
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>> {
+        Ok(self.sessions.read().keys().cloned().collect())
+    }
+
+    async fn delete_sessions(&self, sender_key: &str, session_ids: &[String]) -> Result<()> {
+        let mut sessions = self.sessions.write();
+
+        if let Some(pickles) = sessions.get_mut(sender_key) {
+            for session_id in session_ids {
+                pickles.remove(session_id);
+            }
+
+            if pickles.is_empty() {
+                sessions.remove(sender_key);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
         session_id: &str,
     ) -> Result<Option<InboundGroupSession>> {
-        let pickle: Option<PickledInboundGroupSession> = self
-            .inbound_group_sessions
+        self.inbound_group_sessions
             .read()
             .get(room_id)
             .and_then(|m| m.get(session_id))
-            .and_then(|ser| {
-                serde_json::from_str(ser).expect("Pickle pickle deserialization should work")
-            });
-
-        Ok(pickle.map(|p| {
-            InboundGroupSession::from_pickle(p).expect("Expect from pickle to always work")
-        }))
+            .map(Self::deserialize_inbound_group_session)
+            .transpose()
     }
 
     async fn get_withheld_info(
@@ -428,19 +1051,176 @@ impl CryptoStore for MemoryStore {
             .and_then(|e| Some(e.get(session_id)?.to_owned())))
     }
 
+    async fn record_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        let mut indices = self.megolm_message_indices.write();
+        let session_indices = indices
+            .entry(room_id.to_owned())
+            .or_default()
+            .entry(session_id.to_owned())
+            .or_default();
+
+        match session_indices.entry(message_index) {
+            Entry::Occupied(entry) => Ok(Some(entry.get().clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(event_id.to_owned());
+                Ok(None)
+            }
+        }
+    }
+
+    async fn get_session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>> {
+        Ok(self
+            .session_share_history
+            .read()
+            .get(room_id)
+            .and_then(|sessions| sessions.get(session_id))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn delete_inbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+        session_ids: &[String],
+    ) -> Result<()> {
+        if let Some(sessions) = self.inbound_group_sessions.write().get_mut(room_id) {
+            for session_id in session_ids {
+                sessions.remove(session_id);
+            }
+        }
+
+        if let Some(backed_up_to) =
+            self.inbound_group_sessions_backed_up_to.write().get_mut(room_id)
+        {
+            for session_id in session_ids {
+                backed_up_to.remove(session_id);
+            }
+        }
+
+        if let Some(indices) = self.megolm_message_indices.write().get_mut(room_id) {
+            for session_id in session_ids {
+                indices.remove(session_id);
+            }
+        }
+
+        if let Some(history) = self.session_share_history.write().get_mut(room_id) {
+            for session_id in session_ids {
+                history.remove(session_id);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
-        let inbounds = self
-            .inbound_group_sessions
+        self.inbound_group_sessions
             .read()
             .values()
             .flat_map(HashMap::values)
-            .map(|ser| {
-                let pickle: PickledInboundGroupSession =
-                    serde_json::from_str(ser).expect("Pickle deserialization should work");
-                InboundGroupSession::from_pickle(pickle).expect("Expect from pickle to always work")
+            .map(Self::deserialize_inbound_group_session)
+            .collect()
+    }
+
+    async fn quarantine_undecodable_inbound_group_sessions(&self) -> Result<Vec<QuarantinedEntry>> {
+        let _guard = self.save_changes_lock.lock().await;
+
+        let mut quarantined = Vec::new();
+        let mut backed_up_to = self.inbound_group_sessions_backed_up_to.write();
+
+        for (room_id, sessions) in self.inbound_group_sessions.write().iter_mut() {
+            sessions.retain(|session_id, ser| {
+                let Err(error) = Self::deserialize_inbound_group_session(ser) else {
+                    return true;
+                };
+
+                quarantined.push(MemoryQuarantinedEntry {
+                    room_id: room_id.clone(),
+                    session_id: session_id.clone(),
+                    backed_up_to: backed_up_to
+                        .get_mut(room_id)
+                        .and_then(|sessions| sessions.remove(session_id)),
+                    entry: QuarantinedEntry {
+                        kind: QuarantinedEntryKind::InboundGroupSession,
+                        value: ser.expose_secret().as_bytes().into(),
+                        error: error.to_string(),
+                    },
+                });
+
+                false
+            });
+        }
+
+        let entries = quarantined.iter().map(|q| q.entry.clone()).collect();
+        self.quarantined_entries.write().extend(quarantined);
+
+        Ok(entries)
+    }
+
+    async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>> {
+        Ok(self.quarantined_entries.read().iter().map(|q| q.entry.clone()).collect())
+    }
+
+    async fn restore_quarantined_entries(&self) -> Result<usize> {
+        let _guard = self.save_changes_lock.lock().await;
+
+        let quarantined = mem::take(&mut *self.quarantined_entries.write());
+        let count = quarantined.len();
+
+        let mut backed_up_to = self.inbound_group_sessions_backed_up_to.write();
+        let mut sessions = self.inbound_group_sessions.write();
+
+        for MemoryQuarantinedEntry { room_id, session_id, backed_up_to: version, entry } in
+            quarantined
+        {
+            if let Some(version) = version {
+                backed_up_to
+                    .entry(room_id.clone())
+                    .or_default()
+                    .insert(session_id.clone(), version);
+            }
+
+            let value = String::from_utf8_lossy(entry.value.expose_secret()).into_owned();
+            sessions.entry(room_id).or_default().insert(session_id, value.into());
+        }
+
+        Ok(count)
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        // Collect the pickles after the given session, sorted by room ID and session
+        // ID, so that we return a consistent ordering between calls.
+        let mut pickles: Vec<_> = self
+            .inbound_group_sessions
+            .read()
+            .iter()
+            .flat_map(|(room_id, sessions)| {
+                sessions.iter().map(move |(session_id, pickle)| {
+                    ((room_id.clone(), session_id.clone()), pickle.clone())
+                })
             })
+            .filter(|(key, _)| after.as_ref().is_none_or(|after| key > after))
             .collect();
-        Ok(inbounds)
+        pickles.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        pickles
+            .into_iter()
+            .take(limit)
+            .map(|(_, ser)| Self::deserialize_inbound_group_session(&ser))
+            .collect()
     }
 
     async fn inbound_group_session_counts(
@@ -506,6 +1286,55 @@ impl CryptoStore for MemoryStore {
         Ok(sessions.drain(start_index..).take(limit).collect())
     }
 
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let Some(sessions) = self
+            .inbound_group_sessions
+            .read()
+            .get(room_id)
+            .map(|sessions| {
+                sessions
+                    .values()
+                    .map(Self::deserialize_inbound_group_session)
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?
+        else {
+            return Ok(Vec::new());
+        };
+
+        Ok(sessions
+            .into_iter()
+            .filter(|session| {
+                since.is_none_or(|since| session.received_at().is_some_and(|r| r >= since))
+            })
+            .collect())
+    }
+
+    async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>> {
+        // The sessions are kept in a `BTreeMap`, so the rooms are already sorted.
+        self.inbound_group_sessions
+            .read()
+            .iter()
+            .filter(|(_, sessions)| !sessions.is_empty())
+            .map(|(room_id, sessions)| {
+                let received_at = sessions
+                    .values()
+                    .map(|ser| {
+                        let pickle: PickledInboundGroupSession =
+                            serde_json::from_str(ser.expose_secret())?;
+                        Ok(pickle.received_at)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                Ok(RoomKeySummary::from_received_at(room_id.clone(), received_at))
+            })
+            .collect()
+    }
+
     async fn inbound_group_sessions_for_backup(
         &self,
         backup_version: &str,
@@ -552,7 +1381,8 @@ impl CryptoStore for MemoryStore {
                 self.inbound_group_sessions.write().entry(room_id.to_owned()).or_default().insert(
                     session_id.to_owned(),
                     serde_json::to_string(&updated_pickle)
-                        .expect("Pickle serialization should work"),
+                        .expect("Pickle serialization should work")
+                        .into(),
                 );
             }
         }
@@ -574,6 +1404,11 @@ impl CryptoStore for MemoryStore {
         Ok(self.backup_keys.read().await.to_owned())
     }
 
+    async fn delete_backup_decryption_key(&self) -> Result<()> {
+        self.backup_keys.write().await.decryption_key = None;
+        Ok(())
+    }
+
     async fn load_dehydrated_device_pickle_key(&self) -> Result<Option<DehydratedDeviceKey>> {
         Ok(self.dehydrated_device_pickle_key.read().await.to_owned())
     }
@@ -595,6 +1430,25 @@ impl CryptoStore for MemoryStore {
         Ok(self.tracked_users.read().values().cloned().collect())
     }
 
+    async fn load_tracked_users_paged(
+        &self,
+        after: Option<&UserId>,
+        limit: usize,
+    ) -> Result<Vec<TrackedUser>> {
+        let mut tracked_users: Vec<_> = self
+            .tracked_users
+            .read()
+            .values()
+            .filter(|user| after.is_none_or(|after| &*user.user_id > after))
+            .cloned()
+            .collect();
+
+        tracked_users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        tracked_users.truncate(limit);
+
+        Ok(tracked_users)
+    }
+
     async fn save_tracked_users(&self, tracked_users: &[(&UserId, bool)]) -> Result<()> {
         self.tracked_users.write().extend(tracked_users.iter().map(|(user_id, dirty)| {
             let user_id: OwnedUserId = user_id.to_owned().into();
@@ -640,6 +1494,18 @@ impl CryptoStore for MemoryStore {
         }
     }
 
+    async fn get_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>> {
+        Ok(self
+            .verification_audit_log
+            .read()
+            .get(user_id)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
     async fn is_message_known(&self, message_hash: &crate::olm::OlmMessageHash) -> Result<bool> {
         Ok(self
             .olm_hashes
@@ -732,26 +1598,68 @@ impl CryptoStore for MemoryStore {
         Ok(())
     }
 
+    async fn get_app_data(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.app_data.read().get(namespace).and_then(|entries| entries.get(key)).cloned())
+    }
+
+    async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>> {
+        Ok(self
+            .app_data
+            .read()
+            .get(namespace)
+            .map(|entries| entries.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
     async fn try_take_leased_lock(
         &self,
         lease_duration_ms: u32,
         key: &str,
         holder: &str,
     ) -> Result<bool> {
-        Ok(try_take_leased_lock(&mut self.leases.write(), lease_duration_ms, key, holder))
+        let now = u64::from(self.clock().now().get());
+        let expiration = now.saturating_add(lease_duration_ms.into());
+
+        match self.leases.write().entry(key.to_owned()) {
+            Entry::Occupied(mut entry) => {
+                let (current_holder, current_expiration) = entry.get_mut();
+
+                // We either had the lease before and extend it, or the current holder's
+                // lease has expired and we steal it.
+                if current_holder == holder || *current_expiration < now {
+                    *current_holder = holder.to_owned();
+                    *current_expiration = expiration;
+
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((holder.to_owned(), expiration));
+
+                Ok(true)
+            }
+        }
+    }
+
+    fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.write() = Some(clock);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
 
+    use assert_matches::assert_matches;
     use matrix_sdk_test::async_test;
-    use ruma::{room_id, user_id, RoomId};
+    use ruma::{device_id, room_id, uint, user_id, MilliSecondsSinceUnixEpoch, RoomId};
     use vodozemac::{Curve25519PublicKey, Ed25519PublicKey};
 
-    use super::SessionId;
+    use super::{EncryptedSnapshot, SessionId};
     use crate::{
+        clock::MockClock,
         identities::device::testing::get_device,
         olm::{
             tests::get_account_and_session_test_helper, Account, InboundGroupSession,
@@ -759,10 +1667,12 @@ mod tests {
         },
         store::{
             memorystore::MemoryStore,
-            types::{Changes, DeviceChanges, PendingChanges},
-            CryptoStore,
+            types::{
+                ChangeJournalEntry, Changes, DeviceChanges, PendingChanges, QuarantinedEntryKind,
+            },
+            CryptoStore, CryptoStoreError,
         },
-        DeviceData,
+        DeviceData, OlmMachine,
     };
 
     #[async_test]
@@ -1158,6 +2068,198 @@ mod tests {
         assert_eq!(key_counts.backed_up, 1);
     }
 
+    #[async_test]
+    async fn test_encrypted_snapshot_round_trip() {
+        // Given a store with an account, an olm session, and some backed up room keys
+        let (account, session) = get_account_and_session_test_helper();
+        let room_id = room_id!("!test:localhost");
+        let (store, sessions) = store_with_sessions(3, room_id).await;
+        mark_backed_up(&store, room_id, "bkp1", &sessions[..1]).await;
+
+        let (outbound, _) = account.create_group_session_pair_with_defaults(room_id).await;
+        store
+            .save_changes(Changes {
+                devices: DeviceChanges {
+                    new: vec![DeviceData::from_account(&account)],
+                    ..Default::default()
+                },
+                outbound_group_sessions: vec![outbound.clone()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        store.save_pending_changes(PendingChanges { account: Some(account) }).await.unwrap();
+        store
+            .save_changes(Changes { sessions: vec![session.clone()], ..Default::default() })
+            .await
+            .unwrap();
+        store.set_custom_value("custom", b"value".to_vec()).await.unwrap();
+        store
+            .save_changes(Changes {
+                change_journal: vec![ChangeJournalEntry::from(&sessions[0])],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        // When we export it and import it again
+        let snapshot = store.export_encrypted("passphrase").await.unwrap();
+        let restored = MemoryStore::import_encrypted("passphrase", &snapshot).unwrap();
+
+        // Then the restored store contains the same data
+        assert!(restored.load_account().await.unwrap().is_some());
+
+        let loaded_sessions =
+            restored.get_sessions(&session.sender_key.to_base64()).await.unwrap().unwrap();
+        assert_eq!(loaded_sessions, vec![session]);
+
+        let mut loaded_inbound = restored.get_inbound_group_sessions().await.unwrap();
+        loaded_inbound.sort_by_key(|s| s.session_id().to_owned());
+        assert_eq!(loaded_inbound, sessions);
+        assert_eq!(backed_up_tos(&restored).await, backed_up_tos(&store).await);
+
+        let loaded_outbound = restored.get_outbound_group_session(room_id).await.unwrap().unwrap();
+        assert_eq!(loaded_outbound.session_id(), outbound.session_id());
+
+        assert_eq!(restored.get_custom_value("custom").await.unwrap().unwrap(), b"value");
+        assert_eq!(
+            restored.load_change_journal(0).await.unwrap(),
+            store.load_change_journal(0).await.unwrap()
+        );
+    }
+
+    #[async_test]
+    async fn test_fixture_round_trip() {
+        // Given a store with an account, an olm session, and some room keys
+        let (account, session) = get_account_and_session_test_helper();
+        let room_id = room_id!("!test:localhost");
+        let (store, sessions) = store_with_sessions(3, room_id).await;
+        store.save_pending_changes(PendingChanges { account: Some(account) }).await.unwrap();
+        store
+            .save_changes(Changes { sessions: vec![session.clone()], ..Default::default() })
+            .await
+            .unwrap();
+
+        // When we export it as a fixture, the fixture is deterministic
+        let fixture = store.export_fixture().await.unwrap();
+        assert_eq!(store.export_fixture().await.unwrap(), fixture);
+
+        // And importing the fixture restores the same data
+        let restored = MemoryStore::import_fixture(&fixture).unwrap();
+        assert_eq!(restored.export_fixture().await.unwrap(), fixture);
+
+        assert!(restored.load_account().await.unwrap().is_some());
+        let loaded_sessions =
+            restored.get_sessions(&session.sender_key.to_base64()).await.unwrap().unwrap();
+        assert_eq!(loaded_sessions, vec![session]);
+
+        let mut loaded_inbound = restored.get_inbound_group_sessions().await.unwrap();
+        loaded_inbound.sort_by_key(|s| s.session_id().to_owned());
+        assert_eq!(loaded_inbound, sessions);
+    }
+
+    #[async_test]
+    async fn test_leased_lock_uses_clock() {
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch(uint!(1_000_000)));
+        let store = MemoryStore::with_clock(Arc::new(clock.clone()));
+
+        assert!(store.try_take_leased_lock(500, "key", "alice").await.unwrap());
+        assert!(!store.try_take_leased_lock(500, "key", "bob").await.unwrap());
+
+        // The lease of Alice expires once the clock moves past it
+        clock.advance(Duration::from_millis(501));
+        assert!(store.try_take_leased_lock(500, "key", "bob").await.unwrap());
+        assert!(!store.try_take_leased_lock(500, "key", "alice").await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_leased_lock_uses_clock_of_machine() {
+        let store = Arc::new(MemoryStore::new());
+        let machine = OlmMachine::with_store(
+            user_id!("@alice:localhost"),
+            device_id!("ALICE"),
+            store.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch(uint!(1_000_000)));
+        machine.set_clock(Arc::new(clock.clone()));
+
+        assert!(store.try_take_leased_lock(500, "key", "alice").await.unwrap());
+        assert!(!store.try_take_leased_lock(500, "key", "bob").await.unwrap());
+
+        // The clock of the machine was handed down to the store.
+        clock.advance(Duration::from_millis(501));
+        assert!(store.try_take_leased_lock(500, "key", "bob").await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_undecodable_inbound_group_sessions_are_quarantined() {
+        let room_id = room_id!("!test:localhost");
+        let (store, sessions) = store_with_sessions(2, room_id).await;
+
+        store
+            .inbound_group_sessions
+            .write()
+            .get_mut(room_id)
+            .unwrap()
+            .insert("corrupted".to_owned(), "not a pickle".into());
+
+        // The undecodable session breaks loading the sessions.
+        store.get_inbound_group_sessions().await.unwrap_err();
+
+        let quarantined = store.quarantine_undecodable_inbound_group_sessions().await.unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].kind, QuarantinedEntryKind::InboundGroupSession);
+        assert_eq!(quarantined[0].value.expose_secret(), b"not a pickle");
+
+        // The quarantined entries are remembered, and the remaining sessions can be
+        // loaded.
+        assert_eq!(store.get_quarantined_entries().await.unwrap(), quarantined);
+        let mut loaded = store.get_inbound_group_sessions().await.unwrap();
+        loaded.sort_by_key(|s| s.session_id().to_owned());
+        assert_eq!(loaded, sessions);
+
+        // Running the quarantine again doesn't find anything new.
+        assert!(store.quarantine_undecodable_inbound_group_sessions().await.unwrap().is_empty());
+        assert_eq!(store.get_quarantined_entries().await.unwrap().len(), 1);
+
+        // Restoring the entry puts it back where it was found.
+        assert_eq!(store.restore_quarantined_entries().await.unwrap(), 1);
+        assert!(store.get_quarantined_entries().await.unwrap().is_empty());
+        store.get_inbound_group_sessions().await.unwrap_err();
+        assert_eq!(
+            store.quarantine_undecodable_inbound_group_sessions().await.unwrap(),
+            quarantined
+        );
+    }
+
+    #[async_test]
+    async fn test_encrypted_snapshot_with_wrong_passphrase() {
+        let store = MemoryStore::new();
+        let snapshot = store.export_encrypted("passphrase").await.unwrap();
+
+        MemoryStore::import_encrypted("wrong passphrase", &snapshot)
+            .expect_err("Importing a snapshot with the wrong passphrase should fail");
+    }
+
+    #[test]
+    fn test_encrypted_snapshot_with_unknown_version() {
+        let snapshot = rmp_serde::to_vec_named(&EncryptedSnapshot {
+            version: 2,
+            cipher: Vec::new(),
+            ciphertext: Vec::new(),
+        })
+        .unwrap();
+
+        assert_matches!(
+            MemoryStore::import_encrypted("passphrase", &snapshot),
+            Err(CryptoStoreError::UnsupportedDatabaseVersion(2, 1))
+        );
+    }
+
     /// Mark the supplied sessions as backed up in the supplied backup version
     async fn mark_backed_up(
         store: &MemoryStore,
@@ -1239,12 +2341,14 @@ mod integration_tests {
 
     use async_trait::async_trait;
     use ruma::{
-        events::secret::request::SecretName, DeviceId, OwnedDeviceId, RoomId, TransactionId, UserId,
+        events::secret::request::SecretName, DeviceId, EventId, OwnedDeviceId, OwnedEventId,
+        OwnedRoomId, RoomId, SecondsSinceUnixEpoch, TransactionId, UserId,
     };
     use vodozemac::Curve25519PublicKey;
 
     use super::MemoryStore;
     use crate::{
+        clock::Clock,
         cryptostore_integration_tests, cryptostore_integration_tests_time,
         olm::{
             InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
@@ -1252,8 +2356,10 @@ mod integration_tests {
         },
         store::{
             types::{
-                BackupKeys, Changes, DehydratedDeviceKey, PendingChanges, RoomKeyCounts,
-                RoomSettings, StoredRoomKeyBundleData, TrackedUser,
+                BackupKeys, ChangeJournalRecord, Changes, CompactionReport, DehydratedDeviceKey,
+                DeletedRoomCryptoState, PendingChanges, QuarantinedEntry, RoomKeyCounts,
+                RoomKeySummary, RoomSettings, SessionShareRecord, ShredReport,
+                StoredRoomKeyBundleData, TrackedUser, VerificationAuditEntry,
             },
             CryptoStore,
         },
@@ -1341,6 +2447,18 @@ mod integration_tests {
             self.0.get_sessions(sender_key).await
         }
 
+        async fn get_session_sender_keys(&self) -> Result<Vec<String>, Self::Error> {
+            self.0.get_session_sender_keys().await
+        }
+
+        async fn delete_sessions(
+            &self,
+            sender_key: &str,
+            session_ids: &[String],
+        ) -> Result<(), Self::Error> {
+            self.0.delete_sessions(sender_key, session_ids).await
+        }
+
         async fn get_inbound_group_session(
             &self,
             room_id: &RoomId,
@@ -1357,12 +2475,52 @@ mod integration_tests {
             self.0.get_withheld_info(room_id, session_id).await
         }
 
+        async fn record_megolm_message_index(
+            &self,
+            room_id: &RoomId,
+            session_id: &str,
+            message_index: u32,
+            event_id: &EventId,
+        ) -> Result<Option<OwnedEventId>, Self::Error> {
+            self.0.record_megolm_message_index(room_id, session_id, message_index, event_id).await
+        }
+
+        async fn get_session_share_history(
+            &self,
+            room_id: &RoomId,
+            session_id: &str,
+        ) -> Result<Vec<SessionShareRecord>, Self::Error> {
+            self.0.get_session_share_history(room_id, session_id).await
+        }
+
         async fn get_inbound_group_sessions(
             &self,
         ) -> Result<Vec<InboundGroupSession>, Self::Error> {
             self.0.get_inbound_group_sessions().await
         }
 
+        async fn quarantine_undecodable_inbound_group_sessions(
+            &self,
+        ) -> Result<Vec<QuarantinedEntry>, Self::Error> {
+            self.0.quarantine_undecodable_inbound_group_sessions().await
+        }
+
+        async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>, Self::Error> {
+            self.0.get_quarantined_entries().await
+        }
+
+        async fn restore_quarantined_entries(&self) -> Result<usize, Self::Error> {
+            self.0.restore_quarantined_entries().await
+        }
+
+        async fn delete_inbound_group_sessions(
+            &self,
+            room_id: &RoomId,
+            session_ids: &[String],
+        ) -> Result<(), Self::Error> {
+            self.0.delete_inbound_group_sessions(room_id, session_ids).await
+        }
+
         async fn inbound_group_session_counts(
             &self,
             backup_version: Option<&str>,
@@ -1370,6 +2528,14 @@ mod integration_tests {
             self.0.inbound_group_session_counts(backup_version).await
         }
 
+        async fn get_inbound_group_sessions_batch(
+            &self,
+            after: Option<(OwnedRoomId, String)>,
+            limit: usize,
+        ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+            self.0.get_inbound_group_sessions_batch(after, limit).await
+        }
+
         async fn get_inbound_group_sessions_for_device_batch(
             &self,
             sender_key: Curve25519PublicKey,
@@ -1387,6 +2553,18 @@ mod integration_tests {
                 .await
         }
 
+        async fn get_inbound_group_sessions_for_room(
+            &self,
+            room_id: &RoomId,
+            since: Option<SecondsSinceUnixEpoch>,
+        ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+            self.0.get_inbound_group_sessions_for_room(room_id, since).await
+        }
+
+        async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>, Self::Error> {
+            self.0.get_room_key_summaries().await
+        }
+
         async fn inbound_group_sessions_for_backup(
             &self,
             backup_version: &str,
@@ -1413,6 +2591,10 @@ mod integration_tests {
             self.0.load_backup_keys().await
         }
 
+        async fn delete_backup_decryption_key(&self) -> Result<(), Self::Error> {
+            self.0.delete_backup_decryption_key().await
+        }
+
         async fn load_dehydrated_device_pickle_key(
             &self,
         ) -> Result<Option<DehydratedDeviceKey>, Self::Error> {
@@ -1438,6 +2620,14 @@ mod integration_tests {
             self.0.save_tracked_users(users).await
         }
 
+        async fn load_tracked_users_paged(
+            &self,
+            after: Option<&UserId>,
+            limit: usize,
+        ) -> Result<Vec<TrackedUser>, Self::Error> {
+            self.0.load_tracked_users_paged(after, limit).await
+        }
+
         async fn get_device(
             &self,
             user_id: &UserId,
@@ -1464,6 +2654,13 @@ mod integration_tests {
             self.0.get_user_identity(user_id).await
         }
 
+        async fn get_verification_audit_log(
+            &self,
+            user_id: &UserId,
+        ) -> Result<Vec<VerificationAuditEntry>, Self::Error> {
+            self.0.get_verification_audit_log(user_id).await
+        }
+
         async fn is_message_known(
             &self,
             message_hash: &OlmMessageHash,
@@ -1537,6 +2734,18 @@ mod integration_tests {
             self.0.remove_custom_value(key).await
         }
 
+        async fn get_app_data(
+            &self,
+            namespace: &str,
+            key: &str,
+        ) -> Result<Option<Vec<u8>>, Self::Error> {
+            self.0.get_app_data(namespace, key).await
+        }
+
+        async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>, Self::Error> {
+            self.0.get_app_data_keys(namespace).await
+        }
+
         async fn try_take_leased_lock(
             &self,
             lease_duration_ms: u32,
@@ -1546,9 +2755,40 @@ mod integration_tests {
             self.0.try_take_leased_lock(lease_duration_ms, key, holder).await
         }
 
+        fn set_clock(&self, clock: Arc<dyn Clock>) {
+            self.0.set_clock(clock)
+        }
+
         async fn next_batch_token(&self) -> Result<Option<String>, Self::Error> {
             self.0.next_batch_token().await
         }
+
+        async fn compact(&self) -> Result<CompactionReport, Self::Error> {
+            self.0.compact().await
+        }
+
+        async fn load_change_journal(
+            &self,
+            since: u64,
+        ) -> Result<Vec<ChangeJournalRecord>, Self::Error> {
+            self.0.load_change_journal(since).await
+        }
+
+        async fn truncate_change_journal(&self, up_to: u64) -> Result<(), Self::Error> {
+            self.0.truncate_change_journal(up_to).await
+        }
+
+        async fn shred(&self) -> Result<ShredReport, Self::Error> {
+            self.0.shred().await
+        }
+
+        async fn delete_room_crypto_state(
+            &self,
+            room_id: &RoomId,
+            custom_value_keys: &[String],
+        ) -> Result<DeletedRoomCryptoState, Self::Error> {
+            self.0.delete_room_crypto_state(room_id, custom_value_keys).await
+        }
     }
 
     cryptostore_integration_tests!();
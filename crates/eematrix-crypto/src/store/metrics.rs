@@ -0,0 +1,77 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, time::Duration};
+
+use matrix_sdk_common::{SendOutsideWasm, SyncOutsideWasm};
+
+/// A crypto store operation whose latency is reported to a
+/// [`StoreMetricsObserver`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum StoreOperation {
+    /// A set of changes was saved to the store.
+    SaveChanges,
+
+    /// The Olm sessions of a sender key were loaded from the store, because
+    /// they weren't cached yet.
+    GetSessions,
+
+    /// A device was loaded from the store.
+    GetDevice,
+}
+
+/// An in-memory cache in front of the crypto store, whose hit rate is
+/// reported to a [`StoreMetricsObserver`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum StoreCacheKind {
+    /// The cache holding the Olm sessions, by sender key.
+    Sessions,
+
+    /// The cache holding the plaintext of decrypted room events, by event ID.
+    DecryptedEvents,
+}
+
+/// An observer that gets notified about the operations of the crypto store,
+/// for example to export them as metrics.
+///
+/// The observer can be installed using
+/// [`OlmMachine::set_store_metrics_observer()`].
+///
+/// The methods are called inline while the store is being used, so they
+/// should return quickly, e.g. by only incrementing some counters.
+///
+/// [`OlmMachine::set_store_metrics_observer()`]: crate::OlmMachine::set_store_metrics_observer
+pub trait StoreMetricsObserver: fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// Called when a store operation has completed.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The operation that was performed.
+    ///
+    /// * `duration` - How long the operation took.
+    ///
+    /// * `succeeded` - Whether the operation succeeded or returned an error.
+    fn on_operation(&self, operation: StoreOperation, duration: Duration, succeeded: bool);
+
+    /// Called when a value was looked up in one of the in-memory caches.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - The cache that was used.
+    ///
+    /// * `hit` - Whether the value was found in the cache.
+    fn on_cache_lookup(&self, cache: StoreCacheKind, hit: bool);
+}
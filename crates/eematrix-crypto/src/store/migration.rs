@@ -0,0 +1,500 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copying the content of one crypto store into another one, see
+//! [`migrate_store()`].
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use vodozemac::base64_encode;
+
+use super::{
+    types::{Changes, DeviceChanges, IdentityChanges, PendingChanges},
+    CryptoStoreError, DynCryptoStore, IntoCryptoStore, Result,
+};
+use crate::olm::Session;
+
+/// The default number of items [`migrate_store()`] copies at once.
+const DEFAULT_CHUNK_SIZE: usize = 1000;
+
+/// The options of a [`migrate_store()`] call.
+#[derive(Clone, Debug)]
+pub struct MigrationOptions {
+    /// The maximum number of items that are read from the source store, and
+    /// written to the destination store, at once.
+    pub chunk_size: usize,
+
+    /// Only compare the two stores and report what would be copied, without
+    /// writing anything to the destination store.
+    pub dry_run: bool,
+}
+
+impl Default for MigrationOptions {
+    fn default() -> Self {
+        Self { chunk_size: DEFAULT_CHUNK_SIZE, dry_run: false }
+    }
+}
+
+/// The kind of data a [`migrate_store()`] call is copying.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MigrationStage {
+    /// The Olm account, our own device, the private cross-signing keys and
+    /// the backup keys.
+    Account,
+
+    /// The Olm sessions.
+    Sessions,
+
+    /// The Megolm sessions which are used to decrypt room events.
+    InboundGroupSessions,
+
+    /// The tracked users, together with their devices and identities.
+    TrackedUsers,
+}
+
+/// The progress of a [`migrate_store()`] call, passed to its progress
+/// listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MigrationProgress {
+    /// The kind of data which is being copied.
+    pub stage: MigrationStage,
+
+    /// The number of items of the current stage that were processed so far.
+    pub processed: usize,
+
+    /// The total number of items of the current stage.
+    pub total: usize,
+}
+
+/// An item that exists in both stores, but whose content differs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationMismatch {
+    /// The kind of the item.
+    pub stage: MigrationStage,
+
+    /// A human readable identifier of the item, e.g. the session ID.
+    pub id: String,
+}
+
+/// The result of a [`migrate_store()`] call.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// The number of Olm sessions that were copied.
+    pub sessions: usize,
+
+    /// The number of Megolm sessions that were copied.
+    pub inbound_group_sessions: usize,
+
+    /// The number of tracked users that were copied.
+    pub tracked_users: usize,
+
+    /// The number of devices that were copied.
+    pub devices: usize,
+
+    /// The number of user identities that were copied.
+    pub identities: usize,
+
+    /// The items which already existed in the destination store with a
+    /// different content.
+    ///
+    /// A real migration overwrites these items, a dry run can be used to find
+    /// them beforehand.
+    pub mismatches: Vec<MigrationMismatch>,
+}
+
+/// Copy the content of the `from` store into the `to` store.
+///
+/// This can be used to move the crypto state of a device to another store
+/// backend. The items are copied in chunks of
+/// [`MigrationOptions::chunk_size`] items, so the stores don't need to hold
+/// everything in memory at once.
+///
+/// The migration copies the Olm account, the private cross-signing keys, the
+/// backup keys, the Olm and Megolm sessions and the tracked users with their
+/// devices and identities. Outbound group sessions aren't copied, they will
+/// be rotated instead.
+///
+/// If [`MigrationOptions::dry_run`] is set, nothing is written to the `to`
+/// store. The returned [`MigrationReport`] then contains the number of items
+/// that would be copied, and the items which exist in both stores with a
+/// different content.
+///
+/// Returns an error if the `from` store doesn't contain an account, or if the
+/// `to` store contains the account of another device. A real migration also
+/// refuses to overwrite an account of the same device with different identity
+/// keys, before anything is written to the `to` store.
+///
+/// # Arguments
+///
+/// * `from` - The store the data is copied from.
+///
+/// * `to` - The store the data is copied to.
+///
+/// * `options` - The options of the migration.
+///
+/// * `progress_listener` - A closure that will be called after every chunk
+///   that was processed.
+pub async fn migrate_store(
+    from: impl IntoCryptoStore,
+    to: impl IntoCryptoStore,
+    options: MigrationOptions,
+    progress_listener: impl Fn(MigrationProgress),
+) -> Result<MigrationReport> {
+    let from = from.into_crypto_store();
+    let to = to.into_crypto_store();
+
+    let mut migration = Migration {
+        from: &from,
+        to: &to,
+        chunk_size: options.chunk_size.max(1),
+        dry_run: options.dry_run,
+        progress_listener: &progress_listener,
+        compare: false,
+        report: MigrationReport::default(),
+    };
+
+    migration.migrate_account().await?;
+    migration.migrate_sessions().await?;
+    migration.migrate_inbound_group_sessions().await?;
+    migration.migrate_tracked_users().await?;
+
+    let report = migration.report;
+
+    if report.mismatches.is_empty() {
+        info!(dry_run = options.dry_run, ?report, "Migrated the crypto store");
+    } else {
+        warn!(dry_run = options.dry_run, ?report, "Migrated the crypto store, with mismatches");
+    }
+
+    Ok(report)
+}
+
+/// The state of a running [`migrate_store()`] call.
+struct Migration<'a, F: Fn(MigrationProgress)> {
+    from: &'a DynCryptoStore,
+    to: &'a DynCryptoStore,
+    chunk_size: usize,
+    dry_run: bool,
+    progress_listener: &'a F,
+    /// Whether the `to` store already contains our account, in which case the
+    /// items are compared against the ones in the `to` store.
+    compare: bool,
+    report: MigrationReport,
+}
+
+impl<F: Fn(MigrationProgress)> Migration<'_, F> {
+    fn report_progress(&self, stage: MigrationStage, processed: usize, total: usize) {
+        (self.progress_listener)(MigrationProgress { stage, processed, total });
+    }
+
+    fn add_mismatch(&mut self, stage: MigrationStage, id: String) {
+        self.report.mismatches.push(MigrationMismatch { stage, id });
+    }
+
+    async fn migrate_account(&mut self) -> Result<()> {
+        let account = self.from.load_account().await?.ok_or(CryptoStoreError::AccountUnset)?;
+
+        if let Some(existing) = self.to.load_account().await? {
+            if existing.user_id() != account.user_id()
+                || existing.device_id() != account.device_id()
+            {
+                return Err(CryptoStoreError::MismatchedAccount {
+                    expected: (account.user_id().to_owned(), account.device_id().to_owned()),
+                    got: (existing.user_id().to_owned(), existing.device_id().to_owned()),
+                });
+            }
+
+            if existing.identity_keys().curve25519 != account.identity_keys().curve25519
+                || existing.identity_keys().ed25519 != account.identity_keys().ed25519
+            {
+                // Overwriting the account would lose its private identity keys,
+                // don't touch the `to` store unless we're only looking.
+                if !self.dry_run {
+                    return Err(CryptoStoreError::MismatchedIdentityKeys);
+                }
+
+                self.add_mismatch(MigrationStage::Account, account.device_id().to_string());
+            }
+
+            self.compare = true;
+        }
+
+        if !self.dry_run {
+            let own_device = self.from.get_own_device().await?;
+            let identity = self.from.load_identity().await?;
+            let backup_keys = self.from.load_backup_keys().await?;
+
+            self.to.save_pending_changes(PendingChanges { account: Some(account) }).await?;
+            self.to
+                .save_changes(Changes {
+                    private_identity: identity,
+                    backup_version: backup_keys.backup_version,
+                    backup_decryption_key: backup_keys.decryption_key,
+                    devices: DeviceChanges { new: vec![own_device], ..Default::default() },
+                    ..Default::default()
+                })
+                .await?;
+        }
+
+        self.report_progress(MigrationStage::Account, 1, 1);
+
+        Ok(())
+    }
+
+    async fn migrate_sessions(&mut self) -> Result<()> {
+        let sender_keys = self.from.get_session_sender_keys().await?;
+        let mut total = 0;
+        let mut chunk = Vec::new();
+
+        for sender_key in &sender_keys {
+            total += self.from.get_sessions(sender_key).await?.map_or(0, |s| s.len());
+        }
+
+        for sender_key in &sender_keys {
+            let sessions = self.from.get_sessions(sender_key).await?.unwrap_or_default();
+
+            if self.compare {
+                let existing = self.to.get_sessions(sender_key).await?.unwrap_or_default();
+
+                for session in &sessions {
+                    let other = existing.iter().find(|s| s.session_id() == session.session_id());
+
+                    if let Some(other) = other {
+                        if checksum(&session.pickle().await)? != checksum(&other.pickle().await)? {
+                            self.add_mismatch(
+                                MigrationStage::Sessions,
+                                session.session_id().to_owned(),
+                            );
+                        }
+                    }
+                }
+            }
+
+            self.report.sessions += sessions.len();
+            chunk.extend(sessions);
+
+            if chunk.len() >= self.chunk_size {
+                self.save_sessions(std::mem::take(&mut chunk)).await?;
+                self.report_progress(MigrationStage::Sessions, self.report.sessions, total);
+            }
+        }
+
+        self.save_sessions(chunk).await?;
+        self.report_progress(MigrationStage::Sessions, total, total);
+
+        Ok(())
+    }
+
+    async fn save_sessions(&self, sessions: Vec<Session>) -> Result<()> {
+        if self.dry_run || sessions.is_empty() {
+            Ok(())
+        } else {
+            self.to.save_changes(Changes { sessions, ..Default::default() }).await
+        }
+    }
+
+    async fn migrate_inbound_group_sessions(&mut self) -> Result<()> {
+        let total = self.from.inbound_group_session_counts(None).await?.total;
+        let mut after = None;
+
+        loop {
+            let sessions =
+                self.from.get_inbound_group_sessions_batch(after.take(), self.chunk_size).await?;
+
+            let Some(last) = sessions.last() else {
+                break;
+            };
+            after = Some((last.room_id().to_owned(), last.session_id().to_owned()));
+
+            if self.compare {
+                for session in &sessions {
+                    let existing = self
+                        .to
+                        .get_inbound_group_session(session.room_id(), session.session_id())
+                        .await?;
+
+                    if let Some(existing) = existing {
+                        if checksum(&session.pickle().await)? != checksum(&existing.pickle().await)?
+                        {
+                            self.add_mismatch(
+                                MigrationStage::InboundGroupSessions,
+                                session.session_id().to_owned(),
+                            );
+                        }
+                    }
+                }
+            }
+
+            self.report.inbound_group_sessions += sessions.len();
+
+            if !self.dry_run {
+                self.to
+                    .save_changes(Changes {
+                        inbound_group_sessions: sessions,
+                        ..Default::default()
+                    })
+                    .await?;
+            }
+
+            self.report_progress(
+                MigrationStage::InboundGroupSessions,
+                self.report.inbound_group_sessions,
+                total,
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn migrate_tracked_users(&mut self) -> Result<()> {
+        let tracked_users = self.from.load_tracked_users().await?;
+        let total = tracked_users.len();
+
+        for chunk in tracked_users.chunks(self.chunk_size) {
+            let mut devices = DeviceChanges::default();
+            let mut identities = IdentityChanges::default();
+
+            for user in chunk {
+                devices.new.extend(self.from.get_user_devices(&user.user_id).await?.into_values());
+                identities.new.extend(self.from.get_user_identity(&user.user_id).await?);
+            }
+
+            self.report.tracked_users += chunk.len();
+            self.report.devices += devices.new.len();
+            self.report.identities += identities.new.len();
+
+            if !self.dry_run {
+                let users: Vec<_> =
+                    chunk.iter().map(|user| (user.user_id.as_ref(), user.dirty)).collect();
+
+                self.to.save_changes(Changes { devices, identities, ..Default::default() }).await?;
+                self.to.save_tracked_users(&users).await?;
+            }
+
+            self.report_progress(MigrationStage::TrackedUsers, self.report.tracked_users, total);
+        }
+
+        Ok(())
+    }
+}
+
+/// Calculate a checksum of the serialized form of the given value, used to
+/// detect items whose content differs between the two stores.
+fn checksum(value: &impl Serialize) -> Result<String> {
+    let serialized = serde_json::to_vec(value)?;
+    Ok(base64_encode(Sha256::digest(serialized)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use assert_matches2::assert_matches;
+    use matrix_sdk_test::async_test;
+    use ruma::{device_id, room_id, user_id};
+
+    use super::{migrate_store, MigrationOptions, MigrationProgress, MigrationStage};
+    use crate::{
+        store::{CryptoStore, CryptoStoreError, MemoryStore},
+        OlmMachine,
+    };
+
+    #[async_test]
+    async fn test_migrate_store() {
+        let from = Arc::new(MemoryStore::new());
+        let machine = OlmMachine::with_store(
+            user_id!("@alice:localhost"),
+            device_id!("ALICEDEVICE"),
+            from.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+        machine
+            .create_outbound_group_session_with_defaults_test_helper(room_id!("!room:localhost"))
+            .await
+            .unwrap();
+        machine.update_tracked_users([user_id!("@bob:localhost")]).await.unwrap();
+        let tracked_user_count = from.load_tracked_users().await.unwrap().len();
+
+        // A dry run reports what would be copied, without writing anything.
+        let to = Arc::new(MemoryStore::new());
+        let options = MigrationOptions { chunk_size: 1, dry_run: true };
+        let dry_run_report =
+            migrate_store(from.clone(), to.clone(), options.clone(), |_| {}).await.unwrap();
+
+        assert_eq!(dry_run_report.inbound_group_sessions, 1);
+        assert_eq!(dry_run_report.tracked_users, tracked_user_count);
+        assert!(dry_run_report.mismatches.is_empty());
+        assert!(to.load_account().await.unwrap().is_none());
+
+        // The real migration copies the data, and reports its progress.
+        let progress = Mutex::new(Vec::new());
+        let options = MigrationOptions { dry_run: false, ..options };
+        let report = migrate_store(from.clone(), to.clone(), options.clone(), |p| {
+            progress.lock().unwrap().push(p)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(report, dry_run_report);
+        assert!(progress.lock().unwrap().contains(&MigrationProgress {
+            stage: MigrationStage::InboundGroupSessions,
+            processed: 1,
+            total: 1,
+        }));
+
+        assert_eq!(
+            to.load_account().await.unwrap().unwrap().identity_keys().curve25519,
+            machine.identity_keys().curve25519
+        );
+        assert_eq!(to.get_inbound_group_sessions().await.unwrap().len(), 1);
+        assert_eq!(to.load_tracked_users().await.unwrap().len(), tracked_user_count);
+
+        // Once migrated, the stores contain the same data.
+        let options = MigrationOptions { dry_run: true, ..options };
+        let report = migrate_store(from, to, options, |_| {}).await.unwrap();
+        assert!(report.mismatches.is_empty());
+    }
+
+    #[async_test]
+    async fn test_migrate_store_with_mismatched_identity_keys() {
+        let user_id = user_id!("@alice:localhost");
+        let device_id = device_id!("ALICEDEVICE");
+
+        let from = Arc::new(MemoryStore::new());
+        OlmMachine::with_store(user_id, device_id, from.clone(), None).await.unwrap();
+
+        let to = Arc::new(MemoryStore::new());
+        let existing = OlmMachine::with_store(user_id, device_id, to.clone(), None).await.unwrap();
+
+        // A dry run reports the mismatch.
+        let options = MigrationOptions { dry_run: true, ..Default::default() };
+        let report = migrate_store(from.clone(), to.clone(), options, |_| {}).await.unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].stage, MigrationStage::Account);
+
+        // A real migration refuses to overwrite the account.
+        assert_matches!(
+            migrate_store(from, to.clone(), MigrationOptions::default(), |_| {}).await,
+            Err(CryptoStoreError::MismatchedIdentityKeys)
+        );
+        assert_eq!(
+            to.load_account().await.unwrap().unwrap().identity_keys().curve25519,
+            existing.identity_keys().curve25519
+        );
+    }
+}
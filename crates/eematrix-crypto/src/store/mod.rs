@@ -39,74 +39,126 @@
 //! [`CryptoStore`]: trait.Cryptostore.html
 
 use std::{
+    cmp::Reverse,
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
     ops::Deref,
     pin::pin,
-    sync::{atomic::Ordering, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use as_variant::as_variant;
 use futures_core::Stream;
-use futures_util::StreamExt;
+use futures_util::{stream, StreamExt, TryStreamExt};
 use itertools::{Either, Itertools};
+use matrix_sdk_common::locks::RwLock as StdRwLock;
 use ruma::{
-    encryption::KeyUsage, events::secret::request::SecretName, DeviceId, OwnedDeviceId,
-    OwnedUserId, RoomId, UserId,
+    api::client::keys::upload_signatures::v3::{Request as UploadSignaturesRequest, SignedKeys},
+    encryption::KeyUsage,
+    events::secret::request::SecretName,
+    DeviceId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId,
+    SecondsSinceUnixEpoch, TransactionId, UInt, UserId,
 };
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tokio::sync::{Mutex, Notify, OwnedRwLockWriteGuard, RwLock};
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tracing::{error, info, instrument, trace, warn};
 use types::RoomKeyBundleInfo;
-use vodozemac::{megolm::SessionOrdering, Curve25519PublicKey};
+use vodozemac::{base64_encode, megolm::SessionOrdering, Curve25519PublicKey};
 
 use self::types::{
-    Changes, CrossSigningKeyExport, DeviceChanges, DeviceUpdates, IdentityChanges, IdentityUpdates,
-    PendingChanges, RoomKeyInfo, RoomKeyWithheldInfo, UserKeyQueryResult,
+    BackupKeys, Changes, CrossSigningKeyExport, CryptoStatistics, DeletedRoomCryptoState,
+    DeviceChanges, DeviceListDiff, DeviceListSnapshot, DeviceSnapshot, DeviceUpdates,
+    FullStateExport, IdentityChanges, IdentityDispute, IdentityUpdates, KeyQueryPriority,
+    OwnDeviceChange, PendingChanges, PinViolation, QuarantinedEntry, RoomKeyBundleAcceptancePolicy,
+    RoomKeyBundleImportResult, RoomKeyBundleOptions, RoomKeyBundleSenderPermissions, RoomKeyInfo,
+    RoomKeyRetentionPolicy, RoomKeySkipReason, RoomKeySummary, RoomKeyWithheldInfo,
+    RoomRotationPolicy, RoomSettings, SessionPruningPolicy, SessionShareRecord, ShredReport,
+    StoredRoomKeyBundleData, UserKeyQueryResult, VerificationAuditEntry,
 };
 #[cfg(doc)]
-use crate::{backups::BackupMachine, identities::OwnUserIdentity};
+use crate::backups::BackupMachine;
 use crate::{
+    clock::Clock,
     gossiping::GossippedSecret,
-    identities::{user::UserIdentity, Device, DeviceData, UserDevices, UserIdentityData},
+    identities::{
+        user::UserIdentity, Device, DeviceData, OwnUserIdentity, UserDevices, UserIdentityData,
+    },
     olm::{
-        Account, ExportedRoomKey, InboundGroupSession, PrivateCrossSigningIdentity, SenderData,
-        Session, StaticAccountData,
+        Account, CrossSigningBackends, ExportedRoomKey, InboundGroupSession,
+        PrivateCrossSigningIdentity, SenderData, SenderDataType, Session, StaticAccountData,
     },
     types::{
-        BackupSecrets, CrossSigningSecrets, MegolmBackupV1Curve25519AesSha2Secrets, RoomKeyExport,
-        SecretsBundle,
+        events::room_key_bundle::RoomKeyBundleContent, BackupSecrets, CrossSigningSecrets,
+        RoomKeyExport, SecretString, SecretsBundle,
     },
     verification::VerificationMachine,
-    CrossSigningStatus, OwnUserIdentityData, RoomKeyImportResult,
+    CollectStrategy, CrossSigningStatus, LocalTrust, OwnUserIdentityData, RoomKeyImportResult,
 };
 
+mod app_data;
 pub mod caches;
+mod caching_store;
+mod cipher;
 mod crypto_store_wrapper;
 mod error;
 mod memorystore;
+mod metrics;
+mod migration;
+mod rate_limiter;
+mod read_only;
+mod secret_storage;
+mod session_spam;
+mod statistics;
 mod traits;
 pub mod types;
+mod value_codec;
 
 #[cfg(any(test, feature = "testing"))]
 #[macro_use]
 #[allow(missing_docs)]
 pub mod integration_tests;
 
-pub(crate) use crypto_store_wrapper::CryptoStoreWrapper;
+pub use app_data::AppData;
+pub use caching_store::{CachingStore, CachingStoreLimits};
+pub use cipher::{DefaultStoreCipher, StoreCipher};
+pub(crate) use crypto_store_wrapper::{CryptoStoreWrapper, CURRENT_GENERATION_STORE_KEY};
 pub use error::{CryptoStoreError, Result};
 use matrix_sdk_common::{
     deserialized_responses::WithheldCode, store_locks::CrossProcessStoreLock, timeout::timeout,
 };
 pub use memorystore::MemoryStore;
+pub use metrics::{StoreCacheKind, StoreMetricsObserver, StoreOperation};
+pub use migration::{
+    migrate_store, MigrationMismatch, MigrationOptions, MigrationProgress, MigrationReport,
+    MigrationStage,
+};
+pub(crate) use rate_limiter::RateLimiter;
+pub use rate_limiter::{RateLimit, RateLimitedError, RateLimitedRequest, RateLimits};
+pub use read_only::{
+    AccountDump, DeviceDump, IdentityDump, InboundGroupSessionDump, ReadOnlyStore, SessionDump,
+    StoreDump,
+};
+pub use secret_storage::SecretStorageBackend;
+pub(crate) use session_spam::SessionSpamDetector;
+pub use session_spam::{SessionCreationLimit, SuspiciousActivity};
+use statistics::StatisticsRecorder;
 pub use traits::{CryptoStore, DynCryptoStore, IntoCryptoStore};
+pub use value_codec::ValueCodec;
 
-use self::caches::{SequenceNumber, StoreCache, StoreCacheGuard, UsersForKeyQuery};
+use self::caches::{
+    SequenceNumber, StoreCache, StoreCacheGuard, UnreachableDevices, UsersForKeyQuery,
+};
 use crate::types::{
-    events::room_key_withheld::RoomKeyWithheldContent, room_history::RoomKeyBundle,
+    events::room_key_withheld::{RoomKeyWithheldContent, RoomKeyWithheldEvent},
+    requests::ToDeviceRequest,
+    room_history::RoomKeyBundle,
 };
 pub use crate::{
     dehydrated_devices::DehydrationError,
@@ -124,6 +176,14 @@ pub struct Store {
     inner: Arc<StoreInner>,
 }
 
+/// The default maximum number of users a single `/keys/query` request
+/// contains.
+const DEFAULT_KEY_QUERY_BATCH_SIZE: usize = 250;
+
+/// The number of tracked users we load from the store at once when filling
+/// out our caches.
+const TRACKED_USERS_PAGE_SIZE: usize = 1000;
+
 #[derive(Debug, Default)]
 pub(crate) struct KeyQueryManager {
     /// Record of the users that are waiting for a /keys/query.
@@ -131,9 +191,25 @@ pub(crate) struct KeyQueryManager {
 
     /// Notifier that is triggered each time an update is received for a user.
     users_for_key_query_notify: Notify,
+
+    /// The maximum number of users a single `/keys/query` request contains,
+    /// if it was changed from the default.
+    batch_size: StdRwLock<Option<usize>>,
 }
 
 impl KeyQueryManager {
+    /// Get the maximum number of users a single `/keys/query` request
+    /// contains.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.read().unwrap_or(DEFAULT_KEY_QUERY_BATCH_SIZE)
+    }
+
+    /// Set the maximum number of users a single `/keys/query` request
+    /// contains.
+    pub fn set_batch_size(&self, batch_size: usize) {
+        *self.batch_size.write() = Some(batch_size.max(1));
+    }
+
     pub async fn synced<'a>(&'a self, cache: &'a StoreCache) -> Result<SyncedKeyQueryManager<'a>> {
         self.ensure_sync_tracked_users(cache).await?;
         Ok(SyncedKeyQueryManager { cache, manager: self })
@@ -163,15 +239,32 @@ impl KeyQueryManager {
             return Ok(());
         }
 
-        let tracked_users = cache.store.load_tracked_users().await?;
+        // Load the users page by page, accounts in many rooms can track a lot of
+        // users and we don't want to hold all of them in memory at once.
+        let mut after = None;
+
+        loop {
+            let tracked_users = cache
+                .store
+                .load_tracked_users_paged(after.as_deref(), TRACKED_USERS_PAGE_SIZE)
+                .await?;
+
+            let Some(last) = tracked_users.last() else { break };
+            after = Some(last.user_id.clone());
+            let is_last_page = tracked_users.len() < TRACKED_USERS_PAGE_SIZE;
+
+            let mut query_users_lock = self.users_for_key_query.lock().await;
+            let mut tracked_users_cache = cache.tracked_users.write();
+            for user in tracked_users {
+                tracked_users_cache.insert(user.user_id.to_owned());
 
-        let mut query_users_lock = self.users_for_key_query.lock().await;
-        let mut tracked_users_cache = cache.tracked_users.write();
-        for user in tracked_users {
-            tracked_users_cache.insert(user.user_id.to_owned());
+                if user.dirty {
+                    query_users_lock.insert_user(&user.user_id);
+                }
+            }
 
-            if user.dirty {
-                query_users_lock.insert_user(&user.user_id);
+            if is_last_page {
+                break;
             }
         }
 
@@ -339,6 +432,29 @@ impl SyncedKeyQueryManager<'_> {
         self.manager.users_for_key_query.lock().await.users_for_key_query()
     }
 
+    /// Split the users that have the outdate/dirty flag set for their list of
+    /// devices into batches, each of which should be sent out as a separate
+    /// `/keys/query` request.
+    ///
+    /// The users with the highest [`KeyQueryPriority`] come first, the users
+    /// for which a task is waiting for a `/keys/query` to complete, e.g.
+    /// because a message can't be sent out before their devices are known,
+    /// are treated as [`KeyQueryPriority::BlockingSend`]. Users for which
+    /// `filter` returns false are skipped.
+    ///
+    /// # Returns
+    ///
+    /// A list of `(users, sequence_number)` pairs, one for every batch. The
+    /// sequence number of a batch should be returned in
+    /// `mark_tracked_users_as_up_to_date` once the batch has been queried.
+    pub async fn user_batches_for_key_query(
+        &self,
+        filter: impl Fn(&UserId) -> bool,
+    ) -> Vec<(Vec<OwnedUserId>, SequenceNumber)> {
+        let batch_size = self.manager.batch_size();
+        self.manager.users_for_key_query.lock().await.batches_for_key_query(batch_size, filter)
+    }
+
     /// See the docs for [`crate::OlmMachine::tracked_users()`].
     pub fn tracked_users(&self) -> HashSet<OwnedUserId> {
         self.cache.tracked_users.read().iter().cloned().collect()
@@ -350,7 +466,21 @@ impl SyncedKeyQueryManager<'_> {
     /// This means that the user will be considered for a `/keys/query` request
     /// next time [`Store::users_for_key_query()`] is called.
     pub async fn mark_user_as_changed(&self, user: &UserId) -> Result<()> {
-        self.manager.users_for_key_query.lock().await.insert_user(user);
+        self.mark_user_as_changed_with_priority(user, KeyQueryPriority::Background).await
+    }
+
+    /// Mark the given user as being tracked for device lists, and mark that it
+    /// has an outdated device list which should be queried with the given
+    /// priority.
+    ///
+    /// See [`SyncedKeyQueryManager::user_batches_for_key_query()`] for how the
+    /// priority is used.
+    pub async fn mark_user_as_changed_with_priority(
+        &self,
+        user: &UserId,
+        priority: KeyQueryPriority,
+    ) -> Result<()> {
+        self.manager.users_for_key_query.lock().await.insert_user_with_priority(user, priority);
         self.cache.tracked_users.write().insert(user.to_owned());
 
         self.cache.store.save_tracked_users(&[(user, true)]).await
@@ -470,6 +600,76 @@ impl StoreTransaction {
     }
 }
 
+/// A set of changes which are accumulated in memory, and saved to the store in
+/// a single batch once [`ChangesBuilder::commit()`] is called.
+///
+/// Use [`Store::begin_changes()`] to create one.
+///
+/// Nothing is written to the store if the builder is dropped without being
+/// committed.
+#[derive(Debug)]
+pub struct ChangesBuilder {
+    store: Store,
+    changes: Changes,
+}
+
+impl ChangesBuilder {
+    /// Add Olm sessions that should be saved.
+    pub fn add_sessions(&mut self, sessions: impl IntoIterator<Item = Session>) -> &mut Self {
+        self.changes.sessions.extend(sessions);
+        self
+    }
+
+    /// Add inbound group sessions that should be saved.
+    pub fn add_inbound_group_sessions(
+        &mut self,
+        sessions: impl IntoIterator<Item = InboundGroupSession>,
+    ) -> &mut Self {
+        self.changes.inbound_group_sessions.extend(sessions);
+        self
+    }
+
+    /// Add new, changed or deleted devices.
+    pub fn add_device_changes(&mut self, devices: DeviceChanges) -> &mut Self {
+        self.changes.devices.extend(devices);
+        self
+    }
+
+    /// Add new or changed user identities.
+    pub fn add_identity_changes(&mut self, identities: IdentityChanges) -> &mut Self {
+        self.changes.identities.new.extend(identities.new);
+        self.changes.identities.changed.extend(identities.changed);
+        self.changes.identities.unchanged.extend(identities.unchanged);
+        self
+    }
+
+    /// Add a `m.room_key.withheld` event we received for the given session.
+    pub fn add_withheld_info(
+        &mut self,
+        room_id: OwnedRoomId,
+        session_id: String,
+        event: RoomKeyWithheldEvent,
+    ) -> &mut Self {
+        self.changes.withheld_session_info.entry(room_id).or_default().insert(session_id, event);
+        self
+    }
+
+    /// Have any changes been added to this builder?
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Save all the accumulated changes to the store, in a single backend
+    /// transaction.
+    pub async fn commit(self) -> Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+
+        self.store.save_changes(self.changes).await
+    }
+}
+
 #[derive(Debug)]
 struct StoreInner {
     identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
@@ -485,6 +685,64 @@ struct StoreInner {
     /// Static account data that never changes (and thus can be loaded once and
     /// for all when creating the store).
     static_account: StaticAccountData,
+
+    /// The policy deciding which Olm sessions are removed by
+    /// [`Store::prune_sessions()`].
+    session_pruning_policy: StdRwLock<SessionPruningPolicy>,
+
+    /// The rules deciding whether a room key bundle is accepted by
+    /// [`Store::receive_room_key_bundle()`].
+    room_key_bundle_acceptance_policy: StdRwLock<RoomKeyBundleAcceptancePolicy>,
+
+    /// Counts the Olm sessions other devices create with us, see
+    /// [`Store::session_creation_limit()`].
+    session_spam_detector: SessionSpamDetector,
+
+    /// The changes of the E2EE statistics which weren't persisted yet, see
+    /// [`Store::record_statistics()`].
+    statistics: StatisticsRecorder,
+
+    /// Lock making sure that concurrent flushes of the E2EE statistics don't
+    /// overwrite each other, see [`Store::flush_statistics()`].
+    statistics_flush_lock: Mutex<()>,
+
+    /// The to-device requests which are persisted until they reach the
+    /// server, see [`Store::persist_to_device_requests()`].
+    ///
+    /// `None` until they have been loaded from the store.
+    pending_to_device_requests: Mutex<Option<BTreeMap<OwnedTransactionId, ToDeviceRequest>>>,
+
+    /// Lock making sure that concurrent updates of the identity disputes don't
+    /// overwrite each other, see [`Store::identity_disputes()`].
+    identity_disputes_lock: Mutex<()>,
+
+    /// Lock making sure that concurrent updates of the unreachable devices
+    /// don't overwrite each other, see
+    /// [`Store::mark_devices_as_unreachable()`].
+    unreachable_devices_lock: Mutex<()>,
+
+    /// Lock making sure that concurrent updates of the queued up signature
+    /// uploads don't overwrite each other, see
+    /// [`Store::queue_signature_upload()`].
+    signature_uploads_lock: Mutex<()>,
+}
+
+/// What [`Store::own_devices_stream()`] remembers about one of our own devices.
+#[derive(Clone, Debug)]
+struct OwnDeviceState {
+    display_name: Option<String>,
+    local_trust: LocalTrust,
+    verified: bool,
+}
+
+impl OwnDeviceState {
+    fn new(device: &Device) -> Self {
+        Self {
+            display_name: device.display_name().map(ToOwned::to_owned),
+            local_trust: device.local_trust_state(),
+            verified: device.is_verified(),
+        }
+    }
 }
 
 /// Error describing what went wrong when importing private cross signing keys
@@ -527,6 +785,62 @@ pub enum SecretsBundleExportError {
     MissingBackupVersion,
 }
 
+/// The key under which the algorithm of the backup, whose decryption key we
+/// have in the store, is persisted.
+const BACKUP_ALGORITHM_KEY: &str = "backup_algorithm";
+
+/// The key under which the [`RoomKeyImportCheckpoint`] of a resumable room key
+/// import is persisted.
+const ROOM_KEY_IMPORT_CHECKPOINT_KEY: &str = "room_key_import_checkpoint";
+
+/// The key under which the device blocklist of our account is persisted, see
+/// [`Store::block_device()`].
+const DEVICE_BLOCKLIST_KEY: &str = "device_blocklist";
+
+/// The key under which the disputes about the identities of other users are
+/// persisted, see [`Store::identity_disputes()`].
+const IDENTITY_DISPUTES_KEY: &str = "identity_disputes";
+
+/// The key under which the signature uploads which haven't reached the server
+/// yet are persisted, see [`Store::queue_signature_upload()`].
+const PENDING_SIGNATURE_UPLOADS_KEY: &str = "pending_signature_uploads";
+
+/// The key under which the to-device requests which haven't reached the
+/// server yet are persisted, see [`Store::persist_to_device_requests()`].
+const PENDING_TO_DEVICE_REQUESTS_KEY: &str = "pending_to_device_requests";
+
+/// The key under which the devices for which claiming a one-time key keeps
+/// failing are persisted, see [`Store::mark_devices_as_unreachable()`].
+const UNREACHABLE_DEVICES_KEY: &str = "unreachable_devices";
+
+/// The key under which the E2EE statistics of our account are persisted, see
+/// [`Store::statistics()`].
+const STATISTICS_KEY: &str = "crypto_statistics";
+
+/// The number of room keys a resumable room key import saves between two
+/// checkpoints.
+const ROOM_KEY_IMPORT_BATCH_SIZE: usize = 1000;
+
+/// A signature upload which hasn't reached the server yet, see
+/// [`Store::queue_signature_upload()`].
+#[derive(Debug, Deserialize, Serialize)]
+struct PendingSignatureUpload {
+    signed_keys: BTreeMap<OwnedUserId, SignedKeys>,
+}
+
+/// The progress of a resumable room key import, see
+/// [`Store::import_exported_room_keys_resumable()`].
+#[derive(Debug, Deserialize, Serialize)]
+struct RoomKeyImportCheckpoint {
+    /// The digest of the room key export which is being imported, see
+    /// [`Store::room_key_export_digest()`].
+    digest: String,
+    /// The number of room keys of the export that were already processed.
+    offset: usize,
+    /// The number of room keys that were imported so far.
+    imported_count: usize,
+}
+
 impl Store {
     /// Create a new Store.
     pub(crate) fn new(
@@ -546,7 +860,18 @@ impl Store {
                     tracked_users: Default::default(),
                     loaded_tracked_users: Default::default(),
                     account: Default::default(),
+                    warm_devices: Default::default(),
+                    unreachable_devices: Default::default(),
                 })),
+                session_pruning_policy: Default::default(),
+                room_key_bundle_acceptance_policy: Default::default(),
+                session_spam_detector: Default::default(),
+                statistics: Default::default(),
+                statistics_flush_lock: Default::default(),
+                pending_to_device_requests: Default::default(),
+                identity_disputes_lock: Default::default(),
+                unreachable_devices_lock: Default::default(),
+                signature_uploads_lock: Default::default(),
             }),
         }
     }
@@ -567,17 +892,37 @@ impl Store {
     }
 
     pub(crate) async fn cache(&self) -> Result<StoreCacheGuard> {
-        // TODO: (bnjbvr, #2624) If configured with a cross-process lock:
-        // - try to take the lock,
-        // - if acquired, look if another process touched the underlying storage,
-        // - if yes, reload everything; if no, return current cache
+        self.reload_cache_if_modified().await?;
         Ok(StoreCacheGuard { cache: self.inner.cache.clone().read_owned().await })
     }
 
     pub(crate) async fn transaction(&self) -> StoreTransaction {
+        if let Err(error) = self.reload_cache_if_modified().await {
+            warn!("Couldn't check if the store was modified by another process: {error:?}");
+        }
+
         StoreTransaction::new(self.clone()).await
     }
 
+    /// Reset the [`StoreCache`] if another process wrote to the store since we
+    /// last checked, as detected by the cross-process generation counter.
+    ///
+    /// The cached values will be reloaded from the store the next time they
+    /// are needed.
+    async fn reload_cache_if_modified(&self) -> Result<()> {
+        if self.inner.store.was_modified_by_another_process().await? {
+            let cache = self.inner.cache.read().await;
+
+            *cache.account.lock().await = None;
+            cache.tracked_users.write().clear();
+            *cache.loaded_tracked_users.write().await = false;
+            cache.warm_devices.write().clear();
+            *cache.unreachable_devices.write() = None;
+        }
+
+        Ok(())
+    }
+
     // Note: bnjbvr lost against borrowck here. Ideally, the `F` parameter would
     // take a `&StoreTransaction`, but callers didn't quite like that.
     pub(crate) async fn with_transaction<
@@ -619,8 +964,248 @@ impl Store {
         self.inner.store.get_sessions(sender_key).await
     }
 
+    /// Get the policy deciding which Olm sessions are removed by
+    /// [`Store::prune_sessions()`].
+    pub fn session_pruning_policy(&self) -> SessionPruningPolicy {
+        self.inner.session_pruning_policy.read().clone()
+    }
+
+    pub(crate) fn set_session_pruning_policy(&self, policy: SessionPruningPolicy) {
+        *self.inner.session_pruning_policy.write() = policy;
+    }
+
+    /// Get the rules deciding whether a room key bundle is accepted by
+    /// [`Store::receive_room_key_bundle()`].
+    pub fn room_key_bundle_acceptance_policy(&self) -> RoomKeyBundleAcceptancePolicy {
+        self.inner.room_key_bundle_acceptance_policy.read().clone()
+    }
+
+    pub(crate) fn set_room_key_bundle_acceptance_policy(
+        &self,
+        policy: RoomKeyBundleAcceptancePolicy,
+    ) {
+        *self.inner.room_key_bundle_acceptance_policy.write() = policy;
+    }
+
+    /// Get the number of new Olm sessions a single device may create with us
+    /// within a period of time, `None` if it's unlimited.
+    pub fn session_creation_limit(&self) -> Option<SessionCreationLimit> {
+        self.inner.session_spam_detector.limit()
+    }
+
+    pub(crate) fn set_session_creation_limit(&self, limit: Option<SessionCreationLimit>) {
+        self.inner.session_spam_detector.set_limit(limit);
+    }
+
+    pub(crate) fn session_spam_detector(&self) -> &SessionSpamDetector {
+        &self.inner.session_spam_detector
+    }
+
+    /// Get the source of the current time used by this store.
+    ///
+    /// This is the [`SystemClock`](crate::clock::SystemClock) unless another
+    /// clock was installed using [`OlmMachine::set_clock()`].
+    ///
+    /// [`OlmMachine::set_clock()`]: crate::OlmMachine::set_clock
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.store.clock()
+    }
+
+    pub(crate) fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.inner.store.set_clock(clock)
+    }
+
+    /// Remove the Olm sessions which are no longer needed according to the
+    /// current [`SessionPruningPolicy`].
+    ///
+    /// The policy can be changed using
+    /// [`OlmMachine::set_session_pruning_policy()`].
+    ///
+    /// Returns the number of sessions that were removed.
+    ///
+    /// [`OlmMachine::set_session_pruning_policy()`]: crate::OlmMachine::set_session_pruning_policy
+    pub async fn prune_sessions(&self) -> Result<usize> {
+        let policy = self.session_pruning_policy();
+
+        if policy.is_disabled() {
+            return Ok(0);
+        }
+
+        let now = self.clock().now_secs();
+        let mut pruned_count = 0;
+
+        for sender_key in self.inner.store.get_session_sender_keys().await? {
+            let Some(sessions) = self.get_sessions(&sender_key).await? else {
+                continue;
+            };
+
+            // Hold the lock while we delete the sessions, so nobody uses one of the
+            // sessions we're about to remove in the meantime.
+            let mut sessions = sessions.lock().await;
+            let pruned = policy.sessions_to_prune(&sessions, now);
+
+            if pruned.is_empty() {
+                continue;
+            }
+
+            trace!(%sender_key, ?pruned, "Removing Olm sessions according to the pruning policy");
+
+            self.inner.store.delete_sessions(&sender_key, &pruned).await?;
+            sessions.retain(|s| !pruned.iter().any(|session_id| session_id == s.session_id()));
+
+            pruned_count += pruned.len();
+        }
+
+        if pruned_count > 0 {
+            info!(pruned_count, "Pruned Olm sessions");
+        }
+
+        Ok(pruned_count)
+    }
+
     pub(crate) async fn save_changes(&self, changes: Changes) -> Result<()> {
-        self.inner.store.save_changes(changes).await
+        let backup_key_changed = changes.backup_decryption_key.is_some();
+
+        self.inner.store.save_changes(changes).await?;
+        self.flush_statistics().await;
+
+        // We don't know the algorithm of a backup key that didn't come from a
+        // secrets bundle, forget the one of the key it replaced.
+        if backup_key_changed {
+            self.remove_custom_value(BACKUP_ALGORITHM_KEY).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Load the backup keys, see [`SecretStorageBackend`] for where the
+    /// decryption key might be coming from.
+    pub async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        self.inner.store.load_backup_keys().await
+    }
+
+    /// Enable or disable the lenient mode of the store.
+    ///
+    /// Some store corruption bugs leave behind a single entry which can't be
+    /// decoded anymore, which breaks loading all the entries of its kind. In
+    /// lenient mode, such entries are moved to the quarantine of the store
+    /// instead, so the rest of the store remains usable. The quarantined
+    /// entries can be inspected using [`Store::quarantined_entries()`] and put
+    /// back using [`Store::restore_quarantined_entries()`].
+    ///
+    /// The lenient mode is disabled by default.
+    pub fn set_lenient(&self, lenient: bool) {
+        self.inner.store.set_lenient(lenient)
+    }
+
+    /// Get the entries which were moved to the quarantine of the store because
+    /// they couldn't be decoded, see [`Store::set_lenient()`].
+    pub async fn quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>> {
+        self.inner.store.get_quarantined_entries().await
+    }
+
+    /// Move the entries of the quarantine of the store back to the place they
+    /// were found at, for example after upgrading to a version which can
+    /// decode them again.
+    ///
+    /// Entries which still can't be decoded are quarantined again the next
+    /// time they are loaded in lenient mode. Returns the number of restored
+    /// entries.
+    pub async fn restore_quarantined_entries(&self) -> Result<usize> {
+        self.inner.store.restore_quarantined_entries().await
+    }
+
+    /// Get all the inbound group sessions we have stored.
+    ///
+    /// In lenient mode, sessions which can't be decoded are moved to the
+    /// quarantine of the store instead of failing the whole call, see
+    /// [`Store::set_lenient()`].
+    pub async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        self.inner.store.get_inbound_group_sessions().await
+    }
+
+    /// Get the inbound group session with the given room ID and session ID.
+    ///
+    /// Sessions which can't be decoded are handled like in
+    /// [`Store::get_inbound_group_sessions()`].
+    pub async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        self.inner.store.get_inbound_group_session(room_id, session_id).await
+    }
+
+    /// Get a batch of the inbound group sessions we have stored, see
+    /// [`CryptoStore::get_inbound_group_sessions_batch()`].
+    ///
+    /// Sessions which can't be decoded are handled like in
+    /// [`Store::get_inbound_group_sessions()`].
+    pub async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.store.get_inbound_group_sessions_batch(after, limit).await
+    }
+
+    /// Get a batch of the inbound group sessions of the given device, see
+    /// [`CryptoStore::get_inbound_group_sessions_for_device_batch()`].
+    ///
+    /// Sessions which can't be decoded are handled like in
+    /// [`Store::get_inbound_group_sessions()`].
+    pub async fn get_inbound_group_sessions_for_device_batch(
+        &self,
+        curve_key: Curve25519PublicKey,
+        sender_data_type: SenderDataType,
+        after_session_id: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner
+            .store
+            .get_inbound_group_sessions_for_device_batch(
+                curve_key,
+                sender_data_type,
+                after_session_id,
+                limit,
+            )
+            .await
+    }
+
+    /// Get the inbound group sessions of the given room, see
+    /// [`CryptoStore::get_inbound_group_sessions_for_room()`].
+    ///
+    /// Sessions which can't be decoded are handled like in
+    /// [`Store::get_inbound_group_sessions()`].
+    pub async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.store.get_inbound_group_sessions_for_room(room_id, since).await
+    }
+
+    /// Get a batch of the inbound group sessions which need to be backed up,
+    /// see [`CryptoStore::inbound_group_sessions_for_backup()`].
+    ///
+    /// Sessions which can't be decoded are handled like in
+    /// [`Store::get_inbound_group_sessions()`].
+    pub async fn inbound_group_sessions_for_backup(
+        &self,
+        backup_version: &str,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.store.inbound_group_sessions_for_backup(backup_version, limit).await
+    }
+
+    /// Start accumulating a set of changes that will be saved to the store in a
+    /// single batch.
+    ///
+    /// Saving many small sets of changes can be slow, for example SQLite syncs
+    /// the database to disk for every transaction. Using a [`ChangesBuilder`]
+    /// lets callers save everything at once instead.
+    pub fn begin_changes(&self) -> ChangesBuilder {
+        ChangesBuilder { store: self.clone(), changes: Changes::default() }
     }
 
     /// Compare the given `InboundGroupSession` with an existing session we have
@@ -768,6 +1353,37 @@ impl Store {
         })
     }
 
+    /// Take a [`DeviceListSnapshot`] of the devices the given user has.
+    ///
+    /// The snapshot can be persisted, and compared against the device list of
+    /// the user later on using [`Store::diff_since()`], e.g. to detect that a
+    /// new, unverified device appeared, even across restarts.
+    pub async fn device_list_snapshot(&self, user_id: &UserId) -> Result<DeviceListSnapshot> {
+        let devices = self
+            .get_user_devices(user_id)
+            .await?
+            .devices()
+            .filter(|device| !device.is_deleted())
+            .map(|device| {
+                let snapshot = DeviceSnapshot {
+                    ed25519_key: device.ed25519_key().map(|key| key.to_base64()),
+                    verified: device.is_verified(),
+                };
+
+                (device.device_id().to_owned(), snapshot)
+            })
+            .collect();
+
+        Ok(DeviceListSnapshot { user_id: user_id.to_owned(), devices })
+    }
+
+    /// Compare the current device list of a user with a [`DeviceListSnapshot`]
+    /// that was taken earlier using [`Store::device_list_snapshot()`].
+    pub async fn diff_since(&self, snapshot: &DeviceListSnapshot) -> Result<DeviceListDiff> {
+        let current = self.device_list_snapshot(&snapshot.user_id).await?;
+        Ok(snapshot.diff(current))
+    }
+
     /// Get a [`Device`] for the given user with the given [`DeviceId`].
     ///
     /// This method is more expensive than the [`Store::get_device_data`] method
@@ -833,7 +1449,7 @@ impl Store {
 
     /// Try to export the secret with the given secret name.
     ///
-    /// The exported secret will be encoded as unpadded base64. Returns `Null`
+    /// The exported secret will be encoded as unpadded base64. Returns `None`
     /// if the secret can't be found.
     ///
     /// # Arguments
@@ -842,7 +1458,7 @@ impl Store {
     pub async fn export_secret(
         &self,
         secret_name: &SecretName,
-    ) -> Result<Option<String>, CryptoStoreError> {
+    ) -> Result<Option<SecretString>, CryptoStoreError> {
         Ok(match secret_name {
             SecretName::CrossSigningMasterKey
             | SecretName::CrossSigningUserSigningKey
@@ -852,7 +1468,7 @@ impl Store {
             SecretName::RecoveryKey => {
                 if let Some(key) = self.load_backup_keys().await?.decryption_key {
                     let exported = key.to_base64();
-                    Some(exported)
+                    Some(exported.into())
                 } else {
                     None
                 }
@@ -901,30 +1517,44 @@ impl Store {
             identity
                 .import_secrets(
                     public_identity.to_owned(),
-                    export.master_key.as_deref(),
-                    export.self_signing_key.as_deref(),
-                    export.user_signing_key.as_deref(),
+                    export.master_key.as_ref().map(SecretString::expose_secret),
+                    export.self_signing_key.as_ref().map(SecretString::expose_secret),
+                    export.user_signing_key.as_ref().map(SecretString::expose_secret),
                 )
                 .await?;
 
-            let status = identity.status().await;
-
-            let diff = identity.get_public_identity_diff(&public_identity.inner).await;
+            self.save_imported_cross_signing_keys(&identity, public_identity).await?;
+        } else {
+            warn!(
+                "No public identity found while importing cross-signing keys, \
+                 a /keys/query needs to be done"
+            );
+        }
 
-            let mut changes =
-                Changes { private_identity: Some(identity.clone()), ..Default::default() };
+        Ok(self.inner.identity.lock().await.status().await)
+    }
 
-            if diff.none_differ() {
-                public_identity.mark_as_verified();
-                changes.identities.changed.push(UserIdentityData::Own(public_identity.inner));
-            }
+    /// Use the given [`SigningBackend`]s for our private cross signing keys.
+    ///
+    /// The keys held by the backends aren't persisted in the store, so this
+    /// needs to be called every time the store is opened.
+    ///
+    /// [`SigningBackend`]: crate::olm::SigningBackend
+    pub async fn import_cross_signing_backends(
+        &self,
+        backends: CrossSigningBackends,
+    ) -> Result<CrossSigningStatus, SecretImportError> {
+        if let Some(public_identity) =
+            self.get_identity(self.user_id()).await?.and_then(|i| i.own())
+        {
+            let identity = self.inner.identity.lock().await;
 
-            info!(?status, "Successfully imported the private cross-signing keys");
+            identity.import_backends(&public_identity.inner, backends).await?;
 
-            self.save_changes(changes).await?;
+            self.save_imported_cross_signing_keys(&identity, public_identity).await?;
         } else {
             warn!(
-                "No public identity found while importing cross-signing keys, \
+                "No public identity found while importing cross-signing backends, \
                  a /keys/query needs to be done"
             );
         }
@@ -932,6 +1562,30 @@ impl Store {
         Ok(self.inner.identity.lock().await.status().await)
     }
 
+    /// Persist our private identity after some of its keys were imported, and
+    /// mark our public identity as verified if all the keys match.
+    async fn save_imported_cross_signing_keys(
+        &self,
+        identity: &PrivateCrossSigningIdentity,
+        public_identity: OwnUserIdentity,
+    ) -> Result<()> {
+        let status = identity.status().await;
+
+        let diff = identity.get_public_identity_diff(&public_identity.inner).await;
+
+        let mut changes =
+            Changes { private_identity: Some(identity.clone()), ..Default::default() };
+
+        if diff.none_differ() {
+            public_identity.mark_as_verified();
+            changes.identities.changed.push(UserIdentityData::Own(public_identity.inner));
+        }
+
+        info!(?status, "Successfully imported the private cross-signing keys");
+
+        self.save_changes(changes).await
+    }
+
     /// Export all the secrets we have in the store into a [`SecretsBundle`].
     ///
     /// This method will export all the private cross-signing keys and, if
@@ -964,9 +1618,17 @@ impl Store {
 
         let backup = if let Some(key) = backup_keys.decryption_key {
             if let Some(backup_version) = backup_keys.backup_version {
-                Some(BackupSecrets::MegolmBackupV1Curve25519AesSha2(
-                    MegolmBackupV1Curve25519AesSha2Secrets { key, backup_version },
-                ))
+                let algorithm = self.get_value::<String>(BACKUP_ALGORITHM_KEY).await?;
+                let algorithm =
+                    algorithm.as_deref().unwrap_or("m.megolm_backup.v1.curve25519-aes-sha2");
+
+                let secrets = BackupSecrets::new(algorithm, key, backup_version);
+
+                if secrets.is_none() {
+                    warn!(algorithm, "Not exporting the backup key of an unsupported algorithm");
+                }
+
+                secrets
             } else {
                 return Err(SecretsBundleExportError::MissingBackupVersion);
             }
@@ -999,21 +1661,17 @@ impl Store {
         let mut changes = Changes::default();
 
         if let Some(backup_bundle) = &bundle.backup {
-            match backup_bundle {
-                BackupSecrets::MegolmBackupV1Curve25519AesSha2(bundle) => {
-                    changes.backup_decryption_key = Some(bundle.key.clone());
-                    changes.backup_version = Some(bundle.backup_version.clone());
-                }
-            }
+            changes.backup_decryption_key = Some(backup_bundle.key().clone());
+            changes.backup_version = Some(backup_bundle.backup_version().to_owned());
         }
 
         let identity = self.inner.identity.lock().await;
 
         identity
             .import_secrets_unchecked(
-                Some(&bundle.cross_signing.master_key),
-                Some(&bundle.cross_signing.self_signing_key),
-                Some(&bundle.cross_signing.user_signing_key),
+                Some(bundle.cross_signing.master_key.expose_secret()),
+                Some(bundle.cross_signing.self_signing_key.expose_secret()),
+                Some(bundle.cross_signing.user_signing_key.expose_secret()),
             )
             .await?;
 
@@ -1025,7 +1683,13 @@ impl Store {
         changes.private_identity = Some(identity.clone());
         changes.identities.new.push(UserIdentityData::Own(public_identity));
 
-        Ok(self.save_changes(changes).await?)
+        self.save_changes(changes).await?;
+
+        if let Some(backup_bundle) = &bundle.backup {
+            self.set_value(BACKUP_ALGORITHM_KEY, &backup_bundle.algorithm()).await?;
+        }
+
+        Ok(())
     }
 
     /// Import the given `secret` named `secret_name` into the keystore.
@@ -1043,7 +1707,7 @@ impl Store {
                         .import_secret(
                             public_identity,
                             &secret.secret_name,
-                            &secret.event.content.secret,
+                            secret.event.content.secret.expose_secret(),
                         )
                         .await?;
                     info!(
@@ -1072,30 +1736,747 @@ impl Store {
         Ok(())
     }
 
-    /// Check whether there is a global flag to only encrypt messages for
-    /// trusted devices or for everyone.
-    pub async fn get_only_allow_trusted_devices(&self) -> Result<bool> {
-        let value = self.get_value("only_allow_trusted_devices").await?.unwrap_or_default();
-        Ok(value)
-    }
+    /// Export the full crypto state of this device, so it can be moved to
+    /// another store, e.g. one using a different backend.
+    ///
+    /// The export contains the Olm account, the Olm and Megolm sessions, the
+    /// private cross-signing keys, the backup decryption key and the list of
+    /// tracked users. It can be imported using [`Store::import_full_state()`].
+    ///
+    /// **Warning**: The export contains the private keys of this device, only
+    /// persist it in encrypted form and never share it with anyone.
+    pub async fn export_full_state(&self) -> Result<FullStateExport> {
+        let account = self.cache().await?.account().await?.pickle();
 
-    /// Set global flag whether to encrypt messages for untrusted devices, or
-    /// whether they should be excluded from the conversation.
-    pub async fn set_only_allow_trusted_devices(
-        &self,
-        block_untrusted_devices: bool,
-    ) -> Result<()> {
-        self.set_value("only_allow_trusted_devices", &block_untrusted_devices).await
-    }
+        let private_identity = {
+            let identity = self.inner.identity.lock().await;
 
-    /// Get custom stored value associated with a key
-    pub async fn get_value<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
-        let Some(value) = self.get_custom_value(key).await? else {
-            return Ok(None);
+            if identity.is_empty().await {
+                None
+            } else {
+                Some(identity.pickle().await)
+            }
         };
-        let deserialized = self.deserialize_value(&value)?;
-        Ok(Some(deserialized))
-    }
+
+        let mut sessions = Vec::new();
+
+        for sender_key in self.inner.store.get_session_sender_keys().await? {
+            if let Some(sender_sessions) = self.get_sessions(&sender_key).await? {
+                for session in sender_sessions.lock().await.iter() {
+                    sessions.push(session.pickle().await);
+                }
+            }
+        }
+
+        let mut inbound_group_sessions = Vec::new();
+
+        for session in self.inner.store.get_inbound_group_sessions().await? {
+            inbound_group_sessions.push(session.pickle().await);
+        }
+
+        let tracked_users = self.inner.store.load_tracked_users().await?;
+        let backup_keys = self.load_backup_keys().await?;
+        let backup_algorithm = self.get_value(BACKUP_ALGORITHM_KEY).await?;
+
+        Ok(FullStateExport {
+            account,
+            private_identity,
+            sessions,
+            inbound_group_sessions,
+            tracked_users,
+            backup_decryption_key: backup_keys.decryption_key,
+            backup_version: backup_keys.backup_version,
+            backup_algorithm,
+        })
+    }
+
+    /// Import a [`FullStateExport`], created by [`Store::export_full_state()`],
+    /// into the given store.
+    ///
+    /// The import needs to happen before an [`OlmMachine`] is created using
+    /// the store, the machine will then pick up the imported account.
+    ///
+    /// The device lists aren't part of the export, so all the tracked users
+    /// are marked as dirty and their devices will be queried again.
+    ///
+    /// Returns an error if the store already contains the account of another
+    /// device.
+    ///
+    /// [`OlmMachine`]: crate::OlmMachine
+    pub async fn import_full_state(
+        store: impl IntoCryptoStore,
+        export: FullStateExport,
+    ) -> Result<()> {
+        let store = store.into_crypto_store();
+        let account = Account::from_pickle(export.account)?;
+
+        if let Some(existing) = store.load_account().await? {
+            if existing.user_id() != account.user_id()
+                || existing.device_id() != account.device_id()
+            {
+                return Err(CryptoStoreError::MismatchedAccount {
+                    expected: (account.user_id().to_owned(), account.device_id().to_owned()),
+                    got: (existing.user_id().to_owned(), existing.device_id().to_owned()),
+                });
+            }
+        }
+
+        let device_keys = account.device_keys();
+
+        let sessions = export
+            .sessions
+            .into_iter()
+            .map(|pickle| Session::from_pickle(device_keys.clone(), pickle))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        let inbound_group_sessions = export
+            .inbound_group_sessions
+            .into_iter()
+            .map(InboundGroupSession::from_pickle)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let private_identity = export
+            .private_identity
+            .map(PrivateCrossSigningIdentity::from_pickle)
+            .transpose()
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        // Stores refuse to save sessions before an account was saved.
+        store.save_pending_changes(PendingChanges { account: Some(account) }).await?;
+        store
+            .save_changes(Changes {
+                private_identity,
+                sessions,
+                inbound_group_sessions,
+                backup_decryption_key: export.backup_decryption_key,
+                backup_version: export.backup_version,
+                ..Default::default()
+            })
+            .await?;
+
+        if let Some(algorithm) = &export.backup_algorithm {
+            store
+                .set_custom_value(BACKUP_ALGORITHM_KEY, ValueCodec::default().encode(algorithm)?)
+                .await?;
+        }
+
+        let tracked_users: Vec<_> =
+            export.tracked_users.iter().map(|user| (user.user_id.as_ref(), true)).collect();
+        store.save_tracked_users(&tracked_users).await?;
+
+        Ok(())
+    }
+
+    /// Check whether there is a global flag to only encrypt messages for
+    /// trusted devices or for everyone.
+    pub async fn get_only_allow_trusted_devices(&self) -> Result<bool> {
+        let value = self.get_value("only_allow_trusted_devices").await?.unwrap_or_default();
+        Ok(value)
+    }
+
+    /// Set global flag whether to encrypt messages for untrusted devices, or
+    /// whether they should be excluded from the conversation.
+    pub async fn set_only_allow_trusted_devices(
+        &self,
+        block_untrusted_devices: bool,
+    ) -> Result<()> {
+        self.set_value("only_allow_trusted_devices", &block_untrusted_devices).await
+    }
+
+    /// Check whether the given room has a flag to only encrypt messages for
+    /// trusted devices or for everyone.
+    ///
+    /// This is the [`RoomSettings::only_allow_trusted_devices`] flag, it
+    /// doesn't take the global flag into account, see
+    /// [`Store::get_only_allow_trusted_devices()`].
+    pub async fn get_room_only_allow_trusted_devices(&self, room_id: &RoomId) -> Result<bool> {
+        let settings = self.get_room_settings(room_id).await?;
+        Ok(settings.is_some_and(|settings| settings.only_allow_trusted_devices))
+    }
+
+    /// Set a flag for the given room whether to encrypt messages for untrusted
+    /// devices, or whether they should be excluded from the conversation.
+    ///
+    /// This updates the [`RoomSettings::only_allow_trusted_devices`] flag,
+    /// which can be changed at any time unlike the rest of the settings.
+    pub async fn set_room_only_allow_trusted_devices(
+        &self,
+        room_id: &RoomId,
+        block_untrusted_devices: bool,
+    ) -> Result<()> {
+        self.update_room_settings(room_id, |settings| {
+            settings.only_allow_trusted_devices = block_untrusted_devices;
+        })
+        .await
+    }
+
+    /// Update the [`RoomSettings`] of the given room, starting from the
+    /// default settings if none were stored yet.
+    async fn update_room_settings(
+        &self,
+        room_id: &RoomId,
+        update: impl FnOnce(&mut RoomSettings),
+    ) -> Result<()> {
+        // The transaction is only used as a lock guard, so concurrent updates
+        // of the settings don't overwrite each other.
+        let _transaction = self.transaction().await;
+
+        let mut settings = self.get_room_settings(room_id).await?.unwrap_or_default();
+        update(&mut settings);
+
+        self.save_changes(Changes {
+            room_settings: HashMap::from([(room_id.to_owned(), settings)]),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Get the [`CollectStrategy`] which should be used to share room keys in
+    /// the given room, if one was set.
+    ///
+    /// This is the [`RoomSettings::sharing_strategy`].
+    pub async fn get_room_sharing_strategy(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<CollectStrategy>> {
+        let settings = self.get_room_settings(room_id).await?;
+        Ok(settings.and_then(|settings| settings.sharing_strategy))
+    }
+
+    /// Set the [`CollectStrategy`] which should be used to share room keys in
+    /// the given room, or remove it by passing `None`.
+    ///
+    /// This updates the [`RoomSettings::sharing_strategy`].
+    pub async fn set_room_sharing_strategy(
+        &self,
+        room_id: &RoomId,
+        strategy: Option<CollectStrategy>,
+    ) -> Result<()> {
+        self.update_room_settings(room_id, |settings| settings.sharing_strategy = strategy).await
+    }
+
+    /// Get the history of the given outbound group session being shared with,
+    /// or withheld from, devices.
+    ///
+    /// Every to-device message carrying the room key, or a withheld code, adds
+    /// a [`SessionShareRecord`] for each of its recipients once it's sent
+    /// out. This tells exactly which devices were able to decrypt the messages
+    /// encrypted using the session, starting at which message index.
+    ///
+    /// The history is kept after the session is rotated, and removed together
+    /// with our own copy of the room key, e.g. once it's dropped by the
+    /// [`RoomKeyRetentionPolicy`]. It's empty if the session was never shared,
+    /// or if it isn't one of our own sessions.
+    pub async fn session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>> {
+        self.inner.store.get_session_share_history(room_id, session_id).await
+    }
+
+    /// Get the verification audit log of the given user, oldest entry first.
+    ///
+    /// An entry is added when the identity of the user becomes verified, and
+    /// when the verification of a verified identity is withdrawn with
+    /// [`UserIdentity::withdraw_verification()`] or
+    /// [`UserIdentity::withdraw_verification_with_reason()`]. Only the last
+    /// [`VERIFICATION_AUDIT_LOG_CAPACITY`](types::VERIFICATION_AUDIT_LOG_CAPACITY)
+    /// entries are kept.
+    pub async fn verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>> {
+        self.inner.store.get_verification_audit_log(user_id).await
+    }
+
+    /// Get the rotation policy overrides for the given room, if any were set.
+    pub async fn get_room_rotation_policy(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<RoomRotationPolicy>> {
+        let key = Self::room_rotation_policy_key(room_id);
+        self.get_value(&key).await
+    }
+
+    /// Set the rotation policy overrides for the given room.
+    ///
+    /// If neither a rotation period nor a message count is given, the
+    /// overrides for the room are removed.
+    pub async fn set_room_rotation_policy(
+        &self,
+        room_id: &RoomId,
+        rotation_period: Option<Duration>,
+        rotation_period_msgs: Option<u64>,
+    ) -> Result<()> {
+        let key = Self::room_rotation_policy_key(room_id);
+
+        if rotation_period.is_none() && rotation_period_msgs.is_none() {
+            self.remove_custom_value(&key).await
+        } else {
+            let policy = RoomRotationPolicy { rotation_period, rotation_period_msgs };
+            self.set_value(&key, &policy).await
+        }
+    }
+
+    fn room_rotation_policy_key(room_id: &RoomId) -> String {
+        format!("room_rotation_policy:{room_id}")
+    }
+
+    /// The key of the custom value holding the room keys of the given room
+    /// which were withheld from unverified or blacklisted devices.
+    pub(crate) fn withheld_room_keys_key(room_id: &RoomId) -> String {
+        format!("withheld_room_keys:{room_id}")
+    }
+
+    /// Never share room keys with the given device.
+    ///
+    /// Blocked devices don't receive room keys, regardless of their trust
+    /// state or the [`CollectStrategy`] that is used, and are sent a
+    /// [`WithheldCode::Blacklisted`] instead. Unlike blacklisting a device
+    /// using [`Device::set_local_trust()`], the device doesn't need to be known
+    /// yet, so devices can be blocked before they show up.
+    ///
+    /// [`CollectStrategy`]: crate::CollectStrategy
+    /// [`WithheldCode::Blacklisted`]: matrix_sdk_common::deserialized_responses::WithheldCode::Blacklisted
+    pub async fn block_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut blocklist = self.blocked_devices().await?;
+
+        if blocklist.entry(user_id.to_owned()).or_default().insert(device_id.to_owned()) {
+            info!(?user_id, ?device_id, "Blocking a device from receiving room keys");
+            self.set_value(DEVICE_BLOCKLIST_KEY, &blocklist).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the given device from the device blocklist, see
+    /// [`Store::block_device()`].
+    pub async fn unblock_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut blocklist = self.blocked_devices().await?;
+
+        let Some(devices) = blocklist.get_mut(user_id) else {
+            return Ok(());
+        };
+
+        if devices.remove(device_id) {
+            if devices.is_empty() {
+                blocklist.remove(user_id);
+            }
+
+            info!(?user_id, ?device_id, "Unblocking a device");
+            self.set_value(DEVICE_BLOCKLIST_KEY, &blocklist).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Is the given device on the device blocklist, see
+    /// [`Store::block_device()`]?
+    pub async fn is_device_blocked(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        Ok(self
+            .blocked_devices()
+            .await?
+            .get(user_id)
+            .is_some_and(|devices| devices.contains(device_id)))
+    }
+
+    /// Get all the devices on the device blocklist, see
+    /// [`Store::block_device()`].
+    pub async fn blocked_devices(&self) -> Result<BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>> {
+        Ok(self.get_value(DEVICE_BLOCKLIST_KEY).await?.unwrap_or_default())
+    }
+
+    /// Record that the keys of the given user were disputed by the
+    /// [`KeyQueryValidator`], replacing a previous dispute.
+    ///
+    /// [`KeyQueryValidator`]: crate::KeyQueryValidator
+    pub(crate) async fn dispute_identity(
+        &self,
+        user_id: &UserId,
+        dispute: IdentityDispute,
+    ) -> Result<()> {
+        let _guard = self.inner.identity_disputes_lock.lock().await;
+        let mut disputes = self.identity_disputes().await?;
+
+        warn!(?user_id, ?dispute, "The keys of a user were disputed by a second source");
+        disputes.insert(user_id.to_owned(), dispute);
+        self.set_value(IDENTITY_DISPUTES_KEY, &disputes).await
+    }
+
+    /// Get the dispute about the keys of the given user, if there is one.
+    ///
+    /// Disputes are recorded by the [`KeyQueryValidator`] installed using
+    /// [`OlmMachine::set_key_query_validator()`], and stay around until the
+    /// validator confirms the keys of the user, or the dispute is resolved
+    /// using [`Store::resolve_identity_dispute()`].
+    ///
+    /// [`KeyQueryValidator`]: crate::KeyQueryValidator
+    /// [`OlmMachine::set_key_query_validator()`]: crate::OlmMachine::set_key_query_validator
+    pub async fn identity_dispute(&self, user_id: &UserId) -> Result<Option<IdentityDispute>> {
+        Ok(self.identity_disputes().await?.remove(user_id))
+    }
+
+    /// Get all the users whose keys are disputed, see
+    /// [`Store::identity_dispute()`].
+    pub async fn identity_disputes(&self) -> Result<BTreeMap<OwnedUserId, IdentityDispute>> {
+        Ok(self.get_value(IDENTITY_DISPUTES_KEY).await?.unwrap_or_default())
+    }
+
+    /// Forget the dispute about the keys of the given user, e.g. once the user
+    /// confirmed their keys out of band.
+    ///
+    /// Returns `false` if the keys of the user weren't disputed.
+    pub async fn resolve_identity_dispute(&self, user_id: &UserId) -> Result<bool> {
+        let _guard = self.inner.identity_disputes_lock.lock().await;
+        let mut disputes = self.identity_disputes().await?;
+
+        if disputes.remove(user_id).is_none() {
+            return Ok(false);
+        }
+
+        info!(?user_id, "Resolved the dispute about the keys of a user");
+
+        if disputes.is_empty() {
+            self.remove_custom_value(IDENTITY_DISPUTES_KEY).await?;
+        } else {
+            self.set_value(IDENTITY_DISPUTES_KEY, &disputes).await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Persist the given signature upload, so it's retried until it reaches
+    /// the server, see [`OlmMachine::outgoing_signature_requests()`].
+    ///
+    /// Returns the request ID under which the upload will be sent.
+    ///
+    /// [`OlmMachine::outgoing_signature_requests()`]: crate::OlmMachine::outgoing_signature_requests
+    pub(crate) async fn queue_signature_upload(
+        &self,
+        request: &UploadSignaturesRequest,
+    ) -> Result<OwnedTransactionId> {
+        let _guard = self.inner.signature_uploads_lock.lock().await;
+
+        let request_id = TransactionId::new();
+        let mut pending = self.pending_signature_uploads_map().await?;
+
+        pending.insert(
+            request_id.clone(),
+            PendingSignatureUpload { signed_keys: request.signed_keys.clone() },
+        );
+        self.set_value(PENDING_SIGNATURE_UPLOADS_KEY, &pending).await?;
+
+        Ok(request_id)
+    }
+
+    /// Get the signature uploads which haven't reached the server yet, see
+    /// [`Store::queue_signature_upload()`].
+    pub(crate) async fn pending_signature_uploads(
+        &self,
+    ) -> Result<Vec<(OwnedTransactionId, UploadSignaturesRequest)>> {
+        Ok(self
+            .pending_signature_uploads_map()
+            .await?
+            .into_iter()
+            .map(|(request_id, upload)| {
+                (request_id, UploadSignaturesRequest::new(upload.signed_keys))
+            })
+            .collect())
+    }
+
+    /// Remove a signature upload from the queue, because the server received
+    /// it.
+    ///
+    /// Returns `false` if there was no pending signature upload with the given
+    /// request ID.
+    pub(crate) async fn remove_signature_upload(&self, request_id: &TransactionId) -> Result<bool> {
+        let _guard = self.inner.signature_uploads_lock.lock().await;
+
+        let mut pending = self.pending_signature_uploads_map().await?;
+
+        if pending.remove(request_id).is_none() {
+            return Ok(false);
+        }
+
+        if pending.is_empty() {
+            self.remove_custom_value(PENDING_SIGNATURE_UPLOADS_KEY).await?;
+        } else {
+            self.set_value(PENDING_SIGNATURE_UPLOADS_KEY, &pending).await?;
+        }
+
+        Ok(true)
+    }
+
+    async fn pending_signature_uploads_map(
+        &self,
+    ) -> Result<BTreeMap<OwnedTransactionId, PendingSignatureUpload>> {
+        Ok(self.get_value(PENDING_SIGNATURE_UPLOADS_KEY).await?.unwrap_or_default())
+    }
+
+    /// Persist the to-device requests which are queued up in memory until
+    /// they reach the server, so they can be sent out after a restart, see
+    /// [`OlmMachine::outgoing_requests()`].
+    ///
+    /// The given requests replace the ones which were persisted before, so
+    /// requests which were sent out, or dropped by the component which created
+    /// them, are removed. The requests are kept in memory as well, so nothing
+    /// is written if they didn't change.
+    ///
+    /// [`OlmMachine::outgoing_requests()`]: crate::OlmMachine::outgoing_requests
+    pub(crate) async fn persist_to_device_requests(
+        &self,
+        requests: BTreeMap<OwnedTransactionId, ToDeviceRequest>,
+    ) -> Result<()> {
+        let mut pending = self.inner.pending_to_device_requests.lock().await;
+
+        if pending.is_none() {
+            *pending =
+                Some(self.get_value(PENDING_TO_DEVICE_REQUESTS_KEY).await?.unwrap_or_default());
+        }
+
+        if pending.as_ref().is_some_and(|pending| pending.keys().eq(requests.keys())) {
+            return Ok(());
+        }
+
+        if requests.is_empty() {
+            self.remove_custom_value(PENDING_TO_DEVICE_REQUESTS_KEY).await?;
+        } else {
+            self.set_value(PENDING_TO_DEVICE_REQUESTS_KEY, &requests).await?;
+        }
+
+        *pending = Some(requests);
+
+        Ok(())
+    }
+
+    /// Get the to-device requests which haven't reached the server yet, see
+    /// [`Store::persist_to_device_requests()`].
+    pub(crate) async fn pending_to_device_requests(
+        &self,
+    ) -> Result<BTreeMap<OwnedTransactionId, ToDeviceRequest>> {
+        let mut pending = self.inner.pending_to_device_requests.lock().await;
+
+        if let Some(pending) = pending.as_ref() {
+            return Ok(pending.clone());
+        }
+
+        let requests: BTreeMap<_, _> =
+            self.get_value(PENDING_TO_DEVICE_REQUESTS_KEY).await?.unwrap_or_default();
+        *pending = Some(requests.clone());
+
+        Ok(requests)
+    }
+
+    /// Don't try to claim one-time keys for the given devices until the given
+    /// duration has passed, because claiming one keeps failing.
+    ///
+    /// Room keys are withheld from such devices using the
+    /// [`WithheldCode::Unavailable`] code in the meantime.
+    ///
+    /// [`WithheldCode::Unavailable`]: matrix_sdk_common::deserialized_responses::WithheldCode::Unavailable
+    pub(crate) async fn mark_devices_as_unreachable(
+        &self,
+        devices: impl IntoIterator<Item = (OwnedUserId, OwnedDeviceId)>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let _guard = self.inner.unreachable_devices_lock.lock().await;
+
+        let now = self.clock().now_secs();
+        let ttl = UInt::new_saturating(ttl.as_secs());
+        let until = SecondsSinceUnixEpoch(now.0.saturating_add(ttl));
+
+        let mut unreachable = self.load_unreachable_devices().await?;
+
+        for (user_id, device_id) in devices {
+            unreachable.entry(user_id).or_default().insert(device_id, until);
+        }
+
+        self.save_unreachable_devices(unreachable, now).await
+    }
+
+    /// Remove the given devices from the unreachable devices, see
+    /// [`Store::mark_devices_as_unreachable()`].
+    pub(crate) async fn mark_devices_as_reachable<'a>(
+        &self,
+        devices: impl IntoIterator<Item = (&'a UserId, &'a DeviceId)>,
+    ) -> Result<()> {
+        let _guard = self.inner.unreachable_devices_lock.lock().await;
+
+        let mut unreachable = self.load_unreachable_devices().await?;
+        let mut changed = false;
+
+        for (user_id, device_id) in devices {
+            if let Some(user_devices) = unreachable.get_mut(user_id) {
+                changed |= user_devices.remove(device_id).is_some();
+            }
+        }
+
+        if changed {
+            self.save_unreachable_devices(unreachable, self.clock().now_secs()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the devices we currently don't try to claim one-time keys for, see
+    /// [`Store::mark_devices_as_unreachable()`].
+    ///
+    /// Devices we have an Olm session with by now, e.g. because they created
+    /// one with us, are reachable again and are removed from the list.
+    pub(crate) async fn unreachable_devices(
+        &self,
+    ) -> Result<BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>> {
+        let now = self.clock().now_secs();
+        let mut unreachable: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        let mut reachable = Vec::new();
+
+        for (user_id, devices) in self.load_unreachable_devices().await? {
+            for (device_id, until) in devices {
+                if until <= now {
+                    continue;
+                }
+
+                if self.has_olm_session(&user_id, &device_id).await? {
+                    reachable.push((user_id.clone(), device_id));
+                } else {
+                    unreachable.entry(user_id.clone()).or_default().insert(device_id);
+                }
+            }
+        }
+
+        if !reachable.is_empty() {
+            self.mark_devices_as_reachable(
+                reachable.iter().map(|(user_id, device_id)| (&**user_id, &**device_id)),
+            )
+            .await?;
+        }
+
+        Ok(unreachable)
+    }
+
+    /// Do we have an Olm session with the given device?
+    async fn has_olm_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        let Some(sender_key) = self
+            .get_device_data(user_id, device_id)
+            .await?
+            .and_then(|device| device.curve25519_key())
+        else {
+            return Ok(false);
+        };
+
+        Ok(match self.get_sessions(&sender_key.to_base64()).await? {
+            Some(sessions) => !sessions.lock().await.is_empty(),
+            None => false,
+        })
+    }
+
+    async fn load_unreachable_devices(&self) -> Result<UnreachableDevices> {
+        let cache = self.cache().await?;
+
+        if let Some(unreachable) = cache.unreachable_devices.read().clone() {
+            return Ok(unreachable);
+        }
+
+        let unreachable: UnreachableDevices =
+            self.get_value(UNREACHABLE_DEVICES_KEY).await?.unwrap_or_default();
+        *cache.unreachable_devices.write() = Some(unreachable.clone());
+
+        Ok(unreachable)
+    }
+
+    /// Persist the given unreachable devices, dropping the ones whose time
+    /// has run out.
+    async fn save_unreachable_devices(
+        &self,
+        mut unreachable: UnreachableDevices,
+        now: SecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        for devices in unreachable.values_mut() {
+            devices.retain(|_, until| *until > now);
+        }
+        unreachable.retain(|_, devices| !devices.is_empty());
+
+        if unreachable.is_empty() {
+            self.remove_custom_value(UNREACHABLE_DEVICES_KEY).await?;
+        } else {
+            self.set_value(UNREACHABLE_DEVICES_KEY, &unreachable).await?;
+        }
+
+        *self.cache().await?.unreachable_devices.write() = Some(unreachable);
+
+        Ok(())
+    }
+
+    /// Get the E2EE statistics of our account.
+    ///
+    /// This includes the counts which weren't persisted yet, see
+    /// [`Store::record_statistics()`].
+    pub async fn statistics(&self) -> Result<CryptoStatistics> {
+        let mut statistics = self.get_value(STATISTICS_KEY).await?.unwrap_or_default();
+        self.inner.statistics.apply_pending(&mut statistics);
+
+        Ok(statistics)
+    }
+
+    /// Count something in the E2EE statistics of our account.
+    ///
+    /// The counts are only kept in memory, and are persisted the next time
+    /// changes are saved using [`Store::save_changes()`], so this should be
+    /// called once the counted change was saved, or is part of the changes
+    /// which are about to be saved.
+    pub(crate) fn record_statistics(&self, update: impl FnOnce(&mut CryptoStatistics)) {
+        self.inner.statistics.record(update);
+    }
+
+    /// Count the result of decrypting the room event with the given ID in the
+    /// E2EE statistics of our account.
+    ///
+    /// Retrying the decryption of an event doesn't count it again, see
+    /// [`Store::record_statistics()`] for when the count is persisted.
+    pub(crate) fn record_decryption(&self, event_id: Option<OwnedEventId>, decrypted: bool) {
+        self.inner.statistics.record_decryption(event_id, decrypted);
+    }
+
+    /// Persist the counts of the E2EE statistics which were recorded since
+    /// they were last persisted.
+    ///
+    /// The statistics are informational, so failing to persist them is logged
+    /// instead of being returned to the caller. The counts are kept in memory
+    /// and persisted the next time.
+    pub(crate) async fn flush_statistics(&self) {
+        let _guard = self.inner.statistics_flush_lock.lock().await;
+
+        let Some(pending) = self.inner.statistics.take_pending() else {
+            return;
+        };
+
+        let result = async {
+            let mut statistics: CryptoStatistics =
+                self.get_value(STATISTICS_KEY).await?.unwrap_or_default();
+            pending.apply_to(&mut statistics);
+
+            self.set_value(STATISTICS_KEY, &statistics).await
+        }
+        .await;
+
+        if let Err(error) = result {
+            warn!("Couldn't persist the E2EE statistics: {error:?}");
+            self.inner.statistics.restore_pending(pending);
+        }
+    }
+
+    /// Get custom stored value associated with a key
+    ///
+    /// The value is read in whichever [`ValueCodec`] it was stored with.
+    pub async fn get_value<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let Some(value) = self.get_custom_value(key).await? else {
+            return Ok(None);
+        };
+        let (deserialized, _) = ValueCodec::decode(&value)?;
+        Ok(Some(deserialized))
+    }
 
     /// Store custom value associated with a key
     pub async fn set_value(&self, key: &str, value: &impl Serialize) -> Result<()> {
@@ -1104,33 +2485,101 @@ impl Store {
         Ok(())
     }
 
-    fn serialize_value(&self, value: &impl Serialize) -> Result<Vec<u8>> {
-        let serialized =
-            rmp_serde::to_vec_named(value).map_err(|x| CryptoStoreError::Backend(x.into()))?;
-        Ok(serialized)
+    /// The format custom values are serialized in, see
+    /// [`OlmMachineBuilder::value_codec()`].
+    ///
+    /// [`OlmMachineBuilder::value_codec()`]: crate::OlmMachineBuilder::value_codec
+    pub fn value_codec(&self) -> ValueCodec {
+        self.inner.store.value_codec()
     }
 
-    fn deserialize_value<T: DeserializeOwned>(&self, value: &[u8]) -> Result<T> {
-        let deserialized =
-            rmp_serde::from_slice(value).map_err(|e| CryptoStoreError::Backend(e.into()))?;
-        Ok(deserialized)
+    /// Rewrite the custom values stored under the given keys in the
+    /// configured [`ValueCodec`].
+    ///
+    /// Values in another format are still read, this is only needed if the
+    /// values should be stored in the configured format, e.g. to inspect them
+    /// with JSON tooling. The store can't list the custom values, so their
+    /// keys need to be given. Missing values, and values which are already in
+    /// the configured format, are skipped.
+    ///
+    /// The values are converted as they are stored, without going through
+    /// their types, see [`ValueCodec`]. A value which is changed while it's
+    /// being migrated may be overwritten, so this should be called before the
+    /// values are used, e.g. right after the [`OlmMachine`] was created.
+    ///
+    /// Returns the number of values which were rewritten.
+    ///
+    /// [`OlmMachine`]: crate::OlmMachine
+    pub async fn migrate_values(&self, keys: &[&str]) -> Result<usize> {
+        let codec = self.value_codec();
+        let mut migrated = 0;
+
+        for key in keys {
+            let Some(value) = self.get_custom_value(key).await? else {
+                continue;
+            };
+
+            if let Some(transcoded) = codec.transcode(&value)? {
+                self.set_custom_value(key, transcoded).await?;
+                trace!(key, ?codec, "Rewrote a custom value in another format");
+                migrated += 1;
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    /// Get a typed view on the application data stored in the given namespace.
+    ///
+    /// Unlike [`Store::get_value()`] and [`Store::set_value()`], the entries
+    /// are kept per namespace, so they can't collide with the values stored
+    /// by the SDK itself or by other parts of the application. Values stored
+    /// before using [`Store::set_value()`] can be moved into a namespace using
+    /// [`AppData::migrate_legacy_values()`].
+    ///
+    /// Returns [`CryptoStoreError::EmptyAppDataNamespace`] if the namespace is
+    /// empty.
+    pub fn app_data<T: Serialize + DeserializeOwned>(&self, namespace: &str) -> Result<AppData<T>> {
+        AppData::new(self.clone(), namespace)
+    }
+
+    fn serialize_value(&self, value: &impl Serialize) -> Result<Vec<u8>> {
+        self.value_codec().encode(value)
     }
 
     /// Receive notifications of room keys being received as a [`Stream`].
     ///
-    /// Each time a room key is updated in any way, an update will be sent to
-    /// the stream. Updates that happen at the same time are batched into a
-    /// [`Vec`].
+    /// Each time a room key is updated in any way, an update will be sent to
+    /// the stream. Updates that happen at the same time are batched into a
+    /// [`Vec`].
+    ///
+    /// If the reader of the stream lags too far behind an error will be sent to
+    /// the reader.
+    ///
+    /// The stream will terminate once all references to the underlying
+    /// `CryptoStoreWrapper` are dropped.
+    pub fn room_keys_received_stream(
+        &self,
+    ) -> impl Stream<Item = Result<Vec<RoomKeyInfo>, BroadcastStreamRecvError>> {
+        self.inner.store.room_keys_received_stream()
+    }
+
+    /// Receive notifications of room keys being deleted as a [`Stream`].
+    ///
+    /// Each time room keys are deleted, for example by
+    /// [`Store::apply_room_key_retention()`], an update will be sent to the
+    /// stream. Keys of the same room which are deleted at the same time are
+    /// batched into a [`Vec`].
+    ///
+    /// The events which were encrypted using those room keys can't be
+    /// decrypted anymore, unless the room keys are received again.
     ///
     /// If the reader of the stream lags too far behind an error will be sent to
     /// the reader.
-    ///
-    /// The stream will terminate once all references to the underlying
-    /// `CryptoStoreWrapper` are dropped.
-    pub fn room_keys_received_stream(
+    pub fn room_keys_deleted_stream(
         &self,
     ) -> impl Stream<Item = Result<Vec<RoomKeyInfo>, BroadcastStreamRecvError>> {
-        self.inner.store.room_keys_received_stream()
+        self.inner.store.room_keys_deleted_stream()
     }
 
     /// Receive notifications of received `m.room_key.withheld` messages.
@@ -1204,6 +2653,38 @@ impl Store {
         })
     }
 
+    /// Returns a stream of the user identities which changed after they had
+    /// been pinned, or verified.
+    ///
+    /// We pin the master key of a user the first time we see it. If it changes
+    /// afterwards, the identity needs the approval of the user, see
+    /// [`OtherUserIdentity::identity_needs_user_approval()`], which is reported
+    /// on this stream, as is a change of the identity of a user which we had
+    /// verified before.
+    ///
+    /// Caution: the returned stream will never terminate, and it holds a
+    /// reference to the [`CryptoStore`]. Listeners should be careful to avoid
+    /// resource leaks.
+    ///
+    /// [`OtherUserIdentity::identity_needs_user_approval()`]: crate::OtherUserIdentity::identity_needs_user_approval
+    pub fn pin_violations_stream(&self) -> impl Stream<Item = PinViolation> {
+        self.user_identities_stream().flat_map(|updates| {
+            let violations: Vec<_> = updates
+                .changed
+                .into_values()
+                .filter_map(|identity| identity.other())
+                .filter_map(|identity| {
+                    let verification_violation = identity.has_verification_violation();
+
+                    (verification_violation || identity.identity_needs_user_approval())
+                        .then_some(PinViolation { identity, verification_violation })
+                })
+                .collect();
+
+            stream::iter(violations)
+        })
+    }
+
     /// Returns a stream of device updates, allowing users to listen for
     /// notifications about new or changed devices.
     ///
@@ -1248,6 +2729,130 @@ impl Store {
         })
     }
 
+    /// Returns a stream of the changes of our own devices.
+    ///
+    /// Unlike [`Store::devices_stream`], which reports the new or changed
+    /// devices of every user, this stream only reports the devices of our own
+    /// account and tells what changed about them. This can be used, for
+    /// example, to alert the user when a new device logs into their account.
+    ///
+    /// The devices we already know about when this method is called are not
+    /// reported as added.
+    ///
+    /// Caution: the returned stream will never terminate, and it holds a
+    /// reference to the [`CryptoStore`]. Listeners should be careful to avoid
+    /// resource leaks.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk_crypto::{store::types::OwnDeviceChange, OlmMachine};
+    /// # use futures_util::{pin_mut, StreamExt};
+    /// # let machine: OlmMachine = unimplemented!();
+    /// # futures_executor::block_on(async {
+    /// let own_devices_stream = machine.store().own_devices_stream().await?;
+    /// pin_mut!(own_devices_stream);
+    ///
+    /// while let Some(change) = own_devices_stream.next().await {
+    ///     if let OwnDeviceChange::Added(device) = change {
+    ///         println!("A new device logged in {}", device.device_id());
+    ///     }
+    /// }
+    /// # anyhow::Ok(()) });
+    /// ```
+    pub async fn own_devices_stream(&self) -> Result<impl Stream<Item = OwnDeviceChange>> {
+        let known: BTreeMap<_, _> = self
+            .get_user_devices(self.user_id())
+            .await?
+            .devices()
+            .map(|device| (device.device_id().to_owned(), OwnDeviceState::new(&device)))
+            .collect();
+        let known = Arc::new(Mutex::new(known));
+        let this = self.clone();
+
+        Ok(self
+            .inner
+            .store
+            .identities_stream()
+            .then(move |(_, identities, devices)| {
+                let this = this.clone();
+                let known = known.clone();
+
+                async move {
+                    let mut known = known.lock().await;
+                    this.collect_own_device_changes(&mut known, identities, devices).await
+                }
+            })
+            .flat_map(stream::iter))
+    }
+
+    /// Compare the devices in the given changes with what we know about our
+    /// own devices, see [`Store::own_devices_stream()`].
+    async fn collect_own_device_changes(
+        &self,
+        known: &mut BTreeMap<OwnedDeviceId, OwnDeviceState>,
+        identities: IdentityChanges,
+        devices: DeviceChanges,
+    ) -> Vec<OwnDeviceChange> {
+        let user_id = self.user_id();
+        let mut changes = Vec::new();
+
+        for device in devices.deleted.into_iter().filter(|d| d.user_id() == user_id) {
+            if known.remove(device.device_id()).is_some() {
+                changes.push(OwnDeviceChange::Deleted(device));
+            }
+        }
+
+        let mut updated: Vec<_> = devices
+            .new
+            .into_iter()
+            .chain(devices.changed)
+            .filter(|d| d.user_id() == user_id)
+            .collect();
+
+        // A new or changed identity of our own might have changed which of our
+        // devices are verified, so we need to check all of them.
+        if identities.new.iter().chain(&identities.changed).any(|i| i.user_id() == user_id) {
+            match self.get_device_data_for_user(user_id).await {
+                Ok(devices) => updated = devices.into_values().collect(),
+                Err(e) => warn!("Couldn't load our own devices after our identity changed: {e:?}"),
+            }
+        }
+
+        for device in updated {
+            let device = match self.wrap_device_data(device).await {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!("Couldn't load the trust state of one of our own devices: {e:?}");
+                    continue;
+                }
+            };
+
+            let state = OwnDeviceState::new(&device);
+
+            let Some(previous) = known.insert(device.device_id().to_owned(), state.clone()) else {
+                changes.push(OwnDeviceChange::Added(device));
+                continue;
+            };
+
+            if previous.display_name != state.display_name {
+                changes.push(OwnDeviceChange::DisplayNameChanged {
+                    device: device.clone(),
+                    previous: previous.display_name,
+                });
+            }
+
+            if previous.local_trust != state.local_trust || previous.verified != state.verified {
+                changes.push(OwnDeviceChange::TrustChanged {
+                    device,
+                    previously_verified: previous.verified,
+                });
+            }
+        }
+
+        changes
+    }
+
     /// Returns a [`Stream`] of user identity and device updates
     ///
     /// The stream returned by this method returns the same data as
@@ -1271,6 +2876,27 @@ impl Store {
         self.inner.store.create_store_lock(lock_key, lock_value)
     }
 
+    /// Creates a `CrossProcessStoreLock` for this store which uses the [Web
+    /// Locks API] instead of leases kept in the store, so that multiple tabs
+    /// or workers of a web client can coordinate their access to the store.
+    ///
+    /// The names of the Web Locks are prefixed with our user ID and device ID,
+    /// so the locks of different stores don't clash. Use
+    /// [`WebLocksBackingStore::is_supported()`] to check whether the Web Locks
+    /// API is available.
+    ///
+    /// [Web Locks API]: https://developer.mozilla.org/en-US/docs/Web/API/Web_Locks_API
+    /// [`WebLocksBackingStore::is_supported()`]: matrix_sdk_common::store_locks::WebLocksBackingStore::is_supported
+    #[cfg(all(target_family = "wasm", feature = "js"))]
+    pub fn create_web_store_lock(
+        &self,
+        lock_key: String,
+        lock_value: String,
+    ) -> CrossProcessStoreLock<LockableCryptoStore> {
+        let prefix = format!("matrix-sdk-crypto:{}:{}", self.user_id(), self.device_id());
+        self.inner.store.create_web_store_lock(prefix, lock_key, lock_value)
+    }
+
     /// Receive notifications of gossipped secrets being received and stored in
     /// the secret inbox as a [`Stream`].
     ///
@@ -1328,7 +2954,7 @@ impl Store {
     /// ```no_run
     /// # use matrix_sdk_crypto::{
     /// #    OlmMachine,
-    /// #    store::types::StoredRoomKeyBundleData,
+    /// #    store::types::{RoomKeyBundleSenderPermissions, StoredRoomKeyBundleData},
     /// #    types::room_history::RoomKeyBundle
     /// # };
     /// # use ruma::{device_id, user_id};
@@ -1342,13 +2968,16 @@ impl Store {
     /// while let Some(bundle_info) = bundle_stream.next().await {
     ///     // Try to find the bundle content in the store and if it's valid accept it.
     ///     if let Some(bundle_content) = machine.store().get_received_room_key_bundle_data(&bundle_info.room_id, &bundle_info.sender).await? {
-    ///         let StoredRoomKeyBundleData { sender_user, sender_data, bundle_data } = bundle_content;
+    ///         let StoredRoomKeyBundleData { sender_user, sender_data, .. } = bundle_content;
     ///         // Download the bundle now and import it.
     ///         let bundle: RoomKeyBundle = todo!("Download the bundle");
+    ///         // Look up the membership and power level of the sender.
+    ///         let permissions: RoomKeyBundleSenderPermissions = todo!("Check the room state");
     ///         machine.store().receive_room_key_bundle(
     ///             &bundle_info.room_id,
     ///             &sender_user,
     ///             &sender_data,
+    ///             permissions,
     ///             bundle,
     ///             |_, _| {},
     ///         ).await?;
@@ -1360,6 +2989,20 @@ impl Store {
         self.inner.store.historic_room_key_stream()
     }
 
+    /// Receive notifications of suspicious behaviour of other devices as a
+    /// [`Stream`].
+    ///
+    /// Currently this reports devices which tried to create more new Olm
+    /// sessions with us than the [`Store::session_creation_limit()`] allows,
+    /// which exhausts our one-time keys on the server. Clients can display
+    /// these to the user, e.g. to suggest blocking the device.
+    ///
+    /// If the reader of the stream lags too far behind, a warning will be
+    /// logged and items will be dropped.
+    pub fn suspicious_activity_stream(&self) -> impl Stream<Item = SuspiciousActivity> {
+        self.inner.session_spam_detector.stream()
+    }
+
     /// Import the given room keys into the store.
     ///
     /// # Arguments
@@ -1379,7 +3022,8 @@ impl Store {
         progress_listener: impl Fn(usize, usize),
     ) -> Result<RoomKeyImportResult> {
         let exported_keys: Vec<&ExportedRoomKey> = exported_keys.iter().collect();
-        self.import_sessions_impl(exported_keys, from_backup_version, progress_listener).await
+        self.import_sessions_impl(exported_keys, from_backup_version, progress_listener, |_, _| {})
+            .await
     }
 
     /// Import the given room keys into our store.
@@ -1416,11 +3060,146 @@ impl Store {
         self.import_room_keys(exported_keys, None, progress_listener).await
     }
 
+    /// Import the given room keys into our store, remembering the progress of
+    /// the import so it can be resumed using [`Store::resume_import()`] if it
+    /// gets interrupted, for example because the app is killed.
+    ///
+    /// The room keys are saved in batches, and a checkpoint is persisted in
+    /// the store after every batch. Any previous checkpoint is discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - A list of previously exported keys that should be
+    ///   imported into our store. If we already have a better version of a key
+    ///   the key will *not* be imported.
+    ///
+    /// * `progress_listener` - A closure that will be called with the index of
+    ///   the room key being processed and the total number of room keys.
+    pub async fn import_exported_room_keys_resumable(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let checkpoint = RoomKeyImportCheckpoint {
+            digest: Self::room_key_export_digest(&exported_keys),
+            offset: 0,
+            imported_count: 0,
+        };
+
+        self.run_room_key_import(exported_keys, checkpoint, progress_listener).await
+    }
+
+    /// Resume a room key import that was started using
+    /// [`Store::import_exported_room_keys_resumable()`] and didn't finish.
+    ///
+    /// The same room key export needs to be passed in again. The room keys
+    /// which were already processed are skipped. If there is no checkpoint,
+    /// or the checkpoint belongs to a different export, the import starts
+    /// from the beginning.
+    ///
+    /// The [`RoomKeyImportResult::imported_count`] includes the room keys
+    /// which were imported before the import was interrupted, while
+    /// [`RoomKeyImportResult::keys`] only contains the room keys imported by
+    /// this call.
+    pub async fn resume_import(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let digest = Self::room_key_export_digest(&exported_keys);
+
+        let checkpoint = match self
+            .get_value::<RoomKeyImportCheckpoint>(ROOM_KEY_IMPORT_CHECKPOINT_KEY)
+            .await?
+        {
+            Some(checkpoint) if checkpoint.digest == digest => {
+                info!(offset = checkpoint.offset, "Resuming the import of room keys");
+                checkpoint
+            }
+            Some(_) => {
+                warn!(
+                    "The room key import checkpoint belongs to a different export, \
+                     starting the import from the beginning"
+                );
+                RoomKeyImportCheckpoint { digest, offset: 0, imported_count: 0 }
+            }
+            None => RoomKeyImportCheckpoint { digest, offset: 0, imported_count: 0 },
+        };
+
+        self.run_room_key_import(exported_keys, checkpoint, progress_listener).await
+    }
+
+    /// Is there a room key import which was started using
+    /// [`Store::import_exported_room_keys_resumable()`], that didn't finish
+    /// and should be resumed using [`Store::resume_import()`]?
+    pub async fn has_pending_room_key_import(&self) -> Result<bool> {
+        Ok(self.get_custom_value(ROOM_KEY_IMPORT_CHECKPOINT_KEY).await?.is_some())
+    }
+
+    async fn run_room_key_import(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        mut checkpoint: RoomKeyImportCheckpoint,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let total_count = exported_keys.len();
+        let mut keys: BTreeMap<OwnedRoomId, BTreeMap<String, BTreeSet<String>>> = BTreeMap::new();
+
+        while checkpoint.offset < total_count {
+            let offset = checkpoint.offset;
+            let end = (offset + ROOM_KEY_IMPORT_BATCH_SIZE).min(total_count);
+            let batch: Vec<&ExportedRoomKey> = exported_keys[offset..end].iter().collect();
+
+            let result = self
+                .import_sessions_impl(
+                    batch,
+                    None,
+                    |i, _| progress_listener(offset + i, total_count),
+                    |_, _| {},
+                )
+                .await?;
+
+            for (room_id, sessions) in result.keys {
+                let room_keys = keys.entry(room_id).or_default();
+
+                for (sender_key, session_ids) in sessions {
+                    room_keys.entry(sender_key).or_default().extend(session_ids);
+                }
+            }
+
+            checkpoint.offset = end;
+            checkpoint.imported_count += result.imported_count;
+            self.set_value(ROOM_KEY_IMPORT_CHECKPOINT_KEY, &checkpoint).await?;
+        }
+
+        self.remove_custom_value(ROOM_KEY_IMPORT_CHECKPOINT_KEY).await?;
+
+        Ok(RoomKeyImportResult::new(checkpoint.imported_count, total_count, keys))
+    }
+
+    /// Calculate a digest of the given room key export, used to check that a
+    /// resumed import is importing the same export.
+    fn room_key_export_digest(exported_keys: &[ExportedRoomKey]) -> String {
+        let mut hasher = Sha256::new();
+
+        for key in exported_keys {
+            hasher.update(key.room_id.as_bytes());
+            hasher.update([0]);
+            hasher.update(key.session_id.as_bytes());
+            hasher.update([0]);
+            hasher.update(key.session_key.to_base64().as_bytes());
+            hasher.update([0]);
+        }
+
+        base64_encode(hasher.finalize())
+    }
+
     async fn import_sessions_impl<T>(
         &self,
         room_keys: Vec<T>,
         from_backup_version: Option<&str>,
         progress_listener: impl Fn(usize, usize),
+        mut skipped_listener: impl FnMut(&str, RoomKeySkipReason),
     ) -> Result<RoomKeyImportResult>
     where
         T: TryInto<InboundGroupSession> + RoomKeyExport + Copy,
@@ -1428,14 +3207,21 @@ impl Store {
     {
         let mut sessions = Vec::new();
 
+        /// Check if the new session should replace the old one, returning the
+        /// reason why not otherwise.
         async fn new_session_better(
             session: &InboundGroupSession,
             old_session: Option<InboundGroupSession>,
-        ) -> bool {
-            if let Some(old_session) = &old_session {
-                session.compare(old_session).await == SessionOrdering::Better
-            } else {
-                true
+        ) -> std::result::Result<(), RoomKeySkipReason> {
+            let Some(old_session) = &old_session else {
+                return Ok(());
+            };
+
+            match session.compare(old_session).await {
+                SessionOrdering::Better => Ok(()),
+                SessionOrdering::Equal => Err(RoomKeySkipReason::AlreadyKnown),
+                SessionOrdering::Worse => Err(RoomKeySkipReason::BetterSessionExists),
+                SessionOrdering::Unconnected => Err(RoomKeySkipReason::Conflicting),
             }
         }
 
@@ -1453,18 +3239,21 @@ impl Store {
 
                     // Only import the session if we didn't have this session or
                     // if it's a better version of the same session.
-                    if new_session_better(&session, old_session).await {
-                        if from_backup_version.is_some() {
-                            session.mark_as_backed_up();
+                    match new_session_better(&session, old_session).await {
+                        Ok(()) => {
+                            if from_backup_version.is_some() {
+                                session.mark_as_backed_up();
+                            }
+
+                            keys.entry(session.room_id().to_owned())
+                                .or_insert_with(BTreeMap::new)
+                                .entry(session.sender_key().to_base64())
+                                .or_insert_with(BTreeSet::new)
+                                .insert(session.session_id().to_owned());
+
+                            sessions.push(session);
                         }
-
-                        keys.entry(session.room_id().to_owned())
-                            .or_insert_with(BTreeMap::new)
-                            .entry(session.sender_key().to_base64())
-                            .or_insert_with(BTreeSet::new)
-                            .insert(session.session_id().to_owned());
-
-                        sessions.push(session);
+                        Err(reason) => skipped_listener(session.session_id(), reason),
                     }
                 }
                 Err(e) => {
@@ -1475,6 +3264,8 @@ impl Store {
                         error = ?e,
                         "Couldn't import a room key from a file export."
                     );
+
+                    skipped_listener(key.session_id(), RoomKeySkipReason::Invalid);
                 }
             }
 
@@ -1485,6 +3276,13 @@ impl Store {
 
         self.inner.store.save_inbound_group_sessions(sessions, from_backup_version).await?;
 
+        if imported_count > 0 {
+            self.record_statistics(|statistics| {
+                statistics.megolm_sessions_stored += imported_count as u64
+            });
+            self.flush_statistics().await;
+        }
+
         info!(total_count, imported_count, room_keys = ?keys, "Successfully imported room keys");
 
         Ok(RoomKeyImportResult::new(imported_count, total_count, keys))
@@ -1494,6 +3292,17 @@ impl Store {
         self.inner.store.clone()
     }
 
+    /// Wipe the secrets of the store, see [`CryptoStoreWrapper::shred()`].
+    ///
+    /// The cached [`Account`] is dropped as well, so it can't be written back
+    /// to the store.
+    pub(crate) async fn shred(&self) -> Result<ShredReport> {
+        let result = self.inner.store.shred().await;
+        self.inner.cache.read().await.account.lock().await.take();
+
+        result
+    }
+
     /// Export the keys that match the given predicate.
     ///
     /// # Arguments
@@ -1533,9 +3342,137 @@ impl Store {
         Ok(exported)
     }
 
+    /// Delete the room keys which should no longer be kept according to the
+    /// given [`RoomKeyRetentionPolicy`].
+    ///
+    /// The deleted room keys are sent to the listeners of
+    /// [`Store::room_keys_deleted_stream()`], and returned.
+    pub async fn apply_room_key_retention(
+        &self,
+        policy: &RoomKeyRetentionPolicy,
+    ) -> Result<Vec<RoomKeyInfo>> {
+        const BATCH_SIZE: usize = 1000;
+
+        if policy.is_disabled() {
+            return Ok(Vec::new());
+        }
+
+        let now = self.clock().now_secs();
+        let mut keys_per_room: BTreeMap<OwnedRoomId, Vec<_>> = BTreeMap::new();
+        let mut sessions = pin!(self.get_inbound_group_sessions_stream(BATCH_SIZE));
+
+        while let Some(session) = sessions.try_next().await? {
+            keys_per_room
+                .entry(session.room_id().to_owned())
+                .or_default()
+                .push((session.received_at(), RoomKeyInfo::from(&session)));
+        }
+
+        let mut deleted = Vec::new();
+
+        for (room_id, keys) in keys_per_room {
+            let to_delete = policy.keys_to_delete(keys, now);
+
+            if to_delete.is_empty() {
+                continue;
+            }
+
+            info!(
+                ?room_id,
+                count = to_delete.len(),
+                "Deleting room keys according to the retention policy"
+            );
+
+            self.inner.store.delete_room_keys(&room_id, to_delete.clone()).await?;
+            deleted.extend(to_delete);
+        }
+
+        Ok(deleted)
+    }
+
+    /// Delete all the room keys, the outbound group session, the withheld
+    /// info, the received room key bundles and the settings of the given room,
+    /// for example once the user forgot the room.
+    ///
+    /// Everything is deleted at once, see
+    /// [`CryptoStore::delete_room_crypto_state()`]. The deleted room keys are
+    /// sent to the listeners of [`Store::room_keys_deleted_stream()`].
+    ///
+    /// The outbound group session may still be cached by the `OlmMachine`,
+    /// [`OlmMachine::delete_room_crypto_state()`] should be used instead of
+    /// this method if the machine is still in use.
+    ///
+    /// [`OlmMachine::delete_room_crypto_state()`]: crate::OlmMachine::delete_room_crypto_state
+    pub async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<DeletedRoomCryptoState> {
+        let custom_value_keys =
+            [Self::room_rotation_policy_key(room_id), Self::withheld_room_keys_key(room_id)];
+        let deleted =
+            self.inner.store.delete_room_crypto_state(room_id, &custom_value_keys).await?;
+
+        info!(
+            ?room_id,
+            room_keys = deleted.room_keys.len(),
+            outbound_group_session = deleted.outbound_group_session_deleted,
+            withheld = deleted.withheld_deleted,
+            room_key_bundles = deleted.room_key_bundle_senders.len(),
+            "Deleted the crypto state of the room"
+        );
+
+        Ok(deleted)
+    }
+
+    /// Get the rooms we have room keys for, with the number of room keys we
+    /// have for each of them and when we received the oldest and the newest
+    /// one.
+    ///
+    /// This can be used to show whether the encrypted history of a room is
+    /// likely to be decryptable, without loading all the room keys. The rooms
+    /// are returned sorted by room ID.
+    pub async fn rooms_with_keys(&self) -> Result<Vec<RoomKeySummary>> {
+        self.inner.store.get_room_key_summaries().await
+    }
+
+    /// Get a stream of all the inbound group sessions we have stored.
+    ///
+    /// The sessions are loaded from the store in batches of `batch_size`, so
+    /// they don't all need to be held in memory at once.
+    pub fn get_inbound_group_sessions_stream(
+        &self,
+        batch_size: usize,
+    ) -> impl Stream<Item = Result<InboundGroupSession>> {
+        let store = self.inner.store.clone();
+
+        // The state is `None` once we have reached the end of the sessions,
+        // otherwise it's the position of the next batch.
+        futures_util::stream::try_unfold(Some(None), move |after| {
+            let store = store.clone();
+
+            async move {
+                let Some(after) = after else {
+                    return Ok(None);
+                };
+
+                let batch = store.get_inbound_group_sessions_batch(after, batch_size).await?;
+                let next = batch.last().map(|session| {
+                    Some((session.room_id().to_owned(), session.session_id().to_owned()))
+                });
+
+                Ok(Some((futures_util::stream::iter(batch.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
     /// Export room keys matching a predicate, providing them as an async
     /// `Stream`.
     ///
+    /// The room keys are loaded from the store in batches, which means that
+    /// this can be used to export a large number of room keys without holding
+    /// all of them in memory.
+    ///
     /// # Arguments
     ///
     /// * `predicate` - A closure that will be called for every known
@@ -1555,44 +3492,52 @@ impl Store {
     /// let alice = user_id!("@alice:example.org");
     /// let machine = OlmMachine::new(&alice, device_id!("DEVICEID")).await;
     /// let room_id = room_id!("!test:localhost");
-    /// let mut keys = pin!(machine
-    ///     .store()
-    ///     .export_room_keys_stream(|s| s.room_id() == room_id)
-    ///     .await
-    ///     .unwrap());
+    /// let mut keys = pin!(machine.store().export_room_keys_stream(|s| s.room_id() == room_id));
     /// while let Some(key) = keys.next().await {
-    ///     println!("{}", key.room_id);
+    ///     println!("{}", key?.room_id);
     /// }
-    /// # };
+    /// # anyhow::Ok(()) };
     /// ```
-    pub async fn export_room_keys_stream(
+    pub fn export_room_keys_stream(
         &self,
-        predicate: impl FnMut(&InboundGroupSession) -> bool,
-    ) -> Result<impl Stream<Item = ExportedRoomKey>> {
-        // TODO: if/when there is a get_inbound_group_sessions_stream, use that here.
-        let sessions = self.get_inbound_group_sessions().await?;
-        Ok(futures_util::stream::iter(sessions.into_iter().filter(predicate))
-            .then(|session| async move { session.export().await }))
+        mut predicate: impl FnMut(&InboundGroupSession) -> bool,
+    ) -> impl Stream<Item = Result<ExportedRoomKey>> {
+        const EXPORT_BATCH_SIZE: usize = 1000;
+
+        self.get_inbound_group_sessions_stream(EXPORT_BATCH_SIZE)
+            .try_filter(move |session| std::future::ready(predicate(session)))
+            .and_then(|session| async move { Ok(session.export().await) })
     }
 
     /// Assemble a room key bundle for sharing encrypted history, as per
     /// [MSC4268].
     ///
+    /// The [`RoomKeyBundleOptions`] allow to limit the bundle to the recent
+    /// history of the room.
+    ///
     /// [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
     pub async fn build_room_key_bundle(
         &self,
         room_id: &RoomId,
+        options: RoomKeyBundleOptions,
     ) -> std::result::Result<RoomKeyBundle, CryptoStoreError> {
-        // TODO: make this WAY more efficient. We should only fetch sessions for the
-        // correct room.
-        let mut sessions = self.get_inbound_group_sessions().await?;
-        sessions.retain(|session| session.room_id == room_id);
+        let sessions = self.get_inbound_group_sessions_for_room(room_id, options.since).await?;
+        let (mut shared, not_shared): (Vec<_>, Vec<_>) =
+            sessions.into_iter().partition(|session| session.shared_history());
+
+        if let Some(max_keys) = options.max_keys {
+            // Sessions without a timestamp sort last, we can't tell how old they are.
+            shared.sort_by_key(|session| Reverse(session.received_at()));
+            shared.truncate(max_keys);
+        }
 
         let mut bundle = RoomKeyBundle::default();
-        for session in sessions {
-            if session.shared_history() {
-                bundle.room_keys.push(session.export().await.into());
-            } else {
+        for session in shared {
+            bundle.room_keys.push(session.export().await.into());
+        }
+
+        if options.include_withheld {
+            for session in not_shared {
                 bundle.withheld.push(RoomKeyWithheldContent::new(
                     session.algorithm().to_owned(),
                     WithheldCode::Unauthorised,
@@ -1607,6 +3552,163 @@ impl Store {
         Ok(bundle)
     }
 
+    /// Assemble a room key bundle for sharing encrypted history, like
+    /// [`Store::build_room_key_bundle()`] does with the default options, and
+    /// split it into chunks whose serialized JSON is at most `max_chunk_bytes`
+    /// long.
+    ///
+    /// This is useful if the bundle may not fit in a single upload to the
+    /// media repository. Every chunk needs to be uploaded on its own, and the
+    /// to-device message pointing to it needs to contain a
+    /// [`RoomKeyBundleChunk`] telling the recipient how to put the chunks
+    /// back together. See [`RoomKeyBundle::split()`] for the details.
+    ///
+    /// [`RoomKeyBundleChunk`]: crate::types::events::room_key_bundle::RoomKeyBundleChunk
+    pub async fn build_room_key_bundle_chunks(
+        &self,
+        room_id: &RoomId,
+        max_chunk_bytes: usize,
+    ) -> std::result::Result<Vec<RoomKeyBundle>, CryptoStoreError> {
+        let bundle = self.build_room_key_bundle(room_id, Default::default()).await?;
+        Ok(bundle.split(max_chunk_bytes)?)
+    }
+
+    /// Get all the chunks of the room key bundle the given user sent us for
+    /// the given room, ordered by their index.
+    ///
+    /// Returns `None` if the last bundle the user sent us for the room wasn't
+    /// split into chunks, or if some of its chunks are still missing. Once
+    /// all of them are there, they can be downloaded, put back together using
+    /// [`RoomKeyBundle::merge()`], and imported using
+    /// [`Store::receive_room_key_bundle()`] with the sender data returned by
+    /// [`Store::refresh_room_key_bundle_sender_data()`].
+    pub async fn get_received_room_key_bundle_chunks(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<Vec<RoomKeyBundleContent>>> {
+        let Some(data) = self.get_received_room_key_bundle_data(room_id, user_id).await? else {
+            return Ok(None);
+        };
+
+        let Some(count) = data.bundle_data.chunk.as_ref().map(|chunk| chunk.count) else {
+            return Ok(None);
+        };
+
+        let mut chunks = data.received_chunks;
+        chunks.push(data.bundle_data);
+        chunks.sort_by_key(|content| content.chunk.as_ref().map(|chunk| chunk.index));
+
+        Ok((chunks.len() == count).then_some(chunks))
+    }
+
+    /// Work out the [`SenderData`] of a received room key bundle again, using
+    /// what the store knows about the device which sent it now.
+    ///
+    /// The [`StoredRoomKeyBundleData::sender_data`] is a snapshot taken when
+    /// the bundle was received. If the device, or the cross-signing identity
+    /// of its owner, wasn't known yet, the bundle would be rejected by the
+    /// default [`RoomKeyBundleAcceptancePolicy`] even after we learned about
+    /// them, so this should be used before checking the policy.
+    ///
+    /// The snapshot is returned unchanged if the device isn't in the store,
+    /// or if its Ed25519 key doesn't match the one we saw when the bundle was
+    /// received.
+    pub async fn refresh_room_key_bundle_sender_data(
+        &self,
+        bundle: &StoredRoomKeyBundleData,
+    ) -> Result<SenderData> {
+        let snapshot = &bundle.sender_data;
+
+        let Some(device_id) = snapshot.device_id() else {
+            return Ok(snapshot.clone());
+        };
+
+        let Some(device) = self.get_device(&bundle.sender_user, device_id).await? else {
+            return Ok(snapshot.clone());
+        };
+
+        if let SenderData::DeviceInfo { device_keys, .. } = snapshot {
+            if device_keys.ed25519_key() != device.ed25519_key() {
+                warn!(
+                    %device_id,
+                    "The keys of the device which sent a room key bundle changed, not refreshing \
+                     its sender data"
+                );
+                return Ok(snapshot.clone());
+            }
+        }
+
+        Ok(SenderData::from_device(&device))
+    }
+
+    /// Forget the chunks of the room key bundle the given user sent us for
+    /// the given room, once the bundle was imported.
+    ///
+    /// Only the last received chunk is kept, like the data of a bundle which
+    /// wasn't split into chunks.
+    pub async fn clear_received_room_key_bundle_chunks(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<()> {
+        let Some(mut data) = self.get_received_room_key_bundle_data(room_id, user_id).await? else {
+            return Ok(());
+        };
+
+        if data.received_chunks.is_empty() {
+            return Ok(());
+        }
+
+        data.received_chunks.clear();
+        self.save_changes(Changes { received_room_key_bundles: vec![data], ..Default::default() })
+            .await
+    }
+
+    /// Carry the chunks of a room key bundle which were received before over
+    /// to a newly received chunk of the same bundle, so they are saved
+    /// together with it.
+    ///
+    /// `previous` is the data of the last bundle the sender sent us for the
+    /// room. Its chunks are dropped if they belong to another bundle, or if
+    /// they were sent by another device, since the chunks of a bundle are
+    /// imported using the [`SenderData`] of a single device.
+    ///
+    /// Returns `None` if the chunk has an invalid index and should be ignored.
+    pub(crate) fn merge_received_room_key_bundle_chunk(
+        previous: Option<StoredRoomKeyBundleData>,
+        mut data: StoredRoomKeyBundleData,
+    ) -> Option<StoredRoomKeyBundleData> {
+        let Some(chunk) = data.bundle_data.chunk.clone() else {
+            return Some(data);
+        };
+
+        if chunk.index >= chunk.count {
+            warn!(?chunk, "Received a room key bundle chunk with an invalid index, ignoring it");
+            return None;
+        }
+
+        let previous = previous.filter(|previous| {
+            data.sender_data.device_id().is_some()
+                && previous.sender_data.device_id() == data.sender_data.device_id()
+        });
+
+        data.received_chunks = previous
+            .into_iter()
+            .flat_map(|previous| previous.received_chunks.into_iter().chain([previous.bundle_data]))
+            .filter(|content| {
+                content.chunk.as_ref().is_some_and(|previous_chunk| {
+                    previous_chunk.bundle_id == chunk.bundle_id
+                        && previous_chunk.count == chunk.count
+                        && previous_chunk.index != chunk.index
+                })
+            })
+            .collect();
+        data.received_chunks.sort_by_key(|content| content.chunk.as_ref().map(|chunk| chunk.index));
+
+        Some(data)
+    }
+
     /// Import the contents of a downloaded and decrypted [MSC4268] key bundle.
     ///
     /// # Arguments
@@ -1617,6 +3719,15 @@ impl Store {
     ///   to this data.
     /// * `sender_data` - Information on the sending device at the time we
     ///   received that message.
+    /// * `sender_permissions` - What the state of the room says about
+    ///   `sender_user`.
+    ///
+    /// The bundle is checked against the
+    /// [`Store::room_key_bundle_acceptance_policy()`] first, nothing is
+    /// imported if it's rejected.
+    ///
+    /// Returns a [`RoomKeyBundleImportResult`] listing which room keys of the
+    /// bundle were imported, and why the other ones weren't.
     ///
     /// [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
     #[instrument(skip(self, bundle, progress_listener), fields(bundle_size = bundle.room_keys.len()))]
@@ -1625,9 +3736,18 @@ impl Store {
         room_id: &RoomId,
         sender_user: &UserId,
         sender_data: &SenderData,
+        sender_permissions: RoomKeyBundleSenderPermissions,
         bundle: RoomKeyBundle,
         progress_listener: impl Fn(usize, usize),
-    ) -> Result<(), CryptoStoreError> {
+    ) -> Result<RoomKeyBundleImportResult, CryptoStoreError> {
+        let policy = self.room_key_bundle_acceptance_policy();
+
+        if let Err(reason) = policy.check(sender_user, sender_data, sender_permissions) {
+            warn!(?reason, ?sender_permissions, "Rejecting a room key bundle");
+
+            return Ok(RoomKeyBundleImportResult { rejected: Some(reason), ..Default::default() });
+        }
+
         let (good, bad): (Vec<_>, Vec<_>) = bundle.room_keys.iter().partition_map(|key| {
             if key.room_id != room_id {
                 trace!("Ignoring key for incorrect room {} in bundle", key.room_id);
@@ -1637,6 +3757,11 @@ impl Store {
             }
         });
 
+        let mut result = RoomKeyBundleImportResult {
+            bad_room: bad.iter().map(|key| (key.room_id.clone(), key.session_id.clone())).collect(),
+            ..Default::default()
+        };
+
         match (bad.is_empty(), good.is_empty()) {
             // Case 1: Completely empty bundle.
             (true, true) => {
@@ -1666,11 +3791,18 @@ impl Store {
                     );
                 }
 
-                self.import_sessions_impl(good, None, progress_listener).await?;
+                let imported = self
+                    .import_sessions_impl(good, None, progress_listener, |session_id, reason| {
+                        result.skipped.push((session_id.to_owned(), reason))
+                    })
+                    .await?;
+
+                result.imported =
+                    imported.keys.into_values().flat_map(BTreeMap::into_values).flatten().collect();
             }
         }
 
-        Ok(())
+        Ok(result)
     }
 }
 
@@ -1684,7 +3816,14 @@ impl Deref for Store {
 
 /// A crypto store that implements primitives for cross-process locking.
 #[derive(Clone, Debug)]
-pub struct LockableCryptoStore(Arc<dyn CryptoStore<Error = CryptoStoreError>>);
+pub struct LockableCryptoStore {
+    store: Arc<dyn CryptoStore<Error = CryptoStoreError>>,
+    generation_check_pending: Arc<AtomicBool>,
+    /// The Web Locks used instead of the leases kept in the store, see
+    /// [`Store::create_web_store_lock()`].
+    #[cfg(all(target_family = "wasm", feature = "js"))]
+    web_locks: Option<matrix_sdk_common::store_locks::WebLocksBackingStore>,
+}
 
 impl matrix_sdk_common::store_locks::BackingStore for LockableCryptoStore {
     type LockError = CryptoStoreError;
@@ -1695,26 +3834,72 @@ impl matrix_sdk_common::store_locks::BackingStore for LockableCryptoStore {
         key: &str,
         holder: &str,
     ) -> std::result::Result<bool, Self::LockError> {
-        self.0.try_take_leased_lock(lease_duration_ms, key, holder).await
+        #[cfg(all(target_family = "wasm", feature = "js"))]
+        let acquired = match &self.web_locks {
+            Some(web_locks) => matrix_sdk_common::store_locks::BackingStore::try_lock(
+                web_locks,
+                lease_duration_ms,
+                key,
+                holder,
+            )
+            .await
+            .map_err(CryptoStoreError::backend)?,
+            None => self.store.try_take_leased_lock(lease_duration_ms, key, holder).await?,
+        };
+
+        #[cfg(not(all(target_family = "wasm", feature = "js")))]
+        let acquired = self.store.try_take_leased_lock(lease_duration_ms, key, holder).await?;
+
+        if acquired {
+            // Another process might have held the lock in the meantime, make sure the
+            // caches get checked before they are used again.
+            self.generation_check_pending.store(true, Ordering::SeqCst);
+        }
+
+        Ok(acquired)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::pin::pin;
+    use std::{pin::pin, sync::Arc, time::Duration};
 
+    use assert_matches2::assert_matches;
     use futures_util::StreamExt;
     use insta::{_macro_support::Content, assert_json_snapshot, internals::ContentPath};
     use matrix_sdk_test::async_test;
-    use ruma::{device_id, room_id, user_id, RoomId};
-    use vodozemac::megolm::SessionKey;
+    use ruma::{
+        device_id, owned_mxc_uri, room_id, user_id, MilliSecondsSinceUnixEpoch, RoomId,
+        SecondsSinceUnixEpoch, TransactionId,
+    };
+    use vodozemac::{
+        megolm::{GroupSession, SessionKey},
+        Ed25519PublicKey,
+    };
 
+    use super::{
+        CryptoStore, CryptoStoreError, MemoryStore, RoomKeyImportCheckpoint, Store, ValueCodec,
+        ROOM_KEY_IMPORT_CHECKPOINT_KEY,
+    };
     use crate::{
-        machine::test_helpers::get_machine_pair,
-        olm::{InboundGroupSession, SenderData},
-        store::types::DehydratedDeviceKey,
-        types::EventEncryptionAlgorithm,
-        OlmMachine,
+        clock::MockClock,
+        machine::test_helpers::{
+            bootstrap_requests_to_keys_query_response, get_machine_pair,
+            get_machine_pair_with_session,
+        },
+        olm::{Account, InboundGroupSession, SenderData},
+        store::types::{
+            BackupDecryptionKey, Changes, DehydratedDeviceKey, DeviceChanges, DeviceListSnapshot,
+            FullStateExport, OwnDeviceChange, RoomKeyBundleAcceptancePolicy, RoomKeyBundleOptions,
+            RoomKeyBundleRejection, RoomKeyBundleSenderPermissions, RoomKeyRetentionPolicy,
+            RoomKeySkipReason, SessionPruningPolicy, StoredRoomKeyBundleData,
+        },
+        types::{
+            events::room_key_bundle::{RoomKeyBundleChunk, RoomKeyBundleContent},
+            room_history::RoomKeyBundle,
+            DeviceKey, EventEncryptionAlgorithm,
+        },
+        DeviceData, LocalTrust, OlmMachine,
     };
 
     #[async_test]
@@ -1741,6 +3926,465 @@ mod tests {
         assert_eq!(room_keys[0].room_id, "!room1:localhost");
     }
 
+    #[async_test]
+    async fn test_resume_room_key_import() {
+        let (alice, bob, _) =
+            get_machine_pair(user_id!("@a:s.co"), user_id!("@b:s.co"), false).await;
+
+        for room_id in [room_id!("!room1:localhost"), room_id!("!room2:localhost")] {
+            alice.create_outbound_group_session_with_defaults_test_helper(room_id).await.unwrap();
+        }
+        let exported_keys = alice.store().export_room_keys(|_| true).await.unwrap();
+        assert!(!bob.store().has_pending_room_key_import().await.unwrap());
+
+        // Given an import that was interrupted after processing the first room key
+        let checkpoint = RoomKeyImportCheckpoint {
+            digest: Store::room_key_export_digest(&exported_keys),
+            offset: 1,
+            imported_count: 1,
+        };
+        bob.store().set_value(ROOM_KEY_IMPORT_CHECKPOINT_KEY, &checkpoint).await.unwrap();
+        assert!(bob.store().has_pending_room_key_import().await.unwrap());
+
+        // When we resume the import
+        let result = bob.store().resume_import(exported_keys.clone(), |_, _| {}).await.unwrap();
+
+        // Then only the remaining room key is imported
+        assert_eq!(result.imported_count, 2);
+        assert_eq!(result.total_count, 2);
+        let sessions = bob.store().get_inbound_group_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), exported_keys[1].session_id);
+        assert!(!bob.store().has_pending_room_key_import().await.unwrap());
+
+        // And without a checkpoint, the whole export is imported
+        let result = bob.store().resume_import(exported_keys, |_, _| {}).await.unwrap();
+        assert_eq!(result.imported_count, 1);
+        assert_eq!(bob.store().get_inbound_group_sessions().await.unwrap().len(), 2);
+    }
+
+    #[async_test]
+    async fn test_app_data() {
+        let machine = OlmMachine::new(user_id!("@alice:localhost"), device_id!("ALICE")).await;
+        let store = machine.store();
+
+        assert_matches!(store.app_data::<String>(""), Err(CryptoStoreError::EmptyAppDataNamespace));
+
+        let settings = store.app_data::<String>("settings").unwrap();
+        let drafts = store.app_data::<String>("drafts").unwrap();
+
+        settings.set("theme", &"dark".to_owned()).await.unwrap();
+        drafts.set("theme", &"a draft".to_owned()).await.unwrap();
+
+        // The namespaces don't collide with each other, or with the raw values.
+        assert_eq!(settings.get("theme").await.unwrap().as_deref(), Some("dark"));
+        assert_eq!(drafts.get("theme").await.unwrap().as_deref(), Some("a draft"));
+        assert!(store.get_value::<String>("theme").await.unwrap().is_none());
+
+        // Values stored using a raw key can be moved into a namespace, missing ones are
+        // skipped.
+        store.set_value("language", &"en".to_owned()).await.unwrap();
+        let legacy_keys = [("language", "language"), ("missing", "missing")];
+        assert_eq!(settings.migrate_legacy_values(&legacy_keys).await.unwrap(), 1);
+        assert_eq!(settings.migrate_legacy_values(&legacy_keys).await.unwrap(), 0);
+        assert!(store.get_value::<String>("language").await.unwrap().is_none());
+        assert_eq!(settings.get("language").await.unwrap().as_deref(), Some("en"));
+
+        let keys: Vec<_> = settings.keys().await.unwrap().into_iter().collect();
+        assert_eq!(keys, ["language", "theme"]);
+
+        settings.remove("theme").await.unwrap();
+        assert!(settings.get("theme").await.unwrap().is_none());
+
+        // Clearing a namespace leaves the other ones alone.
+        settings.clear().await.unwrap();
+        assert!(settings.keys().await.unwrap().is_empty());
+        assert!(settings.get("language").await.unwrap().is_none());
+        assert_eq!(drafts.get("theme").await.unwrap().as_deref(), Some("a draft"));
+    }
+
+    #[async_test]
+    async fn test_value_codec() {
+        let user_id = user_id!("@alice:localhost");
+        let device_id = device_id!("ALICE");
+        let store = Arc::new(MemoryStore::new());
+
+        // A value stored with the default codec is untagged MessagePack.
+        let machine =
+            OlmMachine::with_store(user_id, device_id, store.clone(), None).await.unwrap();
+        assert_eq!(machine.store().value_codec(), ValueCodec::MessagePack);
+        machine.store().set_value("numbers", &vec![1u32, 2, 3]).await.unwrap();
+
+        let raw = store.get_custom_value("numbers").await.unwrap().unwrap();
+        assert_eq!(raw, rmp_serde::to_vec_named(&vec![1u32, 2, 3]).unwrap());
+        drop(machine);
+
+        // Once the store uses JSON, the value is still read, without being rewritten.
+        let machine = OlmMachine::builder(user_id, device_id)
+            .store(store.clone())
+            .value_codec(ValueCodec::Json)
+            .build()
+            .await
+            .unwrap();
+        let numbers: Vec<u32> = machine.store().get_value("numbers").await.unwrap().unwrap();
+        assert_eq!(numbers, [1, 2, 3]);
+        assert_eq!(store.get_custom_value("numbers").await.unwrap().unwrap(), raw);
+
+        // Migrating the values rewrites them as JSON.
+        let migrated = machine.store().migrate_values(&["numbers", "missing"]).await.unwrap();
+        assert_eq!(migrated, 1);
+
+        let raw = store.get_custom_value("numbers").await.unwrap().unwrap();
+        assert_eq!(raw[..2], [0xc1, 1]);
+        assert_eq!(serde_json::from_slice::<Vec<u32>>(&raw[2..]).unwrap(), [1, 2, 3]);
+        assert_eq!(machine.store().migrate_values(&["numbers"]).await.unwrap(), 0);
+
+        // New values are written as JSON.
+        machine.store().set_value("name", &"alice").await.unwrap();
+        let raw = store.get_custom_value("name").await.unwrap().unwrap();
+        assert_eq!(&raw[2..], b"\"alice\"");
+        drop(machine);
+
+        // Going back to MessagePack migrates the values back.
+        let machine =
+            OlmMachine::with_store(user_id, device_id, store.clone(), None).await.unwrap();
+        let name: String = machine.store().get_value("name").await.unwrap().unwrap();
+        assert_eq!(name, "alice");
+
+        machine.store().migrate_values(&["name"]).await.unwrap();
+        let raw = store.get_custom_value("name").await.unwrap().unwrap();
+        assert_eq!(raw, rmp_serde::to_vec_named("alice").unwrap());
+
+        // Fields which the type we read the value as doesn't know about survive
+        // the migration.
+        let value = serde_json::json!({ "known": 1, "unknown": "kept" });
+        store.set_custom_value("struct", ValueCodec::Json.encode(&value).unwrap()).await.unwrap();
+        assert_eq!(machine.store().migrate_values(&["struct"]).await.unwrap(), 1);
+        let migrated: serde_json::Value =
+            machine.store().get_value("struct").await.unwrap().unwrap();
+        assert_eq!(migrated, value);
+
+        // Values in an unknown format are rejected.
+        store.set_custom_value("unknown", vec![0xc1, 0xff, 0x00]).await.unwrap();
+        machine.store().get_value::<String>("unknown").await.unwrap_err();
+    }
+
+    #[async_test]
+    async fn test_full_state_export_import() {
+        let (alice, bob) =
+            get_machine_pair_with_session(user_id!("@a:s.co"), user_id!("@b:s.co"), false).await;
+        alice
+            .create_outbound_group_session_with_defaults_test_helper(room_id!("!room:localhost"))
+            .await
+            .unwrap();
+        alice.update_tracked_users([bob.user_id()]).await.unwrap();
+
+        let backup_key = BackupDecryptionKey::new().unwrap();
+        alice
+            .backup_machine()
+            .save_decryption_key(Some(backup_key.clone()), Some("1".to_owned()))
+            .await
+            .unwrap();
+
+        // The export survives a round trip through its serialized form.
+        let export = alice.store().export_full_state().await.unwrap();
+        let export: FullStateExport =
+            serde_json::from_value(serde_json::to_value(&export).unwrap()).unwrap();
+
+        let store = Arc::new(MemoryStore::new());
+        Store::import_full_state(store.clone(), export).await.unwrap();
+
+        let machine =
+            OlmMachine::with_store(alice.user_id(), alice.device_id(), store.clone(), None)
+                .await
+                .unwrap();
+
+        assert_eq!(machine.identity_keys().curve25519, alice.identity_keys().curve25519);
+        let bob_key = bob.identity_keys().curve25519.to_base64();
+        assert!(machine.store().get_sessions(&bob_key).await.unwrap().is_some());
+        assert_eq!(machine.store().get_inbound_group_sessions().await.unwrap().len(), 1);
+        assert!(machine.tracked_users().await.unwrap().contains(bob.user_id()));
+
+        let backup_keys = machine.backup_machine().get_backup_keys().await.unwrap();
+        assert_eq!(backup_keys.decryption_key.unwrap().to_base64(), backup_key.to_base64());
+        assert_eq!(backup_keys.backup_version.as_deref(), Some("1"));
+
+        // Importing into a store holding another account fails.
+        let export = bob.store().export_full_state().await.unwrap();
+        assert_matches!(
+            Store::import_full_state(store, export).await,
+            Err(CryptoStoreError::MismatchedAccount { .. })
+        );
+    }
+
+    #[async_test]
+    async fn test_device_list_snapshot_diff() {
+        let (alice, bob, _) =
+            get_machine_pair(user_id!("@a:s.co"), user_id!("@b:s.co"), false).await;
+
+        let snapshot = alice.store().device_list_snapshot(bob.user_id()).await.unwrap();
+        assert_eq!(snapshot.devices.len(), 1);
+        assert!(!snapshot.devices[bob.device_id()].verified);
+        assert!(alice.store().diff_since(&snapshot).await.unwrap().is_empty());
+
+        // A device which wasn't part of the snapshot shows up as a new, unverified device.
+        let empty =
+            DeviceListSnapshot { user_id: bob.user_id().to_owned(), devices: Default::default() };
+        let diff = alice.store().diff_since(&empty).await.unwrap();
+        let added: Vec<_> = diff.added_unverified().cloned().collect();
+        assert_eq!(added, [bob.device_id().to_owned()]);
+        assert_eq!(diff.snapshot, snapshot);
+
+        // Verifying the device changes it.
+        let device = alice.get_device(bob.user_id(), bob.device_id(), None).await.unwrap().unwrap();
+        device.set_local_trust(LocalTrust::Verified).await.unwrap();
+
+        let diff = alice.store().diff_since(&snapshot).await.unwrap();
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.changed.into_iter().collect::<Vec<_>>(), [bob.device_id().to_owned()]);
+        assert!(diff.snapshot.devices[bob.device_id()].verified);
+    }
+
+    #[async_test]
+    async fn test_own_devices_stream() {
+        use futures_util::FutureExt;
+
+        let alice = OlmMachine::new(user_id!("@alice:localhost"), device_id!("ALICEDEVICE")).await;
+        let stream = alice.store().own_devices_stream().await.unwrap();
+        let mut stream = pin!(stream);
+
+        // A new device of our own is reported as added
+        let other = Account::with_device_id(alice.user_id(), device_id!("OTHERDEVICE"));
+        let mut device_data = DeviceData::from_account(&other);
+        alice.store().save_device_data(&[device_data.clone()]).await.unwrap();
+        assert_matches!(
+            stream.next().now_or_never().flatten(),
+            Some(OwnDeviceChange::Added(added))
+        );
+        assert_eq!(added.device_id(), other.device_id());
+
+        // Devices of other users aren't reported
+        let bob = Account::with_device_id(user_id!("@bob:localhost"), device_id!("BOBDEVICE"));
+        alice.store().save_device_data(&[DeviceData::from_account(&bob)]).await.unwrap();
+        assert!(stream.next().now_or_never().is_none());
+
+        // A new display name is reported
+        let mut device_keys = device_data.as_device_keys().clone();
+        device_keys.unsigned.device_display_name = Some("Work laptop".to_owned());
+        assert!(device_data.update_device(&device_keys).unwrap());
+        alice.store().save_device_data(&[device_data.clone()]).await.unwrap();
+        assert_matches!(
+            stream.next().now_or_never().flatten(),
+            Some(OwnDeviceChange::DisplayNameChanged { device, previous: None })
+        );
+        assert_eq!(device.display_name(), Some("Work laptop"));
+
+        // And so is verifying the device
+        device.set_local_trust(LocalTrust::Verified).await.unwrap();
+        assert_matches!(
+            stream.next().now_or_never().flatten(),
+            Some(OwnDeviceChange::TrustChanged { previously_verified: false, .. })
+        );
+
+        // As well as deleting it
+        let changes = Changes {
+            devices: DeviceChanges { deleted: vec![device_data], ..Default::default() },
+            ..Default::default()
+        };
+        alice.store().save_changes(changes).await.unwrap();
+        assert_matches!(
+            stream.next().now_or_never().flatten(),
+            Some(OwnDeviceChange::Deleted(deleted))
+        );
+        assert_eq!(deleted.device_id(), other.device_id());
+    }
+
+    #[async_test]
+    async fn test_changes_builder_saves_changes_on_commit() {
+        use futures_util::FutureExt;
+
+        let (alice, bob, _) =
+            get_machine_pair(user_id!("@a:s.co"), user_id!("@b:s.co"), false).await;
+
+        let room1_id = room_id!("!room1:localhost");
+        let room2_id = room_id!("!room2:localhost");
+        alice.create_outbound_group_session_with_defaults_test_helper(room1_id).await.unwrap();
+        alice.create_outbound_group_session_with_defaults_test_helper(room2_id).await.unwrap();
+        let sessions: Vec<_> = alice
+            .store()
+            .export_room_keys(|_| true)
+            .await
+            .unwrap()
+            .iter()
+            .map(|key| InboundGroupSession::from_export(key).unwrap())
+            .collect();
+
+        let mut room_keys_received_stream = Box::pin(bob.store().room_keys_received_stream());
+
+        // Given a builder holding two sessions, added separately
+        let mut changes = bob.store().begin_changes();
+        changes.add_inbound_group_sessions([sessions[0].clone()]);
+        changes.add_inbound_group_sessions([sessions[1].clone()]);
+        assert!(!changes.is_empty());
+
+        // Nothing is saved before the changes are committed
+        assert!(bob.store().get_inbound_group_sessions().await.unwrap().is_empty());
+
+        // When we commit the changes
+        changes.commit().await.unwrap();
+
+        // Then both sessions were saved, in a single batch
+        assert_eq!(bob.store().get_inbound_group_sessions().await.unwrap().len(), 2);
+
+        let room_keys = room_keys_received_stream
+            .next()
+            .now_or_never()
+            .flatten()
+            .expect("We should have received an update of room key infos")
+            .unwrap();
+        assert_eq!(room_keys.len(), 2);
+        assert!(room_keys_received_stream.next().now_or_never().is_none());
+    }
+
+    #[async_test]
+    async fn test_prune_sessions() {
+        let alice = OlmMachine::new(user_id!("@alice:localhost"), device_id!("ALICEDEVICE")).await;
+        let mut bob = Account::with_device_id(user_id!("@bob:localhost"), device_id!("BOBDEVICE"));
+        let sender_key = bob.identity_keys().curve25519.to_base64();
+        let now = u64::from(SecondsSinceUnixEpoch::now().get());
+
+        // Given three sessions with Bob, last used a week ago, an hour ago and just now
+        let mut sessions = Vec::new();
+        for idle_for in [7 * 24 * 60 * 60, 60 * 60, 0] {
+            let mut session = alice
+                .store()
+                .with_transaction(|mut tr| async {
+                    let account = tr.account().await?;
+                    let (session, _) = account.create_session_for_test_helper(&mut bob).await;
+                    Ok((tr, session))
+                })
+                .await
+                .unwrap();
+            session.last_use_time = SecondsSinceUnixEpoch((now - idle_for).try_into().unwrap());
+            sessions.push(session);
+        }
+        alice.store().save_sessions(&sessions).await.unwrap();
+
+        // Nothing is removed by default
+        assert_eq!(alice.store().prune_sessions().await.unwrap(), 0);
+
+        // Sessions which haven't been used for a day are removed
+        alice.set_session_pruning_policy(SessionPruningPolicy {
+            max_idle_age: Some(Duration::from_secs(24 * 60 * 60)),
+            ..Default::default()
+        });
+        assert_eq!(alice.store().prune_sessions().await.unwrap(), 1);
+
+        // Only the most recently used session is kept if we allow a single one
+        alice.set_session_pruning_policy(SessionPruningPolicy {
+            max_sessions_per_sender_key: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(alice.store().prune_sessions().await.unwrap(), 1);
+
+        let cached = alice.store().get_sessions(&sender_key).await.unwrap().unwrap();
+        let cached = cached.lock().await;
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].session_id(), sessions[2].session_id());
+
+        // The sessions are gone from the store too, not only from the cache
+        let store = alice.store().crypto_store();
+        let stored = (**store).get_sessions(&sender_key).await.unwrap().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].session_id(), sessions[2].session_id());
+    }
+
+    #[async_test]
+    async fn test_prune_sessions_uses_clock() {
+        let alice = OlmMachine::new(user_id!("@alice:localhost"), device_id!("ALICEDEVICE")).await;
+        let mut bob = Account::with_device_id(user_id!("@bob:localhost"), device_id!("BOBDEVICE"));
+
+        // Given a session with Bob which was just used
+        let session = alice
+            .store()
+            .with_transaction(|mut tr| async {
+                let account = tr.account().await?;
+                let (session, _) = account.create_session_for_test_helper(&mut bob).await;
+                Ok((tr, session))
+            })
+            .await
+            .unwrap();
+        alice.store().save_sessions(&[session]).await.unwrap();
+
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch::now());
+        alice.set_clock(Arc::new(clock.clone()));
+        alice.set_session_pruning_policy(SessionPruningPolicy {
+            max_idle_age: Some(Duration::from_secs(24 * 60 * 60)),
+            ..Default::default()
+        });
+
+        // It's kept while the clock says it was used recently
+        assert_eq!(alice.store().prune_sessions().await.unwrap(), 0);
+
+        // And removed once the clock moved two days ahead
+        clock.advance(Duration::from_secs(2 * 24 * 60 * 60));
+        assert_eq!(alice.store().prune_sessions().await.unwrap(), 1);
+    }
+
+    #[async_test]
+    async fn test_apply_room_key_retention() {
+        use futures_util::FutureExt;
+
+        let alice = Account::new(user_id!("@alice:localhost"));
+        let bob = OlmMachine::new(user_id!("@bob:localhost"), device_id!("BOBDEVICE")).await;
+        let room_id = room_id!("!room1:localhost");
+        let now = u64::from(SecondsSinceUnixEpoch::now().get());
+
+        // Given room keys received a week ago, an hour ago and just now, and one room
+        // key for which we don't know when it was received
+        let mut sessions = Vec::new();
+        for received_at in [Some(now - 7 * 24 * 60 * 60), Some(now - 60 * 60), Some(now), None] {
+            let (_, session) = alice.create_group_session_pair_with_defaults(room_id).await;
+            let mut pickle = session.pickle().await;
+            pickle.received_at =
+                received_at.map(|ts| SecondsSinceUnixEpoch(ts.try_into().unwrap()));
+            sessions.push(InboundGroupSession::from_pickle(pickle).unwrap());
+        }
+        bob.store().save_inbound_group_sessions(&sessions).await.unwrap();
+
+        let mut room_keys_deleted_stream = pin!(bob.store().room_keys_deleted_stream());
+
+        // Nothing is deleted by the default policy
+        let policy = RoomKeyRetentionPolicy::default();
+        assert!(bob.store().apply_room_key_retention(&policy).await.unwrap().is_empty());
+
+        // Room keys older than a day are deleted, the one without a timestamp is kept
+        let policy = RoomKeyRetentionPolicy {
+            max_age: Some(Duration::from_secs(24 * 60 * 60)),
+            ..Default::default()
+        };
+        let deleted = bob.store().apply_room_key_retention(&policy).await.unwrap();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].session_id, sessions[0].session_id());
+
+        let update = room_keys_deleted_stream
+            .next()
+            .now_or_never()
+            .flatten()
+            .expect("We should have been notified about the deleted room keys")
+            .unwrap();
+        assert_eq!(update, deleted);
+
+        // Only the newest room key is kept if we allow a single one per room
+        let policy = RoomKeyRetentionPolicy { max_keys_per_room: Some(1), ..Default::default() };
+        let deleted = bob.store().apply_room_key_retention(&policy).await.unwrap();
+        assert_eq!(deleted.len(), 2);
+
+        let remaining = bob.store().get_inbound_group_sessions().await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].session_id(), sessions[2].session_id());
+    }
+
     #[async_test]
     async fn test_export_room_keys_provides_selected_keys() {
         // Given an OlmMachine with room keys in it
@@ -1779,12 +4423,12 @@ mod tests {
         alice.create_outbound_group_session_with_defaults_test_helper(room2_id).await.unwrap();
 
         // When I export the keys as a stream
-        let mut keys = pin!(alice.store().export_room_keys_stream(|_| true).await.unwrap());
+        let mut keys = pin!(alice.store().export_room_keys_stream(|_| true));
 
         // And collect them
         let mut collected = vec![];
         while let Some(key) = keys.next().await {
-            collected.push(key);
+            collected.push(key.unwrap());
         }
 
         // Then all the keys were provided
@@ -1807,13 +4451,12 @@ mod tests {
         alice.create_outbound_group_session_with_defaults_test_helper(room2_id).await.unwrap();
 
         // When I export the keys as a stream
-        let mut keys =
-            pin!(alice.store().export_room_keys_stream(|s| s.room_id() == room1_id).await.unwrap());
+        let mut keys = pin!(alice.store().export_room_keys_stream(|s| s.room_id() == room1_id));
 
         // And collect them
         let mut collected = vec![];
         while let Some(key) = keys.next().await {
-            collected.push(key);
+            collected.push(key.unwrap());
         }
 
         // Then all the keys matching our predicate were provided, and no others
@@ -1854,6 +4497,52 @@ mod tests {
         assert!(status.is_complete(), "We should have imported all the cross-signing keys");
     }
 
+    #[cfg(feature = "experimental-symmetric-backup")]
+    #[async_test]
+    async fn test_export_secrets_bundle_with_symmetric_backup_key() {
+        use crate::types::BackupSecrets;
+
+        let user_id = user_id!("@alice:example.com");
+        let (first, second, _) = get_machine_pair(user_id, user_id, false).await;
+
+        first.bootstrap_cross_signing(false).await.unwrap();
+
+        let mut bundle = first.store().export_secrets_bundle().await.unwrap();
+        bundle.backup = BackupSecrets::new(
+            "org.matrix.msc3270.v1.aes-hmac-sha2",
+            BackupDecryptionKey::new().unwrap(),
+            "2".to_owned(),
+        );
+
+        second.store().import_secrets_bundle(&bundle).await.unwrap();
+
+        // The algorithm of the backup key was remembered, so it's exported again.
+        let exported = second.store().export_secrets_bundle().await.unwrap();
+        let backup = exported.backup.as_ref().expect("The bundle should contain the backup key");
+        assert_eq!(backup.algorithm(), "org.matrix.msc3270.v1.aes-hmac-sha2");
+        assert_eq!(backup.backup_version(), "2");
+        assert_eq!(backup.key().as_bytes(), bundle.backup.as_ref().unwrap().key().as_bytes());
+
+        let json = serde_json::to_value(&exported).unwrap();
+        assert_eq!(json["backup"]["algorithm"], "org.matrix.msc3270.v1.aes-hmac-sha2");
+
+        let deserialized: crate::types::SecretsBundle = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized.backup.unwrap().algorithm(), backup.algorithm());
+
+        // Replacing the backup key forgets the algorithm of the old one.
+        let changes = Changes {
+            backup_decryption_key: Some(BackupDecryptionKey::new().unwrap()),
+            backup_version: Some("3".to_owned()),
+            ..Default::default()
+        };
+        second.store().save_changes(changes).await.unwrap();
+
+        let exported = second.store().export_secrets_bundle().await.unwrap();
+        let backup = exported.backup.expect("The bundle should contain the backup key");
+        assert_eq!(backup.algorithm(), "m.megolm_backup.v1.curve25519-aes-sha2");
+        assert_eq!(backup.backup_version(), "3");
+    }
+
     #[async_test]
     async fn test_create_dehydrated_device_key() {
         let pickle_key = DehydratedDeviceKey::new()
@@ -1927,7 +4616,8 @@ mod tests {
         bob.store().save_inbound_group_sessions(&sessions).await.unwrap();
 
         // When I build the bundle
-        let mut bundle = bob.store().build_room_key_bundle(room1_id).await.unwrap();
+        let mut bundle =
+            bob.store().build_room_key_bundle(room1_id, Default::default()).await.unwrap();
 
         // Then the bundle matches the snapshot.
 
@@ -1955,6 +4645,419 @@ mod tests {
         });
     }
 
+    #[async_test]
+    async fn test_build_room_key_bundle_with_options() {
+        let alice = Account::new(user_id!("@alice:localhost"));
+        let bob = OlmMachine::new(user_id!("@bob:localhost"), device_id!("BOBDEVICE")).await;
+        let room_id = room_id!("!room1:localhost");
+        let now = u64::from(SecondsSinceUnixEpoch::now().get());
+
+        // Given shareable room keys received a day ago, an hour ago and just now, and
+        // a room key which isn't shareable
+        let mut sessions = Vec::new();
+        for (received_at, shared_history) in
+            [(now - 24 * 60 * 60, true), (now - 60 * 60, true), (now, true), (now, false)]
+        {
+            let (_, session) = alice.create_group_session_pair_with_defaults(room_id).await;
+            let mut pickle = session.pickle().await;
+            pickle.received_at = Some(SecondsSinceUnixEpoch(received_at.try_into().unwrap()));
+            pickle.shared_history = shared_history;
+            sessions.push(InboundGroupSession::from_pickle(pickle).unwrap());
+        }
+        bob.store().save_inbound_group_sessions(&sessions).await.unwrap();
+
+        // All the room keys are part of the bundle by default
+        let bundle = bob.store().build_room_key_bundle(room_id, Default::default()).await.unwrap();
+        assert_eq!(bundle.room_keys.len(), 3);
+        assert_eq!(bundle.withheld.len(), 1);
+
+        // The old room key is left out if we only want the last two hours
+        let options = RoomKeyBundleOptions {
+            since: Some(SecondsSinceUnixEpoch((now - 2 * 60 * 60).try_into().unwrap())),
+            include_withheld: false,
+            ..Default::default()
+        };
+        let bundle = bob.store().build_room_key_bundle(room_id, options).await.unwrap();
+        let mut session_ids: Vec<_> =
+            bundle.room_keys.iter().map(|key| key.session_id.as_str()).collect();
+        session_ids.sort();
+        let mut expected = vec![sessions[1].session_id(), sessions[2].session_id()];
+        expected.sort();
+        assert_eq!(session_ids, expected);
+        assert!(bundle.withheld.is_empty());
+
+        // The most recent room key is kept if we only want one
+        let options = RoomKeyBundleOptions { max_keys: Some(1), ..Default::default() };
+        let bundle = bob.store().build_room_key_bundle(room_id, options).await.unwrap();
+        assert_eq!(bundle.room_keys.len(), 1);
+        assert_eq!(bundle.room_keys[0].session_id, sessions[2].session_id());
+        assert_eq!(bundle.withheld.len(), 1);
+    }
+
+    #[async_test]
+    async fn test_receive_room_key_bundle_result() {
+        let alice = OlmMachine::new(user_id!("@a:s.co"), device_id!("ALICE")).await;
+        let bob = OlmMachine::new(user_id!("@b:s.co"), device_id!("BOB")).await;
+
+        let room1_id = room_id!("!room1:localhost");
+        let room2_id = room_id!("!room2:localhost");
+
+        let new_session = |room_id| {
+            let session_key = GroupSession::new(Default::default()).session_key();
+            create_inbound_group_session_with_visibility(&alice, room_id, &session_key, true)
+        };
+        let known = new_session(room1_id);
+        let unknown = new_session(room1_id);
+        let other_room = new_session(room2_id);
+
+        // Given a bundle for room 1 which also contains a room key for room 2, and a
+        // room key which Bob already has
+        alice
+            .store()
+            .save_inbound_group_sessions(&[known.clone(), unknown.clone(), other_room.clone()])
+            .await
+            .unwrap();
+        let mut bundle =
+            alice.store().build_room_key_bundle(room1_id, Default::default()).await.unwrap();
+        let other_bundle =
+            alice.store().build_room_key_bundle(room2_id, Default::default()).await.unwrap();
+        bundle.room_keys.extend(other_bundle.room_keys);
+
+        bob.store().save_inbound_group_sessions(&[known.clone()]).await.unwrap();
+        bob.set_room_key_bundle_acceptance_policy(RoomKeyBundleAcceptancePolicy::accept_all());
+
+        // When Bob receives the bundle
+        let result = bob
+            .store()
+            .receive_room_key_bundle(
+                room1_id,
+                alice.user_id(),
+                &SenderData::unknown(),
+                Default::default(),
+                bundle,
+                |_, _| {},
+            )
+            .await
+            .unwrap();
+
+        // Then the result tells what happened to every room key
+        assert_eq!(result.imported, vec![unknown.session_id().to_owned()]);
+        assert_eq!(
+            result.skipped,
+            vec![(known.session_id().to_owned(), RoomKeySkipReason::AlreadyKnown)]
+        );
+        assert_eq!(
+            result.bad_room,
+            vec![(room2_id.to_owned(), other_room.session_id().to_owned())]
+        );
+    }
+
+    #[async_test]
+    async fn test_room_key_bundle_acceptance_policy() {
+        let alice = OlmMachine::new(user_id!("@a:s.co"), device_id!("ALICE")).await;
+        let bob = OlmMachine::new(user_id!("@b:s.co"), device_id!("BOB")).await;
+        let room_id = room_id!("!room1:localhost");
+
+        let session_key = GroupSession::new(Default::default()).session_key();
+        let session =
+            create_inbound_group_session_with_visibility(&alice, room_id, &session_key, true);
+        alice.store().save_inbound_group_sessions(&[session]).await.unwrap();
+
+        let master_key =
+            Ed25519PublicKey::from_base64("2/5LWJMow5zhJqakV88SIc7q/1pa8fmkfgAzx72w9G4").unwrap();
+        let cross_signed =
+            SenderData::sender_unverified(alice.user_id(), alice.device_id(), master_key);
+        let allowed = RoomKeyBundleSenderPermissions { is_joined: true, can_share_history: true };
+
+        // By default, the sender must be allowed to share the history of the room
+        let policy = bob.room_key_bundle_acceptance_policy();
+        assert_eq!(policy, RoomKeyBundleAcceptancePolicy::default());
+        assert_eq!(policy.check(alice.user_id(), &cross_signed, allowed), Ok(()));
+        assert_eq!(
+            policy.check(alice.user_id(), &cross_signed, Default::default()),
+            Err(RoomKeyBundleRejection::NotJoined)
+        );
+        assert_eq!(
+            policy.check(
+                alice.user_id(),
+                &cross_signed,
+                RoomKeyBundleSenderPermissions { is_joined: true, can_share_history: false }
+            ),
+            Err(RoomKeyBundleRejection::MissingHistorySharingPermission)
+        );
+
+        // And use a cross-signed device, which belongs to them
+        assert_eq!(
+            policy.check(alice.user_id(), &SenderData::unknown(), allowed),
+            Err(RoomKeyBundleRejection::DeviceNotCrossSigned)
+        );
+        let violation = SenderData::sender_verification_violation(
+            alice.user_id(),
+            alice.device_id(),
+            master_key,
+        );
+        assert_eq!(
+            policy.check(alice.user_id(), &violation, allowed),
+            Err(RoomKeyBundleRejection::VerificationViolation)
+        );
+        let policy = RoomKeyBundleAcceptancePolicy::accept_all();
+        assert_eq!(
+            policy.check(bob.user_id(), &cross_signed, allowed),
+            Err(RoomKeyBundleRejection::SenderMismatch)
+        );
+
+        // A verified sender can be required as well
+        let policy =
+            RoomKeyBundleAcceptancePolicy { require_verified_sender: true, ..Default::default() };
+        assert_eq!(
+            policy.check(alice.user_id(), &cross_signed, allowed),
+            Err(RoomKeyBundleRejection::SenderNotVerified)
+        );
+        let verified = SenderData::sender_verified(alice.user_id(), alice.device_id(), master_key);
+        assert_eq!(policy.check(alice.user_id(), &verified, allowed), Ok(()));
+
+        // Nothing is imported from a rejected bundle
+        let bundle =
+            alice.store().build_room_key_bundle(room_id, Default::default()).await.unwrap();
+        let result = bob
+            .store()
+            .receive_room_key_bundle(
+                room_id,
+                alice.user_id(),
+                &SenderData::unknown(),
+                allowed,
+                bundle,
+                |_, _| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.rejected, Some(RoomKeyBundleRejection::DeviceNotCrossSigned));
+        assert!(result.imported.is_empty());
+
+        let bundle =
+            alice.store().build_room_key_bundle(room_id, Default::default()).await.unwrap();
+        let result = bob
+            .store()
+            .receive_room_key_bundle(
+                room_id,
+                alice.user_id(),
+                &cross_signed,
+                allowed,
+                bundle,
+                |_, _| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.rejected, None);
+        assert_eq!(result.imported.len(), 1);
+    }
+
+    #[async_test]
+    async fn test_refresh_room_key_bundle_sender_data() {
+        let (alice, bob, _) =
+            get_machine_pair(user_id!("@alice:localhost"), user_id!("@bob:localhost"), false).await;
+        let room_id = room_id!("!room1:localhost");
+        let allowed = RoomKeyBundleSenderPermissions { is_joined: true, can_share_history: true };
+        let policy = bob.room_key_bundle_acceptance_policy();
+
+        let bundle = |sender_data| StoredRoomKeyBundleData {
+            sender_user: alice.user_id().to_owned(),
+            sender_data,
+            bundle_data: RoomKeyBundle::default()
+                .encrypt()
+                .unwrap()
+                .to_content(room_id.to_owned(), owned_mxc_uri!("mxc://localhost/bundle")),
+            received_chunks: Vec::new(),
+        };
+
+        // Given a bundle Bob received before Alice's device was cross-signed
+        let device = bob.get_device(alice.user_id(), alice.device_id(), None).await.unwrap();
+        let device_keys = device.unwrap().as_device_keys().clone();
+        let snapshot = SenderData::device_info(device_keys.clone());
+        assert_eq!(
+            policy.check(alice.user_id(), &snapshot, allowed),
+            Err(RoomKeyBundleRejection::DeviceNotCrossSigned)
+        );
+
+        let bootstrap_requests = alice.bootstrap_cross_signing(false).await.unwrap();
+        let response = bootstrap_requests_to_keys_query_response(bootstrap_requests);
+        bob.receive_keys_query_response(&TransactionId::new(), &response).await.unwrap();
+
+        // When its sender data is refreshed, Alice's cross-signing identity is used
+        let refreshed =
+            bob.store().refresh_room_key_bundle_sender_data(&bundle(snapshot)).await.unwrap();
+        assert_matches!(refreshed, SenderData::SenderUnverified(_));
+        assert_eq!(policy.check(alice.user_id(), &refreshed, allowed), Ok(()));
+
+        // The snapshot is kept if the device is unknown
+        let unknown = SenderData::unknown();
+        let refreshed =
+            bob.store().refresh_room_key_bundle_sender_data(&bundle(unknown.clone())).await;
+        assert_eq!(refreshed.unwrap(), unknown);
+
+        // Or if the keys of the device changed
+        let other_key =
+            Ed25519PublicKey::from_base64("2/5LWJMow5zhJqakV88SIc7q/1pa8fmkfgAzx72w9G4").unwrap();
+        let mut other_keys = device_keys;
+        for key in other_keys.keys.values_mut() {
+            if let DeviceKey::Ed25519(ed25519) = key {
+                *ed25519 = other_key;
+            }
+        }
+        let snapshot = SenderData::device_info(other_keys);
+        let refreshed =
+            bob.store().refresh_room_key_bundle_sender_data(&bundle(snapshot.clone())).await;
+        assert_eq!(refreshed.unwrap(), snapshot);
+    }
+
+    #[async_test]
+    async fn test_received_room_key_bundle_chunks() {
+        let bob = OlmMachine::new(user_id!("@bob:localhost"), device_id!("BOBDEVICE")).await;
+        let alice = user_id!("@alice:localhost");
+        let room_id = room_id!("!room1:localhost");
+        let master_key = bob.identity_keys().ed25519;
+
+        let chunk = |bundle_id: &str, index, count, device_id| {
+            let mut content = RoomKeyBundle::default()
+                .encrypt()
+                .unwrap()
+                .to_content(room_id.to_owned(), owned_mxc_uri!("mxc://localhost/chunk"));
+            content.chunk =
+                Some(RoomKeyBundleChunk { bundle_id: bundle_id.to_owned(), index, count });
+
+            StoredRoomKeyBundleData {
+                sender_user: alice.to_owned(),
+                sender_data: SenderData::sender_unverified(alice, device_id, master_key),
+                bundle_data: content,
+                received_chunks: Vec::new(),
+            }
+        };
+        let indices = |chunks: Option<Vec<RoomKeyBundleContent>>| -> Vec<_> {
+            chunks.unwrap().into_iter().map(|content| content.chunk.unwrap().index).collect()
+        };
+
+        let store = bob.store();
+        let receive = |data| async move {
+            let previous = store.get_received_room_key_bundle_data(room_id, alice).await.unwrap();
+
+            if let Some(data) = Store::merge_received_room_key_bundle_chunk(previous, data) {
+                let changes =
+                    Changes { received_room_key_bundles: vec![data], ..Default::default() };
+                store.save_changes(changes).await.unwrap();
+            }
+        };
+        let alice_device = device_id!("ALICEDEVICE");
+        assert!(store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap().is_none());
+
+        // The chunks aren't available until all of them were received, in any order
+        receive(chunk("first", 2, 3, alice_device)).await;
+        receive(chunk("first", 0, 3, alice_device)).await;
+        assert!(store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap().is_none());
+
+        // Chunks with an invalid index are ignored, duplicates are replaced
+        receive(chunk("first", 3, 3, alice_device)).await;
+        receive(chunk("first", 0, 3, alice_device)).await;
+        assert!(store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap().is_none());
+
+        receive(chunk("first", 1, 3, alice_device)).await;
+        let chunks = store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap();
+        assert_eq!(indices(chunks), vec![0, 1, 2]);
+
+        // Once the bundle was imported, only the last chunk is kept
+        store.clear_received_room_key_bundle_chunks(room_id, alice).await.unwrap();
+        assert!(store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap().is_none());
+        let data = store.get_received_room_key_bundle_data(room_id, alice).await.unwrap().unwrap();
+        assert!(data.received_chunks.is_empty());
+
+        // A chunk of a new bundle drops the chunks of the previous one
+        receive(chunk("second", 1, 2, alice_device)).await;
+        assert!(store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap().is_none());
+
+        receive(chunk("second", 0, 2, alice_device)).await;
+        let chunks = store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap();
+        assert_eq!(indices(chunks), vec![0, 1]);
+
+        // The chunks of a bundle have to come from the same device
+        receive(chunk("third", 0, 2, alice_device)).await;
+        receive(chunk("third", 1, 2, device_id!("OTHERDEVICE"))).await;
+        assert!(store.get_received_room_key_bundle_chunks(room_id, alice).await.unwrap().is_none());
+
+        // The chunks of the bundles of other users aren't mixed up
+        let other_user = user_id!("@carol:localhost");
+        assert!(store
+            .get_received_room_key_bundle_chunks(room_id, other_user)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[async_test]
+    async fn test_delete_room_crypto_state() {
+        use futures_util::FutureExt;
+
+        let bob = OlmMachine::new(user_id!("@bob:localhost"), device_id!("BOBDEVICE")).await;
+        let alice = user_id!("@alice:localhost");
+        let room_id = room_id!("!room1:localhost");
+        let other_room_id = room_id!("!room2:localhost");
+
+        // Given the room keys and outbound group sessions of two rooms, and a partially
+        // received room key bundle
+        bob.create_outbound_group_session_with_defaults_test_helper(room_id).await.unwrap();
+        bob.create_outbound_group_session_with_defaults_test_helper(other_room_id).await.unwrap();
+        assert!(bob.outbound_session_info(room_id).await.is_some());
+
+        let mut content = RoomKeyBundle::default()
+            .encrypt()
+            .unwrap()
+            .to_content(room_id.to_owned(), owned_mxc_uri!("mxc://localhost/chunk"));
+        content.chunk =
+            Some(RoomKeyBundleChunk { bundle_id: "bundle".to_owned(), index: 0, count: 2 });
+        let data = StoredRoomKeyBundleData {
+            sender_user: alice.to_owned(),
+            sender_data: SenderData::unknown(),
+            bundle_data: content,
+            received_chunks: Vec::new(),
+        };
+        bob.store()
+            .save_changes(Changes { received_room_key_bundles: vec![data], ..Default::default() })
+            .await
+            .unwrap();
+        bob.store()
+            .set_room_rotation_policy(room_id, Some(Duration::from_secs(60)), None)
+            .await
+            .unwrap();
+
+        let mut room_keys_deleted_stream = pin!(bob.store().room_keys_deleted_stream());
+
+        // When the crypto state of the first room is deleted
+        let deleted = bob.delete_room_crypto_state(room_id).await.unwrap();
+
+        // Then its room key is gone, and reported
+        assert_eq!(deleted.room_keys.len(), 1);
+        assert_eq!(deleted.room_keys[0].room_id, room_id);
+        assert_eq!(deleted.room_key_bundle_senders, [alice.to_owned()]);
+
+        let update = room_keys_deleted_stream
+            .next()
+            .now_or_never()
+            .flatten()
+            .expect("We should have been notified about the deleted room keys")
+            .unwrap();
+        assert_eq!(update, deleted.room_keys);
+
+        // And so are the outbound group session, the received bundle and the settings
+        assert!(bob.outbound_session_info(room_id).await.is_none());
+        let bundle = bob.store().get_received_room_key_bundle_data(room_id, alice).await.unwrap();
+        assert!(bundle.is_none());
+        assert!(bob.store().get_room_rotation_policy(room_id).await.unwrap().is_none());
+
+        // But the other room is left alone
+        assert!(bob.outbound_session_info(other_room_id).await.is_some());
+        let remaining = bob.store().get_inbound_group_sessions().await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].room_id(), other_room_id);
+    }
+
     /// Create an inbound Megolm session for the given room.
     ///
     /// `olm_machine` is used to set the `sender_key` and `signing_key`
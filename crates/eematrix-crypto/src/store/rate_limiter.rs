@@ -0,0 +1,399 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Token buckets limiting how many outgoing requests of a kind the
+//! [`OlmMachine`] creates, see [`OlmMachineBuilder::rate_limits()`].
+//!
+//! [`OlmMachine`]: crate::OlmMachine
+//! [`OlmMachineBuilder::rate_limits()`]: crate::OlmMachineBuilder::rate_limits
+
+use std::{collections::BTreeSet, fmt, num::NonZeroU32, time::Duration};
+
+use ruma::OwnedTransactionId;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use super::{CryptoStoreError, Result, Store};
+use crate::types::requests::{AnyOutgoingRequest, OutgoingRequest};
+
+/// The limit of a single kind of request.
+///
+/// The limit is a token bucket: it holds up to `burst` tokens, every request
+/// takes one, and one token is added back every `refill_interval`.
+///
+/// The burst can't be zero, since no request could ever be created. Use a long
+/// `refill_interval` to allow requests rarely.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of requests which can be created in a row.
+    pub burst: NonZeroU32,
+    /// The time it takes for one more request to become available.
+    pub refill_interval: Duration,
+}
+
+impl RateLimit {
+    /// Create a new limit allowing `burst` requests in a row, and one more
+    /// every `refill_interval`.
+    pub fn new(burst: NonZeroU32, refill_interval: Duration) -> Self {
+        Self { burst, refill_interval }
+    }
+}
+
+/// The limits of the outgoing requests of an [`OlmMachine`].
+///
+/// A limit of `None` disables the limit for that kind of request, which is the
+/// default.
+///
+/// [`OlmMachine`]: crate::OlmMachine
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RateLimits {
+    /// The limit of the `/keys/claim` requests returned by
+    /// [`OlmMachine::get_missing_sessions()`] and
+    /// [`OlmMachine::prewarm_room()`].
+    ///
+    /// [`OlmMachine::get_missing_sessions()`]: crate::OlmMachine::get_missing_sessions
+    /// [`OlmMachine::prewarm_room()`]: crate::OlmMachine::prewarm_room
+    pub keys_claim: Option<RateLimit>,
+
+    /// The limit of the `/keys/query` requests returned by
+    /// [`OlmMachine::outgoing_requests()`]. Every request counts, the
+    /// requests exceeding the limit are left out.
+    ///
+    /// [`OlmMachine::outgoing_requests()`]: crate::OlmMachine::outgoing_requests
+    pub keys_query: Option<RateLimit>,
+
+    /// The limit of the to-device requests returned when sharing room keys,
+    /// e.g. by [`OlmMachine::share_room_key()`], or when encrypting custom
+    /// to-device events. Every request counts, not every call.
+    ///
+    /// The verification messages, key requests and key forwards returned by
+    /// [`OlmMachine::outgoing_requests()`] count as well, the requests
+    /// exceeding the limit are left out until tokens are added back.
+    ///
+    /// [`OlmMachine::share_room_key()`]: crate::OlmMachine::share_room_key
+    /// [`OlmMachine::outgoing_requests()`]: crate::OlmMachine::outgoing_requests
+    pub to_device: Option<RateLimit>,
+}
+
+impl RateLimits {
+    /// Limit the `/keys/claim` requests, see [`RateLimits::keys_claim`].
+    pub fn keys_claim(mut self, limit: RateLimit) -> Self {
+        self.keys_claim = Some(limit);
+        self
+    }
+
+    /// Limit the `/keys/query` requests, see [`RateLimits::keys_query`].
+    pub fn keys_query(mut self, limit: RateLimit) -> Self {
+        self.keys_query = Some(limit);
+        self
+    }
+
+    /// Limit the to-device requests, see [`RateLimits::to_device`].
+    pub fn to_device(mut self, limit: RateLimit) -> Self {
+        self.to_device = Some(limit);
+        self
+    }
+
+    fn get(&self, request_type: RateLimitedRequest) -> Option<RateLimit> {
+        match request_type {
+            RateLimitedRequest::KeysClaim => self.keys_claim,
+            RateLimitedRequest::KeysQuery => self.keys_query,
+            RateLimitedRequest::ToDevice => self.to_device,
+        }
+    }
+}
+
+/// The kinds of requests which can be rate limited, see [`RateLimits`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitedRequest {
+    /// A `/keys/claim` request.
+    KeysClaim,
+    /// A `/keys/query` request.
+    KeysQuery,
+    /// A to-device request.
+    ToDevice,
+}
+
+impl RateLimitedRequest {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitedRequest::KeysClaim => "keys_claim",
+            RateLimitedRequest::KeysQuery => "keys_query",
+            RateLimitedRequest::ToDevice => "to_device",
+        }
+    }
+}
+
+impl fmt::Display for RateLimitedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A request wasn't created because its [`RateLimit`] was exhausted.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("too many {request_type} requests, retry in {retry_after:?}")]
+pub struct RateLimitedError {
+    /// The kind of request that was throttled.
+    pub request_type: RateLimitedRequest,
+    /// The time after which the request can be retried.
+    pub retry_after: Duration,
+}
+
+/// The persisted state of a token bucket.
+#[derive(Debug, Serialize, Deserialize)]
+struct Bucket {
+    /// The tokens which are left.
+    tokens: u32,
+    /// When the tokens were last refilled, in milliseconds since the unix
+    /// epoch.
+    refilled_at: u64,
+}
+
+impl Bucket {
+    /// Add the tokens which became available since the last refill.
+    fn refill(&mut self, limit: &RateLimit, now: u64) {
+        let interval = limit.refill_interval.as_millis().max(1) as u64;
+        let elapsed = now.saturating_sub(self.refilled_at);
+        let new_tokens = elapsed / interval;
+
+        let burst = limit.burst.get();
+
+        if self.tokens.saturating_add(new_tokens.try_into().unwrap_or(u32::MAX)) >= burst {
+            self.tokens = burst;
+            self.refilled_at = now;
+        } else {
+            // The new tokens are fewer than the burst, so they fit into an u32.
+            self.tokens += new_tokens as u32;
+            self.refilled_at += new_tokens * interval;
+        }
+    }
+}
+
+/// Enforces the [`RateLimits`] of an [`OlmMachine`].
+///
+/// The buckets are kept in the store as custom values, so the limits hold
+/// across restarts.
+///
+/// [`OlmMachine`]: crate::OlmMachine
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    /// Serializes the updates of the buckets.
+    lock: Mutex<()>,
+    /// The to-device requests which already took a token, see
+    /// [`RateLimiter::admit_to_device_requests()`].
+    admitted_to_device_requests: Mutex<BTreeSet<OwnedTransactionId>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self { limits, lock: Mutex::new(()), admitted_to_device_requests: Default::default() }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits
+    }
+
+    fn bucket_key(request_type: RateLimitedRequest) -> String {
+        format!("rate_limit_bucket:{request_type}")
+    }
+
+    /// Load the bucket of the given kind of request, refill it, and store it
+    /// again once `f` updated it.
+    async fn update_bucket<T>(
+        &self,
+        store: &Store,
+        request_type: RateLimitedRequest,
+        limit: &RateLimit,
+        f: impl FnOnce(&mut Bucket, u64) -> Result<T>,
+    ) -> Result<T> {
+        let _guard = self.lock.lock().await;
+        let now: u64 = store.clock().now().get().into();
+
+        let mut bucket = store
+            .get_value(&Self::bucket_key(request_type))
+            .await?
+            .unwrap_or(Bucket { tokens: limit.burst.get(), refilled_at: now });
+        bucket.refill(limit, now);
+
+        let result = f(&mut bucket, now)?;
+        store.set_value(&Self::bucket_key(request_type), &bucket).await?;
+
+        Ok(result)
+    }
+
+    /// Take the tokens of up to `count` requests of the given kind, before
+    /// the requests are created.
+    ///
+    /// Returns the number of requests which may be created, which is at least
+    /// one, or a [`RateLimitedError`] if no tokens are left.
+    pub async fn acquire(
+        &self,
+        store: &Store,
+        request_type: RateLimitedRequest,
+        count: usize,
+    ) -> Result<usize> {
+        let Some(limit) = self.limits.get(request_type) else {
+            return Ok(count);
+        };
+
+        if count == 0 {
+            return Ok(0);
+        }
+
+        self.update_bucket(store, request_type, &limit, |bucket, now| {
+            if bucket.tokens == 0 {
+                let elapsed = Duration::from_millis(now.saturating_sub(bucket.refilled_at));
+                let retry_after = limit.refill_interval.saturating_sub(elapsed);
+
+                debug!(%request_type, ?retry_after, "Throttling an outgoing request");
+
+                return Err(RateLimitedError { request_type, retry_after }.into());
+            }
+
+            let taken = bucket.tokens.min(count.try_into().unwrap_or(u32::MAX));
+            bucket.tokens -= taken;
+
+            Ok(taken as usize)
+        })
+        .await
+    }
+
+    /// Settle the tokens taken by [`RateLimiter::acquire()`] once it's known
+    /// how many requests were created.
+    ///
+    /// Unused tokens are given back, requests beyond the acquired ones take
+    /// their tokens after the fact. The bucket can't go below zero, so
+    /// requests which were created while there were tokens left are never
+    /// throttled after the fact.
+    ///
+    /// This can't fail, since the requests were already created. If the
+    /// bucket can't be updated, this is only logged.
+    pub async fn settle(
+        &self,
+        store: &Store,
+        request_type: RateLimitedRequest,
+        acquired: usize,
+        created: usize,
+    ) {
+        let Some(limit) = self.limits.get(request_type) else {
+            return;
+        };
+
+        if acquired == created {
+            return;
+        }
+
+        let to_u32 = |count: usize| -> u32 { count.try_into().unwrap_or(u32::MAX) };
+
+        let result = self
+            .update_bucket(store, request_type, &limit, |bucket, _| {
+                if created > acquired {
+                    bucket.tokens = bucket.tokens.saturating_sub(to_u32(created - acquired));
+                } else {
+                    bucket.tokens = bucket
+                        .tokens
+                        .saturating_add(to_u32(acquired - created))
+                        .min(limit.burst.get());
+                }
+
+                Ok(())
+            })
+            .await;
+
+        if let Err(error) = result {
+            warn!(%request_type, ?error, "Couldn't update the rate limit bucket");
+        }
+    }
+
+    /// Apply the [`RateLimits::to_device`] limit to the to-device requests
+    /// among the given outgoing requests.
+    ///
+    /// Every request takes a token the first time it's returned, requests
+    /// which are returned again, because they weren't sent out yet, don't.
+    /// Requests which don't fit into the limit are left out, and are admitted
+    /// once tokens are added back. Other kinds of requests are returned as
+    /// they are.
+    pub async fn admit_to_device_requests(
+        &self,
+        store: &Store,
+        requests: Vec<OutgoingRequest>,
+    ) -> Result<Vec<OutgoingRequest>> {
+        if self.limits.to_device.is_none() {
+            return Ok(requests);
+        }
+
+        let (to_device, mut admitted): (Vec<_>, Vec<_>) = requests
+            .into_iter()
+            .partition(|r| matches!(r.request(), AnyOutgoingRequest::ToDeviceRequest(_)));
+
+        let mut admitted_requests = self.admitted_to_device_requests.lock().await;
+
+        // Forget the requests which were sent out, or dropped, since.
+        let request_ids: BTreeSet<_> = to_device.iter().map(|r| &r.request_id).collect();
+        admitted_requests.retain(|request_id| request_ids.contains(request_id));
+
+        let (previously_admitted, new): (Vec<_>, Vec<_>) =
+            to_device.into_iter().partition(|r| admitted_requests.contains(&r.request_id));
+        admitted.extend(previously_admitted);
+
+        let count = match self.acquire(store, RateLimitedRequest::ToDevice, new.len()).await {
+            Ok(count) => count,
+            Err(CryptoStoreError::RateLimited(error)) => {
+                debug!(retry_after = ?error.retry_after, "Not sending to-device requests for now");
+                0
+            }
+            Err(error) => return Err(error),
+        };
+
+        for request in new.into_iter().take(count) {
+            admitted_requests.insert(request.request_id.clone());
+            admitted.push(request);
+        }
+
+        Ok(admitted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, time::Duration};
+
+    use super::{Bucket, RateLimit};
+
+    #[test]
+    fn test_bucket_refill() {
+        let limit = RateLimit::new(NonZeroU32::new(3).unwrap(), Duration::from_secs(1));
+        let mut bucket = Bucket { tokens: 0, refilled_at: 10_000 };
+
+        // Not a whole interval has passed.
+        bucket.refill(&limit, 10_999);
+        assert_eq!(bucket.tokens, 0);
+        assert_eq!(bucket.refilled_at, 10_000);
+
+        // The remainder of the interval is kept for the next token.
+        bucket.refill(&limit, 12_500);
+        assert_eq!(bucket.tokens, 2);
+        assert_eq!(bucket.refilled_at, 12_000);
+
+        // The bucket never holds more than the burst.
+        bucket.refill(&limit, 100_000);
+        assert_eq!(bucket.tokens, 3);
+        assert_eq!(bucket.refilled_at, 100_000);
+    }
+}
@@ -0,0 +1,648 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A [`CryptoStore`] adapter which rejects all writes, see [`ReadOnlyStore`].
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use ruma::{
+    events::secret::request::SecretName, DeviceId, EventId, OwnedDeviceId, OwnedEventId,
+    OwnedRoomId, OwnedUserId, RoomId, SecondsSinceUnixEpoch, TransactionId, UserId,
+};
+use serde::Serialize;
+use vodozemac::Curve25519PublicKey;
+
+use super::{
+    types::{
+        BackupKeys, ChangeJournalRecord, Changes, CompactionReport, DehydratedDeviceKey,
+        DeletedRoomCryptoState, PendingChanges, QuarantinedEntry, RoomKeyCounts, RoomKeySummary,
+        RoomSettings, SessionShareRecord, ShredReport, StoredRoomKeyBundleData, TrackedUser,
+        VerificationAuditEntry,
+    },
+    CryptoStore, CryptoStoreError, DynCryptoStore, IntoCryptoStore, Result,
+};
+use crate::{
+    clock::Clock,
+    olm::{
+        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
+        SenderDataType, Session,
+    },
+    types::events::room_key_withheld::RoomKeyWithheldEvent,
+    Account, CrossSigningStatus, DeviceData, GossipRequest, GossippedSecret, LocalTrust,
+    SecretInfo, UserIdentityData,
+};
+
+/// A [`CryptoStore`] which wraps another store and rejects all writes with a
+/// [`CryptoStoreError::ReadOnly`] error.
+///
+/// This allows tooling, e.g. for incident response, to open the store of a
+/// user and inspect its content without the risk of modifying it. The content
+/// can be summarized using [`ReadOnlyStore::dump()`].
+#[derive(Debug)]
+pub struct ReadOnlyStore {
+    inner: Arc<DynCryptoStore>,
+}
+
+impl ReadOnlyStore {
+    /// Wrap the given store, making it read-only.
+    pub fn new(store: impl IntoCryptoStore) -> Self {
+        Self { inner: store.into_crypto_store() }
+    }
+
+    /// Collect a summary of the content of the store.
+    ///
+    /// The summary contains the public parts of the stored data, e.g. the
+    /// identity keys of our account and the IDs of the sessions, but none of
+    /// the private keys.
+    pub async fn dump(&self) -> Result<StoreDump> {
+        let account = self.inner.load_account().await?.map(|account| {
+            let identity_keys = account.identity_keys();
+
+            AccountDump {
+                user_id: account.user_id().to_owned(),
+                device_id: account.device_id().to_owned(),
+                curve25519_key: identity_keys.curve25519.to_base64(),
+                ed25519_key: identity_keys.ed25519.to_base64(),
+                shared: account.shared(),
+            }
+        });
+
+        let cross_signing = match self.inner.load_identity().await? {
+            Some(identity) => Some(identity.status().await),
+            None => None,
+        };
+
+        let backup_version = self.inner.load_backup_keys().await?.backup_version;
+
+        let mut sessions = Vec::new();
+
+        for sender_key in self.inner.get_session_sender_keys().await? {
+            for session in self.inner.get_sessions(&sender_key).await?.unwrap_or_default() {
+                sessions.push(SessionDump {
+                    session_id: session.session_id().to_owned(),
+                    sender_key: session.sender_key.to_base64(),
+                    created_using_fallback_key: session.created_using_fallback_key,
+                    creation_time: session.creation_time,
+                    last_use_time: session.last_use_time,
+                });
+            }
+        }
+
+        let inbound_group_sessions = self
+            .inner
+            .get_inbound_group_sessions()
+            .await?
+            .iter()
+            .map(|session| InboundGroupSessionDump {
+                room_id: session.room_id().to_owned(),
+                session_id: session.session_id().to_owned(),
+                sender_key: session.sender_key().to_base64(),
+                first_known_index: session.first_known_index(),
+                backed_up: session.backed_up(),
+            })
+            .collect();
+
+        let tracked_users = self.inner.load_tracked_users().await?;
+        let mut devices = Vec::new();
+        let mut identities = Vec::new();
+
+        for user in &tracked_users {
+            for device in self.inner.get_user_devices(&user.user_id).await?.into_values() {
+                devices.push(DeviceDump {
+                    user_id: device.user_id().to_owned(),
+                    device_id: device.device_id().to_owned(),
+                    curve25519_key: device.curve25519_key().map(|k| k.to_base64()),
+                    ed25519_key: device.ed25519_key().map(|k| k.to_base64()),
+                    local_trust: device.local_trust_state(),
+                    deleted: device.is_deleted(),
+                });
+            }
+
+            if let Some(identity) = self.inner.get_user_identity(&user.user_id).await? {
+                identities.push(IdentityDump {
+                    user_id: identity.user_id().to_owned(),
+                    master_key: identity.master_key().get_first_key().map(|k| k.to_base64()),
+                    own: matches!(identity, UserIdentityData::Own(_)),
+                });
+            }
+        }
+
+        Ok(StoreDump {
+            account,
+            cross_signing,
+            backup_version,
+            sessions,
+            inbound_group_sessions,
+            tracked_users,
+            devices,
+            identities,
+        })
+    }
+}
+
+/// A summary of the content of a crypto store, as returned by
+/// [`ReadOnlyStore::dump()`].
+#[derive(Debug, Clone, Serialize)]
+pub struct StoreDump {
+    /// Our Olm account, if the store contains one.
+    pub account: Option<AccountDump>,
+    /// Which of the private cross-signing keys the store contains.
+    pub cross_signing: Option<CrossSigningStatus>,
+    /// The version of the backup whose decryption key we have, if any.
+    pub backup_version: Option<String>,
+    /// The Olm sessions.
+    pub sessions: Vec<SessionDump>,
+    /// The Megolm sessions which are used to decrypt room events.
+    pub inbound_group_sessions: Vec<InboundGroupSessionDump>,
+    /// The users whose device lists we are tracking.
+    pub tracked_users: Vec<TrackedUser>,
+    /// The devices of the tracked users.
+    pub devices: Vec<DeviceDump>,
+    /// The cross-signing identities of the tracked users.
+    pub identities: Vec<IdentityDump>,
+}
+
+/// The public parts of an Olm account, see [`StoreDump`].
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountDump {
+    /// The user the account belongs to.
+    pub user_id: OwnedUserId,
+    /// The ID of our device.
+    pub device_id: OwnedDeviceId,
+    /// The Curve25519 identity key of our device, encoded as base64.
+    pub curve25519_key: String,
+    /// The Ed25519 identity key of our device, encoded as base64.
+    pub ed25519_key: String,
+    /// Whether the device keys were uploaded to the server.
+    pub shared: bool,
+}
+
+/// A summary of an Olm session, see [`StoreDump`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionDump {
+    /// The ID of the session.
+    pub session_id: String,
+    /// The Curve25519 key of the other side of the session, encoded as base64.
+    pub sender_key: String,
+    /// Whether the session was created using a fallback key.
+    pub created_using_fallback_key: bool,
+    /// When the session was created.
+    pub creation_time: SecondsSinceUnixEpoch,
+    /// When the session was last used.
+    pub last_use_time: SecondsSinceUnixEpoch,
+}
+
+/// A summary of a Megolm session, see [`StoreDump`].
+#[derive(Debug, Clone, Serialize)]
+pub struct InboundGroupSessionDump {
+    /// The room the session is used in.
+    pub room_id: OwnedRoomId,
+    /// The ID of the session.
+    pub session_id: String,
+    /// The Curve25519 key of the device which created the session, encoded as
+    /// base64.
+    pub sender_key: String,
+    /// The first message index the session can decrypt.
+    pub first_known_index: u32,
+    /// Whether the session was backed up.
+    pub backed_up: bool,
+}
+
+/// A summary of a device, see [`StoreDump`].
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceDump {
+    /// The user the device belongs to.
+    pub user_id: OwnedUserId,
+    /// The ID of the device.
+    pub device_id: OwnedDeviceId,
+    /// The Curve25519 key of the device, encoded as base64.
+    pub curve25519_key: Option<String>,
+    /// The Ed25519 key of the device, encoded as base64.
+    pub ed25519_key: Option<String>,
+    /// The local trust state of the device.
+    pub local_trust: LocalTrust,
+    /// Whether the device was deleted.
+    pub deleted: bool,
+}
+
+/// A summary of a user identity, see [`StoreDump`].
+#[derive(Debug, Clone, Serialize)]
+pub struct IdentityDump {
+    /// The user the identity belongs to.
+    pub user_id: OwnedUserId,
+    /// The master key of the identity, encoded as base64.
+    pub master_key: Option<String>,
+    /// Whether this is our own identity.
+    pub own: bool,
+}
+
+#[cfg_attr(target_family = "wasm", async_trait(?Send))]
+#[cfg_attr(not(target_family = "wasm"), async_trait)]
+impl CryptoStore for ReadOnlyStore {
+    type Error = CryptoStoreError;
+
+    async fn load_account(&self) -> Result<Option<Account>> {
+        self.inner.load_account().await
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        self.inner.load_identity().await
+    }
+
+    async fn save_changes(&self, _changes: Changes) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn save_pending_changes(&self, _changes: PendingChanges) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn save_inbound_group_sessions(
+        &self,
+        _sessions: Vec<InboundGroupSession>,
+        _backed_up_to_version: Option<&str>,
+    ) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Vec<Session>>> {
+        self.inner.get_sessions(sender_key).await
+    }
+
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>> {
+        self.inner.get_session_sender_keys().await
+    }
+
+    async fn delete_sessions(&self, _sender_key: &str, _session_ids: &[String]) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        self.inner.get_inbound_group_session(room_id, session_id).await
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>> {
+        self.inner.get_withheld_info(room_id, session_id).await
+    }
+
+    async fn record_megolm_message_index(
+        &self,
+        _room_id: &RoomId,
+        _session_id: &str,
+        _message_index: u32,
+        _event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>> {
+        self.inner.get_session_share_history(room_id, session_id).await
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        self.inner.get_inbound_group_sessions().await
+    }
+
+    async fn quarantine_undecodable_inbound_group_sessions(&self) -> Result<Vec<QuarantinedEntry>> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>> {
+        self.inner.get_quarantined_entries().await
+    }
+
+    async fn restore_quarantined_entries(&self) -> Result<usize> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn delete_inbound_group_sessions(
+        &self,
+        _room_id: &RoomId,
+        _session_ids: &[String],
+    ) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.get_inbound_group_sessions_batch(after, limit).await
+    }
+
+    async fn inbound_group_session_counts(
+        &self,
+        backup_version: Option<&str>,
+    ) -> Result<RoomKeyCounts> {
+        self.inner.inbound_group_session_counts(backup_version).await
+    }
+
+    async fn get_inbound_group_sessions_for_device_batch(
+        &self,
+        curve_key: Curve25519PublicKey,
+        sender_data_type: SenderDataType,
+        after_session_id: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner
+            .get_inbound_group_sessions_for_device_batch(
+                curve_key,
+                sender_data_type,
+                after_session_id,
+                limit,
+            )
+            .await
+    }
+
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.get_inbound_group_sessions_for_room(room_id, since).await
+    }
+
+    async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>> {
+        self.inner.get_room_key_summaries().await
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        backup_version: &str,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.inbound_group_sessions_for_backup(backup_version, limit).await
+    }
+
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &self,
+        _backup_version: &str,
+        _room_and_session_ids: &[(&RoomId, &str)],
+    ) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        self.inner.load_backup_keys().await
+    }
+
+    async fn delete_backup_decryption_key(&self) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn load_dehydrated_device_pickle_key(&self) -> Result<Option<DehydratedDeviceKey>> {
+        self.inner.load_dehydrated_device_pickle_key().await
+    }
+
+    async fn delete_dehydrated_device_pickle_key(&self) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_outbound_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        self.inner.get_outbound_group_session(room_id).await
+    }
+
+    async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>> {
+        self.inner.load_tracked_users().await
+    }
+
+    async fn load_tracked_users_paged(
+        &self,
+        after: Option<&UserId>,
+        limit: usize,
+    ) -> Result<Vec<TrackedUser>> {
+        self.inner.load_tracked_users_paged(after, limit).await
+    }
+
+    async fn save_tracked_users(&self, _users: &[(&UserId, bool)]) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<DeviceData>> {
+        self.inner.get_device(user_id, device_id).await
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<OwnedDeviceId, DeviceData>> {
+        self.inner.get_user_devices(user_id).await
+    }
+
+    async fn get_own_device(&self) -> Result<DeviceData> {
+        self.inner.get_own_device().await
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentityData>> {
+        self.inner.get_user_identity(user_id).await
+    }
+
+    async fn get_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>> {
+        self.inner.get_verification_audit_log(user_id).await
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        self.inner.is_message_known(message_hash).await
+    }
+
+    async fn get_outgoing_secret_requests(
+        &self,
+        request_id: &TransactionId,
+    ) -> Result<Option<GossipRequest>> {
+        self.inner.get_outgoing_secret_requests(request_id).await
+    }
+
+    async fn get_secret_request_by_info(
+        &self,
+        secret_info: &SecretInfo,
+    ) -> Result<Option<GossipRequest>> {
+        self.inner.get_secret_request_by_info(secret_info).await
+    }
+
+    async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
+        self.inner.get_unsent_secret_requests().await
+    }
+
+    async fn delete_outgoing_secret_requests(&self, _request_id: &TransactionId) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_secrets_from_inbox(
+        &self,
+        secret_name: &SecretName,
+    ) -> Result<Vec<GossippedSecret>> {
+        self.inner.get_secrets_from_inbox(secret_name).await
+    }
+
+    async fn delete_secrets_from_inbox(&self, _secret_name: &SecretName) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
+        self.inner.get_room_settings(room_id).await
+    }
+
+    async fn get_received_room_key_bundle_data(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<StoredRoomKeyBundleData>> {
+        self.inner.get_received_room_key_bundle_data(room_id, user_id).await
+    }
+
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get_custom_value(key).await
+    }
+
+    async fn set_custom_value(&self, _key: &str, _value: Vec<u8>) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn remove_custom_value(&self, _key: &str) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn get_app_data(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get_app_data(namespace, key).await
+    }
+
+    async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>> {
+        self.inner.get_app_data_keys(namespace).await
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        _lease_duration_ms: u32,
+        _key: &str,
+        _holder: &str,
+    ) -> Result<bool> {
+        // Taking a lease writes to the store as well.
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.inner.set_clock(clock)
+    }
+
+    async fn next_batch_token(&self) -> Result<Option<String>> {
+        self.inner.next_batch_token().await
+    }
+
+    async fn compact(&self) -> Result<CompactionReport> {
+        // Compacting rewrites the underlying storage.
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn load_change_journal(&self, since: u64) -> Result<Vec<ChangeJournalRecord>> {
+        self.inner.load_change_journal(since).await
+    }
+
+    async fn truncate_change_journal(&self, _up_to: u64) -> Result<()> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn shred(&self) -> Result<ShredReport> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+
+    async fn delete_room_crypto_state(
+        &self,
+        _room_id: &RoomId,
+        _custom_value_keys: &[String],
+    ) -> Result<DeletedRoomCryptoState> {
+        Err(CryptoStoreError::ReadOnly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use assert_matches2::assert_matches;
+    use matrix_sdk_test::async_test;
+    use ruma::{device_id, room_id, user_id};
+
+    use super::ReadOnlyStore;
+    use crate::{
+        store::{types::Changes, CryptoStore, CryptoStoreError, MemoryStore},
+        OlmMachine,
+    };
+
+    #[async_test]
+    async fn test_read_only_store() {
+        let store = Arc::new(MemoryStore::new());
+        let machine = OlmMachine::with_store(
+            user_id!("@alice:localhost"),
+            device_id!("ALICEDEVICE"),
+            store.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+        let room_id = room_id!("!room:localhost");
+        machine.create_outbound_group_session_with_defaults_test_helper(room_id).await.unwrap();
+
+        let read_only = ReadOnlyStore::new(store.clone());
+
+        // Reads are passed through to the wrapped store.
+        assert!(read_only.load_account().await.unwrap().is_some());
+        assert_eq!(read_only.get_inbound_group_sessions().await.unwrap().len(), 1);
+
+        // While writes are rejected.
+        assert_matches!(
+            read_only.save_changes(Changes::default()).await,
+            Err(CryptoStoreError::ReadOnly)
+        );
+        assert_matches!(
+            read_only.set_custom_value("key", b"value".to_vec()).await,
+            Err(CryptoStoreError::ReadOnly)
+        );
+        assert!(store.get_custom_value("key").await.unwrap().is_none());
+
+        let dump = read_only.dump().await.unwrap();
+        let account = dump.account.unwrap();
+        assert_eq!(account.device_id, "ALICEDEVICE");
+        assert_eq!(account.curve25519_key, machine.identity_keys().curve25519.to_base64());
+        assert_eq!(dump.inbound_group_sessions.len(), 1);
+        assert_eq!(dump.inbound_group_sessions[0].room_id, room_id);
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use async_trait::async_trait;
+use matrix_sdk_common::{SendOutsideWasm, SyncOutsideWasm};
+use ruma::{DeviceId, UserId};
+
+use super::{types::BackupDecryptionKey, Result};
+
+/// A backend storing the [`BackupDecryptionKey`] outside of the crypto store,
+/// for example in a platform keychain such as the Keychain Services on Apple
+/// platforms, libsecret on Linux or the Windows Credential Manager.
+///
+/// If a backend is used, the backup decryption key isn't pickled into the
+/// crypto store anymore, it's handed to the backend instead. A key which was
+/// stored before the backend was selected is moved into the backend, and
+/// deleted from the store afterwards. The backend can be selected using
+/// [`OlmMachineBuilder::secret_storage()`].
+///
+/// The key can be converted to and from a string using
+/// [`BackupDecryptionKey::to_base64()`] and
+/// [`BackupDecryptionKey::from_base64()`].
+///
+/// This crate doesn't provide any implementations, since talking to the
+/// platform keychains requires platform-specific dependencies. Applications
+/// and bindings are expected to implement it using e.g. the `keyring` crate.
+///
+/// [`OlmMachineBuilder::secret_storage()`]: crate::OlmMachineBuilder::secret_storage
+#[cfg_attr(target_family = "wasm", async_trait(?Send))]
+#[cfg_attr(not(target_family = "wasm"), async_trait)]
+pub trait SecretStorageBackend: fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// Store the backup decryption key of the given device, replacing any
+    /// previously stored key.
+    async fn save_backup_decryption_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key: &BackupDecryptionKey,
+    ) -> Result<()>;
+
+    /// Load the backup decryption key of the given device, if one was stored.
+    async fn load_backup_decryption_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<BackupDecryptionKey>>;
+
+    /// Delete the backup decryption key of the given device, if one was
+    /// stored.
+    ///
+    /// This is called when the crypto state is shredded, see
+    /// [`OlmMachine::shred()`](crate::OlmMachine::shred).
+    async fn delete_backup_decryption_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<()>;
+}
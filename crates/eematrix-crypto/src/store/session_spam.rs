@@ -0,0 +1,311 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detection of devices creating an excessive number of Olm sessions with us,
+//! see [`SessionCreationLimit`] and [`Store::suspicious_activity_stream()`].
+//!
+//! Every new Olm session uses up one of our one-time keys, so a device which
+//! keeps creating sessions can exhaust our one-time keys on the server, and
+//! forces other devices to use our fallback key.
+//!
+//! [`Store::suspicious_activity_stream()`]: super::Store::suspicious_activity_stream
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future,
+    time::Duration,
+};
+
+use futures_core::Stream;
+use futures_util::StreamExt;
+use matrix_sdk_common::locks::{Mutex as StdMutex, RwLock as StdRwLock};
+use ruma::{OwnedUserId, UserId};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::warn;
+use vodozemac::Curve25519PublicKey;
+
+/// The number of new Olm sessions a single device, identified by its
+/// Curve25519 key, may create with us within a period of time.
+///
+/// A device which creates more sessions is reported as a
+/// [`SuspiciousActivity::SessionSpam`]. If [`SessionCreationLimit::reject`]
+/// is set, pre-key messages which would create more sessions are rejected with
+/// [`OlmError::SessionCreationThrottled`] as well.
+///
+/// [`OlmError::SessionCreationThrottled`]: crate::OlmError::SessionCreationThrottled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionCreationLimit {
+    /// The number of sessions a device may create within the period.
+    pub max_sessions: u32,
+    /// The period the sessions are counted over.
+    pub period: Duration,
+    /// Whether to reject the sessions exceeding the limit, instead of only
+    /// reporting the device.
+    pub reject: bool,
+}
+
+impl SessionCreationLimit {
+    /// Create a new limit allowing `max_sessions` new sessions per device
+    /// within `period`.
+    ///
+    /// Devices exceeding the limit are only reported, use
+    /// [`SessionCreationLimit::rejecting()`] to reject their sessions.
+    pub fn new(max_sessions: u32, period: Duration) -> Self {
+        Self { max_sessions, period, reject: false }
+    }
+
+    /// Reject the sessions exceeding the limit, instead of only reporting the
+    /// device.
+    pub fn rejecting(self) -> Self {
+        Self { reject: true, ..self }
+    }
+}
+
+impl Default for SessionCreationLimit {
+    /// Ten sessions per device within an hour, devices exceeding it are only
+    /// reported.
+    ///
+    /// Devices only create a new session with us when they don't have a
+    /// working one, and unwedge a session at most once an hour.
+    fn default() -> Self {
+        Self::new(10, Duration::from_secs(60 * 60))
+    }
+}
+
+/// Suspicious behaviour of another device, see
+/// [`Store::suspicious_activity_stream()`].
+///
+/// [`Store::suspicious_activity_stream()`]: super::Store::suspicious_activity_stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SuspiciousActivity {
+    /// A device tried to create more new Olm sessions with us than the
+    /// [`SessionCreationLimit`] allows.
+    ///
+    /// This is reported once until the device created fewer sessions within
+    /// the period again.
+    SessionSpam {
+        /// The user the pre-key messages claimed to come from.
+        sender: OwnedUserId,
+        /// The Curve25519 key of the device.
+        sender_key: Curve25519PublicKey,
+        /// The limit which was exceeded.
+        limit: SessionCreationLimit,
+    },
+}
+
+/// The sessions a single device recently created with us.
+#[derive(Debug, Default)]
+struct SenderSessions {
+    /// When the sessions were created, in milliseconds since the unix epoch,
+    /// oldest first.
+    ///
+    /// At most [`SessionCreationLimit::max_sessions`] are kept, the older ones
+    /// don't matter to tell if the limit was exceeded.
+    created_at: VecDeque<u64>,
+    /// Whether the device was reported since it last stayed within the limit.
+    reported: bool,
+}
+
+impl SenderSessions {
+    /// Forget the sessions which were created before the period.
+    fn expire(&mut self, period: Duration, now: u64) {
+        let cutoff = now.saturating_sub(period.as_millis() as u64);
+
+        while self.created_at.front().is_some_and(|created_at| *created_at <= cutoff) {
+            self.created_at.pop_front();
+        }
+    }
+}
+
+/// Counts the new Olm sessions per sender key and enforces the
+/// [`SessionCreationLimit`].
+///
+/// The counts are only kept in memory, they start from zero after a restart.
+#[derive(Debug)]
+pub(crate) struct SessionSpamDetector {
+    limit: StdRwLock<Option<SessionCreationLimit>>,
+    /// The recently created sessions, keyed by the base64 encoded sender key.
+    senders: StdMutex<HashMap<String, SenderSessions>>,
+    sender: broadcast::Sender<SuspiciousActivity>,
+}
+
+impl Default for SessionSpamDetector {
+    fn default() -> Self {
+        Self {
+            limit: StdRwLock::new(Some(SessionCreationLimit::default())),
+            senders: Default::default(),
+            sender: broadcast::Sender::new(10),
+        }
+    }
+}
+
+impl SessionSpamDetector {
+    pub fn limit(&self) -> Option<SessionCreationLimit> {
+        *self.limit.read()
+    }
+
+    pub fn set_limit(&self, limit: Option<SessionCreationLimit>) {
+        *self.limit.write() = limit;
+    }
+
+    /// Check that the device with the given sender key may create another
+    /// session at `now`, reporting it if it exceeds the limit.
+    ///
+    /// Returns `false` if the session should be rejected, which is only the
+    /// case if [`SessionCreationLimit::reject`] is set. The session isn't
+    /// counted, use [`SessionSpamDetector::record()`] once it was created.
+    pub fn check(&self, sender: &UserId, sender_key: Curve25519PublicKey, now: u64) -> bool {
+        let Some(limit) = self.limit() else {
+            return true;
+        };
+
+        let mut senders = self.senders.lock();
+        let Some(sessions) = senders.get_mut(&sender_key.to_base64()) else {
+            return true;
+        };
+
+        sessions.expire(limit.period, now);
+
+        if sessions.created_at.len() < limit.max_sessions as usize {
+            sessions.reported = false;
+            return true;
+        }
+
+        if !sessions.reported {
+            sessions.reported = true;
+
+            warn!(
+                ?sender,
+                ?sender_key,
+                max_sessions = limit.max_sessions,
+                period = ?limit.period,
+                reject = limit.reject,
+                "A device created too many Olm sessions with us"
+            );
+
+            // Ignore the result. It can only fail if there are no listeners.
+            let _ = self.sender.send(SuspiciousActivity::SessionSpam {
+                sender: sender.to_owned(),
+                sender_key,
+                limit,
+            });
+        }
+
+        !limit.reject
+    }
+
+    /// Count a session which the device with the given sender key created at
+    /// `now`.
+    pub fn record(&self, sender_key: Curve25519PublicKey, now: u64) {
+        let Some(limit) = self.limit() else {
+            return;
+        };
+
+        let mut senders = self.senders.lock();
+
+        // Forget the devices which didn't create any sessions lately, so
+        // devices creating a single session each don't pile up.
+        senders.retain(|_, sessions| {
+            sessions.expire(limit.period, now);
+            !sessions.created_at.is_empty()
+        });
+
+        let sessions = senders.entry(sender_key.to_base64()).or_default();
+        sessions.created_at.push_back(now);
+
+        if sessions.created_at.len() > limit.max_sessions as usize {
+            sessions.created_at.pop_front();
+        }
+    }
+
+    pub fn stream(&self) -> impl Stream<Item = SuspiciousActivity> {
+        BroadcastStream::new(self.sender.subscribe()).filter_map(|result| {
+            future::ready(match result {
+                Ok(activity) => Some(activity),
+                Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                    warn!("suspicious_activity_stream missed {lag} updates");
+                    None
+                }
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ruma::user_id;
+    use vodozemac::Curve25519PublicKey;
+
+    use super::{SessionCreationLimit, SessionSpamDetector};
+
+    #[test]
+    fn test_session_creation_limit() {
+        let detector = SessionSpamDetector::default();
+        let limit = SessionCreationLimit::new(2, Duration::from_secs(10)).rejecting();
+        detector.set_limit(Some(limit));
+
+        let sender = user_id!("@mallory:localhost");
+        let sender_key = Curve25519PublicKey::from([1; 32]);
+        let other_key = Curve25519PublicKey::from([2; 32]);
+
+        for now in [1_000, 2_000] {
+            assert!(detector.check(sender, sender_key, now));
+            detector.record(sender_key, now);
+        }
+
+        // The third session within the period is rejected, other devices are
+        // unaffected.
+        assert!(!detector.check(sender, sender_key, 10_999));
+        assert!(detector.check(sender, other_key, 10_999));
+
+        // Once the first session is older than the period, another one is
+        // allowed.
+        assert!(detector.check(sender, sender_key, 11_000));
+
+        // Without a limit, every session is allowed.
+        detector.set_limit(None);
+        detector.record(sender_key, 11_000);
+        assert!(detector.check(sender, sender_key, 11_000));
+    }
+
+    #[test]
+    fn test_session_creation_limit_report_only() {
+        let detector = SessionSpamDetector::default();
+        detector.set_limit(Some(SessionCreationLimit::new(2, Duration::from_secs(10))));
+
+        let sender = user_id!("@mallory:localhost");
+        let sender_key = Curve25519PublicKey::from([1; 32]);
+
+        // Checking devices which never created a session doesn't keep track of
+        // them.
+        assert!(detector.check(sender, sender_key, 1_000));
+        assert!(detector.senders.lock().is_empty());
+
+        // Sessions exceeding the limit are allowed, but only the latest ones
+        // are kept.
+        for now in 1_000..1_100 {
+            assert!(detector.check(sender, sender_key, now));
+            detector.record(sender_key, now);
+        }
+
+        let senders = detector.senders.lock();
+        let sessions = &senders[&sender_key.to_base64()];
+        assert_eq!(sessions.created_at, [1_098, 1_099]);
+        assert!(sessions.reported);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bookkeeping of the [`CryptoStatistics`] of our account, see
+//! [`Store::statistics()`].
+//!
+//! [`Store::statistics()`]: super::Store::statistics
+
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+};
+
+use matrix_sdk_common::locks::Mutex as StdMutex;
+use ruma::OwnedEventId;
+
+use super::types::CryptoStatistics;
+
+/// The number of room events whose decryption result is remembered, so
+/// retrying their decryption doesn't count them again.
+const MAX_REMEMBERED_EVENTS: usize = 1000;
+
+/// Counts which weren't persisted yet.
+#[derive(Debug, Default)]
+pub(crate) struct PendingStatistics {
+    /// The counts which were added.
+    added: CryptoStatistics,
+    /// The number of room events we failed to decrypt at first, and decrypted
+    /// later on.
+    late_decryptions: u64,
+}
+
+impl PendingStatistics {
+    fn is_empty(&self) -> bool {
+        self.added == CryptoStatistics::default() && self.late_decryptions == 0
+    }
+
+    /// Apply the counts to the given statistics.
+    pub fn apply_to(&self, statistics: &mut CryptoStatistics) {
+        Self::add(statistics, &self.added);
+        statistics.undecryptable_messages =
+            statistics.undecryptable_messages.saturating_sub(self.late_decryptions);
+    }
+
+    fn merge(&mut self, other: PendingStatistics) {
+        Self::add(&mut self.added, &other.added);
+        self.late_decryptions += other.late_decryptions;
+    }
+
+    fn add(statistics: &mut CryptoStatistics, added: &CryptoStatistics) {
+        statistics.olm_sessions_created += added.olm_sessions_created;
+        statistics.megolm_sessions_stored += added.megolm_sessions_stored;
+        statistics.messages_encrypted += added.messages_encrypted;
+        statistics.messages_decrypted += added.messages_decrypted;
+        statistics.undecryptable_messages += added.undecryptable_messages;
+        statistics.keys_backed_up += added.keys_backed_up;
+    }
+}
+
+/// The decryption results of the room events we decrypted lately.
+#[derive(Debug, Default)]
+struct RecentDecryptions {
+    /// Whether the event could be decrypted, by event ID.
+    results: HashMap<OwnedEventId, bool>,
+    /// The event IDs, oldest first.
+    order: VecDeque<OwnedEventId>,
+}
+
+/// Accumulates the changes of the [`CryptoStatistics`] in memory until they
+/// are persisted, see [`StatisticsRecorder::take_pending()`].
+///
+/// Counting an event doesn't touch the store, the counts are written together
+/// with the next batch of changes instead.
+#[derive(Debug, Default)]
+pub(crate) struct StatisticsRecorder {
+    pending: StdMutex<PendingStatistics>,
+    recent_decryptions: StdMutex<RecentDecryptions>,
+}
+
+impl StatisticsRecorder {
+    /// Count something in the statistics.
+    pub fn record(&self, update: impl FnOnce(&mut CryptoStatistics)) {
+        update(&mut self.pending.lock().added);
+    }
+
+    /// Count the result of decrypting the room event with the given ID.
+    ///
+    /// Every event is only counted once. If an event which couldn't be
+    /// decrypted is decrypted later on, e.g. because its room key arrived, it
+    /// moves from the undecryptable to the decrypted messages.
+    pub fn record_decryption(&self, event_id: Option<OwnedEventId>, decrypted: bool) {
+        let previous = event_id.and_then(|event_id| {
+            let mut recent = self.recent_decryptions.lock();
+            let previous = recent.results.insert(event_id.clone(), decrypted);
+
+            if previous.is_none() {
+                recent.order.push_back(event_id);
+
+                if recent.order.len() > MAX_REMEMBERED_EVENTS {
+                    if let Some(oldest) = recent.order.pop_front() {
+                        recent.results.remove(&oldest);
+                    }
+                }
+            }
+
+            previous
+        });
+
+        let mut pending = self.pending.lock();
+
+        match (previous, decrypted) {
+            (None, true) => pending.added.messages_decrypted += 1,
+            (None, false) => pending.added.undecryptable_messages += 1,
+            (Some(false), true) => {
+                pending.added.messages_decrypted += 1;
+                pending.late_decryptions += 1;
+            }
+            // Retries with the same result, and events which can't be
+            // decrypted anymore, e.g. because their room key was deleted,
+            // aren't counted again.
+            (Some(_), _) => {}
+        }
+    }
+
+    /// Apply the counts which weren't persisted yet to the given statistics.
+    pub fn apply_pending(&self, statistics: &mut CryptoStatistics) {
+        self.pending.lock().apply_to(statistics);
+    }
+
+    /// Take the counts which weren't persisted yet, if there are any.
+    ///
+    /// If persisting them fails, they need to be put back using
+    /// [`StatisticsRecorder::restore_pending()`].
+    pub fn take_pending(&self) -> Option<PendingStatistics> {
+        let pending = mem::take(&mut *self.pending.lock());
+        (!pending.is_empty()).then_some(pending)
+    }
+
+    /// Put back the counts taken by [`StatisticsRecorder::take_pending()`],
+    /// because they couldn't be persisted.
+    pub fn restore_pending(&self, pending: PendingStatistics) {
+        self.pending.lock().merge(pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::owned_event_id;
+
+    use super::StatisticsRecorder;
+    use crate::store::types::CryptoStatistics;
+
+    #[test]
+    fn test_record_decryption() {
+        let recorder = StatisticsRecorder::default();
+        let event_id = owned_event_id!("$event:example.org");
+
+        recorder.record_decryption(Some(event_id.clone()), false);
+        recorder.record_decryption(Some(event_id.clone()), false);
+
+        let mut statistics = CryptoStatistics::default();
+        recorder.apply_pending(&mut statistics);
+        assert_eq!(statistics.undecryptable_messages, 1);
+
+        // The undecryptable message was persisted before it was decrypted.
+        let pending = recorder.take_pending().unwrap();
+        let mut persisted = CryptoStatistics::default();
+        pending.apply_to(&mut persisted);
+        assert!(recorder.take_pending().is_none());
+
+        recorder.record_decryption(Some(event_id.clone()), true);
+        recorder.record_decryption(Some(event_id), true);
+
+        // Failing to persist the counts keeps them around.
+        let pending = recorder.take_pending().unwrap();
+        recorder.restore_pending(pending);
+
+        recorder.take_pending().unwrap().apply_to(&mut persisted);
+        assert_eq!(persisted.undecryptable_messages, 0);
+        assert_eq!(persisted.messages_decrypted, 1);
+    }
+}
@@ -17,20 +17,26 @@ use std::{collections::HashMap, fmt, sync::Arc};
 use async_trait::async_trait;
 use matrix_sdk_common::AsyncTraitDeps;
 use ruma::{
-    events::secret::request::SecretName, DeviceId, OwnedDeviceId, RoomId, TransactionId, UserId,
+    events::secret::request::SecretName, DeviceId, EventId, OwnedDeviceId, OwnedEventId,
+    OwnedRoomId, RoomId, SecondsSinceUnixEpoch, TransactionId, UserId,
 };
 use vodozemac::Curve25519PublicKey;
 
+#[cfg(doc)]
+use super::types::VERIFICATION_AUDIT_LOG_CAPACITY;
 use super::{
     types::{
-        BackupKeys, Changes, DehydratedDeviceKey, PendingChanges, RoomKeyCounts, RoomSettings,
-        StoredRoomKeyBundleData, TrackedUser,
+        BackupKeys, ChangeJournalRecord, Changes, CompactionReport, DehydratedDeviceKey,
+        DeletedRoomCryptoState, PendingChanges, QuarantinedEntry, RoomKeyCounts, RoomKeySummary,
+        RoomSettings, SessionShareRecord, ShredReport, StoredRoomKeyBundleData, TrackedUser,
+        VerificationAuditEntry,
     },
     CryptoStoreError, Result,
 };
 #[cfg(doc)]
 use crate::olm::SenderData;
 use crate::{
+    clock::Clock,
     olm::{
         InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
         SenderDataType, Session,
@@ -93,6 +99,23 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `sender_key` - The sender key that was used to establish the sessions.
     async fn get_sessions(&self, sender_key: &str) -> Result<Option<Vec<Session>>, Self::Error>;
 
+    /// Get the sender keys of all the sessions we have stored.
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>, Self::Error>;
+
+    /// Delete the sessions with the given session IDs that belong to the given
+    /// sender key.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender_key` - The sender key that was used to establish the sessions.
+    ///
+    /// * `session_ids` - The IDs of the sessions that should be deleted.
+    async fn delete_sessions(
+        &self,
+        sender_key: &str,
+        session_ids: &[String],
+    ) -> Result<(), Self::Error>;
+
     /// Get the inbound group session from our store.
     ///
     /// # Arguments
@@ -118,9 +141,112 @@ pub trait CryptoStore: AsyncTraitDeps {
         session_id: &str,
     ) -> Result<Option<RoomKeyWithheldEvent>, Self::Error>;
 
+    /// Record that the event with the given ID was encrypted using the given
+    /// message index of a Megolm session.
+    ///
+    /// If an event was already recorded for this message index, the record is
+    /// left untouched and the ID of that event is returned. An event ID which
+    /// differs from the given one means that the message index was reused,
+    /// i.e. that the event is a replay.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room the session belongs to.
+    ///
+    /// * `session_id` - The unique id of the session.
+    ///
+    /// * `message_index` - The message index used to encrypt the event.
+    ///
+    /// * `event_id` - The ID of the event that was decrypted.
+    async fn record_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>, Self::Error>;
+
+    /// Get the records of the given outbound group session being shared with,
+    /// or withheld from, devices, in the order they were saved.
+    ///
+    /// The records are removed together with the inbound group session, see
+    /// [`CryptoStore::delete_inbound_group_sessions()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room the session belongs to.
+    ///
+    /// * `session_id` - The unique id of the session.
+    async fn get_session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>, Self::Error>;
+
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>, Self::Error>;
 
+    /// Move the inbound group sessions which can't be decoded anymore out of
+    /// the way and into the quarantine of the store, so that the remaining
+    /// sessions can be loaded again.
+    ///
+    /// Returns the entries which were quarantined by this call.
+    async fn quarantine_undecodable_inbound_group_sessions(
+        &self,
+    ) -> Result<Vec<QuarantinedEntry>, Self::Error>;
+
+    /// Get all the entries which were moved to the quarantine of the store,
+    /// see [`CryptoStore::quarantine_undecodable_inbound_group_sessions()`].
+    async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>, Self::Error>;
+
+    /// Move all the entries of the quarantine of the store back to the place
+    /// they were found at, for example after upgrading to a version which can
+    /// decode them again.
+    ///
+    /// Returns the number of restored entries.
+    async fn restore_quarantined_entries(&self) -> Result<usize, Self::Error>;
+
+    /// Delete the inbound group sessions with the given session IDs that
+    /// belong to the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room the sessions belong to.
+    ///
+    /// * `session_ids` - The IDs of the sessions that should be deleted.
+    async fn delete_inbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+        session_ids: &[String],
+    ) -> Result<(), Self::Error>;
+
+    /// Get a batch of the inbound group sessions we have stored.
+    ///
+    /// Sessions are not necessarily returned in any specific order, but the
+    /// returned batches are consistent: if this function is called repeatedly
+    /// with `after` set to the room ID and session ID of the last result
+    /// from the previous call, until an empty result is returned, then
+    /// eventually all sessions are returned. (New sessions that are added in
+    /// the course of iteration may or may not be returned.)
+    ///
+    /// This allows callers to go through all the room keys without having to
+    /// hold them in memory at once, see
+    /// [`Store::get_inbound_group_sessions_stream()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - return the sessions after the session with this room ID
+    ///   and session ID, or start at the earliest if this is None.
+    ///
+    /// * `limit` - return a maximum of this many sessions.
+    ///
+    /// [`Store::get_inbound_group_sessions_stream()`]: crate::store::Store::get_inbound_group_sessions_stream
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error>;
+
     /// Get the number inbound group sessions we have and how many of them are
     /// backed up.
     async fn inbound_group_session_counts(
@@ -162,6 +288,32 @@ pub trait CryptoStore: AsyncTraitDeps {
         limit: usize,
     ) -> Result<Vec<InboundGroupSession>, Self::Error>;
 
+    /// Get all the inbound group sessions we have stored for the given room.
+    ///
+    /// Sessions are not returned in any specific order.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - only return sessions belonging to this room.
+    ///
+    /// * `since` - if set, only return sessions which we received at or after
+    ///   this point in time. Sessions for which we don't know when we received
+    ///   them are skipped in that case.
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error>;
+
+    /// Get a summary of the inbound group sessions we have for every room
+    /// which has at least one.
+    ///
+    /// The summaries are returned sorted by room ID.
+    ///
+    /// Implementations should compute the summaries from their indices where
+    /// possible, rather than by loading every session.
+    async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>, Self::Error>;
+
     /// Return a batch of ['InboundGroupSession'] ("room keys") that have not
     /// yet been backed up in the supplied backup version.
     ///
@@ -199,6 +351,10 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get the backup keys we have stored.
     async fn load_backup_keys(&self) -> Result<BackupKeys, Self::Error>;
 
+    /// Deletes the previously stored backup decryption key, the backup
+    /// version is kept.
+    async fn delete_backup_decryption_key(&self) -> Result<(), Self::Error>;
+
     /// Get the dehydrated device pickle key we have stored.
     async fn load_dehydrated_device_pickle_key(
         &self,
@@ -218,6 +374,25 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// whether they are considered dirty/outdated.
     async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>, Self::Error>;
 
+    /// Provide a page of the list of users whose devices we are keeping track
+    /// of, see [`CryptoStore::load_tracked_users()`].
+    ///
+    /// The users are returned in an order defined by the store, which allows
+    /// all the tracked users to be loaded without holding them in memory at
+    /// once.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - return the users after this user, or start at the first
+    ///   one if this is None.
+    ///
+    /// * `limit` - return a maximum of this many users.
+    async fn load_tracked_users_paged(
+        &self,
+        after: Option<&UserId>,
+        limit: usize,
+    ) -> Result<Vec<TrackedUser>, Self::Error>;
+
     /// Update the list of users whose devices we are keeping track of, and
     /// whether they are considered dirty/outdated.
     ///
@@ -263,6 +438,19 @@ pub trait CryptoStore: AsyncTraitDeps {
         user_id: &UserId,
     ) -> Result<Option<UserIdentityData>, Self::Error>;
 
+    /// Get the verification audit log of the given user, oldest entry first.
+    ///
+    /// Only the last [`VERIFICATION_AUDIT_LOG_CAPACITY`] entries of a user
+    /// are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user for which we should get the audit log.
+    async fn get_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>, Self::Error>;
+
     /// Check if a hash for an Olm message stored in the database.
     async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool, Self::Error>;
 
@@ -357,6 +545,28 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `key` - The key to insert data into
     async fn remove_custom_value(&self, key: &str) -> Result<(), Self::Error>;
 
+    /// Get the serialized value of an entry of the application data, see
+    /// [`Store::app_data()`].
+    ///
+    /// Entries are stored and removed using [`CryptoStore::save_changes()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace the entry belongs to.
+    ///
+    /// * `key` - The key of the entry in its namespace.
+    ///
+    /// [`Store::app_data()`]: crate::store::Store::app_data
+    async fn get_app_data(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Get the keys of all the entries of the application data in the given
+    /// namespace, in no particular order.
+    async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>, Self::Error>;
+
     /// Try to take a leased lock.
     ///
     /// This attempts to take a lock for the given lease duration.
@@ -375,8 +585,79 @@ pub trait CryptoStore: AsyncTraitDeps {
         holder: &str,
     ) -> Result<bool, Self::Error>;
 
+    /// Set the source of the current time used by this store, for example to
+    /// decide when leased locks expire, see [`OlmMachine::set_clock()`].
+    ///
+    /// Stores which share their leases with other processes should keep using
+    /// the time of the system, the default implementation ignores the clock.
+    ///
+    /// [`OlmMachine::set_clock()`]: crate::OlmMachine::set_clock
+    fn set_clock(&self, _clock: Arc<dyn Clock>) {}
+
     /// Load the next-batch token for a to-device query, if any.
     async fn next_batch_token(&self) -> Result<Option<String>, Self::Error>;
+
+    /// Reclaim the space taken up by data which was removed from the store.
+    ///
+    /// Stores of long-lived clients can grow large over time, this can be
+    /// called periodically, for example when the application is idle, to give
+    /// the unused space back to the system.
+    ///
+    /// Returns a [`CompactionReport`] describing how much space was reclaimed.
+    async fn compact(&self) -> Result<CompactionReport, Self::Error>;
+
+    /// Load the records of the change journal which were appended after the
+    /// record with the given sequence number, ordered by sequence number.
+    ///
+    /// The change journal is only written to if it has been enabled using
+    /// [`OlmMachine::set_change_journal_enabled()`]. Processes sharing the
+    /// store can remember the sequence number of the last record they have
+    /// seen and reload only the objects which changed since then, instead of
+    /// reloading everything.
+    ///
+    /// [`OlmMachine::set_change_journal_enabled()`]: crate::OlmMachine::set_change_journal_enabled
+    async fn load_change_journal(
+        &self,
+        since: u64,
+    ) -> Result<Vec<ChangeJournalRecord>, Self::Error>;
+
+    /// Remove the records of the change journal up to, and including, the
+    /// record with the given sequence number.
+    ///
+    /// Sequence numbers are never reused, the journal keeps counting from the
+    /// last record that was appended.
+    async fn truncate_change_journal(&self, up_to: u64) -> Result<(), Self::Error>;
+
+    /// Delete the account, the private cross-signing identity, the backup
+    /// keys, the dehydrated device pickle key, the secrets inbox, the custom
+    /// values, and all the Olm and Megolm sessions of the store, together with
+    /// the data derived from them, like the recorded Megolm message indices,
+    /// the received room key bundles, the change journal and the quarantined
+    /// entries.
+    ///
+    /// Everything has to be deleted atomically. Public data, like the devices
+    /// and user identities of other users, is kept. Stores which are able to
+    /// overwrite the deleted data, instead of only unlinking it, should do so
+    /// and report it in the returned [`ShredReport`].
+    async fn shred(&self) -> Result<ShredReport, Self::Error>;
+
+    /// Delete all the crypto state of the given room.
+    ///
+    /// This covers the inbound group sessions of the room and the data
+    /// derived from them, like the recorded Megolm message indices and the
+    /// share history, the outbound group session, the withheld info, the data
+    /// of the received room key bundles, the settings of the room, and the
+    /// custom values with the given keys, which hold the room-scoped data kept
+    /// by the [`Store`].
+    ///
+    /// Everything has to be deleted atomically.
+    ///
+    /// [`Store`]: crate::store::Store
+    async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+        custom_value_keys: &[String],
+    ) -> Result<DeletedRoomCryptoState, Self::Error>;
 }
 
 #[repr(transparent)]
@@ -422,6 +703,14 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_sessions(sender_key).await.map_err(Into::into)
     }
 
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>> {
+        self.0.get_session_sender_keys().await.map_err(Into::into)
+    }
+
+    async fn delete_sessions(&self, sender_key: &str, session_ids: &[String]) -> Result<()> {
+        self.0.delete_sessions(sender_key, session_ids).await.map_err(Into::into)
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -434,6 +723,34 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_inbound_group_sessions().await.map_err(Into::into)
     }
 
+    async fn quarantine_undecodable_inbound_group_sessions(&self) -> Result<Vec<QuarantinedEntry>> {
+        self.0.quarantine_undecodable_inbound_group_sessions().await.map_err(Into::into)
+    }
+
+    async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>> {
+        self.0.get_quarantined_entries().await.map_err(Into::into)
+    }
+
+    async fn restore_quarantined_entries(&self) -> Result<usize> {
+        self.0.restore_quarantined_entries().await.map_err(Into::into)
+    }
+
+    async fn delete_inbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+        session_ids: &[String],
+    ) -> Result<()> {
+        self.0.delete_inbound_group_sessions(room_id, session_ids).await.map_err(Into::into)
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.0.get_inbound_group_sessions_batch(after, limit).await.map_err(Into::into)
+    }
+
     async fn get_inbound_group_sessions_for_device_batch(
         &self,
         curve_key: Curve25519PublicKey,
@@ -458,6 +775,19 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
     ) -> Result<RoomKeyCounts> {
         self.0.inbound_group_session_counts(backup_version).await.map_err(Into::into)
     }
+
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.0.get_inbound_group_sessions_for_room(room_id, since).await.map_err(Into::into)
+    }
+
+    async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>> {
+        self.0.get_room_key_summaries().await.map_err(Into::into)
+    }
+
     async fn inbound_group_sessions_for_backup(
         &self,
         backup_version: &str,
@@ -485,6 +815,10 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.load_backup_keys().await.map_err(Into::into)
     }
 
+    async fn delete_backup_decryption_key(&self) -> Result<(), Self::Error> {
+        self.0.delete_backup_decryption_key().await.map_err(Into::into)
+    }
+
     async fn load_dehydrated_device_pickle_key(&self) -> Result<Option<DehydratedDeviceKey>> {
         self.0.load_dehydrated_device_pickle_key().await.map_err(Into::into)
     }
@@ -504,6 +838,14 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.load_tracked_users().await.map_err(Into::into)
     }
 
+    async fn load_tracked_users_paged(
+        &self,
+        after: Option<&UserId>,
+        limit: usize,
+    ) -> Result<Vec<TrackedUser>> {
+        self.0.load_tracked_users_paged(after, limit).await.map_err(Into::into)
+    }
+
     async fn save_tracked_users(&self, users: &[(&UserId, bool)]) -> Result<()> {
         self.0.save_tracked_users(users).await.map_err(Into::into)
     }
@@ -531,6 +873,13 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_user_identity(user_id).await.map_err(Into::into)
     }
 
+    async fn get_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>> {
+        self.0.get_verification_audit_log(user_id).await.map_err(Into::into)
+    }
+
     async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
         self.0.is_message_known(message_hash).await.map_err(Into::into)
     }
@@ -576,6 +925,27 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_withheld_info(room_id, session_id).await.map_err(Into::into)
     }
 
+    async fn record_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>, Self::Error> {
+        self.0
+            .record_megolm_message_index(room_id, session_id, message_index, event_id)
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn get_session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>, Self::Error> {
+        self.0.get_session_share_history(room_id, session_id).await.map_err(Into::into)
+    }
+
     async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
         self.0.get_room_settings(room_id).await.map_err(Into::into)
     }
@@ -600,6 +970,18 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.remove_custom_value(key).await.map_err(Into::into)
     }
 
+    async fn get_app_data(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.0.get_app_data(namespace, key).await.map_err(Into::into)
+    }
+
+    async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>, Self::Error> {
+        self.0.get_app_data_keys(namespace).await.map_err(Into::into)
+    }
+
     async fn try_take_leased_lock(
         &self,
         lease_duration_ms: u32,
@@ -609,9 +991,40 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.try_take_leased_lock(lease_duration_ms, key, holder).await.map_err(Into::into)
     }
 
+    fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.0.set_clock(clock)
+    }
+
     async fn next_batch_token(&self) -> Result<Option<String>, Self::Error> {
         self.0.next_batch_token().await.map_err(Into::into)
     }
+
+    async fn compact(&self) -> Result<CompactionReport, Self::Error> {
+        self.0.compact().await.map_err(Into::into)
+    }
+
+    async fn load_change_journal(
+        &self,
+        since: u64,
+    ) -> Result<Vec<ChangeJournalRecord>, Self::Error> {
+        self.0.load_change_journal(since).await.map_err(Into::into)
+    }
+
+    async fn truncate_change_journal(&self, up_to: u64) -> Result<(), Self::Error> {
+        self.0.truncate_change_journal(up_to).await.map_err(Into::into)
+    }
+
+    async fn shred(&self) -> Result<ShredReport, Self::Error> {
+        self.0.shred().await.map_err(Into::into)
+    }
+
+    async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+        custom_value_keys: &[String],
+    ) -> Result<DeletedRoomCryptoState, Self::Error> {
+        self.0.delete_room_crypto_state(room_id, custom_value_keys).await.map_err(Into::into)
+    }
 }
 
 /// A type-erased [`CryptoStore`].
@@ -18,26 +18,32 @@
 //! represent objects that are persisted in the database.
 
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     time::Duration,
 };
 
-use ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId};
+use itertools::Itertools;
+use ruma::{
+    MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedRoomId, OwnedUserId, SecondsSinceUnixEpoch,
+    UserId,
+};
 use serde::{Deserialize, Serialize};
-use vodozemac::{base64_encode, Curve25519PublicKey};
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use vodozemac::{base64_decode, base64_encode, Curve25519PublicKey, Ed25519PublicKey};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 use super::{DehydrationError, GossipRequest};
 use crate::{
     olm::{
-        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        SenderData,
+        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PickledAccount,
+        PickledCrossSigningIdentity, PickledInboundGroupSession, PickledSession,
+        PrivateCrossSigningIdentity, SenderData, ShareInfo,
     },
     types::{
         events::{room_key_bundle::RoomKeyBundleContent, room_key_withheld::RoomKeyWithheldEvent},
-        EventEncryptionAlgorithm,
+        EventEncryptionAlgorithm, MasterPubkey, SecretBytes, SecretString,
     },
-    Account, Device, DeviceData, GossippedSecret, Session, UserIdentity, UserIdentityData,
+    Account, CollectStrategy, Device, DeviceData, EncryptionSettings, GossippedSecret,
+    OtherUserIdentity, Session, UserIdentity, UserIdentityData,
 };
 
 /// Aggregated changes to be saved in the database.
@@ -83,6 +89,37 @@ pub struct Changes {
     /// Historical room key history bundles that we have received and should
     /// store.
     pub received_room_key_bundles: Vec<StoredRoomKeyBundleData>,
+
+    /// Entries which should be appended to the change journal, see
+    /// [`CryptoStore::load_change_journal()`].
+    ///
+    /// [`CryptoStore::load_change_journal()`]: crate::store::CryptoStore::load_change_journal
+    pub change_journal: Vec<ChangeJournalEntry>,
+
+    /// Records of our outbound group sessions being shared with, or withheld
+    /// from, devices, which should be appended to the share history, see
+    /// [`CryptoStore::get_session_share_history()`].
+    ///
+    /// [`CryptoStore::get_session_share_history()`]: crate::store::CryptoStore::get_session_share_history
+    pub session_share_records: Vec<SessionShareRecord>,
+
+    /// Entries which should be appended to the verification audit logs of
+    /// their users, see [`CryptoStore::get_verification_audit_log()`].
+    ///
+    /// [`CryptoStore::get_verification_audit_log()`]: crate::store::CryptoStore::get_verification_audit_log
+    pub verification_audit_log: Vec<VerificationAuditEntry>,
+
+    /// Entries of the application data which should be stored or removed, see
+    /// [`Store::app_data()`].
+    ///
+    /// [`Store::app_data()`]: crate::store::Store::app_data
+    pub app_data: Vec<AppDataChange>,
+
+    /// The backup version the `inbound_group_sessions` were backed up to, if
+    /// they were, see [`CryptoStore::save_inbound_group_sessions()`].
+    ///
+    /// [`CryptoStore::save_inbound_group_sessions()`]: crate::store::CryptoStore::save_inbound_group_sessions
+    pub inbound_group_sessions_backed_up_to: Option<String>,
 }
 
 /// Information about an [MSC4268] room key bundle.
@@ -99,6 +136,14 @@ pub struct StoredRoomKeyBundleData {
 
     /// The room key bundle data itself.
     pub bundle_data: RoomKeyBundleContent,
+
+    /// If `bundle_data` is a chunk of a bundle which was split into multiple
+    /// chunks, the other chunks of the bundle which were received so far,
+    /// ordered by their index.
+    ///
+    /// They were all sent by the device `sender_data` describes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub received_chunks: Vec<RoomKeyBundleContent>,
 }
 
 /// A user for which we are tracking the list of devices.
@@ -113,6 +158,137 @@ pub struct TrackedUser {
     pub dirty: bool,
 }
 
+/// The full crypto state of a device, as exported by
+/// [`Store::export_full_state()`].
+///
+/// The export can be imported into another store using
+/// [`Store::import_full_state()`], for example to move from one store backend
+/// to another.
+///
+/// **Warning**: The export contains the private keys of the device in
+/// plaintext, it should be encrypted before it's written anywhere.
+///
+/// [`Store::export_full_state()`]: super::Store::export_full_state
+/// [`Store::import_full_state()`]: super::Store::import_full_state
+#[derive(Serialize, Deserialize)]
+pub struct FullStateExport {
+    /// The pickled Olm account of the device.
+    pub account: PickledAccount,
+    /// The pickled private cross-signing keys, if we have any.
+    pub private_identity: Option<PickledCrossSigningIdentity>,
+    /// The pickled Olm sessions.
+    pub sessions: Vec<PickledSession>,
+    /// The pickled Megolm sessions which we use to decrypt room events.
+    pub inbound_group_sessions: Vec<PickledInboundGroupSession>,
+    /// The users whose device lists we are tracking.
+    pub tracked_users: Vec<TrackedUser>,
+    /// The key used to decrypt backed up room keys, if we have one.
+    pub backup_decryption_key: Option<BackupDecryptionKey>,
+    /// The version of the backup the backup decryption key belongs to.
+    pub backup_version: Option<String>,
+    /// The algorithm of the backup the backup decryption key belongs to, if
+    /// we know it.
+    pub backup_algorithm: Option<String>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl std::fmt::Debug for FullStateExport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FullStateExport")
+            .field("sessions", &self.sessions.len())
+            .field("inbound_group_sessions", &self.inbound_group_sessions.len())
+            .field("tracked_users", &self.tracked_users)
+            .field("backup_version", &self.backup_version)
+            .field("backup_algorithm", &self.backup_algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A summary of the device list of a user, as returned by
+/// [`Store::device_list_snapshot()`].
+///
+/// The snapshot can be persisted and compared against the current device list
+/// later on, using [`Store::diff_since()`].
+///
+/// [`Store::device_list_snapshot()`]: super::Store::device_list_snapshot
+/// [`Store::diff_since()`]: super::Store::diff_since
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceListSnapshot {
+    /// The user the devices belong to.
+    pub user_id: OwnedUserId,
+    /// The devices of the user, at the time the snapshot was taken.
+    pub devices: BTreeMap<OwnedDeviceId, DeviceSnapshot>,
+}
+
+/// The state of a single device in a [`DeviceListSnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceSnapshot {
+    /// The Ed25519 key of the device, encoded as base64.
+    pub ed25519_key: Option<String>,
+    /// Whether the device was verified.
+    pub verified: bool,
+}
+
+impl DeviceListSnapshot {
+    /// Compare this snapshot with a newer snapshot of the same user.
+    pub fn diff(&self, current: DeviceListSnapshot) -> DeviceListDiff {
+        let added = current
+            .devices
+            .keys()
+            .filter(|device_id| !self.devices.contains_key(*device_id))
+            .cloned()
+            .collect();
+
+        let removed = self
+            .devices
+            .keys()
+            .filter(|device_id| !current.devices.contains_key(*device_id))
+            .cloned()
+            .collect();
+
+        let changed = current
+            .devices
+            .iter()
+            .filter(|(device_id, device)| {
+                self.devices.get(*device_id).is_some_and(|previous| previous != *device)
+            })
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+
+        DeviceListDiff { added, removed, changed, snapshot: current }
+    }
+}
+
+/// The changes of a device list since a [`DeviceListSnapshot`] was taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceListDiff {
+    /// The devices that were added since the snapshot was taken.
+    pub added: BTreeSet<OwnedDeviceId>,
+    /// The devices that were removed since the snapshot was taken.
+    pub removed: BTreeSet<OwnedDeviceId>,
+    /// The devices whose keys or verification state changed since the
+    /// snapshot was taken.
+    pub changed: BTreeSet<OwnedDeviceId>,
+    /// A snapshot of the current device list, which should replace the old
+    /// one.
+    pub snapshot: DeviceListSnapshot,
+}
+
+impl DeviceListDiff {
+    /// Did the device list stay the same since the snapshot was taken?
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Get the devices that were added since the snapshot was taken and which
+    /// aren't verified.
+    pub fn added_unverified(&self) -> impl Iterator<Item = &OwnedDeviceId> {
+        self.added.iter().filter(|device_id| {
+            self.snapshot.devices.get(*device_id).is_some_and(|device| !device.verified)
+        })
+    }
+}
+
 impl Changes {
     /// Are there any changes stored or is this an empty `Changes` struct?
     pub fn is_empty(&self) -> bool {
@@ -132,7 +308,100 @@ impl Changes {
             && self.secrets.is_empty()
             && self.next_batch_token.is_none()
             && self.received_room_key_bundles.is_empty()
+            && self.change_journal.is_empty()
+            && self.session_share_records.is_empty()
+            && self.verification_audit_log.is_empty()
+            && self.app_data.is_empty()
     }
+
+    /// Get the change journal entries describing the sessions, devices and
+    /// identities which are saved by these changes.
+    pub(crate) fn change_journal_entries(&self) -> Vec<ChangeJournalEntry> {
+        let sessions = self.sessions.iter().map(|session| ChangeJournalEntry::Session {
+            sender_key: session.sender_key().to_base64(),
+            session_id: session.session_id().to_owned(),
+        });
+
+        let inbound_group_sessions = self.inbound_group_sessions.iter().map(Into::into);
+
+        let devices = [&self.devices.new, &self.devices.changed, &self.devices.deleted]
+            .into_iter()
+            .flatten()
+            .map(|device| ChangeJournalEntry::Device {
+                user_id: device.user_id().to_owned(),
+                device_id: device.device_id().to_owned(),
+            });
+
+        let identities =
+            self.identities.new.iter().chain(&self.identities.changed).map(|identity| {
+                ChangeJournalEntry::Identity { user_id: identity.user_id().to_owned() }
+            });
+
+        sessions.chain(inbound_group_sessions).chain(devices).chain(identities).collect()
+    }
+}
+
+/// An entry of the change journal, describing an object which was saved in
+/// the crypto store.
+///
+/// The entries only identify the objects, processes replaying the journal are
+/// expected to reload the objects from the store.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangeJournalEntry {
+    /// An Olm session was saved.
+    Session {
+        /// The Curve25519 key of the device the session was established with,
+        /// encoded as base64.
+        sender_key: String,
+        /// The ID of the session.
+        session_id: String,
+    },
+
+    /// An inbound group session was saved.
+    InboundGroupSession {
+        /// The room the session is used in.
+        room_id: OwnedRoomId,
+        /// The ID of the session.
+        session_id: String,
+    },
+
+    /// A device was saved or deleted.
+    Device {
+        /// The user the device belongs to.
+        user_id: OwnedUserId,
+        /// The ID of the device.
+        device_id: OwnedDeviceId,
+    },
+
+    /// A user identity was saved.
+    Identity {
+        /// The user the identity belongs to.
+        user_id: OwnedUserId,
+    },
+}
+
+impl From<&InboundGroupSession> for ChangeJournalEntry {
+    fn from(session: &InboundGroupSession) -> Self {
+        Self::InboundGroupSession {
+            room_id: session.room_id().to_owned(),
+            session_id: session.session_id().to_owned(),
+        }
+    }
+}
+
+/// A record of the change journal, as returned by
+/// [`CryptoStore::load_change_journal()`].
+///
+/// [`CryptoStore::load_change_journal()`]: crate::store::CryptoStore::load_change_journal
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeJournalRecord {
+    /// The sequence number of the record, sequence numbers are strictly
+    /// increasing in the order the records were appended to the journal.
+    pub sequence_number: u64,
+
+    /// The change this record describes.
+    pub entry: ChangeJournalEntry,
 }
 
 /// This struct is used to remember whether an identity has undergone a change
@@ -154,6 +423,14 @@ pub struct IdentityChanges {
 }
 
 impl IdentityChanges {
+    /// Merge the given `IdentityChanges` into this instance of
+    /// `IdentityChanges`.
+    pub fn extend(&mut self, other: IdentityChanges) {
+        self.new.extend(other.new);
+        self.changed.extend(other.changed);
+        self.unchanged.extend(other.unchanged);
+    }
+
     pub(super) fn is_empty(&self) -> bool {
         self.new.is_empty() && self.changed.is_empty()
     }
@@ -211,6 +488,34 @@ pub struct DeviceUpdates {
     pub changed: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, Device>>,
 }
 
+/// A change of one of our own devices, as reported by
+/// [`Store::own_devices_stream()`].
+///
+/// [`Store::own_devices_stream()`]: crate::store::Store::own_devices_stream
+#[derive(Clone, Debug)]
+pub enum OwnDeviceChange {
+    /// A device was added to our account, for example because somebody logged
+    /// in.
+    Added(Device),
+    /// A device was removed from our account.
+    Deleted(DeviceData),
+    /// The display name of a device changed.
+    DisplayNameChanged {
+        /// The device, with its new display name.
+        device: Device,
+        /// The display name the device had before.
+        previous: Option<String>,
+    },
+    /// Whether the device is trusted changed, either because it got
+    /// (un)verified or because its local trust state changed.
+    TrustChanged {
+        /// The device, in its new trust state.
+        device: Device,
+        /// Whether the device was considered verified before.
+        previously_verified: bool,
+    },
+}
+
 /// Updates about [`UserIdentity`]s which got received over the `/keys/query`
 /// endpoint.
 #[derive(Clone, Debug, Default)]
@@ -227,6 +532,27 @@ pub struct IdentityUpdates {
     pub unchanged: BTreeMap<OwnedUserId, UserIdentity>,
 }
 
+/// A user identity which changed in a way that should be shown to the user,
+/// e.g. as an "identity changed" warning, as reported by
+/// [`Store::pin_violations_stream()`].
+///
+/// [`Store::pin_violations_stream()`]: super::Store::pin_violations_stream
+#[derive(Clone, Debug)]
+pub struct PinViolation {
+    /// The identity of the user, after it changed.
+    ///
+    /// The violation can be resolved by pinning the new identity, using
+    /// [`OtherUserIdentity::pin_current_master_key()`], by withdrawing the
+    /// verification of the user, using
+    /// [`OtherUserIdentity::withdraw_verification()`], or by verifying the new
+    /// identity.
+    pub identity: OtherUserIdentity,
+
+    /// Whether the user was verified before their identity changed, see
+    /// [`OtherUserIdentity::has_verification_violation()`].
+    pub verification_violation: bool,
+}
+
 /// The private part of a backup key.
 ///
 /// The private part of the key is not used on a regular basis. Rather, it is
@@ -318,6 +644,13 @@ impl DehydratedDeviceKey {
     pub fn to_base64(&self) -> String {
         base64_encode(self.inner.as_slice())
     }
+
+    /// Restore a [`DehydratedDeviceKey`] from a base64 encoded string, as
+    /// exported by [`DehydratedDeviceKey::to_base64()`].
+    pub fn from_base64(key: &str) -> Result<Self, DehydrationError> {
+        let decoded = Zeroizing::new(base64_decode(key)?);
+        Self::from_slice(&decoded)
+    }
 }
 
 impl From<&[u8; 32]> for DehydratedDeviceKey {
@@ -362,6 +695,93 @@ pub struct RoomKeyCounts {
     pub backed_up: usize,
 }
 
+/// Struct holding info about the room keys the store has for a single room,
+/// see [`Store::rooms_with_keys()`].
+///
+/// [`Store::rooms_with_keys()`]: crate::store::Store::rooms_with_keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomKeySummary {
+    /// The room the room keys belong to.
+    pub room_id: OwnedRoomId,
+    /// The number of room keys the store has for the room.
+    pub session_count: usize,
+    /// When we received the oldest room key of the room, if we know when we
+    /// received any of them.
+    ///
+    /// Room keys which were stored before we started to record when we
+    /// received them are counted, but don't contribute to the timestamps.
+    pub earliest_received_at: Option<SecondsSinceUnixEpoch>,
+    /// When we received the newest room key of the room, if we know when we
+    /// received any of them.
+    pub latest_received_at: Option<SecondsSinceUnixEpoch>,
+}
+
+impl RoomKeySummary {
+    /// Create a summary for a room, from the timestamps of its room keys.
+    ///
+    /// Used by the store implementations which have to look at every room
+    /// key.
+    pub fn from_received_at(
+        room_id: OwnedRoomId,
+        received_at: impl IntoIterator<Item = Option<SecondsSinceUnixEpoch>>,
+    ) -> Self {
+        let mut summary = Self {
+            room_id,
+            session_count: 0,
+            earliest_received_at: None,
+            latest_received_at: None,
+        };
+
+        for received_at in received_at {
+            summary.session_count += 1;
+
+            if let Some(received_at) = received_at {
+                summary.earliest_received_at =
+                    Some(summary.earliest_received_at.map_or(received_at, |e| e.min(received_at)));
+                summary.latest_received_at =
+                    Some(summary.latest_received_at.map_or(received_at, |l| l.max(received_at)));
+            }
+        }
+
+        summary
+    }
+}
+
+/// Counters describing the end-to-end encryption activity of an account, see
+/// [`OlmMachine::statistics()`].
+///
+/// The counters are persisted in the store, so they keep growing for as long
+/// as the account is used, across restarts of the application. They are
+/// written together with the next changes which are saved, so the latest
+/// counts can get lost if the application exits before that.
+///
+/// [`OlmMachine::statistics()`]: crate::OlmMachine::statistics
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CryptoStatistics {
+    /// The number of Olm sessions we created, either to send a to-device
+    /// message to a device or because a device sent us a pre-key message.
+    pub olm_sessions_created: u64,
+    /// The number of new room keys we stored, either because we created them,
+    /// received them from another device or imported them.
+    pub megolm_sessions_stored: u64,
+    /// The number of room events we encrypted.
+    pub messages_encrypted: u64,
+    /// The number of room events we successfully decrypted.
+    ///
+    /// Decrypting an event which was decrypted recently again, e.g. when it's
+    /// displayed again, doesn't count it twice.
+    pub messages_decrypted: u64,
+    /// The number of room events we failed to decrypt.
+    ///
+    /// Events we failed to decrypt at first, and decrypted once their room
+    /// key arrived, count as decrypted instead.
+    pub undecryptable_messages: u64,
+    /// The number of room keys which were uploaded to the server-side key
+    /// backup.
+    pub keys_backed_up: u64,
+}
+
 /// Stored versions of the backup keys.
 #[derive(Default, Clone, Debug)]
 pub struct BackupKeys {
@@ -376,11 +796,11 @@ pub struct BackupKeys {
 #[derive(Default, Zeroize, ZeroizeOnDrop)]
 pub struct CrossSigningKeyExport {
     /// The seed of the master key encoded as unpadded base64.
-    pub master_key: Option<String>,
+    pub master_key: Option<SecretString>,
     /// The seed of the self signing key encoded as unpadded base64.
-    pub self_signing_key: Option<String>,
+    pub self_signing_key: Option<SecretString>,
     /// The seed of the user signing key encoded as unpadded base64.
-    pub user_signing_key: Option<String>,
+    pub user_signing_key: Option<SecretString>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -405,6 +825,24 @@ pub(crate) enum UserKeyQueryResult {
     TimeoutExpired,
 }
 
+/// The priority with which the device list of a user is queried, see
+/// [`OlmMachine::mark_user_as_changed_with_priority()`].
+///
+/// [`OlmMachine::mark_user_as_changed_with_priority()`]: crate::OlmMachine::mark_user_as_changed_with_priority
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KeyQueryPriority {
+    /// The devices of the user aren't needed any time soon, e.g. because we
+    /// only share rooms the user isn't looking at.
+    #[default]
+    Background,
+
+    /// The user is a member of a room which is currently visible.
+    VisibleRoom,
+
+    /// A message can't be sent out before the devices of the user are known.
+    BlockingSend,
+}
+
 /// Room encryption settings which are modified by state events or user options
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct RoomSettings {
@@ -413,6 +851,11 @@ pub struct RoomSettings {
 
     /// Should untrusted devices receive the room key, or should they be
     /// excluded from the conversation.
+    ///
+    /// Can be changed at any time using
+    /// [`OlmMachine::set_room_only_allow_trusted_devices()`].
+    ///
+    /// [`OlmMachine::set_room_only_allow_trusted_devices()`]: crate::OlmMachine::set_room_only_allow_trusted_devices
     pub only_allow_trusted_devices: bool,
 
     /// The maximum time an encryption session should be used for, before it is
@@ -422,6 +865,17 @@ pub struct RoomSettings {
     /// The maximum number of messages an encryption session should be used for,
     /// before it is rotated.
     pub session_rotation_period_messages: Option<usize>,
+
+    /// The [`CollectStrategy`] which should be used to share room keys in the
+    /// room, instead of the one of the [`EncryptionSettings`].
+    ///
+    /// Unlike the rest of the settings, this isn't part of the
+    /// `m.room.encryption` state event, it can be changed at any time using
+    /// [`OlmMachine::set_room_sharing_strategy()`].
+    ///
+    /// [`OlmMachine::set_room_sharing_strategy()`]: crate::OlmMachine::set_room_sharing_strategy
+    #[serde(default)]
+    pub sharing_strategy: Option<CollectStrategy>,
 }
 
 impl Default for RoomSettings {
@@ -431,7 +885,626 @@ impl Default for RoomSettings {
             only_allow_trusted_devices: false,
             session_rotation_period: None,
             session_rotation_period_messages: None,
+            sharing_strategy: None,
+        }
+    }
+}
+
+/// Per-room overrides of the criteria deciding when an outbound group session
+/// gets rotated, configured using [`OlmMachine::set_room_rotation_policy()`].
+///
+/// Unlike the rotation periods in [`RoomSettings`], which follow the
+/// `m.room.encryption` state event, these can be changed at any time. Fields
+/// which are `None` fall back to the values of the [`EncryptionSettings`]
+/// passed to [`OlmMachine::share_room_key()`].
+///
+/// [`OlmMachine::set_room_rotation_policy()`]: crate::OlmMachine::set_room_rotation_policy
+/// [`OlmMachine::share_room_key()`]: crate::OlmMachine::share_room_key
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RoomRotationPolicy {
+    /// How long an outbound group session should be used before rotating it.
+    pub rotation_period: Option<Duration>,
+
+    /// How many messages should be encrypted with an outbound group session
+    /// before rotating it.
+    pub rotation_period_msgs: Option<u64>,
+}
+
+impl RoomRotationPolicy {
+    /// Override the rotation criteria of the given [`EncryptionSettings`] with
+    /// the ones of this policy.
+    pub(crate) fn apply(&self, settings: &mut EncryptionSettings) {
+        if let Some(rotation_period) = self.rotation_period {
+            settings.rotation_period = rotation_period;
         }
+
+        if let Some(rotation_period_msgs) = self.rotation_period_msgs {
+            settings.rotation_period_msgs = rotation_period_msgs;
+        }
+    }
+}
+
+/// Policy deciding when our fallback key is rotated, configured using
+/// [`OlmMachine::set_fallback_key_rotation_policy()`].
+///
+/// The policy is persisted together with our account. A fallback key is
+/// rotated when either of the criteria is met, the new fallback key is then
+/// uploaded by the next keys upload request.
+///
+/// [`OlmMachine::set_fallback_key_rotation_policy()`]: crate::OlmMachine::set_fallback_key_rotation_policy
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FallbackKeyRotationPolicy {
+    /// How long a fallback key should be used before rotating it.
+    pub max_age: Duration,
+
+    /// How many Olm sessions other devices may create using a fallback key
+    /// before rotating it, `None` if the number of uses doesn't matter.
+    pub max_uses: Option<u64>,
+}
+
+impl Default for FallbackKeyRotationPolicy {
+    /// The fallback key is rotated once it's older than a week. This is the
+    /// lower bound for the recommended signed pre-key bundle rotation interval
+    /// in the X3DH spec[1].
+    ///
+    /// [1]: https://signal.org/docs/specifications/x3dh/#publishing-keys
+    fn default() -> Self {
+        Self { max_age: Duration::from_secs(3600 * 24 * 7), max_uses: None }
+    }
+}
+
+/// A record of an outbound group session being shared with, or withheld from,
+/// a single device, see [`Store::session_share_history()`].
+///
+/// [`Store::session_share_history()`]: crate::store::Store::session_share_history
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionShareRecord {
+    /// The room the session belongs to.
+    pub room_id: OwnedRoomId,
+
+    /// The ID of the session.
+    pub session_id: String,
+
+    /// The user owning the device.
+    pub user_id: OwnedUserId,
+
+    /// The device the room key was shared with, or withheld from.
+    pub device_id: OwnedDeviceId,
+
+    /// The message index from which on the device is able to decrypt
+    /// messages, or the reason why the room key was withheld.
+    pub share_info: ShareInfo,
+
+    /// When the to-device message carrying the room key, or the withheld code,
+    /// was sent out.
+    pub shared_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// Why the verification of a user identity was withdrawn, see
+/// [`UserIdentity::withdraw_verification_with_reason()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum VerificationWithdrawalReason {
+    /// The identity of the user changed, and the change was accepted instead
+    /// of verifying the new identity.
+    KeyChange,
+    /// The local user decided to withdraw the verification.
+    Manual,
+    /// A policy of the application required the verification to be
+    /// withdrawn.
+    Policy,
+}
+
+/// The number of entries the verification audit log keeps per user, see
+/// [`CryptoStore::get_verification_audit_log()`].
+///
+/// Once a log is full, its oldest entries are dropped to make room for new
+/// ones.
+///
+/// [`CryptoStore::get_verification_audit_log()`]: crate::store::CryptoStore::get_verification_audit_log
+pub const VERIFICATION_AUDIT_LOG_CAPACITY: usize = 100;
+
+/// What happened to the verification of a user identity, see
+/// [`VerificationAuditEntry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub enum VerificationAuditEvent {
+    /// The identity became verified.
+    ///
+    /// For our own identity, this means that we verified it. The identity of
+    /// another user is verified once our own verified identity signed it.
+    Verified,
+    /// The verification of a previously verified identity was withdrawn, see
+    /// [`UserIdentity::withdraw_verification_with_reason()`].
+    VerificationWithdrawn(VerificationWithdrawalReason),
+}
+
+/// An entry of the verification audit log of a user, see
+/// [`Store::verification_audit_log()`].
+///
+/// [`Store::verification_audit_log()`]: crate::store::Store::verification_audit_log
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerificationAuditEntry {
+    /// The user the identity belongs to.
+    pub user_id: OwnedUserId,
+
+    /// The master key of the identity at the time of the event.
+    pub master_key: Option<Ed25519PublicKey>,
+
+    /// What happened to the verification of the identity.
+    pub event: VerificationAuditEvent,
+
+    /// When it happened.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+}
+
+impl VerificationAuditEntry {
+    pub(crate) fn new(
+        master_key: &MasterPubkey,
+        event: VerificationAuditEvent,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) -> Self {
+        Self {
+            user_id: master_key.user_id().to_owned(),
+            master_key: master_key.get_first_key(),
+            event,
+            timestamp,
+        }
+    }
+}
+
+/// A change of an entry of the application data, see [`Store::app_data()`].
+///
+/// [`Store::app_data()`]: crate::store::Store::app_data
+#[derive(Clone, Debug)]
+pub struct AppDataChange {
+    /// The namespace the entry belongs to.
+    pub namespace: String,
+
+    /// The key of the entry in its namespace.
+    pub key: String,
+
+    /// The serialized value of the entry, or `None` if the entry should be
+    /// removed.
+    pub value: Option<Vec<u8>>,
+}
+
+/// The outcome of compacting a crypto store, see [`CryptoStore::compact()`].
+///
+/// [`CryptoStore::compact()`]: crate::store::CryptoStore::compact
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// The size of the store in bytes before it was compacted, `None` if the
+    /// store can't measure its size.
+    pub size_before: Option<u64>,
+
+    /// The size of the store in bytes after it was compacted, `None` if the
+    /// store can't measure its size.
+    pub size_after: Option<u64>,
+}
+
+/// The outcome of shredding a crypto store, see [`CryptoStore::shred()`].
+///
+/// [`CryptoStore::shred()`]: crate::store::CryptoStore::shred
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShredReport {
+    /// Whether an account was stored, and got deleted.
+    pub account_deleted: bool,
+
+    /// The number of Olm sessions which were deleted.
+    pub sessions_deleted: usize,
+
+    /// The number of inbound group sessions which were deleted.
+    pub inbound_group_sessions_deleted: usize,
+
+    /// Whether the store overwrote the deleted data before releasing it,
+    /// instead of only unlinking it.
+    pub secure_delete: bool,
+}
+
+/// The crypto data of a room which was deleted, see
+/// [`Store::delete_room_crypto_state()`].
+///
+/// [`Store::delete_room_crypto_state()`]: crate::store::Store::delete_room_crypto_state
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeletedRoomCryptoState {
+    /// The room keys, i.e. inbound group sessions, which were deleted.
+    pub room_keys: Vec<RoomKeyInfo>,
+
+    /// Whether an outbound group session was stored for the room, and got
+    /// deleted.
+    pub outbound_group_session_deleted: bool,
+
+    /// The number of `m.room_key.withheld` messages which were deleted.
+    pub withheld_deleted: usize,
+
+    /// The senders of the room key bundles whose data was deleted.
+    pub room_key_bundle_senders: Vec<OwnedUserId>,
+}
+
+/// A mismatch between the keys of a user returned by the homeserver and the
+/// ones known by a second source, as reported by a [`KeyQueryValidator`].
+///
+/// [`KeyQueryValidator`]: crate::KeyQueryValidator
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityDispute {
+    /// Whether the master key of the user didn't match.
+    pub master_key: bool,
+
+    /// The devices whose keys didn't match.
+    pub devices: BTreeSet<OwnedDeviceId>,
+
+    /// The description of the mismatch given by the validator.
+    pub reason: String,
+
+    /// When the keys were last disputed.
+    pub disputed_at: MilliSecondsSinceUnixEpoch,
+}
+
+impl CompactionReport {
+    /// The number of bytes which were reclaimed by the compaction, `None` if
+    /// the store can't measure its size.
+    pub fn reclaimed(&self) -> Option<u64> {
+        Some(self.size_before?.saturating_sub(self.size_after?))
+    }
+}
+
+/// An entry of the store which couldn't be decoded anymore, for example
+/// because it got corrupted, and which was moved out of the way so the rest of
+/// the store remains usable, see [`Store::quarantined_entries()`].
+///
+/// [`Store::quarantined_entries()`]: crate::store::Store::quarantined_entries
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QuarantinedEntry {
+    /// The kind of object the entry was supposed to contain.
+    pub kind: QuarantinedEntryKind,
+
+    /// The value of the entry, as it was found in the store.
+    ///
+    /// If the store encrypts its values, this is the encrypted value.
+    pub value: SecretBytes,
+
+    /// A description of the error we got while decoding the entry.
+    pub error: String,
+}
+
+/// The kind of object a [`QuarantinedEntry`] was supposed to contain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub enum QuarantinedEntryKind {
+    /// An [`InboundGroupSession`].
+    InboundGroupSession,
+}
+
+/// Policy deciding which Olm sessions are removed by
+/// [`Store::prune_sessions()`], configured using
+/// [`OlmMachine::set_session_pruning_policy()`].
+///
+/// Olm sessions are never removed by default. The most recently used session
+/// for a given sender key is always kept, no matter the policy, since that's
+/// the one we're going to use to encrypt messages for the device.
+///
+/// [`Store::prune_sessions()`]: crate::store::Store::prune_sessions
+/// [`OlmMachine::set_session_pruning_policy()`]: crate::OlmMachine::set_session_pruning_policy
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionPruningPolicy {
+    /// The maximum number of sessions we keep for a given sender key. The
+    /// least recently used sessions are removed first.
+    pub max_sessions_per_sender_key: Option<usize>,
+
+    /// Sessions which haven't been used for longer than this are removed.
+    pub max_idle_age: Option<Duration>,
+}
+
+impl SessionPruningPolicy {
+    /// Does this policy never remove any sessions?
+    pub fn is_disabled(&self) -> bool {
+        self.max_sessions_per_sender_key.is_none() && self.max_idle_age.is_none()
+    }
+
+    /// Get the IDs of the sessions, out of the given sessions belonging to a
+    /// single sender key, that should be removed according to this policy.
+    pub(crate) fn sessions_to_prune(
+        &self,
+        sessions: &[Session],
+        now: SecondsSinceUnixEpoch,
+    ) -> Vec<String> {
+        let now = u64::from(now.get());
+        let max_sessions = self.max_sessions_per_sender_key.unwrap_or(usize::MAX).max(1);
+
+        sessions
+            .iter()
+            .sorted_by_key(|s| std::cmp::Reverse(s.last_use_time))
+            .enumerate()
+            .filter(|(position, session)| {
+                // Never remove the most recently used session.
+                if *position == 0 {
+                    return false;
+                }
+
+                let idle_for = now.saturating_sub(session.last_use_time.get().into());
+                let too_old = self.max_idle_age.is_some_and(|max_age| idle_for > max_age.as_secs());
+
+                *position >= max_sessions || too_old
+            })
+            .map(|(_, session)| session.session_id().to_owned())
+            .collect()
+    }
+}
+
+/// Options deciding which room keys are put into a room key bundle by
+/// [`Store::build_room_key_bundle()`].
+///
+/// The default options put all the room keys we have for the room into the
+/// bundle.
+///
+/// [`Store::build_room_key_bundle()`]: crate::store::Store::build_room_key_bundle
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomKeyBundleOptions {
+    /// Only include the room keys which we received at or after this point in
+    /// time.
+    ///
+    /// Room keys for which we don't know when we received them are left out
+    /// if this is set.
+    pub since: Option<SecondsSinceUnixEpoch>,
+
+    /// The maximum number of room keys to include in the bundle. The most
+    /// recently received room keys are included first.
+    ///
+    /// This doesn't limit the number of withheld entries.
+    pub max_keys: Option<usize>,
+
+    /// Whether to include a withheld entry for the room keys which aren't
+    /// marked as shareable with future members of the room.
+    pub include_withheld: bool,
+}
+
+impl Default for RoomKeyBundleOptions {
+    fn default() -> Self {
+        Self { since: None, max_keys: None, include_withheld: true }
+    }
+}
+
+/// The reason why a room key wasn't imported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomKeySkipReason {
+    /// We already have the same room key.
+    AlreadyKnown,
+
+    /// We already have a better version of the room key, which can decrypt
+    /// more messages.
+    BetterSessionExists,
+
+    /// We already have a room key with the same session ID, which doesn't
+    /// match the one we tried to import.
+    Conflicting,
+
+    /// The room key couldn't be turned into a session, for example because
+    /// it uses an unsupported algorithm.
+    Invalid,
+}
+
+/// Return type of [`Store::receive_room_key_bundle()`], describing what
+/// happened to the room keys of a room key bundle.
+///
+/// [`Store::receive_room_key_bundle()`]: crate::store::Store::receive_room_key_bundle
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomKeyBundleImportResult {
+    /// The session IDs of the room keys which were imported.
+    pub imported: Vec<String>,
+
+    /// The session IDs of the room keys which weren't imported, together with
+    /// the reason why.
+    pub skipped: Vec<(String, RoomKeySkipReason)>,
+
+    /// The room IDs and session IDs of the room keys which were ignored
+    /// because they belong to another room than the bundle.
+    pub bad_room: Vec<(OwnedRoomId, String)>,
+
+    /// Why the whole bundle was rejected by the
+    /// [`RoomKeyBundleAcceptancePolicy`], if it was. No room keys are
+    /// imported from a rejected bundle.
+    pub rejected: Option<RoomKeyBundleRejection>,
+}
+
+/// What the state of the room says about the sender of a room key bundle.
+///
+/// The crypto store doesn't know the state of the room, so it has to be
+/// passed to [`Store::receive_room_key_bundle()`], which checks it against
+/// the [`RoomKeyBundleAcceptancePolicy`].
+///
+/// [`Store::receive_room_key_bundle()`]: crate::store::Store::receive_room_key_bundle
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoomKeyBundleSenderPermissions {
+    /// Whether the sender is a joined member of the room.
+    pub is_joined: bool,
+
+    /// Whether the power level of the sender allows them to share the history
+    /// of the room with new members, i.e. to invite users into the room.
+    pub can_share_history: bool,
+}
+
+/// The reason why a room key bundle was rejected by the
+/// [`RoomKeyBundleAcceptancePolicy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomKeyBundleRejection {
+    /// The sender isn't a joined member of the room.
+    NotJoined,
+
+    /// The sender isn't allowed to share the history of the room.
+    MissingHistorySharingPermission,
+
+    /// The device which sent the bundle is unknown, or isn't cross-signed by
+    /// its owner.
+    DeviceNotCrossSigned,
+
+    /// We verified the sender before, and their identity changed since then.
+    VerificationViolation,
+
+    /// We didn't verify the sender.
+    SenderNotVerified,
+
+    /// The device which sent the bundle belongs to another user than the
+    /// sender of the bundle.
+    SenderMismatch,
+}
+
+/// The rules deciding whether a room key bundle, as defined in [MSC4268], is
+/// accepted by [`Store::receive_room_key_bundle()`].
+///
+/// The default policy only accepts bundles sent by a cross-signed device of a
+/// member of the room who is allowed to share its history.
+///
+/// [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
+/// [`Store::receive_room_key_bundle()`]: crate::store::Store::receive_room_key_bundle
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomKeyBundleAcceptancePolicy {
+    /// Whether the sender must be a joined member of the room who is allowed
+    /// to share its history, see [`RoomKeyBundleSenderPermissions`].
+    pub require_history_sharing_permission: bool,
+
+    /// Whether the device which sent the bundle must be cross-signed by its
+    /// owner.
+    ///
+    /// A sender whose identity changed since we verified them is rejected as
+    /// well.
+    pub require_cross_signed_device: bool,
+
+    /// Whether we must have verified the sender.
+    pub require_verified_sender: bool,
+}
+
+impl Default for RoomKeyBundleAcceptancePolicy {
+    fn default() -> Self {
+        Self {
+            require_history_sharing_permission: true,
+            require_cross_signed_device: true,
+            require_verified_sender: false,
+        }
+    }
+}
+
+impl RoomKeyBundleAcceptancePolicy {
+    /// A policy accepting all the bundles, as long as the device which sent
+    /// them belongs to the sender.
+    pub fn accept_all() -> Self {
+        Self {
+            require_history_sharing_permission: false,
+            require_cross_signed_device: false,
+            require_verified_sender: false,
+        }
+    }
+
+    /// Check whether a bundle sent by the given user, using the device
+    /// described by the given [`SenderData`], is accepted.
+    pub fn check(
+        &self,
+        sender_user: &UserId,
+        sender_data: &SenderData,
+        permissions: RoomKeyBundleSenderPermissions,
+    ) -> Result<(), RoomKeyBundleRejection> {
+        let known_sender = match sender_data {
+            SenderData::UnknownDevice { .. } => None,
+            SenderData::DeviceInfo { device_keys, .. } => {
+                if *device_keys.user_id != *sender_user {
+                    return Err(RoomKeyBundleRejection::SenderMismatch);
+                }
+
+                None
+            }
+            SenderData::VerificationViolation(known)
+            | SenderData::SenderUnverified(known)
+            | SenderData::SenderVerified(known) => Some(known),
+        };
+
+        if known_sender.is_some_and(|known| *known.user_id != *sender_user) {
+            return Err(RoomKeyBundleRejection::SenderMismatch);
+        }
+
+        if self.require_history_sharing_permission {
+            if !permissions.is_joined {
+                return Err(RoomKeyBundleRejection::NotJoined);
+            }
+
+            if !permissions.can_share_history {
+                return Err(RoomKeyBundleRejection::MissingHistorySharingPermission);
+            }
+        }
+
+        if self.require_cross_signed_device {
+            match sender_data {
+                SenderData::UnknownDevice { .. } | SenderData::DeviceInfo { .. } => {
+                    return Err(RoomKeyBundleRejection::DeviceNotCrossSigned);
+                }
+                SenderData::VerificationViolation(_) => {
+                    return Err(RoomKeyBundleRejection::VerificationViolation);
+                }
+                SenderData::SenderUnverified(_) | SenderData::SenderVerified(_) => {}
+            }
+        }
+
+        if self.require_verified_sender && !matches!(sender_data, SenderData::SenderVerified(_)) {
+            return Err(RoomKeyBundleRejection::SenderNotVerified);
+        }
+
+        Ok(())
+    }
+}
+
+/// Policy deciding which room keys are deleted by
+/// [`Store::apply_room_key_retention()`].
+///
+/// Deleted room keys are gone for good, unless they are still in the key
+/// backup, so the events they were used to encrypt can't be decrypted anymore.
+///
+/// [`Store::apply_room_key_retention()`]: crate::store::Store::apply_room_key_retention
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomKeyRetentionPolicy {
+    /// Room keys which we received longer ago than this are deleted.
+    ///
+    /// Room keys which were stored before we started to record when they are
+    /// received are never deleted because of their age.
+    pub max_age: Option<Duration>,
+
+    /// The maximum number of room keys we keep per room. The oldest room keys
+    /// are deleted first.
+    pub max_keys_per_room: Option<usize>,
+}
+
+impl RoomKeyRetentionPolicy {
+    /// Does this policy never delete any room keys?
+    pub fn is_disabled(&self) -> bool {
+        self.max_age.is_none() && self.max_keys_per_room.is_none()
+    }
+
+    /// Get the room keys, out of the given room keys belonging to a single
+    /// room, that should be deleted according to this policy.
+    ///
+    /// The room keys are given together with the time we received them, if
+    /// known.
+    pub(crate) fn keys_to_delete(
+        &self,
+        mut keys: Vec<(Option<SecondsSinceUnixEpoch>, RoomKeyInfo)>,
+        now: SecondsSinceUnixEpoch,
+    ) -> Vec<RoomKeyInfo> {
+        let now = u64::from(now.get());
+        let max_keys = self.max_keys_per_room.unwrap_or(usize::MAX);
+
+        // Sort the newest keys first, keys without a timestamp are considered to be
+        // the oldest ones.
+        keys.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        keys.into_iter()
+            .enumerate()
+            .filter(|(position, (received_at, _))| {
+                let too_old =
+                    self.max_age.zip(*received_at).is_some_and(|(max_age, received_at)| {
+                        now.saturating_sub(received_at.get().into()) > max_age.as_secs()
+                    });
+
+                *position >= max_keys || too_old
+            })
+            .map(|(_, (_, key))| key)
+            .collect()
     }
 }
 
@@ -497,7 +1570,7 @@ pub struct RoomKeyBundleInfo {
 
 impl From<&StoredRoomKeyBundleData> for RoomKeyBundleInfo {
     fn from(value: &StoredRoomKeyBundleData) -> Self {
-        let StoredRoomKeyBundleData { sender_user, sender_data: _, bundle_data } = value;
+        let StoredRoomKeyBundleData { sender_user, bundle_data, .. } = value;
         Self { sender: sender_user.clone(), room_id: bundle_data.room_id.clone() }
     }
 }
@@ -0,0 +1,139 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The serialization formats of the custom values kept by the [`Store`].
+//!
+//! [`Store`]: super::Store
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{CryptoStoreError, Result};
+
+/// Marks a value whose format is given by the next byte.
+///
+/// `0xc1` is never used by MessagePack, so a tagged value can't be mistaken
+/// for one of the untagged MessagePack values written before the format
+/// became configurable.
+const TAG_MARKER: u8 = 0xc1;
+
+const JSON_TAG: u8 = 1;
+const CBOR_TAG: u8 = 2;
+
+/// The format used to serialize the custom values of the [`Store`], like the
+/// ones stored with [`Store::set_value()`].
+///
+/// Every value records the format it was written with, so the format can be
+/// changed for an existing store. Values in another format are still read,
+/// [`Store::migrate_values()`] rewrites them in the configured format.
+///
+/// MessagePack values aren't tagged, so they stay readable by versions of the
+/// SDK which always use MessagePack.
+///
+/// [`Store`]: super::Store
+/// [`Store::set_value()`]: super::Store::set_value
+/// [`Store::migrate_values()`]: super::Store::migrate_values
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ValueCodec {
+    /// Compact, binary MessagePack, the default.
+    #[default]
+    MessagePack,
+
+    /// JSON, which can be inspected with the usual JSON tooling once the two
+    /// bytes of the tag are skipped.
+    Json,
+
+    /// Binary CBOR.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl ValueCodec {
+    /// Serialize the given value in this format, tagged with the format if
+    /// needed.
+    pub(crate) fn encode(self, value: &impl Serialize) -> Result<Vec<u8>> {
+        let encoded = match self {
+            Self::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(CryptoStoreError::backend)?
+            }
+            Self::Json => {
+                let mut encoded = vec![TAG_MARKER, JSON_TAG];
+                serde_json::to_writer(&mut encoded, value)?;
+                encoded
+            }
+            #[cfg(feature = "cbor")]
+            Self::Cbor => {
+                let mut encoded = vec![TAG_MARKER, CBOR_TAG];
+                ciborium::into_writer(value, &mut encoded).map_err(CryptoStoreError::backend)?;
+                encoded
+            }
+        };
+
+        Ok(encoded)
+    }
+
+    /// Deserialize the given value, using the format it was tagged with.
+    ///
+    /// Returns the value and the format it was stored in.
+    pub(crate) fn decode<T: DeserializeOwned>(value: &[u8]) -> Result<(T, Self)> {
+        let (codec, payload) = Self::detect(value)?;
+
+        let decoded = match codec {
+            Self::MessagePack => {
+                rmp_serde::from_slice(payload).map_err(CryptoStoreError::backend)?
+            }
+            Self::Json => serde_json::from_slice(payload)?,
+            #[cfg(feature = "cbor")]
+            Self::Cbor => ciborium::from_reader(payload).map_err(CryptoStoreError::backend)?,
+        };
+
+        Ok((decoded, codec))
+    }
+
+    /// Rewrite the given value in this format.
+    ///
+    /// The value is converted as is, instead of going through the type it was
+    /// serialized from, so fields which that type doesn't know about are kept.
+    /// Values which can't be represented as JSON, like binary data or maps
+    /// with keys which aren't strings, are rejected.
+    ///
+    /// Returns `None` if the value is already in this format.
+    pub(crate) fn transcode(self, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (codec, _) = Self::detect(value)?;
+
+        if codec == self {
+            return Ok(None);
+        }
+
+        let (value, _) = Self::decode::<serde_json::Value>(value)?;
+        self.encode(&value).map(Some)
+    }
+
+    /// Find out the format of the given value, and where its payload starts.
+    fn detect(value: &[u8]) -> Result<(Self, &[u8])> {
+        match value {
+            [TAG_MARKER, JSON_TAG, payload @ ..] => Ok((Self::Json, payload)),
+            #[cfg(feature = "cbor")]
+            [TAG_MARKER, CBOR_TAG, payload @ ..] => Ok((Self::Cbor, payload)),
+            #[cfg(not(feature = "cbor"))]
+            [TAG_MARKER, CBOR_TAG, ..] => Err(CryptoStoreError::Backend(
+                "the value is stored as CBOR, but the `cbor` feature is disabled".into(),
+            )),
+            [TAG_MARKER, tag, ..] => {
+                Err(CryptoStoreError::Backend(format!("unknown value format: {tag}").into()))
+            }
+            _ => Ok((Self::MessagePack, value)),
+        }
+    }
+}
@@ -0,0 +1,721 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in-process fake of the key and to-device endpoints of a homeserver, to
+//! run end-to-end encryption scenarios between multiple [`OlmMachine`]s.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use matrix_sdk_common::deserialized_responses::ProcessedToDeviceEvent;
+use ruma::{
+    api::client::{
+        keys::{
+            claim_keys::v3::{Request as KeysClaimRequest, Response as KeysClaimResponse},
+            get_keys::v3::Response as KeysQueryResponse,
+            upload_keys::v3::{Request as KeysUploadRequest, Response as KeysUploadResponse},
+            upload_signatures::v3::{
+                Request as SignatureUploadRequest, Response as SignatureUploadResponse,
+            },
+            upload_signing_keys::v3::Response as SigningKeysUploadResponse,
+        },
+        message::send_message_event::v3::Response as RoomMessageResponse,
+        sync::sync_events::DeviceLists,
+        to_device::send_event_to_device::v3::Response as ToDeviceResponse,
+    },
+    encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
+    events::AnyToDeviceEvent,
+    serde::Raw,
+    to_device::DeviceIdOrAllDevices,
+    DeviceId, EventId, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedOneTimeKeyId, OwnedUserId, RoomId,
+    TransactionId, UInt, UserId,
+};
+use serde_json::{json, value::RawValue as RawJsonValue, Value};
+
+use crate::{
+    error::OlmResult,
+    olm::EncryptionSettings,
+    types::requests::{
+        AnyOutgoingRequest, KeysQueryRequest, OutgoingRequest, ToDeviceRequest,
+        UploadSigningKeysRequest,
+    },
+    EncryptionSyncChanges, OlmMachine,
+};
+
+/// A fallback key, which is handed out when a device runs out of one-time
+/// keys.
+#[derive(Debug)]
+struct FallbackKey {
+    key: Raw<OneTimeKey>,
+    /// Whether the key was claimed since it was uploaded.
+    used: bool,
+}
+
+/// What the server knows about a single device.
+#[derive(Debug, Default)]
+struct DeviceState {
+    keys: Option<Raw<DeviceKeys>>,
+    one_time_keys: BTreeMap<OwnedOneTimeKeyId, Raw<OneTimeKey>>,
+    fallback_keys: BTreeMap<OwnedOneTimeKeyId, FallbackKey>,
+    /// The to-device events waiting for the next sync of the device.
+    to_device_events: Vec<Raw<AnyToDeviceEvent>>,
+    /// The users whose keys changed since the last sync of the device.
+    changed_users: BTreeSet<OwnedUserId>,
+}
+
+impl DeviceState {
+    fn one_time_key_counts(&self) -> BTreeMap<OneTimeKeyAlgorithm, UInt> {
+        // Like real homeservers, always report the signed Curve25519 keys, even
+        // if none are left.
+        let mut counts = BTreeMap::from([(OneTimeKeyAlgorithm::SignedCurve25519, 0u32)]);
+
+        for key_id in self.one_time_keys.keys() {
+            *counts.entry(key_id.algorithm()).or_default() += 1;
+        }
+
+        counts.into_iter().map(|(algorithm, count)| (algorithm, count.into())).collect()
+    }
+
+    fn unused_fallback_keys(&self) -> Vec<OneTimeKeyAlgorithm> {
+        self.fallback_keys
+            .iter()
+            .filter(|(_, key)| !key.used)
+            .map(|(key_id, _)| key_id.algorithm())
+            .collect()
+    }
+
+    /// Take a one-time key of the given algorithm, or hand out the fallback
+    /// key if there are none left.
+    fn claim_key(
+        &mut self,
+        algorithm: &OneTimeKeyAlgorithm,
+    ) -> Option<(OwnedOneTimeKeyId, Raw<OneTimeKey>)> {
+        let one_time_key_id =
+            self.one_time_keys.keys().find(|key_id| key_id.algorithm() == *algorithm).cloned();
+
+        if let Some(key_id) = one_time_key_id {
+            let key = self.one_time_keys.remove(&key_id)?;
+            return Some((key_id, key));
+        }
+
+        let (key_id, fallback_key) =
+            self.fallback_keys.iter_mut().find(|(key_id, _)| key_id.algorithm() == *algorithm)?;
+        fallback_key.used = true;
+
+        Some((key_id.clone(), fallback_key.key.clone()))
+    }
+}
+
+/// What the server knows about a single user.
+#[derive(Debug, Default)]
+struct UserState {
+    devices: BTreeMap<OwnedDeviceId, DeviceState>,
+    master_key: Option<Raw<CrossSigningKey>>,
+    self_signing_key: Option<Raw<CrossSigningKey>>,
+    user_signing_key: Option<Raw<CrossSigningKey>>,
+}
+
+#[derive(Debug, Default)]
+struct ServerState {
+    users: BTreeMap<OwnedUserId, UserState>,
+}
+
+impl ServerState {
+    fn device_mut(&mut self, user_id: &UserId, device_id: &DeviceId) -> &mut DeviceState {
+        self.users
+            .entry(user_id.to_owned())
+            .or_default()
+            .devices
+            .entry(device_id.to_owned())
+            .or_default()
+    }
+
+    /// Tell every device that the keys of the given user changed in its next
+    /// sync.
+    ///
+    /// There are no rooms on the fake homeserver, all the users are considered
+    /// to share one.
+    fn notify_key_change(&mut self, user_id: &UserId) {
+        for device in self.users.values_mut().flat_map(|user| user.devices.values_mut()) {
+            device.changed_users.insert(user_id.to_owned());
+        }
+    }
+}
+
+/// A fake homeserver implementing `/keys/upload`, `/keys/query`,
+/// `/keys/claim`, the cross-signing uploads and to-device messaging in
+/// memory.
+///
+/// Every [`OlmMachine`] talking to the same fake homeserver can establish
+/// Olm sessions with the other ones and exchange room keys, without mocking
+/// any HTTP endpoints. The requests of a machine are sent with
+/// [`FakeHomeserver::send_outgoing_requests()`], and the to-device events,
+/// device list changes and one-time key counts meant for it are received with
+/// [`FakeHomeserver::sync()`].
+///
+/// There are no rooms: the keys of every user are tracked by every device
+/// which asks for them, see [`OlmMachine::update_tracked_users()`], and room
+/// messages, like in-room verification events, are acknowledged but not
+/// delivered.
+///
+/// The fake homeserver can be cloned, the clones share their state.
+///
+/// # Examples
+///
+/// ```
+/// # use matrix_sdk_crypto::testing::FakeHomeserver;
+/// # use ruma::{device_id, room_id, user_id};
+/// # async {
+/// let server = FakeHomeserver::new();
+/// let alice = server.new_machine(user_id!("@alice:localhost"), device_id!("ALICE")).await?;
+/// let bob = server.new_machine(user_id!("@bob:localhost"), device_id!("BOB")).await?;
+///
+/// // Alice fetches the device keys of Bob and sends him a room key.
+/// alice.update_tracked_users([bob.user_id()]).await?;
+/// server.send_outgoing_requests(&alice).await?;
+///
+/// let room_id = room_id!("!test:localhost");
+/// server.share_room_key(&alice, room_id, [bob.user_id()], Default::default()).await?;
+///
+/// // Bob receives the room key on his next sync.
+/// server.sync(&bob).await?;
+/// # anyhow::Ok(()) };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FakeHomeserver {
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl FakeHomeserver {
+    /// Create a new fake homeserver without any users.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, ServerState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Create an [`OlmMachine`] backed by a memory store, and upload its
+    /// device and one-time keys.
+    pub async fn new_machine(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> OlmResult<OlmMachine> {
+        let machine = OlmMachine::new(user_id, device_id).await;
+        self.send_outgoing_requests(&machine).await?;
+
+        Ok(machine)
+    }
+
+    /// Send all the [`OlmMachine::outgoing_requests()`] of the given machine
+    /// to the fake homeserver, and pass the responses back to the machine.
+    pub async fn send_outgoing_requests(&self, machine: &OlmMachine) -> OlmResult<()> {
+        for request in machine.outgoing_requests().await? {
+            self.send_request(machine, &request).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a single outgoing request of the given machine to the fake
+    /// homeserver, and pass the response back to the machine.
+    pub async fn send_request(
+        &self,
+        machine: &OlmMachine,
+        request: &OutgoingRequest,
+    ) -> OlmResult<()> {
+        let request_id = request.request_id();
+        let (user_id, device_id) = (machine.user_id(), machine.device_id());
+
+        match request.request() {
+            AnyOutgoingRequest::KeysUpload(request) => {
+                let response = self.keys_upload(user_id, device_id, request);
+                machine.mark_request_as_sent(request_id, &response).await
+            }
+            AnyOutgoingRequest::KeysQuery(request) => {
+                let response = self.keys_query(user_id, request);
+                machine.mark_request_as_sent(request_id, &response).await
+            }
+            AnyOutgoingRequest::KeysClaim(request) => {
+                let response = self.keys_claim(request);
+                machine.mark_request_as_sent(request_id, &response).await
+            }
+            AnyOutgoingRequest::ToDeviceRequest(request) => {
+                let response = self.send_to_device(user_id, request);
+                machine.mark_request_as_sent(request_id, &response).await
+            }
+            AnyOutgoingRequest::SignatureUpload(request) => {
+                let response = self.upload_signatures(request);
+                machine.mark_request_as_sent(request_id, &response).await
+            }
+            AnyOutgoingRequest::RoomMessage(_) => {
+                // There are no rooms, only make up an event ID.
+                let event_id = EventId::parse(format!("${}:localhost", TransactionId::new()))
+                    .expect("A transaction ID makes a valid event ID");
+                let response = RoomMessageResponse::new(event_id);
+                machine.mark_request_as_sent(request_id, &response).await
+            }
+        }
+    }
+
+    /// Deliver the to-device events, the device list changes and the
+    /// one-time key counts meant for the given machine, as a sync would.
+    ///
+    /// Returns the to-device events, as processed by
+    /// [`OlmMachine::receive_sync_changes()`].
+    pub async fn sync(&self, machine: &OlmMachine) -> OlmResult<Vec<ProcessedToDeviceEvent>> {
+        let mut changed_devices = DeviceLists::new();
+
+        let (to_device_events, one_time_keys_counts, unused_fallback_keys) = {
+            let mut state = self.state();
+            let device = state.device_mut(machine.user_id(), machine.device_id());
+            changed_devices.changed = mem::take(&mut device.changed_users).into_iter().collect();
+
+            (
+                mem::take(&mut device.to_device_events),
+                device.one_time_key_counts(),
+                device.unused_fallback_keys(),
+            )
+        };
+
+        let (events, _) = machine
+            .receive_sync_changes(EncryptionSyncChanges {
+                to_device_events,
+                changed_devices: &changed_devices,
+                one_time_keys_counts: &one_time_keys_counts,
+                unused_fallback_keys: Some(&unused_fallback_keys),
+                next_batch_token: None,
+            })
+            .await?;
+
+        Ok(events)
+    }
+
+    /// Set up cross-signing for the given machine, and upload its public
+    /// cross-signing keys and signatures.
+    pub async fn bootstrap_cross_signing(&self, machine: &OlmMachine) -> OlmResult<()> {
+        let requests = machine.bootstrap_cross_signing(false).await?;
+
+        if let Some(request) = &requests.upload_keys_req {
+            self.send_request(machine, request).await?;
+        }
+
+        let response = self.upload_signing_keys(&requests.upload_signing_keys_req);
+        machine.mark_request_as_sent(&TransactionId::new(), &response).await?;
+        self.upload_signatures(&requests.upload_signatures_req);
+
+        Ok(())
+    }
+
+    /// Claim one-time keys for the devices of the given users which the
+    /// machine doesn't share an Olm session with yet, and create the sessions.
+    pub async fn claim_missing_sessions(
+        &self,
+        machine: &OlmMachine,
+        users: impl IntoIterator<Item = &UserId>,
+    ) -> OlmResult<()> {
+        if let Some((request_id, request)) = machine.get_missing_sessions(users.into_iter()).await?
+        {
+            let response = self.keys_claim(&request);
+            machine.mark_request_as_sent(&request_id, &response).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Share the room key of the given room with the devices of the given
+    /// users, creating the missing Olm sessions first, and deliver the
+    /// to-device requests.
+    ///
+    /// The machine must already know the devices of the users, see
+    /// [`OlmMachine::update_tracked_users()`].
+    pub async fn share_room_key<'a>(
+        &self,
+        machine: &OlmMachine,
+        room_id: &RoomId,
+        users: impl IntoIterator<Item = &'a UserId>,
+        settings: EncryptionSettings,
+    ) -> OlmResult<()> {
+        let users: Vec<_> = users.into_iter().collect();
+        self.claim_missing_sessions(machine, users.iter().copied()).await?;
+
+        for request in machine.share_room_key(room_id, users.into_iter(), settings).await? {
+            let response = self.send_to_device(machine.user_id(), &request);
+            machine.mark_request_as_sent(&request.txn_id, &response).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The `/keys/upload` endpoint, called by the given device.
+    ///
+    /// New device keys are stored, and only their signatures are merged if
+    /// the device keys are already known.
+    pub fn keys_upload(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request: &KeysUploadRequest,
+    ) -> KeysUploadResponse {
+        let mut state = self.state();
+        let device = state.device_mut(user_id, device_id);
+
+        if let Some(new_keys) = &request.device_keys {
+            match &mut device.keys {
+                Some(keys) => merge_signatures(keys, new_keys.json()),
+                None => device.keys = Some(new_keys.clone()),
+            }
+        }
+
+        device.one_time_keys.extend(request.one_time_keys.clone());
+
+        if !request.fallback_keys.is_empty() {
+            device.fallback_keys = request
+                .fallback_keys
+                .iter()
+                .map(|(key_id, key)| {
+                    (key_id.clone(), FallbackKey { key: key.clone(), used: false })
+                })
+                .collect();
+        }
+
+        let one_time_key_counts = device.one_time_key_counts();
+
+        if request.device_keys.is_some() {
+            state.notify_key_change(user_id);
+        }
+
+        KeysUploadResponse::new(one_time_key_counts)
+    }
+
+    /// The `/keys/query` endpoint, called by the given user.
+    ///
+    /// The user-signing key of a user is only returned to that user.
+    pub fn keys_query(&self, user_id: &UserId, request: &KeysQueryRequest) -> KeysQueryResponse {
+        let state = self.state();
+        let mut response = KeysQueryResponse::new();
+
+        for (queried_user_id, device_ids) in &request.device_keys {
+            let Some(user) = state.users.get(queried_user_id) else {
+                continue;
+            };
+
+            let device_keys = user
+                .devices
+                .iter()
+                .filter(|(device_id, _)| device_ids.is_empty() || device_ids.contains(device_id))
+                .filter_map(|(device_id, device)| Some((device_id.clone(), device.keys.clone()?)))
+                .collect();
+            response.device_keys.insert(queried_user_id.clone(), device_keys);
+
+            if let Some(key) = &user.master_key {
+                response.master_keys.insert(queried_user_id.clone(), key.clone());
+            }
+
+            if let Some(key) = &user.self_signing_key {
+                response.self_signing_keys.insert(queried_user_id.clone(), key.clone());
+            }
+
+            if let Some(key) = &user.user_signing_key {
+                if **queried_user_id == *user_id {
+                    response.user_signing_keys.insert(queried_user_id.clone(), key.clone());
+                }
+            }
+        }
+
+        response
+    }
+
+    /// The `/keys/claim` endpoint.
+    ///
+    /// Every one-time key is only handed out once, the fallback key of a
+    /// device is handed out once it has no one-time keys left.
+    pub fn keys_claim(&self, request: &KeysClaimRequest) -> KeysClaimResponse {
+        let mut state = self.state();
+        let mut one_time_keys = BTreeMap::new();
+
+        for (user_id, devices) in &request.one_time_keys {
+            let Some(user) = state.users.get_mut(user_id) else {
+                continue;
+            };
+
+            for (device_id, algorithm) in devices {
+                let Some((key_id, key)) =
+                    user.devices.get_mut(device_id).and_then(|device| device.claim_key(algorithm))
+                else {
+                    continue;
+                };
+
+                one_time_keys
+                    .entry(user_id.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(device_id.clone(), BTreeMap::from([(key_id, key)]));
+            }
+        }
+
+        KeysClaimResponse::new(one_time_keys)
+    }
+
+    /// The `/keys/device_signing/upload` endpoint.
+    pub fn upload_signing_keys(
+        &self,
+        request: &UploadSigningKeysRequest,
+    ) -> SigningKeysUploadResponse {
+        let UploadSigningKeysRequest { master_key, self_signing_key, user_signing_key } = request;
+        let Some(user_id) = [master_key, self_signing_key, user_signing_key]
+            .into_iter()
+            .flatten()
+            .map(|key| key.user_id.clone())
+            .next()
+        else {
+            return SigningKeysUploadResponse::new();
+        };
+
+        let mut state = self.state();
+        let user = state.users.entry(user_id.clone()).or_default();
+
+        if let Some(key) = master_key {
+            user.master_key = Some(cross_signing_key_to_raw(key));
+        }
+
+        if let Some(key) = self_signing_key {
+            user.self_signing_key = Some(cross_signing_key_to_raw(key));
+        }
+
+        if let Some(key) = user_signing_key {
+            user.user_signing_key = Some(cross_signing_key_to_raw(key));
+        }
+
+        state.notify_key_change(&user_id);
+
+        SigningKeysUploadResponse::new()
+    }
+
+    /// The `/keys/signatures/upload` endpoint.
+    ///
+    /// The signatures are merged into the master key, the self-signing key or
+    /// the device keys with the same ID. Signatures of unknown keys are
+    /// ignored.
+    pub fn upload_signatures(&self, request: &SignatureUploadRequest) -> SignatureUploadResponse {
+        let mut state = self.state();
+
+        for (user_id, signed_keys) in &request.signed_keys {
+            let Some(user) = state.users.get_mut(user_id) else {
+                continue;
+            };
+
+            for (key_id, signed_key) in signed_keys.iter() {
+                let cross_signing_key = [&mut user.master_key, &mut user.self_signing_key]
+                    .into_iter()
+                    .flatten()
+                    .find(|key| has_ed25519_key(key, key_id));
+
+                if let Some(key) = cross_signing_key {
+                    merge_signatures(key, signed_key);
+                } else if let Some(keys) = user
+                    .devices
+                    .iter_mut()
+                    .find(|(device_id, _)| device_id.as_str() == key_id)
+                    .and_then(|(_, device)| device.keys.as_mut())
+                {
+                    merge_signatures(keys, signed_key);
+                }
+            }
+
+            state.notify_key_change(user_id);
+        }
+
+        SignatureUploadResponse::new(BTreeMap::new())
+    }
+
+    /// The `/sendToDevice` endpoint, called by the given user.
+    ///
+    /// The events are queued up until the next [`FakeHomeserver::sync()`] of
+    /// the recipient devices. Events for unknown devices are dropped.
+    pub fn send_to_device(&self, sender: &UserId, request: &ToDeviceRequest) -> ToDeviceResponse {
+        let mut state = self.state();
+
+        for (user_id, messages) in &request.messages {
+            let Some(user) = state.users.get_mut(user_id) else {
+                continue;
+            };
+
+            for (recipient, content) in messages {
+                let event: Raw<AnyToDeviceEvent> = Raw::new(&json!({
+                    "sender": sender,
+                    "type": request.event_type,
+                    "content": content,
+                }))
+                .expect("A to-device event can always be serialized")
+                .cast();
+
+                match recipient {
+                    DeviceIdOrAllDevices::DeviceId(device_id) => {
+                        if let Some(device) = user.devices.get_mut(device_id) {
+                            device.to_device_events.push(event);
+                        }
+                    }
+                    DeviceIdOrAllDevices::AllDevices => {
+                        for device in user.devices.values_mut() {
+                            device.to_device_events.push(event.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        ToDeviceResponse::new()
+    }
+}
+
+fn cross_signing_key_to_raw(key: &crate::types::CrossSigningKey) -> Raw<CrossSigningKey> {
+    Raw::new(key).expect("A cross-signing key can always be serialized").cast()
+}
+
+/// Whether the given cross-signing key has the given Ed25519 public key.
+fn has_ed25519_key(key: &Raw<CrossSigningKey>, public_key: &str) -> bool {
+    key.get_field::<BTreeMap<String, Value>>("keys")
+        .ok()
+        .flatten()
+        .is_some_and(|keys| keys.contains_key(&format!("ed25519:{public_key}")))
+}
+
+/// Add the signatures of `new` to the signatures of `existing`.
+fn merge_signatures<T>(existing: &mut Raw<T>, new: &RawJsonValue) {
+    let mut merged: Value = existing.deserialize_as().expect("The stored keys are valid JSON");
+    let new: Value = serde_json::from_str(new.get()).expect("The uploaded keys are valid JSON");
+
+    let Some(new_signatures) = new.get("signatures").and_then(Value::as_object) else {
+        return;
+    };
+
+    let signatures = merged
+        .as_object_mut()
+        .expect("The stored keys are a JSON object")
+        .entry("signatures")
+        .or_insert_with(|| json!({}));
+
+    for (user_id, new_user_signatures) in new_signatures {
+        let user_signatures = signatures
+            .as_object_mut()
+            .expect("The signatures are a JSON object")
+            .entry(user_id.clone())
+            .or_insert_with(|| json!({}));
+
+        if let (Some(user_signatures), Some(new_user_signatures)) =
+            (user_signatures.as_object_mut(), new_user_signatures.as_object())
+        {
+            user_signatures.extend(new_user_signatures.clone());
+        }
+    }
+
+    *existing = Raw::new(&merged).expect("The merged keys can always be serialized").cast();
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_let;
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        device_id,
+        events::{
+            room::message::{MessageType, RoomMessageEventContent},
+            AnyMessageLikeEvent, MessageLikeEvent,
+        },
+        room_id, user_id, MilliSecondsSinceUnixEpoch,
+    };
+    use serde_json::json;
+
+    use super::FakeHomeserver;
+    use crate::{utilities::json_convert, DecryptionSettings, TrustRequirement};
+
+    #[async_test]
+    async fn test_exchange_room_keys() {
+        let server = FakeHomeserver::new();
+        let alice =
+            server.new_machine(user_id!("@alice:localhost"), device_id!("ALICE")).await.unwrap();
+        let bob = server.new_machine(user_id!("@bob:localhost"), device_id!("BOB")).await.unwrap();
+        let bob_phone = server.new_machine(bob.user_id(), device_id!("BOBPHONE")).await.unwrap();
+        let room_id = room_id!("!test:localhost");
+
+        // Given that Alice knows the devices of Bob
+        alice.update_tracked_users([bob.user_id()]).await.unwrap();
+        server.send_outgoing_requests(&alice).await.unwrap();
+        let devices = alice.get_user_devices(bob.user_id(), None).await.unwrap();
+        assert_eq!(devices.devices().count(), 2);
+
+        // When she shares a room key with him
+        server.share_room_key(&alice, room_id, [bob.user_id()], Default::default()).await.unwrap();
+
+        // Then both of his devices receive it, and can decrypt her messages
+        let content = RoomMessageEventContent::text_plain("It's a secret to everybody");
+        let encrypted = alice.encrypt_room_event(room_id, content).await.unwrap();
+        let event = json_convert(&json!({
+            "event_id": "$xxxxx:localhost",
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": encrypted,
+        }))
+        .unwrap();
+        let decryption_settings =
+            DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+
+        for machine in [&bob, &bob_phone] {
+            assert_eq!(server.sync(machine).await.unwrap().len(), 1);
+
+            let decrypted =
+                machine.decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap();
+            assert_let!(
+                Ok(AnyMessageLikeEvent::RoomMessage(MessageLikeEvent::Original(message))) =
+                    decrypted.event.deserialize()
+            );
+            assert_let!(MessageType::Text(text) = &message.content.msgtype);
+            assert_eq!(text.body, "It's a secret to everybody");
+        }
+    }
+
+    #[async_test]
+    async fn test_cross_signing_keys_are_shared() {
+        let server = FakeHomeserver::new();
+        let alice =
+            server.new_machine(user_id!("@alice:localhost"), device_id!("ALICE")).await.unwrap();
+        let bob = server.new_machine(user_id!("@bob:localhost"), device_id!("BOB")).await.unwrap();
+
+        // Given that Alice set up cross-signing
+        server.bootstrap_cross_signing(&alice).await.unwrap();
+
+        // When Bob fetches her keys
+        bob.update_tracked_users([alice.user_id()]).await.unwrap();
+        server.send_outgoing_requests(&bob).await.unwrap();
+
+        // Then he sees her identity, and her device is signed by it
+        let identity = bob.get_identity(alice.user_id(), None).await.unwrap().unwrap();
+        assert!(identity.other().is_some());
+        let device = bob.get_device(alice.user_id(), alice.device_id(), None).await.unwrap();
+        assert!(device.unwrap().is_cross_signed_by_owner());
+
+        // And he's told when she adds a new device
+        server.new_machine(alice.user_id(), device_id!("ALICEPHONE")).await.unwrap();
+        server.sync(&bob).await.unwrap();
+        server.send_outgoing_requests(&bob).await.unwrap();
+        let devices = bob.get_user_devices(alice.user_id(), None).await.unwrap();
+        assert_eq!(devices.devices().count(), 2);
+    }
+}
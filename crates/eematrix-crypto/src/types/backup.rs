@@ -14,6 +14,8 @@
 
 use std::collections::BTreeMap;
 
+#[cfg(feature = "experimental-symmetric-backup")]
+use ruma::serde::Base64;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use vodozemac::Curve25519PublicKey;
@@ -44,6 +46,52 @@ impl MegolmV1AuthData {
     }
 }
 
+/// Auth data for the `org.matrix.msc3270.v1.aes-hmac-sha2` symmetric backup
+/// algorithm as defined in [MSC3270].
+///
+/// The `iv` and `mac` fields are a key check, they allow us to find out if a
+/// backup key belongs to this backup, the same way it's done for a secret
+/// storage key.
+///
+/// [MSC3270]: https://github.com/matrix-org/matrix-spec-proposals/pull/3270
+#[cfg(feature = "experimental-symmetric-backup")]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MegolmV2AuthData {
+    /// The initialization vector that was used to create the key check.
+    pub iv: Base64,
+    /// The MAC of the key check.
+    pub mac: Base64,
+    /// *Optional.* Signatures of the auth_data, as Signed JSON.
+    #[serde(default)]
+    pub signatures: Signatures,
+    #[serde(flatten)]
+    extra: BTreeMap<String, Value>,
+}
+
+#[cfg(feature = "experimental-symmetric-backup")]
+impl MegolmV2AuthData {
+    // Create a new [`MegolmV2AuthData`] from a key check and a [`Signatures`]
+    // map.
+    pub(crate) fn new(iv: Base64, mac: Base64, signatures: Signatures) -> Self {
+        Self { iv, mac, signatures, extra: Default::default() }
+    }
+}
+
+/// A room key which was encrypted using the
+/// `org.matrix.msc3270.v1.aes-hmac-sha2` backup algorithm.
+///
+/// This is the `session_data` of a room key in such a backup.
+#[cfg(feature = "experimental-symmetric-backup")]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MegolmV2EncryptedSessionData {
+    /// The initialization vector that was used to encrypt the room key.
+    pub iv: Base64,
+    /// The encrypted room key.
+    pub ciphertext: Base64,
+    /// The MAC of the ciphertext.
+    pub mac: Base64,
+}
+
 /// Information pertaining to a room key backup. Can be used to upload a new
 /// backup version as defined in the [spec].
 ///
@@ -53,6 +101,9 @@ impl MegolmV1AuthData {
 pub enum RoomKeyBackupInfo {
     /// The `m.megolm_backup.v1.curve25519-aes-sha2` variant of a backup.
     MegolmBackupV1Curve25519AesSha2(MegolmV1AuthData),
+    /// The `org.matrix.msc3270.v1.aes-hmac-sha2` variant of a backup.
+    #[cfg(feature = "experimental-symmetric-backup")]
+    MegolmBackupV2AesHmacSha2(MegolmV2AuthData),
     /// Any other unknown backup variant.
     Other {
         /// The algorithm of the unknown backup variant.
@@ -77,6 +128,11 @@ impl TryFrom<BackupInfoHelper> for RoomKeyBackupInfo {
                 let data: MegolmV1AuthData = serde_json::from_value(value.auth_data)?;
                 RoomKeyBackupInfo::MegolmBackupV1Curve25519AesSha2(data)
             }
+            #[cfg(feature = "experimental-symmetric-backup")]
+            "org.matrix.msc3270.v1.aes-hmac-sha2" => {
+                let data: MegolmV2AuthData = serde_json::from_value(value.auth_data)?;
+                RoomKeyBackupInfo::MegolmBackupV2AesHmacSha2(data)
+            }
             _ => RoomKeyBackupInfo::Other {
                 algorithm: value.algorithm,
                 auth_data: serde_json::from_value(value.auth_data)?,
@@ -95,6 +151,11 @@ impl Serialize for RoomKeyBackupInfo {
                 algorithm: "m.megolm_backup.v1.curve25519-aes-sha2".to_owned(),
                 auth_data: serde_json::to_value(d).map_err(serde::ser::Error::custom)?,
             },
+            #[cfg(feature = "experimental-symmetric-backup")]
+            RoomKeyBackupInfo::MegolmBackupV2AesHmacSha2(d) => BackupInfoHelper {
+                algorithm: "org.matrix.msc3270.v1.aes-hmac-sha2".to_owned(),
+                auth_data: serde_json::to_value(d).map_err(serde::ser::Error::custom)?,
+            },
             RoomKeyBackupInfo::Other { algorithm, auth_data } => BackupInfoHelper {
                 algorithm: algorithm.to_owned(),
                 auth_data: serde_json::to_value(auth_data.clone())
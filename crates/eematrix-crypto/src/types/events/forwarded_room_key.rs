@@ -79,6 +79,17 @@ impl ForwardedRoomKeyContent {
             ForwardedRoomKeyContent::Unknown(c) => c.algorithm.to_owned(),
         }
     }
+
+    /// Get the Curve25519 key of the device which created the forwarded room
+    /// key, if the content is of a supported variant.
+    pub fn claimed_sender_key(&self) -> Option<Curve25519PublicKey> {
+        match self {
+            ForwardedRoomKeyContent::MegolmV1AesSha2(c) => Some(c.claimed_sender_key),
+            #[cfg(feature = "experimental-algorithms")]
+            ForwardedRoomKeyContent::MegolmV2AesSha2(c) => Some(c.claimed_sender_key),
+            ForwardedRoomKeyContent::Unknown(_) => None,
+        }
+    }
 }
 
 impl EventType for ForwardedRoomKeyContent {
@@ -269,6 +269,15 @@ impl SupportedEventEncryptionSchemes<'_> {
         }
     }
 
+    /// The encrypted Megolm message.
+    pub fn ciphertext(&self) -> &MegolmMessage {
+        match self {
+            SupportedEventEncryptionSchemes::MegolmV1AesSha2(c) => &c.ciphertext,
+            #[cfg(feature = "experimental-algorithms")]
+            SupportedEventEncryptionSchemes::MegolmV2AesSha2(c) => &c.ciphertext,
+        }
+    }
+
     /// The index of the Megolm ratchet that was used to encrypt the message.
     pub fn message_index(&self) -> u32 {
         match self {
@@ -0,0 +1,88 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for the experimental `m.room_key.ack` to-device events.
+//!
+//! An `m.room_key.ack` event is sent back to the device which shared a room
+//! key with us, once the room key has been stored. It's sent Olm-encrypted, so
+//! the device which acknowledges the room key is authenticated. It isn't part
+//! of the spec, it's meant to measure how reliably room keys are delivered.
+
+use std::collections::BTreeMap;
+
+use ruma::OwnedRoomId;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{EventType, ToDeviceEvent};
+
+/// The `m.room_key.ack` to-device event.
+pub type RoomKeyAckEvent = ToDeviceEvent<RoomKeyAckContent>;
+
+/// The content of an `m.room_key.ack` event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomKeyAckContent {
+    /// The room the acknowledged room key is used in.
+    pub room_id: OwnedRoomId,
+
+    /// The ID of the session of the acknowledged room key.
+    pub session_id: String,
+
+    /// Any other, custom and non-specced fields of the content.
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+impl RoomKeyAckContent {
+    /// Create a new `m.room_key.ack` event content.
+    pub fn new(room_id: OwnedRoomId, session_id: String) -> Self {
+        Self { room_id, session_id, other: Default::default() }
+    }
+}
+
+impl EventType for RoomKeyAckContent {
+    const EVENT_TYPE: &'static str = "m.room_key.ack";
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::RoomKeyAckEvent;
+
+    fn json() -> Value {
+        json!({
+            "sender": "@alice:example.org",
+            "content": {
+                "room_id": "!Cuyf34gef24t:localhost",
+                "session_id": "ZFD6+OmV7fVCsJ7Gap8UnORH8EnmiAkes8FAvQuCw/I",
+                "m.custom": "something custom",
+            },
+            "type": "m.room_key.ack",
+        })
+    }
+
+    #[test]
+    fn deserialization() -> Result<(), serde_json::Error> {
+        let json = json();
+        let event: RoomKeyAckEvent = serde_json::from_value(json.clone())?;
+
+        assert_eq!(event.content.session_id, "ZFD6+OmV7fVCsJ7Gap8UnORH8EnmiAkes8FAvQuCw/I");
+
+        let serialized = serde_json::to_value(event)?;
+        assert_eq!(json, serialized);
+
+        Ok(())
+    }
+}
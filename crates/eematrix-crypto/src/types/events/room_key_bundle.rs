@@ -34,6 +34,27 @@ pub struct RoomKeyBundleContent {
 
     /// The location and encryption info of the key bundle.
     pub file: EncryptedFile,
+
+    /// Where this bundle fits, if the keys were split into multiple bundles
+    /// because they didn't fit in a single upload.
+    #[serde(default, rename = "io.eematrix.chunk", skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<RoomKeyBundleChunk>,
+}
+
+/// Information about a chunk of a key bundle that was split into multiple
+/// parts, see [`RoomKeyBundle::split()`].
+///
+/// [`RoomKeyBundle::split()`]: crate::types::room_history::RoomKeyBundle::split
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomKeyBundleChunk {
+    /// An identifier shared by all the chunks of the same bundle.
+    pub bundle_id: String,
+
+    /// The position of this chunk, starting at zero.
+    pub index: usize,
+
+    /// The total number of chunks of the bundle.
+    pub count: usize,
 }
 
 impl EventType for RoomKeyBundleContent {
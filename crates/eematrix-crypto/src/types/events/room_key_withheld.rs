@@ -17,7 +17,7 @@
 use std::collections::BTreeMap;
 
 use matrix_sdk_common::deserialized_responses::WithheldCode;
-use ruma::{OwnedDeviceId, OwnedRoomId};
+use ruma::{DeviceId, OwnedDeviceId, OwnedRoomId, RoomId};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use vodozemac::Curve25519PublicKey;
@@ -140,6 +140,30 @@ impl RoomKeyWithheldContent {
         }
     }
 
+    /// Get the ID of the room the withheld room key is used in.
+    ///
+    /// Returns `None` for `m.no_olm` codes, which aren't about a specific room
+    /// key, and for unknown algorithms.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        match self {
+            RoomKeyWithheldContent::MegolmV1AesSha2(c) => c.room_id(),
+            #[cfg(feature = "experimental-algorithms")]
+            RoomKeyWithheldContent::MegolmV2AesSha2(c) => c.room_id(),
+            RoomKeyWithheldContent::Unknown(_) => None,
+        }
+    }
+
+    /// Get the ID of the device which sent this `m.room_key.withheld` message,
+    /// if it told us about it.
+    pub fn from_device(&self) -> Option<&DeviceId> {
+        match self {
+            RoomKeyWithheldContent::MegolmV1AesSha2(c) => c.from_device(),
+            #[cfg(feature = "experimental-algorithms")]
+            RoomKeyWithheldContent::MegolmV2AesSha2(c) => c.from_device(),
+            RoomKeyWithheldContent::Unknown(_) => None,
+        }
+    }
+
     /// Get the algorithm of the room key withheld.
     pub fn algorithm(&self) -> EventEncryptionAlgorithm {
         match &self {
@@ -232,6 +256,30 @@ impl MegolmV1AesSha2WithheldContent {
         }
     }
 
+    /// Get the ID of the device which sent this content, if it told us about
+    /// it.
+    pub fn from_device(&self) -> Option<&DeviceId> {
+        match self {
+            MegolmV1AesSha2WithheldContent::BlackListed(c)
+            | MegolmV1AesSha2WithheldContent::Unverified(c)
+            | MegolmV1AesSha2WithheldContent::Unauthorised(c)
+            | MegolmV1AesSha2WithheldContent::Unavailable(c) => c.from_device.as_deref(),
+            MegolmV1AesSha2WithheldContent::NoOlm(c) => c.from_device.as_deref(),
+        }
+    }
+
+    /// Get the ID of the room the withheld room key is used in, `None` for
+    /// `m.no_olm` codes.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        match self {
+            MegolmV1AesSha2WithheldContent::BlackListed(c)
+            | MegolmV1AesSha2WithheldContent::Unverified(c)
+            | MegolmV1AesSha2WithheldContent::Unauthorised(c)
+            | MegolmV1AesSha2WithheldContent::Unavailable(c) => Some(&c.room_id),
+            MegolmV1AesSha2WithheldContent::NoOlm(_) => None,
+        }
+    }
+
     fn from_code_and_content(code: WithheldCode, content: CommonWithheldCodeContent) -> Self {
         let content = content.into();
 
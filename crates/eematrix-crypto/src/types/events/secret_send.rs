@@ -22,6 +22,7 @@ use serde_json::Value;
 use zeroize::Zeroize;
 
 use super::{EventType, ToDeviceEvent};
+use crate::types::SecretString;
 
 /// The `m.secret.send` to-device event.
 pub type SecretSendEvent = ToDeviceEvent<SecretSendContent>;
@@ -36,7 +37,7 @@ pub struct SecretSendContent {
     /// The ID of the request that this a response to.
     pub request_id: OwnedTransactionId,
     /// The contents of the secret.
-    pub secret: String,
+    pub secret: SecretString,
     /// The name of the secret, typically not part of the event but can be
     /// inserted when processing `m.secret.send` events so other event consumers
     /// know which secret this event contains.
@@ -49,7 +50,7 @@ pub struct SecretSendContent {
 
 impl SecretSendContent {
     /// Create a new `m.secret.send` content.
-    pub fn new(request_id: OwnedTransactionId, secret: String) -> Self {
+    pub fn new(request_id: OwnedTransactionId, secret: SecretString) -> Self {
         Self { request_id, secret, secret_name: None, other: Default::default() }
     }
 }
@@ -60,12 +61,6 @@ impl Zeroize for SecretSendContent {
     }
 }
 
-impl Drop for SecretSendContent {
-    fn drop(&mut self) {
-        self.zeroize()
-    }
-}
-
 #[cfg(not(tarpaulin_include))]
 impl std::fmt::Debug for SecretSendContent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -101,7 +96,7 @@ pub(crate) mod tests {
         let json = json();
         let event: SecretSendEvent = serde_json::from_value(json.clone())?;
 
-        assert_eq!(&event.content.secret, "ThisIsASecretDon'tTellAnyone");
+        assert_eq!(event.content.secret.expose_secret(), "ThisIsASecretDon'tTellAnyone");
 
         let serialized = serde_json::to_value(event)?;
         assert_eq!(json, serialized);
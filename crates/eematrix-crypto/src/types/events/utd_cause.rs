@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::time::Duration;
+
 use matrix_sdk_common::deserialized_responses::{
     UnableToDecryptInfo, UnableToDecryptReason, VerificationLevel, WithheldCode,
 };
@@ -95,8 +97,20 @@ pub enum UtdCause {
     ///
     /// Expected message to user: "You need to verify this device".
     HistoricalMessageAndDeviceIsUnverified = 8,
+
+    /// We are missing the keys for this event, but they are probably on their
+    /// way: either they were received after we tried to decrypt the event, or
+    /// the event was sent so recently that the to-device message carrying the
+    /// keys may not have reached us yet.
+    ///
+    /// Decrypting the event again later is likely to succeed.
+    KeyNotYetReceived = 9,
 }
 
+/// How long after an event was sent we still expect the keys for it to arrive,
+/// see [`UtdCause::KeyNotYetReceived`].
+const KEY_ARRIVAL_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
 /// MSC4115 membership info in the unsigned area.
 #[derive(Deserialize)]
 struct UnsignedWithMembership {
@@ -135,6 +149,24 @@ pub struct CryptoContextInfo {
     pub is_backup_configured: bool,
 }
 
+/// What the crypto store currently knows about the room key of an event which
+/// couldn't be decrypted, used by [`UtdCause::determine_with_store_info`] in
+/// addition to the information used by [`UtdCause::determine`].
+///
+/// The [`UnableToDecryptInfo`] describes the state of things at the time we
+/// tried to decrypt the event, this describes the state of things now.
+#[derive(Debug, Clone)]
+pub struct UtdStoreInfo {
+    /// True if we have the room key of the event now.
+    pub session_is_known: bool,
+
+    /// The withheld code we received for the room key of the event, if any.
+    pub withheld_code: Option<WithheldCode>,
+
+    /// The current time.
+    pub now: MilliSecondsSinceUnixEpoch,
+}
+
 impl UtdCause {
     /// Decide the cause of this UTD, based on the evidence we have.
     pub fn determine(
@@ -144,7 +176,7 @@ impl UtdCause {
     ) -> Self {
         // TODO: in future, use more information to give a richer answer. E.g.
         match &unable_to_decrypt_info.reason {
-            UnableToDecryptReason::MissingMegolmSession { withheld_code: Some(reason) } => {
+            UnableToDecryptReason::MissingMegolmSession { withheld_code: Some(reason), .. } => {
                 match reason {
                     WithheldCode::Unverified => UtdCause::WithheldForUnverifiedOrInsecureDevice,
                     WithheldCode::Blacklisted
@@ -154,7 +186,7 @@ impl UtdCause {
                     | WithheldCode::_Custom(_) => UtdCause::WithheldBySender,
                 }
             }
-            UnableToDecryptReason::MissingMegolmSession { withheld_code: None }
+            UnableToDecryptReason::MissingMegolmSession { withheld_code: None, .. }
             | UnableToDecryptReason::UnknownMegolmMessageIndex => {
                 // Look in the unsigned area for a `membership` field.
                 if let Some(unsigned) =
@@ -192,6 +224,51 @@ impl UtdCause {
         }
     }
 
+    /// Decide the cause of this UTD like [`UtdCause::determine`] does, also
+    /// taking into account what the crypto store knows about the room key of
+    /// the event by now.
+    ///
+    /// A withheld code which was received after we tried to decrypt the event
+    /// is taken into account, and [`UtdCause::KeyNotYetReceived`] is returned
+    /// if the room key arrived in the meantime, or if the event is recent
+    /// enough for the room key to still arrive.
+    pub fn determine_with_store_info(
+        raw_event: &Raw<AnySyncTimelineEvent>,
+        crypto_context_info: CryptoContextInfo,
+        store_info: &UtdStoreInfo,
+        unable_to_decrypt_info: &UnableToDecryptInfo,
+    ) -> Self {
+        let UnableToDecryptReason::MissingMegolmSession { withheld_code: None, .. } =
+            &unable_to_decrypt_info.reason
+        else {
+            return UtdCause::determine(raw_event, crypto_context_info, unable_to_decrypt_info);
+        };
+
+        if store_info.session_is_known {
+            return UtdCause::KeyNotYetReceived;
+        }
+
+        if let Some(withheld_code) = &store_info.withheld_code {
+            let unable_to_decrypt_info = UnableToDecryptInfo {
+                session_id: unable_to_decrypt_info.session_id.clone(),
+                reason: UnableToDecryptReason::MissingMegolmSession {
+                    withheld_code: Some(withheld_code.clone()),
+                    withheld_by: None,
+                },
+            };
+
+            return UtdCause::determine(raw_event, crypto_context_info, &unable_to_decrypt_info);
+        }
+
+        let cause = UtdCause::determine(raw_event, crypto_context_info, unable_to_decrypt_info);
+
+        if cause == UtdCause::Unknown && is_recent(raw_event, store_info.now) {
+            UtdCause::KeyNotYetReceived
+        } else {
+            cause
+        }
+    }
+
     /**
      * Below is the flow chart we follow for deciding whether historical
      * UTDs are expected. This function starts at position `B`.
@@ -237,19 +314,37 @@ impl UtdCause {
     }
 }
 
+/// Was the given event sent less than [`KEY_ARRIVAL_GRACE_PERIOD`] ago?
+fn is_recent(raw_event: &Raw<AnySyncTimelineEvent>, now: MilliSecondsSinceUnixEpoch) -> bool {
+    let Ok(Some(origin_server_ts)) =
+        raw_event.get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+    else {
+        return false;
+    };
+
+    let age = u64::from(now.get()).saturating_sub(origin_server_ts.get().into());
+    Duration::from_millis(age) < KEY_ARRIVAL_GRACE_PERIOD
+}
+
 #[cfg(test)]
 mod tests {
     use matrix_sdk_common::deserialized_responses::{
         DeviceLinkProblem, UnableToDecryptInfo, UnableToDecryptReason, VerificationLevel,
+        WithheldCode,
     };
     use ruma::{events::AnySyncTimelineEvent, serde::Raw, MilliSecondsSinceUnixEpoch};
     use serde_json::{json, value::to_raw_value};
 
-    use crate::types::events::{utd_cause::CryptoContextInfo, UtdCause};
+    use crate::types::events::{
+        utd_cause::{CryptoContextInfo, UtdStoreInfo},
+        UtdCause,
+    };
 
     const EVENT_TIME: usize = 5555;
     const BEFORE_EVENT_TIME: usize = 1111;
     const AFTER_EVENT_TIME: usize = 9999;
+    const DURING_GRACE_PERIOD: usize = EVENT_TIME + 30_000;
+    const AFTER_GRACE_PERIOD: usize = EVENT_TIME + 120_000;
 
     #[test]
     fn test_if_there_is_no_membership_info_we_guess_unknown() {
@@ -516,6 +611,114 @@ mod tests {
         assert_eq!(UtdCause::determine(&utd_event(), context, &info), UtdCause::Unknown);
     }
 
+    #[test]
+    fn test_keys_received_since_are_not_yet_received() {
+        // If we have the room key by now, decrypting the event again should work.
+        let store_info = UtdStoreInfo { session_is_known: true, ..store_info(AFTER_GRACE_PERIOD) };
+
+        assert_eq!(
+            UtdCause::determine_with_store_info(
+                &utd_event(),
+                device_old(),
+                &store_info,
+                &missing_megolm_session()
+            ),
+            UtdCause::KeyNotYetReceived
+        );
+    }
+
+    #[test]
+    fn test_withheld_code_received_since_is_used() {
+        // If the room key was withheld after we tried to decrypt the event, the
+        // withheld code tells why.
+        let store_info = UtdStoreInfo {
+            withheld_code: Some(WithheldCode::Unverified),
+            ..store_info(DURING_GRACE_PERIOD)
+        };
+
+        assert_eq!(
+            UtdCause::determine_with_store_info(
+                &utd_event(),
+                device_old(),
+                &store_info,
+                &missing_megolm_session()
+            ),
+            UtdCause::WithheldForUnverifiedOrInsecureDevice
+        );
+    }
+
+    #[test]
+    fn test_recent_events_keys_are_not_yet_received() {
+        // If the event was just sent, the room key may still be on its way.
+        assert_eq!(
+            UtdCause::determine_with_store_info(
+                &utd_event(),
+                device_old(),
+                &store_info(DURING_GRACE_PERIOD),
+                &missing_megolm_session()
+            ),
+            UtdCause::KeyNotYetReceived
+        );
+
+        // But not once the grace period is over.
+        assert_eq!(
+            UtdCause::determine_with_store_info(
+                &utd_event(),
+                device_old(),
+                &store_info(AFTER_GRACE_PERIOD),
+                &missing_megolm_session()
+            ),
+            UtdCause::Unknown
+        );
+
+        // And events sent before we joined are still recognized as such.
+        assert_eq!(
+            UtdCause::determine_with_store_info(
+                &raw_event(json!({
+                    "origin_server_ts": EVENT_TIME,
+                    "unsigned": { "membership": "leave" }
+                })),
+                device_old(),
+                &store_info(DURING_GRACE_PERIOD),
+                &missing_megolm_session()
+            ),
+            UtdCause::SentBeforeWeJoined
+        );
+    }
+
+    #[test]
+    fn test_store_info_is_ignored_for_other_reasons() {
+        // The room key being known doesn't matter if the problem was something else.
+        let store_info = UtdStoreInfo { session_is_known: true, ..store_info(DURING_GRACE_PERIOD) };
+
+        assert_eq!(
+            UtdCause::determine_with_store_info(
+                &utd_event(),
+                device_old(),
+                &store_info,
+                &verification_violation()
+            ),
+            UtdCause::VerificationViolation
+        );
+        assert_eq!(
+            UtdCause::determine_with_store_info(
+                &utd_event(),
+                device_old(),
+                &store_info,
+                &unknown_megolm_message_index()
+            ),
+            UtdCause::Unknown
+        );
+    }
+
+    fn store_info(now: usize) -> UtdStoreInfo {
+        UtdStoreInfo {
+            session_is_known: false,
+            withheld_code: None,
+            now: MilliSecondsSinceUnixEpoch(now.try_into().unwrap()),
+        }
+    }
+
     fn utd_event() -> Raw<AnySyncTimelineEvent> {
         raw_event(json!({
             "type": "m.room.encrypted",
@@ -559,7 +762,10 @@ mod tests {
     fn missing_megolm_session() -> UnableToDecryptInfo {
         UnableToDecryptInfo {
             session_id: None,
-            reason: UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+            reason: UnableToDecryptReason::MissingMegolmSession {
+                withheld_code: None,
+                withheld_by: None,
+            },
         }
     }
 
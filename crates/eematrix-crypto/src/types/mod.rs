@@ -51,8 +51,9 @@ mod one_time_keys;
 pub mod qr_login;
 pub mod requests;
 pub mod room_history;
+mod secrets;
 
-pub use self::{backup::*, cross_signing::*, device_keys::*, one_time_keys::*};
+pub use self::{backup::*, cross_signing::*, device_keys::*, one_time_keys::*, secrets::*};
 use crate::store::types::BackupDecryptionKey;
 
 macro_rules! from_base64 {
@@ -102,13 +103,13 @@ pub struct SecretsBundle {
 pub struct CrossSigningSecrets {
     /// The seed for the private part of the cross-signing master key, encoded
     /// as base64.
-    pub master_key: String,
+    pub master_key: SecretString,
     /// The seed for the private part of the cross-signing user-signing key,
     /// encoded as base64.
-    pub user_signing_key: String,
+    pub user_signing_key: SecretString,
     /// The seed for the private part of the cross-signing self-signing key,
     /// encoded as base64.
-    pub self_signing_key: String,
+    pub self_signing_key: SecretString,
 }
 
 impl std::fmt::Debug for CrossSigningSecrets {
@@ -134,6 +135,19 @@ pub struct MegolmBackupV1Curve25519AesSha2Secrets {
     pub backup_version: String,
 }
 
+/// Data for the secrets bundle containing the secret and version for a
+/// `org.matrix.msc3270.v1.aes-hmac-sha2` backup.
+#[cfg(feature = "experimental-symmetric-backup")]
+#[derive(Debug, Deserialize, Clone, Serialize, ZeroizeOnDrop)]
+pub struct MegolmBackupV2AesHmacSha2Secrets {
+    /// The symmetric backup key, used to encrypt and decrypt the room keys in
+    /// the backup.
+    #[serde(serialize_with = "backup_key_to_base64", deserialize_with = "backup_key_from_base64")]
+    pub key: BackupDecryptionKey,
+    /// The backup version that is tied to the above backup key.
+    pub backup_version: String,
+}
+
 from_base64!(BackupDecryptionKey, backup_key_from_base64);
 to_base64!(BackupDecryptionKey, backup_key_to_base64);
 
@@ -145,15 +159,62 @@ pub enum BackupSecrets {
     /// algorithm.
     #[serde(rename = "m.megolm_backup.v1.curve25519-aes-sha2")]
     MegolmBackupV1Curve25519AesSha2(MegolmBackupV1Curve25519AesSha2Secrets),
+    /// Backup secrets for the `org.matrix.msc3270.v1.aes-hmac-sha2` backup
+    /// algorithm.
+    #[cfg(feature = "experimental-symmetric-backup")]
+    #[serde(rename = "org.matrix.msc3270.v1.aes-hmac-sha2")]
+    MegolmBackupV2AesHmacSha2(MegolmBackupV2AesHmacSha2Secrets),
 }
 
 impl BackupSecrets {
+    /// Create the [`BackupSecrets`] for a backup using the given algorithm.
+    ///
+    /// Returns `None` if the algorithm isn't supported.
+    pub fn new(algorithm: &str, key: BackupDecryptionKey, backup_version: String) -> Option<Self> {
+        match algorithm {
+            "m.megolm_backup.v1.curve25519-aes-sha2" => {
+                Some(BackupSecrets::MegolmBackupV1Curve25519AesSha2(
+                    MegolmBackupV1Curve25519AesSha2Secrets { key, backup_version },
+                ))
+            }
+            #[cfg(feature = "experimental-symmetric-backup")]
+            "org.matrix.msc3270.v1.aes-hmac-sha2" => {
+                Some(BackupSecrets::MegolmBackupV2AesHmacSha2(MegolmBackupV2AesHmacSha2Secrets {
+                    key,
+                    backup_version,
+                }))
+            }
+            _ => None,
+        }
+    }
+
     /// Get the algorithm of the secrets contained in the [`BackupSecrets`].
     pub fn algorithm(&self) -> &str {
         match &self {
             BackupSecrets::MegolmBackupV1Curve25519AesSha2(_) => {
                 "m.megolm_backup.v1.curve25519-aes-sha2"
             }
+            #[cfg(feature = "experimental-symmetric-backup")]
+            BackupSecrets::MegolmBackupV2AesHmacSha2(_) => "org.matrix.msc3270.v1.aes-hmac-sha2",
+        }
+    }
+
+    /// Get the backup key contained in the [`BackupSecrets`].
+    pub fn key(&self) -> &BackupDecryptionKey {
+        match &self {
+            BackupSecrets::MegolmBackupV1Curve25519AesSha2(secrets) => &secrets.key,
+            #[cfg(feature = "experimental-symmetric-backup")]
+            BackupSecrets::MegolmBackupV2AesHmacSha2(secrets) => &secrets.key,
+        }
+    }
+
+    /// Get the backup version the secrets contained in the [`BackupSecrets`]
+    /// belong to.
+    pub fn backup_version(&self) -> &str {
+        match &self {
+            BackupSecrets::MegolmBackupV1Curve25519AesSha2(secrets) => &secrets.backup_version,
+            #[cfg(feature = "experimental-symmetric-backup")]
+            BackupSecrets::MegolmBackupV2AesHmacSha2(secrets) => &secrets.backup_version,
         }
     }
 }
@@ -613,9 +674,9 @@ mod test {
     fn snapshot_secret_bundle() {
         let secret_bundle = SecretsBundle {
             cross_signing: CrossSigningSecrets {
-                master_key: "MSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSK".to_owned(),
-                user_signing_key: "USKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSK".to_owned(),
-                self_signing_key: "SSKSSKSSKSSKSSKSSKSSKSSKSSKSSKSSK".to_owned(),
+                master_key: "MSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSK".into(),
+                user_signing_key: "USKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSK".into(),
+                self_signing_key: "SSKSSKSSKSSKSSKSSKSSKSSKSSKSSKSSK".into(),
             },
             backup: Some(BackupSecrets::MegolmBackupV1Curve25519AesSha2(
                 MegolmBackupV1Curve25519AesSha2Secrets {
@@ -629,9 +690,9 @@ mod test {
 
         let secret_bundle = SecretsBundle {
             cross_signing: CrossSigningSecrets {
-                master_key: "MSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSK".to_owned(),
-                user_signing_key: "USKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSK".to_owned(),
-                self_signing_key: "SSKSSKSSKSSKSSKSSKSSKSSKSSKSSKSSK".to_owned(),
+                master_key: "MSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSKMSK".into(),
+                user_signing_key: "USKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSKUSK".into(),
+                self_signing_key: "SSKSSKSSKSSKSSKSSKSSKSSKSSKSSKSSK".into(),
             },
             backup: None,
         };
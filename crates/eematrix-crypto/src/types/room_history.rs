@@ -18,19 +18,28 @@ limitations under the License.
 //!
 //! [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
 
-use std::fmt::Debug;
+use std::{fmt::Debug, io::Read, mem};
 
-use ruma::{DeviceKeyAlgorithm, OwnedRoomId};
+use ruma::{
+    events::room::{EncryptedFile, EncryptedFileInit},
+    DeviceKeyAlgorithm, OwnedMxcUri, OwnedRoomId,
+};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use vodozemac::{megolm::ExportedSessionKey, Curve25519PublicKey};
 
 use super::RoomKeyExport;
 use crate::{
+    file_encryption::{AttachmentDecryptor, AttachmentEncryptor, DecryptorError},
     olm::ExportedRoomKey,
     types::{
-        deserialize_curve_key, events::room_key_withheld::RoomKeyWithheldContent,
+        deserialize_curve_key,
+        events::{
+            room_key_bundle::RoomKeyBundleContent, room_key_withheld::RoomKeyWithheldContent,
+        },
         serialize_curve_key, EventEncryptionAlgorithm, SigningKeys,
     },
+    MediaEncryptionInfo,
 };
 #[cfg(doc)]
 use crate::{olm::InboundGroupSession, types::events::room_key::RoomKeyContent};
@@ -53,6 +62,185 @@ impl RoomKeyBundle {
     pub fn is_empty(&self) -> bool {
         self.room_keys.is_empty() && self.withheld.is_empty()
     }
+
+    /// Serialize and encrypt the bundle as an encrypted attachment, as
+    /// described in the [spec].
+    ///
+    /// The resulting [`EncryptedRoomKeyBundle::ciphertext`] needs to be
+    /// uploaded to the media repository, after which the content of the
+    /// to-device message pointing to it can be created using
+    /// [`EncryptedRoomKeyBundle::to_content()`].
+    ///
+    /// [spec]: https://spec.matrix.org/latest/client-server-api/#sending-encrypted-attachments
+    pub fn encrypt(&self) -> Result<EncryptedRoomKeyBundle, RoomKeyBundleError> {
+        let plaintext = serde_json::to_vec(self)?;
+
+        let mut reader = plaintext.as_slice();
+        let mut encryptor = AttachmentEncryptor::new(&mut reader);
+        let mut ciphertext = Vec::new();
+        encryptor.read_to_end(&mut ciphertext)?;
+
+        Ok(EncryptedRoomKeyBundle { ciphertext, encryption_info: encryptor.finish() })
+    }
+
+    /// Decrypt and deserialize a bundle which was downloaded from the media
+    /// repository.
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` - The downloaded, still encrypted, bundle.
+    ///
+    /// * `content` - The content of the to-device message which pointed us to
+    ///   the bundle.
+    pub fn decrypt(
+        ciphertext: &[u8],
+        content: &RoomKeyBundleContent,
+    ) -> Result<Self, RoomKeyBundleError> {
+        let mut reader = ciphertext;
+        let mut decryptor = AttachmentDecryptor::new(&mut reader, content.file.clone().into())?;
+        let mut plaintext = Vec::new();
+        decryptor.read_to_end(&mut plaintext)?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /// Split the bundle into multiple bundles whose serialized JSON is at most
+    /// `max_chunk_bytes` long, so they can be uploaded separately if the
+    /// bundle is too big for the media repository.
+    ///
+    /// A key which doesn't fit in `max_chunk_bytes` on its own ends up alone
+    /// in a chunk, so the chunks may exceed the limit in that case. At least
+    /// one chunk is returned, even if the bundle is empty.
+    ///
+    /// The chunks can be put back together using [`RoomKeyBundle::merge()`].
+    pub fn split(self, max_chunk_bytes: usize) -> Result<Vec<Self>, serde_json::Error> {
+        let mut splitter = BundleSplitter::new(max_chunk_bytes)?;
+
+        for key in self.room_keys {
+            let size = serde_json::to_vec(&key)?.len();
+            splitter.make_room(size).room_keys.push(key);
+        }
+
+        for withheld in self.withheld {
+            let size = serde_json::to_vec(&withheld)?.len();
+            splitter.make_room(size).withheld.push(withheld);
+        }
+
+        Ok(splitter.finish())
+    }
+
+    /// Put the chunks of a bundle split using [`RoomKeyBundle::split()`] back
+    /// together.
+    pub fn merge(chunks: impl IntoIterator<Item = Self>) -> Self {
+        chunks.into_iter().fold(Self::default(), |mut bundle, chunk| {
+            bundle.room_keys.extend(chunk.room_keys);
+            bundle.withheld.extend(chunk.withheld);
+            bundle
+        })
+    }
+}
+
+/// Helper for [`RoomKeyBundle::split()`], filling up one chunk after the
+/// other.
+struct BundleSplitter {
+    max_chunk_bytes: usize,
+
+    /// The size of the JSON of an empty bundle.
+    overhead: usize,
+
+    chunks: Vec<RoomKeyBundle>,
+    current: RoomKeyBundle,
+    current_size: usize,
+}
+
+impl BundleSplitter {
+    fn new(max_chunk_bytes: usize) -> Result<Self, serde_json::Error> {
+        let overhead = serde_json::to_vec(&RoomKeyBundle::default())?.len();
+
+        Ok(Self {
+            max_chunk_bytes,
+            overhead,
+            chunks: Vec::new(),
+            current: RoomKeyBundle::default(),
+            current_size: overhead,
+        })
+    }
+
+    /// Get the chunk an item with a JSON of the given size should be added to,
+    /// starting a new one if the current chunk is full.
+    fn make_room(&mut self, item_size: usize) -> &mut RoomKeyBundle {
+        // Every item is followed by a comma, except for the last one of a list.
+        let item_size = item_size + 1;
+
+        if !self.current.is_empty() && self.current_size + item_size > self.max_chunk_bytes {
+            self.chunks.push(mem::take(&mut self.current));
+            self.current_size = self.overhead;
+        }
+
+        self.current_size += item_size;
+        &mut self.current
+    }
+
+    fn finish(mut self) -> Vec<RoomKeyBundle> {
+        if !self.current.is_empty() || self.chunks.is_empty() {
+            self.chunks.push(self.current);
+        }
+
+        self.chunks
+    }
+}
+
+/// A [`RoomKeyBundle`] which was encrypted using [`RoomKeyBundle::encrypt()`].
+#[derive(Debug)]
+pub struct EncryptedRoomKeyBundle {
+    /// The encrypted bundle, which needs to be uploaded to the media
+    /// repository.
+    pub ciphertext: Vec<u8>,
+
+    /// The information needed to decrypt the bundle.
+    pub encryption_info: MediaEncryptionInfo,
+}
+
+impl EncryptedRoomKeyBundle {
+    /// Create the content of the `io.element.msc4268.room_key_bundle`
+    /// to-device message, once the bundle has been uploaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the bundle contains the keys of.
+    ///
+    /// * `url` - The MXC URI the encrypted bundle was uploaded to.
+    pub fn to_content(&self, room_id: OwnedRoomId, url: OwnedMxcUri) -> RoomKeyBundleContent {
+        let MediaEncryptionInfo { version, key, iv, hashes } = &self.encryption_info;
+
+        let file: EncryptedFile = EncryptedFileInit {
+            url,
+            key: key.clone(),
+            iv: iv.clone(),
+            hashes: hashes.clone(),
+            v: version.clone(),
+        }
+        .into();
+
+        RoomKeyBundleContent { room_id, file }
+    }
+}
+
+/// Error type for the encryption and decryption of a [`RoomKeyBundle`].
+#[derive(Debug, Error)]
+pub enum RoomKeyBundleError {
+    /// The bundle couldn't be serialized or deserialized.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The encryption info of the bundle is invalid.
+    #[error(transparent)]
+    Decryptor(#[from] DecryptorError),
+
+    /// The bundle couldn't be encrypted or decrypted, e.g. because the hash of
+    /// the ciphertext didn't match.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
 /// An [`InboundGroupSession`] for sharing as part of a [`RoomKeyBundle`].
@@ -123,6 +311,7 @@ impl From<ExportedRoomKey> for HistoricRoomKey {
             sender_claimed_keys,
             shared_history: _,
             forwarding_curve25519_key_chain: _,
+            sender_data: _,
         } = exported_room_key;
         HistoricRoomKey {
             algorithm,
@@ -137,25 +326,92 @@ impl From<ExportedRoomKey> for HistoricRoomKey {
 
 #[cfg(test)]
 mod tests {
+    use assert_matches2::assert_matches;
     use insta::assert_debug_snapshot;
-    use ruma::{owned_room_id, DeviceKeyAlgorithm};
+    use ruma::{owned_mxc_uri, owned_room_id, DeviceKeyAlgorithm};
     use vodozemac::{
         megolm::ExportedSessionKey, Curve25519PublicKey, Curve25519SecretKey, Ed25519SecretKey,
     };
 
+    use super::{RoomKeyBundle, RoomKeyBundleError};
     use crate::types::{room_history::HistoricRoomKey, EventEncryptionAlgorithm};
 
-    #[test]
-    fn test_historic_room_key_debug() {
-        let key = HistoricRoomKey {
+    fn historic_room_key() -> HistoricRoomKey {
+        HistoricRoomKey {
             algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
             room_id: owned_room_id!("!room:id"),
             sender_key: Curve25519PublicKey::from(&Curve25519SecretKey::from_slice(b"abcdabcdabcdabcdabcdabcdabcdabcd")),
             session_id: "id1234".to_owned(),
             session_key: ExportedSessionKey::from_base64("AQAAAAC2XHVzsMBKs4QCRElJ92CJKyGtknCSC8HY7cQ7UYwndMKLQAejXLh5UA0l6s736mgctcUMNvELScUWrObdflrHo+vth/gWreXOaCnaSxmyjjKErQwyIYTkUfqbHy40RJfEesLwnN23on9XAkch/iy8R2+Jz7B8zfG01f2Ow2SxPQFnAndcO1ZSD2GmXgedy6n4B20MWI1jGP2wiexOWbFS").unwrap(),
             sender_claimed_keys: vec![(DeviceKeyAlgorithm::Ed25519, Ed25519SecretKey::from_slice(b"abcdabcdabcdabcdabcdabcdabcdabcd").public_key().into())].into_iter().collect(),
-        };
+        }
+    }
+
+    #[test]
+    fn test_historic_room_key_debug() {
+        let key = historic_room_key();
 
         assert_debug_snapshot!(key);
     }
+
+    #[test]
+    fn test_room_key_bundle_encryption_roundtrip() {
+        let bundle = RoomKeyBundle { room_keys: vec![historic_room_key()], withheld: Vec::new() };
+
+        let encrypted = bundle.encrypt().unwrap();
+        let content =
+            encrypted.to_content(owned_room_id!("!room:id"), owned_mxc_uri!("mxc://localhost/abc"));
+        assert_eq!(content.file.url, "mxc://localhost/abc");
+
+        let decrypted = RoomKeyBundle::decrypt(&encrypted.ciphertext, &content).unwrap();
+        assert_eq!(decrypted.room_keys.len(), 1);
+        assert_eq!(decrypted.room_keys[0].session_id, "id1234");
+
+        // A tampered ciphertext is rejected.
+        let mut tampered = encrypted.ciphertext.clone();
+        tampered[0] ^= 1;
+        assert_matches!(
+            RoomKeyBundle::decrypt(&tampered, &content),
+            Err(RoomKeyBundleError::Io(_))
+        );
+    }
+
+    #[test]
+    fn test_room_key_bundle_split_and_merge() {
+        let mut keys: Vec<_> = (0..10).map(|_| historic_room_key()).collect();
+        for (i, key) in keys.iter_mut().enumerate() {
+            key.session_id = format!("session{i}");
+        }
+
+        let bundle = RoomKeyBundle { room_keys: keys, withheld: Vec::new() };
+        let bundle_size = serde_json::to_vec(&bundle).unwrap().len();
+
+        // A bundle which fits is kept as is.
+        let chunks = bundle.split(bundle_size).unwrap();
+        assert_eq!(chunks.len(), 1);
+
+        // Otherwise, every chunk stays below the limit.
+        let bundle = RoomKeyBundle::merge(chunks);
+        let chunks = bundle.split(bundle_size / 3).unwrap();
+        assert!(chunks.len() > 3);
+        for chunk in &chunks {
+            assert!(!chunk.is_empty());
+            assert!(serde_json::to_vec(chunk).unwrap().len() <= bundle_size / 3);
+        }
+
+        // And the chunks can be put back together, in order.
+        let merged = RoomKeyBundle::merge(chunks);
+        let session_ids: Vec<_> = merged.room_keys.iter().map(|key| &key.session_id).collect();
+        let expected: Vec<_> = (0..10).map(|i| format!("session{i}")).collect();
+        assert_eq!(session_ids, expected.iter().collect::<Vec<_>>());
+
+        // A key which doesn't fit on its own gets a chunk of its own.
+        let chunks = merged.split(1).unwrap();
+        assert_eq!(chunks.len(), 10);
+
+        // An empty bundle results in a single, empty, chunk.
+        let chunks = RoomKeyBundle::default().split(1).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_empty());
+    }
 }
@@ -0,0 +1,167 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Wrappers for secrets which need to be handed out as plain strings or
+//! bytes, like pickles, recovery keys and exported cross-signing keys.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// A string holding secret key material.
+///
+/// The string is zeroized when it's dropped, and isn't shown by the [`Debug`]
+/// implementation. It serializes as a plain string, so it can replace a
+/// [`String`] field without changing the serialization format.
+///
+/// The secret can be accessed with [`SecretString::expose_secret()`], copies
+/// of it made by the caller need to be zeroized by the caller.
+#[derive(Clone, Default, Zeroize, ZeroizeOnDrop, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Wrap the given secret.
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    /// Get the secret.
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self(secret.to_owned())
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_bytes().ct_eq(other.0.as_bytes()).into()
+    }
+}
+
+impl Eq for SecretString {}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(...)")
+    }
+}
+
+/// A byte buffer holding secret key material.
+///
+/// The bytes are zeroized when they're dropped, and aren't shown by the
+/// [`Debug`] implementation. They serialize as a plain byte sequence.
+///
+/// The secret can be accessed with [`SecretBytes::expose_secret()`], copies of
+/// it made by the caller need to be zeroized by the caller.
+#[derive(Clone, Default, Zeroize, ZeroizeOnDrop, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    /// Wrap the given secret.
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self(secret)
+    }
+
+    /// Get the secret.
+    pub fn expose_secret(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(secret: Vec<u8>) -> Self {
+        Self(secret)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(secret: &[u8]) -> Self {
+        Self(secret.to_vec())
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for SecretBytes {}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes(...)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{SecretBytes, SecretString};
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let string = SecretString::from("It's a secret to everybody");
+        let bytes = SecretBytes::from(b"It's a secret to everybody".as_slice());
+
+        assert_eq!(format!("{string:?}"), "SecretString(...)");
+        assert_eq!(format!("{bytes:?}"), "SecretBytes(...)");
+    }
+
+    #[test]
+    fn test_equality() {
+        let secret = SecretString::from("secret");
+
+        assert_eq!(secret, SecretString::from("secret"));
+        assert_ne!(secret, SecretString::from("secreT"));
+        assert_ne!(secret, SecretString::from("secret2"));
+        assert_eq!(SecretBytes::from(vec![1, 2, 3]), SecretBytes::from(vec![1, 2, 3]));
+        assert_ne!(SecretBytes::from(vec![1, 2, 3]), SecretBytes::from(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_serialization_is_transparent() {
+        let secret = SecretString::from("secret");
+        let value = serde_json::to_value(&secret).unwrap();
+        assert_eq!(value, json!("secret"));
+
+        let deserialized: SecretString = serde_json::from_value(value).unwrap();
+        assert_eq!(deserialized.expose_secret(), "secret");
+
+        let bytes = SecretBytes::from(vec![1, 2, 3]);
+        let value = serde_json::to_value(&bytes).unwrap();
+        assert_eq!(value, json!([1, 2, 3]));
+
+        let deserialized: SecretBytes = serde_json::from_value(value).unwrap();
+        assert_eq!(deserialized.expose_secret(), &[1, 2, 3]);
+    }
+}
@@ -14,11 +14,12 @@
 
 use std::num::NonZeroU8;
 
-use ruma::MilliSecondsSinceUnixEpoch;
+use ruma::{time::Instant, MilliSecondsSinceUnixEpoch};
 use time::{
     format_description::well_known::{iso8601, Iso8601},
     OffsetDateTime,
 };
+use tracing::Span;
 
 #[cfg(test)]
 pub(crate) fn json_convert<T, U>(value: &T) -> serde_json::Result<U>
@@ -64,6 +65,60 @@ pub fn timestamp_to_iso8601(ts: MilliSecondsSinceUnixEpoch) -> Option<String> {
     Some(dt.format(&Iso8601::<ISO8601_WITH_MILLIS>).unwrap())
 }
 
+/// The target of the `tracing` events emitted by [`LatencyTimer`] if the
+/// `metrics` feature is enabled.
+#[cfg(feature = "metrics")]
+pub(crate) const METRICS_TARGET: &str = "matrix_sdk_crypto::metrics";
+
+/// Measures how long one of the encryption hot paths takes.
+///
+/// When dropped, the elapsed time is recorded in the `duration_ms` field, and
+/// the count set using [`LatencyTimer::set_count()`] in the `count` field, of
+/// the span that was current when the timer was started. The span needs to
+/// declare those fields, e.g. using `#[instrument(fields(duration_ms, count))]`.
+///
+/// If the `metrics` feature is enabled, an event with the name of the
+/// operation, the duration and the count is emitted as well, using the
+/// [`METRICS_TARGET`] target, so it can be exported as a metric.
+pub(crate) struct LatencyTimer {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    operation: &'static str,
+    span: Span,
+    start: Instant,
+    count: Option<u64>,
+}
+
+impl LatencyTimer {
+    pub(crate) fn start(operation: &'static str) -> Self {
+        Self { operation, span: Span::current(), start: Instant::now(), count: None }
+    }
+
+    /// Set the number of items, e.g. events or requests, that were handled.
+    pub(crate) fn set_count(&mut self, count: usize) {
+        self.count = Some(count as u64);
+    }
+}
+
+impl Drop for LatencyTimer {
+    fn drop(&mut self) {
+        let duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+
+        self.span.record("duration_ms", duration_ms);
+        if let Some(count) = self.count {
+            self.span.record("count", count);
+        }
+
+        #[cfg(feature = "metrics")]
+        tracing::info!(
+            target: METRICS_TARGET,
+            parent: &self.span,
+            operation = self.operation,
+            duration_ms,
+            count = self.count,
+        );
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use ruma::{MilliSecondsSinceUnixEpoch, UInt};
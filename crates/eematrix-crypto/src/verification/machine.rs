@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future, sync::Arc};
 
+use futures_core::Stream;
+use futures_util::StreamExt;
 use matrix_sdk_common::locks::RwLock as StdRwLock;
 use ruma::{
     events::{
@@ -22,9 +24,10 @@ use ruma::{
     },
     serde::Raw,
     uint, DeviceId, EventId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId,
-    SecondsSinceUnixEpoch, TransactionId, UInt, UserId,
+    TransactionId, UInt, UserId,
 };
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tracing::{debug, info, instrument, trace, warn, Span};
 
 use super::{
@@ -48,6 +51,10 @@ pub struct VerificationMachine {
     pub(crate) store: VerificationStore,
     verifications: VerificationCache,
     requests: Arc<StdRwLock<HashMap<OwnedUserId, HashMap<String, VerificationRequest>>>>,
+    /// The sender side of a broadcast channel which sends out verification
+    /// requests whose state was changed by the machine itself, e.g. because
+    /// they timed out.
+    state_sender: broadcast::Sender<VerificationRequest>,
 }
 
 impl VerificationMachine {
@@ -60,6 +67,7 @@ impl VerificationMachine {
             store: VerificationStore { account, private_identity: identity, inner: store },
             verifications: VerificationCache::new(),
             requests: Default::default(),
+            state_sender: broadcast::Sender::new(10),
         }
     }
 
@@ -204,7 +212,7 @@ impl VerificationMachine {
         self.verifications.get_sas(user_id, flow_id)
     }
 
-    fn is_timestamp_valid(timestamp: MilliSecondsSinceUnixEpoch) -> bool {
+    fn is_timestamp_valid(&self, timestamp: MilliSecondsSinceUnixEpoch) -> bool {
         // The event should be ignored if the event is older than 10 minutes
         let old_timestamp_threshold: UInt = uint!(600);
         // The event should be ignored if the event is 5 minutes or more into the
@@ -212,7 +220,7 @@ impl VerificationMachine {
         let timestamp_threshold: UInt = uint!(300);
 
         let timestamp = timestamp.as_secs();
-        let now = SecondsSinceUnixEpoch::now().get();
+        let now = self.store.clock().now_secs().get();
 
         !(now.saturating_sub(timestamp) > old_timestamp_threshold
             || timestamp.saturating_sub(now) > timestamp_threshold)
@@ -236,10 +244,30 @@ impl VerificationMachine {
         self.verifications.outgoing_requests()
     }
 
+    /// Receive the verification requests whose state has been changed by the
+    /// machine itself, rather than by an event of the other side, as a
+    /// [`Stream`].
+    ///
+    /// Currently this is the case for requests which have been cancelled
+    /// because they timed out. Requests time out ten minutes after they were
+    /// created, and are cancelled the next time [`Self::garbage_collect()`]
+    /// runs.
+    pub fn verification_state_stream(&self) -> impl Stream<Item = VerificationRequest> {
+        BroadcastStream::new(self.state_sender.subscribe()).filter_map(|result| {
+            future::ready(match result {
+                Ok(request) => Some(request),
+                Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                    warn!("verification_state_stream missed {lag} updates");
+                    None
+                }
+            })
+        })
+    }
+
     pub fn garbage_collect(&self) -> Vec<Raw<AnyToDeviceEvent>> {
         let mut events = vec![];
 
-        let mut requests: Vec<OutgoingVerificationRequest> = {
+        let timed_out: Vec<VerificationRequest> = {
             let mut requests = self.requests.write();
 
             for user_verification in requests.values_mut() {
@@ -247,9 +275,21 @@ impl VerificationMachine {
             }
             requests.retain(|_, v| !v.is_empty());
 
-            requests.values().flatten().filter_map(|(_, v)| v.cancel_if_timed_out()).collect()
+            requests.values().flat_map(|r| r.values()).filter(|r| r.timed_out()).cloned().collect()
         };
 
+        let mut requests: Vec<OutgoingVerificationRequest> = Vec::new();
+
+        for request in timed_out {
+            requests.extend(request.cancel_if_timed_out());
+
+            // Only report the requests we actually cancelled.
+            if request.is_cancelled() {
+                // Nobody might be listening, so ignore the error.
+                let _ = self.state_sender.send(request);
+            }
+        }
+
         requests.extend(self.verifications.garbage_collect());
 
         for request in requests {
@@ -347,7 +387,7 @@ impl VerificationMachine {
                     return Ok(());
                 };
 
-                if !Self::is_timestamp_valid(timestamp) {
+                if !self.is_timestamp_valid(timestamp) {
                     info!(
                         from_device = r.from_device().as_str(),
                         ?timestamp,
@@ -531,14 +571,16 @@ impl VerificationMachine {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{sync::Arc, time::Duration};
 
+    use futures_util::{pin_mut, FutureExt, StreamExt};
     use matrix_sdk_test::async_test;
-    use ruma::TransactionId;
-    use tokio::sync::Mutex;
+    use ruma::{MilliSecondsSinceUnixEpoch, TransactionId};
+    use tokio::sync::{broadcast, Mutex};
 
     use super::{Sas, VerificationMachine};
     use crate::{
+        clock::MockClock,
         olm::PrivateCrossSigningIdentity,
         store::{CryptoStoreWrapper, MemoryStore},
         verification::{
@@ -557,6 +599,7 @@ mod tests {
             store,
             verifications: VerificationCache::new(),
             requests: Default::default(),
+            state_sender: broadcast::Sender::new(10),
         };
 
         (machine, bob_store)
@@ -669,6 +712,68 @@ mod tests {
         assert!(alice_machine.verifications.is_empty());
     }
 
+    #[cfg(not(target_os = "macos"))]
+    #[allow(unknown_lints, clippy::unchecked_duration_subtraction)]
+    #[async_test]
+    async fn test_timed_out_request_is_reported() {
+        use std::time::Duration;
+
+        use ruma::time::Instant;
+
+        let (machine, bob_store) = verification_machine().await;
+        let bob_id = bob_store.account.user_id.clone();
+
+        let (request, _) = machine.request_to_device_verification(&bob_id, vec![], None);
+
+        let stream = machine.verification_state_stream();
+        pin_mut!(stream);
+
+        machine.garbage_collect();
+        assert!(stream.next().now_or_never().is_none());
+        assert!(!request.is_cancelled());
+
+        // This line panics on macOS, so we're disabled for now.
+        machine
+            .requests
+            .write()
+            .get_mut(&bob_id)
+            .and_then(|r| r.get_mut(request.flow_id().as_str()))
+            .unwrap()
+            .set_creation_time(Instant::now() - Duration::from_secs(60 * 15));
+
+        machine.garbage_collect();
+
+        let timed_out = stream.next().now_or_never().flatten().expect("An update should be sent");
+        assert_eq!(timed_out.flow_id(), request.flow_id());
+        assert!(timed_out.is_cancelled());
+        assert!(request.is_cancelled());
+
+        // The request is gone now, so no further updates are sent.
+        machine.garbage_collect();
+        assert!(stream.next().now_or_never().is_none());
+    }
+
+    #[async_test]
+    async fn test_request_timeout_follows_clock() {
+        let (machine, bob_store) = verification_machine().await;
+        let bob_id = bob_store.account.user_id.clone();
+
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch::now());
+        machine.store.inner.set_clock(Arc::new(clock.clone()));
+
+        let (request, _) = machine.request_to_device_verification(&bob_id, vec![], None);
+
+        machine.garbage_collect();
+        assert!(!request.is_cancelled());
+
+        clock.advance(Duration::from_secs(60 * 15));
+        assert!(request.timed_out());
+        assert_eq!(request.time_remaining(), Duration::from_secs(0));
+
+        machine.garbage_collect();
+        assert!(request.is_cancelled());
+    }
+
     /// Test to ensure that we cancel both verifications if a second one gets
     /// started while another one is going on.
     #[async_test]
@@ -50,6 +50,7 @@ use tokio::sync::Mutex;
 use tracing::{debug, error, info, warn};
 
 use crate::{
+    clock::Clock,
     error::SignatureError,
     gossiping::{GossipMachine, GossipRequest},
     olm::{PrivateCrossSigningIdentity, StaticAccountData},
@@ -118,6 +119,10 @@ pub fn format_emojis(emojis: [Emoji; 7]) -> String {
 }
 
 impl VerificationStore {
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.clock()
+    }
+
     pub async fn get_device(
         &self,
         user_id: &UserId,
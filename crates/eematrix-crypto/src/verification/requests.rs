@@ -35,8 +35,7 @@ use ruma::{
     },
     time::Instant,
     to_device::DeviceIdOrAllDevices,
-    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId, TransactionId,
-    UserId,
+    DeviceId, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId,
 };
 #[cfg(feature = "qrcode")]
 use tracing::debug;
@@ -52,6 +51,7 @@ use super::{
     CancelInfo, Cancelled, FlowId, Verification, VerificationStore,
 };
 use crate::{
+    clock::Clock,
     olm::StaticAccountData,
     types::requests::{OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest},
     CryptoStoreError, DeviceData, Sas,
@@ -156,6 +156,7 @@ pub struct VerificationRequest {
     other_user_id: OwnedUserId,
     inner: SharedObservable<InnerRequest>,
     creation_time: Arc<Instant>,
+    clock: Arc<dyn Clock>,
     we_started: bool,
     recipient_devices: Arc<Vec<OwnedDeviceId>>,
 }
@@ -203,6 +204,7 @@ impl VerificationRequest {
         methods: Option<Vec<VerificationMethod>>,
     ) -> Self {
         let account = store.account.clone();
+        let clock = store.clock();
         let inner = SharedObservable::new(InnerRequest::Created(RequestState::new(
             cache.clone(),
             store,
@@ -217,7 +219,8 @@ impl VerificationRequest {
             flow_id: flow_id.into(),
             inner,
             other_user_id: other_user.into(),
-            creation_time: Instant::now().into(),
+            creation_time: clock.instant().into(),
+            clock,
             we_started: true,
             recipient_devices: recipient_devices.into(),
         }
@@ -240,7 +243,7 @@ impl VerificationRequest {
             self.account.device_id.clone(),
             self.flow_id().as_str().into(),
             methods,
-            MilliSecondsSinceUnixEpoch::now(),
+            self.clock.now(),
         );
 
         ToDeviceRequest::for_recipients(
@@ -327,7 +330,12 @@ impl VerificationRequest {
 
     /// Has the verification flow timed out.
     pub fn timed_out(&self) -> bool {
-        self.creation_time.elapsed() > VERIFICATION_TIMEOUT
+        self.clock.instant().saturating_duration_since(*self.creation_time) > VERIFICATION_TIMEOUT
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_creation_time(&mut self, time: Instant) {
+        self.creation_time = Arc::new(time);
     }
 
     /// Get the time left before the verification flow will time out, without
@@ -335,7 +343,7 @@ impl VerificationRequest {
     pub fn time_remaining(&self) -> Duration {
         self.creation_time
             .add(VERIFICATION_TIMEOUT)
-            .checked_duration_since(Instant::now())
+            .checked_duration_since(self.clock.instant())
             .unwrap_or(Duration::from_secs(0))
     }
 
@@ -477,6 +485,7 @@ impl VerificationRequest {
         device_data: DeviceData,
     ) -> Self {
         let account = store.account.clone();
+        let clock = store.clock();
 
         Self {
             verification_cache: cache.clone(),
@@ -494,7 +503,8 @@ impl VerificationRequest {
             other_user_id: sender.into(),
             flow_id: flow_id.into(),
             we_started: false,
-            creation_time: Instant::now().into(),
+            creation_time: clock.instant().into(),
+            clock,
             recipient_devices: vec![].into(),
         }
     }
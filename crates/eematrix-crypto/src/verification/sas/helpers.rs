@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
 
 use ruma::{
     events::{
@@ -32,6 +32,7 @@ use vodozemac::{sas::EstablishedSas, Curve25519PublicKey};
 
 use super::{sas_state::SupportedMacMethod, FlowId, OutgoingContent};
 use crate::{
+    clock::Clock,
     identities::{DeviceData, UserIdentityData},
     olm::StaticAccountData,
     verification::event_enums::{MacContent, StartContent},
@@ -44,6 +45,7 @@ pub struct SasIds {
     pub own_identity: Option<OwnUserIdentityData>,
     pub other_device: DeviceData,
     pub other_identity: Option<UserIdentityData>,
+    pub clock: Arc<dyn Clock>,
 }
 
 /// Calculate the commitment for a accept event from the public key and the
@@ -31,6 +31,7 @@ use super::{
     FlowId,
 };
 use crate::{
+    clock::Clock,
     identities::{DeviceData, UserIdentityData},
     olm::StaticAccountData,
     verification::{
@@ -58,6 +59,7 @@ pub enum InnerSas {
 }
 
 impl InnerSas {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         account: StaticAccountData,
         other_device: DeviceData,
@@ -66,6 +68,7 @@ impl InnerSas {
         transaction_id: FlowId,
         started_from_request: bool,
         short_auth_string: Option<Vec<ShortAuthenticationString>>,
+        clock: Arc<dyn Clock>,
     ) -> (InnerSas, OutgoingContent) {
         let sas = SasState::<Created>::new(
             account,
@@ -75,6 +78,7 @@ impl InnerSas {
             transaction_id,
             started_from_request,
             short_auth_string,
+            clock,
         );
         let content = sas.as_content();
         (InnerSas::Created(sas), content.into())
@@ -157,6 +161,7 @@ impl InnerSas {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn from_start_event(
         account: StaticAccountData,
         other_device: DeviceData,
@@ -165,6 +170,7 @@ impl InnerSas {
         own_identity: Option<OwnUserIdentityData>,
         other_identity: Option<UserIdentityData>,
         started_from_request: bool,
+        clock: Arc<dyn Clock>,
     ) -> Result<InnerSas, OutgoingContent> {
         match SasState::<Started>::from_start_event(
             account,
@@ -174,6 +180,7 @@ impl InnerSas {
             flow_id,
             content,
             started_from_request,
+            clock,
         ) {
             Ok(s) => Ok(InnerSas::Started(s)),
             Err(s) => Err(s.as_content()),
@@ -329,6 +329,7 @@ impl Sas {
             flow_id.clone(),
             request_handle.is_some(),
             short_auth_strings,
+            identities.store.clock(),
         );
 
         let account = identities.store.account.clone();
@@ -415,6 +416,7 @@ impl Sas {
             identities.own_identity.clone(),
             identities.identity_being_verified.clone(),
             request_handle.is_some(),
+            identities.store.clock(),
         )?;
 
         let account = identities.store.account.clone();
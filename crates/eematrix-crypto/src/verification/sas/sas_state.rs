@@ -54,6 +54,7 @@ use super::{
     OutgoingContent,
 };
 use crate::{
+    clock::Clock,
     identities::{DeviceData, UserIdentityData},
     olm::StaticAccountData,
     verification::{
@@ -510,7 +511,10 @@ impl<S: Clone> SasState<S> {
 
     /// Did our SAS verification time out.
     pub fn timed_out(&self) -> bool {
-        self.creation_time.elapsed() > MAX_AGE || self.last_event_time.elapsed() > MAX_EVENT_TIMEOUT
+        let now = self.ids.clock.instant();
+
+        now.saturating_duration_since(*self.creation_time) > MAX_AGE
+            || now.saturating_duration_since(*self.last_event_time) > MAX_EVENT_TIMEOUT
     }
 
     /// Is this verification happening inside a DM.
@@ -548,6 +552,9 @@ impl SasState<Created> {
     /// * `other_device` - The other device which we are going to verify.
     ///
     /// * `other_identity` - The identity of the other user if one exists.
+    ///
+    /// * `clock` - The clock used to measure the timeouts of the flow.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         account: StaticAccountData,
         other_device: DeviceData,
@@ -556,6 +563,7 @@ impl SasState<Created> {
         flow_id: FlowId,
         started_from_request: bool,
         short_auth_strings: Option<Vec<ShortAuthenticationString>>,
+        clock: Arc<dyn Clock>,
     ) -> SasState<Created> {
         Self::new_helper(
             flow_id,
@@ -565,9 +573,11 @@ impl SasState<Created> {
             other_identity,
             started_from_request,
             short_auth_strings,
+            clock,
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new_helper(
         flow_id: FlowId,
         account: StaticAccountData,
@@ -576,20 +586,23 @@ impl SasState<Created> {
         other_identity: Option<UserIdentityData>,
         started_from_request: bool,
         short_auth_strings: Option<Vec<ShortAuthenticationString>>,
+        clock: Arc<dyn Clock>,
     ) -> SasState<Created> {
         let sas = Sas::new();
         let our_public_key = sas.public_key();
 
         let protocol_definitions = the_protocol_definitions(short_auth_strings);
 
+        let now = clock.instant();
+
         SasState {
             inner: Arc::new(Mutex::new(Some(sas))),
             our_public_key,
-            ids: Box::new(SasIds { account, other_device, other_identity, own_identity }),
+            ids: Box::new(SasIds { account, other_device, other_identity, own_identity, clock }),
             verification_flow_id: flow_id.into(),
 
-            creation_time: Arc::new(Instant::now()),
-            last_event_time: Arc::new(Instant::now()),
+            creation_time: Arc::new(now),
+            last_event_time: Arc::new(now),
             started_from_request,
 
             state: Arc::new(Created { protocol_definitions }),
@@ -639,13 +652,14 @@ impl SasState<Created> {
 
         let start_content = self.as_content().into();
 
+        let now = self.ids.clock.instant();
         Ok(SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             ids: self.ids,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             started_from_request: self.started_from_request,
             state: Arc::new(Accepted {
                 start_content,
@@ -671,6 +685,9 @@ impl SasState<Started> {
     ///
     /// * `event` - The m.key.verification.start event that was sent to us by
     ///   the other side.
+    ///
+    /// * `clock` - The clock used to measure the timeouts of the flow.
+    #[allow(clippy::too_many_arguments)]
     pub fn from_start_event(
         account: StaticAccountData,
         other_device: DeviceData,
@@ -679,18 +696,20 @@ impl SasState<Started> {
         flow_id: FlowId,
         content: &StartContent<'_>,
         started_from_request: bool,
+        clock: Arc<dyn Clock>,
     ) -> Result<SasState<Started>, SasState<Cancelled>> {
         let flow_id = Arc::new(flow_id);
 
         let sas = Sas::new();
         let our_public_key = sas.public_key();
+        let now = clock.instant();
 
         let canceled = || SasState {
             inner: Arc::new(Mutex::new(None)),
             our_public_key,
 
-            creation_time: Arc::new(Instant::now()),
-            last_event_time: Arc::new(Instant::now()),
+            creation_time: Arc::new(now),
+            last_event_time: Arc::new(now),
             started_from_request,
 
             ids: Box::new(SasIds {
@@ -698,6 +717,7 @@ impl SasState<Started> {
                 other_device: other_device.clone(),
                 own_identity: own_identity.clone(),
                 other_identity: other_identity.clone(),
+                clock: clock.clone(),
             }),
 
             verification_flow_id: flow_id.clone(),
@@ -732,10 +752,10 @@ impl SasState<Started> {
             inner: Arc::new(Mutex::new(Some(sas))),
             our_public_key,
 
-            ids: Box::new(SasIds { account, other_device, other_identity, own_identity }),
+            ids: Box::new(SasIds { account, other_device, other_identity, own_identity, clock }),
 
-            creation_time: Arc::new(Instant::now()),
-            last_event_time: Arc::new(Instant::now()),
+            creation_time: Arc::new(now),
+            last_event_time: Arc::new(now),
             started_from_request,
 
             verification_flow_id: flow_id,
@@ -840,13 +860,14 @@ impl SasState<Started> {
 
         let start_content = self.as_content().into();
 
+        let now = self.ids.clock.instant();
         Ok(SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             ids: self.ids,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             started_from_request: self.started_from_request,
             state: Arc::new(Accepted {
                 start_content,
@@ -914,13 +935,14 @@ impl SasState<WeAccepted> {
         let established =
             self.handle_key_content(sender, content).map_err(|c| self.clone().cancel(true, c))?;
 
+        let now = self.ids.clock.instant();
         Ok(SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             ids: self.ids,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             started_from_request: self.started_from_request,
             state: Arc::new(KeyReceived {
                 sas: Mutex::new(established).into(),
@@ -955,13 +977,14 @@ impl SasState<Accepted> {
             calculate_commitment(their_public_key, &self.state.start_content.as_start_content());
 
         if self.state.commitment == commitment {
+            let now = self.ids.clock.instant();
             Ok(SasState {
                 inner: self.inner,
                 our_public_key: self.our_public_key,
                 ids: self.ids,
                 verification_flow_id: self.verification_flow_id,
                 creation_time: self.creation_time,
-                last_event_time: Instant::now().into(),
+                last_event_time: now.into(),
                 started_from_request: self.started_from_request,
                 state: Arc::new(KeyReceived {
                     sas: Mutex::new(established).into(),
@@ -976,13 +999,14 @@ impl SasState<Accepted> {
     }
 
     pub fn into_key_sent(self, request_id: &TransactionId) -> Option<SasState<KeySent>> {
+        let now = self.ids.clock.instant();
         (self.state.request_id == request_id).then(|| SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             ids: self.ids,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             started_from_request: self.started_from_request,
             state: Arc::new(KeySent {
                 we_started: true,
@@ -1041,13 +1065,14 @@ impl SasState<KeySent> {
             calculate_commitment(their_public_key, &self.state.start_content.as_start_content());
 
         if self.state.commitment == commitment {
+            let now = self.ids.clock.instant();
             Ok(SasState {
                 inner: self.inner,
                 our_public_key: self.our_public_key,
                 ids: self.ids,
                 verification_flow_id: self.verification_flow_id,
                 creation_time: self.creation_time,
-                last_event_time: Instant::now().into(),
+                last_event_time: now.into(),
                 started_from_request: self.started_from_request,
                 state: Arc::new(KeysExchanged {
                     sas: Mutex::new(established).into(),
@@ -1100,13 +1125,14 @@ impl SasState<KeyReceived> {
         self,
         request_id: &TransactionId,
     ) -> Option<SasState<KeysExchanged>> {
+        let now = self.ids.clock.instant();
         (self.state.request_id == request_id).then(|| SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             ids: self.ids,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             started_from_request: self.started_from_request,
             state: KeysExchanged {
                 sas: self.state.sas.clone(),
@@ -1182,12 +1208,13 @@ impl SasState<KeysExchanged> {
         )
         .map_err(|c| self.clone().cancel(true, c))?;
 
+        let now = self.ids.clock.instant();
         Ok(SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             ids: self.ids,
             started_from_request: self.started_from_request,
             state: Arc::new(MacReceived {
@@ -1246,11 +1273,12 @@ impl SasState<Confirmed> {
         )
         .map_err(|c| self.clone().cancel(true, c))?;
 
+        let now = self.ids.clock.instant();
         Ok(SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             verification_flow_id: self.verification_flow_id,
             started_from_request: self.started_from_request,
             ids: self.ids,
@@ -1290,11 +1318,12 @@ impl SasState<Confirmed> {
         )
         .map_err(|c| self.clone().cancel(true, c))?;
 
+        let now = self.ids.clock.instant();
         Ok(SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             verification_flow_id: self.verification_flow_id,
             started_from_request: self.started_from_request,
             ids: self.ids,
@@ -1452,11 +1481,12 @@ impl SasState<WaitingForDone> {
     ) -> Result<SasState<Done>, SasState<Cancelled>> {
         self.check_event(sender, content.flow_id()).map_err(|c| self.clone().cancel(true, c))?;
 
+        let now = self.ids.clock.instant();
         Ok(SasState {
             inner: self.inner,
             our_public_key: self.our_public_key,
             creation_time: self.creation_time,
-            last_event_time: Instant::now().into(),
+            last_event_time: now.into(),
             verification_flow_id: self.verification_flow_id,
             started_from_request: self.started_from_request,
             ids: self.ids,
@@ -1504,6 +1534,8 @@ impl SasState<Cancelled> {
 
 #[cfg(test)]
 mod tests {
+    use std::{sync::Arc, time::Duration};
+
     use matrix_sdk_test::async_test;
     use ruma::{
         device_id,
@@ -1517,12 +1549,13 @@ mod tests {
             ShortAuthenticationString,
         },
         serde::Base64,
-        user_id, DeviceId, TransactionId, UserId,
+        user_id, DeviceId, MilliSecondsSinceUnixEpoch, TransactionId, UserId,
     };
     use serde_json::json;
 
     use super::{Accepted, Created, SasState, Started, SupportedMacMethod, WeAccepted};
     use crate::{
+        clock::{MockClock, SystemClock},
         verification::{
             event_enums::{AcceptContent, KeyContent, MacContent, StartContent},
             FlowId,
@@ -1564,6 +1597,7 @@ mod tests {
             flow_id,
             false,
             None,
+            Arc::new(SystemClock),
         );
 
         let start_content = alice_sas.as_content();
@@ -1577,6 +1611,7 @@ mod tests {
             flow_id,
             &start_content.as_start_content(),
             false,
+            Arc::new(SystemClock),
         );
         let bob_sas = bob_sas
             .unwrap()
@@ -1635,6 +1670,31 @@ mod tests {
         let (_, _) = get_sas_pair(None);
     }
 
+    #[test]
+    fn test_sas_timeout_follows_clock() {
+        let alice = Account::with_device_id(alice_id(), alice_device_id());
+        let bob = Account::with_device_id(bob_id(), bob_device_id());
+        let clock = MockClock::new(MilliSecondsSinceUnixEpoch::now());
+
+        let alice_sas = SasState::<Created>::new(
+            alice.static_data().clone(),
+            DeviceData::from_account(&bob),
+            None,
+            None,
+            TransactionId::new().into(),
+            false,
+            None,
+            Arc::new(clock.clone()),
+        );
+        assert!(!alice_sas.timed_out());
+
+        clock.advance(Duration::from_secs(61));
+        assert!(
+            alice_sas.timed_out(),
+            "The flow should time out once no event came in for a minute"
+        );
+    }
+
     #[test]
     fn test_sas_accept() {
         let (alice, bob) = get_sas_pair(None);
@@ -1837,6 +1897,7 @@ mod tests {
             flow_id,
             false,
             None,
+            Arc::new(SystemClock),
         );
 
         let mut start_content = alice_sas.as_content();
@@ -1860,6 +1921,7 @@ mod tests {
             flow_id,
             &content,
             false,
+            Arc::new(SystemClock),
         )
         .expect_err("Didn't cancel on invalid MAC method");
 
@@ -1883,6 +1945,7 @@ mod tests {
             FlowId::ToDevice(flow_id.into()),
             &content,
             false,
+            Arc::new(SystemClock),
         )
         .expect_err("Didn't cancel on unknown sas method");
     }
@@ -25,6 +25,13 @@ mod v0_to_v5;
 mod v10_to_v11;
 mod v11_to_v12;
 mod v12_to_v13;
+mod v13_to_v14;
+mod v14_to_v15;
+mod v15_to_v17;
+mod v17_to_v18;
+mod v18_to_v19;
+mod v19_to_v20;
+mod v20_to_v21;
 mod v5_to_v7;
 mod v7;
 mod v7_to_v8;
@@ -163,6 +170,38 @@ pub async fn open_and_upgrade_db(
         v12_to_v13::schema_add(name).await?;
     }
 
+    if old_version < 14 {
+        v13_to_v14::schema_add(name).await?;
+    }
+
+    if old_version < 15 {
+        v14_to_v15::schema_add(name).await?;
+    }
+
+    if old_version < 16 {
+        v15_to_v17::schema_add(name).await?;
+    }
+    if old_version < 17 {
+        v15_to_v17::data_migrate(name, serializer).await?;
+        v15_to_v17::schema_bump(name).await?;
+    }
+
+    if old_version < 18 {
+        v17_to_v18::schema_add(name).await?;
+    }
+
+    if old_version < 19 {
+        v18_to_v19::schema_add(name).await?;
+    }
+
+    if old_version < 20 {
+        v19_to_v20::schema_add(name).await?;
+    }
+
+    if old_version < 21 {
+        v20_to_v21::schema_add(name).await?;
+    }
+
     // If you add more migrations here, you'll need to update
     // `tests::EXPECTED_SCHEMA_VERSION`.
 
@@ -267,7 +306,7 @@ mod tests {
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
     /// The schema version we expect after we open the store.
-    const EXPECTED_SCHEMA_VERSION: u32 = 13;
+    const EXPECTED_SCHEMA_VERSION: u32 = 21;
 
     /// Adjust this to test do a more comprehensive perf test
     const NUM_RECORDS_FOR_PERF: usize = 2_000;
@@ -422,6 +461,8 @@ mod tests {
             backed_up_to: -1,
             sender_key: None,
             sender_data_type: None,
+            room_id: None,
+            received_at: 0,
         };
         let session_js: JsValue = serde_wasm_bindgen::to_value(&session_dbo).unwrap();
 
@@ -0,0 +1,34 @@
+/*
+Copyright 2025 The Matrix.org Foundation C.I.C.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use indexed_db_futures::idb_object_store::IdbObjectStoreParameters;
+use web_sys::DomException;
+
+use crate::crypto_store::{keys, migrations::do_schema_upgrade, Result};
+
+/// Perform the schema upgrade v14 to v15, adding the
+/// `change_journal` store.
+pub(crate) async fn schema_add(name: &str) -> Result<(), DomException> {
+    do_schema_upgrade(name, 15, |db, _, _| {
+        // The keys of the journal are its sequence numbers. They are generated
+        // by the database, so concurrent writers never hand out the same one.
+        let mut params = IdbObjectStoreParameters::new();
+        params.auto_increment(true);
+        db.create_object_store_with_params(keys::CHANGE_JOURNAL, &params)?;
+        Ok(())
+    })
+    .await
+}
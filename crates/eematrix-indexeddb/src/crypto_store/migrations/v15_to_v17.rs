@@ -0,0 +1,109 @@
+/*
+Copyright 2025 The Matrix.org Foundation C.I.C.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Migration code that adds the room ID and the time at which we received the
+//! session to the records of `inbound_group_sessions3`, so that the sessions
+//! of a room can be looked up by index.
+
+use indexed_db_futures::{IdbKeyPath, IdbQuerySource};
+use matrix_sdk_crypto::olm::PickledInboundGroupSession;
+use tracing::{debug, info};
+use web_sys::{DomException, IdbTransactionMode};
+
+use crate::{
+    crypto_store::{
+        keys,
+        migrations::{do_schema_upgrade, MigrationDb},
+        InboundGroupSessionIndexedDbObject, Result,
+    },
+    serializer::IndexeddbSerializer,
+};
+
+/// Perform the schema upgrade v15 to v16, adding an index on
+/// `(room_id, received_at)` to `inbound_group_sessions3`.
+pub(crate) async fn schema_add(name: &str) -> Result<(), DomException> {
+    do_schema_upgrade(name, 16, |_, transaction, _| {
+        let object_store = transaction.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+
+        object_store.create_index(
+            keys::INBOUND_GROUP_SESSIONS_ROOM_ID_RECEIVED_AT_INDEX,
+            &IdbKeyPath::str_sequence(&["room_id", "received_at"]),
+        )?;
+
+        Ok(())
+    })
+    .await
+}
+
+/// Populate the `room_id` and `received_at` properties of the existing
+/// `inbound_group_sessions3` records.
+pub(crate) async fn data_migrate(name: &str, serializer: &IndexeddbSerializer) -> Result<()> {
+    let db = MigrationDb::new(name, 17).await?;
+
+    let txn = db.transaction_on_one_with_mode(
+        keys::INBOUND_GROUP_SESSIONS_V3,
+        IdbTransactionMode::Readwrite,
+    )?;
+
+    let store = txn.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+
+    let row_count = store.count()?.await?;
+    info!(row_count, "Adding the room ID to inbound group session records");
+
+    if let Some(cursor) = store.open_cursor()?.await? {
+        let mut idx = 0;
+        loop {
+            idx += 1;
+
+            if idx % 100 == 0 {
+                debug!("Migrating session {idx} of {row_count}");
+            }
+
+            // We only need the pickle to find out the room ID and the timestamp, the
+            // other properties of the record, such as `needs_backup`, are kept as they
+            // are.
+            let value = cursor.value();
+            let mut idb_object: InboundGroupSessionIndexedDbObject =
+                serde_wasm_bindgen::from_value(value.clone())?;
+            let pickle: PickledInboundGroupSession = serializer.maybe_decrypt_value(
+                serde_wasm_bindgen::from_value::<InboundGroupSessionIndexedDbObject>(value)?
+                    .pickled_session,
+            )?;
+
+            idb_object.room_id = Some(
+                serializer.encode_key_as_string(keys::INBOUND_GROUP_SESSIONS_V3, &pickle.room_id),
+            );
+            idb_object.received_at = pickle.received_at.map_or(0, |ts| ts.get().into());
+
+            cursor.update(&serde_wasm_bindgen::to_value(&idb_object)?)?;
+
+            if !cursor.continue_cursor()?.await? {
+                debug!("Migrated {idx} sessions.");
+                break;
+            }
+        }
+    }
+
+    txn.await.into_result()?;
+    Ok(())
+}
+
+/// Perform the schema upgrade v16 to v17, just bumping the schema version.
+pub(crate) async fn schema_bump(name: &str) -> Result<(), DomException> {
+    // Just bump the version number to 17 to demonstrate that we have run the data
+    // changes from data_migrate.
+    do_schema_upgrade(name, 17, |_, _, _| Ok(())).await
+}
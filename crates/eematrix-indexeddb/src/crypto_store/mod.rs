@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     sync::{Arc, RwLock},
 };
 
@@ -25,13 +25,16 @@ use js_sys::Array;
 use matrix_sdk_crypto::{
     olm::{
         Curve25519PublicKey, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
-        PickledInboundGroupSession, PrivateCrossSigningIdentity, SenderDataType, Session,
-        StaticAccountData,
+        PickledInboundGroupSession, PickledSession, PrivateCrossSigningIdentity, SenderDataType,
+        Session, StaticAccountData,
     },
     store::{
         types::{
-            BackupKeys, Changes, DehydratedDeviceKey, PendingChanges, RoomKeyCounts, RoomSettings,
-            StoredRoomKeyBundleData,
+            BackupKeys, ChangeJournalRecord, Changes, CompactionReport, DehydratedDeviceKey,
+            DeletedRoomCryptoState, PendingChanges, QuarantinedEntry, QuarantinedEntryKind,
+            RoomKeyCounts, RoomKeyInfo, RoomKeySummary, RoomSettings, SessionShareRecord,
+            ShredReport, StoredRoomKeyBundleData, VerificationAuditEntry,
+            VERIFICATION_AUDIT_LOG_CAPACITY,
         },
         CryptoStore, CryptoStoreError,
     },
@@ -41,8 +44,9 @@ use matrix_sdk_crypto::{
 };
 use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
-    events::secret::request::SecretName, DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
-    RoomId, TransactionId, UserId,
+    events::secret::request::SecretName, DeviceId, EventId, MilliSecondsSinceUnixEpoch,
+    OwnedDeviceId, OwnedEventId, OwnedRoomId, RoomId, SecondsSinceUnixEpoch, TransactionId, UInt,
+    UserId,
 };
 use sha2::Sha256;
 use tokio::sync::Mutex;
@@ -68,6 +72,8 @@ mod keys {
     pub const INBOUND_GROUP_SESSIONS_BACKED_UP_TO_INDEX: &str = "backed_up_to";
     pub const INBOUND_GROUP_SESSIONS_SENDER_KEY_INDEX: &str =
         "inbound_group_session_sender_key_sender_data_type_idx";
+    pub const INBOUND_GROUP_SESSIONS_ROOM_ID_RECEIVED_AT_INDEX: &str =
+        "inbound_group_session_room_id_received_at_idx";
 
     pub const OUTBOUND_GROUP_SESSIONS: &str = "outbound_group_sessions";
 
@@ -89,6 +95,18 @@ mod keys {
 
     pub const RECEIVED_ROOM_KEY_BUNDLES: &str = "received_room_key_bundles";
 
+    pub const MEGOLM_MESSAGE_INDICES: &str = "megolm_message_indices";
+
+    pub const CHANGE_JOURNAL: &str = "change_journal";
+
+    pub const QUARANTINED_ENTRIES: &str = "quarantined_entries";
+
+    pub const SESSION_SHARE_RECORDS: &str = "session_share_records";
+
+    pub const VERIFICATION_AUDIT_LOG: &str = "verification_audit_log";
+
+    pub const APP_DATA: &str = "app_data";
+
     // keys
     pub const STORE_CIPHER: &str = "store_cipher";
     pub const ACCOUNT: &str = "account";
@@ -197,6 +215,8 @@ type Result<A, E = IndexeddbCryptoStoreError> = std::result::Result<A, E>;
 /// Defines an operation to perform on the database.
 enum PendingOperation {
     Put { key: JsValue, value: JsValue },
+    /// Add a value to a store which generates its own keys.
+    Add(JsValue),
     Delete(JsValue),
 }
 
@@ -221,6 +241,10 @@ impl PendingStoreChanges<'_> {
         self.operations.push(PendingOperation::Put { key, value });
     }
 
+    fn add(&mut self, value: JsValue) {
+        self.operations.push(PendingOperation::Add(value));
+    }
+
     fn delete(&mut self, key: JsValue) {
         self.operations.push(PendingOperation::Delete(key));
     }
@@ -267,6 +291,9 @@ impl PendingIndexeddbChanges {
                     PendingOperation::Put { key, value } => {
                         object_store.put_key_val(&key, &value)?;
                     }
+                    PendingOperation::Add(value) => {
+                        object_store.add_val(&value)?;
+                    }
                     PendingOperation::Delete(key) => {
                         object_store.delete(&key)?;
                     }
@@ -478,6 +505,24 @@ impl IndexeddbCryptoStore {
         Ok(self.serializer.deserialize_value_from_bytes(&idb_object.request)?)
     }
 
+    /// Load the verification audit log of the given user, oldest entry first.
+    async fn load_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>> {
+        self.inner
+            .transaction_on_one_with_mode(
+                keys::VERIFICATION_AUDIT_LOG,
+                IdbTransactionMode::Readonly,
+            )?
+            .object_store(keys::VERIFICATION_AUDIT_LOG)?
+            .get(&self.serializer.encode_key(keys::VERIFICATION_AUDIT_LOG, user_id))?
+            .await?
+            .map(|value| self.serializer.deserialize_value(value).map_err(Into::into))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
     /// Process all the changes and do all encryption/serialization before the
     /// actual transaction.
     ///
@@ -675,6 +720,71 @@ impl IndexeddbCryptoStore {
             }
         }
 
+        if !changes.change_journal.is_empty() {
+            // The journal store generates the sequence numbers itself, inside
+            // the transaction which saves the changes.
+            let mut journal_store = indexeddb_changes.get(keys::CHANGE_JOURNAL);
+            for entry in &changes.change_journal {
+                journal_store.add(self.serializer.serialize_value(entry)?);
+            }
+        }
+
+        if !changes.session_share_records.is_empty() {
+            let mut share_record_store = indexeddb_changes.get(keys::SESSION_SHARE_RECORDS);
+            for record in &changes.session_share_records {
+                let key = self.serializer.encode_key(
+                    keys::SESSION_SHARE_RECORDS,
+                    (
+                        &record.room_id,
+                        &record.session_id,
+                        &record.user_id,
+                        &*record.device_id,
+                        record.shared_at.0.to_string(),
+                    ),
+                );
+                let value = self.serializer.serialize_value(&record)?;
+                share_record_store.put(key, value);
+            }
+        }
+
+        if !changes.verification_audit_log.is_empty() {
+            // Each user has a single, bounded, log, which gets rewritten. The
+            // `save_changes_lock` makes sure nobody else does so in between.
+            let mut audit_logs: BTreeMap<_, Vec<_>> = BTreeMap::new();
+            for entry in &changes.verification_audit_log {
+                audit_logs.entry(&entry.user_id).or_default().push(entry.clone());
+            }
+
+            let mut audit_log_store = indexeddb_changes.get(keys::VERIFICATION_AUDIT_LOG);
+            for (user_id, new_entries) in audit_logs {
+                let mut entries = self.load_verification_audit_log(user_id).await?;
+                entries.extend(new_entries);
+
+                let overflow = entries.len().saturating_sub(VERIFICATION_AUDIT_LOG_CAPACITY);
+                entries.drain(..overflow);
+
+                let key = self.serializer.encode_key(keys::VERIFICATION_AUDIT_LOG, user_id);
+                audit_log_store.put(key, self.serializer.serialize_value(&entries)?);
+            }
+        }
+
+        if !changes.app_data.is_empty() {
+            // The key of an entry is stored along with its value, so the keys of a
+            // namespace can be listed even if they are hashed.
+            let mut app_data_store = indexeddb_changes.get(keys::APP_DATA);
+            for change in &changes.app_data {
+                let key =
+                    self.serializer.encode_key(keys::APP_DATA, (&change.namespace, &change.key));
+                match &change.value {
+                    Some(value) => {
+                        let value = self.serializer.serialize_value(&(&change.key, value))?;
+                        app_data_store.put(key, value);
+                    }
+                    None => app_data_store.delete(key),
+                }
+            }
+        }
+
         Ok(indexeddb_changes)
     }
 }
@@ -815,6 +925,40 @@ impl_crypto_store! {
         Ok(users)
     }
 
+    async fn load_tracked_users_paged(
+        &self,
+        after: Option<&UserId>,
+        limit: usize,
+    ) -> Result<Vec<TrackedUser>> {
+        // The empty string is before all keys in Indexed DB - the first page starts there.
+        let after = JsValue::from_str(after.map_or("", |u| u.as_str()));
+        let range = IdbKeyRange::lower_bound_with_open(&after, true).expect("Key was not valid!");
+
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(keys::TRACKED_USERS, IdbTransactionMode::Readonly)?;
+        let os = tx.object_store(keys::TRACKED_USERS)?;
+
+        let mut users = Vec::new();
+
+        if let Some(cursor) = os.open_cursor_with_range(&range)?.await? {
+            while users.len() < limit {
+                let dirty = !matches!(cursor.value().into_serde(), Ok(false));
+
+                let user_id = cursor.key().and_then(|k| k.as_string()).map(UserId::parse);
+                if let Some(Ok(user_id)) = user_id {
+                    users.push(TrackedUser { user_id, dirty });
+                }
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        Ok(users)
+    }
+
     async fn get_outbound_group_session(
         &self,
         room_id: &RoomId,
@@ -893,6 +1037,211 @@ impl_crypto_store! {
         }
     }
 
+    async fn compact(&self) -> Result<CompactionReport> {
+        // The browser manages the storage of IndexedDB databases and reclaims the space
+        // by itself, and it doesn't let us measure the size of a single database.
+        Ok(CompactionReport::default())
+    }
+
+    async fn load_change_journal(&self, since: u64) -> Result<Vec<ChangeJournalRecord>> {
+        let range = IdbKeyRange::lower_bound_with_open(&JsValue::from_f64(since as f64), true)
+            .expect("Key was not valid!");
+
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(keys::CHANGE_JOURNAL, IdbTransactionMode::Readonly)?;
+        let store = tx.object_store(keys::CHANGE_JOURNAL)?;
+
+        let mut records = Vec::new();
+
+        // The sequence numbers are the generated keys of the store, so we need
+        // a cursor to get them together with the entries.
+        if let Some(cursor) = store.open_cursor_with_range(&range)?.await? {
+            while let Some(key) = cursor.key() {
+                let sequence_number = key.as_f64().expect("Journal keys should be numbers") as u64;
+                let entry = self.serializer.deserialize_value(cursor.value())?;
+                records.push(ChangeJournalRecord { sequence_number, entry });
+
+                cursor.continue_cursor()?.await?;
+            }
+        }
+
+        tx.await.into_result()?;
+
+        Ok(records)
+    }
+
+    async fn truncate_change_journal(&self, up_to: u64) -> Result<()> {
+        let range = IdbKeyRange::upper_bound(&JsValue::from_f64(up_to as f64))
+            .expect("Key was not valid!");
+
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(keys::CHANGE_JOURNAL, IdbTransactionMode::Readwrite)?;
+        tx.object_store(keys::CHANGE_JOURNAL)?.delete(&range)?;
+
+        tx.await.into_result().map_err(Into::into)
+    }
+
+    async fn shred(&self) -> Result<ShredReport> {
+        let stores = [
+            keys::CORE,
+            keys::BACKUP_KEYS,
+            keys::SESSION,
+            keys::INBOUND_GROUP_SESSIONS_V3,
+            keys::OUTBOUND_GROUP_SESSIONS,
+            keys::SECRETS_INBOX,
+            keys::RECEIVED_ROOM_KEY_BUNDLES,
+            keys::MEGOLM_MESSAGE_INDICES,
+            keys::CHANGE_JOURNAL,
+            keys::QUARANTINED_ENTRIES,
+            keys::SESSION_SHARE_RECORDS,
+            keys::VERIFICATION_AUDIT_LOG,
+            keys::APP_DATA,
+        ];
+        let tx = self.inner.transaction_on_multi_with_mode(&stores, IdbTransactionMode::Readwrite)?;
+
+        // The core store holds the account, the private identity, the
+        // dehydrated device pickle key, and the custom values, which all go.
+        // The store cipher lives in a separate database and is kept.
+        let core = tx.object_store(keys::CORE)?;
+        let account_deleted = core.get(&JsValue::from_str(keys::ACCOUNT))?.await?.is_some();
+        core.clear()?;
+
+        tx.object_store(keys::BACKUP_KEYS)?.clear()?;
+
+        let sessions = tx.object_store(keys::SESSION)?;
+        let sessions_deleted = sessions.count()?.await? as usize;
+        sessions.clear()?;
+
+        let inbound_group_sessions = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let inbound_group_sessions_deleted = inbound_group_sessions.count()?.await? as usize;
+        inbound_group_sessions.clear()?;
+
+        for store in [
+            keys::OUTBOUND_GROUP_SESSIONS,
+            keys::SECRETS_INBOX,
+            keys::RECEIVED_ROOM_KEY_BUNDLES,
+            keys::MEGOLM_MESSAGE_INDICES,
+            keys::CHANGE_JOURNAL,
+            keys::QUARANTINED_ENTRIES,
+            keys::SESSION_SHARE_RECORDS,
+            keys::VERIFICATION_AUDIT_LOG,
+            keys::APP_DATA,
+        ] {
+            tx.object_store(store)?.clear()?;
+        }
+
+        tx.await.into_result()?;
+
+        // The browser decides what happens to the deleted data on disk.
+        Ok(ShredReport {
+            account_deleted,
+            sessions_deleted,
+            inbound_group_sessions_deleted,
+            secure_delete: false,
+        })
+    }
+
+    async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+        custom_value_keys: &[String],
+    ) -> Result<DeletedRoomCryptoState> {
+        let _guard = self.save_changes_lock.lock().await;
+
+        let stores = [
+            keys::INBOUND_GROUP_SESSIONS_V3,
+            keys::MEGOLM_MESSAGE_INDICES,
+            keys::SESSION_SHARE_RECORDS,
+            keys::ROOM_SETTINGS,
+            keys::CORE,
+            keys::OUTBOUND_GROUP_SESSIONS,
+            keys::DIRECT_WITHHELD_INFO,
+            keys::RECEIVED_ROOM_KEY_BUNDLES,
+        ];
+        let tx = self.inner.transaction_on_multi_with_mode(&stores, IdbTransactionMode::Readwrite)?;
+
+        // The inbound group sessions are keyed by room and session ID.
+        let sessions = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let range = self.serializer.encode_to_range(keys::INBOUND_GROUP_SESSIONS_V3, room_id)?;
+        let room_keys = sessions
+            .get_all_with_key(&range)?
+            .await?
+            .into_iter()
+            .map(|value| {
+                self.deserialize_inbound_group_session(value)
+                    .map(|session| RoomKeyInfo::from(&session))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        sessions.delete(&range)?;
+
+        let range = self.serializer.encode_to_range(keys::MEGOLM_MESSAGE_INDICES, room_id)?;
+        tx.object_store(keys::MEGOLM_MESSAGE_INDICES)?.delete(&range)?;
+        let range = self.serializer.encode_to_range(keys::SESSION_SHARE_RECORDS, room_id)?;
+        tx.object_store(keys::SESSION_SHARE_RECORDS)?.delete(&range)?;
+
+        let settings_key = self.serializer.encode_key(keys::ROOM_SETTINGS, room_id);
+        tx.object_store(keys::ROOM_SETTINGS)?.delete(&settings_key)?;
+
+        let core = tx.object_store(keys::CORE)?;
+        for key in custom_value_keys {
+            core.delete(&JsValue::from_str(key))?;
+        }
+
+        let outbound = tx.object_store(keys::OUTBOUND_GROUP_SESSIONS)?;
+        let outbound_key = self.serializer.encode_key(keys::OUTBOUND_GROUP_SESSIONS, room_id);
+        let outbound_group_session_deleted = outbound.get(&outbound_key)?.await?.is_some();
+        outbound.delete(&outbound_key)?;
+
+        // The keys are hashed, so the entries of the room can only be found by
+        // looking at their values.
+        let mut withheld_deleted = 0;
+        if let Some(cursor) = tx.object_store(keys::DIRECT_WITHHELD_INFO)?.open_cursor()?.await? {
+            loop {
+                let event: RoomKeyWithheldEvent =
+                    self.serializer.deserialize_value(cursor.value())?;
+
+                if event.content.room_id() == Some(room_id) {
+                    cursor.delete()?;
+                    withheld_deleted += 1;
+                }
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        let mut room_key_bundle_senders = Vec::new();
+        let bundles = tx.object_store(keys::RECEIVED_ROOM_KEY_BUNDLES)?;
+        if let Some(cursor) = bundles.open_cursor()?.await? {
+            loop {
+                let bundle: StoredRoomKeyBundleData =
+                    self.serializer.deserialize_value(cursor.value())?;
+
+                if bundle.bundle_data.room_id == room_id {
+                    cursor.delete()?;
+                    room_key_bundle_senders.push(bundle.sender_user);
+                }
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+        room_key_bundle_senders.sort();
+
+        tx.await.into_result()?;
+
+        Ok(DeletedRoomCryptoState {
+            room_keys,
+            outbound_group_session_deleted,
+            withheld_deleted,
+            room_key_bundle_senders,
+        })
+    }
+
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
         if let Some(pickle) = self
             .inner
@@ -942,6 +1291,37 @@ impl_crypto_store! {
         }
     }
 
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>> {
+        let sender_keys: BTreeSet<_> = self
+            .inner
+            .transaction_on_one_with_mode(keys::SESSION, IdbTransactionMode::Readonly)?
+            .object_store(keys::SESSION)?
+            .get_all()?
+            .await?
+            .iter()
+            .map(|f| {
+                let pickle: PickledSession = self.serializer.deserialize_value(f)?;
+                Ok(pickle.sender_key.to_base64())
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(sender_keys.into_iter().collect())
+    }
+
+    async fn delete_sessions(&self, sender_key: &str, session_ids: &[String]) -> Result<()> {
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(keys::SESSION, IdbTransactionMode::Readwrite)?;
+        let store = tx.object_store(keys::SESSION)?;
+
+        for session_id in session_ids {
+            let key = self.serializer.encode_key(keys::SESSION, (sender_key, session_id));
+            store.delete_owned(key)?;
+        }
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -964,6 +1344,45 @@ impl_crypto_store! {
         }
     }
 
+    async fn delete_inbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+        session_ids: &[String],
+    ) -> Result<()> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[
+                keys::INBOUND_GROUP_SESSIONS_V3,
+                keys::MEGOLM_MESSAGE_INDICES,
+                keys::SESSION_SHARE_RECORDS,
+            ],
+            IdbTransactionMode::Readwrite,
+        )?;
+        let sessions = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let message_indices = tx.object_store(keys::MEGOLM_MESSAGE_INDICES)?;
+        let share_records = tx.object_store(keys::SESSION_SHARE_RECORDS)?;
+
+        for session_id in session_ids {
+            let key =
+                self.serializer.encode_key(keys::INBOUND_GROUP_SESSIONS_V3, (room_id, session_id));
+            sessions.delete_owned(key)?;
+
+            // The recorded message indices are keyed by room, session and
+            // index, remove the ones of this session.
+            let range = self
+                .serializer
+                .encode_to_range(keys::MEGOLM_MESSAGE_INDICES, (room_id, session_id))?;
+            message_indices.delete(&range)?;
+
+            // So are the share records, if it's one of our own sessions.
+            let range = self
+                .serializer
+                .encode_to_range(keys::SESSION_SHARE_RECORDS, (room_id, session_id))?;
+            share_records.delete(&range)?;
+        }
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
         const INBOUND_GROUP_SESSIONS_BATCH_SIZE: usize = 1000;
 
@@ -983,6 +1402,122 @@ impl_crypto_store! {
         ).await
     }
 
+    async fn quarantine_undecodable_inbound_group_sessions(&self) -> Result<Vec<QuarantinedEntry>> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::INBOUND_GROUP_SESSIONS_V3, keys::QUARANTINED_ENTRIES],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let quarantine = tx.object_store(keys::QUARANTINED_ENTRIES)?;
+        let mut entries = Vec::new();
+
+        if let Some(cursor) = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?.open_cursor()?.await? {
+            loop {
+                let value = cursor.value();
+
+                if let Err(error) = self.deserialize_inbound_group_session(value.clone()) {
+                    let value = js_sys::JSON::stringify(&value)
+                        .map(String::from)
+                        .unwrap_or_default()
+                        .into_bytes();
+                    let entry = QuarantinedEntry {
+                        kind: QuarantinedEntryKind::InboundGroupSession,
+                        value: value.into(),
+                        error: error.to_string(),
+                    };
+
+                    let key = cursor.key().ok_or(CryptoStoreError::Backend(
+                        "inbound_group_sessions cursor has no key".into(),
+                    ))?;
+                    quarantine.put_key_val(&key, &self.serializer.serialize_value(&entry)?)?;
+                    cursor.delete()?;
+                    entries.push(entry);
+                }
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        tx.await.into_result()?;
+
+        Ok(entries)
+    }
+
+    async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>> {
+        self
+            .inner
+            .transaction_on_one_with_mode(keys::QUARANTINED_ENTRIES, IdbTransactionMode::Readonly)?
+            .object_store(keys::QUARANTINED_ENTRIES)?
+            .get_all()?
+            .await?
+            .iter()
+            .map(|v| self.serializer.deserialize_value(v).map_err(Into::into))
+            .collect()
+    }
+
+    async fn restore_quarantined_entries(&self) -> Result<usize> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::INBOUND_GROUP_SESSIONS_V3, keys::QUARANTINED_ENTRIES],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let sessions = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let mut count = 0;
+
+        if let Some(cursor) = tx.object_store(keys::QUARANTINED_ENTRIES)?.open_cursor()?.await? {
+            loop {
+                let entry: QuarantinedEntry = self.serializer.deserialize_value(cursor.value())?;
+                let key = cursor.key().ok_or(CryptoStoreError::Backend(
+                    "quarantined_entries cursor has no key".into(),
+                ))?;
+
+                // Entries are quarantined under the key they were found at, with their value
+                // serialized as JSON.
+                let value: serde_json::Value = serde_json::from_slice(entry.value.expose_secret())?;
+                sessions.put_key_val(&key, &JsValue::from_serde(&value)?)?;
+                cursor.delete()?;
+                count += 1;
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        tx.await.into_result()?;
+
+        Ok(count)
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        // The empty string is before all keys in Indexed DB - first batch starts there.
+        let after = after
+            .map(|(room_id, session_id)| self.serializer.encode_key(keys::INBOUND_GROUP_SESSIONS_V3, (&*room_id, session_id.as_str())))
+            .unwrap_or("".into());
+        let range = IdbKeyRange::lower_bound_with_open(&after, true).expect("Key was not valid!");
+
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(
+                keys::INBOUND_GROUP_SESSIONS_V3,
+                IdbTransactionMode::Readonly,
+            )?;
+
+        let object_store = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let cursor = object_store.open_cursor_with_range(&range)?.await?;
+
+        let mut result = Vec::new();
+        fetch_batch(cursor, limit, &|value| self.deserialize_inbound_group_session(value), &mut result).await?;
+
+        Ok(result)
+    }
+
     async fn get_inbound_group_sessions_for_device_batch(
         &self,
         sender_key: Curve25519PublicKey,
@@ -1028,6 +1563,45 @@ impl_crypto_store! {
         Ok(result)
     }
 
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let room_id = self.serializer.encode_key(keys::INBOUND_GROUP_SESSIONS_V3, room_id);
+
+        // Sessions for which we don't know when we received them are stored with a
+        // `received_at` of 0, which is included in the range if no `since` is given.
+        let since = since.map_or(0, |ts| u64::from(ts.get()) as f64);
+        let lower_bound: Array = [room_id.clone(), since.into()].iter().collect();
+        let upper_bound: Array = [room_id, f64::INFINITY.into()].iter().collect();
+        let key = IdbKeyRange::bound(&lower_bound, &upper_bound).expect("Key was not valid!");
+
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(
+                keys::INBOUND_GROUP_SESSIONS_V3,
+                IdbTransactionMode::Readonly,
+            )?;
+
+        let store = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let idx = store.index(keys::INBOUND_GROUP_SESSIONS_ROOM_ID_RECEIVED_AT_INDEX)?;
+        let serialized_sessions = idx.get_all_with_key(&key)?.await?;
+
+        // Deserialize and decrypt after the transaction is complete.
+        let result = serialized_sessions.into_iter()
+            .filter_map(|v| match self.deserialize_inbound_group_session(v) {
+                Ok(session) => Some(session),
+                Err(e) => {
+                    warn!("Failed to deserialize inbound group session: {e}");
+                    None
+                }
+            })
+            .collect::<Vec<InboundGroupSession>>();
+
+        Ok(result)
+    }
+
     async fn inbound_group_session_counts(&self, _backup_version: Option<&str>) -> Result<RoomKeyCounts> {
         let tx = self
             .inner
@@ -1042,6 +1616,67 @@ impl_crypto_store! {
         Ok(RoomKeyCounts { total: all, backed_up: all - not_backed_up })
     }
 
+    async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>> {
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(
+                keys::INBOUND_GROUP_SESSIONS_V3,
+                IdbTransactionMode::Readonly,
+            )?;
+
+        let store = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let idx = store.index(keys::INBOUND_GROUP_SESSIONS_ROOM_ID_RECEIVED_AT_INDEX)?;
+
+        // The index is sorted by the (hashed) room ID, so the sessions of a room are
+        // next to each other. The room ID can only be found in the possibly encrypted
+        // pickle, so only the first pickle of every room is kept to be decrypted.
+        let mut rooms: Vec<(String, MaybeEncrypted, Vec<Option<SecondsSinceUnixEpoch>>)> =
+            Vec::new();
+
+        if let Some(cursor) = idx.open_cursor()?.await? {
+            loop {
+                let idb_object: InboundGroupSessionIndexedDbObject =
+                    serde_wasm_bindgen::from_value(cursor.value())?;
+
+                // Sessions without a room ID can't be grouped by room, they are skipped. The
+                // migration to v17 populated the room ID of all the existing sessions.
+                if let Some(room_id) = idb_object.room_id {
+                    // Sessions for which we don't know when we received them are stored with
+                    // a `received_at` of 0.
+                    let received_at = (idb_object.received_at != 0).then(|| {
+                        SecondsSinceUnixEpoch(UInt::new_saturating(idb_object.received_at))
+                    });
+
+                    match rooms.last_mut() {
+                        Some((last_room_id, _, timestamps)) if *last_room_id == room_id => {
+                            timestamps.push(received_at);
+                        }
+                        _ => rooms.push((room_id, idb_object.pickled_session, vec![received_at])),
+                    }
+                }
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        tx.await.into_result()?;
+
+        let mut summaries = rooms
+            .into_iter()
+            .map(|(_, pickled_session, timestamps)| {
+                let pickle: PickledInboundGroupSession =
+                    self.serializer.maybe_decrypt_value(pickled_session)?;
+                Ok(RoomKeySummary::from_received_at(pickle.room_id, timestamps))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        summaries.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+
+        Ok(summaries)
+    }
+
     async fn inbound_group_sessions_for_backup(
         &self,
         _backup_version: &str,
@@ -1212,6 +1847,13 @@ impl_crypto_store! {
             .transpose()
     }
 
+    async fn get_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>> {
+        self.load_verification_audit_log(user_id).await
+    }
+
     async fn is_message_known(&self, hash: &OlmMessageHash) -> Result<bool> {
         Ok(self
             .inner
@@ -1332,6 +1974,14 @@ impl_crypto_store! {
         Ok(key)
     }
 
+    async fn delete_backup_decryption_key(&self) -> Result<()> {
+        self
+            .inner
+            .transaction_on_one_with_mode(keys::BACKUP_KEYS, IdbTransactionMode::Readwrite)?
+            .object_store(keys::BACKUP_KEYS)?
+            .delete(&JsValue::from_str(keys::RECOVERY_KEY_V1))?;
+        Ok(())
+    }
 
      async fn load_dehydrated_device_pickle_key(&self) -> Result<Option<DehydratedDeviceKey>> {
        if let Some(pickle) = self
@@ -1377,6 +2027,62 @@ impl_crypto_store! {
         }
     }
 
+    async fn record_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        let key = self
+            .serializer
+            .encode_key(keys::MEGOLM_MESSAGE_INDICES, (room_id, session_id, message_index));
+        let txn = self.inner.transaction_on_one_with_mode(
+            keys::MEGOLM_MESSAGE_INDICES,
+            IdbTransactionMode::Readwrite,
+        )?;
+        let object_store = txn.object_store(keys::MEGOLM_MESSAGE_INDICES)?;
+
+        let recorded = match object_store.get(&key)?.await? {
+            Some(value) => Some(self.serializer.deserialize_value(value)?),
+            None => {
+                object_store.put_key_val(&key, &self.serializer.serialize_value(&event_id)?)?;
+                None
+            }
+        };
+
+        txn.await.into_result()?;
+
+        Ok(recorded)
+    }
+
+    async fn get_session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>> {
+        let range = self
+            .serializer
+            .encode_to_range(keys::SESSION_SHARE_RECORDS, (room_id, session_id))?;
+        let mut records = self
+            .inner
+            .transaction_on_one_with_mode(
+                keys::SESSION_SHARE_RECORDS,
+                IdbTransactionMode::Readonly,
+            )?
+            .object_store(keys::SESSION_SHARE_RECORDS)?
+            .get_all_with_key(&range)?
+            .await?
+            .iter()
+            .map(|value| self.serializer.deserialize_value(value))
+            .collect::<Result<Vec<SessionShareRecord>, _>>()?;
+
+        // The keys are hashed, so the records need to be put back in order.
+        records.sort_by_key(|record| record.shared_at);
+
+        Ok(records)
+    }
+
     async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
         let key = self.serializer.encode_key(keys::ROOM_SETTINGS, room_id);
         self
@@ -1434,13 +2140,40 @@ impl_crypto_store! {
         Ok(())
     }
 
+    async fn get_app_data(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .transaction_on_one_with_mode(keys::APP_DATA, IdbTransactionMode::Readonly)?
+            .object_store(keys::APP_DATA)?
+            .get(&self.serializer.encode_key(keys::APP_DATA, (namespace, key)))?
+            .await?
+            .map(|value| {
+                let (_, value): (String, Vec<u8>) = self.serializer.deserialize_value(value)?;
+                Ok(value)
+            })
+            .transpose()
+    }
+
+    async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>> {
+        let range = self.serializer.encode_to_range(keys::APP_DATA, namespace)?;
+        self.inner
+            .transaction_on_one_with_mode(keys::APP_DATA, IdbTransactionMode::Readonly)?
+            .object_store(keys::APP_DATA)?
+            .get_all_with_key(&range)?
+            .await?
+            .iter()
+            .map(|value| {
+                let (key, _): (String, Vec<u8>) = self.serializer.deserialize_value(value)?;
+                Ok(key)
+            })
+            .collect()
+    }
+
     async fn try_take_leased_lock(
         &self,
         lease_duration_ms: u32,
         key: &str,
         holder: &str,
     ) -> Result<bool> {
-        // As of 2023-06-23, the code below hasn't been tested yet.
         let key = JsValue::from_str(key);
         let txn = self
             .inner
@@ -1457,22 +2190,22 @@ impl_crypto_store! {
         let now_ts: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
         let expiration_ts = now_ts + lease_duration_ms as u64;
 
-        let prev = object_store.get(&key)?.await?;
-        match prev {
+        let acquired = match object_store.get(&key)?.await? {
             Some(prev) => {
                 let lease: Lease = self.serializer.deserialize_value(prev)?;
-                if lease.holder == holder || lease.expiration_ts < now_ts {
-                    object_store.put_key_val(&key, &self.serializer.serialize_value(&Lease { holder: holder.to_owned(), expiration_ts })?)?;
-                    Ok(true)
-                } else {
-                    Ok(false)
-                }
-            }
-            None => {
-                object_store.put_key_val(&key, &self.serializer.serialize_value(&Lease { holder: holder.to_owned(), expiration_ts })?)?;
-                Ok(true)
+                lease.holder == holder || lease.expiration_ts < now_ts
             }
+            None => true,
+        };
+
+        if acquired {
+            object_store.put_key_val(&key, &self.serializer.serialize_value(&Lease { holder: holder.to_owned(), expiration_ts })?)?;
         }
+
+        // Make sure the lease was written before we report it as taken.
+        txn.await.into_result()?;
+
+        Ok(acquired)
     }
 }
 
@@ -1767,6 +2500,22 @@ struct InboundGroupSessionIndexedDbObject {
     /// present for sessions received or modified since DB schema v12.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     sender_data_type: Option<u8>,
+
+    /// The (hashed) room ID of this session, base64-encoded.
+    ///
+    /// Added in database schema v16, and populated for the existing sessions
+    /// by the migration to v17.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room_id: Option<String>,
+
+    /// When we received this session, in seconds since the Unix epoch, or `0`
+    /// if we don't know. Together with `room_id`, this allows us to find the
+    /// sessions of a room received since a given time.
+    ///
+    /// Added in database schema v16, and populated for the existing sessions
+    /// by the migration to v17.
+    #[serde(default)]
+    received_at: u64,
 }
 
 impl InboundGroupSessionIndexedDbObject {
@@ -1784,6 +2533,9 @@ impl InboundGroupSessionIndexedDbObject {
             session.sender_key().to_base64(),
         );
 
+        let room_id =
+            serializer.encode_key_as_string(keys::INBOUND_GROUP_SESSIONS_V3, session.room_id());
+
         Ok(InboundGroupSessionIndexedDbObject {
             pickled_session: serializer.maybe_encrypt_value(session.pickle().await)?,
             session_id: Some(session_id),
@@ -1791,6 +2543,8 @@ impl InboundGroupSessionIndexedDbObject {
             backed_up_to: -1,
             sender_key: Some(sender_key),
             sender_data_type: Some(session.sender_data_type() as u8),
+            room_id: Some(room_id),
+            received_at: session.received_at().map_or(0, |ts| ts.get().into()),
         })
     }
 }
@@ -1839,6 +2593,8 @@ mod unit_tests {
             backed_up_to: -1,
             sender_key: None,
             sender_data_type: None,
+            room_id: None,
+            received_at: 0,
         }
     }
 
@@ -1957,7 +2713,7 @@ mod wasm_unit_tests {
 
 #[cfg(all(test, target_family = "wasm"))]
 mod tests {
-    use matrix_sdk_crypto::cryptostore_integration_tests;
+    use matrix_sdk_crypto::{cryptostore_integration_tests, cryptostore_integration_tests_time};
 
     use super::IndexeddbCryptoStore;
 
@@ -1982,12 +2738,13 @@ mod tests {
     }
 
     cryptostore_integration_tests!();
+    cryptostore_integration_tests_time!();
 }
 
 #[cfg(all(test, target_family = "wasm"))]
 mod encrypted_tests {
     use matrix_sdk_crypto::{
-        cryptostore_integration_tests,
+        cryptostore_integration_tests, cryptostore_integration_tests_time,
         olm::Account,
         store::{types::PendingChanges, CryptoStore},
         vodozemac::base64_encode,
@@ -2014,6 +2771,7 @@ mod encrypted_tests {
             .expect("Can't create a passphrase protected store")
     }
     cryptostore_integration_tests!();
+    cryptostore_integration_tests_time!();
 
     /// Test that we can migrate a store created with a passphrase, to being
     /// encrypted with a key instead.
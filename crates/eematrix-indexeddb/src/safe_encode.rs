@@ -315,6 +315,16 @@ impl SafeEncode for MxcUri {
     }
 }
 
+impl SafeEncode for u32 {
+    fn as_encoded_string(&self) -> String {
+        self.to_string()
+    }
+
+    fn as_secure_string(&self, _table_name: &str, _store_cipher: &StoreCipher) -> String {
+        self.to_string()
+    }
+}
+
 impl SafeEncode for usize {
     fn as_encoded_string(&self) -> String {
         self.to_string()
@@ -324,3 +334,26 @@ impl SafeEncode for usize {
         self.to_string()
     }
 }
+
+#[cfg(all(test, target_family = "wasm"))]
+mod tests {
+    use matrix_sdk_store_encryption::StoreCipher;
+    use matrix_sdk_test::async_test;
+
+    use super::SafeEncode;
+
+    wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
+    /// Test that every element of a five element tuple ends up in the hashed
+    /// key, so keys which differ only in their last element don't collide.
+    #[async_test]
+    async fn test_five_tuple_secure_string_uses_every_element() {
+        let cipher = StoreCipher::new().unwrap();
+
+        let first = ("a", "b", "c", "d", "e").as_secure_string("table", &cipher);
+        let second = ("a", "b", "c", "d", "f").as_secure_string("table", &cipher);
+
+        assert_ne!(first, second);
+        assert_eq!(first.split(super::KEY_SEPARATOR).count(), 5);
+    }
+}
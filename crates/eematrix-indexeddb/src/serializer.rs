@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::{mem, sync::Arc};
 
 use base64::{
     alphabet,
@@ -25,7 +25,7 @@ use matrix_sdk_store_encryption::{EncryptedValueBase64, StoreCipher};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use wasm_bindgen::JsValue;
 use web_sys::IdbKeyRange;
-use zeroize::Zeroizing;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::safe_encode::SafeEncode;
 
@@ -143,8 +143,16 @@ impl IndexeddbSerializer {
         &self,
         value: &impl Serialize,
     ) -> Result<JsValue, IndexeddbSerializerError> {
-        let serialized = self.maybe_encrypt_value(value)?;
-        Ok(serde_wasm_bindgen::to_value(&serialized)?)
+        let mut serialized = self.maybe_encrypt_value(value)?;
+        let js_value = serde_wasm_bindgen::to_value(&serialized);
+
+        // The unencrypted value may contain pickled secrets, don't leave it
+        // behind once it was handed over to indexeddb.
+        if let MaybeEncrypted::Unencrypted(unencrypted) = &mut serialized {
+            unencrypted.zeroize();
+        }
+
+        Ok(js_value?)
     }
 
     /// Encode the value for storage as a value in indexeddb.
@@ -180,14 +188,18 @@ impl IndexeddbSerializer {
         value: T,
     ) -> Result<MaybeEncrypted, CryptoStoreError> {
         // First serialize the object as JSON.
-        let serialized = serde_json::to_vec(&value).map_err(CryptoStoreError::backend)?;
+        let mut serialized =
+            Zeroizing::new(serde_json::to_vec(&value).map_err(CryptoStoreError::backend)?);
 
-        // Then either encrypt the JSON, or just base64-encode it.
+        // Then either encrypt the JSON, or just base64-encode it. The cipher
+        // zeroizes the JSON once it's encrypted.
         Ok(match &self.store_cipher {
             Some(cipher) => MaybeEncrypted::Encrypted(
-                cipher.encrypt_value_base64_data(serialized).map_err(CryptoStoreError::backend)?,
+                cipher
+                    .encrypt_value_base64_data(mem::take(&mut *serialized))
+                    .map_err(CryptoStoreError::backend)?,
             ),
-            None => MaybeEncrypted::Unencrypted(BASE64.encode(serialized)),
+            None => MaybeEncrypted::Unencrypted(BASE64.encode(&*serialized)),
         })
     }
 
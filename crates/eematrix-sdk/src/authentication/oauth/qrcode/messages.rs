@@ -304,9 +304,18 @@ mod test {
         assert_let!(
             QrAuthMessage::LoginSecrets(SecretsBundle { cross_signing, backup }) = &message
         );
-        assert_eq!(cross_signing.master_key, "rTtSv67XGS6k/rg6/yTG/m573cyFTPFRqluFhQY+hSw");
-        assert_eq!(cross_signing.self_signing_key, "4jbPt7jh5D2iyM4U+3IDa+WthgJB87IQN1ATdkau+xk");
-        assert_eq!(cross_signing.user_signing_key, "YkFKtkjcsTxF6UAzIIG/l6Nog/G2RigCRfWj3cjNWeM");
+        assert_eq!(
+            cross_signing.master_key.expose_secret(),
+            "rTtSv67XGS6k/rg6/yTG/m573cyFTPFRqluFhQY+hSw"
+        );
+        assert_eq!(
+            cross_signing.self_signing_key.expose_secret(),
+            "4jbPt7jh5D2iyM4U+3IDa+WthgJB87IQN1ATdkau+xk"
+        );
+        assert_eq!(
+            cross_signing.user_signing_key.expose_secret(),
+            "YkFKtkjcsTxF6UAzIIG/l6Nog/G2RigCRfWj3cjNWeM"
+        );
 
         assert_let!(Some(BackupSecrets::MegolmBackupV1Curve25519AesSha2(backup)) = backup);
         assert_eq!(backup.backup_version, "2");
@@ -1485,8 +1485,12 @@ impl Client {
             if let Some(inviter) =
                 pre_join_room_info.as_ref().and_then(|info| info.inviter.as_ref())
             {
-                crate::room::shared_room_history::maybe_accept_key_bundle(&room, inviter.user_id())
-                    .await?;
+                crate::room::shared_room_history::maybe_accept_key_bundle(
+                    &room,
+                    inviter.user_id(),
+                    |imported, total| trace!(imported, total, "Importing the room key bundle"),
+                )
+                .await?;
             }
         }
 
@@ -715,6 +715,7 @@ impl Backups {
                             // TODO: If we're verified and there are other devices besides us,
                             // request the new backup key over `m.secret.send`.
 
+                            olm_machine.backup_machine().mark_backup_version_outdated();
                             self.handle_deleted_backup_version(olm_machine).await?;
                         }
 
@@ -934,7 +935,7 @@ impl Backups {
         let secrets = olm_machine.store().get_secrets_from_inbox(&SecretName::RecoveryKey).await?;
 
         for secret in secrets {
-            if self.maybe_enable_backups(&secret.event.content.secret).await? {
+            if self.maybe_enable_backups(secret.event.content.secret.expose_secret()).await? {
                 break;
             }
         }
@@ -1146,6 +1147,40 @@ mod test {
         server.verify().await;
     }
 
+    #[async_test]
+    async fn test_backup_version_outdated_after_new_remote_version() {
+        use matrix_sdk_base::crypto::backups::BackupState as CryptoBackupState;
+
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let olm_machine = client.olm_machine().await.as_ref().unwrap().clone();
+        olm_machine
+            .store()
+            .import_exported_room_keys(vec![room_key()], |_, _| {})
+            .await
+            .expect("We should be able to import a room key");
+
+        let mut states = Box::pin(olm_machine.backup_machine().state_stream());
+
+        backup_disabling_test_body(
+            &client,
+            &server,
+            ResponseTemplate::new(403).set_body_json(json!({
+                "current_version": "42",
+                "errcode": "M_WRONG_ROOM_KEYS_VERSION",
+                "error": "Wrong backup version."
+            })),
+        )
+        .await;
+
+        // The backup got disabled because it's outdated.
+        assert_eq!(states.next().await, Some(CryptoBackupState::VersionOutdated));
+        assert_eq!(olm_machine.backup_machine().state(), CryptoBackupState::VersionOutdated);
+
+        server.verify().await;
+    }
+
     #[async_test]
     async fn test_when_a_backup_exists_then_fetch_exists_on_server_returns_true() {
         let server = MatrixMockServer::new().await;
@@ -290,7 +290,7 @@ impl Device {
     /// ```
     pub async fn verify(&self) -> Result<(), ManualVerifyError> {
         let request = self.inner.verify().await?;
-        self.client.send(request).await?;
+        self.client.send_signature_upload(request).await?;
 
         Ok(())
     }
@@ -98,6 +98,10 @@ pub enum ManualVerifyError {
     /// Error that happens when we try to upload the user or device signature.
     #[error(transparent)]
     Http(#[from] crate::HttpError),
+    /// Error that happens when we try to queue up or upload the user or device
+    /// signature, i.e. if there's an error with the storage layer.
+    #[error(transparent)]
+    Sdk(#[from] crate::Error),
     /// Error that happens when we try to sign the user or device.
     #[error(transparent)]
     Signature(#[from] matrix_sdk_base::crypto::SignatureError),
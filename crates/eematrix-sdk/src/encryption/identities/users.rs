@@ -366,7 +366,7 @@ impl UserIdentity {
             CryptoUserIdentity::Other(identity) => identity.verify().await?,
         };
 
-        self.client.send(request).await?;
+        self.client.send_signature_upload(request).await?;
 
         Ok(())
     }
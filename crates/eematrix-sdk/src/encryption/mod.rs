@@ -55,12 +55,14 @@ use ruma::{
     assign,
     events::{
         direct::DirectUserIdentifier,
-        room::{MediaSource, ThumbnailInfo},
+        room::{encrypted::OriginalSyncRoomEncryptedEvent, MediaSource, ThumbnailInfo},
     },
-    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, TransactionId, UserId,
+    serde::Raw,
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedRoomId, OwnedUserId, TransactionId,
+    UserId,
 };
 #[cfg(feature = "experimental-send-custom-to-device")]
-use ruma::{events::AnyToDeviceEventContent, serde::Raw, to_device::DeviceIdOrAllDevices};
+use ruma::{events::AnyToDeviceEventContent, to_device::DeviceIdOrAllDevices};
 use serde::Deserialize;
 use tokio::sync::{Mutex, RwLockReadGuard};
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
@@ -74,7 +76,7 @@ use self::{
     identities::{Device, DeviceUpdates, IdentityUpdates, UserDevices, UserIdentity},
     recovery::{Recovery, RecoveryState},
     secret_storage::SecretStorage,
-    tasks::{BackupDownloadTask, BackupUploadingTask, ClientTasks},
+    tasks::{BackupDownloadTask, BackupUploadingTask, ClientTasks, RoomKeyRequestTask},
     verification::{SasVerification, Verification, VerificationRequest},
 };
 use crate::{
@@ -140,6 +142,10 @@ impl EncryptionData {
         let mut tasks = self.tasks.lock();
         tasks.upload_room_keys = Some(BackupUploadingTask::new(weak_client.clone()));
 
+        if self.encryption_settings.automatic_room_key_requests {
+            tasks.request_room_keys = Some(RoomKeyRequestTask::new(weak_client.clone()));
+        }
+
         if self.encryption_settings.backup_download_strategy
             == BackupDownloadStrategy::AfterDecryptionFailure
         {
@@ -180,6 +186,13 @@ pub struct EncryptionSettings {
 
     /// Automatically create a backup version if no backup exists.
     pub auto_enable_backups: bool,
+
+    /// Automatically request the room keys of events we failed to decrypt from
+    /// our other devices.
+    ///
+    /// Requests are retried with an exponential backoff, up to a maximum number
+    /// of attempts, and cancelled once the room key is received.
+    pub automatic_room_key_requests: bool,
 }
 
 /// Settings for end-to-end encryption features.
@@ -302,7 +315,7 @@ impl CrossSigningResetHandle {
             }
         }
 
-        self.client.send(self.signatures_request.clone()).await?;
+        self.client.send_signature_upload(self.signatures_request.clone()).await?;
 
         Ok(())
     }
@@ -393,6 +406,29 @@ impl Client {
             .await?)
     }
 
+    /// Send out the given signature upload.
+    ///
+    /// The upload is queued up in the crypto store first, so if sending it
+    /// fails, it's retried together with the other outgoing requests until it
+    /// reaches the server.
+    pub(crate) async fn send_signature_upload(
+        &self,
+        request: UploadSignaturesRequest,
+    ) -> Result<()> {
+        let request_id = self
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::NoOlmMachine)?
+            .queue_signature_upload(request.clone())
+            .await?;
+
+        let response = self.send(request).await?;
+        self.mark_request_as_sent(&request_id, &response).await?;
+
+        Ok(())
+    }
+
     /// Query the server for users device keys.
     ///
     /// # Panics
@@ -1140,7 +1176,7 @@ impl Encryption {
             self.client.send_outgoing_request(req).await?;
         }
         self.client.send(upload_signing_keys_req).await?;
-        self.client.send(upload_signatures_req).await?;
+        self.client.send_signature_upload(upload_signatures_req).await?;
 
         Ok(())
     }
@@ -1216,7 +1252,7 @@ impl Encryption {
                 Err(error.into())
             }
         } else {
-            self.client.send(upload_signatures_req).await?;
+            self.client.send_signature_upload(upload_signatures_req).await?;
 
             Ok(None)
         }
@@ -1554,6 +1590,16 @@ impl Encryption {
         let olm_machine = self.client.base_client().olm_machine().await;
         let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
 
+        // The browser releases the Web Locks of a tab as soon as it's closed, instead of
+        // waiting for its lease to expire, so use the Web Locks API if it's available.
+        #[cfg(all(target_family = "wasm", feature = "js"))]
+        let lock = if matrix_sdk_common::store_locks::WebLocksBackingStore::is_supported() {
+            olm_machine.store().create_web_store_lock("cross_process_lock".to_owned(), lock_value)
+        } else {
+            olm_machine.store().create_store_lock("cross_process_lock".to_owned(), lock_value)
+        };
+
+        #[cfg(not(all(target_family = "wasm", feature = "js")))]
         let lock =
             olm_machine.store().create_store_lock("cross_process_lock".to_owned(), lock_value);
 
@@ -1700,12 +1746,40 @@ impl Encryption {
             if let Err(e) = this.backups().setup_and_resume().await {
                 error!("Couldn't setup and resume backups {e:?}");
             }
+
+            if this.settings().automatic_room_key_requests {
+                this.client.add_event_handler(Self::utd_room_key_request_handler);
+            }
             if let Err(e) = this.recovery().setup().await {
                 error!("Couldn't setup and resume recovery {e:?}");
             }
         }));
     }
 
+    /// Event handler which requests the room keys of the encrypted events
+    /// that we failed to decrypt from our other devices.
+    #[allow(clippy::unused_async)] // Because it's used as an event handler, which must be async.
+    pub(crate) async fn utd_room_key_request_handler(
+        event: Raw<OriginalSyncRoomEncryptedEvent>,
+        room: Room,
+        client: Client,
+    ) {
+        client.encryption().maybe_request_room_key(room.room_id().to_owned(), event);
+    }
+
+    /// Send a notification to the task responsible for automatic room key
+    /// requests that it should request the room key of the given event.
+    pub(crate) fn maybe_request_room_key(
+        &self,
+        room_id: OwnedRoomId,
+        event: Raw<OriginalSyncRoomEncryptedEvent>,
+    ) {
+        let tasks = self.client.inner.e2ee.tasks.lock();
+        if let Some(task) = tasks.request_room_keys.as_ref() {
+            task.trigger_request_for_utd_event(room_id, event);
+        }
+    }
+
     /// Waits for end-to-end encryption initialization tasks to finish, if any
     /// was running in the background.
     pub async fn wait_for_e2ee_initialization_tasks(&self) {
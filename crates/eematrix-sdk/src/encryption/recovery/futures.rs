@@ -18,6 +18,7 @@ use std::future::IntoFuture;
 
 use futures_core::Stream;
 use futures_util::{pin_mut, StreamExt};
+use matrix_sdk_base::crypto::types::SecretString;
 use matrix_sdk_common::boxed_into_future;
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tracing::{warn, Instrument, Span};
@@ -80,7 +81,7 @@ impl<'a> Enable<'a> {
 }
 
 impl<'a> IntoFuture for Enable<'a> {
-    type Output = Result<String>;
+    type Output = Result<SecretString>;
     boxed_into_future!(extra_bounds: 'a);
 
     fn into_future(self) -> Self::IntoFuture {
@@ -179,7 +180,7 @@ impl<'a> Reset<'a> {
 }
 
 impl<'a> IntoFuture for Reset<'a> {
-    type Output = Result<String>;
+    type Output = Result<SecretString>;
     boxed_into_future!(extra_bounds: 'a);
 
     fn into_future(self) -> Self::IntoFuture {
@@ -229,7 +230,7 @@ impl<'a> RecoverAndReset<'a> {
 }
 
 impl<'a> IntoFuture for RecoverAndReset<'a> {
-    type Output = Result<String>;
+    type Output = Result<SecretString>;
     boxed_into_future!(extra_bounds: 'a);
 
     fn into_future(self) -> Self::IntoFuture {
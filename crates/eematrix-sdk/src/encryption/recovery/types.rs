@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_base::crypto::store::types::RoomKeyCounts;
+use matrix_sdk_base::crypto::{store::types::RoomKeyCounts, types::SecretString};
 use ruma::{
     events::{EventContent, GlobalAccountDataEventType},
     exports::ruma_macros::EventContent,
@@ -78,7 +78,7 @@ pub enum EnableProgress {
     Done {
         /// The newly created recovery key.
         // TODO: Can I remove this from here? It seems a bit dumb.
-        recovery_key: String,
+        recovery_key: SecretString,
     },
 }
 
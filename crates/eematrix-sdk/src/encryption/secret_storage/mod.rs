@@ -260,7 +260,10 @@ impl SecretStorage {
     ///
     /// let secret_storage_key = secret_store.secret_storage_key();
     ///
-    /// println!("Your secret storage key is {secret_storage_key}, save it somewhere safe.");
+    /// println!(
+    ///     "Your secret storage key is {}, save it somewhere safe.",
+    ///     secret_storage_key.expose_secret()
+    /// );
     ///
     /// # anyhow::Ok(()) };
     /// ```
@@ -14,7 +14,9 @@
 
 use std::fmt;
 
-use matrix_sdk_base::crypto::{secret_storage::SecretStorageKey, CrossSigningKeyExport};
+use matrix_sdk_base::crypto::{
+    secret_storage::SecretStorageKey, types::SecretString, CrossSigningKeyExport,
+};
 use ruma::{
     events::{
         secret::request::SecretName, secret_storage::secret::SecretEventContent,
@@ -101,12 +103,8 @@ impl SecretStore {
     /// Export the [`SecretStorageKey`] of this [`SecretStore`] as a
     /// base58-encoded string as defined in the [spec].
     ///
-    /// *Note*: This returns a copy of the private key material of the
-    /// [`SecretStorageKey`] as a string. The caller needs to ensure that this
-    /// string is zeroized.
-    ///
     /// [spec]: https://spec.matrix.org/v1.8/client-server-api/#key-representation
-    pub fn secret_storage_key(&self) -> String {
+    pub fn secret_storage_key(&self) -> SecretString {
         self.key.to_base58()
     }
 
@@ -270,24 +268,35 @@ impl SecretStore {
     async fn get_cross_signing_keys(&self) -> Result<CrossSigningKeyExport> {
         let mut export = CrossSigningKeyExport::default();
 
-        export.master_key = self.get_secret(SecretName::CrossSigningMasterKey).await?;
-        export.self_signing_key = self.get_secret(SecretName::CrossSigningSelfSigningKey).await?;
-        export.user_signing_key = self.get_secret(SecretName::CrossSigningUserSigningKey).await?;
+        export.master_key =
+            self.get_secret(SecretName::CrossSigningMasterKey).await?.map(SecretString::new);
+        export.self_signing_key =
+            self.get_secret(SecretName::CrossSigningSelfSigningKey).await?.map(SecretString::new);
+        export.user_signing_key =
+            self.get_secret(SecretName::CrossSigningUserSigningKey).await?.map(SecretString::new);
 
         Ok(export)
     }
 
     async fn put_cross_signing_keys(&self, export: CrossSigningKeyExport) -> Result<()> {
         if let Some(master_key) = &export.master_key {
-            self.put_secret(SecretName::CrossSigningMasterKey, master_key).await?;
+            self.put_secret(SecretName::CrossSigningMasterKey, master_key.expose_secret()).await?;
         }
 
         if let Some(user_signing_key) = &export.user_signing_key {
-            self.put_secret(SecretName::CrossSigningUserSigningKey, user_signing_key).await?;
+            self.put_secret(
+                SecretName::CrossSigningUserSigningKey,
+                user_signing_key.expose_secret(),
+            )
+            .await?;
         }
 
         if let Some(self_signing_key) = &export.self_signing_key {
-            self.put_secret(SecretName::CrossSigningSelfSigningKey, self_signing_key).await?;
+            self.put_secret(
+                SecretName::CrossSigningSelfSigningKey,
+                self_signing_key.expose_secret(),
+            )
+            .await?;
         }
 
         Ok(())
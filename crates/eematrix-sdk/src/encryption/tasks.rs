@@ -12,9 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future,
+    sync::Arc,
+    time::Duration,
+};
 
-use matrix_sdk_common::failures_cache::FailuresCache;
+use futures_util::{pin_mut, StreamExt};
+use matrix_sdk_base::crypto::KeyRequestState;
+use matrix_sdk_common::{failures_cache::FailuresCache, timeout::timeout};
 use ruma::{
     events::room::encrypted::{EncryptedEventScheme, OriginalSyncRoomEncryptedEvent},
     serde::Raw,
@@ -43,6 +50,8 @@ pub(crate) struct ClientTasks {
     #[cfg(feature = "e2e-encryption")]
     pub(crate) download_room_keys: Option<BackupDownloadTask>,
     #[cfg(feature = "e2e-encryption")]
+    pub(crate) request_room_keys: Option<RoomKeyRequestTask>,
+    #[cfg(feature = "e2e-encryption")]
     pub(crate) update_recovery_state_after_backup: Option<JoinHandle<()>>,
     pub(crate) setup_e2ee: Option<JoinHandle<()>>,
 }
@@ -389,16 +398,362 @@ impl BackupDownloadTaskListenerState {
     }
 }
 
+/// Information about an undecryptable event whose room key should be
+/// requested from our other devices.
+#[derive(Debug)]
+struct UtdRoomKeyRequest {
+    /// The room in which the event was sent.
+    room_id: OwnedRoomId,
+
+    /// The event we could not decrypt.
+    event: Raw<OriginalSyncRoomEncryptedEvent>,
+
+    /// The unique ID of the room key that the event was encrypted with.
+    megolm_session_id: String,
+}
+
+impl UtdRoomKeyRequest {
+    pub fn to_room_key_info(&self) -> RoomKeyInfo {
+        (self.room_id.clone(), self.megolm_session_id.clone())
+    }
+}
+
+/// The task which automatically requests the room keys of undecryptable
+/// events from our other devices.
+pub(crate) struct RoomKeyRequestTask {
+    sender: mpsc::UnboundedSender<UtdRoomKeyRequest>,
+    #[allow(dead_code)]
+    join_handle: JoinHandle<()>,
+}
+
+#[cfg(feature = "e2e-encryption")]
+impl Drop for RoomKeyRequestTask {
+    fn drop(&mut self) {
+        #[cfg(not(target_family = "wasm"))]
+        self.join_handle.abort();
+    }
+}
+
+impl RoomKeyRequestTask {
+    /// The maximum number of room key requests we send for a single room key.
+    const MAX_ATTEMPTS: u32 = 5;
+
+    /// The time we wait for a room key after the first request.
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+
+    /// The maximum time we wait for a room key between two requests.
+    const MAX_BACKOFF: Duration = Duration::from_secs(60 * 30);
+
+    pub(crate) fn new(client: WeakClient) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let join_handle = spawn(async move {
+            Self::listen(client, receiver).await;
+        });
+
+        Self { sender, join_handle }
+    }
+
+    /// Trigger a room key request for the room key of the given event.
+    ///
+    /// Does nothing unless the event is encrypted using `m.megolm.v1.aes-sha2`.
+    /// Otherwise, tells the listener task to start requesting the room key,
+    /// unless it's already doing so.
+    pub(crate) fn trigger_request_for_utd_event(
+        &self,
+        room_id: OwnedRoomId,
+        event: Raw<OriginalSyncRoomEncryptedEvent>,
+    ) {
+        if let Ok(deserialized_event) = event.deserialize() {
+            if let EncryptedEventScheme::MegolmV1AesSha2(c) = deserialized_event.content.scheme {
+                let _ = self.sender.send(UtdRoomKeyRequest {
+                    room_id,
+                    event,
+                    megolm_session_id: c.session_id,
+                });
+            }
+        }
+    }
+
+    /// The time to wait for the room key after the given attempt, starting
+    /// at zero, before requesting it again.
+    fn backoff(attempt: u32) -> Duration {
+        Self::INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt)).min(Self::MAX_BACKOFF)
+    }
+
+    /// Listen for incoming [`UtdRoomKeyRequest`]s and process them.
+    ///
+    /// Only one task is requesting a given room key at any time, and room keys
+    /// for which we already used up all our attempts aren't requested again.
+    ///
+    /// This will keep running until either the request channel is closed, or
+    /// all other references to `Client` are dropped.
+    async fn listen(client: WeakClient, mut receiver: UnboundedReceiver<UtdRoomKeyRequest>) {
+        let state = Arc::new(Mutex::new(RoomKeyRequestTaskListenerState::new(client)));
+
+        while let Some(request) = receiver.recv().await {
+            let mut state_guard = state.lock().await;
+
+            if state_guard.client.strong_count() == 0 {
+                trace!("Client got dropped, shutting down the task");
+                break;
+            }
+
+            let room_key_info = request.to_room_key_info();
+            if !state_guard.active_tasks.contains_key(&room_key_info)
+                && !state_guard.exhausted_room_keys.contains(&room_key_info)
+            {
+                let task = spawn(Self::handle_request(state.clone(), request));
+                state_guard.active_tasks.insert(room_key_info, task);
+            }
+        }
+    }
+
+    /// Keep requesting the room key of the given event until it arrives, or
+    /// until we run out of attempts.
+    async fn handle_request(
+        state: Arc<Mutex<RoomKeyRequestTaskListenerState>>,
+        request: UtdRoomKeyRequest,
+    ) {
+        let room_key_info = request.to_room_key_info();
+        let mut attempt = 0;
+
+        let exhausted = loop {
+            let Some(client) = state.lock().await.client.get() else {
+                return;
+            };
+
+            // Subscribe before checking if the room key is available, so we can't miss it.
+            let Some(room_keys) = client.encryption().room_keys_received_stream().await else {
+                break false;
+            };
+
+            let machine_guard = client.olm_machine().await;
+            let Some(machine) = machine_guard.as_ref() else {
+                break false;
+            };
+
+            // If we get a store error here, we assume the key hasn't arrived.
+            if machine
+                .is_room_key_available(request.event.cast_ref(), &request.room_id)
+                .await
+                .unwrap_or(false)
+            {
+                debug!(?request, "The room key arrived, no need to request it");
+
+                // Cancel our request, if it's still waiting for an answer, the cancellation
+                // will be sent out with the other outgoing requests.
+                if let Err(e) = machine
+                    .cancel_room_key_request(&request.room_id, &request.megolm_session_id)
+                    .await
+                {
+                    warn!(?request, "Couldn't cancel the room key request: {e:?}");
+                }
+
+                break false;
+            }
+
+            if attempt == Self::MAX_ATTEMPTS {
+                debug!(?request, "Giving up on requesting the room key");
+                break true;
+            }
+
+            // The first time around, the room key might already have been requested when
+            // we failed to decrypt the event.
+            let already_requested = attempt == 0
+                && machine
+                    .room_key_request_status(&request.room_id, &request.megolm_session_id)
+                    .await
+                    .ok()
+                    .flatten()
+                    .is_some_and(|status| {
+                        matches!(status.state, KeyRequestState::Unsent | KeyRequestState::Pending)
+                    });
+
+            if already_requested {
+                debug!(?request, "The room key was already requested, waiting for it");
+                drop(machine_guard);
+            } else {
+                let outgoing_requests =
+                    machine.request_room_key(request.event.cast_ref(), &request.room_id).await;
+                drop(machine_guard);
+
+                match outgoing_requests {
+                    Ok((cancel, key_request)) => {
+                        debug!(?request, attempt, "Requesting the room key from our other devices");
+
+                        // The cancellation must be sent out before the new request, otherwise
+                        // our other devices ignore the new request.
+                        for outgoing_request in cancel.into_iter().chain([key_request]) {
+                            if let Err(e) = client.send_outgoing_request(outgoing_request).await {
+                                warn!(?request, "Couldn't send a room key request: {e:?}");
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!(?request, "Couldn't create a room key request: {e:?}");
+                    }
+                }
+            }
+
+            let delay = Self::backoff(attempt);
+            attempt += 1;
+
+            // Don't keep the client alive while we're waiting.
+            drop(client);
+
+            let room_key_received = async {
+                pin_mut!(room_keys);
+
+                while let Some(update) = room_keys.next().await {
+                    // If we lagged behind, we might have missed the room key, check the store.
+                    let Ok(received) = update else {
+                        return;
+                    };
+
+                    if received.iter().any(|room_key| {
+                        room_key.room_id == request.room_id
+                            && room_key.session_id == request.megolm_session_id
+                    }) {
+                        return;
+                    }
+                }
+
+                future::pending().await
+            };
+
+            // Either way, the next iteration will check if the room key is available.
+            let _ = timeout(room_key_received, delay).await;
+        };
+
+        let mut state = state.lock().await;
+
+        if exhausted {
+            state.exhausted_room_keys.insert(room_key_info.clone());
+        }
+
+        state.active_tasks.remove(&room_key_info);
+    }
+}
+
+/// The state for an active [`RoomKeyRequestTask`].
+struct RoomKeyRequestTaskListenerState {
+    /// Reference to the `Client`, which will be used to send out the room key
+    /// requests.
+    client: WeakClient,
+
+    /// Map from room key to the task requesting it.
+    active_tasks: BTreeMap<RoomKeyInfo, JoinHandle<()>>,
+
+    /// The room keys that we gave up requesting, after using up all our
+    /// attempts.
+    exhausted_room_keys: BTreeSet<RoomKeyInfo>,
+}
+
+impl RoomKeyRequestTaskListenerState {
+    /// Prepare a new `RoomKeyRequestTaskListenerState`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A reference to the `Client`, which is used to send out the
+    ///   room key requests.
+    fn new(client: WeakClient) -> Self {
+        Self { client, active_tasks: Default::default(), exhausted_room_keys: Default::default() }
+    }
+}
+
 #[cfg(all(test, not(target_family = "wasm")))]
 mod test {
     use matrix_sdk_test::async_test;
     use ruma::{event_id, room_id};
     use serde_json::json;
-    use wiremock::MockServer;
+    use wiremock::{
+        matchers::{method, path_regex},
+        Mock, MockServer, ResponseTemplate,
+    };
 
     use super::*;
     use crate::test_utils::logged_in_client;
 
+    #[test]
+    fn test_room_key_request_backoff() {
+        assert_eq!(RoomKeyRequestTask::backoff(0), Duration::from_secs(30));
+        assert_eq!(RoomKeyRequestTask::backoff(1), Duration::from_secs(60));
+        assert_eq!(RoomKeyRequestTask::backoff(2), Duration::from_secs(120));
+
+        // The delay between two requests is capped.
+        assert_eq!(RoomKeyRequestTask::backoff(10), RoomKeyRequestTask::MAX_BACKOFF);
+        assert_eq!(RoomKeyRequestTask::backoff(u32::MAX), RoomKeyRequestTask::MAX_BACKOFF);
+    }
+
+    // Test that the room key of an undecryptable event is requested from our other
+    // devices, and that it's only requested once if the event is reported twice.
+    #[async_test]
+    async fn test_room_key_request_task_requests_room_key_once() {
+        let room_id = room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost");
+        let session_id = "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA";
+
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        Mock::given(method("PUT"))
+            .and(path_regex(r"^/_matrix/client/.*/sendToDevice/m.room_key_request/.*"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let event: Raw<OriginalSyncRoomEncryptedEvent> = serde_json::from_value(json!({
+            "event_id": "$JbFHtZpEJiH8uaajZjPLz0QUZc1xtBR9rPGBOjF6WFM",
+            "origin_server_ts": 1698579035927u64,
+            "sender": "@example2:morpheus.localhost",
+            "type": "m.room.encrypted",
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "ciphertext": "AwgAEpABhetEzzZzyYrxtEVUtlJnZtJcURBlQUQJ9irVeklCTs06LwgTMQj61",
+                "device_id": "KIUVQQSDTM",
+                "sender_key": "LvryVyoCjdONdBCi2vvoSbI34yTOx7YrCFACUEKoXnc",
+                "session_id": session_id,
+            }
+        }))
+        .expect("We should be able to deserialize the encrypted event");
+
+        let task = RoomKeyRequestTask::new(WeakClient::from_client(&client));
+        task.trigger_request_for_utd_event(room_id.to_owned(), event.clone());
+        task.trigger_request_for_utd_event(room_id.to_owned(), event);
+
+        // Wait for the task to send out the room key request.
+        let mut state = None;
+
+        for _ in 0..50 {
+            state = client
+                .olm_machine()
+                .await
+                .as_ref()
+                .unwrap()
+                .room_key_request_status(room_id, session_id)
+                .await
+                .unwrap()
+                .map(|status| status.state);
+
+            if state == Some(KeyRequestState::Pending) {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(
+            state,
+            Some(KeyRequestState::Pending),
+            "The room key request should have been sent out"
+        );
+
+        // The second notification about the same event must not trigger another
+        // request, the mock expects a single one.
+        server.verify().await;
+    }
+
     // Test that, if backups are not enabled, we don't incorrectly mark a room key
     // as downloaded.
     #[async_test]
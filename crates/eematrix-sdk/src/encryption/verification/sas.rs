@@ -86,7 +86,7 @@ impl SasVerification {
         }
 
         if let Some(s) = signature {
-            self.client.send(s).await?;
+            self.client.send_signature_upload(s).await?;
         }
 
         Ok(())
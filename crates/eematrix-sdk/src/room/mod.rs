@@ -1489,6 +1489,9 @@ impl Room {
                     .encryption()
                     .backups()
                     .maybe_download_room_key(self.room_id().to_owned(), event.clone());
+                self.client
+                    .encryption()
+                    .maybe_request_room_key(self.room_id().to_owned(), event.clone());
                 Ok(TimelineEvent::from_utd(event.clone().cast(), utd_info))
             }
         }
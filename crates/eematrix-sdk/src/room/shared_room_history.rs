@@ -15,13 +15,24 @@
 use std::iter;
 
 use matrix_sdk_base::{
-    crypto::store::types::StoredRoomKeyBundleData,
+    crypto::store::types::{
+        RoomKeyBundleImportResult, RoomKeyBundleSenderPermissions, StoredRoomKeyBundleData,
+    },
     media::{MediaFormat, MediaRequestParameters},
 };
-use ruma::{events::room::MediaSource, OwnedUserId, UserId};
+use ruma::{
+    events::room::{member::MembershipState, MediaSource},
+    OwnedUserId, TransactionId, UserId,
+};
 use tracing::{info, instrument, warn};
 
-use crate::{crypto::types::events::room_key_bundle::RoomKeyBundleContent, Error, Result, Room};
+use crate::{
+    crypto::types::{
+        events::room_key_bundle::{RoomKeyBundleChunk, RoomKeyBundleContent},
+        room_history::RoomKeyBundle,
+    },
+    Error, Result, Room,
+};
 
 /// Share any shareable E2EE history in the given room with the given recipient,
 /// as per [MSC4268].
@@ -47,24 +58,50 @@ pub(super) async fn share_room_history(room: &Room, user_id: OwnedUserId) -> Res
     let olm_machine = client.olm_machine().await;
     let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
 
-    // 1. Construct the key bundle
-    let bundle = olm_machine.store().build_room_key_bundle(room.room_id()).await?;
+    // 1. Construct the key bundle, split into chunks which fit in a single upload
+    let max_chunk_bytes = match client.load_or_fetch_max_upload_size().await {
+        Ok(max_upload_size) => usize::try_from(u64::from(max_upload_size)).unwrap_or(usize::MAX),
+        Err(err) => {
+            warn!("Failed to fetch the maximum upload size, not splitting the key bundle: {err}");
+            usize::MAX
+        }
+    };
+    let chunks =
+        olm_machine.store().build_room_key_bundle_chunks(room.room_id(), max_chunk_bytes).await?;
 
-    if bundle.is_empty() {
+    if chunks.iter().all(RoomKeyBundle::is_empty) {
         info!("No keys to share");
         return Ok(());
     }
 
-    // 2. Upload to the server as an encrypted file
-    let json = serde_json::to_vec(&bundle)?;
-    let upload = client.upload_encrypted_file(&mut (json.as_slice())).await?;
+    // 2. Upload every chunk to the server as an encrypted file
+    let bundle_id = TransactionId::new().to_string();
+    let count = chunks.len();
+    let mut contents = Vec::with_capacity(count);
 
-    info!(
-        media_url = ?upload.url,
-        shared_keys = bundle.room_keys.len(),
-        withheld_keys = bundle.withheld.len(),
-        "Uploaded encrypted key blob"
-    );
+    for (index, bundle) in chunks.into_iter().enumerate() {
+        let json = serde_json::to_vec(&bundle)?;
+        let upload = client.upload_encrypted_file(&mut (json.as_slice())).await?;
+
+        info!(
+            media_url = ?upload.url,
+            chunk = index,
+            chunk_count = count,
+            shared_keys = bundle.room_keys.len(),
+            withheld_keys = bundle.withheld.len(),
+            "Uploaded encrypted key blob"
+        );
+
+        // A bundle which fits in a single upload is sent as is, so that clients which
+        // don't know about chunks can still use it.
+        let chunk =
+            (count > 1).then(|| RoomKeyBundleChunk { bundle_id: bundle_id.clone(), index, count });
+        contents.push(RoomKeyBundleContent {
+            room_id: room.room_id().to_owned(),
+            file: upload,
+            chunk,
+        });
+    }
 
     // 3. Ensure that we get a fresh list of devices for the invited user.
     let (req_id, request) = olm_machine.query_keys_for_users(iter::once(user_id.as_ref()));
@@ -76,23 +113,24 @@ pub(super) async fn share_room_history(room: &Room, user_id: OwnedUserId) -> Res
     // 4. Establish Olm sessions with all of the recipient's devices.
     client.claim_one_time_keys(iter::once(user_id.as_ref())).await?;
 
-    // 5. Send to-device messages to the recipient to share the keys.
-    let content = RoomKeyBundleContent { room_id: room.room_id().to_owned(), file: upload };
-    let requests = {
-        let olm_machine = client.olm_machine().await;
-        let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
-        olm_machine
-            .share_room_key_bundle_data(
-                &user_id,
-                &client.base_client().room_key_recipient_strategy,
-                content,
-            )
-            .await?
-    };
+    // 5. Send to-device messages to the recipient to share the keys, one per chunk.
+    for content in contents {
+        let requests = {
+            let olm_machine = client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+            olm_machine
+                .share_room_key_bundle_data(
+                    &user_id,
+                    &client.base_client().room_key_recipient_strategy,
+                    content,
+                )
+                .await?
+        };
 
-    for request in requests {
-        let response = client.send_to_device(&request).await?;
-        client.mark_request_as_sent(&request.txn_id, &response).await?;
+        for request in requests {
+            let response = client.send_to_device(&request).await?;
+            client.mark_request_as_sent(&request.txn_id, &response).await?;
+        }
     }
 
     Ok(())
@@ -110,9 +148,20 @@ pub(super) async fn share_room_history(room: &Room, user_id: OwnedUserId) -> Res
 /// * `inviter` - The user who invited us to the room and is expected to have
 ///   sent the room key bundle.
 ///
+/// * `progress_listener` - A closure that is called with the number of room
+///   keys imported so far and the total number of room keys in the bundle.
+///
+/// Returns a [`RoomKeyBundleImportResult`] describing which room keys of the
+/// bundle were imported, or why the bundle was rejected. Returns `None` if no
+/// complete bundle was available yet, or it couldn't be downloaded.
+///
 /// [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
-#[instrument(skip(room), fields(room_id = ?room.room_id(), bundle_sender))]
-pub(crate) async fn maybe_accept_key_bundle(room: &Room, inviter: &UserId) -> Result<()> {
+#[instrument(skip(room, progress_listener), fields(room_id = ?room.room_id(), bundle_sender))]
+pub(crate) async fn maybe_accept_key_bundle(
+    room: &Room,
+    inviter: &UserId,
+    progress_listener: impl Fn(usize, usize),
+) -> Result<Option<RoomKeyBundleImportResult>> {
     // TODO: retry this if it gets interrupted or it fails.
     // TODO: do this in the background.
 
@@ -121,55 +170,118 @@ pub(crate) async fn maybe_accept_key_bundle(room: &Room, inviter: &UserId) -> Re
 
     let Some(olm_machine) = olm_machine.as_ref() else {
         warn!("Not fetching room key bundle as the Olm machine is not available");
-        return Ok(());
+        return Ok(None);
     };
 
-    let Some(StoredRoomKeyBundleData { sender_user, sender_data, bundle_data }) =
+    let Some(bundle) =
         olm_machine.store().get_received_room_key_bundle_data(room.room_id(), inviter).await?
     else {
         // No bundle received (yet).
         // TODO: deal with the bundle arriving later (https://github.com/matrix-org/matrix-rust-sdk/issues/4926)
         // We need to check for all them bundles in the store when we create the client
         // object and we need to process them when they arrive.
-        return Ok(());
+        return Ok(None);
     };
 
-    tracing::Span::current().record("bundle_sender", sender_user.as_str());
+    tracing::Span::current().record("bundle_sender", bundle.sender_user.as_str());
+
+    // The sender data was stored when the bundle arrived, we might know more about
+    // the device which sent it by now.
+    let sender_data = olm_machine.store().refresh_room_key_bundle_sender_data(&bundle).await?;
+    let StoredRoomKeyBundleData { sender_user, bundle_data, .. } = bundle;
+
+    // The acceptance policy checks whether the sender was allowed to share the
+    // history of the room, using what our view of the room state says about them.
+    // There's no point in downloading the bundle if it's going to be rejected.
+    let sender_permissions = match room.get_member_no_sync(&sender_user).await? {
+        Some(member) => RoomKeyBundleSenderPermissions {
+            is_joined: *member.membership() == MembershipState::Join,
+            can_share_history: member.can_invite(),
+        },
+        None => RoomKeyBundleSenderPermissions::default(),
+    };
 
-    let bundle_content = client
-        .media()
-        .get_media_content(
-            &MediaRequestParameters {
-                source: MediaSource::Encrypted(Box::new(bundle_data.file)),
-                format: MediaFormat::File,
-            },
-            false,
+    if let Err(reason) = olm_machine.room_key_bundle_acceptance_policy().check(
+        &sender_user,
+        &sender_data,
+        sender_permissions,
+    ) {
+        info!(?reason, "Not downloading the rejected room key bundle");
+        let result = RoomKeyBundleImportResult { rejected: Some(reason), ..Default::default() };
+        return Ok(Some(result));
+    }
+
+    // If the bundle was split into chunks, we need all of them before we can import
+    // it.
+    let files = if bundle_data.chunk.is_some() {
+        let Some(chunks) = olm_machine
+            .store()
+            .get_received_room_key_bundle_chunks(room.room_id(), inviter)
+            .await?
+        else {
+            info!("Not all the chunks of the room key bundle were received yet");
+            return Ok(None);
+        };
+
+        chunks.into_iter().map(|content| content.file).collect()
+    } else {
+        vec![bundle_data.file]
+    };
+
+    let mut chunks = Vec::with_capacity(files.len());
+
+    for file in files {
+        let bundle_content = client
+            .media()
+            .get_media_content(
+                &MediaRequestParameters {
+                    source: MediaSource::Encrypted(Box::new(file)),
+                    format: MediaFormat::File,
+                },
+                false,
+            )
+            .await?;
+
+        match serde_json::from_slice(&bundle_content) {
+            Ok(chunk) => chunks.push(chunk),
+            Err(err) => {
+                warn!("Failed to deserialize room key bundle: {err}");
+                return Ok(None);
+            }
+        }
+    }
+
+    let result = olm_machine
+        .store()
+        .receive_room_key_bundle(
+            room.room_id(),
+            &sender_user,
+            &sender_data,
+            sender_permissions,
+            RoomKeyBundle::merge(chunks),
+            progress_listener,
         )
         .await?;
 
-    match serde_json::from_slice(&bundle_content) {
-        Ok(bundle) => {
-            olm_machine
-                .store()
-                .receive_room_key_bundle(
-                    room.room_id(),
-                    &sender_user,
-                    &sender_data,
-                    bundle,
-                    // TODO: Use the progress listener and expose an argument for it.
-                    |_, _| {},
-                )
-                .await?;
-        }
-        Err(err) => {
-            warn!("Failed to deserialize room key bundle: {err}");
-        }
+    // The chunks hold the keys of the uploaded files, which aren't needed anymore.
+    olm_machine.store().clear_received_room_key_bundle_chunks(room.room_id(), inviter).await?;
+
+    if let Some(reason) = &result.rejected {
+        info!(?reason, "Not importing the rejected room key bundle");
+        return Ok(Some(result));
     }
 
+    info!(
+        imported = result.imported.len(),
+        skipped = result.skipped.len(),
+        bad_room = result.bad_room.len(),
+        "Imported the room key bundle"
+    );
+
     // TODO: Now that we downloaded and imported the bundle, or the bundle was
     // invalid, we can safely remove the info about the bundle.
     // olm_machine.store().clear_received_room_key_bundle_data(room.room_id(),
     // user_id).await?;
 
-    Ok(())
+    Ok(Some(result))
 }
@@ -164,3 +164,29 @@ async fn test_reset_oauth() {
         "After the reset we have the cross-signing available.",
     );
 }
+
+#[async_test]
+async fn test_failed_signature_upload_is_queued_up() {
+    let server = MatrixMockServer::new().await;
+    let client = server.client_builder().build().await;
+
+    server.mock_upload_keys().ok().mock_once().mount().await;
+    server.mock_upload_cross_signing_keys().ok().expect(1).mount().await;
+    server.mock_upload_cross_signing_signatures().error500().expect(1).mount().await;
+
+    client
+        .encryption()
+        .bootstrap_cross_signing(None)
+        .await
+        .expect_err("Bootstrapping should fail if the signatures can't be uploaded");
+
+    // The signatures aren't lost, they are sent out with the other outgoing
+    // requests.
+    let olm_machine = client.olm_machine_for_testing().await;
+    let olm_machine = olm_machine.as_ref().unwrap();
+    let requests = olm_machine.outgoing_signature_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+
+    let outgoing_requests = olm_machine.outgoing_requests().await.unwrap();
+    assert!(outgoing_requests.iter().any(|r| r.request_id() == requests[0].request_id()));
+}
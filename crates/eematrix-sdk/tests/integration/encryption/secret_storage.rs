@@ -559,7 +559,7 @@ async fn test_restore_cross_signing_from_secret_store() {
 
     assert_eq!(
         SECRET_STORE_KEY,
-        secret_store.secret_storage_key(),
+        secret_store.secret_storage_key().expose_secret(),
         "We should be able to retrieve the secret storage key from the store",
     );
 
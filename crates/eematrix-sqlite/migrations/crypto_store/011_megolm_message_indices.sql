@@ -0,0 +1,10 @@
+CREATE TABLE "megolm_message_index"
+(
+    "room_id"       BLOB NOT NULL,
+    "session_id"    BLOB NOT NULL,
+    "message_index" INTEGER NOT NULL,
+    "event_id"      BLOB NOT NULL
+);
+
+CREATE UNIQUE INDEX "megolm_message_index_room_id_session_id_message_index_idx"
+    ON "megolm_message_index" ("room_id", "session_id", "message_index");
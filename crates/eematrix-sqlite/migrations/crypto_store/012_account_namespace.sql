@@ -0,0 +1,18 @@
+-- Tables which are listed in full need to know which account a row belongs to,
+-- point lookups are already isolated since their keys are prefixed with the
+-- account namespace. Rows written before namespaces were introduced belong to
+-- the default namespace, which is the empty string.
+ALTER TABLE "session"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE "inbound_group_session"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE "tracked_user"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE "key_requests"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX "inbound_group_session_account_session_id_idx"
+    ON "inbound_group_session" ("account", "session_id");
@@ -0,0 +1,14 @@
+-- Append-only journal of the objects saved in the store, allowing other
+-- processes to reload only what changed since they last looked.
+--
+-- AUTOINCREMENT makes sure that sequence numbers are never reused, even after
+-- the journal has been truncated.
+CREATE TABLE "change_journal"
+(
+    "sequence_number" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "account"         TEXT NOT NULL,
+    "data"            BLOB NOT NULL
+);
+
+CREATE INDEX "change_journal_account_sequence_number_idx"
+    ON "change_journal" ("account", "sequence_number");
@@ -0,0 +1,10 @@
+-- The time at which we received an inbound group session, in seconds since the
+-- Unix epoch, to help with `get_inbound_group_sessions_for_room`.
+--
+-- Sessions stored before this migration have a NULL timestamp, even if their
+-- pickle knows when they were received.
+ALTER TABLE "inbound_group_session"
+    ADD COLUMN "received_at" INTEGER;
+
+CREATE INDEX "inbound_group_session_account_room_id_received_at_idx"
+    ON "inbound_group_session" ("account", "room_id", "received_at");
@@ -0,0 +1,12 @@
+-- Entries which couldn't be decoded anymore, e.g. because they got corrupted,
+-- and which were moved out of their table so the rest of the store remains
+-- usable.
+CREATE TABLE "quarantined_entry"
+(
+    "id"      INTEGER PRIMARY KEY AUTOINCREMENT,
+    "account" TEXT NOT NULL,
+    "data"    BLOB NOT NULL
+);
+
+CREATE INDEX "quarantined_entry_account_idx"
+    ON "quarantined_entry" ("account");
@@ -0,0 +1,15 @@
+-- The rows of these tables are only looked up by hashed keys, record the
+-- account they belong to so they can be deleted when the account is shredded.
+-- Rows written before this migration belong to the default namespace, which is
+-- the empty string.
+ALTER TABLE "outbound_group_session"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE "secrets"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE "received_room_key_bundle"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE "megolm_message_index"
+    ADD COLUMN "account" TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,12 @@
+-- Append-only history of our outbound group sessions being shared with, or
+-- withheld from, devices.
+CREATE TABLE "session_share_record"
+(
+    "account"    TEXT NOT NULL,
+    "room_id"    BLOB NOT NULL,
+    "session_id" BLOB NOT NULL,
+    "data"       BLOB NOT NULL
+);
+
+CREATE INDEX "session_share_record_room_id_session_id_idx"
+    ON "session_share_record" ("room_id", "session_id");
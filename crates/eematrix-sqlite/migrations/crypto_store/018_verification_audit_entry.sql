@@ -0,0 +1,12 @@
+-- Bounded, append-only log of the verification state changes of user
+-- identities.
+CREATE TABLE "verification_audit_entry"
+(
+    "id"      INTEGER PRIMARY KEY AUTOINCREMENT,
+    "account" TEXT NOT NULL,
+    "user_id" BLOB NOT NULL,
+    "data"    BLOB NOT NULL
+);
+
+CREATE INDEX "verification_audit_entry_user_id_idx"
+    ON "verification_audit_entry" ("user_id");
@@ -0,0 +1,13 @@
+-- The namespaced application data of `Store::app_data()`. The key of an entry
+-- is stored along with its value in `data`, so the keys of a namespace can be
+-- listed even if the `key` column is hashed.
+CREATE TABLE "app_data"
+(
+    "account"   TEXT NOT NULL,
+    "namespace" BLOB NOT NULL,
+    "key"       BLOB NOT NULL,
+    "data"      BLOB NOT NULL
+);
+
+CREATE UNIQUE INDEX "app_data_namespace_key_idx"
+    ON "app_data" ("namespace", "key");
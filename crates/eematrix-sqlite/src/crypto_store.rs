@@ -14,47 +14,57 @@
 
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    fmt,
+    collections::{BTreeSet, HashMap},
+    fmt, iter, mem,
     path::Path,
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use async_trait::async_trait;
-use deadpool_sqlite::{Object as SqliteAsyncConn, Pool as SqlitePool, Runtime};
+use deadpool_sqlite::{
+    CreatePoolError, Hook, HookError, Object as SqliteAsyncConn, Pool as SqlitePool, PoolConfig,
+    Runtime, SyncWrapper,
+};
 use matrix_sdk_crypto::{
+    appservice::AppserviceCryptoStore,
     olm::{
-        InboundGroupSession, OutboundGroupSession, PickledInboundGroupSession,
+        InboundGroupSession, OutboundGroupSession, PickledInboundGroupSession, PickledSession,
         PrivateCrossSigningIdentity, SenderDataType, Session, StaticAccountData,
     },
     store::{
         types::{
-            BackupKeys, Changes, DehydratedDeviceKey, PendingChanges, RoomKeyCounts, RoomSettings,
-            StoredRoomKeyBundleData,
+            BackupKeys, ChangeJournalEntry, ChangeJournalRecord, Changes, CompactionReport,
+            DehydratedDeviceKey, DeletedRoomCryptoState, PendingChanges, QuarantinedEntry,
+            QuarantinedEntryKind, RoomKeyCounts, RoomKeyInfo, RoomKeySummary, RoomSettings,
+            SessionShareRecord, ShredReport, StoredRoomKeyBundleData, VerificationAuditEntry,
+            VERIFICATION_AUDIT_LOG_CAPACITY,
         },
-        CryptoStore,
+        CryptoStore, CryptoStoreError, DefaultStoreCipher, DynCryptoStore, IntoCryptoStore,
+        StoreCipher,
     },
     types::events::room_key_withheld::RoomKeyWithheldEvent,
     Account, DeviceData, GossipRequest, GossippedSecret, SecretInfo, TrackedUser, UserIdentityData,
 };
-use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
-    events::secret::request::SecretName, DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
-    RoomId, TransactionId, UserId,
+    events::secret::request::SecretName, DeviceId, EventId, MilliSecondsSinceUnixEpoch,
+    OwnedDeviceId, OwnedEventId, OwnedRoomId, RoomId, SecondsSinceUnixEpoch, TransactionId, UInt,
+    UserId,
 };
-use rusqlite::{named_params, params_from_iter, OptionalExtension};
-use serde::{de::DeserializeOwned, Serialize};
+use rusqlite::{backup::Backup, named_params, params_from_iter, OptionalExtension};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{fs, sync::Mutex};
 use tracing::{debug, instrument, warn};
 use vodozemac::Curve25519PublicKey;
+use zeroize::Zeroizing;
 
 use crate::{
     error::{Error, Result},
     utils::{
-        repeat_vars, Key, SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt,
-        SqliteKeyValueStoreConnExt,
+        apply_connection_config, repeat_vars, Key, SqliteAsyncConnExt,
+        SqliteKeyValueStoreAsyncConnExt, SqliteKeyValueStoreConnExt, SqliteTransactionExt,
     },
-    OpenStoreError, SqliteStoreConfig,
+    ConnectionConfig, OpenStoreError, SqliteStoreConfig,
 };
 
 /// The database name.
@@ -63,8 +73,9 @@ const DATABASE_NAME: &str = "matrix-sdk-crypto.sqlite3";
 /// An SQLite-based crypto store.
 #[derive(Clone)]
 pub struct SqliteCryptoStore {
-    store_cipher: Option<Arc<StoreCipher>>,
+    store_cipher: Option<Arc<dyn StoreCipher>>,
     pool: SqlitePool,
+    account_namespace: Option<Arc<str>>,
 
     // DB values cached in memory
     static_account: Arc<RwLock<Option<StaticAccountData>>>,
@@ -90,19 +101,91 @@ impl SqliteCryptoStore {
 
     /// Open the SQLite-based crypto store with the config open config.
     pub async fn open_with_config(config: SqliteStoreConfig) -> Result<Self, OpenStoreError> {
-        let SqliteStoreConfig { path, passphrase, pool_config, runtime_config } = config;
+        let SqliteStoreConfig {
+            path,
+            passphrase,
+            pool_config,
+            runtime_config,
+            account_namespace,
+            connection_config,
+        } = config;
+
+        validate_account_namespace(account_namespace.as_deref())?;
+        let pool = create_pool(&path, pool_config, connection_config).await?;
+
+        let this =
+            Self::open_with_pool(pool, passphrase.as_deref(), account_namespace.as_deref()).await?;
+        this.pool.get().await?.apply_runtime_config(runtime_config).await?;
 
-        fs::create_dir_all(&path).await.map_err(OpenStoreError::CreateDir)?;
+        Ok(this)
+    }
 
-        let mut config = deadpool_sqlite::Config::new(path.join(DATABASE_NAME));
-        config.pool = Some(pool_config);
+    /// Open the SQLite-based crypto store with the given config, using the
+    /// given [`StoreCipher`] to encrypt private data.
+    ///
+    /// This allows the encryption key to be kept outside of the application,
+    /// e.g. in a hardware-backed key store. The passphrase of the config, if
+    /// any, is ignored.
+    ///
+    /// The same cipher must be used every time the store is opened, otherwise
+    /// the data in the store can't be decrypted.
+    pub async fn open_with_cipher(
+        config: SqliteStoreConfig,
+        cipher: Arc<dyn StoreCipher>,
+    ) -> Result<Self, OpenStoreError> {
+        let SqliteStoreConfig {
+            path,
+            pool_config,
+            runtime_config,
+            account_namespace,
+            connection_config,
+            ..
+        } = config;
+
+        validate_account_namespace(account_namespace.as_deref())?;
+        let pool = create_pool(&path, pool_config, connection_config).await?;
 
-        let pool = config.create_pool(Runtime::Tokio1)?;
+        let conn = pool.get().await?;
+        Self::migrate(&conn).await?;
+        conn.apply_runtime_config(runtime_config).await?;
+
+        Ok(Self::new(pool, Some(cipher), account_namespace.as_deref()))
+    }
+
+    /// Copy the crypto database into the given directory, while the store is
+    /// in use.
+    ///
+    /// This uses SQLite's [online backup API], so the copy is a consistent
+    /// snapshot of the database even if the client keeps syncing in the
+    /// meantime. The copy can be opened with [`SqliteCryptoStore::open()`],
+    /// using the given path and the passphrase of this store.
+    ///
+    /// The whole database file is copied, including the data of other account
+    /// namespaces sharing it. A database already present in the given
+    /// directory is overwritten.
+    ///
+    /// [online backup API]: https://www.sqlite.org/backup.html
+    pub async fn backup_to(&self, path: impl AsRef<Path>) -> Result<(), CryptoStoreError> {
+        let path = path.as_ref();
+        fs::create_dir_all(path).await?;
+        let destination = path.join(DATABASE_NAME);
 
-        let this = Self::open_with_pool(pool, passphrase.as_deref()).await?;
-        this.pool.get().await?.apply_runtime_config(runtime_config).await?;
+        let conn = self.acquire().await?;
+        conn.interact(move |conn| {
+            let mut destination = rusqlite::Connection::open(destination)?;
+            let backup = Backup::new(conn, &mut destination)?;
 
-        Ok(this)
+            // Copy all the pages in a single step, otherwise the backup is restarted
+            // every time another connection writes to the database.
+            backup.run_to_completion(-1, Duration::from_millis(10), None)
+        })
+        .await
+        .unwrap()
+        .map_err(Error::from)?;
+
+        debug!(?path, "Backed up the crypto store");
+
+        Ok(())
     }
 
     /// Create an SQLite-based crypto store using the given SQLite database
@@ -110,30 +193,54 @@ impl SqliteCryptoStore {
     async fn open_with_pool(
         pool: SqlitePool,
         passphrase: Option<&str>,
+        account_namespace: Option<&str>,
     ) -> Result<Self, OpenStoreError> {
         let conn = pool.get().await?;
+        Self::migrate(&conn).await?;
+
+        let store_cipher: Option<Arc<dyn StoreCipher>> = match passphrase {
+            Some(p) => {
+                // Every account namespace gets its own cipher, so accounts sharing a
+                // database can use different passphrases.
+                let cipher_key = namespaced_key(account_namespace, "cipher");
+                let cipher = conn.get_or_create_store_cipher_at(&cipher_key, p).await?;
+                Some(Arc::new(DefaultStoreCipher::new(cipher)))
+            }
+            None => None,
+        };
 
+        Ok(Self::new(pool, store_cipher, account_namespace))
+    }
+
+    async fn migrate(conn: &SqliteAsyncConn) -> Result<(), OpenStoreError> {
         let version = conn.db_version().await?;
         debug!("Opened sqlite store with version {}", version);
-        run_migrations(&conn, version).await?;
+        run_migrations(conn, version).await?;
 
-        let store_cipher = match passphrase {
-            Some(p) => Some(Arc::new(conn.get_or_create_store_cipher(p).await?)),
-            None => None,
-        };
+        Ok(())
+    }
 
-        Ok(SqliteCryptoStore {
+    fn new(
+        pool: SqlitePool,
+        store_cipher: Option<Arc<dyn StoreCipher>>,
+        account_namespace: Option<&str>,
+    ) -> Self {
+        SqliteCryptoStore {
             store_cipher,
             pool,
+            account_namespace: account_namespace.map(Into::into),
             static_account: Arc::new(RwLock::new(None)),
             save_changes_lock: Default::default(),
-        })
+        }
     }
 
-    fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+    /// Encrypt the serialized value if the store has a cipher.
+    ///
+    /// The value may contain pickled secrets, so it's zeroized once it's
+    /// dropped, whether or not it was encrypted.
+    fn encode_value(&self, mut value: Zeroizing<Vec<u8>>) -> Result<Zeroizing<Vec<u8>>> {
         if let Some(key) = &self.store_cipher {
-            let encrypted = key.encrypt_value_data(value)?;
-            Ok(rmp_serde::to_vec_named(&encrypted)?)
+            Ok(Zeroizing::new(key.encrypt(mem::take(&mut *value))?))
         } else {
             Ok(value)
         }
@@ -141,16 +248,15 @@ impl SqliteCryptoStore {
 
     fn decode_value<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
         if let Some(key) = &self.store_cipher {
-            let encrypted = rmp_serde::from_slice(value)?;
-            let decrypted = key.decrypt_value_data(encrypted)?;
+            let decrypted = key.decrypt(value)?;
             Ok(Cow::Owned(decrypted))
         } else {
             Ok(Cow::Borrowed(value))
         }
     }
 
-    fn serialize_json(&self, value: &impl Serialize) -> Result<Vec<u8>> {
-        let serialized = serde_json::to_vec(value)?;
+    fn serialize_json(&self, value: &impl Serialize) -> Result<Zeroizing<Vec<u8>>> {
+        let serialized = Zeroizing::new(serde_json::to_vec(value)?);
         self.encode_value(serialized)
     }
 
@@ -159,8 +265,8 @@ impl SqliteCryptoStore {
         Ok(serde_json::from_slice(&decoded)?)
     }
 
-    fn serialize_value(&self, value: &impl Serialize) -> Result<Vec<u8>> {
-        let serialized = rmp_serde::to_vec_named(value)?;
+    fn serialize_value(&self, value: &impl Serialize) -> Result<Zeroizing<Vec<u8>>> {
+        let serialized = Zeroizing::new(rmp_serde::to_vec_named(value)?);
         self.encode_value(serialized)
     }
 
@@ -194,14 +300,39 @@ impl SqliteCryptoStore {
     }
 
     fn encode_key(&self, table_name: &str, key: impl AsRef<[u8]>) -> Key {
-        let bytes = key.as_ref();
+        let bytes = self.namespaced_bytes(key.as_ref());
         if let Some(store_cipher) = &self.store_cipher {
-            Key::Hashed(store_cipher.hash_key(table_name, bytes))
+            Key::Hashed(store_cipher.hash_key(table_name, &bytes))
         } else {
-            Key::Plain(bytes.to_owned())
+            Key::Plain(bytes.into_owned())
+        }
+    }
+
+    /// Prefix the given bytes with the account namespace of the store, if
+    /// there is one.
+    fn namespaced_bytes<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.account_namespace {
+            Some(namespace) => {
+                let mut namespaced = Vec::with_capacity(namespace.len() + 1 + bytes.len());
+                namespaced.extend_from_slice(namespace.as_bytes());
+                namespaced.push(NAMESPACE_SEPARATOR as u8);
+                namespaced.extend_from_slice(bytes);
+                Cow::Owned(namespaced)
+            }
+            None => Cow::Borrowed(bytes),
         }
     }
 
+    /// The key used in the key/value and lease lock tables for the given key.
+    fn kv_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        namespaced_key(self.account_namespace.as_deref(), key)
+    }
+
+    /// The value of the `account` column of the rows of this store.
+    fn account(&self) -> &str {
+        self.account_namespace.as_deref().unwrap_or_default()
+    }
+
     fn get_static_account(&self) -> Option<StaticAccountData> {
         self.static_account.read().unwrap().clone()
     }
@@ -211,11 +342,107 @@ impl SqliteCryptoStore {
     }
 }
 
-const DATABASE_VERSION: u8 = 10;
+const DATABASE_VERSION: u8 = 19;
+
+/// Separator between the account namespace and a key.
+///
+/// It's a control character, so it won't clash with the keys we're using.
+const NAMESPACE_SEPARATOR: char = '\u{1f}';
+
+/// Check that the given account namespace can be told apart from the others,
+/// and from the keys it's prefixed to.
+fn validate_account_namespace(account_namespace: Option<&str>) -> Result<(), OpenStoreError> {
+    match account_namespace {
+        Some(namespace) if namespace.is_empty() || namespace.contains(NAMESPACE_SEPARATOR) => {
+            Err(OpenStoreError::InvalidAccountNamespace(namespace.to_owned()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Prefix the given key with the account namespace, if there is one.
+fn namespaced_key<'a>(account_namespace: Option<&str>, key: &'a str) -> Cow<'a, str> {
+    match account_namespace {
+        Some(namespace) => Cow::Owned(format!("{namespace}{NAMESPACE_SEPARATOR}{key}")),
+        None => Cow::Borrowed(key),
+    }
+}
 
 /// key for the dehydrated device pickle key in the key/value table.
 const DEHYDRATED_DEVICE_PICKLE_KEY: &str = "dehydrated_device_pickle_key";
 
+/// The columns of a row of the `inbound_group_session` table, apart from its
+/// account, see [`SqliteConnectionExt::set_inbound_group_session()`].
+struct InboundGroupSessionRow<'a> {
+    room_id: &'a [u8],
+    session_id: &'a [u8],
+    data: &'a [u8],
+    backed_up: bool,
+    sender_key: Option<&'a [u8]>,
+    sender_data_type: Option<u8>,
+    received_at: Option<u64>,
+}
+
+/// The columns of a row of the `inbound_group_session` table, apart from its
+/// account, as read back from the database.
+struct OwnedInboundGroupSessionRow {
+    room_id: Vec<u8>,
+    session_id: Vec<u8>,
+    data: Vec<u8>,
+    backed_up: bool,
+    sender_key: Option<Vec<u8>>,
+    sender_data_type: Option<u8>,
+    received_at: Option<u64>,
+}
+
+/// A row of the `inbound_group_session` table which couldn't be decoded, as
+/// stored in the `data` column of the `quarantined_entry` table.
+///
+/// The columns of the original row are kept, so the row can be restored.
+#[derive(Serialize, Deserialize)]
+struct QuarantinedInboundGroupSessionRow {
+    entry: QuarantinedEntry,
+    room_id: Vec<u8>,
+    session_id: Vec<u8>,
+    backed_up: bool,
+    sender_key: Option<Vec<u8>>,
+    sender_data_type: Option<u8>,
+    received_at: Option<u64>,
+}
+
+/// Create the connection pool of the database.
+///
+/// The [`ConnectionConfig`] is applied to every connection once, when the pool
+/// creates it.
+async fn create_pool(
+    path: &Path,
+    pool_config: PoolConfig,
+    connection_config: ConnectionConfig,
+) -> Result<SqlitePool, OpenStoreError> {
+    fs::create_dir_all(path).await.map_err(OpenStoreError::CreateDir)?;
+
+    let mut config = deadpool_sqlite::Config::new(path.join(DATABASE_NAME));
+    config.pool = Some(pool_config);
+
+    let post_create = Hook::async_fn(move |conn: &mut SyncWrapper<rusqlite::Connection>, _| {
+        Box::pin(async move {
+            conn.interact(move |conn| apply_connection_config(conn, connection_config))
+                .await
+                .map_err(|e| HookError::Message(e.to_string().into()))?
+                .map_err(HookError::Backend)
+        })
+    });
+
+    let pool = config
+        .builder(Runtime::Tokio1)
+        .map_err(CreatePoolError::Config)?
+        .post_create(post_create)
+        .build()
+        .map_err(CreatePoolError::Build)?;
+
+    Ok(pool)
+}
+
 /// Run migrations for the given version of the database.
 async fn run_migrations(conn: &SqliteAsyncConn, version: u8) -> Result<()> {
     if version == 0 {
@@ -317,12 +544,99 @@ async fn run_migrations(conn: &SqliteAsyncConn, version: u8) -> Result<()> {
         .await?;
     }
 
+    if version < 11 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!(
+                "../migrations/crypto_store/011_megolm_message_indices.sql"
+            ))?;
+            txn.set_db_version(11)
+        })
+        .await?;
+    }
+
+    if version < 12 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!(
+                "../migrations/crypto_store/012_account_namespace.sql"
+            ))?;
+            txn.set_db_version(12)
+        })
+        .await?;
+    }
+
+    if version < 13 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!("../migrations/crypto_store/013_change_journal.sql"))?;
+            txn.set_db_version(13)
+        })
+        .await?;
+    }
+
+    if version < 14 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!(
+                "../migrations/crypto_store/014_inbound_group_session_received_at.sql"
+            ))?;
+            txn.set_db_version(14)
+        })
+        .await?;
+    }
+
+    if version < 15 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!(
+                "../migrations/crypto_store/015_quarantined_entry.sql"
+            ))?;
+            txn.set_db_version(15)
+        })
+        .await?;
+    }
+
+    if version < 16 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!(
+                "../migrations/crypto_store/016_account_secret_tables.sql"
+            ))?;
+            txn.set_db_version(16)
+        })
+        .await?;
+    }
+
+    if version < 17 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!(
+                "../migrations/crypto_store/017_session_share_record.sql"
+            ))?;
+            txn.set_db_version(17)
+        })
+        .await?;
+    }
+
+    if version < 18 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!(
+                "../migrations/crypto_store/018_verification_audit_entry.sql"
+            ))?;
+            txn.set_db_version(18)
+        })
+        .await?;
+    }
+
+    if version < 19 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!("../migrations/crypto_store/019_app_data.sql"))?;
+            txn.set_db_version(19)
+        })
+        .await?;
+    }
+
     Ok(())
 }
 
 trait SqliteConnectionExt {
     fn set_session(
         &self,
+        account: &str,
         session_id: &[u8],
         sender_key: &[u8],
         data: &[u8],
@@ -330,16 +644,17 @@ trait SqliteConnectionExt {
 
     fn set_inbound_group_session(
         &self,
+        account: &str,
+        session: InboundGroupSessionRow<'_>,
+    ) -> rusqlite::Result<()>;
+
+    fn set_outbound_group_session(
+        &self,
+        account: &str,
         room_id: &[u8],
-        session_id: &[u8],
         data: &[u8],
-        backed_up: bool,
-        sender_key: Option<&[u8]>,
-        sender_data_type: Option<u8>,
     ) -> rusqlite::Result<()>;
 
-    fn set_outbound_group_session(&self, room_id: &[u8], data: &[u8]) -> rusqlite::Result<()>;
-
     fn set_device(&self, user_id: &[u8], device_id: &[u8], data: &[u8]) -> rusqlite::Result<()>;
     fn delete_device(&self, user_id: &[u8], device_id: &[u8]) -> rusqlite::Result<()>;
 
@@ -349,6 +664,7 @@ trait SqliteConnectionExt {
 
     fn set_key_request(
         &self,
+        account: &str,
         request_id: &[u8],
         sent_out: bool,
         data: &[u8],
@@ -363,56 +679,106 @@ trait SqliteConnectionExt {
 
     fn set_room_settings(&self, room_id: &[u8], data: &[u8]) -> rusqlite::Result<()>;
 
-    fn set_secret(&self, request_id: &[u8], data: &[u8]) -> rusqlite::Result<()>;
+    fn set_secret(&self, account: &str, request_id: &[u8], data: &[u8]) -> rusqlite::Result<()>;
 
     fn set_received_room_key_bundle(
         &self,
+        account: &str,
+        room_id: &[u8],
+        user_id: &[u8],
+        data: &[u8],
+    ) -> rusqlite::Result<()>;
+
+    fn append_to_change_journal(&self, account: &str, data: &[u8]) -> rusqlite::Result<()>;
+
+    fn add_session_share_record(
+        &self,
+        account: &str,
         room_id: &[u8],
+        session_id: &[u8],
+        data: &[u8],
+    ) -> rusqlite::Result<()>;
+
+    fn add_verification_audit_entry(
+        &self,
+        account: &str,
         user_id: &[u8],
         data: &[u8],
+        capacity: usize,
+    ) -> rusqlite::Result<()>;
+
+    fn set_app_data(
+        &self,
+        account: &str,
+        namespace: &[u8],
+        key: &[u8],
+        data: &[u8],
     ) -> rusqlite::Result<()>;
+
+    fn remove_app_data(&self, namespace: &[u8], key: &[u8]) -> rusqlite::Result<()>;
 }
 
 impl SqliteConnectionExt for rusqlite::Connection {
     fn set_session(
         &self,
+        account: &str,
         session_id: &[u8],
         sender_key: &[u8],
         data: &[u8],
     ) -> rusqlite::Result<()> {
         self.execute(
-            "INSERT INTO session (session_id, sender_key, data)
-             VALUES (?1, ?2, ?3)
+            "INSERT INTO session (session_id, sender_key, data, account)
+             VALUES (?1, ?2, ?3, ?4)
              ON CONFLICT (session_id) DO UPDATE SET data = ?3",
-            (session_id, sender_key, data),
+            (session_id, sender_key, data, account),
         )?;
         Ok(())
     }
 
     fn set_inbound_group_session(
         &self,
-        room_id: &[u8],
-        session_id: &[u8],
-        data: &[u8],
-        backed_up: bool,
-        sender_key: Option<&[u8]>,
-        sender_data_type: Option<u8>,
+        account: &str,
+        session: InboundGroupSessionRow<'_>,
     ) -> rusqlite::Result<()> {
+        let InboundGroupSessionRow {
+            room_id,
+            session_id,
+            data,
+            backed_up,
+            sender_key,
+            sender_data_type,
+            received_at,
+        } = session;
+
         self.execute(
-            "INSERT INTO inbound_group_session (session_id, room_id, data, backed_up, sender_key, sender_data_type) \
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
-             ON CONFLICT (session_id) DO UPDATE SET data = ?3, backed_up = ?4, sender_key = ?5, sender_data_type = ?6",
-            (session_id, room_id, data, backed_up, sender_key, sender_data_type),
+            "INSERT INTO inbound_group_session (session_id, room_id, data, backed_up, sender_key, sender_data_type, account, received_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (session_id) DO UPDATE SET data = ?3, backed_up = ?4, sender_key = ?5, sender_data_type = ?6, received_at = ?8",
+            (
+                session_id,
+                room_id,
+                data,
+                backed_up,
+                sender_key,
+                sender_data_type,
+                account,
+                received_at,
+            ),
         )?;
         Ok(())
     }
 
-    fn set_outbound_group_session(&self, room_id: &[u8], data: &[u8]) -> rusqlite::Result<()> {
+    fn set_outbound_group_session(
+        &self,
+        account: &str,
+        room_id: &[u8],
+        data: &[u8],
+    ) -> rusqlite::Result<()> {
         self.execute(
-            "INSERT INTO outbound_group_session (room_id, data) \
-             VALUES (?1, ?2)
+            "INSERT INTO outbound_group_session (room_id, data, account) \
+             VALUES (?1, ?2, ?3)
              ON CONFLICT (room_id) DO UPDATE SET data = ?2",
-            (room_id, data),
+            (room_id, data, account),
         )?;
         Ok(())
     }
@@ -452,15 +818,16 @@ impl SqliteConnectionExt for rusqlite::Connection {
 
     fn set_key_request(
         &self,
+        account: &str,
         request_id: &[u8],
         sent_out: bool,
         data: &[u8],
     ) -> rusqlite::Result<()> {
         self.execute(
-            "INSERT INTO key_requests (request_id, sent_out, data)
-            VALUES (?1, ?2, ?3)
+            "INSERT INTO key_requests (request_id, sent_out, data, account)
+            VALUES (?1, ?2, ?3, ?4)
             ON CONFLICT (request_id) DO UPDATE SET sent_out = ?2, data = ?3",
-            (request_id, sent_out, data),
+            (request_id, sent_out, data, account),
         )?;
         Ok(())
     }
@@ -490,11 +857,11 @@ impl SqliteConnectionExt for rusqlite::Connection {
         Ok(())
     }
 
-    fn set_secret(&self, secret_name: &[u8], data: &[u8]) -> rusqlite::Result<()> {
+    fn set_secret(&self, account: &str, secret_name: &[u8], data: &[u8]) -> rusqlite::Result<()> {
         self.execute(
-            "INSERT INTO secrets (secret_name, data)
-            VALUES (?1, ?2)",
-            (secret_name, data),
+            "INSERT INTO secrets (secret_name, data, account)
+            VALUES (?1, ?2, ?3)",
+            (secret_name, data, account),
         )?;
 
         Ok(())
@@ -502,18 +869,86 @@ impl SqliteConnectionExt for rusqlite::Connection {
 
     fn set_received_room_key_bundle(
         &self,
+        account: &str,
         room_id: &[u8],
         sender_user_id: &[u8],
         data: &[u8],
     ) -> rusqlite::Result<()> {
         self.execute(
-            "INSERT INTO received_room_key_bundle(room_id, sender_user_id, bundle_data)
-            VALUES (?1, ?2, ?3)
+            "INSERT INTO received_room_key_bundle(room_id, sender_user_id, bundle_data, account)
+            VALUES (?1, ?2, ?3, ?4)
             ON CONFLICT (room_id, sender_user_id) DO UPDATE SET bundle_data = ?3",
-            (room_id, sender_user_id, data),
+            (room_id, sender_user_id, data, account),
+        )?;
+        Ok(())
+    }
+
+    fn append_to_change_journal(&self, account: &str, data: &[u8]) -> rusqlite::Result<()> {
+        self.execute(
+            "INSERT INTO change_journal (account, data) VALUES (?1, ?2)",
+            (account, data),
+        )?;
+        Ok(())
+    }
+
+    fn add_session_share_record(
+        &self,
+        account: &str,
+        room_id: &[u8],
+        session_id: &[u8],
+        data: &[u8],
+    ) -> rusqlite::Result<()> {
+        self.execute(
+            "INSERT INTO session_share_record (account, room_id, session_id, data)
+             VALUES (?1, ?2, ?3, ?4)",
+            (account, room_id, session_id, data),
+        )?;
+        Ok(())
+    }
+
+    fn add_verification_audit_entry(
+        &self,
+        account: &str,
+        user_id: &[u8],
+        data: &[u8],
+        capacity: usize,
+    ) -> rusqlite::Result<()> {
+        self.execute(
+            "INSERT INTO verification_audit_entry (account, user_id, data) VALUES (?1, ?2, ?3)",
+            (account, user_id, data),
+        )?;
+        self.execute(
+            "DELETE FROM verification_audit_entry \
+             WHERE account = ?1 AND user_id = ?2 AND id NOT IN ( \
+                 SELECT id FROM verification_audit_entry \
+                 WHERE account = ?1 AND user_id = ?2 \
+                 ORDER BY id DESC LIMIT ?3 \
+             )",
+            (account, user_id, capacity),
+        )?;
+        Ok(())
+    }
+
+    fn set_app_data(
+        &self,
+        account: &str,
+        namespace: &[u8],
+        key: &[u8],
+        data: &[u8],
+    ) -> rusqlite::Result<()> {
+        self.execute(
+            "INSERT INTO app_data (account, namespace, key, data)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (namespace, key) DO UPDATE SET data = ?4",
+            (account, namespace, key, data),
         )?;
         Ok(())
     }
+
+    fn remove_app_data(&self, namespace: &[u8], key: &[u8]) -> rusqlite::Result<()> {
+        self.execute("DELETE FROM app_data WHERE namespace = ?1 AND key = ?2", (namespace, key))?;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -526,6 +961,28 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .await?)
     }
 
+    async fn get_all_sessions(&self, account: String) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare("SELECT data FROM session WHERE account = ?", |mut stmt| {
+                stmt.query((account,))?.mapped(|row| row.get(0)).collect()
+            })
+            .await?)
+    }
+
+    async fn delete_sessions(&self, session_ids: Vec<Key>) -> Result<()> {
+        self.chunk_large_query_over(session_ids, None, |txn, session_ids| {
+            // Safety: placeholders is not generated using any user input except the number
+            // of session IDs, so it is safe from injection.
+            let sql_params = repeat_vars(session_ids.len());
+            let query = format!("DELETE FROM session WHERE session_id IN ({sql_params})");
+            txn.prepare(&query)?.execute(params_from_iter(session_ids.iter()))?;
+            Ok(Vec::<()>::new())
+        })
+        .await?;
+
+        Ok(())
+    }
+
     async fn get_inbound_group_session(
         &self,
         session_id: Key,
@@ -540,25 +997,119 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .optional()?)
     }
 
-    async fn get_inbound_group_sessions(&self) -> Result<Vec<(Vec<u8>, bool)>> {
+    async fn delete_inbound_group_sessions(
+        &self,
+        session_ids: Vec<Key>,
+        message_index_room_id: Key,
+        message_index_session_ids: Vec<Key>,
+        share_record_room_id: Key,
+        share_record_session_ids: Vec<Key>,
+    ) -> Result<()> {
+        self.with_transaction(move |txn| {
+            txn.chunk_large_query_over(session_ids, None, |txn, session_ids| {
+                // Safety: placeholders is not generated using any user input except the number
+                // of session IDs, so it is safe from injection.
+                let sql_params = repeat_vars(session_ids.len());
+                let query =
+                    format!("DELETE FROM inbound_group_session WHERE session_id IN ({sql_params})");
+                txn.prepare(&query)?.execute(params_from_iter(session_ids.iter()))?;
+                Ok(Vec::<()>::new())
+            })?;
+
+            // The message indices recorded for replay detection are useless once the
+            // session is gone.
+            txn.chunk_large_query_over(message_index_session_ids, None, |txn, session_ids| {
+                // Safety: placeholders is not generated using any user input except the number
+                // of session IDs, so it is safe from injection.
+                let sql_params = repeat_vars(session_ids.len());
+                let query = format!(
+                    "DELETE FROM megolm_message_index \
+                     WHERE room_id = ? AND session_id IN ({sql_params})"
+                );
+                let params = iter::once(&message_index_room_id).chain(session_ids.iter());
+                txn.prepare(&query)?.execute(params_from_iter(params))?;
+                Ok(Vec::<()>::new())
+            })?;
+
+            // So is the share history, if they are our own sessions.
+            txn.chunk_large_query_over(share_record_session_ids, None, |txn, session_ids| {
+                // Safety: placeholders is not generated using any user input except the number
+                // of session IDs, so it is safe from injection.
+                let sql_params = repeat_vars(session_ids.len());
+                let query = format!(
+                    "DELETE FROM session_share_record \
+                     WHERE room_id = ? AND session_id IN ({sql_params})"
+                );
+                let params = iter::once(&share_record_room_id).chain(session_ids.iter());
+                txn.prepare(&query)?.execute(params_from_iter(params))?;
+                Ok(Vec::<()>::new())
+            })?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_inbound_group_sessions(&self, account: String) -> Result<Vec<(Vec<u8>, bool)>> {
         Ok(self
-            .prepare("SELECT data, backed_up FROM inbound_group_session", |mut stmt| {
-                stmt.query(())?.mapped(|row| Ok((row.get(0)?, row.get(1)?))).collect()
-            })
+            .prepare(
+                "SELECT data, backed_up FROM inbound_group_session WHERE account = ?",
+                |mut stmt| {
+                    stmt.query((account,))?.mapped(|row| Ok((row.get(0)?, row.get(1)?))).collect()
+                },
+            )
+            .await?)
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        account: String,
+        after_session_id: Option<Key>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, bool)>> {
+        Ok(self
+            .prepare(
+                "
+                SELECT data, backed_up
+                FROM inbound_group_session
+                WHERE account = :account
+                    AND session_id > :after_session_id
+                ORDER BY session_id
+                LIMIT :limit
+                ",
+                move |mut stmt| {
+                    // If we are not provided with an `after_session_id`, use a key which will sort
+                    // before all real keys: the empty string.
+                    let after_session_id = after_session_id.unwrap_or(Key::Plain(Vec::new()));
+
+                    stmt.query(named_params! {
+                        ":account": account,
+                        ":after_session_id": after_session_id,
+                        ":limit": limit,
+                    })?
+                    .mapped(|row| Ok((row.get(0)?, row.get(1)?)))
+                    .collect()
+                },
+            )
             .await?)
     }
 
     async fn get_inbound_group_session_counts(
         &self,
+        account: String,
         _backup_version: Option<&str>,
     ) -> Result<RoomKeyCounts> {
         let total = self
-            .query_row("SELECT count(*) FROM inbound_group_session", (), |row| row.get(0))
+            .query_row(
+                "SELECT count(*) FROM inbound_group_session WHERE account = ?",
+                (account.clone(),),
+                |row| row.get(0),
+            )
             .await?;
         let backed_up = self
             .query_row(
-                "SELECT count(*) FROM inbound_group_session WHERE backed_up = TRUE",
-                (),
+                "SELECT count(*) FROM inbound_group_session WHERE account = ? AND backed_up = TRUE",
+                (account,),
                 |row| row.get(0),
             )
             .await?;
@@ -603,12 +1154,70 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .await?)
     }
 
-    async fn get_inbound_group_sessions_for_backup(&self, limit: usize) -> Result<Vec<Vec<u8>>> {
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        account: String,
+        room_id: Key,
+        since: Option<u64>,
+    ) -> Result<Vec<(Vec<u8>, bool)>> {
+        // Sessions stored before we had the `received_at` column have a NULL
+        // timestamp, those need to be checked against the pickle by the caller.
         Ok(self
             .prepare(
-                "SELECT data FROM inbound_group_session WHERE backed_up = FALSE LIMIT ?",
-                move |mut stmt| stmt.query((limit,))?.mapped(|row| row.get(0)).collect(),
-            )
+                "
+                SELECT data, backed_up
+                FROM inbound_group_session
+                WHERE account = :account
+                    AND room_id = :room_id
+                    AND (:since IS NULL OR received_at IS NULL OR received_at >= :since)
+                ",
+                move |mut stmt| {
+                    stmt.query(named_params! {
+                        ":account": account,
+                        ":room_id": room_id,
+                        ":since": since,
+                    })?
+                    .mapped(|row| Ok((row.get(0)?, row.get(1)?)))
+                    .collect()
+                },
+            )
+            .await?)
+    }
+
+    async fn get_room_key_summaries(
+        &self,
+        account: String,
+    ) -> Result<Vec<(Vec<u8>, usize, Option<u64>, Option<u64>)>> {
+        // The `data` of any session of the room will do, we only need it for the room
+        // ID, since the `room_id` column may be hashed.
+        Ok(self
+            .prepare(
+                "
+                SELECT data, count(*), min(received_at), max(received_at)
+                FROM inbound_group_session
+                WHERE account = ?
+                GROUP BY room_id
+                ",
+                move |mut stmt| {
+                    stmt.query((account,))?
+                        .mapped(|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+                        .collect()
+                },
+            )
+            .await?)
+    }
+
+    async fn get_inbound_group_sessions_for_backup(
+        &self,
+        account: String,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare(
+                "SELECT data FROM inbound_group_session
+                WHERE account = ? AND backed_up = FALSE LIMIT ?",
+                move |mut stmt| stmt.query((account, limit))?.mapped(|row| row.get(0)).collect(),
+            )
             .await?)
     }
 
@@ -633,8 +1242,12 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
         Ok(())
     }
 
-    async fn reset_inbound_group_session_backup_state(&self) -> Result<()> {
-        self.execute("UPDATE inbound_group_session SET backed_up = FALSE", ()).await?;
+    async fn reset_inbound_group_session_backup_state(&self, account: String) -> Result<()> {
+        self.execute(
+            "UPDATE inbound_group_session SET backed_up = FALSE WHERE account = ?",
+            (account,),
+        )
+        .await?;
         Ok(())
     }
 
@@ -675,6 +1288,34 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .optional()?)
     }
 
+    async fn get_verification_audit_entries(&self, user_id: Key) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare(
+                "SELECT data FROM verification_audit_entry WHERE user_id = ? ORDER BY id ASC",
+                |mut stmt| stmt.query((user_id,))?.mapped(|row| row.get(0)).collect(),
+            )
+            .await?)
+    }
+
+    async fn get_app_data(&self, namespace: Key, key: Key) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .query_row(
+                "SELECT data FROM app_data WHERE namespace = ? AND key = ?",
+                (namespace, key),
+                |row| row.get(0),
+            )
+            .await
+            .optional()?)
+    }
+
+    async fn get_app_data_for_namespace(&self, namespace: Key) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare("SELECT data FROM app_data WHERE namespace = ?", |mut stmt| {
+                stmt.query((namespace,))?.mapped(|row| row.get(0)).collect()
+            })
+            .await?)
+    }
+
     async fn has_olm_hash(&self, data: Vec<u8>) -> Result<bool> {
         Ok(self
             .query_row("SELECT count(*) FROM olm_hash WHERE data = ?", (data,), |row| {
@@ -684,23 +1325,60 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             > 0)
     }
 
-    async fn get_tracked_users(&self) -> Result<Vec<Vec<u8>>> {
+    async fn get_tracked_users(&self, account: String) -> Result<Vec<Vec<u8>>> {
         Ok(self
-            .prepare("SELECT data FROM tracked_user", |mut stmt| {
-                stmt.query(())?.mapped(|row| row.get(0)).collect()
+            .prepare("SELECT data FROM tracked_user WHERE account = ?", |mut stmt| {
+                stmt.query((account,))?.mapped(|row| row.get(0)).collect()
             })
             .await?)
     }
 
-    async fn add_tracked_users(&self, users: Vec<(Key, Vec<u8>)>) -> Result<()> {
+    async fn get_tracked_users_paged(
+        &self,
+        account: String,
+        after_user_id: Option<Key>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare(
+                "
+                SELECT data
+                FROM tracked_user
+                WHERE account = :account
+                    AND user_id > :after_user_id
+                ORDER BY user_id
+                LIMIT :limit
+                ",
+                move |mut stmt| {
+                    // If we are not provided with an `after_user_id`, use a key which will sort
+                    // before all real keys: the empty string.
+                    let after_user_id = after_user_id.unwrap_or(Key::Plain(Vec::new()));
+
+                    stmt.query(named_params! {
+                        ":account": account,
+                        ":after_user_id": after_user_id,
+                        ":limit": limit,
+                    })?
+                    .mapped(|row| row.get(0))
+                    .collect()
+                },
+            )
+            .await?)
+    }
+
+    async fn add_tracked_users(
+        &self,
+        account: String,
+        users: Vec<(Key, Zeroizing<Vec<u8>>)>,
+    ) -> Result<()> {
         Ok(self
             .prepare(
-                "INSERT INTO tracked_user (user_id, data) \
-                 VALUES (?1, ?2) \
+                "INSERT INTO tracked_user (user_id, data, account) \
+                 VALUES (?1, ?2, ?3) \
                  ON CONFLICT (user_id) DO UPDATE SET data = ?2",
                 |mut stmt| {
                     for (user_id, data) in users {
-                        stmt.execute((user_id, data))?;
+                        stmt.execute((user_id, data.as_slice(), &account))?;
                     }
 
                     Ok(())
@@ -723,19 +1401,20 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .optional()?)
     }
 
-    async fn get_outgoing_secret_requests(&self) -> Result<Vec<(Vec<u8>, bool)>> {
+    async fn get_outgoing_secret_requests(&self, account: String) -> Result<Vec<(Vec<u8>, bool)>> {
         Ok(self
-            .prepare("SELECT data, sent_out FROM key_requests", |mut stmt| {
-                stmt.query(())?.mapped(|row| Ok((row.get(0)?, row.get(1)?))).collect()
+            .prepare("SELECT data, sent_out FROM key_requests WHERE account = ?", |mut stmt| {
+                stmt.query((account,))?.mapped(|row| Ok((row.get(0)?, row.get(1)?))).collect()
             })
             .await?)
     }
 
-    async fn get_unsent_secret_requests(&self) -> Result<Vec<Vec<u8>>> {
+    async fn get_unsent_secret_requests(&self, account: String) -> Result<Vec<Vec<u8>>> {
         Ok(self
-            .prepare("SELECT data FROM key_requests WHERE sent_out = FALSE", |mut stmt| {
-                stmt.query(())?.mapped(|row| row.get(0)).collect()
-            })
+            .prepare(
+                "SELECT data FROM key_requests WHERE account = ? AND sent_out = FALSE",
+                |mut stmt| stmt.query((account,))?.mapped(|row| row.get(0)).collect(),
+            )
             .await?)
     }
 
@@ -795,6 +1474,55 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .await
             .optional()?)
     }
+
+    async fn get_change_journal(&self, account: String, since: u64) -> Result<Vec<(u64, Vec<u8>)>> {
+        Ok(self
+            .prepare(
+                "SELECT sequence_number, data FROM change_journal \
+                 WHERE account = ?1 AND sequence_number > ?2 \
+                 ORDER BY sequence_number ASC",
+                |mut stmt| {
+                    stmt.query((account, since))?
+                        .mapped(|row| Ok((row.get(0)?, row.get(1)?)))
+                        .collect()
+                },
+            )
+            .await?)
+    }
+
+    async fn get_session_share_records(
+        &self,
+        room_id: Key,
+        session_id: Key,
+    ) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare(
+                "SELECT data FROM session_share_record \
+                 WHERE room_id = ?1 AND session_id = ?2 \
+                 ORDER BY rowid ASC",
+                |mut stmt| stmt.query((room_id, session_id))?.mapped(|row| row.get(0)).collect(),
+            )
+            .await?)
+    }
+
+    async fn truncate_change_journal(&self, account: String, up_to: u64) -> Result<()> {
+        self.execute(
+            "DELETE FROM change_journal WHERE account = ?1 AND sequence_number <= ?2",
+            (account, up_to),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_quarantined_entries(&self, account: String) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare(
+                "SELECT data FROM quarantined_entry WHERE account = ? ORDER BY id ASC",
+                |mut stmt| stmt.query((account,))?.mapped(|row| row.get(0)).collect(),
+            )
+            .await?)
+    }
 }
 
 #[async_trait]
@@ -806,7 +1534,7 @@ impl CryptoStore for SqliteCryptoStore {
 
     async fn load_account(&self) -> Result<Option<Account>> {
         let conn = self.acquire().await?;
-        if let Some(pickle) = conn.get_kv("account").await? {
+        if let Some(pickle) = conn.get_kv(&self.kv_key("account")).await? {
             let pickle = self.deserialize_value(&pickle)?;
 
             let account = Account::from_pickle(pickle).map_err(|_| Error::Unpickle)?;
@@ -821,7 +1549,7 @@ impl CryptoStore for SqliteCryptoStore {
 
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
         let conn = self.acquire().await?;
-        if let Some(i) = conn.get_kv("identity").await? {
+        if let Some(i) = conn.get_kv(&self.kv_key("identity")).await? {
             let pickle = self.deserialize_value(&i)?;
             Ok(Some(PrivateCrossSigningIdentity::from_pickle(pickle).map_err(|_| Error::Unpickle)?))
         } else {
@@ -849,7 +1577,7 @@ impl CryptoStore for SqliteCryptoStore {
             .with_transaction(move |txn| {
                 if let Some(pickled_account) = pickled_account {
                     let serialized_account = this.serialize_value(&pickled_account)?;
-                    txn.set_kv("account", &serialized_account)?;
+                    txn.set_kv(&this.kv_key("account"), &serialized_account)?;
                 }
 
                 Ok::<_, Error>(())
@@ -902,27 +1630,27 @@ impl CryptoStore for SqliteCryptoStore {
                 if let Some(pickled_private_identity) = &pickled_private_identity {
                     let serialized_private_identity =
                         this.serialize_value(pickled_private_identity)?;
-                    txn.set_kv("identity", &serialized_private_identity)?;
+                    txn.set_kv(&this.kv_key("identity"), &serialized_private_identity)?;
                 }
 
                 if let Some(token) = &changes.next_batch_token {
                     let serialized_token = this.serialize_value(token)?;
-                    txn.set_kv("next_batch_token", &serialized_token)?;
+                    txn.set_kv(&this.kv_key("next_batch_token"), &serialized_token)?;
                 }
 
                 if let Some(decryption_key) = &changes.backup_decryption_key {
                     let serialized_decryption_key = this.serialize_value(decryption_key)?;
-                    txn.set_kv("recovery_key_v1", &serialized_decryption_key)?;
+                    txn.set_kv(&this.kv_key("recovery_key_v1"), &serialized_decryption_key)?;
                 }
 
                 if let Some(backup_version) = &changes.backup_version {
                     let serialized_backup_version = this.serialize_value(backup_version)?;
-                    txn.set_kv("backup_version_v1", &serialized_backup_version)?;
+                    txn.set_kv(&this.kv_key("backup_version_v1"), &serialized_backup_version)?;
                 }
 
                 if let Some(pickle_key) = &changes.dehydrated_device_pickle_key {
                     let serialized_pickle_key = this.serialize_value(pickle_key)?;
-                    txn.set_kv(DEHYDRATED_DEVICE_PICKLE_KEY, &serialized_pickle_key)?;
+                    txn.set_kv(&this.kv_key(DEHYDRATED_DEVICE_PICKLE_KEY), &serialized_pickle_key)?;
                 }
 
                 for device in changes.devices.new.iter().chain(&changes.devices.changed) {
@@ -946,35 +1674,44 @@ impl CryptoStore for SqliteCryptoStore {
 
                 for (session_id, sender_key, pickle) in &session_changes {
                     let serialized_session = this.serialize_value(&pickle)?;
-                    txn.set_session(session_id, sender_key, &serialized_session)?;
+                    txn.set_session(this.account(), session_id, sender_key, &serialized_session)?;
                 }
 
                 for (room_id, session_id, pickle, sender_key) in &inbound_session_changes {
                     let serialized_session = this.serialize_value(&pickle)?;
                     txn.set_inbound_group_session(
-                        room_id,
-                        session_id,
-                        &serialized_session,
-                        pickle.backed_up,
-                        Some(sender_key),
-                        Some(pickle.sender_data.to_type() as u8),
+                        this.account(),
+                        InboundGroupSessionRow {
+                            room_id,
+                            session_id,
+                            data: &serialized_session,
+                            backed_up: pickle.backed_up,
+                            sender_key: Some(sender_key),
+                            sender_data_type: Some(pickle.sender_data.to_type() as u8),
+                            received_at: pickle.received_at.map(|ts| ts.get().into()),
+                        },
                     )?;
                 }
 
                 for (room_id, pickle) in &outbound_session_changes {
                     let serialized_session = this.serialize_json(&pickle)?;
-                    txn.set_outbound_group_session(room_id, &serialized_session)?;
+                    txn.set_outbound_group_session(this.account(), room_id, &serialized_session)?;
                 }
 
                 for hash in &changes.message_hashes {
                     let hash = rmp_serde::to_vec(hash)?;
-                    txn.add_olm_hash(&hash)?;
+                    txn.add_olm_hash(&this.namespaced_bytes(&hash))?;
                 }
 
                 for request in changes.key_requests {
                     let request_id = this.encode_key("key_requests", request.request_id.as_bytes());
                     let serialized_request = this.serialize_value(&request)?;
-                    txn.set_key_request(&request_id, request.sent_out, &serialized_request)?;
+                    txn.set_key_request(
+                        this.account(),
+                        &request_id,
+                        request.sent_out,
+                        &serialized_request,
+                    )?;
                 }
 
                 for (room_id, data) in changes.withheld_session_info {
@@ -995,7 +1732,7 @@ impl CryptoStore for SqliteCryptoStore {
                 for secret in changes.secrets {
                     let secret_name = this.encode_key("secrets", secret.secret_name.to_string());
                     let value = this.serialize_json(&secret)?;
-                    txn.set_secret(&secret_name, &value)?;
+                    txn.set_secret(this.account(), &secret_name, &value)?;
                 }
 
                 for bundle in changes.received_room_key_bundles {
@@ -1003,7 +1740,43 @@ impl CryptoStore for SqliteCryptoStore {
                         this.encode_key("received_room_key_bundle", &bundle.bundle_data.room_id);
                     let user_id = this.encode_key("received_room_key_bundle", &bundle.sender_user);
                     let value = this.serialize_value(&bundle)?;
-                    txn.set_received_room_key_bundle(&room_id, &user_id, &value)?;
+                    txn.set_received_room_key_bundle(this.account(), &room_id, &user_id, &value)?;
+                }
+
+                for entry in changes.change_journal {
+                    let value = this.serialize_value(&entry)?;
+                    txn.append_to_change_journal(this.account(), &value)?;
+                }
+
+                for record in changes.session_share_records {
+                    let room_id = this.encode_key("session_share_record", &record.room_id);
+                    let session_id = this.encode_key("session_share_record", &record.session_id);
+                    let value = this.serialize_value(&record)?;
+                    txn.add_session_share_record(this.account(), &room_id, &session_id, &value)?;
+                }
+
+                for entry in changes.verification_audit_log {
+                    let user_id =
+                        this.encode_key("verification_audit_entry", entry.user_id.as_bytes());
+                    let value = this.serialize_value(&entry)?;
+                    txn.add_verification_audit_entry(
+                        this.account(),
+                        &user_id,
+                        &value,
+                        VERIFICATION_AUDIT_LOG_CAPACITY,
+                    )?;
+                }
+
+                for change in changes.app_data {
+                    let namespace = this.encode_key("app_data", &change.namespace);
+                    let key = this.encode_key("app_data", &change.key);
+                    match change.value {
+                        Some(value) => {
+                            let value = this.serialize_value(&(change.key, value))?;
+                            txn.set_app_data(this.account(), &namespace, &key, &value)?;
+                        }
+                        None => txn.remove_app_data(&namespace, &key)?,
+                    }
                 }
 
                 Ok::<_, Error>(())
@@ -1057,6 +1830,35 @@ impl CryptoStore for SqliteCryptoStore {
         }
     }
 
+    async fn get_session_sender_keys(&self) -> Result<Vec<String>> {
+        let sender_keys: BTreeSet<_> = self
+            .acquire()
+            .await?
+            .get_all_sessions(self.account().to_owned())
+            .await?
+            .into_iter()
+            .map(|bytes| {
+                let pickle: PickledSession = self.deserialize_value(&bytes)?;
+                Ok(pickle.sender_key.to_base64())
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(sender_keys.into_iter().collect())
+    }
+
+    async fn delete_sessions(&self, _sender_key: &str, session_ids: &[String]) -> Result<()> {
+        if session_ids.is_empty() {
+            return Ok(());
+        }
+
+        // The session ID is the primary key of the table, so we don't need the sender
+        // key to find the sessions.
+        let session_ids =
+            session_ids.iter().map(|session_id| self.encode_key("session", session_id)).collect();
+
+        self.acquire().await?.delete_sessions(session_ids).await
+    }
+
     #[instrument(skip(self))]
     async fn get_inbound_group_session(
         &self,
@@ -1079,10 +1881,194 @@ impl CryptoStore for SqliteCryptoStore {
         Ok(Some(self.deserialize_and_unpickle_inbound_group_session(value, backed_up)?))
     }
 
+    async fn delete_inbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+        session_ids: &[String],
+    ) -> Result<()> {
+        if session_ids.is_empty() {
+            return Ok(());
+        }
+
+        // The session ID is the primary key of the table, so we don't need the room ID
+        // to find the sessions.
+        let encoded_session_ids = session_ids
+            .iter()
+            .map(|session_id| self.encode_key("inbound_group_session", session_id))
+            .collect();
+        let message_index_room_id = self.encode_key("megolm_message_index", room_id);
+        let message_index_session_ids = session_ids
+            .iter()
+            .map(|session_id| self.encode_key("megolm_message_index", session_id))
+            .collect();
+        let share_record_room_id = self.encode_key("session_share_record", room_id);
+        let share_record_session_ids = session_ids
+            .iter()
+            .map(|session_id| self.encode_key("session_share_record", session_id))
+            .collect();
+
+        self.acquire()
+            .await?
+            .delete_inbound_group_sessions(
+                encoded_session_ids,
+                message_index_room_id,
+                message_index_session_ids,
+                share_record_room_id,
+                share_record_session_ids,
+            )
+            .await
+    }
+
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
         self.acquire()
             .await?
-            .get_inbound_group_sessions()
+            .get_inbound_group_sessions(self.account().to_owned())
+            .await?
+            .into_iter()
+            .map(|(value, backed_up)| {
+                self.deserialize_and_unpickle_inbound_group_session(value, backed_up)
+            })
+            .collect()
+    }
+
+    async fn quarantine_undecodable_inbound_group_sessions(&self) -> Result<Vec<QuarantinedEntry>> {
+        let this = self.clone();
+
+        // Decode and move the sessions in a single transaction, so we don't race with
+        // sessions being saved in the meantime.
+        self.acquire()
+            .await?
+            .with_transaction(move |txn| {
+                let rows: Vec<OwnedInboundGroupSessionRow> = txn
+                    .prepare(
+                        "SELECT room_id, session_id, data, backed_up, sender_key, \
+                         sender_data_type, received_at \
+                         FROM inbound_group_session WHERE account = ?",
+                    )?
+                    .query((this.account(),))?
+                    .mapped(|row| {
+                        Ok(OwnedInboundGroupSessionRow {
+                            room_id: row.get(0)?,
+                            session_id: row.get(1)?,
+                            data: row.get(2)?,
+                            backed_up: row.get(3)?,
+                            sender_key: row.get(4)?,
+                            sender_data_type: row.get(5)?,
+                            received_at: row.get(6)?,
+                        })
+                    })
+                    .collect::<rusqlite::Result<_>>()?;
+
+                let mut quarantined = Vec::new();
+
+                for OwnedInboundGroupSessionRow {
+                    room_id,
+                    session_id,
+                    data,
+                    backed_up,
+                    sender_key,
+                    sender_data_type,
+                    received_at,
+                } in rows
+                {
+                    let Err(error) = this
+                        .deserialize_and_unpickle_inbound_group_session(data.clone(), backed_up)
+                    else {
+                        continue;
+                    };
+
+                    let row = QuarantinedInboundGroupSessionRow {
+                        entry: QuarantinedEntry {
+                            kind: QuarantinedEntryKind::InboundGroupSession,
+                            value: data.into(),
+                            error: error.to_string(),
+                        },
+                        room_id,
+                        session_id,
+                        backed_up,
+                        sender_key,
+                        sender_data_type,
+                        received_at,
+                    };
+
+                    txn.execute(
+                        "DELETE FROM inbound_group_session WHERE account = ?1 AND session_id = ?2",
+                        (this.account(), &row.session_id),
+                    )?;
+                    txn.execute(
+                        "INSERT INTO quarantined_entry (account, data) VALUES (?1, ?2)",
+                        (this.account(), this.serialize_value(&row)?.as_slice()),
+                    )?;
+
+                    quarantined.push(row.entry);
+                }
+
+                Ok::<_, Error>(quarantined)
+            })
+            .await
+    }
+
+    async fn get_quarantined_entries(&self) -> Result<Vec<QuarantinedEntry>> {
+        self.acquire()
+            .await?
+            .get_quarantined_entries(self.account().to_owned())
+            .await?
+            .iter()
+            .map(|value| {
+                let row: QuarantinedInboundGroupSessionRow = self.deserialize_value(value)?;
+                Ok(row.entry)
+            })
+            .collect()
+    }
+
+    async fn restore_quarantined_entries(&self) -> Result<usize> {
+        let this = self.clone();
+
+        self.acquire()
+            .await?
+            .with_transaction(move |txn| {
+                let entries: Vec<Vec<u8>> = txn
+                    .prepare("SELECT data FROM quarantined_entry WHERE account = ?")?
+                    .query((this.account(),))?
+                    .mapped(|row| row.get(0))
+                    .collect::<rusqlite::Result<_>>()?;
+
+                for data in &entries {
+                    let row: QuarantinedInboundGroupSessionRow = this.deserialize_value(data)?;
+
+                    txn.set_inbound_group_session(
+                        this.account(),
+                        InboundGroupSessionRow {
+                            room_id: &row.room_id,
+                            session_id: &row.session_id,
+                            data: row.entry.value.expose_secret(),
+                            backed_up: row.backed_up,
+                            sender_key: row.sender_key.as_deref(),
+                            sender_data_type: row.sender_data_type,
+                            received_at: row.received_at,
+                        },
+                    )?;
+                }
+
+                txn.execute("DELETE FROM quarantined_entry WHERE account = ?", (this.account(),))?;
+
+                Ok::<_, Error>(entries.len())
+            })
+            .await
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        // Session IDs are unique, so the room ID isn't needed to find our position.
+        let after_session_id =
+            after.map(|(_, session_id)| self.encode_key("inbound_group_session", session_id));
+
+        self.acquire()
+            .await?
+            .get_inbound_group_sessions_batch(self.account().to_owned(), after_session_id, limit)
             .await?
             .into_iter()
             .map(|(value, backed_up)| {
@@ -1118,11 +2104,75 @@ impl CryptoStore for SqliteCryptoStore {
             .collect()
     }
 
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+        since: Option<SecondsSinceUnixEpoch>,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        let room_id = self.encode_key("inbound_group_session", room_id.as_bytes());
+
+        let sessions = self
+            .acquire()
+            .await?
+            .get_inbound_group_sessions_for_room(
+                self.account().to_owned(),
+                room_id,
+                since.map(|ts| ts.get().into()),
+            )
+            .await?
+            .into_iter()
+            .map(|(value, backed_up)| {
+                self.deserialize_and_unpickle_inbound_group_session(value, backed_up)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Double check the timestamp of the sessions that were stored before we
+        // started to fill the `received_at` column.
+        Ok(sessions
+            .into_iter()
+            .filter(|session| {
+                since.is_none_or(|since| session.received_at().is_some_and(|r| r >= since))
+            })
+            .collect())
+    }
+
+    async fn get_room_key_summaries(&self) -> Result<Vec<RoomKeySummary>> {
+        let to_timestamp = |ts: u64| SecondsSinceUnixEpoch(UInt::new_saturating(ts));
+
+        let mut summaries = self
+            .acquire()
+            .await?
+            .get_room_key_summaries(self.account().to_owned())
+            .await?
+            .into_iter()
+            .map(|(value, session_count, earliest, latest)| {
+                let pickle: PickledInboundGroupSession = self.deserialize_value(&value)?;
+
+                // Sessions stored before we had the `received_at` column have a NULL
+                // timestamp, those don't contribute to the timestamps.
+                Ok(RoomKeySummary {
+                    room_id: pickle.room_id,
+                    session_count,
+                    earliest_received_at: earliest.map(to_timestamp),
+                    latest_received_at: latest.map(to_timestamp),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        summaries.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+
+        Ok(summaries)
+    }
+
     async fn inbound_group_session_counts(
         &self,
         backup_version: Option<&str>,
     ) -> Result<RoomKeyCounts> {
-        Ok(self.acquire().await?.get_inbound_group_session_counts(backup_version).await?)
+        Ok(self
+            .acquire()
+            .await?
+            .get_inbound_group_session_counts(self.account().to_owned(), backup_version)
+            .await?)
     }
 
     async fn inbound_group_sessions_for_backup(
@@ -1132,7 +2182,7 @@ impl CryptoStore for SqliteCryptoStore {
     ) -> Result<Vec<InboundGroupSession>> {
         self.acquire()
             .await?
-            .get_inbound_group_sessions_for_backup(limit)
+            .get_inbound_group_sessions_for_backup(self.account().to_owned(), limit)
             .await?
             .into_iter()
             .map(|value| self.deserialize_and_unpickle_inbound_group_session(value, false))
@@ -1157,20 +2207,24 @@ impl CryptoStore for SqliteCryptoStore {
     }
 
     async fn reset_backup_state(&self) -> Result<()> {
-        Ok(self.acquire().await?.reset_inbound_group_session_backup_state().await?)
+        Ok(self
+            .acquire()
+            .await?
+            .reset_inbound_group_session_backup_state(self.account().to_owned())
+            .await?)
     }
 
     async fn load_backup_keys(&self) -> Result<BackupKeys> {
         let conn = self.acquire().await?;
 
         let backup_version = conn
-            .get_kv("backup_version_v1")
+            .get_kv(&self.kv_key("backup_version_v1"))
             .await?
             .map(|value| self.deserialize_value(&value))
             .transpose()?;
 
         let decryption_key = conn
-            .get_kv("recovery_key_v1")
+            .get_kv(&self.kv_key("recovery_key_v1"))
             .await?
             .map(|value| self.deserialize_value(&value))
             .transpose()?;
@@ -1181,15 +2235,22 @@ impl CryptoStore for SqliteCryptoStore {
     async fn load_dehydrated_device_pickle_key(&self) -> Result<Option<DehydratedDeviceKey>> {
         let conn = self.acquire().await?;
 
-        conn.get_kv(DEHYDRATED_DEVICE_PICKLE_KEY)
+        conn.get_kv(&self.kv_key(DEHYDRATED_DEVICE_PICKLE_KEY))
             .await?
             .map(|value| self.deserialize_value(&value))
             .transpose()
     }
 
+    async fn delete_backup_decryption_key(&self) -> Result<(), Self::Error> {
+        let conn = self.acquire().await?;
+        conn.clear_kv(&self.kv_key("recovery_key_v1")).await?;
+
+        Ok(())
+    }
+
     async fn delete_dehydrated_device_pickle_key(&self) -> Result<(), Self::Error> {
         let conn = self.acquire().await?;
-        conn.clear_kv(DEHYDRATED_DEVICE_PICKLE_KEY).await?;
+        conn.clear_kv(&self.kv_key(DEHYDRATED_DEVICE_PICKLE_KEY)).await?;
 
         Ok(())
     }
@@ -1218,7 +2279,23 @@ impl CryptoStore for SqliteCryptoStore {
     async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>> {
         self.acquire()
             .await?
-            .get_tracked_users()
+            .get_tracked_users(self.account().to_owned())
+            .await?
+            .iter()
+            .map(|value| self.deserialize_value(value))
+            .collect()
+    }
+
+    async fn load_tracked_users_paged(
+        &self,
+        after: Option<&UserId>,
+        limit: usize,
+    ) -> Result<Vec<TrackedUser>> {
+        let after_user_id = after.map(|u| self.encode_key("tracked_users", u.as_bytes()));
+
+        self.acquire()
+            .await?
+            .get_tracked_users_paged(self.account().to_owned(), after_user_id, limit)
             .await?
             .iter()
             .map(|value| self.deserialize_value(value))
@@ -1226,7 +2303,7 @@ impl CryptoStore for SqliteCryptoStore {
     }
 
     async fn save_tracked_users(&self, tracked_users: &[(&UserId, bool)]) -> Result<()> {
-        let users: Vec<(Key, Vec<u8>)> = tracked_users
+        let users: Vec<(Key, Zeroizing<Vec<u8>>)> = tracked_users
             .iter()
             .map(|(u, d)| {
                 let user_id = self.encode_key("tracked_users", u.as_bytes());
@@ -1236,7 +2313,7 @@ impl CryptoStore for SqliteCryptoStore {
             })
             .collect::<Result<_>>()?;
 
-        Ok(self.acquire().await?.add_tracked_users(users).await?)
+        Ok(self.acquire().await?.add_tracked_users(self.account().to_owned(), users).await?)
     }
 
     async fn get_device(
@@ -1292,11 +2369,26 @@ impl CryptoStore for SqliteCryptoStore {
             .transpose()?)
     }
 
+    async fn get_verification_audit_log(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<VerificationAuditEntry>> {
+        let user_id = self.encode_key("verification_audit_entry", user_id.as_bytes());
+        self.acquire()
+            .await?
+            .get_verification_audit_entries(user_id)
+            .await?
+            .into_iter()
+            .map(|value| self.deserialize_value(&value))
+            .collect()
+    }
+
     async fn is_message_known(
         &self,
         message_hash: &matrix_sdk_crypto::olm::OlmMessageHash,
     ) -> Result<bool> {
         let value = rmp_serde::to_vec(message_hash)?;
+        let value = self.namespaced_bytes(&value).into_owned();
         Ok(self.acquire().await?.has_olm_hash(value).await?)
     }
 
@@ -1318,7 +2410,8 @@ impl CryptoStore for SqliteCryptoStore {
         &self,
         key_info: &SecretInfo,
     ) -> Result<Option<GossipRequest>> {
-        let requests = self.acquire().await?.get_outgoing_secret_requests().await?;
+        let requests =
+            self.acquire().await?.get_outgoing_secret_requests(self.account().to_owned()).await?;
         for (request, sent_out) in requests {
             let request = self.deserialize_key_request(&request, sent_out)?;
             if request.info == *key_info {
@@ -1331,7 +2424,7 @@ impl CryptoStore for SqliteCryptoStore {
     async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
         self.acquire()
             .await?
-            .get_unsent_secret_requests()
+            .get_unsent_secret_requests(self.account().to_owned())
             .await?
             .iter()
             .map(|value| {
@@ -1385,6 +2478,69 @@ impl CryptoStore for SqliteCryptoStore {
             .transpose()
     }
 
+    async fn record_megolm_message_index(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        let room_id = self.encode_key("megolm_message_index", room_id);
+        let session_id = self.encode_key("megolm_message_index", session_id);
+        let serialized_event_id = self.serialize_value(&event_id)?;
+        let account = self.account().to_owned();
+
+        let recorded = self
+            .acquire()
+            .await?
+            .with_transaction(move |txn| {
+                let recorded: Option<Vec<u8>> = txn
+                    .query_row(
+                        "SELECT event_id FROM megolm_message_index
+                        WHERE room_id = ?1 AND session_id = ?2 AND message_index = ?3",
+                        (&room_id, &session_id, message_index),
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                if recorded.is_none() {
+                    txn.execute(
+                        "INSERT INTO megolm_message_index
+                        (room_id, session_id, message_index, event_id, account)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                        (
+                            &room_id,
+                            &session_id,
+                            message_index,
+                            serialized_event_id.as_slice(),
+                            &account,
+                        ),
+                    )?;
+                }
+
+                Ok::<_, rusqlite::Error>(recorded)
+            })
+            .await?;
+
+        recorded.map(|value| self.deserialize_value(&value)).transpose()
+    }
+
+    async fn get_session_share_history(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Vec<SessionShareRecord>> {
+        let room_id = self.encode_key("session_share_record", room_id);
+        let session_id = self.encode_key("session_share_record", session_id);
+        self.acquire()
+            .await?
+            .get_session_share_records(room_id, session_id)
+            .await?
+            .into_iter()
+            .map(|value| self.deserialize_value(&value))
+            .collect()
+    }
+
     async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
         let room_id = self.encode_key("room_settings", room_id.as_bytes());
         let Some(value) = self.acquire().await?.get_room_settings(room_id).await? else {
@@ -1412,12 +2568,11 @@ impl CryptoStore for SqliteCryptoStore {
     }
 
     async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        let Some(serialized) = self.acquire().await?.get_kv(key).await? else {
+        let Some(serialized) = self.acquire().await?.get_kv(&self.kv_key(key)).await? else {
             return Ok(None);
         };
         let value = if let Some(cipher) = &self.store_cipher {
-            let encrypted = rmp_serde::from_slice(&serialized)?;
-            cipher.decrypt_value_data(encrypted)?
+            cipher.decrypt(&serialized)?
         } else {
             serialized
         };
@@ -1426,19 +2581,15 @@ impl CryptoStore for SqliteCryptoStore {
     }
 
     async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()> {
-        let serialized = if let Some(cipher) = &self.store_cipher {
-            let encrypted = cipher.encrypt_value_data(value)?;
-            rmp_serde::to_vec_named(&encrypted)?
-        } else {
-            value
-        };
+        let serialized =
+            if let Some(cipher) = &self.store_cipher { cipher.encrypt(value)? } else { value };
 
-        self.acquire().await?.set_kv(key, serialized).await?;
+        self.acquire().await?.set_kv(&self.kv_key(key), serialized).await?;
         Ok(())
     }
 
     async fn remove_custom_value(&self, key: &str) -> Result<()> {
-        let key = key.to_owned();
+        let key = self.kv_key(key).into_owned();
         self.acquire()
             .await?
             .interact(move |conn| conn.execute("DELETE FROM kv WHERE key = ?1", (&key,)))
@@ -1447,13 +2598,38 @@ impl CryptoStore for SqliteCryptoStore {
         Ok(())
     }
 
+    async fn get_app_data(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let namespace = self.encode_key("app_data", namespace);
+        let key = self.encode_key("app_data", key);
+        let Some(data) = self.acquire().await?.get_app_data(namespace, key).await? else {
+            return Ok(None);
+        };
+
+        let (_, value): (String, Vec<u8>) = self.deserialize_value(&data)?;
+        Ok(Some(value))
+    }
+
+    async fn get_app_data_keys(&self, namespace: &str) -> Result<Vec<String>> {
+        let namespace = self.encode_key("app_data", namespace);
+        self.acquire()
+            .await?
+            .get_app_data_for_namespace(namespace)
+            .await?
+            .into_iter()
+            .map(|data| {
+                let (key, _): (String, Vec<u8>) = self.deserialize_value(&data)?;
+                Ok(key)
+            })
+            .collect()
+    }
+
     async fn try_take_leased_lock(
         &self,
         lease_duration_ms: u32,
         key: &str,
         holder: &str,
     ) -> Result<bool> {
-        let key = key.to_owned();
+        let key = self.kv_key(key).into_owned();
         let holder = holder.to_owned();
 
         let now_ts: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
@@ -1482,33 +2658,260 @@ impl CryptoStore for SqliteCryptoStore {
 
     async fn next_batch_token(&self) -> Result<Option<String>, Self::Error> {
         let conn = self.acquire().await?;
-        if let Some(token) = conn.get_kv("next_batch_token").await? {
+        if let Some(token) = conn.get_kv(&self.kv_key("next_batch_token")).await? {
             let maybe_token: Option<String> = self.deserialize_value(&token)?;
             Ok(maybe_token)
         } else {
             Ok(None)
         }
     }
+
+    async fn compact(&self) -> Result<CompactionReport, Self::Error> {
+        let conn = self.acquire().await?;
+
+        let size_before = conn.database_size().await?;
+        conn.compact().await?;
+        let size_after = conn.database_size().await?;
+
+        debug!(size_before, size_after, "Compacted the crypto store");
+
+        Ok(CompactionReport { size_before: Some(size_before), size_after: Some(size_after) })
+    }
+
+    async fn load_change_journal(&self, since: u64) -> Result<Vec<ChangeJournalRecord>> {
+        self.acquire()
+            .await?
+            .get_change_journal(self.account().to_owned(), since)
+            .await?
+            .into_iter()
+            .map(|(sequence_number, value)| {
+                let entry: ChangeJournalEntry = self.deserialize_value(&value)?;
+                Ok(ChangeJournalRecord { sequence_number, entry })
+            })
+            .collect()
+    }
+
+    async fn truncate_change_journal(&self, up_to: u64) -> Result<()> {
+        self.acquire().await?.truncate_change_journal(self.account().to_owned(), up_to).await
+    }
+
+    async fn shred(&self) -> Result<ShredReport> {
+        let _guard = self.save_changes_lock.lock().await;
+
+        let conn = self.acquire().await?;
+
+        // Make SQLite overwrite the deleted content with zeros, instead of only
+        // marking its pages as free.
+        conn.execute_batch("PRAGMA secure_delete = ON;").await?;
+
+        let this = self.clone();
+        let result = conn
+            .with_transaction(move |txn| {
+                let secure_delete: bool =
+                    txn.query_row("PRAGMA secure_delete", (), |row| row.get(0))?;
+
+                let account_deleted =
+                    txn.execute("DELETE FROM kv WHERE key = ?", (this.kv_key("account"),))? > 0;
+
+                // Every other value of the account goes as well, the private
+                // identity, the backup keys and the custom values included. The
+                // store cipher is kept, so the public data can still be read.
+                let cipher_key = this.kv_key("cipher");
+                match &this.account_namespace {
+                    Some(_) => txn.execute(
+                        "DELETE FROM kv WHERE substr(key, 1, length(?1)) = ?1 AND key != ?2",
+                        (this.kv_key(""), &cipher_key),
+                    )?,
+                    // The keys of the other accounts contain the separator.
+                    None => txn.execute(
+                        "DELETE FROM kv WHERE instr(key, ?1) = 0 AND key NOT IN ('version', ?2)",
+                        (NAMESPACE_SEPARATOR.to_string(), &cipher_key),
+                    )?,
+                };
+
+                let sessions_deleted =
+                    txn.execute("DELETE FROM session WHERE account = ?", (this.account(),))?;
+                let inbound_group_sessions_deleted = txn.execute(
+                    "DELETE FROM inbound_group_session WHERE account = ?",
+                    (this.account(),),
+                )?;
+
+                for table in [
+                    "outbound_group_session",
+                    "secrets",
+                    "received_room_key_bundle",
+                    "megolm_message_index",
+                    "session_share_record",
+                    "verification_audit_entry",
+                    "app_data",
+                    "change_journal",
+                    "quarantined_entry",
+                ] {
+                    txn.execute(
+                        &format!("DELETE FROM {table} WHERE account = ?"),
+                        (this.account(),),
+                    )?;
+                }
+
+                Ok::<_, Error>(ShredReport {
+                    account_deleted,
+                    sessions_deleted,
+                    inbound_group_sessions_deleted,
+                    secure_delete,
+                })
+            })
+            .await;
+
+        // The connection goes back to the pool, restore the default setting once
+        // everything was deleted.
+        conn.execute_batch("PRAGMA secure_delete = OFF;").await?;
+        let report = result?;
+
+        // The deleted content may still be in the write-ahead log.
+        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);").await?;
+
+        debug!(?report, "Shredded the crypto store");
+
+        Ok(report)
+    }
+
+    async fn delete_room_crypto_state(
+        &self,
+        room_id: &RoomId,
+        custom_value_keys: &[String],
+    ) -> Result<DeletedRoomCryptoState> {
+        let _guard = self.save_changes_lock.lock().await;
+
+        let inbound_room_id = self.encode_key("inbound_group_session", room_id.as_bytes());
+        let message_index_room_id = self.encode_key("megolm_message_index", room_id);
+        let share_record_room_id = self.encode_key("session_share_record", room_id);
+        let settings_room_id = self.encode_key("room_settings", room_id.as_bytes());
+        let outbound_room_id = self.encode_key("outbound_group_session", room_id.as_bytes());
+        let withheld_room_id = self.encode_key("direct_withheld_info", room_id);
+        let bundle_room_id = self.encode_key("received_room_key_bundle", room_id);
+        let custom_value_keys: Vec<_> =
+            custom_value_keys.iter().map(|key| self.kv_key(key).into_owned()).collect();
+
+        let this = self.clone();
+        self.acquire()
+            .await?
+            .with_transaction(move |txn| {
+                let sessions = txn
+                    .prepare(
+                        "SELECT data, backed_up FROM inbound_group_session \
+                         WHERE account = ? AND room_id = ?",
+                    )?
+                    .query_map((this.account(), &inbound_room_id), |row| {
+                        Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, bool>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let room_keys = sessions
+                    .into_iter()
+                    .map(|(value, backed_up)| {
+                        this.deserialize_and_unpickle_inbound_group_session(value, backed_up)
+                            .map(|session| RoomKeyInfo::from(&session))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                txn.execute(
+                    "DELETE FROM inbound_group_session WHERE account = ? AND room_id = ?",
+                    (this.account(), &inbound_room_id),
+                )?;
+                txn.execute(
+                    "DELETE FROM megolm_message_index WHERE room_id = ?",
+                    (&message_index_room_id,),
+                )?;
+                txn.execute(
+                    "DELETE FROM session_share_record WHERE room_id = ?",
+                    (&share_record_room_id,),
+                )?;
+                txn.execute("DELETE FROM room_settings WHERE room_id = ?", (&settings_room_id,))?;
+
+                for key in &custom_value_keys {
+                    txn.execute("DELETE FROM kv WHERE key = ?", (key,))?;
+                }
+
+                let outbound_group_session_deleted = txn.execute(
+                    "DELETE FROM outbound_group_session WHERE room_id = ?",
+                    (&outbound_room_id,),
+                )? > 0;
+                let withheld_deleted = txn.execute(
+                    "DELETE FROM direct_withheld_info WHERE room_id = ?",
+                    (&withheld_room_id,),
+                )?;
+
+                // The sender of a bundle is only stored as a hashed key, read it back from the
+                // bundle data.
+                let bundles = txn
+                    .prepare("SELECT bundle_data FROM received_room_key_bundle WHERE room_id = ?")?
+                    .query_map((&bundle_room_id,), |row| row.get::<_, Vec<u8>>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut room_key_bundle_senders = bundles
+                    .iter()
+                    .map(|value| {
+                        this.deserialize_value::<StoredRoomKeyBundleData>(value)
+                            .map(|bundle| bundle.sender_user)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                room_key_bundle_senders.sort();
+
+                txn.execute(
+                    "DELETE FROM received_room_key_bundle WHERE room_id = ?",
+                    (&bundle_room_id,),
+                )?;
+
+                Ok::<_, Error>(DeletedRoomCryptoState {
+                    room_keys,
+                    outbound_group_session_deleted,
+                    withheld_deleted,
+                    room_key_bundle_senders,
+                })
+            })
+            .await
+    }
+}
+
+impl AppserviceCryptoStore for SqliteCryptoStore {
+    /// Get a view on this store, in the account namespace of the given user.
+    ///
+    /// The views share the database connections and the store cipher of this
+    /// store. The keys of their data contain the namespace, so they are kept
+    /// apart nonetheless.
+    fn store_for_user(&self, user_id: &UserId) -> Result<Arc<DynCryptoStore>, CryptoStoreError> {
+        let namespace = namespaced_key(self.account_namespace.as_deref(), user_id.as_str());
+        let store = Self::new(self.pool.clone(), self.store_cipher.clone(), Some(&namespace));
+
+        Ok(store.into_crypto_store())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{path::Path, sync::Arc, time::Duration};
 
+    use assert_matches::assert_matches;
     use matrix_sdk_common::deserialized_responses::WithheldCode;
     use matrix_sdk_crypto::{
-        cryptostore_integration_tests, cryptostore_integration_tests_time, olm::SenderDataType,
-        store::CryptoStore,
+        cryptostore_integration_tests, cryptostore_integration_tests_time,
+        olm::SenderDataType,
+        store::{
+            types::{Changes, PendingChanges, QuarantinedEntryKind},
+            CryptoStore, CryptoStoreError, StoreCipher,
+        },
+        Account, OlmMachine,
     };
     use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
-    use ruma::{device_id, room_id, user_id};
+    use ruma::{device_id, room_id, server_name, user_id};
     use similar_asserts::assert_eq;
     use tempfile::{tempdir, TempDir};
     use tokio::fs;
 
     use super::SqliteCryptoStore;
-    use crate::SqliteStoreConfig;
+    use crate::{
+        utils::{SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt},
+        JournalMode, OpenStoreError, SqliteStoreConfig, Synchronous,
+    };
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
 
@@ -1554,6 +2957,370 @@ mod tests {
         assert_eq!(store.pool.status().max_size, 42);
     }
 
+    #[async_test]
+    async fn test_connection_config() {
+        let path = TMP_DIR.path().join("test_connection_config");
+        let store_open_config = SqliteStoreConfig::new(path)
+            .journal_mode(JournalMode::Truncate)
+            .busy_timeout(Duration::from_millis(1234))
+            .synchronous(Synchronous::Normal);
+
+        let store = SqliteCryptoStore::open_with_config(store_open_config).await.unwrap();
+        let conn = store.acquire().await.unwrap();
+
+        let journal_mode: String =
+            conn.query_row("PRAGMA journal_mode", (), |row| row.get(0)).await.unwrap();
+        assert_eq!(journal_mode, "truncate");
+
+        let busy_timeout: u64 =
+            conn.query_row("PRAGMA busy_timeout", (), |row| row.get(0)).await.unwrap();
+        assert_eq!(busy_timeout, 1234);
+
+        // `NORMAL` is reported as `1`.
+        let synchronous: u8 =
+            conn.query_row("PRAGMA synchronous", (), |row| row.get(0)).await.unwrap();
+        assert_eq!(synchronous, 1);
+
+        // The other connections of the pool are configured as well.
+        let other_conn = store.acquire().await.unwrap();
+        let busy_timeout: u64 =
+            other_conn.query_row("PRAGMA busy_timeout", (), |row| row.get(0)).await.unwrap();
+        assert_eq!(busy_timeout, 1234);
+    }
+
+    /// A toy cipher standing in for a hardware-backed one.
+    #[derive(Debug)]
+    struct XorCipher(u8);
+
+    impl StoreCipher for XorCipher {
+        fn encrypt(&self, plaintext: Vec<u8>) -> Result<Vec<u8>, CryptoStoreError> {
+            Ok(plaintext.into_iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoStoreError> {
+            Ok(ciphertext.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn hash_key(&self, _table_name: &str, key: &[u8]) -> [u8; 32] {
+            let mut hash = [self.0; 32];
+            for (i, b) in key.iter().enumerate() {
+                hash[i % 32] ^= b;
+            }
+            hash
+        }
+    }
+
+    #[async_test]
+    async fn test_open_with_custom_cipher() {
+        let path = TMP_DIR.path().join("test_open_with_custom_cipher");
+
+        let store = SqliteCryptoStore::open_with_cipher(
+            SqliteStoreConfig::new(&path),
+            Arc::new(XorCipher(0x42)),
+        )
+        .await
+        .unwrap();
+
+        store.set_custom_value("secret", b"hunter2".to_vec()).await.unwrap();
+
+        // The value is encrypted using our cipher before it hits the database.
+        let raw = store.acquire().await.unwrap().get_kv("secret").await.unwrap().unwrap();
+        assert_eq!(raw, XorCipher(0x42).encrypt(b"hunter2".to_vec()).unwrap());
+        drop(store);
+
+        // Opening the store again with the same cipher lets us read the value back.
+        let store = SqliteCryptoStore::open_with_cipher(
+            SqliteStoreConfig::new(&path),
+            Arc::new(XorCipher(0x42)),
+        )
+        .await
+        .unwrap();
+
+        let value = store.get_custom_value("secret").await.unwrap();
+        assert_eq!(value.as_deref(), Some(b"hunter2".as_slice()));
+    }
+
+    #[async_test]
+    async fn test_account_namespaces_are_isolated() {
+        let path = TMP_DIR.path().join("test_account_namespaces_are_isolated");
+
+        let open_store = |namespace: Option<&'static str>, passphrase: Option<&'static str>| {
+            let config =
+                SqliteStoreConfig::new(&path).account_namespace(namespace).passphrase(passphrase);
+            SqliteCryptoStore::open_with_config(config)
+        };
+
+        // Both accounts use the same database file, with different passphrases.
+        let alice_store = open_store(Some("alice"), Some("alice's passphrase")).await.unwrap();
+        let bob_store = open_store(Some("bob"), Some("bob's passphrase")).await.unwrap();
+        let default_store = open_store(None, None).await.unwrap();
+
+        let alice = Account::with_device_id(user_id!("@alice:localhost"), device_id!("ALICE"));
+        let bob = Account::with_device_id(user_id!("@bob:localhost"), device_id!("BOB"));
+
+        alice_store
+            .save_pending_changes(PendingChanges { account: Some(alice.deep_clone()) })
+            .await
+            .unwrap();
+        bob_store
+            .save_pending_changes(PendingChanges { account: Some(bob.deep_clone()) })
+            .await
+            .unwrap();
+
+        // Every namespace only sees its own account.
+        let loaded = alice_store.load_account().await.unwrap().unwrap();
+        assert_eq!(loaded.user_id(), alice.user_id());
+        let loaded = bob_store.load_account().await.unwrap().unwrap();
+        assert_eq!(loaded.user_id(), bob.user_id());
+        assert!(default_store.load_account().await.unwrap().is_none());
+
+        // Tables which are listed in full are isolated as well.
+        alice_store.save_tracked_users(&[(user_id!("@carol:localhost"), false)]).await.unwrap();
+        assert_eq!(alice_store.load_tracked_users().await.unwrap().len(), 1);
+        assert!(bob_store.load_tracked_users().await.unwrap().is_empty());
+        assert!(default_store.load_tracked_users().await.unwrap().is_empty());
+
+        // And so are the custom values.
+        alice_store.set_custom_value("custom", b"alice".to_vec()).await.unwrap();
+        assert!(bob_store.get_custom_value("custom").await.unwrap().is_none());
+
+        // Reopening a namespace gives us back its data.
+        drop(alice_store);
+        let alice_store = open_store(Some("alice"), Some("alice's passphrase")).await.unwrap();
+        let value = alice_store.get_custom_value("custom").await.unwrap();
+        assert_eq!(value.as_deref(), Some(b"alice".as_slice()));
+    }
+
+    #[async_test]
+    async fn test_invalid_account_namespaces_are_rejected() {
+        let path = TMP_DIR.path().join("test_invalid_account_namespaces_are_rejected");
+
+        for namespace in ["", "alice\u{1f}bob"] {
+            let config = SqliteStoreConfig::new(&path).account_namespace(Some(namespace));
+            assert_matches!(
+                SqliteCryptoStore::open_with_config(config).await,
+                Err(OpenStoreError::InvalidAccountNamespace(_))
+            );
+
+            let config = SqliteStoreConfig::new(&path).account_namespace(Some(namespace));
+            assert_matches!(
+                SqliteCryptoStore::open_with_cipher(config, Arc::new(XorCipher(0x42))).await,
+                Err(OpenStoreError::InvalidAccountNamespace(_))
+            );
+        }
+    }
+
+    #[async_test]
+    async fn test_appservice_users_share_the_store() {
+        let path = TMP_DIR.path().join("test_appservice_users_share_the_store");
+        let open_appservice = || {
+            let path = path.clone();
+            async move {
+                let store = SqliteCryptoStore::open(path, Some("passphrase")).await.unwrap();
+                OlmMachine::for_appservice(
+                    server_name!("localhost"),
+                    "_bridge_*",
+                    device_id!("BRIDGE"),
+                    Arc::new(store),
+                )
+            }
+        };
+
+        let alice = user_id!("@_bridge_alice:localhost");
+        let bob = user_id!("@_bridge_bob:localhost");
+
+        let appservice = open_appservice().await;
+        let alice_keys = appservice.machine_for_user(alice).await.unwrap().identity_keys();
+        let bob_keys = appservice.machine_for_user(bob).await.unwrap().identity_keys();
+        assert_ne!(alice_keys.curve25519, bob_keys.curve25519);
+        drop(appservice);
+
+        // Every user gets back their own account after a restart.
+        let appservice = open_appservice().await;
+        let machine = appservice.machine_for_user(alice).await.unwrap();
+        assert_eq!(machine.identity_keys().curve25519, alice_keys.curve25519);
+        let machine = appservice.machine_for_user(bob).await.unwrap();
+        assert_eq!(machine.identity_keys().curve25519, bob_keys.curve25519);
+    }
+
+    #[async_test]
+    async fn test_shred_only_deletes_its_own_account() {
+        let path = TMP_DIR.path().join("test_shred_only_deletes_its_own_account");
+
+        let open_store = |namespace: Option<&'static str>| {
+            let config = SqliteStoreConfig::new(&path)
+                .account_namespace(namespace)
+                .passphrase(Some("passphrase"));
+            SqliteCryptoStore::open_with_config(config)
+        };
+
+        let alice_store = open_store(Some("alice")).await.unwrap();
+        let bob_store = open_store(Some("bob")).await.unwrap();
+
+        let alice = Account::with_device_id(user_id!("@alice:localhost"), device_id!("ALICE"));
+        let bob = Account::with_device_id(user_id!("@bob:localhost"), device_id!("BOB"));
+        let room_id = room_id!("!test:localhost");
+
+        for (store, account) in [(&alice_store, &alice), (&bob_store, &bob)] {
+            store
+                .save_pending_changes(PendingChanges { account: Some(account.deep_clone()) })
+                .await
+                .unwrap();
+
+            let (outbound, _) = account.create_group_session_pair_with_defaults(room_id).await;
+            let changes = Changes { outbound_group_sessions: vec![outbound], ..Default::default() };
+            store.save_changes(changes).await.unwrap();
+            store.set_custom_value("custom", b"value".to_vec()).await.unwrap();
+        }
+
+        let report = alice_store.shred().await.unwrap();
+        assert!(report.account_deleted);
+        assert!(report.secure_delete);
+
+        assert!(alice_store.load_account().await.unwrap().is_none());
+        assert!(alice_store.get_outbound_group_session(room_id).await.unwrap().is_none());
+        assert!(alice_store.get_custom_value("custom").await.unwrap().is_none());
+
+        // The other account sharing the database is untouched.
+        assert!(bob_store.load_account().await.unwrap().is_some());
+        assert!(bob_store.get_outbound_group_session(room_id).await.unwrap().is_some());
+        assert!(bob_store.get_custom_value("custom").await.unwrap().is_some());
+
+        // The store cipher is kept, so the namespace can still be opened.
+        drop(alice_store);
+        open_store(Some("alice")).await.unwrap();
+    }
+
+    #[async_test]
+    async fn test_backup_to() {
+        let path = TMP_DIR.path().join("test_backup_to");
+        let store = SqliteCryptoStore::open(&path, Some("passphrase")).await.unwrap();
+
+        let account = Account::with_device_id(user_id!("@alice:localhost"), device_id!("ALICE"));
+        store
+            .save_pending_changes(PendingChanges { account: Some(account.deep_clone()) })
+            .await
+            .unwrap();
+        store.set_custom_value("custom", b"value".to_vec()).await.unwrap();
+
+        let backup_path = TMP_DIR.path().join("test_backup_to_copy");
+        store.backup_to(&backup_path).await.unwrap();
+
+        // The store stays usable after the backup, changes don't end up in the copy.
+        store.set_custom_value("custom", b"changed".to_vec()).await.unwrap();
+
+        // The copy can be opened using the same passphrase.
+        let backup = SqliteCryptoStore::open(&backup_path, Some("passphrase")).await.unwrap();
+        let loaded = backup.load_account().await.unwrap().unwrap();
+        assert_eq!(loaded.user_id(), account.user_id());
+        let value = backup.get_custom_value("custom").await.unwrap();
+        assert_eq!(value.as_deref(), Some(b"value".as_slice()));
+    }
+
+    #[async_test]
+    async fn test_compact() {
+        let path = TMP_DIR.path().join("test_compact");
+        let store = SqliteCryptoStore::open(&path, None).await.unwrap();
+
+        async fn fill_and_clear(store: &SqliteCryptoStore) {
+            for i in 0..100 {
+                store.set_custom_value(&format!("key_{i}"), vec![0; 4096]).await.unwrap();
+            }
+            for i in 0..100 {
+                store.remove_custom_value(&format!("key_{i}")).await.unwrap();
+            }
+        }
+
+        fill_and_clear(&store).await;
+
+        let report = store.compact().await.unwrap();
+        let size_before = report.size_before.expect("The SQLite store should measure its size");
+        let size_after = report.size_after.expect("The SQLite store should measure its size");
+        assert!(size_after < size_before, "The store didn't shrink: {size_before} -> {size_after}");
+
+        // The first compaction switches the database to the incremental auto-vacuum mode.
+        let conn = store.acquire().await.unwrap();
+        let auto_vacuum: u8 =
+            conn.query_row("PRAGMA auto_vacuum", (), |row| row.get(0)).await.unwrap();
+        assert_eq!(auto_vacuum, 2);
+        drop(conn);
+
+        // Later compactions only use an incremental vacuum, which reclaims the space as
+        // well.
+        fill_and_clear(&store).await;
+
+        let report = store.compact().await.unwrap();
+        assert!(report.reclaimed().unwrap() > 0);
+    }
+
+    #[async_test]
+    async fn test_lenient_mode_quarantines_corrupted_inbound_group_sessions() {
+        let path = TMP_DIR.path().join("test_lenient_mode_quarantines_corrupted_sessions");
+        let store = SqliteCryptoStore::open(&path, None).await.unwrap();
+
+        let account = Account::with_device_id(user_id!("@alice:localhost"), device_id!("ALICE"));
+        let room_id = room_id!("!test:localhost");
+        let (_, first) = account.create_group_session_pair_with_defaults(room_id).await;
+        let (_, second) = account.create_group_session_pair_with_defaults(room_id).await;
+        store.save_inbound_group_sessions(vec![first.clone(), second.clone()], None).await.unwrap();
+
+        // Corrupt one of the two sessions.
+        store
+            .acquire()
+            .await
+            .unwrap()
+            .execute(
+                "UPDATE inbound_group_session SET data = x'00' \
+                 WHERE rowid = (SELECT MIN(rowid) FROM inbound_group_session)",
+                (),
+            )
+            .await
+            .unwrap();
+
+        let machine =
+            OlmMachine::with_store(account.user_id(), account.device_id(), store.clone(), None)
+                .await
+                .unwrap();
+
+        // By default, the corrupted session breaks loading all the sessions.
+        machine.store().get_inbound_group_sessions().await.unwrap_err();
+        assert!(machine.store().quarantined_entries().await.unwrap().is_empty());
+
+        // In lenient mode, it's moved to the quarantine instead.
+        machine.store().set_lenient(true);
+        let sessions = machine.store().get_inbound_group_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0] == first || sessions[0] == second);
+
+        let quarantined = machine.store().quarantined_entries().await.unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].kind, QuarantinedEntryKind::InboundGroupSession);
+        assert_eq!(quarantined[0].value.expose_secret(), &[0]);
+
+        // The quarantine survives reopening the store.
+        drop(machine);
+        drop(store);
+        let store = SqliteCryptoStore::open(&path, None).await.unwrap();
+        assert_eq!(store.get_quarantined_entries().await.unwrap(), quarantined);
+        assert_eq!(store.get_inbound_group_sessions().await.unwrap(), sessions);
+
+        // Restoring the quarantine puts the corrupted session back in place.
+        assert_eq!(store.restore_quarantined_entries().await.unwrap(), 1);
+        assert!(store.get_quarantined_entries().await.unwrap().is_empty());
+        store.get_inbound_group_sessions().await.unwrap_err();
+
+        // Lenient mode also covers the other ways to load sessions.
+        let machine =
+            OlmMachine::with_store(account.user_id(), account.device_id(), store.clone(), None)
+                .await
+                .unwrap();
+        machine.store().set_lenient(true);
+        let room_sessions =
+            machine.store().get_inbound_group_sessions_for_room(room_id, None).await.unwrap();
+        assert_eq!(room_sessions, sessions);
+        assert_eq!(machine.store().quarantined_entries().await.unwrap(), quarantined);
+    }
+
     /// Test that we didn't regress in our storage layer by loading data from a
     /// pre-filled database, or in other words use a test vector for this.
     #[async_test]
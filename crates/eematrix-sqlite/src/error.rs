@@ -65,6 +65,11 @@ pub enum OpenStoreError {
     /// Failed to save the store cipher to the DB.
     #[error("Failed to save the store cipher to the DB: {0}")]
     SaveCipher(#[source] rusqlite::Error),
+
+    /// The account namespace of the store is empty, or contains the character
+    /// separating it from the keys of the store.
+    #[error("Invalid account namespace: {0:?}")]
+    InvalidAccountNamespace(String),
 }
 
 #[derive(Debug, Error)]
@@ -107,6 +112,10 @@ pub enum Error {
 
     #[error("The store contains invalid data: {details}")]
     InvalidData { details: String },
+
+    #[cfg(feature = "crypto-store")]
+    #[error(transparent)]
+    Cipher(CryptoStoreError),
 }
 
 macro_rules! impl_from {
@@ -139,11 +148,16 @@ impl_from!(PoolError => Error::Pool);
 impl_from!(rmp_serde::encode::Error => Error::Encode);
 impl_from!(rmp_serde::decode::Error => Error::Decode);
 impl_from!(matrix_sdk_store_encryption::Error => Error::Encryption);
+#[cfg(feature = "crypto-store")]
+impl_from!(CryptoStoreError => Error::Cipher);
 
 #[cfg(feature = "crypto-store")]
 impl From<Error> for CryptoStoreError {
     fn from(e: Error) -> Self {
-        CryptoStoreError::backend(e)
+        match e {
+            Error::Cipher(e) => e,
+            e => CryptoStoreError::backend(e),
+        }
     }
 }
 
@@ -27,6 +27,7 @@ mod utils;
 use std::{
     fmt,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use deadpool_sqlite::PoolConfig;
@@ -53,6 +54,12 @@ pub struct SqliteStoreConfig {
     pool_config: PoolConfig,
     /// The runtime configuration to apply when opening an SQLite connection.
     runtime_config: RuntimeConfig,
+    /// The account namespace used to isolate the crypto state of an account,
+    /// if any.
+    account_namespace: Option<String>,
+    /// The configuration to apply to every SQLite connection of the crypto
+    /// store.
+    connection_config: ConnectionConfig,
 }
 
 impl fmt::Debug for SqliteStoreConfig {
@@ -62,6 +69,8 @@ impl fmt::Debug for SqliteStoreConfig {
             .field("path", &self.path)
             .field("pool_config", &self.pool_config)
             .field("runtime_config", &self.runtime_config)
+            .field("account_namespace", &self.account_namespace)
+            .field("connection_config", &self.connection_config)
             .finish_non_exhaustive()
     }
 }
@@ -78,6 +87,8 @@ impl SqliteStoreConfig {
             passphrase: None,
             pool_config: PoolConfig::new(num_cpus::get_physical() * 4),
             runtime_config: RuntimeConfig::default(),
+            account_namespace: None,
+            connection_config: ConnectionConfig::default(),
         }
     }
 
@@ -118,6 +129,26 @@ impl SqliteStoreConfig {
         self
     }
 
+    /// Define the account namespace of the store.
+    ///
+    /// Multiple [`SqliteCryptoStore`]s can share the same database file as
+    /// long as each of them uses a different namespace, the crypto state of
+    /// every account is kept isolated from the others. Each namespace uses its
+    /// own store cipher, so the accounts may use different passphrases.
+    ///
+    /// Stores opened without a namespace use the default namespace, which is
+    /// the one that existing databases have been using. Opening a store with
+    /// an empty namespace, or with one containing the `U+001F` control
+    /// character, fails with [`OpenStoreError::InvalidAccountNamespace`].
+    ///
+    /// This is only used by the [`SqliteCryptoStore`], the other stores ignore
+    /// it.
+    #[cfg(feature = "crypto-store")]
+    pub fn account_namespace(mut self, namespace: Option<&str>) -> Self {
+        self.account_namespace = namespace.map(|namespace| namespace.to_owned());
+        self
+    }
+
     /// Define the maximum pool size for [`deadpool_sqlite`].
     ///
     /// See [`deadpool_sqlite::PoolConfig::max_size`] to learn more.
@@ -177,6 +208,119 @@ impl SqliteStoreConfig {
         self.runtime_config.journal_size_limit = limit;
         self
     }
+
+    /// Define the journal mode of the database.
+    ///
+    /// See [`PRAGMA journal_mode`] to learn more.
+    ///
+    /// The default value is [`JournalMode::Wal`].
+    ///
+    /// [`PRAGMA journal_mode`]: https://www.sqlite.org/pragma.html#pragma_journal_mode
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.runtime_config.journal_mode = journal_mode;
+        self
+    }
+
+    /// Define how long a connection waits for a lock held by another
+    /// connection before failing with `SQLITE_BUSY`.
+    ///
+    /// See [`PRAGMA busy_timeout`] to learn more.
+    ///
+    /// The default value is 5 seconds.
+    ///
+    /// This is only used by the [`SqliteCryptoStore`], the other stores ignore
+    /// it.
+    ///
+    /// [`PRAGMA busy_timeout`]: https://www.sqlite.org/pragma.html#pragma_busy_timeout
+    #[cfg(feature = "crypto-store")]
+    pub fn busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.connection_config.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Define how often SQLite waits for data to reach the disk.
+    ///
+    /// Lower levels are faster, especially on slow flash storage, at the cost
+    /// of durability: with [`Synchronous::Normal`] in WAL mode, the last
+    /// transactions may be rolled back after a power loss, but the database
+    /// doesn't get corrupted.
+    ///
+    /// See [`PRAGMA synchronous`] to learn more.
+    ///
+    /// The default value is [`Synchronous::Full`].
+    ///
+    /// This is only used by the [`SqliteCryptoStore`], the other stores ignore
+    /// it.
+    ///
+    /// [`PRAGMA synchronous`]: https://www.sqlite.org/pragma.html#pragma_synchronous
+    #[cfg(feature = "crypto-store")]
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.connection_config.synchronous = synchronous;
+        self
+    }
+}
+
+/// The journal mode of an SQLite database.
+///
+/// See [`PRAGMA journal_mode`] to learn more.
+///
+/// [`PRAGMA journal_mode`]: https://www.sqlite.org/pragma.html#pragma_journal_mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JournalMode {
+    /// Use a write-ahead log, readers don't block writers and vice versa.
+    #[default]
+    Wal,
+    /// Use a rollback journal which is deleted at the end of each
+    /// transaction.
+    Delete,
+    /// Use a rollback journal which is truncated to zero length at the end of
+    /// each transaction.
+    Truncate,
+    /// Use a rollback journal whose header is overwritten with zeros at the
+    /// end of each transaction.
+    Persist,
+}
+
+impl JournalMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Wal => "WAL",
+            Self::Delete => "DELETE",
+            Self::Truncate => "TRUNCATE",
+            Self::Persist => "PERSIST",
+        }
+    }
+}
+
+/// The synchronous level of an SQLite connection.
+///
+/// See [`PRAGMA synchronous`] to learn more.
+///
+/// [`PRAGMA synchronous`]: https://www.sqlite.org/pragma.html#pragma_synchronous
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Synchronous {
+    /// Hand the data to the operating system and continue without syncing.
+    Off,
+    /// Sync at the most critical moments only.
+    Normal,
+    /// Sync after every transaction.
+    #[default]
+    Full,
+    /// Like [`Synchronous::Full`], and also sync the directory of a rollback
+    /// journal when it's unlinked.
+    Extra,
+}
+
+#[cfg_attr(not(feature = "crypto-store"), allow(dead_code))]
+impl Synchronous {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
 }
 
 /// This type represents values to set at runtime when a database is opened.
@@ -196,6 +340,10 @@ struct RuntimeConfig {
     /// [`utils::SqliteAsyncConnExt::journal_size_limit`] will always be called
     /// with this value.
     journal_size_limit: u32,
+
+    /// Regardless of the value, [`utils::SqliteAsyncConnExt::journal_mode`]
+    /// will always be called with this value.
+    journal_mode: JournalMode,
 }
 
 impl Default for RuntimeConfig {
@@ -207,6 +355,34 @@ impl Default for RuntimeConfig {
             cache_size: 2_000_000,
             // A limit of 10Mib.
             journal_size_limit: 10_000_000,
+            // The mode the databases are created with.
+            journal_mode: JournalMode::Wal,
+        }
+    }
+}
+
+/// This type represents values to set on every connection of a database.
+///
+/// Unlike the [`RuntimeConfig`], these settings only apply to the connection
+/// they're set on, so they are applied by [`utils::apply_connection_config`]
+/// to every connection of the pool, once it's created.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "crypto-store"), allow(dead_code))]
+struct ConnectionConfig {
+    /// How long to wait for a lock before failing.
+    busy_timeout: Duration,
+
+    /// The synchronous level of the connection.
+    synchronous: Synchronous,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            // The timeout `rusqlite` sets on the connections it opens.
+            busy_timeout: Duration::from_secs(5),
+            // The default of SQLite.
+            synchronous: Synchronous::Full,
         }
     }
 }
@@ -216,9 +392,10 @@ mod tests {
     use std::{
         ops::Not,
         path::{Path, PathBuf},
+        time::Duration,
     };
 
-    use super::SqliteStoreConfig;
+    use super::{JournalMode, SqliteStoreConfig, Synchronous};
 
     #[test]
     fn test_new() {
@@ -228,6 +405,9 @@ mod tests {
         assert!(store_config.runtime_config.optimize);
         assert_eq!(store_config.runtime_config.cache_size, 2_000_000);
         assert_eq!(store_config.runtime_config.journal_size_limit, 10_000_000);
+        assert_eq!(store_config.runtime_config.journal_mode, JournalMode::Wal);
+        assert_eq!(store_config.connection_config.busy_timeout, Duration::from_secs(5));
+        assert_eq!(store_config.connection_config.synchronous, Synchronous::Full);
     }
 
     #[test]
@@ -247,7 +427,8 @@ mod tests {
             .pool_max_size(42)
             .optimize(false)
             .cache_size(43)
-            .journal_size_limit(44);
+            .journal_size_limit(44)
+            .journal_mode(JournalMode::Truncate);
 
         assert_eq!(store_config.path, PathBuf::from("foo"));
         assert_eq!(store_config.passphrase, Some("bar".to_owned()));
@@ -255,6 +436,18 @@ mod tests {
         assert!(store_config.runtime_config.optimize.not());
         assert_eq!(store_config.runtime_config.cache_size, 43);
         assert_eq!(store_config.runtime_config.journal_size_limit, 44);
+        assert_eq!(store_config.runtime_config.journal_mode, JournalMode::Truncate);
+    }
+
+    #[cfg(feature = "crypto-store")]
+    #[test]
+    fn test_store_config_connection() {
+        let store_config = SqliteStoreConfig::new(Path::new("foo"))
+            .busy_timeout(Duration::from_millis(45))
+            .synchronous(Synchronous::Normal);
+
+        assert_eq!(store_config.connection_config.busy_timeout, Duration::from_millis(45));
+        assert_eq!(store_config.connection_config.synchronous, Synchronous::Normal);
     }
 
     #[test]
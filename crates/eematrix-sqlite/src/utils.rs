@@ -23,9 +23,11 @@ use ruma::time::SystemTime;
 use rusqlite::{limits::Limit, OptionalExtension, Params, Row, Statement, Transaction};
 use serde::{de::DeserializeOwned, Serialize};
 
+#[cfg(feature = "crypto-store")]
+use crate::ConnectionConfig;
 use crate::{
     error::{Error, Result},
-    OpenStoreError, RuntimeConfig,
+    JournalMode, OpenStoreError, RuntimeConfig,
 };
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -114,7 +116,8 @@ pub(crate) trait SqliteAsyncConnExt {
     /// It is possible to call these methods individually though. This
     /// `apply_runtime_config` method allows to automate this process.
     async fn apply_runtime_config(&self, runtime_config: RuntimeConfig) -> Result<()> {
-        let RuntimeConfig { optimize, cache_size, journal_size_limit } = runtime_config;
+        let RuntimeConfig { optimize, cache_size, journal_size_limit, journal_mode } =
+            runtime_config;
 
         if optimize {
             self.optimize().await?;
@@ -122,6 +125,7 @@ pub(crate) trait SqliteAsyncConnExt {
 
         self.cache_size(cache_size).await?;
         self.journal_size_limit(journal_size_limit).await?;
+        self.journal_mode(journal_mode).await?;
 
         Ok(())
     }
@@ -176,6 +180,19 @@ pub(crate) trait SqliteAsyncConnExt {
         Ok(())
     }
 
+    /// Define the journal mode of the database.
+    ///
+    /// Unlike most other settings, the WAL mode is persistent: it's stored in
+    /// the database file and applies to all its connections.
+    ///
+    /// See [`PRAGMA journal_mode`] to learn more.
+    ///
+    /// [`PRAGMA journal_mode`]: https://www.sqlite.org/pragma.html#pragma_journal_mode
+    async fn journal_mode(&self, journal_mode: JournalMode) -> Result<()> {
+        self.execute_batch(format!("PRAGMA journal_mode = {};", journal_mode.as_str())).await?;
+        Ok(())
+    }
+
     /// Defragment the database and free space on the filesystem.
     ///
     /// Only returns an error in tests, otherwise the error is only logged.
@@ -193,6 +210,49 @@ pub(crate) trait SqliteAsyncConnExt {
 
         Ok(())
     }
+
+    /// Get the size of the database in **bytes**.
+    ///
+    /// This is computed from the number of pages and the page size of the
+    /// database, see [`PRAGMA page_count`] and [`PRAGMA page_size`].
+    ///
+    /// [`PRAGMA page_count`]: https://www.sqlite.org/pragma.html#pragma_page_count
+    /// [`PRAGMA page_size`]: https://www.sqlite.org/pragma.html#pragma_page_size
+    async fn database_size(&self) -> Result<u64> {
+        let page_count: u64 = self.query_row("PRAGMA page_count", (), |row| row.get(0)).await?;
+        let page_size: u64 = self.query_row("PRAGMA page_size", (), |row| row.get(0)).await?;
+
+        Ok(page_count.saturating_mul(page_size))
+    }
+
+    /// Give the free pages of the database back to the filesystem.
+    ///
+    /// The first time this is called the database is switched to the
+    /// incremental [`PRAGMA auto_vacuum`] mode, which requires a full `VACUUM`.
+    /// After that, the cheaper [`PRAGMA incremental_vacuum`] is used. Finally,
+    /// the WAL file is truncated so the space is actually freed.
+    ///
+    /// Unlike [`Self::vacuum`], errors are always returned since this is
+    /// explicitly requested.
+    ///
+    /// [`PRAGMA auto_vacuum`]: https://www.sqlite.org/pragma.html#pragma_auto_vacuum
+    /// [`PRAGMA incremental_vacuum`]: https://www.sqlite.org/pragma.html#pragma_incremental_vacuum
+    async fn compact(&self) -> Result<()> {
+        // `2` stands for the `INCREMENTAL` mode.
+        let auto_vacuum: u8 = self.query_row("PRAGMA auto_vacuum", (), |row| row.get(0)).await?;
+
+        if auto_vacuum == 2 {
+            self.execute_batch("PRAGMA incremental_vacuum;").await?;
+        } else {
+            // Changing the auto-vacuum mode of an existing database only takes effect
+            // after a `VACUUM`.
+            self.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;").await?;
+        }
+
+        self.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);").await?;
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -453,7 +513,17 @@ pub(crate) trait SqliteKeyValueStoreAsyncConnExt: SqliteAsyncConnExt {
         &self,
         passphrase: &str,
     ) -> Result<StoreCipher, OpenStoreError> {
-        let encrypted_cipher = self.get_kv("cipher").await.map_err(OpenStoreError::LoadCipher)?;
+        self.get_or_create_store_cipher_at("cipher", passphrase).await
+    }
+
+    /// Get the [`StoreCipher`] saved under the given key of the database or
+    /// create it.
+    async fn get_or_create_store_cipher_at(
+        &self,
+        key: &str,
+        passphrase: &str,
+    ) -> Result<StoreCipher, OpenStoreError> {
+        let encrypted_cipher = self.get_kv(key).await.map_err(OpenStoreError::LoadCipher)?;
 
         let cipher = if let Some(encrypted) = encrypted_cipher {
             StoreCipher::import(passphrase, &encrypted)?
@@ -463,7 +533,7 @@ pub(crate) trait SqliteKeyValueStoreAsyncConnExt: SqliteAsyncConnExt {
             let export = cipher.export(passphrase);
             #[cfg(test)]
             let export = cipher._insecure_export_fast_for_testing(passphrase);
-            self.set_kv("cipher", export?).await.map_err(OpenStoreError::SaveCipher)?;
+            self.set_kv(key, export?).await.map_err(OpenStoreError::SaveCipher)?;
             cipher
         };
 
@@ -506,6 +576,24 @@ pub(crate) fn repeat_vars(count: usize) -> impl fmt::Display {
     iter::repeat_n("?", count).format(",")
 }
 
+/// Apply the [`ConnectionConfig`] to the given connection.
+///
+/// The settings only apply to this connection, so this is called once for
+/// every connection the pool creates.
+#[cfg(feature = "crypto-store")]
+pub(crate) fn apply_connection_config(
+    conn: &rusqlite::Connection,
+    connection_config: ConnectionConfig,
+) -> rusqlite::Result<()> {
+    let ConnectionConfig { busy_timeout, synchronous } = connection_config;
+
+    conn.execute_batch(&format!(
+        "PRAGMA busy_timeout = {}; PRAGMA synchronous = {};",
+        busy_timeout.as_millis(),
+        synchronous.as_str(),
+    ))
+}
+
 /// Convert the given `SystemTime` to a timestamp, as the number of seconds
 /// since Unix Epoch.
 ///
@@ -951,7 +951,10 @@ fn utd_event_with_unsigned(unsigned: serde_json::Value) -> TimelineEvent {
         raw,
         matrix_sdk::deserialized_responses::UnableToDecryptInfo {
             session_id: Some("SESSION_ID".into()),
-            reason: UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+            reason: UnableToDecryptReason::MissingMegolmSession {
+                withheld_code: None,
+                withheld_by: None,
+            },
         },
     )
 }
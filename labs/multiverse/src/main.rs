@@ -511,6 +511,7 @@ async fn configure_client(cli: Cli) -> Result<Client> {
             auto_enable_cross_signing: true,
             backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
             auto_enable_backups: true,
+            automatic_room_key_requests: false,
         })
         .with_enable_share_history_on_invite(true);
 
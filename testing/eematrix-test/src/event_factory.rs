@@ -331,7 +331,10 @@ impl EventBuilder<RoomEncryptedEventContent> {
             self.into(),
             UnableToDecryptInfo {
                 session_id,
-                reason: UnableToDecryptReason::MissingMegolmSession { withheld_code: None },
+                reason: UnableToDecryptReason::MissingMegolmSession {
+                    withheld_code: None,
+                    withheld_by: None,
+                },
             },
         )
     }
@@ -299,6 +299,13 @@ fn run_crypto_tests() -> Result<()> {
     cmd!(sh, "rustup run stable cargo nextest run -p matrix-sdk-crypto --features=testing")
         .run()?;
     cmd!(sh, "rustup run stable cargo test --doc -p matrix-sdk-crypto --features=testing").run()?;
+    cmd!(
+        sh,
+        "rustup run stable cargo clippy -p matrix-sdk-crypto --all-targets --features=fuzzing,testing -- -D warnings"
+    )
+    .run()?;
+    cmd!(sh, "rustup run stable cargo nextest run -p matrix-sdk-crypto --features=fuzzing,testing")
+        .run()?;
     cmd!(
         sh,
         "rustup run stable cargo clippy -p matrix-sdk-crypto --features=experimental-algorithms -- -D warnings"